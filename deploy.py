@@ -865,6 +865,15 @@ if __name__ == "__main__":
       help=("The console will be used to output allocator statistics every "
             "time an allocation/deallocation happens."),
   )
+  main_parser.add_argument(
+      "--perf",
+      action="append_const",
+      const="perf",
+      dest="features",
+      help=("The console will be used to output how long each CTAP command "
+            "took. The statistics per command can also be read with a vendor "
+            "command."),
+  )
   main_parser.add_argument(
       "--verbose",
       action="append_const",
@@ -72,11 +72,8 @@ fn compute_latency(timer: &Timer, num_pages: usize, key_increment: usize, word_l
     let ((), time) = measure(timer, || {
         for i in 0..count {
             let key = 1 + key_increment * i;
-            // For some reason the kernel sometimes fails.
-            while store.insert(key, &vec![0; 4 * word_length]).is_err() {
-                // We never enter this loop in practice, but we still need it for the kernel.
-                writeln!(console, "Retry insert.").unwrap();
-            }
+            // Transient kernel errors are retried by the storage.
+            store.insert(key, &vec![0; 4 * word_length]).unwrap();
         }
     });
     writeln!(console, "Setup: {:.1}ms for {} entries.", time.ms(), count).unwrap();
@@ -92,9 +89,18 @@ fn compute_latency(timer: &Timer, num_pages: usize, key_increment: usize, word_l
         num_pages + (1 + count) * (1 + word_length)
     );
 
-    // Measure latency of boot.
+    // Measure latency and peak heap usage of boot. Only the embedded allocator tracks the heap.
+    #[cfg(not(feature = "std"))]
+    lang_items::reset_heap_high_water_mark();
     let (mut store, time) = measure(&timer, || unsafe { boot_store(num_pages, false) });
     writeln!(console, "Boot: {:.1}ms.", time.ms()).unwrap();
+    #[cfg(not(feature = "std"))]
+    writeln!(
+        console,
+        "Boot heap: {} bytes.",
+        lang_items::heap_high_water_mark()
+    )
+    .unwrap();
 
     // Measure latency of remove.
     let ((), time) = measure(&timer, || store.remove(key).unwrap());
@@ -0,0 +1,35 @@
+#![no_main]
+extern crate alloc;
+
+use alloc::vec::Vec;
+use cbor::reader::DecoderError;
+use libfuzzer_sys::fuzz_target;
+
+// Checks that deeply nested arrays and maps are rejected exactly when they exceed the limit.
+//
+// The first byte is the nesting limit. Each following byte opens an array (even) or a map (odd).
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+    let max_nesting = data[0] as i8;
+    let mut encoded = Vec::new();
+    for &byte in &data[1..] {
+        if byte & 1 == 0 {
+            // Array with a single element.
+            encoded.push(0x81);
+        } else {
+            // Map with a single pair with key 0.
+            encoded.extend_from_slice(&[0xA1, 0x00]);
+        }
+    }
+    encoded.push(0x00);
+    let depth = data.len() - 1;
+    match cbor::read_nested(&encoded, max_nesting) {
+        Ok(_) => assert!(max_nesting >= 0 && depth <= max_nesting as usize),
+        Err(DecoderError::TooMuchNesting) => {
+            assert!(max_nesting < 0 || depth > max_nesting as usize)
+        }
+        Err(error) => panic!("{:?}", error),
+    }
+});
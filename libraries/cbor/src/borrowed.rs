@@ -0,0 +1,369 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::reader::{DecoderError, Reader};
+use super::values::{Constants, SimpleValue};
+use core::cmp::Ordering;
+use core::str;
+
+/// A decoded CBOR value borrowing its content from the encoded input.
+///
+/// Decoding does not allocate: strings are slices of the input, and arrays and maps are decoded
+/// again each time they are iterated. The input is fully validated once, with the same rules as
+/// `read`, so iterating never fails.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValueRef<'a> {
+    Unsigned(u64),
+    // We only use 63 bits of information here.
+    Negative(i64),
+    ByteString(&'a [u8]),
+    TextString(&'a str),
+    Array(ArrayRef<'a>),
+    Map(MapRef<'a>),
+    Simple(SimpleValue),
+}
+
+/// A validated CBOR array, whose elements are decoded on demand.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArrayRef<'a> {
+    len: usize,
+    content: &'a [u8],
+}
+
+/// A validated CBOR map with canonically ordered keys, whose entries are decoded on demand.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MapRef<'a> {
+    len: usize,
+    content: &'a [u8],
+}
+
+pub struct ArrayIter<'a> {
+    reader: Reader<'a>,
+    remaining_len: usize,
+}
+
+pub struct MapIter<'a> {
+    reader: Reader<'a>,
+    remaining_len: usize,
+}
+
+/// Decodes a CBOR value without allocating.
+pub fn read_ref(encoded_cbor: &[u8]) -> Result<ValueRef<'_>, DecoderError> {
+    read_ref_nested(encoded_cbor, Reader::MAX_NESTING_DEPTH)
+}
+
+/// Decodes a CBOR value with at most `max_nesting` levels of nested arrays and maps, without
+/// allocating.
+pub fn read_ref_nested(encoded_cbor: &[u8], max_nesting: i8) -> Result<ValueRef<'_>, DecoderError> {
+    let mut reader = Reader::new(encoded_cbor);
+    let value = decode(&mut reader, max_nesting)?;
+    if !reader.remaining_cbor.is_empty() {
+        return Err(DecoderError::ExtranousData);
+    }
+    Ok(value)
+}
+
+impl<'a> ValueRef<'a> {
+    fn is_key(&self) -> bool {
+        matches!(
+            self,
+            ValueRef::Unsigned(_)
+                | ValueRef::Negative(_)
+                | ValueRef::ByteString(_)
+                | ValueRef::TextString(_)
+        )
+    }
+
+    pub fn type_label(&self) -> u8 {
+        match self {
+            ValueRef::Unsigned(_) => 0,
+            ValueRef::Negative(_) => 1,
+            ValueRef::ByteString(_) => 2,
+            ValueRef::TextString(_) => 3,
+            ValueRef::Array(_) => 4,
+            ValueRef::Map(_) => 5,
+            ValueRef::Simple(_) => 7,
+        }
+    }
+}
+
+// Compares map keys with the canonical order of `KeyType`.
+fn cmp_keys(key1: &ValueRef, key2: &ValueRef) -> Ordering {
+    match (key1, key2) {
+        (ValueRef::Unsigned(u1), ValueRef::Unsigned(u2)) => u1.cmp(u2),
+        (ValueRef::Negative(n1), ValueRef::Negative(n2)) => n1.cmp(n2).reverse(),
+        (ValueRef::ByteString(b1), ValueRef::ByteString(b2)) => {
+            b1.len().cmp(&b2.len()).then(b1.cmp(b2))
+        }
+        (ValueRef::TextString(t1), ValueRef::TextString(t2)) => {
+            t1.len().cmp(&t2.len()).then(t1.cmp(t2))
+        }
+        _ => key1.type_label().cmp(&key2.type_label()),
+    }
+}
+
+fn decode<'a>(reader: &mut Reader<'a>, remaining_depth: i8) -> Result<ValueRef<'a>, DecoderError> {
+    if remaining_depth < 0 {
+        return Err(DecoderError::TooMuchNesting);
+    }
+    let first_byte = match reader.read_bytes(1) {
+        Some([first_byte]) => *first_byte,
+        _ => return Err(DecoderError::IncompleteCborData),
+    };
+    // Unsigned byte means logical shift, so only zeros get shifted in.
+    let major_type_value = first_byte >> Constants::MAJOR_TYPE_BIT_SHIFT;
+    let additional_info = first_byte & Constants::ADDITIONAL_INFORMATION_MASK;
+    let size_value = reader.read_variadic_length_integer(additional_info)?;
+    match major_type_value {
+        0 => Ok(ValueRef::Unsigned(size_value)),
+        1 => {
+            let signed_size = size_value as i64;
+            if signed_size < 0 {
+                Err(DecoderError::OutOfRangeIntegerValue)
+            } else {
+                Ok(ValueRef::Negative(-signed_size - 1))
+            }
+        }
+        2 => reader
+            .read_bytes(size_value as usize)
+            .map(ValueRef::ByteString)
+            .ok_or(DecoderError::IncompleteCborData),
+        3 => {
+            let bytes = reader
+                .read_bytes(size_value as usize)
+                .ok_or(DecoderError::IncompleteCborData)?;
+            str::from_utf8(bytes)
+                .map(ValueRef::TextString)
+                .map_err(|_| DecoderError::InvalidUtf8)
+        }
+        4 => {
+            let start = reader.remaining_cbor;
+            for _ in 0..size_value {
+                decode(reader, remaining_depth - 1)?;
+            }
+            Ok(ValueRef::Array(ArrayRef {
+                len: size_value as usize,
+                content: consumed(start, reader),
+            }))
+        }
+        5 => {
+            let start = reader.remaining_cbor;
+            let mut last_key: Option<ValueRef> = None;
+            for _ in 0..size_value {
+                let key = decode(reader, remaining_depth - 1)?;
+                if !key.is_key() {
+                    return Err(DecoderError::IncorrectMapKeyType);
+                }
+                if let Some(last_key) = &last_key {
+                    if cmp_keys(last_key, &key) != Ordering::Less {
+                        return Err(DecoderError::OutOfOrderKey);
+                    }
+                }
+                decode(reader, remaining_depth - 1)?;
+                last_key = Some(key);
+            }
+            Ok(ValueRef::Map(MapRef {
+                len: size_value as usize,
+                content: consumed(start, reader),
+            }))
+        }
+        7 => reader
+            .decode_simple_value(size_value, additional_info)
+            .map(ValueRef::Simple),
+        _ => Err(DecoderError::UnsupportedMajorType),
+    }
+}
+
+// Returns the part of `start` that the reader consumed.
+fn consumed<'a>(start: &'a [u8], reader: &Reader<'a>) -> &'a [u8] {
+    &start[..start.len() - reader.remaining_cbor.len()]
+}
+
+impl<'a> ArrayRef<'a> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> ArrayIter<'a> {
+        ArrayIter {
+            reader: Reader::new(self.content),
+            remaining_len: self.len,
+        }
+    }
+}
+
+impl<'a> MapRef<'a> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> MapIter<'a> {
+        MapIter {
+            reader: Reader::new(self.content),
+            remaining_len: self.len,
+        }
+    }
+
+    /// Returns the value associated with a key, if any.
+    pub fn get(&self, key: &ValueRef) -> Option<ValueRef<'a>> {
+        self.iter()
+            .find(|(entry_key, _)| entry_key == key)
+            .map(|(_, value)| value)
+    }
+}
+
+impl<'a> Iterator for ArrayIter<'a> {
+    type Item = ValueRef<'a>;
+
+    fn next(&mut self) -> Option<ValueRef<'a>> {
+        if self.remaining_len == 0 {
+            return None;
+        }
+        self.remaining_len -= 1;
+        // The content was validated when the array was decoded.
+        decode(&mut self.reader, i8::MAX).ok()
+    }
+}
+
+impl<'a> Iterator for MapIter<'a> {
+    type Item = (ValueRef<'a>, ValueRef<'a>);
+
+    fn next(&mut self) -> Option<(ValueRef<'a>, ValueRef<'a>)> {
+        if self.remaining_len == 0 {
+            return None;
+        }
+        self.remaining_len -= 1;
+        // The content was validated when the map was decoded.
+        let key = decode(&mut self.reader, i8::MAX).ok()?;
+        let value = decode(&mut self.reader, i8::MAX).ok()?;
+        Some((key, value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::reader::read;
+
+    #[test]
+    fn test_read_ref_scalars() {
+        assert_eq!(read_ref(&[0x18, 0x64]), Ok(ValueRef::Unsigned(100)));
+        assert_eq!(read_ref(&[0x38, 0x63]), Ok(ValueRef::Negative(-100)));
+        assert_eq!(
+            read_ref(&[0xF5]),
+            Ok(ValueRef::Simple(SimpleValue::TrueValue))
+        );
+        assert_eq!(
+            read_ref(&[0xF6]),
+            Ok(ValueRef::Simple(SimpleValue::NullValue))
+        );
+    }
+
+    #[test]
+    fn test_read_ref_borrows_strings() {
+        let cbor = [0x44, 0x01, 0x02, 0x03, 0x04];
+        match read_ref(&cbor) {
+            Ok(ValueRef::ByteString(bytes)) => assert_eq!(bytes.as_ptr(), cbor[1..].as_ptr()),
+            _ => panic!("Invalid byte string"),
+        }
+        let cbor = [0x64, 0x49, 0x45, 0x54, 0x46];
+        assert_eq!(read_ref(&cbor), Ok(ValueRef::TextString("IETF")));
+    }
+
+    #[test]
+    fn test_read_ref_array() {
+        let cbor = [0x83, 0x01, 0x82, 0x02, 0x03, 0x61, 0x61];
+        let array = match read_ref(&cbor) {
+            Ok(ValueRef::Array(array)) => array,
+            _ => panic!("Invalid array"),
+        };
+        assert_eq!(array.len(), 3);
+        let mut elements = array.iter();
+        assert_eq!(elements.next(), Some(ValueRef::Unsigned(1)));
+        match elements.next() {
+            Some(ValueRef::Array(inner)) => {
+                assert!(inner
+                    .iter()
+                    .eq(vec![ValueRef::Unsigned(2), ValueRef::Unsigned(3)]));
+            }
+            _ => panic!("Invalid inner array"),
+        }
+        assert_eq!(elements.next(), Some(ValueRef::TextString("a")));
+        assert_eq!(elements.next(), None);
+    }
+
+    #[test]
+    fn test_read_ref_map() {
+        // {1: "a", -1: h'00', "id": true}
+        let cbor = [
+            0xA3, 0x01, 0x61, 0x61, 0x20, 0x41, 0x00, 0x62, 0x69, 0x64, 0xF5,
+        ];
+        let map = match read_ref(&cbor) {
+            Ok(ValueRef::Map(map)) => map,
+            _ => panic!("Invalid map"),
+        };
+        assert_eq!(map.len(), 3);
+        assert_eq!(
+            map.get(&ValueRef::Unsigned(1)),
+            Some(ValueRef::TextString("a"))
+        );
+        assert_eq!(
+            map.get(&ValueRef::Negative(-1)),
+            Some(ValueRef::ByteString(&[0x00]))
+        );
+        assert_eq!(
+            map.get(&ValueRef::TextString("id")),
+            Some(ValueRef::Simple(SimpleValue::TrueValue))
+        );
+        assert_eq!(map.get(&ValueRef::Unsigned(2)), None);
+    }
+
+    #[test]
+    fn test_read_ref_errors_match_read() {
+        let cases: Vec<&[u8]> = vec![
+            // Out of order keys.
+            &[0xA2, 0x02, 0x00, 0x01, 0x00],
+            // Duplicate keys.
+            &[0xA2, 0x01, 0x00, 0x01, 0x00],
+            // Array as map key.
+            &[0xA1, 0x80, 0x00],
+            // Too much nesting.
+            &[0x81, 0x81, 0x81, 0x81, 0x81, 0x00],
+            // Truncated byte string.
+            &[0x44, 0x01, 0x02],
+            // Invalid UTF-8.
+            &[0x61, 0xFF],
+            // Extraneous data.
+            &[0x00, 0x00],
+            // Non-minimal encoding.
+            &[0x18, 0x17],
+            // Floating point value.
+            &[0xF9, 0x00, 0x00],
+            // Tag.
+            &[0xC0, 0x00],
+        ];
+        for cbor in cases {
+            assert_eq!(read_ref(cbor).err(), read(cbor).err());
+            assert!(read_ref(cbor).is_err());
+        }
+    }
+}
@@ -0,0 +1,416 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Streaming CBOR encoding, without building a `Value` first.
+//!
+//! Encoding a `Value` with `write` holds both the value and its encoding in memory. For large
+//! items, such as a byte string of several kilobytes, the `Encoder` writes each item directly to
+//! its output instead. Both can be mixed: `Encoder::value` writes a `Value` inside a stream.
+
+use super::values::{Constants, KeyType, SimpleValue, Value};
+use super::writer::Writer;
+use alloc::vec::Vec;
+
+/// Output of an `Encoder`.
+pub trait Write {
+    /// Appends bytes to the output.
+    ///
+    /// Returns false, and writes nothing, if the bytes don't fit.
+    fn write_all(&mut self, bytes: &[u8]) -> bool;
+}
+
+impl Write for Vec<u8> {
+    fn write_all(&mut self, bytes: &[u8]) -> bool {
+        self.extend_from_slice(bytes);
+        true
+    }
+}
+
+impl<W: Write + ?Sized> Write for &mut W {
+    fn write_all(&mut self, bytes: &[u8]) -> bool {
+        (**self).write_all(bytes)
+    }
+}
+
+/// Writes into a fixed buffer, like the buffer of an outgoing message.
+pub struct SliceWriter<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+}
+
+impl<'a> SliceWriter<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> SliceWriter<'a> {
+        SliceWriter { buffer, length: 0 }
+    }
+
+    /// Returns the written prefix of the buffer.
+    pub fn written(&self) -> &[u8] {
+        &self.buffer[..self.length]
+    }
+}
+
+impl Write for SliceWriter<'_> {
+    fn write_all(&mut self, bytes: &[u8]) -> bool {
+        let end = self.length + bytes.len();
+        if end > self.buffer.len() {
+            return false;
+        }
+        self.buffer[self.length..end].copy_from_slice(bytes);
+        self.length = end;
+        true
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EncoderError {
+    /// The output has no space left.
+    OutputFull,
+    /// More than 4 levels of arrays and maps are nested, like for `write`.
+    TooMuchNesting,
+    /// An item doesn't fit in the announced length of its array or map, or in the data item.
+    TooManyItems,
+    /// An array or map is missing items, or there is no data item.
+    MissingItems,
+    /// A `KeyType::Negative` holds a non-negative integer.
+    InvalidNegativeKey,
+}
+
+/// Encodes a CBOR data item in the CTAP2 canonical form, one item at a time.
+///
+/// Arrays and maps are announced with their length, and followed by that many items. Map entries
+/// are a key followed by its value, and it is up to the caller to encode keys in canonical order.
+/// The number of items and the nesting depth are checked, such that `finish` only succeeds once
+/// a complete data item is written. After an error, the output is incomplete and the encoder
+/// should be dropped.
+pub struct Encoder<W: Write> {
+    output: W,
+    // Number of items left in each open array or map, innermost last. Only the first `depth`
+    // entries are used. A map entry counts as 2 items.
+    remaining: [u64; Writer::MAX_NESTING_DEPTH as usize],
+    depth: usize,
+    // Whether the top-level data item is complete.
+    complete: bool,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(output: W) -> Encoder<W> {
+        Encoder {
+            output,
+            remaining: [0; Writer::MAX_NESTING_DEPTH as usize],
+            depth: 0,
+            complete: false,
+        }
+    }
+
+    /// Returns the output after checking that a complete data item was written.
+    pub fn finish(self) -> Result<W, EncoderError> {
+        if !self.complete {
+            return Err(EncoderError::MissingItems);
+        }
+        Ok(self.output)
+    }
+
+    pub fn unsigned(&mut self, unsigned: u64) -> Result<(), EncoderError> {
+        self.item(0, unsigned, &[])
+    }
+
+    /// Encodes an integer, either as unsigned or as negative.
+    pub fn integer(&mut self, int: i64) -> Result<(), EncoderError> {
+        if int >= 0 {
+            self.item(0, int as u64, &[])
+        } else {
+            self.item(1, -(int + 1) as u64, &[])
+        }
+    }
+
+    pub fn byte_string(&mut self, byte_string: &[u8]) -> Result<(), EncoderError> {
+        self.item(2, byte_string.len() as u64, byte_string)
+    }
+
+    pub fn text_string(&mut self, text_string: &str) -> Result<(), EncoderError> {
+        self.item(3, text_string.len() as u64, text_string.as_bytes())
+    }
+
+    pub fn simple(&mut self, simple_value: SimpleValue) -> Result<(), EncoderError> {
+        self.item(7, simple_value as u64, &[])
+    }
+
+    pub fn bool(&mut self, b: bool) -> Result<(), EncoderError> {
+        if b {
+            self.simple(SimpleValue::TrueValue)
+        } else {
+            self.simple(SimpleValue::FalseValue)
+        }
+    }
+
+    /// Starts an array, to be followed by `length` items.
+    pub fn array(&mut self, length: usize) -> Result<(), EncoderError> {
+        self.container(4, length as u64, length as u64)
+    }
+
+    /// Starts a map, to be followed by `length` pairs of key and value.
+    pub fn map(&mut self, length: usize) -> Result<(), EncoderError> {
+        self.container(5, length as u64, 2 * length as u64)
+    }
+
+    /// Encodes a map key.
+    pub fn key(&mut self, key: &KeyType) -> Result<(), EncoderError> {
+        match key {
+            KeyType::Unsigned(unsigned) => self.unsigned(*unsigned),
+            KeyType::Negative(negative) if *negative < 0 => self.integer(*negative),
+            KeyType::Negative(_) => Err(EncoderError::InvalidNegativeKey),
+            KeyType::ByteString(byte_string) => self.byte_string(byte_string),
+            KeyType::TextString(text_string) => self.text_string(text_string),
+        }
+    }
+
+    /// Encodes a value, with its arrays and maps counting towards the nesting depth.
+    pub fn value(&mut self, value: &Value) -> Result<(), EncoderError> {
+        match value {
+            Value::KeyValue(key) => self.key(key),
+            Value::Array(array) => {
+                self.array(array.len())?;
+                array.iter().try_for_each(|el| self.value(el))
+            }
+            Value::Map(map) => {
+                self.map(map.len())?;
+                map.iter().try_for_each(|(k, v)| {
+                    self.key(k)?;
+                    self.value(v)
+                })
+            }
+            Value::Simple(simple_value) => self.simple(simple_value.clone()),
+        }
+    }
+
+    // Encodes an item without content, or with its content following the header.
+    fn item(&mut self, type_label: u8, size: u64, content: &[u8]) -> Result<(), EncoderError> {
+        self.count_item()?;
+        self.header(type_label, size)?;
+        self.write(content)?;
+        self.close_containers();
+        Ok(())
+    }
+
+    // Starts an array or map, followed by `items` items.
+    fn container(&mut self, type_label: u8, size: u64, items: u64) -> Result<(), EncoderError> {
+        if items > 0 && self.depth == self.remaining.len() {
+            return Err(EncoderError::TooMuchNesting);
+        }
+        self.count_item()?;
+        self.header(type_label, size)?;
+        if items > 0 {
+            self.remaining[self.depth] = items;
+            self.depth += 1;
+        } else {
+            self.close_containers();
+        }
+        Ok(())
+    }
+
+    // Counts an item in its array or map, if any.
+    fn count_item(&mut self) -> Result<(), EncoderError> {
+        if self.complete {
+            return Err(EncoderError::TooManyItems);
+        }
+        if self.depth > 0 {
+            // Containers are closed as soon as their last item is written, so this is positive.
+            self.remaining[self.depth - 1] -= 1;
+        }
+        Ok(())
+    }
+
+    // Closes the arrays and maps whose last item was written.
+    fn close_containers(&mut self) {
+        while self.depth > 0 && self.remaining[self.depth - 1] == 0 {
+            self.depth -= 1;
+        }
+        self.complete = self.depth == 0;
+    }
+
+    // Writes the initial byte and argument of an item, like `Writer::start_item`.
+    fn header(&mut self, type_label: u8, size: u64) -> Result<(), EncoderError> {
+        let (first_byte, shift) = match size {
+            0..=23 => (size as u8, 0),
+            24..=0xFF => (Constants::ADDITIONAL_INFORMATION_1_BYTE, 1),
+            0x100..=0xFFFF => (Constants::ADDITIONAL_INFORMATION_2_BYTES, 2),
+            0x10000..=0xFFFF_FFFF => (Constants::ADDITIONAL_INFORMATION_4_BYTES, 4),
+            _ => (Constants::ADDITIONAL_INFORMATION_8_BYTES, 8),
+        };
+        let mut header = [0u8; 9];
+        header[0] = first_byte | type_label << Constants::MAJOR_TYPE_BIT_SHIFT;
+        for i in 0..shift {
+            header[1 + i] = (size >> ((shift - 1 - i) * 8)) as u8;
+        }
+        self.write(&header[..1 + shift])
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), EncoderError> {
+        if self.output.write_all(bytes) {
+            Ok(())
+        } else {
+            Err(EncoderError::OutputFull)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        cbor_array, cbor_bytes, cbor_false, cbor_int, cbor_map, cbor_null, cbor_text, cbor_true,
+        write,
+    };
+
+    type EncodeFn = fn(&mut Encoder<Vec<u8>>) -> Result<(), EncoderError>;
+
+    fn write_return(value: Value) -> Vec<u8> {
+        let mut encoded_cbor = Vec::new();
+        assert!(write(value, &mut encoded_cbor));
+        encoded_cbor
+    }
+
+    #[test]
+    fn test_encode_items() {
+        let cases: Vec<(Value, EncodeFn)> = vec![
+            (cbor_int!(0), |e| e.unsigned(0)),
+            (cbor_int!(24), |e| e.integer(24)),
+            (cbor_int!(0x1_0000_0000), |e| e.unsigned(0x1_0000_0000)),
+            (cbor_int!(-1), |e| e.integer(-1)),
+            (cbor_int!(-1000), |e| e.integer(-1000)),
+            (cbor_int!(i64::MIN), |e| e.integer(i64::MIN)),
+            (cbor_bytes!(vec![0x55; 300]), |e| {
+                e.byte_string(&[0x55; 300])
+            }),
+            (cbor_text!("IETF"), |e| e.text_string("IETF")),
+            (cbor_true!(), |e| e.bool(true)),
+            (cbor_false!(), |e| e.bool(false)),
+            (cbor_null!(), |e| e.simple(SimpleValue::NullValue)),
+        ];
+        for (value, encode) in cases {
+            let mut encoder = Encoder::new(Vec::new());
+            encode(&mut encoder).unwrap();
+            assert_eq!(encoder.finish(), Ok(write_return(value)));
+        }
+    }
+
+    #[test]
+    fn test_encode_containers() {
+        let value = cbor_map! {
+            1 => vec![0x01, 0x02],
+            "a" => cbor_array![],
+            "b" => cbor_array![cbor_map! {}, 3],
+        };
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.map(3).unwrap();
+        encoder.unsigned(1).unwrap();
+        encoder.byte_string(&[0x01, 0x02]).unwrap();
+        encoder.text_string("a").unwrap();
+        encoder.array(0).unwrap();
+        encoder.text_string("b").unwrap();
+        encoder.array(2).unwrap();
+        encoder.map(0).unwrap();
+        encoder.unsigned(3).unwrap();
+        assert_eq!(encoder.finish(), Ok(write_return(value)));
+    }
+
+    #[test]
+    fn test_encode_value() {
+        let value = cbor_map! {
+            1 => cbor_array![cbor_map! {"a" => -2}, cbor_null!()],
+            "b" => vec![0x01],
+        };
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.value(&value).unwrap();
+        assert_eq!(encoder.finish(), Ok(write_return(value)));
+    }
+
+    #[test]
+    fn test_encode_item_count() {
+        let encoder = Encoder::new(Vec::new());
+        assert_eq!(encoder.finish().err(), Some(EncoderError::MissingItems));
+
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.array(2).unwrap();
+        encoder.unsigned(1).unwrap();
+        assert_eq!(encoder.finish().err(), Some(EncoderError::MissingItems));
+
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.map(1).unwrap();
+        encoder.unsigned(1).unwrap();
+        assert_eq!(encoder.finish().err(), Some(EncoderError::MissingItems));
+
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.array(1).unwrap();
+        encoder.unsigned(1).unwrap();
+        assert_eq!(encoder.unsigned(2), Err(EncoderError::TooManyItems));
+    }
+
+    #[test]
+    fn test_encode_nesting() {
+        let mut encoder = Encoder::new(Vec::new());
+        for _ in 0..4 {
+            encoder.array(1).unwrap();
+        }
+        // Empty containers don't nest any item, like for `write`.
+        encoder.array(0).unwrap();
+        assert!(encoder.finish().is_ok());
+
+        let mut encoder = Encoder::new(Vec::new());
+        for _ in 0..4 {
+            encoder.array(1).unwrap();
+        }
+        assert_eq!(encoder.map(1), Err(EncoderError::TooMuchNesting));
+
+        let mut encoder = Encoder::new(Vec::new());
+        encoder.array(1).unwrap();
+        let value = cbor_array![cbor_array![cbor_array![cbor_array![1]]]];
+        assert_eq!(encoder.value(&value), Err(EncoderError::TooMuchNesting));
+    }
+
+    #[test]
+    fn test_encode_invalid_negative_key() {
+        let mut encoder = Encoder::new(Vec::new());
+        assert_eq!(
+            encoder.key(&KeyType::Negative(0)),
+            Err(EncoderError::InvalidNegativeKey)
+        );
+    }
+
+    #[test]
+    fn test_slice_writer() {
+        let mut buffer = [0u8; 4];
+        let mut encoder = Encoder::new(SliceWriter::new(&mut buffer));
+        encoder.array(1).unwrap();
+        encoder.byte_string(&[0x01, 0x02]).unwrap();
+        let writer = encoder.finish().unwrap();
+        assert_eq!(writer.written(), &[0x81, 0x42, 0x01, 0x02]);
+
+        let mut buffer = [0u8; 4];
+        let mut encoder = Encoder::new(SliceWriter::new(&mut buffer));
+        assert_eq!(
+            encoder.byte_string(&[0x01, 0x02, 0x03, 0x04]),
+            Err(EncoderError::OutputFull)
+        );
+    }
+
+    #[test]
+    fn test_write_to_borrowed_output() {
+        let mut output = vec![0x00];
+        let mut encoder = Encoder::new(&mut output);
+        encoder.unsigned(1).unwrap();
+        assert!(encoder.finish().is_ok());
+        assert_eq!(output, vec![0x00, 0x01]);
+    }
+}
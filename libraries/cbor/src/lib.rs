@@ -12,17 +12,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+//! CBOR encoding and decoding for CTAP2.
+//!
+//! Values are represented by `Value` and built with the `cbor_*` macros or with `MapBuilder`.
+//! They are encoded with `write`, which always produces the CTAP2 canonical form: integers and
+//! lengths use their shortest encoding, and map keys are sorted. Decoding with `read` rejects
+//! any other form. Both directions bound the nesting of arrays and maps, and `read_limited` also
+//! bounds the number of decoded elements. Large data items can be streamed with `Encoder` instead
+//! of being built as a `Value`.
+
 #![cfg_attr(not(feature = "std"), no_std)]
 
 extern crate alloc;
 #[cfg(feature = "std")]
 extern crate core;
 
+pub mod borrowed;
+pub mod encoder;
 pub mod macros;
 pub mod reader;
 pub mod values;
 pub mod writer;
 
-pub use self::reader::read;
+pub use self::borrowed::{read_ref, read_ref_nested, ValueRef};
+pub use self::encoder::{Encoder, EncoderError, SliceWriter, Write};
+pub use self::reader::{read, read_limited, read_nested, ReadLimits};
 pub use self::values::{KeyType, SimpleValue, Value};
-pub use self::writer::write;
+pub use self::writer::{encoded_len, encoded_len_nested, write, write_nested, MapBuilder};
@@ -25,6 +25,7 @@ pub enum DecoderError {
     IncompleteCborData,
     IncorrectMapKeyType,
     TooMuchNesting,
+    TooManyElements,
     InvalidUtf8,
     ExtranousData,
     OutOfOrderKey,
@@ -34,25 +35,70 @@ pub enum DecoderError {
     OutOfRangeIntegerValue,
 }
 
+/// Bounds on the values accepted by `read_limited`.
+///
+/// The default has the nesting limit of `read` and no element limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReadLimits {
+    /// Maximum levels of nested arrays and maps. A negative limit rejects all values.
+    pub max_nesting: i8,
+    /// Maximum number of data items, counting the value itself and all array elements, map keys
+    /// and map values inside it. There is no limit if `None`.
+    pub max_elements: Option<usize>,
+}
+
+impl Default for ReadLimits {
+    fn default() -> ReadLimits {
+        ReadLimits {
+            max_nesting: Reader::MAX_NESTING_DEPTH,
+            max_elements: None,
+        }
+    }
+}
+
 pub fn read(encoded_cbor: &[u8]) -> Result<Value, DecoderError> {
+    read_limited(encoded_cbor, ReadLimits::default())
+}
+
+/// Decodes a CBOR value with at most `max_nesting` levels of nested arrays and maps.
+///
+/// Decoding stops with `TooMuchNesting` as soon as the limit is exceeded, so the stack usage is
+/// bounded by the limit and not by the input. A negative limit rejects all values.
+pub fn read_nested(encoded_cbor: &[u8], max_nesting: i8) -> Result<Value, DecoderError> {
+    let limits = ReadLimits {
+        max_nesting,
+        ..ReadLimits::default()
+    };
+    read_limited(encoded_cbor, limits)
+}
+
+/// Decodes a CBOR value within the given limits.
+///
+/// Decoding stops with `TooManyElements` as soon as an array or map announces more elements than
+/// the remaining budget, so the allocated memory is bounded by the limit and not by the input.
+pub fn read_limited(encoded_cbor: &[u8], limits: ReadLimits) -> Result<Value, DecoderError> {
     let mut reader = Reader::new(encoded_cbor);
-    let value = reader.decode_complete_data_item(Reader::MAX_NESTING_DEPTH)?;
+    reader.remaining_elements = limits.max_elements;
+    let value = reader.decode_complete_data_item(limits.max_nesting)?;
     if !reader.remaining_cbor.is_empty() {
         return Err(DecoderError::ExtranousData);
     }
     Ok(value)
 }
 
-struct Reader<'a> {
-    remaining_cbor: &'a [u8],
+pub(crate) struct Reader<'a> {
+    pub(crate) remaining_cbor: &'a [u8],
+    // Number of data items that can still be decoded, if limited.
+    remaining_elements: Option<usize>,
 }
 
 impl<'a> Reader<'a> {
-    const MAX_NESTING_DEPTH: i8 = 4;
+    pub(crate) const MAX_NESTING_DEPTH: i8 = 4;
 
     pub fn new(cbor: &'a [u8]) -> Reader<'a> {
         Reader {
             remaining_cbor: cbor,
+            remaining_elements: None,
         }
     }
 
@@ -63,6 +109,7 @@ impl<'a> Reader<'a> {
         if remaining_depth < 0 {
             return Err(DecoderError::TooMuchNesting);
         }
+        self.reserve_elements(1)?;
 
         match self.read_bytes(1) {
             Some([first_byte]) => {
@@ -88,7 +135,23 @@ impl<'a> Reader<'a> {
         }
     }
 
-    fn read_bytes(&mut self, num_bytes: usize) -> Option<&[u8]> {
+    // Checks that the budget of data items is not exceeded, without consuming it.
+    fn check_elements(&self, count: u64) -> Result<(), DecoderError> {
+        match self.remaining_elements {
+            Some(remaining) if count > remaining as u64 => Err(DecoderError::TooManyElements),
+            _ => Ok(()),
+        }
+    }
+
+    fn reserve_elements(&mut self, count: u64) -> Result<(), DecoderError> {
+        self.check_elements(count)?;
+        if let Some(remaining) = &mut self.remaining_elements {
+            *remaining -= count as usize;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read_bytes(&mut self, num_bytes: usize) -> Option<&'a [u8]> {
         if num_bytes > self.remaining_cbor.len() {
             None
         } else {
@@ -98,7 +161,10 @@ impl<'a> Reader<'a> {
         }
     }
 
-    fn read_variadic_length_integer(&mut self, additional_info: u8) -> Result<u64, DecoderError> {
+    pub(crate) fn read_variadic_length_integer(
+        &mut self,
+        additional_info: u8,
+    ) -> Result<u64, DecoderError> {
         let additional_bytes_num = match additional_info {
             0..=Constants::ADDITIONAL_INFORMATION_MAX_INT => return Ok(additional_info as u64),
             Constants::ADDITIONAL_INFORMATION_1_BYTE => 1,
@@ -161,6 +227,7 @@ impl<'a> Reader<'a> {
         size_value: u64,
         remaining_depth: i8,
     ) -> Result<Value, DecoderError> {
+        self.check_elements(size_value)?;
         // Don't set the capacity already, it is an unsanitized input.
         let mut value_array = Vec::new();
         for _ in 0..size_value {
@@ -174,6 +241,7 @@ impl<'a> Reader<'a> {
         size_value: u64,
         remaining_depth: i8,
     ) -> Result<Value, DecoderError> {
+        self.check_elements(size_value.saturating_mul(2))?;
         let mut value_map = BTreeMap::new();
         let mut last_key_option = None;
         for _ in 0..size_value {
@@ -198,6 +266,15 @@ impl<'a> Reader<'a> {
         size_value: u64,
         additional_info: u8,
     ) -> Result<Value, DecoderError> {
+        self.decode_simple_value(size_value, additional_info)
+            .map(Value::Simple)
+    }
+
+    pub(crate) fn decode_simple_value(
+        &self,
+        size_value: u64,
+        additional_info: u8,
+    ) -> Result<SimpleValue, DecoderError> {
         if additional_info > Constants::ADDITIONAL_INFORMATION_MAX_INT
             && additional_info != Constants::ADDITIONAL_INFORMATION_1_BYTE
         {
@@ -205,10 +282,7 @@ impl<'a> Reader<'a> {
             // Also, why not just disallow ANY additional_info != size_value?
             return Err(DecoderError::UnsupportedFloatingPointValue);
         }
-        match SimpleValue::from_integer(size_value) {
-            Some(simple_value) => Ok(Value::Simple(simple_value)),
-            None => Err(DecoderError::UnsupportedSimpleValue),
-        }
+        SimpleValue::from_integer(size_value).ok_or(DecoderError::UnsupportedSimpleValue)
     }
 }
 
@@ -674,6 +748,71 @@ mod test {
         assert!(reader.decode_complete_data_item(2).is_ok());
     }
 
+    #[test]
+    fn test_read_nested_limit() {
+        // Builds an array nested `depth` times around an integer.
+        let nested = |depth: usize| {
+            let mut cbor = vec![0x81; depth];
+            cbor.push(0x00);
+            cbor
+        };
+        assert_eq!(read(&nested(4)).map(|_| ()), Ok(()));
+        assert_eq!(read(&nested(5)), Err(DecoderError::TooMuchNesting));
+        assert_eq!(
+            read_nested(&nested(0), -1),
+            Err(DecoderError::TooMuchNesting)
+        );
+        assert_eq!(read_nested(&nested(0), 0), Ok(cbor_int!(0)));
+        assert_eq!(read_nested(&nested(8), 8).map(|_| ()), Ok(()));
+        assert_eq!(
+            read_nested(&nested(9), 8),
+            Err(DecoderError::TooMuchNesting)
+        );
+        // The nesting limit is enforced before decoding incomplete data.
+        let cbor = vec![0xA1; 100];
+        assert_eq!(read(&cbor), Err(DecoderError::TooMuchNesting));
+    }
+
+    #[test]
+    fn test_read_limited_elements() {
+        let limits = |max_elements| ReadLimits {
+            max_elements: Some(max_elements),
+            ..ReadLimits::default()
+        };
+        // An array of 2 integers and a map with 1 pair are 3 data items.
+        let array_cbor = vec![0x82, 0x01, 0x02];
+        let map_cbor = vec![0xA1, 0x01, 0x02];
+        for cbor in &[array_cbor, map_cbor] {
+            assert!(read_limited(cbor, limits(3)).is_ok());
+            assert_eq!(
+                read_limited(cbor, limits(2)),
+                Err(DecoderError::TooManyElements)
+            );
+        }
+        assert_eq!(
+            read_limited(&[0x00], limits(0)),
+            Err(DecoderError::TooManyElements)
+        );
+        // Elements are counted across nested values.
+        let nested_cbor = vec![0x82, 0x81, 0x01, 0x81, 0x02];
+        assert!(read_limited(&nested_cbor, limits(5)).is_ok());
+        assert_eq!(
+            read_limited(&nested_cbor, limits(4)),
+            Err(DecoderError::TooManyElements)
+        );
+        // Announced lengths are rejected before decoding the elements.
+        let huge_array = vec![0x9B, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+        assert_eq!(
+            read_limited(&huge_array, limits(100)),
+            Err(DecoderError::TooManyElements)
+        );
+        let huge_map = vec![0xBB, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(
+            read_limited(&huge_map, limits(usize::MAX / 2)),
+            Err(DecoderError::TooManyElements)
+        );
+    }
+
     #[test]
     fn test_read_out_of_order_key_error() {
         let cases = vec![
@@ -12,20 +12,157 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::values::{Constants, KeyType, Value};
+use super::values::{Constants, IntoCborKey, IntoCborValue, IntoCborValueOption, KeyType, Value};
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
 
+/// Encodes a CBOR value in the CTAP2 canonical form and appends it to `encoded_cbor`.
+///
+/// At most 4 levels of nested arrays and maps are encoded, see `write_nested`.
 pub fn write(value: Value, encoded_cbor: &mut Vec<u8>) -> bool {
+    write_nested(value, encoded_cbor, Writer::MAX_NESTING_DEPTH)
+}
+
+/// Encodes a CBOR value with at most `max_nesting` levels of nested arrays and maps.
+///
+/// The output is always in the CTAP2 canonical form: integers and lengths use their shortest
+/// encoding, and map keys are sorted by the order of `KeyType`, which matches the canonical
+/// order. Returns false if the value is nested too deeply or if a `KeyType::Negative` holds a
+/// non-negative integer. In that case, `encoded_cbor` is left unchanged.
+///
+/// The output is grown exactly once, by the length given by `encoded_len_nested`.
+pub fn write_nested(value: Value, encoded_cbor: &mut Vec<u8>, max_nesting: i8) -> bool {
+    match encoded_len_nested(&value, max_nesting) {
+        None => return false,
+        Some(length) => encoded_cbor.reserve_exact(length),
+    }
+    let initial_length = encoded_cbor.len();
     let mut writer = Writer::new(encoded_cbor);
-    writer.encode_cbor(value, Writer::MAX_NESTING_DEPTH)
+    let success = writer.encode_cbor(value, max_nesting);
+    if !success {
+        encoded_cbor.truncate(initial_length);
+    }
+    success
+}
+
+/// Returns the length of the encoding of a CBOR value by `write`, without encoding it.
+///
+/// This permits to allocate a buffer for the encoding, or check its size against a limit,
+/// before encoding. Returns `None` if `write` would fail.
+pub fn encoded_len(value: &Value) -> Option<usize> {
+    encoded_len_nested(value, Writer::MAX_NESTING_DEPTH)
 }
 
-struct Writer<'a> {
+/// Returns the length of the encoding of a CBOR value by `write_nested`, without encoding it.
+///
+/// This uses constant memory (besides the bounded recursion) and returns `None` if
+/// `write_nested` would fail.
+pub fn encoded_len_nested(value: &Value, max_nesting: i8) -> Option<usize> {
+    if max_nesting < 0 {
+        return None;
+    }
+    let length = match value {
+        Value::KeyValue(key) => key_len(key)?,
+        Value::Array(array) => {
+            let mut length = header_len(array.len() as u64);
+            for el in array {
+                length += encoded_len_nested(el, max_nesting - 1)?;
+            }
+            length
+        }
+        Value::Map(map) => {
+            let mut length = header_len(map.len() as u64);
+            for (k, v) in map {
+                // Keys are nested like values.
+                if max_nesting < 1 {
+                    return None;
+                }
+                length += key_len(k)?;
+                length += encoded_len_nested(v, max_nesting - 1)?;
+            }
+            length
+        }
+        Value::Simple(simple_value) => header_len(simple_value.clone() as u64),
+    };
+    Some(length)
+}
+
+// Returns the length of an encoded key.
+fn key_len(key: &KeyType) -> Option<usize> {
+    Some(match key {
+        KeyType::Unsigned(unsigned) => header_len(*unsigned),
+        KeyType::Negative(negative) if *negative < 0 => header_len(-(negative + 1) as u64),
+        KeyType::Negative(_) => return None,
+        KeyType::ByteString(byte_string) => {
+            header_len(byte_string.len() as u64) + byte_string.len()
+        }
+        KeyType::TextString(text_string) => {
+            header_len(text_string.len() as u64) + text_string.len()
+        }
+    })
+}
+
+// Returns the length of the initial byte and argument of an item, see `Writer::start_item`.
+fn header_len(size: u64) -> usize {
+    match size {
+        0..=23 => 1,
+        24..=0xFF => 2,
+        0x100..=0xFFFF => 3,
+        0x10000..=0xFFFF_FFFF => 5,
+        _ => 9,
+    }
+}
+
+/// Builds a CBOR map entry by entry.
+///
+/// Entries can be inserted in any order, the map is encoded with sorted keys. This is the
+/// function-based counterpart of the `cbor_map!` and `cbor_map_options!` macros.
+#[derive(Default)]
+pub struct MapBuilder {
+    map: BTreeMap<KeyType, Value>,
+}
+
+impl MapBuilder {
+    pub fn new() -> MapBuilder {
+        MapBuilder::default()
+    }
+
+    /// Adds an entry to the map.
+    ///
+    /// Keys must be unique: inserting a key twice panics in debug builds, since the previous
+    /// value would be lost.
+    pub fn insert(mut self, key: impl IntoCborKey, value: impl IntoCborValue) -> MapBuilder {
+        let previous = self
+            .map
+            .insert(key.into_cbor_key(), value.into_cbor_value());
+        debug_assert!(previous.is_none(), "Duplicate CBOR map key");
+        self
+    }
+
+    /// Adds an entry to the map if the value is present.
+    pub fn insert_option(
+        self,
+        key: impl IntoCborKey,
+        value: impl IntoCborValueOption,
+    ) -> MapBuilder {
+        match value.into_cbor_value_option() {
+            Some(value) => self.insert(key, value),
+            None => self,
+        }
+    }
+
+    /// Returns the map as a CBOR value.
+    pub fn build(self) -> Value {
+        Value::Map(self.map)
+    }
+}
+
+pub(crate) struct Writer<'a> {
     encoded_cbor: &'a mut Vec<u8>,
 }
 
 impl<'a> Writer<'a> {
-    const MAX_NESTING_DEPTH: i8 = 4;
+    pub(crate) const MAX_NESTING_DEPTH: i8 = 4;
 
     pub fn new(encoded_cbor: &mut Vec<u8>) -> Writer {
         Writer { encoded_cbor }
@@ -38,6 +175,9 @@ impl<'a> Writer<'a> {
         match value {
             Value::KeyValue(KeyType::Unsigned(unsigned)) => self.start_item(0, unsigned),
             Value::KeyValue(KeyType::Negative(negative)) => {
+                if negative >= 0 {
+                    return false;
+                }
                 self.start_item(1, -(negative + 1) as u64)
             }
             Value::KeyValue(KeyType::ByteString(byte_string)) => {
@@ -403,6 +543,178 @@ mod test {
         assert!(!writer.encode_cbor(cbor_array, 2));
     }
 
+    #[test]
+    fn test_write_length_boundaries() {
+        // The header of each major type is checked at the boundaries of the length encodings.
+        let cases = vec![
+            (23, vec![0x17]),
+            (24, vec![0x18, 0x18]),
+            (255, vec![0x18, 0xFF]),
+            (256, vec![0x19, 0x01, 0x00]),
+            (65535, vec![0x19, 0xFF, 0xFF]),
+            (65536, vec![0x1A, 0x00, 0x01, 0x00, 0x00]),
+        ];
+        for (length, header) in cases {
+            let with_major_type = |major_type: u8| {
+                let mut header = header.clone();
+                header[0] |= major_type << Constants::MAJOR_TYPE_BIT_SHIFT;
+                header
+            };
+
+            let encoded_cbor = write_return(cbor_bytes!(vec![0x00; length])).unwrap();
+            assert_eq!(encoded_cbor[..header.len()], with_major_type(2)[..]);
+            assert_eq!(encoded_cbor.len(), header.len() + length);
+
+            let encoded_cbor = write_return(cbor_text!("a".repeat(length))).unwrap();
+            assert_eq!(encoded_cbor[..header.len()], with_major_type(3)[..]);
+            assert_eq!(encoded_cbor.len(), header.len() + length);
+
+            let encoded_cbor = write_return(cbor_array_vec!(vec![0; length])).unwrap();
+            assert_eq!(encoded_cbor[..header.len()], with_major_type(4)[..]);
+            assert_eq!(encoded_cbor.len(), header.len() + length);
+
+            let map = (0..length as i64)
+                .fold(MapBuilder::new(), |map, i| map.insert(-1 - i, 0))
+                .build();
+            let encoded_cbor = write_return(map).unwrap();
+            assert_eq!(encoded_cbor[..header.len()], with_major_type(5)[..]);
+        }
+    }
+
+    #[test]
+    fn test_write_invalid_negative() {
+        let mut encoded_cbor = vec![0x12];
+        assert!(!write(
+            Value::KeyValue(KeyType::Negative(0)),
+            &mut encoded_cbor
+        ));
+        assert!(!write(
+            cbor_array![1, Value::KeyValue(KeyType::Negative(5))],
+            &mut encoded_cbor
+        ));
+        assert_eq!(encoded_cbor, vec![0x12]);
+    }
+
+    #[test]
+    fn test_write_failure_keeps_output() {
+        let mut encoded_cbor = vec![0x12];
+        assert!(!write_nested(
+            cbor_array![1, 2, cbor_array![3]],
+            &mut encoded_cbor,
+            1
+        ));
+        assert_eq!(encoded_cbor, vec![0x12]);
+        assert!(write_nested(
+            cbor_array![1, 2, cbor_array![3]],
+            &mut encoded_cbor,
+            2
+        ));
+        assert_eq!(encoded_cbor, vec![0x12, 0x83, 0x01, 0x02, 0x81, 0x03]);
+    }
+
+    #[test]
+    fn test_encoded_len() {
+        let values = vec![
+            cbor_int!(0),
+            cbor_int!(24),
+            cbor_int!(-25),
+            cbor_int!(0x1_0000),
+            cbor_int!(i64::MAX),
+            cbor_int!(i64::MIN),
+            cbor_bytes!(vec![0x5C; 300]),
+            cbor_text!("a"),
+            cbor_false!(),
+            cbor_null!(),
+            cbor_array![],
+            cbor_array_vec!(vec![1; 24]),
+            cbor_map! {
+                1 => cbor_array!["b", cbor_array![0x01]],
+                -1 => cbor_map! {"c" => cbor_true!()},
+                "d" => cbor_map! {},
+            },
+        ];
+        for value in values {
+            let length = encoded_len(&value);
+            let mut encoded_cbor = Vec::new();
+            assert!(write(value, &mut encoded_cbor));
+            assert_eq!(length, Some(encoded_cbor.len()));
+            // The output is allocated exactly once.
+            assert_eq!(encoded_cbor.capacity(), encoded_cbor.len());
+        }
+    }
+
+    #[test]
+    fn test_encoded_len_failure() {
+        let value = cbor_array![1, 2, cbor_array![3]];
+        assert_eq!(encoded_len_nested(&value, 1), None);
+        assert_eq!(encoded_len_nested(&value, 2), Some(5));
+        let value = cbor_map! {"a" => 1};
+        assert_eq!(encoded_len_nested(&value, 0), None);
+        assert_eq!(encoded_len_nested(&value, 1), Some(4));
+        assert_eq!(encoded_len_nested(&cbor_map! {}, 0), Some(1));
+        assert_eq!(encoded_len(&Value::KeyValue(KeyType::Negative(0))), None);
+    }
+
+    #[test]
+    fn test_write_is_canonical() {
+        // The reader rejects non-canonical encodings, so a round trip proves canonicity.
+        let values = vec![
+            cbor_int!(0),
+            cbor_int!(i64::MAX),
+            cbor_int!(i64::MIN),
+            cbor_bytes!(vec![0x55; 300]),
+            cbor_text!("\u{e9}t\u{e9}"),
+            cbor_array![cbor_true!(), cbor_null!(), cbor_undefined!()],
+            cbor_map! {
+                "aa" => 1,
+                "b" => 2,
+                b"a" => 3,
+                -1 => 4,
+                24 => 5,
+                1 => cbor_map! {
+                    "x" => cbor_array![],
+                    0 => cbor_false!(),
+                },
+            },
+        ];
+        for value in values {
+            let encoded_cbor = write_return(value.clone()).unwrap();
+            assert_eq!(crate::read(&encoded_cbor), Ok(value));
+        }
+    }
+
+    #[test]
+    fn test_map_builder() {
+        let built_map = MapBuilder::new()
+            .insert("b", 2)
+            .insert_option("c", None::<i64>)
+            .insert(-1, cbor_array![])
+            .insert_option("a", Some(1))
+            .insert(3, true)
+            .build();
+        let expected_map = cbor_map! {
+            3 => true,
+            -1 => cbor_array![],
+            "a" => 1,
+            "b" => 2,
+        };
+        assert_eq!(built_map, expected_map);
+        assert_eq!(
+            write_return(built_map),
+            Some(vec![
+                0xA4, 0x03, 0xF5, 0x20, 0x80, 0x61, 0x61, 0x01, 0x61, 0x62, 0x02
+            ])
+        );
+        assert_eq!(MapBuilder::new().build(), cbor_map! {});
+    }
+
+    #[test]
+    #[should_panic]
+    #[cfg(debug_assertions)]
+    fn test_map_builder_duplicate_key() {
+        MapBuilder::new().insert(1, 1).insert(1, 2);
+    }
+
     #[test]
     fn test_write_overly_nested() {
         let cbor_map: Value = cbor_map! {
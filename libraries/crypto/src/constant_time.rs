@@ -0,0 +1,78 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use subtle::ConstantTimeEq;
+
+/// Returns whether both byte slices are equal.
+///
+/// The running time only depends on the lengths of the slices, not on their content. Slices of
+/// different lengths are never equal.
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && bool::from(a.ct_eq(b))
+}
+
+/// Secret bytes that can only be compared in constant time.
+///
+/// This type intentionally implements neither `PartialEq` nor `Debug`, so that a secret can't
+/// accidentally be compared with `==` (which returns at the first mismatching byte) or printed.
+///
+/// ```compile_fail
+/// use crypto::constant_time::Secret;
+///
+/// let secret = Secret::new([0u8; 16]);
+/// assert!(secret == Secret::new([0u8; 16]));
+/// ```
+#[derive(Clone, Copy)]
+pub struct Secret<T: AsRef<[u8]>>(T);
+
+impl<T: AsRef<[u8]>> Secret<T> {
+    pub fn new(value: T) -> Secret<T> {
+        Secret(value)
+    }
+
+    /// Returns whether the secret is equal to the given bytes, in constant time.
+    pub fn ct_eq(&self, other: &[u8]) -> bool {
+        ct_eq(self.0.as_ref(), other)
+    }
+
+    /// Gives access to the secret value, e.g. to store it.
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ct_eq() {
+        assert!(ct_eq(&[], &[]));
+        assert!(ct_eq(&[0x01, 0x02], &[0x01, 0x02]));
+        assert!(!ct_eq(&[0x01, 0x02], &[0x01, 0x03]));
+        assert!(!ct_eq(&[0x01, 0x02], &[0x01]));
+        assert!(!ct_eq(&[0x01], &[0x01, 0x02]));
+    }
+
+    #[test]
+    fn test_secret_ct_eq() {
+        let secret = Secret::new([0x55; 16]);
+        assert!(secret.ct_eq(&[0x55; 16]));
+        assert!(!secret.ct_eq(&[0x55; 15]));
+        let mut other = [0x55; 16];
+        other[15] = 0x56;
+        assert!(!secret.ct_eq(&other));
+        assert_eq!(secret.expose(), &[0x55; 16]);
+    }
+}
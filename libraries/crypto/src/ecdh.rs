@@ -51,15 +51,11 @@ impl SecKey {
         // At this point, the PubKey type guarantees that other.p is a valid point on the curve.
         // It's the responsibility of the caller to handle errors when converting serialized bytes
         // to a PubKey.
+        // The exchanged point is never infinite: the P-256 curve has a prime order and a cofactor
+        // of 1, so any valid point other than infinity generates the whole group, and the secret
+        // exponent is non-zero and smaller than the group order. See also section 3.2.2.1 of
+        // https://www.secg.org/sec1-v2.pdf for the validation of public keys.
         other.p.mul(self.a.as_exponent())
-        // TODO: Do we need to check that the exchanged point is not infinite, and if yes handle
-        // the error? The following argument should be reviewed:
-        //
-        // In principle this isn't needed on the P-256 curve, which has a prime order and a
-        // cofactor of 1.
-        //
-        // Some pointers on this:
-        // - https://www.secg.org/sec1-v2.pdf
     }
 
     // DH key agreement method defined in the FIDO2 specification, Section 5.5.4. "Getting
@@ -83,6 +79,9 @@ impl PubKey {
         self.p.to_bytes_uncompressed(bytes);
     }
 
+    // Returns None unless both coordinates are in the field and the point is on the curve. This
+    // rules out points on the twist of the curve, as well as the point at infinity, which has no
+    // affine coordinates.
     pub fn from_coordinates(x: &[u8; NBYTES], y: &[u8; NBYTES]) -> Option<PubKey> {
         PointP256::new_checked_vartime(Int256::from_bin(x), Int256::from_bin(y))
             .map(|p| PubKey { p })
@@ -150,5 +149,66 @@ mod test {
         }
     }
 
-    // TODO: tests with invalid public shares.
+    /** Test that invalid public shares are rejected **/
+    const GENERATOR_X: [u8; NBYTES] = [
+        0x6B, 0x17, 0xD1, 0xF2, 0xE1, 0x2C, 0x42, 0x47, 0xF8, 0xBC, 0xE6, 0xE5, 0x63, 0xA4, 0x40,
+        0xF2, 0x77, 0x03, 0x7D, 0x81, 0x2D, 0xEB, 0x33, 0xA0, 0xF4, 0xA1, 0x39, 0x45, 0xD8, 0x98,
+        0xC2, 0x96,
+    ];
+    const GENERATOR_Y: [u8; NBYTES] = [
+        0x4F, 0xE3, 0x42, 0xE2, 0xFE, 0x1A, 0x7F, 0x9B, 0x8E, 0xE7, 0xEB, 0x4A, 0x7C, 0x0F, 0x9E,
+        0x16, 0x2B, 0xCE, 0x33, 0x57, 0x6B, 0x31, 0x5E, 0xCE, 0xCB, 0xB6, 0x40, 0x68, 0x37, 0xBF,
+        0x51, 0xF5,
+    ];
+    // The y-coordinate of the inverse of the generator, i.e. p - GENERATOR_Y.
+    const GENERATOR_NEG_Y: [u8; NBYTES] = [
+        0xB0, 0x1C, 0xBD, 0x1C, 0x01, 0xE5, 0x80, 0x65, 0x71, 0x18, 0x14, 0xB5, 0x83, 0xF0, 0x61,
+        0xE9, 0xD4, 0x31, 0xCC, 0xA9, 0x94, 0xCE, 0xA1, 0x31, 0x34, 0x49, 0xBF, 0x97, 0xC8, 0x40,
+        0xAE, 0x0A,
+    ];
+    const FIELD_PRIME: [u8; NBYTES] = [
+        0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFF, 0xFF,
+    ];
+    // The point (1, TWIST_Y) is on the quadratic twist -y^2 = x^3 - 3x + b, not on the curve.
+    const TWIST_Y: [u8; NBYTES] = [
+        0xDE, 0x17, 0xB2, 0x67, 0x10, 0x45, 0xFE, 0x58, 0x74, 0x02, 0x48, 0xC1, 0x7A, 0x8B, 0xD2,
+        0xFB, 0x2E, 0xF7, 0xC3, 0xC4, 0x9B, 0x01, 0xDA, 0x9F, 0x90, 0x3E, 0xFA, 0x3D, 0xCD, 0xA6,
+        0x69, 0xC7,
+    ];
+
+    #[test]
+    fn test_from_coordinates_valid_points() {
+        assert!(PubKey::from_coordinates(&GENERATOR_X, &GENERATOR_Y).is_some());
+        assert!(PubKey::from_coordinates(&GENERATOR_X, &GENERATOR_NEG_Y).is_some());
+    }
+
+    #[test]
+    fn test_from_coordinates_off_curve() {
+        let mut y = GENERATOR_Y;
+        y[NBYTES - 1] ^= 0x01;
+        assert!(PubKey::from_coordinates(&GENERATOR_X, &y).is_none());
+        assert!(PubKey::from_coordinates(&GENERATOR_Y, &GENERATOR_X).is_none());
+    }
+
+    #[test]
+    fn test_from_coordinates_twist() {
+        let mut x = [0x00; NBYTES];
+        x[NBYTES - 1] = 0x01;
+        assert!(PubKey::from_coordinates(&x, &TWIST_Y).is_none());
+    }
+
+    #[test]
+    fn test_from_coordinates_infinity() {
+        let zero = [0x00; NBYTES];
+        assert!(PubKey::from_coordinates(&zero, &zero).is_none());
+    }
+
+    #[test]
+    fn test_from_coordinates_out_of_field() {
+        assert!(PubKey::from_coordinates(&FIELD_PRIME, &GENERATOR_Y).is_none());
+        assert!(PubKey::from_coordinates(&GENERATOR_X, &FIELD_PRIME).is_none());
+        assert!(PubKey::from_coordinates(&GENERATOR_X, &[0xFF; NBYTES]).is_none());
+    }
 }
@@ -215,7 +215,6 @@ impl Signature {
 
 impl PubKey {
     pub const ES256_ALGORITHM: i64 = -7;
-    #[cfg(feature = "with_ctap1")]
     const UNCOMPRESSED_LENGTH: usize = 1 + 2 * int256::NBYTES;
 
     #[cfg(feature = "std")]
@@ -228,7 +227,6 @@ impl PubKey {
         self.p.to_bytes_uncompressed(bytes);
     }
 
-    #[cfg(feature = "with_ctap1")]
     pub fn to_uncompressed(&self) -> [u8; PubKey::UNCOMPRESSED_LENGTH] {
         // Formatting according to:
         // https://tools.ietf.org/id/draft-jivsov-ecc-compact-05.html#overview
@@ -12,9 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::{Hash256, HashBlockSize64Bytes};
-use arrayref::array_ref;
-use subtle::ConstantTimeEq;
+use super::{constant_time, Hash256, HashBlockSize64Bytes};
 
 const BLOCK_SIZE: usize = 64;
 const HASH_SIZE: usize = 32;
@@ -24,7 +22,7 @@ where
     H: Hash256 + HashBlockSize64Bytes,
 {
     let expected_mac = hmac_256::<H>(key, contents);
-    bool::from(expected_mac.ct_eq(mac))
+    constant_time::ct_eq(&expected_mac, mac)
 }
 
 // FIDO2's PIN verification is just matching the first 16 bytes of the HMAC
@@ -34,7 +32,7 @@ where
     H: Hash256 + HashBlockSize64Bytes,
 {
     let expected_mac = hmac_256::<H>(key, contents);
-    bool::from(array_ref![expected_mac, 0, 16].ct_eq(pin))
+    constant_time::ct_eq(&expected_mac[..16], pin)
 }
 
 pub fn hmac_256<H>(key: &[u8], contents: &[u8]) -> [u8; HASH_SIZE]
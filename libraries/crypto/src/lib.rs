@@ -19,10 +19,12 @@ extern crate alloc;
 
 pub mod aes256;
 pub mod cbc;
+pub mod constant_time;
 mod ec;
 pub mod ecdh;
 pub mod ecdsa;
 pub mod hmac;
+pub mod provider;
 pub mod rng256;
 pub mod sha256;
 pub mod util;
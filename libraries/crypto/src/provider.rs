@@ -0,0 +1,284 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::{aes256, ecdsa, sha256};
+use super::{Decrypt16BytesBlock, Encrypt16BytesBlock, Hash256, HashBlockSize64Bytes};
+
+// Trait for the implementations of the primitives that dominate the latency of CTAP commands.
+//
+// The software provider is a pure Rust implementation that works on every board. A board with a
+// hardware accelerator (e.g. the nRF52840 CryptoCell) can implement this trait with calls to the
+// driver exposing the accelerator, and select its provider at compile time.
+//
+// Primitives built on top of SHA-256 default to the hash of the provider. A board with only a hash
+// accelerator gets it in the signatures of the authenticator data, and keeps the software for the
+// rest.
+pub trait CryptoProvider {
+    type Sha256: Hash256 + HashBlockSize64Bytes;
+    type Aes256EncryptionKey: Encrypt16BytesBlock;
+    type Aes256DecryptionKey: Decrypt16BytesBlock;
+
+    fn aes256_encryption_key(key: &[u8; 32]) -> Self::Aes256EncryptionKey;
+    fn aes256_decryption_key(key: &Self::Aes256EncryptionKey) -> Self::Aes256DecryptionKey;
+
+    // Deterministic ECDSA signature with SHA-256, as defined by RFC 6979.
+    //
+    // The message and the nonce are hashed with the SHA-256 of the provider.
+    fn ecdsa_sign(key: &ecdsa::SecKey, msg: &[u8]) -> ecdsa::Signature {
+        key.sign_rfc6979::<Self::Sha256>(msg)
+    }
+}
+
+pub struct SoftwareCryptoProvider;
+
+impl CryptoProvider for SoftwareCryptoProvider {
+    type Sha256 = sha256::Sha256;
+    type Aes256EncryptionKey = aes256::EncryptionKey;
+    type Aes256DecryptionKey = aes256::DecryptionKey;
+
+    fn aes256_encryption_key(key: &[u8; 32]) -> aes256::EncryptionKey {
+        aes256::EncryptionKey::new(key)
+    }
+
+    fn aes256_decryption_key(key: &aes256::EncryptionKey) -> aes256::DecryptionKey {
+        aes256::DecryptionKey::new(key)
+    }
+}
+
+// Provider for desktop builds, delegating to the ring crate where it exposes the primitive.
+//
+// This speeds up the emulator and the tests, and cross-validates the software implementation. The
+// ring crate has no raw AES block cipher and only randomized ECDSA signatures, so these primitives
+// still use the software implementation, with the hash of ring for signatures.
+#[cfg(feature = "std")]
+pub struct HostCryptoProvider;
+
+#[cfg(feature = "std")]
+impl CryptoProvider for HostCryptoProvider {
+    type Sha256 = HostSha256;
+    type Aes256EncryptionKey = aes256::EncryptionKey;
+    type Aes256DecryptionKey = aes256::DecryptionKey;
+
+    fn aes256_encryption_key(key: &[u8; 32]) -> aes256::EncryptionKey {
+        SoftwareCryptoProvider::aes256_encryption_key(key)
+    }
+
+    fn aes256_decryption_key(key: &aes256::EncryptionKey) -> aes256::DecryptionKey {
+        SoftwareCryptoProvider::aes256_decryption_key(key)
+    }
+}
+
+#[cfg(feature = "std")]
+pub struct HostSha256 {
+    context: ring::digest::Context,
+}
+
+#[cfg(feature = "std")]
+impl Hash256 for HostSha256 {
+    fn new() -> Self {
+        HostSha256 {
+            context: ring::digest::Context::new(&ring::digest::SHA256),
+        }
+    }
+
+    fn update(&mut self, contents: &[u8]) {
+        self.context.update(contents);
+    }
+
+    fn finalize(self) -> [u8; 32] {
+        let mut digest = [0; 32];
+        digest.copy_from_slice(self.context.finish().as_ref());
+        digest
+    }
+}
+
+// The ring crate doesn't expose the compression function. Users of this trait only rely on the
+// block size, so the software compression function is good enough.
+#[cfg(feature = "std")]
+impl HashBlockSize64Bytes for HostSha256 {
+    type State = <sha256::Sha256 as HashBlockSize64Bytes>::State;
+
+    fn hash_block(state: &mut Self::State, block: &[u8; 64]) {
+        sha256::Sha256::hash_block(state, block)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::rng256::ThreadRng256;
+    use super::*;
+
+    #[test]
+    fn test_software_aes256_round_trip() {
+        let key = [0x42; 32];
+        let enc_key = SoftwareCryptoProvider::aes256_encryption_key(&key);
+        let dec_key = SoftwareCryptoProvider::aes256_decryption_key(&enc_key);
+        let plaintext = [0x11; 16];
+        let mut block = plaintext;
+        enc_key.encrypt_block(&mut block);
+        assert_ne!(block, plaintext);
+        dec_key.decrypt_block(&mut block);
+        assert_eq!(block, plaintext);
+    }
+
+    #[test]
+    fn test_software_ecdsa_sign_verify() {
+        let mut rng = ThreadRng256 {};
+        let sk = ecdsa::SecKey::gensk(&mut rng);
+        let pk = sk.genpk();
+        let msg = b"message";
+        let sign = SoftwareCryptoProvider::ecdsa_sign(&sk, msg);
+        type Sha256 = <SoftwareCryptoProvider as CryptoProvider>::Sha256;
+        assert!(pk.verify_vartime::<Sha256>(msg, &sign));
+    }
+
+    /** Differential tests against the ring crate on random inputs **/
+    #[cfg(feature = "std")]
+    mod differential {
+        use super::super::super::rng256::Rng256;
+        use super::super::super::{ecdh, hmac};
+        use super::*;
+        use arrayref::{array_ref, mut_array_refs};
+        use rand::Rng;
+
+        // Run more test iterations in release mode, as the code should be faster.
+        #[cfg(not(debug_assertions))]
+        const ITERATIONS: u32 = 10000;
+        #[cfg(debug_assertions)]
+        const ITERATIONS: u32 = 500;
+
+        fn random_bytes(max_length: usize) -> Vec<u8> {
+            let mut rng = rand::thread_rng();
+            let mut bytes = vec![0; rng.gen_range(0, max_length + 1)];
+            rng.fill(&mut bytes[..]);
+            bytes
+        }
+
+        #[test]
+        fn test_sha256_matches_ring() {
+            for _ in 0..ITERATIONS {
+                let msg = random_bytes(300);
+                let digest = sha256::Sha256::hash(&msg);
+                assert_eq!(HostSha256::hash(&msg), digest);
+                assert_eq!(
+                    ring::digest::digest(&ring::digest::SHA256, &msg).as_ref(),
+                    &digest[..]
+                );
+            }
+        }
+
+        #[test]
+        fn test_sha256_split_updates_match_ring() {
+            for _ in 0..ITERATIONS {
+                let msg = random_bytes(300);
+                let split = rand::thread_rng().gen_range(0, msg.len() + 1);
+                let mut software = sha256::Sha256::new();
+                let mut host = HostSha256::new();
+                software.update(&msg[..split]);
+                host.update(&msg[..split]);
+                software.update(&msg[split..]);
+                host.update(&msg[split..]);
+                assert_eq!(host.finalize(), software.finalize());
+            }
+        }
+
+        #[test]
+        fn test_hmac_256_matches_ring() {
+            for _ in 0..ITERATIONS {
+                let key = random_bytes(100);
+                let msg = random_bytes(300);
+                let mac = hmac::hmac_256::<sha256::Sha256>(&key, &msg);
+                assert_eq!(hmac::hmac_256::<HostSha256>(&key, &msg), mac);
+                let ring_key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &key);
+                assert_eq!(ring::hmac::sign(&ring_key, &msg).as_ref(), &mac[..]);
+            }
+        }
+
+        // Signatures are deterministic, so hashing with ring must give the same signature.
+        #[test]
+        fn test_ecdsa_sign_matches_ring() {
+            let mut rng = ThreadRng256 {};
+            for _ in 0..ITERATIONS {
+                let sk = ecdsa::SecKey::gensk(&mut rng);
+                let msg = random_bytes(300);
+                let sign = HostCryptoProvider::ecdsa_sign(&sk, &msg);
+                assert_eq!(
+                    sign.to_asn1_der(),
+                    SoftwareCryptoProvider::ecdsa_sign(&sk, &msg).to_asn1_der()
+                );
+                let ring_pk = ring::signature::UnparsedPublicKey::new(
+                    &ring::signature::ECDSA_P256_SHA256_ASN1,
+                    sk.genpk().to_uncompressed().to_vec(),
+                );
+                assert!(ring_pk.verify(&msg, &sign.to_asn1_der()).is_ok());
+            }
+        }
+
+        // The ring crate has no raw AES API, but QUIC header protection masks are the first 5
+        // bytes of the AES encryption of a 16-byte sample.
+        #[test]
+        fn test_aes256_matches_ring() {
+            let mut rng = ThreadRng256 {};
+            for _ in 0..ITERATIONS {
+                let key = rng.gen_uniform_u8x32();
+                let mut block = [0; 16];
+                block.copy_from_slice(&rng.gen_uniform_u8x32()[..16]);
+                let ring_key =
+                    ring::aead::quic::HeaderProtectionKey::new(&ring::aead::quic::AES_256, &key)
+                        .unwrap();
+                let mask = ring_key.new_mask(&block).unwrap();
+                HostCryptoProvider::aes256_encryption_key(&key).encrypt_block(&mut block);
+                assert_eq!(mask, block[..5]);
+            }
+        }
+
+        #[test]
+        fn test_ecdh_matches_ring() {
+            let ring_rng = ring::rand::SystemRandom::new();
+            let mut rng = ThreadRng256 {};
+            for _ in 0..ITERATIONS {
+                let sk = ecdh::SecKey::gensk(&mut rng);
+                let mut pk_bytes = [0x04; 65];
+                {
+                    let (_, x, y) = mut_array_refs![&mut pk_bytes, 1, 32, 32];
+                    sk.genpk().to_coordinates(x, y);
+                }
+
+                let ring_sk = ring::agreement::EphemeralPrivateKey::generate(
+                    &ring::agreement::ECDH_P256,
+                    &ring_rng,
+                )
+                .unwrap();
+                let ring_pk_bytes = ring_sk.compute_public_key().unwrap();
+                let ring_pk = ecdh::PubKey::from_coordinates(
+                    array_ref![ring_pk_bytes.as_ref(), 1, 32],
+                    array_ref![ring_pk_bytes.as_ref(), 33, 32],
+                )
+                .unwrap();
+
+                let shared = ring::agreement::agree_ephemeral(
+                    ring_sk,
+                    &ring::agreement::UnparsedPublicKey::new(
+                        &ring::agreement::ECDH_P256,
+                        &pk_bytes[..],
+                    ),
+                    (),
+                    |x| Ok(sha256::Sha256::hash(x)),
+                )
+                .unwrap();
+                assert_eq!(sk.exchange_x_sha256(&ring_pk), shared);
+            }
+        }
+    }
+}
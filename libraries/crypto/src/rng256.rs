@@ -22,6 +22,12 @@ pub trait Rng256 {
     fn gen_uniform_u32x8(&mut self) -> [u32; 8] {
         bytes_to_u32(self.gen_uniform_u8x32())
     }
+
+    // Returns whether the generator passed its health tests so far. The output of an unhealthy
+    // generator must not be used. Generators without health tests are always healthy.
+    fn is_healthy(&self) -> bool {
+        true
+    }
 }
 
 // The TockOS rng driver fills a buffer of bytes, but we need 32-bit words for ECDSA.
@@ -48,6 +54,87 @@ impl Rng256 for TockRng256 {
     }
 }
 
+// Continuous health tests of NIST SP 800-90B section 4.4, run on each output byte of an entropy
+// source. The cutoffs assume a min-entropy of 4 bits per byte and a false positive probability of
+// 2^-20 per sample:
+// - The repetition count test fails if a byte is repeated 1 + ceil(20 / 4) = 6 times in a row.
+// - The adaptive proportion test fails if the first byte of a 512-byte window appears 62 times in
+//   that window, which is 1 + CritBinom(512, 2^-4, 1 - 2^-20).
+const REPETITION_COUNT_CUTOFF: usize = 6;
+const ADAPTIVE_PROPORTION_WINDOW: usize = 512;
+const ADAPTIVE_PROPORTION_CUTOFF: usize = 62;
+
+// Wraps an entropy source with continuous health tests. A failure is permanent: once a test
+// failed, the generator reports itself as unhealthy until it is recreated, usually at reboot.
+pub struct HealthCheckedRng256<R: Rng256> {
+    rng: R,
+    // The last byte and how many times it was repeated in a row.
+    last_byte: u8,
+    repetitions: usize,
+    // The first byte of the current window, how many times it appeared in the window, and how
+    // many bytes of the window were seen.
+    window_byte: u8,
+    window_matches: usize,
+    window_length: usize,
+    healthy: bool,
+}
+
+impl<R: Rng256> HealthCheckedRng256<R> {
+    pub fn new(rng: R) -> HealthCheckedRng256<R> {
+        HealthCheckedRng256 {
+            rng,
+            last_byte: 0,
+            repetitions: 0,
+            window_byte: 0,
+            window_matches: 0,
+            window_length: 0,
+            healthy: true,
+        }
+    }
+
+    // Runs both health tests on the next byte of the entropy source.
+    fn check_byte(&mut self, byte: u8) {
+        if self.repetitions > 0 && byte == self.last_byte {
+            self.repetitions += 1;
+        } else {
+            self.last_byte = byte;
+            self.repetitions = 1;
+        }
+        if self.repetitions >= REPETITION_COUNT_CUTOFF {
+            self.healthy = false;
+        }
+
+        if self.window_length == ADAPTIVE_PROPORTION_WINDOW {
+            self.window_length = 0;
+        }
+        if self.window_length == 0 {
+            self.window_byte = byte;
+            self.window_matches = 0;
+        }
+        self.window_length += 1;
+        if byte == self.window_byte {
+            self.window_matches += 1;
+        }
+        if self.window_matches >= ADAPTIVE_PROPORTION_CUTOFF {
+            self.healthy = false;
+        }
+    }
+}
+
+impl<R: Rng256> Rng256 for HealthCheckedRng256<R> {
+    fn gen_uniform_u8x32(&mut self) -> [u8; 32] {
+        let bytes = self.rng.gen_uniform_u8x32();
+        for &byte in bytes.iter() {
+            self.check_byte(byte);
+        }
+        bytes
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy
+    }
+}
+
 // For tests on the desktop, we use the cryptographically secure thread rng as entropy source.
 #[cfg(feature = "std")]
 pub struct ThreadRng256 {}
@@ -89,4 +176,58 @@ pub mod test {
 
         assert_eq!(bytes_to_u32(*bytes), expected);
     }
+
+    // Generates the bytes of a sequence, repeated forever.
+    struct SequenceRng256 {
+        sequence: &'static [u8],
+        index: usize,
+    }
+
+    impl Rng256 for SequenceRng256 {
+        fn gen_uniform_u8x32(&mut self) -> [u8; 32] {
+            let mut result = [0; 32];
+            for byte in result.iter_mut() {
+                *byte = self.sequence[self.index % self.sequence.len()];
+                self.index += 1;
+            }
+            result
+        }
+    }
+
+    #[test]
+    fn test_health_checked_thread_rng() {
+        let mut rng = HealthCheckedRng256::new(ThreadRng256 {});
+        for _ in 0..1000 {
+            rng.gen_uniform_u8x32();
+        }
+        assert!(rng.is_healthy());
+    }
+
+    #[test]
+    fn test_health_checked_repetition_count() {
+        let mut rng = HealthCheckedRng256::new(SequenceRng256 {
+            sequence: &[0x00, 0x01, 0x02, 0x03, 0x03, 0x03, 0x03, 0x03, 0x03, 0x04],
+            index: 0,
+        });
+        assert!(rng.is_healthy());
+        rng.gen_uniform_u8x32();
+        assert!(!rng.is_healthy());
+    }
+
+    #[test]
+    fn test_health_checked_adaptive_proportion() {
+        // This sequence never repeats a byte, but the window starts with a byte that appears every
+        // other byte.
+        let mut rng = HealthCheckedRng256::new(SequenceRng256 {
+            sequence: &[0x00, 0x01, 0x00, 0x02, 0x00, 0x03, 0x00, 0x04],
+            index: 0,
+        });
+        // The cutoff is reached after 2 * 61 + 1 = 123 bytes, in the 4th call.
+        for _ in 0..3 {
+            rng.gen_uniform_u8x32();
+        }
+        assert!(rng.is_healthy());
+        rng.gen_uniform_u8x32();
+        assert!(!rng.is_healthy());
+    }
 }
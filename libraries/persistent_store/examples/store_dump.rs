@@ -0,0 +1,172 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Prints the entries of a store from a raw flash dump.
+//!
+//! The dump is mounted read-only, such that what is printed is exactly what the device would read.
+//! A store that needs to write to boot (for example to complete an interrupted operation) can be
+//! mounted with `--recover`, which applies the writes in memory only. The dump file is never
+//! modified.
+//!
+//! Usage: `cargo run --features=std --example=store_dump -- [--page-size=BYTES] [--json]
+//! [--recover] FILE`
+
+use persistent_store::{BufferOptions, BufferStorage, Store, StoreError, StoreHandle};
+use std::process::exit;
+
+/// Configuration, built from the command-line arguments.
+struct Config {
+    /// Path to the raw flash dump of the store pages.
+    dump: String,
+    /// Size of a flash page in bytes.
+    page_size: usize,
+    /// Whether entries are printed as JSON.
+    json: bool,
+    /// Whether the store may write (in memory) to boot.
+    recover: bool,
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: store_dump [--page-size=BYTES] [--json] [--recover] FILE");
+    exit(2);
+}
+
+fn parse_args() -> Config {
+    let mut dump = None;
+    let mut page_size = 0x1000;
+    let mut json = false;
+    let mut recover = false;
+    for arg in std::env::args().skip(1) {
+        if let Some(value) = arg.strip_prefix("--page-size=") {
+            page_size = match value.strip_prefix("0x") {
+                Some(hex) => usize::from_str_radix(hex, 16),
+                None => value.parse(),
+            }
+            .unwrap_or_else(|_| usage());
+        } else if arg == "--json" {
+            json = true;
+        } else if arg == "--recover" {
+            recover = true;
+        } else if arg.starts_with("--") || dump.is_some() {
+            usage();
+        } else {
+            dump = Some(arg);
+        }
+    }
+    Config {
+        dump: dump.unwrap_or_else(|| usage()),
+        page_size,
+        json,
+        recover,
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn mount(config: &Config) -> Result<Store<BufferStorage>, StoreError> {
+    let content = std::fs::read(&config.dump).unwrap_or_else(|error| {
+        eprintln!("Couldn't read {}: {}", config.dump, error);
+        exit(1);
+    });
+    if !config.page_size.is_power_of_two() || content.is_empty() {
+        usage();
+    }
+    if content.len() % config.page_size != 0 {
+        eprintln!(
+            "The dump has {} bytes, which is not a multiple of the page size {}.",
+            content.len(),
+            config.page_size
+        );
+        exit(1);
+    }
+    // The options of the nRF52840 flash. The counters are not checked, since the dump doesn't
+    // contain them.
+    let options = BufferOptions {
+        word_size: 4,
+        page_size: config.page_size,
+        max_word_writes: 2,
+        max_page_erases: 10000,
+        strict_mode: false,
+    };
+    let mut storage = BufferStorage::new(content.into_boxed_slice(), options);
+    storage.set_read_only(!config.recover);
+    Store::new(storage).map_err(|(error, _)| error)
+}
+
+fn print_entries(store: &Store<BufferStorage>, handles: &[StoreHandle]) {
+    for handle in handles {
+        let key = handle.get_key();
+        match handle.get_value(store) {
+            Ok(value) => println!("{:4} ({} bytes): {}", key, value.len(), to_hex(&value)),
+            Err(error) => println!("{:4}: {:?}", key, error),
+        }
+    }
+    let capacity = store.capacity().unwrap();
+    println!(
+        "Capacity: {} of {} words used",
+        capacity.used(),
+        capacity.total()
+    );
+}
+
+fn print_json(store: &Store<BufferStorage>, handles: &[StoreHandle]) {
+    let entries: Vec<String> = handles
+        .iter()
+        .map(|handle| {
+            let key = handle.get_key();
+            match handle.get_value(store) {
+                Ok(value) => format!(
+                    "{{\"key\": {}, \"length\": {}, \"value\": \"{}\"}}",
+                    key,
+                    value.len(),
+                    to_hex(&value)
+                ),
+                Err(error) => format!("{{\"key\": {}, \"error\": \"{:?}\"}}", key, error),
+            }
+        })
+        .collect();
+    let capacity = store.capacity().unwrap();
+    println!(
+        "{{\"entries\": [{}], \"capacity\": {{\"used\": {}, \"total\": {}}}}}",
+        entries.join(", "),
+        capacity.used(),
+        capacity.total()
+    );
+}
+
+fn main() {
+    let config = parse_args();
+    let store = mount(&config).unwrap_or_else(|error| {
+        eprintln!("Couldn't mount the store: {:?}", error);
+        if error == StoreError::StorageError && !config.recover {
+            eprintln!("The store needs to write to boot, try again with --recover.");
+        }
+        exit(1);
+    });
+    let mut handles = store
+        .iter()
+        .and_then(|iter| iter.collect::<Result<Vec<_>, _>>())
+        .unwrap_or_else(|error| {
+            eprintln!("Couldn't iterate the store: {:?}", error);
+            exit(1);
+        });
+    handles.sort_by_key(|handle| handle.get_key());
+    if config.json {
+        print_json(&store, &handles);
+    } else {
+        print_entries(&store, &handles);
+    }
+}
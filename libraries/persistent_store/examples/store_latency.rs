@@ -0,0 +1,136 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Simulates the flash latency of store operations on the host.
+//!
+//! This mirrors the `store_latency` example of the firmware, which measures the latency on an
+//! nrf52840dk. Only the time spent in flash operations is simulated, so boot (which mostly parses
+//! the storage) is not comparable.
+
+use persistent_store::{BufferOptions, BufferStorage, Store, TimedOptions, TimedStorage};
+use std::time::Duration;
+
+fn new_store(num_pages: usize) -> Store<TimedStorage<BufferStorage>> {
+    let options = BufferOptions {
+        word_size: 4,
+        page_size: 0x1000,
+        max_word_writes: 2,
+        max_page_erases: 10000,
+        strict_mode: true,
+    };
+    let storage = vec![0xff; num_pages * options.page_size].into_boxed_slice();
+    let storage = TimedStorage::new(BufferStorage::new(storage, options), TimedOptions::NRF52840);
+    Store::new(storage).ok().unwrap()
+}
+
+fn measure<T>(
+    store: &mut Store<TimedStorage<BufferStorage>>,
+    operation: impl FnOnce(&mut Store<TimedStorage<BufferStorage>>) -> T,
+) -> (T, Duration) {
+    store.storage_mut().reset();
+    let result = operation(store);
+    (result, store.storage().elapsed())
+}
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn compute_latency(num_pages: usize, key_increment: usize, word_length: usize) {
+    println!(
+        "\nLatency for num_pages={} key_increment={} word_length={}.",
+        num_pages, key_increment, word_length
+    );
+
+    let mut store = new_store(num_pages);
+    let total_capacity = store.capacity().unwrap().total();
+    assert_eq!(store.capacity().unwrap().used(), 0);
+    assert_eq!(store.lifetime().unwrap().used(), 0);
+
+    // Burn N words to align the end of the user capacity with the virtual capacity.
+    store.insert(0, &vec![0; 4 * (num_pages - 1)]).unwrap();
+    store.remove(0).unwrap();
+    assert_eq!(store.capacity().unwrap().used(), 0);
+    assert_eq!(store.lifetime().unwrap().used(), num_pages);
+
+    // Insert entries until there is space for one more.
+    let count = total_capacity / (1 + word_length) - 1;
+    let ((), time) = measure(&mut store, |store| {
+        for i in 0..count {
+            let key = 1 + key_increment * i;
+            store.insert(key, &vec![0; 4 * word_length]).unwrap();
+        }
+    });
+    println!("Setup: {:.1}ms for {} entries.", ms(time), count);
+
+    // Measure latency of insert.
+    let key = 1 + key_increment * count;
+    let ((), time) = measure(&mut store, |store| {
+        store.insert(key, &vec![0; 4 * word_length]).unwrap()
+    });
+    println!("Insert: {:.1}ms.", ms(time));
+    assert_eq!(
+        store.lifetime().unwrap().used(),
+        num_pages + (1 + count) * (1 + word_length)
+    );
+
+    // Measure latency of boot.
+    let mut storage = store.extract_storage();
+    storage.reset();
+    let mut store = Store::new(storage).ok().unwrap();
+    let time = store.storage().elapsed();
+    println!(
+        "Boot: {:.1}ms ({} words read).",
+        ms(time),
+        store.storage().word_reads()
+    );
+
+    // Measure latency of remove.
+    let ((), time) = measure(&mut store, |store| store.remove(key).unwrap());
+    println!("Remove: {:.1}ms.", ms(time));
+
+    // Measure latency of compaction.
+    let length = total_capacity + num_pages - store.lifetime().unwrap().used();
+    if length > 0 {
+        // Fill the store such that compaction is needed for one word.
+        store.insert(0, &vec![0; 4 * (length - 1)]).unwrap();
+        store.remove(0).unwrap();
+    }
+    assert!(store.capacity().unwrap().remaining() > 0);
+    assert_eq!(store.lifetime().unwrap().used(), num_pages + total_capacity);
+    let ((), time) = measure(&mut store, |store| store.prepare(1).unwrap());
+    println!("Compaction: {:.1}ms.", ms(time));
+    assert!(store.lifetime().unwrap().used() > total_capacity + num_pages);
+}
+
+fn main() {
+    println!("\nRunning 4 tests...");
+    // Those non-overwritten 50 words entries simulate credentials.
+    compute_latency(3, 1, 50);
+    compute_latency(20, 1, 50);
+    // Those overwritten 1 word entries simulate counters.
+    compute_latency(3, 0, 1);
+    compute_latency(6, 0, 1);
+    println!("\nDone.");
+
+    // Simulated results (the nrf52840dk measurements of the firmware example are comparable except
+    // for boot, which is dominated by the CPU):
+    //
+    // | Pages | Overwrite | Length    | Compaction | Insert  | Remove  |
+    // | ----- | --------- | --------- | ---------- | ------- | ------- |
+    // | 3     | no        | 50 words  | 127.0 ms   | 2.1 ms  | 0.0 ms  |
+    // | 20    | no        | 50 words  | 127.0 ms   | 2.1 ms  | 0.0 ms  |
+    // | 3     | yes       | 1 word    | 85.1 ms    | 0.1 ms  | 0.0 ms  |
+    // | 6     | yes       | 1 word    | 85.1 ms    | 0.1 ms  | 0.0 ms  |
+}
@@ -31,7 +31,7 @@ each <bucket_predicate> is <bucket_key>=<bucket_value>."#,
 
 fn debug(data: &[u8]) {
     println!("{:02x?}", data);
-    fuzz(data, true, None);
+    fuzz(data, true, None, None);
 }
 
 /// Bucket predicate.
@@ -74,7 +74,7 @@ fn analyze(corpus: &Path, predicates: Vec<Predicate>) {
     for entry in std::fs::read_dir(corpus).unwrap() {
         let data = std::fs::read(entry.unwrap().path()).unwrap();
         let mut stat = Stats::default();
-        fuzz(&data, false, Some(&mut stat));
+        fuzz(&data, false, Some(&mut stat), None);
         if predicates
             .iter()
             .all(|p| stat.get_count(p.key, p.value).is_some())
@@ -0,0 +1,87 @@
+// Copyright 2019-2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use fuzz_store::{fuzz, Trace};
+use std::path::Path;
+
+fn usage(program: &str) {
+    println!(
+        r#"Usage: {0} record <corpus_directory> <trace_directory>
+       {0} replay <trace_directory>
+       {0} storage <trace_file>
+
+Recording runs each artifact of the corpus and writes its trace (if any) in the trace directory
+under the same name. Replaying checks each trace of the trace directory against the current store.
+Traces are meant to be recorded and replayed with different versions of the store. Printing the
+storage of a trace replays its storage operations (including corruptions) on a fresh storage."#,
+        program
+    );
+}
+
+fn record(corpus: &Path, traces: &Path) {
+    std::fs::create_dir_all(traces).unwrap();
+    let mut count = 0;
+    for entry in std::fs::read_dir(corpus).unwrap() {
+        let entry = entry.unwrap();
+        let data = std::fs::read(entry.path()).unwrap();
+        let mut trace = Trace::default();
+        fuzz(&data, false, None, Some(&mut trace));
+        if trace.page_size == 0 {
+            // The artifact starts from a dirty storage.
+            continue;
+        }
+        std::fs::write(traces.join(entry.file_name()), trace.encode()).unwrap();
+        count += 1;
+    }
+    println!("Recorded {} traces.", count);
+}
+
+fn replay(traces: &Path) {
+    let mut count = 0;
+    let mut failures = 0;
+    for entry in std::fs::read_dir(traces).unwrap() {
+        let path = entry.unwrap().path();
+        let result = Trace::decode(&std::fs::read(&path).unwrap()).and_then(|x| x.replay());
+        if let Err(error) = result {
+            println!("{}: {:?}", path.display(), error);
+            failures += 1;
+        }
+        count += 1;
+    }
+    println!("Replayed {} traces with {} failures.", count, failures);
+    if failures > 0 {
+        std::process::exit(1);
+    }
+}
+
+fn storage(trace: &Path) {
+    let trace = Trace::decode(&std::fs::read(trace).unwrap()).unwrap();
+    match trace.replay_records() {
+        Ok(storage) => print!("{}", storage),
+        Err(error) => {
+            println!("{:?}", error);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    match args.iter().map(|x| x.as_str()).collect::<Vec<_>>()[..] {
+        [_, "record", corpus, traces] => record(Path::new(corpus), Path::new(traces)),
+        [_, "replay", traces] => replay(Path::new(traces)),
+        [_, "storage", trace] => storage(Path::new(trace)),
+        _ => usage(&args[0]),
+    }
+}
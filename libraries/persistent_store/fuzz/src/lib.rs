@@ -24,13 +24,21 @@
 //! - Surjectivity: all sequences of manipulations are reachable by fuzzing for some seed. The only
 //!   situation where coverage takes precedence over surjectivity is for the value of insert updates
 //!   where a pseudo-random generator is used to avoid wasting entropy.
+//!
+//! Operations are decoded with a structured format, which is more stable when the fuzzing code
+//! evolves. The bit-level format used before is still available with the `legacy_decoder` feature
+//! to replay old corpora.
 
 mod histogram;
+mod operation;
 mod stats;
 mod store;
+mod trace;
 
+use arbitrary::{Arbitrary, Unstructured};
 pub use stats::{StatKey, Stats};
-pub use store::fuzz;
+pub use store::{fuzz, fuzz_reboots, fuzz_storm};
+pub use trace::{ReplayError, Trace, TraceStep};
 
 /// Bit-level entropy source based on a byte slice shared reference.
 ///
@@ -119,6 +127,23 @@ impl Entropy<'_> {
         result
     }
 
+    /// Reads a structured value.
+    ///
+    /// The value is read from whole bytes, starting at the next byte boundary. Missing bytes are
+    /// read as zero.
+    ///
+    /// # Preconditions
+    ///
+    /// - The value should only read from the front of the data. In particular, it should not
+    ///   contain collections, because their length is read from the back of the data.
+    fn read_arbitrary<T: Arbitrary>(&mut self) -> T {
+        let byte = self.bit.div_ceil(8);
+        let mut data = Unstructured::new(&self.data[byte..]);
+        let result = T::arbitrary(&mut data).unwrap();
+        self.bit = 8 * (self.data.len() - data.len());
+        result
+    }
+
     /// Reads a number between `min` and `max` (inclusive bounds).
     ///
     /// The distribution is uniform if the range width is a power of two. Otherwise, the minimum
@@ -181,6 +206,16 @@ fn read_bits_ok() {
     assert_eq!(entropy.read_bits(2), 0b10);
 }
 
+#[test]
+fn read_arbitrary_ok() {
+    let mut entropy = Entropy::new(&[0x83, 0x92, 0x34, 0x56]);
+    assert!(entropy.read_bit());
+    assert_eq!(entropy.read_arbitrary::<u16>(), 0x3492);
+    assert_eq!(entropy.read_bits(4), 0x6);
+    assert_eq!(entropy.read_arbitrary::<(u8, u8)>(), (0x00, 0x00));
+    assert!(entropy.is_empty());
+}
+
 #[test]
 fn read_range_ok() {
     let mut entropy = Entropy::new(&[0b00101011]);
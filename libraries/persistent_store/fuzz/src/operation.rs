@@ -0,0 +1,110 @@
+// Copyright 2019-2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Structured format of the fuzzed operations.
+//!
+//! Each operation is decoded from whole bytes of the fuzzing input with [`Arbitrary`]. Compared to
+//! the legacy bit-level format, a mutation of an operation doesn't shift the decoding of the
+//! following operations, and variants may be added without changing how existing ones decode.
+//! Numbers are decoded without bounds and reduced modulo their range when converted to the store
+//! operation.
+//!
+//! The types don't contain collections, because their length is read from the end of the data.
+//! This would make operations depend on bytes far from where they are decoded. Instead, the
+//! updates of a transaction are decoded one after the other following the transaction.
+//!
+//! [`Arbitrary`]: https://docs.rs/arbitrary/0.4.7/arbitrary/trait.Arbitrary.html
+
+use arbitrary::Arbitrary;
+
+/// Possibly invalid operation.
+#[derive(Arbitrary, Debug)]
+pub enum Operation {
+    /// Transaction whose updates follow.
+    Transaction { count: u8 },
+
+    /// Clear operation.
+    Clear { min_key: Key },
+
+    /// Prepare operation.
+    Prepare { length: u16 },
+}
+
+/// Possibly invalid update.
+#[derive(Arbitrary, Debug)]
+pub enum Update {
+    /// Insert update.
+    Insert { key: Key, value: Value },
+
+    /// Remove update.
+    Remove { key: Key },
+
+    /// Insert update if the key is absent.
+    InsertIfAbsent { key: Key, value: Value },
+
+    /// Compare-and-swap update.
+    CompareAndSwap {
+        key: Key,
+
+        /// Whether the old value is the current value (if any) instead of `old`.
+        current: bool,
+
+        old: Value,
+
+        /// Whether the new value is the old value instead of `new`.
+        same: bool,
+
+        new: Value,
+    },
+
+    /// Remove range update.
+    RemoveRange { start: Key, length: u16 },
+}
+
+/// Possibly invalid key.
+#[derive(Arbitrary, Debug)]
+pub struct Key(u16);
+
+impl Key {
+    /// Returns the key.
+    pub fn get(&self) -> usize {
+        // Use 4096 as the canonical invalid key.
+        self.0 as usize % 4097
+    }
+}
+
+/// Possibly invalid value.
+///
+/// The content of values is generated with a pseudo-random generator, only their length is decoded.
+#[derive(Arbitrary, Debug)]
+pub enum Value {
+    /// Empty value.
+    ///
+    /// Empty values are used as flags, so they have their own variant.
+    Empty,
+
+    /// Value of a given length.
+    Random { length: u16 },
+}
+
+impl Value {
+    /// Returns the length of the value.
+    pub fn length(&self) -> usize {
+        match self {
+            Value::Empty => 0,
+            // Use 1024 as the canonical invalid length.
+            Value::Random { length } => *length as usize % 1025,
+        }
+    }
+}
@@ -80,8 +80,41 @@ pub enum StatKey {
     /// The number of times a remove update was applied.
     RemoveCount,
 
+    /// The number of times an insert-if-absent update was applied.
+    InsertIfAbsentCount,
+
+    /// The number of times a compare-and-swap update was applied.
+    CompareAndSwapCount,
+
+    /// The number of times a remove range update was applied.
+    RemoveRangeCount,
+
+    /// The number of times a conditional update was generated to have no effect.
+    ///
+    /// This is an insert-if-absent update of a present key or a compare-and-swap update with
+    /// identical old and new values. Such updates should not write to the storage.
+    DedupCount,
+
+    /// The number of times an operation was rejected for modifying a write-once entry.
+    WriteOnceCount,
+
+    /// The number of times an empty value was generated.
+    EmptyValueCount,
+
+    /// The number of times the store format was upgraded.
+    ///
+    /// The upgrade applies the migrations when the store is powered on. It may be interrupted, in
+    /// which case it is applied again at the next power on.
+    UpgradeCount,
+
     /// The number of times a store operation was interrupted.
     InterruptionCount,
+
+    /// The number of consecutive interrupted power-ons before a power on.
+    ///
+    /// This is the number of times recovery was re-entered without completing. It is only measured
+    /// when consecutive power-ons may be interrupted.
+    RecoveryDepth,
 }
 
 /// Statistics about multiple fuzzing runs.
@@ -12,11 +12,14 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::operation::{Operation, Update, Value};
 use crate::stats::{StatKey, Stats};
+use crate::trace::{digest, Trace, TraceStep};
 use crate::Entropy;
 use persistent_store::{
-    BufferOptions, BufferStorage, Store, StoreDriver, StoreDriverOff, StoreDriverOn,
-    StoreInterruption, StoreInvariant, StoreOperation, StoreUpdate,
+    BufferOptions, BufferStorage, Migration, Storage, Store, StoreDriver, StoreDriverOff,
+    StoreDriverOn, StoreError, StoreInterruption, StoreInvariant, StoreOperation, StoreResult,
+    StoreUpdate,
 };
 use rand_core::{RngCore, SeedableRng};
 use rand_pcg::Pcg32;
@@ -29,12 +32,74 @@ use std::convert::TryInto;
 // NOTE: Maybe we should split the fuzz target in smaller parts (like one per init). We should also
 // name the fuzz targets with action names.
 
+/// Migrations used to simulate a format upgrade.
+///
+/// The format of the store doesn't have any migration yet, so we upgrade with a migration that
+/// doesn't change the storage. This still exercises the upgrade of all pages to the new version.
+pub(crate) const MIGRATIONS: &[&dyn Migration] = &[&IdentityMigration];
+
+/// Migration to a new format version without changing the layout.
+struct IdentityMigration;
+
+impl Migration for IdentityMigration {
+    fn migrate(&self, _: &mut dyn Storage) -> StoreResult<()> {
+        Ok(())
+    }
+}
+
+/// Tells whether a key is write-once when fuzzing with write-once keys.
+///
+/// Only an eighth of the keys are write-once, such that clear and remove range operations still
+/// mostly succeed.
+pub(crate) fn is_write_once(key: usize) -> bool {
+    key < 512
+}
+
+/// Maximum number of consecutive interrupted power-ons in the reboots scenario.
+const MAX_CONSECUTIVE_REBOOTS: usize = 7;
+
+/// Maximum number of consecutive interrupted power-ons in the storm scenario.
+const MAX_STORM_REBOOTS: usize = 255;
+
 /// Checks the store against a sequence of manipulations.
 ///
 /// The entropy to generate the sequence of manipulation should be provided in `data`. Debugging
-/// information is printed if `debug` is set. Statistics are gathered if `stats` is set.
-pub fn fuzz(data: &[u8], debug: bool, stats: Option<&mut Stats>) {
-    let mut fuzzer = Fuzzer::new(data, debug, stats);
+/// information is printed if `debug` is set. Statistics are gathered if `stats` is set. A trace is
+/// recorded if `trace` is set (unless fuzzing starts from a dirty storage).
+pub fn fuzz(data: &[u8], debug: bool, stats: Option<&mut Stats>, trace: Option<&mut Trace>) {
+    run(Fuzzer::new(data, debug, stats, trace, 0));
+}
+
+/// Checks the store against a sequence of manipulations with consecutive interrupted reboots.
+///
+/// This is like [`fuzz`] but each power on may be preceded by a sequence of power-ons which are all
+/// interrupted, without a successful power on in between. This exercises the recovery of
+/// operations whose recovery was itself interrupted several times, like compactions.
+///
+/// [`fuzz`]: fn.fuzz.html
+pub fn fuzz_reboots(data: &[u8], debug: bool, stats: Option<&mut Stats>) {
+    run(Fuzzer::new(
+        data,
+        debug,
+        stats,
+        None,
+        MAX_CONSECUTIVE_REBOOTS,
+    ));
+}
+
+/// Checks the store against a sequence of manipulations with storms of interrupted reboots.
+///
+/// This is like [`fuzz_reboots`] but with up to hundreds of consecutive interrupted power-ons.
+/// Since each power-on is interrupted during recovery, this mostly exercises the recovery of
+/// partial compactions which were themselves partially recovered many times.
+///
+/// [`fuzz_reboots`]: fn.fuzz_reboots.html
+pub fn fuzz_storm(data: &[u8], debug: bool, stats: Option<&mut Stats>) {
+    run(Fuzzer::new(data, debug, stats, None, MAX_STORM_REBOOTS));
+}
+
+/// Runs a fuzzing scenario until entropy or lifetime is exhausted.
+fn run(mut fuzzer: Fuzzer) {
     let mut driver = fuzzer.init();
     let store = loop {
         if fuzzer.debug {
@@ -44,6 +109,7 @@ pub fn fuzz(data: &[u8], debug: bool, stats: Option<&mut Stats>) {
             if !fuzzer.init.is_dirty() {
                 driver.check().unwrap();
             }
+            fuzzer.settle(driver);
             if fuzzer.debug {
                 println!("----------------------------------------------------------------------");
             }
@@ -75,6 +141,13 @@ pub fn fuzz(data: &[u8], debug: bool, stats: Option<&mut Stats>) {
             StoreDriver::Off(driver) => fuzzer.power_on(driver),
         }
     };
+    if let Some(trace) = &mut fuzzer.trace {
+        if fuzzer.pending {
+            trace.steps.last_mut().unwrap().digest =
+                digest(&crate::trace::content(&store).unwrap());
+        }
+        trace.set_storage(store.storage());
+    }
     let virt_window = (store.format().num_pages() * store.format().virt_page_size()) as usize;
     let init_lifetime = fuzzer.init.used_cycles() * virt_window;
     let lifetime = store.lifetime().unwrap().used() - init_lifetime;
@@ -108,11 +181,33 @@ struct Fuzzer<'a> {
     /// The counters are written to the statistics at the end of the fuzzing run, when their value
     /// is final.
     counters: HashMap<StatKey, usize>,
+
+    /// The trace being recorded (only used when recording a trace).
+    trace: Option<&'a mut Trace>,
+
+    /// Whether the last recorded step is waiting for its digest.
+    ///
+    /// The digest of a step is known once the store is powered on after the operation.
+    pending: bool,
+
+    /// Whether the store has been upgraded to a new format version.
+    upgraded: bool,
+
+    /// Maximum number of consecutive interrupted power-ons before a power on.
+    ///
+    /// Power on is not interrupted several times in a row if this is zero.
+    max_reboots: usize,
 }
 
 impl<'a> Fuzzer<'a> {
     /// Creates an initial fuzzing state.
-    fn new(data: &'a [u8], debug: bool, stats: Option<&'a mut Stats>) -> Fuzzer<'a> {
+    fn new(
+        data: &'a [u8],
+        debug: bool,
+        stats: Option<&'a mut Stats>,
+        trace: Option<&'a mut Trace>,
+        max_reboots: usize,
+    ) -> Fuzzer<'a> {
         let mut entropy = Entropy::new(data);
         let seed = entropy.read_slice(16);
         let values = Pcg32::from_seed(seed[..].try_into().unwrap());
@@ -123,6 +218,10 @@ impl<'a> Fuzzer<'a> {
             debug,
             stats,
             counters: HashMap::new(),
+            trace,
+            pending: false,
+            upgraded: false,
+            max_reboots,
         };
         fuzzer.init_counters();
         fuzzer.record(StatKey::Entropy, data.len());
@@ -142,13 +241,18 @@ impl<'a> Fuzzer<'a> {
         self.record(StatKey::PageSize, options.page_size);
         self.record(StatKey::MaxPageErases, options.max_page_erases);
         self.record(StatKey::NumPages, num_pages);
+        if let Some(trace) = &mut self.trace {
+            trace.page_size = options.page_size;
+            trace.num_pages = num_pages;
+            trace.max_page_erases = options.max_page_erases;
+        }
         if self.debug {
             println!("page_size: {}", options.page_size);
             println!("num_pages: {}", num_pages);
             println!("max_cycle: {}", options.max_page_erases);
         }
         let storage_size = num_pages * options.page_size;
-        if self.entropy.read_bit() {
+        let mut driver = if self.entropy.read_bit() {
             self.init = Init::Dirty;
             let mut storage = vec![0xff; storage_size].into_boxed_slice();
             let length = self.entropy.read_range(0, storage_size);
@@ -160,8 +264,12 @@ impl<'a> Fuzzer<'a> {
                 println!("Start with dirty storage.");
             }
             options.strict_mode = false;
+            // There is no model to trace.
+            if let Some(trace) = self.trace.take() {
+                *trace = Trace::default();
+            }
             let storage = BufferStorage::new(storage, options);
-            StoreDriver::Off(StoreDriverOff::new_dirty(storage))
+            StoreDriverOff::new_dirty(storage)
         } else if self.entropy.read_bit() {
             let cycle = self.entropy.read_range(0, options.max_page_erases);
             self.init = Init::Used { cycle };
@@ -169,21 +277,58 @@ impl<'a> Fuzzer<'a> {
                 println!("Start with {} consumed erase cycles.", cycle);
             }
             self.record(StatKey::InitCycles, cycle);
+            if let Some(trace) = &mut self.trace {
+                trace.init_cycles = Some(cycle);
+            }
             let storage = vec![0xff; storage_size].into_boxed_slice();
             let mut storage = BufferStorage::new(storage, options);
+            if self.trace.is_some() {
+                storage.start_recording();
+            }
             Store::init_with_cycle(&mut storage, cycle);
-            StoreDriver::Off(StoreDriverOff::new_dirty(storage))
+            StoreDriverOff::new_dirty(storage)
         } else {
-            StoreDriver::Off(StoreDriverOff::new(options, num_pages))
+            let mut driver = StoreDriverOff::new(options, num_pages);
+            if self.trace.is_some() {
+                driver.storage_mut().start_recording();
+            }
+            driver
+        };
+        if self.entropy.read_bit() {
+            if self.debug {
+                println!("Use write-once keys.");
+            }
+            if let Some(trace) = &mut self.trace {
+                trace.write_once = true;
+            }
+            driver.set_write_once(Some(is_write_once));
         }
+        StoreDriver::Off(driver)
     }
 
-    /// Powers a driver with possible interruption.
-    fn power_on(&mut self, driver: StoreDriverOff) -> StoreDriver {
+    /// Powers a driver with possible interruption and format upgrade.
+    fn power_on(&mut self, mut driver: StoreDriverOff) -> StoreDriver {
         if self.debug {
             println!("Power on the store.");
         }
         self.increment(StatKey::PowerOnCount);
+        if !self.upgraded && self.entropy.read_bit() {
+            if self.debug {
+                println!("Upgrade the store format.");
+            }
+            self.increment(StatKey::UpgradeCount);
+            self.upgraded = true;
+            driver.set_migrations(MIGRATIONS);
+            if let Some(trace) = &mut self.trace {
+                trace.migrations = MIGRATIONS.len();
+            }
+        }
+        if self.max_reboots > 0 && !self.init.is_dirty() {
+            driver = match self.reboot(driver) {
+                StoreDriver::Off(driver) => driver,
+                driver => return driver,
+            };
+        }
         let interruption = self.interruption(driver.count_operations());
         match driver.partial_power_on(interruption) {
             Err((storage, _)) if self.init.is_dirty() => {
@@ -195,12 +340,47 @@ impl<'a> Fuzzer<'a> {
         }
     }
 
+    /// Powers on a driver several times in a row, interrupting each power on.
+    ///
+    /// The driver is returned powered off, unless a power on could not be interrupted.
+    fn reboot(&mut self, mut driver: StoreDriverOff) -> StoreDriver {
+        let count = self.entropy.read_range(0, self.max_reboots);
+        for depth in 0..count {
+            let max_delay = match driver.count_operations() {
+                Some(x) if x > 0 => x,
+                _ => {
+                    self.record(StatKey::RecoveryDepth, depth);
+                    break;
+                }
+            };
+            let delay = self.entropy.read_range(0, max_delay - 1);
+            if self.debug {
+                println!("Power on the store.");
+                println!("Interrupt after {} operations.", delay);
+            }
+            self.increment(StatKey::PowerOnCount);
+            self.increment(StatKey::InterruptionCount);
+            let interruption = self.interrupt_after(delay);
+            driver = match driver.partial_power_on(interruption) {
+                Ok(StoreDriver::Off(driver)) => driver,
+                Ok(driver) => {
+                    self.record(StatKey::RecoveryDepth, depth);
+                    return driver;
+                }
+                Err(error) => self.crash(error),
+            };
+        }
+        self.record(StatKey::RecoveryDepth, count);
+        StoreDriver::Off(driver)
+    }
+
     /// Generates and applies an operation with possible interruption.
     fn apply(&mut self, driver: StoreDriverOn) -> Result<StoreDriver, Store<BufferStorage>> {
         let operation = self.operation(&driver);
         if self.debug {
             println!("{:?}", operation);
         }
+        let traced = self.trace.as_ref().map(|_| operation.clone());
         let interruption = self.interruption(driver.count_operations(&operation));
         match driver.partial_apply(operation, interruption) {
             Err((store, _)) if self.init.is_dirty() => {
@@ -211,22 +391,47 @@ impl<'a> Fuzzer<'a> {
             Err((store, error)) => self.crash((store.extract_storage(), error)),
             Ok((error, driver)) => {
                 if self.debug {
-                    if let Some(error) = error {
+                    if let Some(error) = &error {
                         println!("{:?}", error);
                     }
                 }
+                if error == Some(StoreError::WriteOnce) {
+                    self.increment(StatKey::WriteOnceCount);
+                }
+                if let (Some(trace), Some(operation)) = (&mut self.trace, traced) {
+                    let result = match driver {
+                        StoreDriver::On(_) => Some(error.map_or(Ok(()), Err)),
+                        StoreDriver::Off(_) => None,
+                    };
+                    trace.steps.push(TraceStep {
+                        operation,
+                        result,
+                        digest: 0,
+                    });
+                    self.pending = true;
+                }
                 Ok(driver)
             }
         }
     }
 
+    /// Records the digest of the last step if pending.
+    fn settle(&mut self, driver: &StoreDriverOn) {
+        if let Some(trace) = &mut self.trace {
+            if self.pending {
+                trace.steps.last_mut().unwrap().digest = digest(driver.model().content());
+                self.pending = false;
+            }
+        }
+    }
+
     /// Reports a broken invariant and terminates fuzzing.
     fn crash(&self, error: (BufferStorage, StoreInvariant)) -> ! {
         let (storage, invariant) = error;
         if self.debug {
             print!("{}", storage);
         }
-        panic!("{:?}", invariant);
+        panic!("{}", invariant);
     }
 
     /// Records a statistics if enabled.
@@ -253,6 +458,13 @@ impl<'a> Fuzzer<'a> {
             self.counters.insert(PrepareCount, 0);
             self.counters.insert(InsertCount, 0);
             self.counters.insert(RemoveCount, 0);
+            self.counters.insert(InsertIfAbsentCount, 0);
+            self.counters.insert(CompareAndSwapCount, 0);
+            self.counters.insert(RemoveRangeCount, 0);
+            self.counters.insert(DedupCount, 0);
+            self.counters.insert(WriteOnceCount, 0);
+            self.counters.insert(EmptyValueCount, 0);
+            self.counters.insert(UpgradeCount, 0);
             self.counters.insert(InterruptionCount, 0);
         }
     }
@@ -268,6 +480,116 @@ impl<'a> Fuzzer<'a> {
 
     /// Generates a possibly invalid operation.
     fn operation(&mut self, driver: &StoreDriverOn) -> StoreOperation {
+        if cfg!(feature = "legacy_decoder") {
+            self.legacy_operation(driver)
+        } else {
+            self.structured_operation(driver)
+        }
+    }
+
+    /// Generates a possibly invalid operation with the structured format.
+    fn structured_operation(&mut self, driver: &StoreDriverOn) -> StoreOperation {
+        let format = driver.model().format();
+        match self.entropy.read_arbitrary() {
+            Operation::Transaction { count } => {
+                // We also generate an invalid count (one past the maximum value) to test the error
+                // scenario, like the legacy format.
+                let count = count as usize % (format.max_updates() as usize + 2);
+                let mut updates = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let update = self.entropy.read_arbitrary();
+                    updates.push(self.structured_update(driver, update));
+                }
+                self.increment(StatKey::TransactionCount);
+                StoreOperation::Transaction { updates }
+            }
+            Operation::Clear { min_key } => {
+                self.increment(StatKey::ClearCount);
+                StoreOperation::Clear {
+                    min_key: min_key.get(),
+                }
+            }
+            Operation::Prepare { length } => {
+                // We also generate an invalid length (one past the total capacity) to test the
+                // error scenario, like the legacy format.
+                let length = length as usize % (format.total_capacity() as usize + 2);
+                self.increment(StatKey::PrepareCount);
+                StoreOperation::Prepare { length }
+            }
+        }
+    }
+
+    /// Converts a possibly invalid update of the structured format.
+    fn structured_update(&mut self, driver: &StoreDriverOn, update: Update) -> StoreUpdate {
+        match update {
+            Update::Insert { key, value } => {
+                let value = self.structured_value(value);
+                self.increment(StatKey::InsertCount);
+                StoreUpdate::Insert {
+                    key: key.get(),
+                    value,
+                }
+            }
+            Update::Remove { key } => {
+                self.increment(StatKey::RemoveCount);
+                StoreUpdate::Remove { key: key.get() }
+            }
+            Update::InsertIfAbsent { key, value } => {
+                let key = key.get();
+                let value = self.structured_value(value);
+                self.increment(StatKey::InsertIfAbsentCount);
+                if driver.model().content().contains_key(&key) {
+                    self.increment(StatKey::DedupCount);
+                }
+                StoreUpdate::InsertIfAbsent { key, value }
+            }
+            Update::CompareAndSwap {
+                key,
+                current,
+                old,
+                same,
+                new,
+            } => {
+                let key = key.get();
+                // See the legacy format for why we mostly want the condition to hold.
+                let current_value = driver.model().content().get(&key);
+                let old = match current_value {
+                    Some(value) if current => value.to_vec(),
+                    _ => self.structured_value(old),
+                };
+                let new = if same {
+                    if current_value.is_some_and(|x| x[..] == old[..]) {
+                        self.increment(StatKey::DedupCount);
+                    }
+                    old.clone()
+                } else {
+                    self.structured_value(new)
+                };
+                self.increment(StatKey::CompareAndSwapCount);
+                StoreUpdate::CompareAndSwap { key, old, new }
+            }
+            Update::RemoveRange { start, length } => {
+                // Use 0 and 513 as the canonical invalid lengths, like the legacy format.
+                let start = start.get();
+                let end = start + length as usize % 514;
+                self.increment(StatKey::RemoveRangeCount);
+                StoreUpdate::RemoveRange { keys: start..end }
+            }
+        }
+    }
+
+    /// Converts a possibly invalid value of the structured format.
+    fn structured_value(&mut self, value: Value) -> Vec<u8> {
+        if let Value::Empty = value {
+            self.increment(StatKey::EmptyValueCount);
+        }
+        let mut result = vec![0; value.length()];
+        self.values.fill_bytes(&mut result);
+        result
+    }
+
+    /// Generates a possibly invalid operation with the legacy format.
+    fn legacy_operation(&mut self, driver: &StoreDriverOn) -> StoreOperation {
         let format = driver.model().format();
         match self.entropy.read_range(0, 2) {
             0 => {
@@ -279,7 +601,7 @@ impl<'a> Fuzzer<'a> {
                     .read_range(0, format.max_updates() as usize + 1);
                 let mut updates = Vec::with_capacity(count);
                 for _ in 0..count {
-                    updates.push(self.update());
+                    updates.push(self.legacy_update(driver));
                 }
                 self.increment(StatKey::TransactionCount);
                 StoreOperation::Transaction { updates }
@@ -302,9 +624,9 @@ impl<'a> Fuzzer<'a> {
         }
     }
 
-    /// Generates a possibly invalid update.
-    fn update(&mut self) -> StoreUpdate {
-        match self.entropy.read_range(0, 1) {
+    /// Generates a possibly invalid update with the legacy format.
+    fn legacy_update(&mut self, driver: &StoreDriverOn) -> StoreUpdate {
+        match self.entropy.read_range(0, 4) {
             0 => {
                 let key = self.key();
                 let value = self.value();
@@ -316,18 +638,61 @@ impl<'a> Fuzzer<'a> {
                 self.increment(StatKey::RemoveCount);
                 StoreUpdate::Remove { key }
             }
+            2 => {
+                let key = self.key();
+                let value = self.value();
+                self.increment(StatKey::InsertIfAbsentCount);
+                if driver.model().content().contains_key(&key) {
+                    self.increment(StatKey::DedupCount);
+                }
+                StoreUpdate::InsertIfAbsent { key, value }
+            }
+            3 => {
+                let key = self.key();
+                // We mostly want the condition to hold, so we use the current value as the old
+                // value unless told otherwise or there is no current value.
+                let current = driver.model().content().get(&key);
+                let old = match current {
+                    Some(value) if self.entropy.read_bit() => value.to_vec(),
+                    _ => self.value(),
+                };
+                // We also want to cover compare-and-swap updates without effect.
+                let new = if self.entropy.read_bit() {
+                    if current.is_some_and(|x| x[..] == old[..]) {
+                        self.increment(StatKey::DedupCount);
+                    }
+                    old.clone()
+                } else {
+                    self.value()
+                };
+                self.increment(StatKey::CompareAndSwapCount);
+                StoreUpdate::CompareAndSwap { key, old, new }
+            }
+            4 => {
+                // Use 0 and 513 as the canonical invalid lengths. Ranges may also end past the
+                // maximum key.
+                let start = self.key();
+                let end = start + self.entropy.read_range(0, 513);
+                self.increment(StatKey::RemoveRangeCount);
+                StoreUpdate::RemoveRange { keys: start..end }
+            }
             _ => unreachable!(),
         }
     }
 
-    /// Generates a possibly invalid key.
+    /// Generates a possibly invalid key with the legacy format.
     fn key(&mut self) -> usize {
         // Use 4096 as the canonical invalid key.
         self.entropy.read_range(0, 4096)
     }
 
-    /// Generates a possibly invalid value.
+    /// Generates a possibly invalid value with the legacy format.
     fn value(&mut self) -> Vec<u8> {
+        // Empty values are used as flags, so we generate them more often than other lengths.
+        if self.entropy.read_bit() {
+            self.increment(StatKey::EmptyValueCount);
+            return Vec::new();
+        }
         // Use 1024 as the canonical invalid length.
         let length = self.entropy.read_range(0, 1024);
         let mut value = vec![0; length];
@@ -360,6 +725,13 @@ impl<'a> Fuzzer<'a> {
         if delay < max_delay {
             self.increment(StatKey::InterruptionCount);
         }
+        self.interrupt_after(delay)
+    }
+
+    /// Generates an interruption after a given number of storage operations.
+    ///
+    /// The interrupted operation is corrupted using entropy.
+    fn interrupt_after(&mut self, delay: usize) -> StoreInterruption<'_> {
         let corrupt = Box::new(move |old: &mut [u8], new: &[u8]| {
             let mut count = 0;
             let mut total = 0;
@@ -0,0 +1,640 @@
+// Copyright 2019-2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Differential traces of fuzzing runs.
+//!
+//! A trace records the operations applied during a fuzzing run, their result, and a digest of the
+//! store content once each operation settled (possibly after interruptions and reboots). It also
+//! records the storage operations (including corrupted ones) and the final storage.
+//!
+//! Traces are meant to be recorded with one version of the store and replayed with another to
+//! detect format regressions:
+//! - The final storage is mounted and its content is checked against the last digest.
+//! - The operations are applied to a fresh storage and their results and digests are checked.
+//!
+//! The storage operations reproduce the final storage deterministically on a fresh storage, without
+//! the entropy of the fuzzing run. This is useful to report a storage corruption.
+
+use crate::store::{is_write_once, MIGRATIONS};
+use persistent_store::{
+    BufferOptions, BufferRecord, BufferStorage, Storage, StorageIndex, Store, StoreError,
+    StoreOperation, StoreUpdate,
+};
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+/// Version of the trace encoding.
+const TRACE_VERSION: u8 = 4;
+
+/// Records a fuzzing run.
+#[derive(Debug, Default)]
+pub struct Trace {
+    /// The size of a page in bytes.
+    pub page_size: usize,
+
+    /// The number of pages.
+    pub num_pages: usize,
+
+    /// The maximum number of times a page can be erased.
+    pub max_page_erases: usize,
+
+    /// The number of simulated used cycles of the initial storage.
+    ///
+    /// This is `None` if the initial storage is clean.
+    pub init_cycles: Option<usize>,
+
+    /// The number of migrations with which the final storage was mounted.
+    ///
+    /// This is non-zero if the store format was upgraded during the fuzzing run.
+    pub migrations: usize,
+
+    /// Whether the store was fuzzed with write-once keys.
+    pub write_once: bool,
+
+    /// The applied operations.
+    pub steps: Vec<TraceStep>,
+
+    /// The storage operations applied to the initial storage.
+    ///
+    /// Replaying them onto a fresh storage results in the final storage.
+    pub records: Vec<BufferRecord>,
+
+    /// The final storage.
+    pub storage: Vec<u8>,
+}
+
+/// Records an applied operation.
+#[derive(Debug)]
+pub struct TraceStep {
+    /// The applied operation.
+    pub operation: StoreOperation,
+
+    /// The result of the operation.
+    ///
+    /// This is `None` if the operation was interrupted.
+    pub result: Option<Result<(), StoreError>>,
+
+    /// The digest of the store content once the operation settled.
+    pub digest: u64,
+}
+
+/// Possible ways a replay may fail.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The trace could not be decoded.
+    InvalidTrace,
+
+    /// The final storage could not be mounted.
+    MountFailed(StoreError),
+
+    /// The final storage has a different content than recorded.
+    DifferentFinalContent,
+
+    /// The storage operations result in a different storage than recorded.
+    DifferentFinalStorage,
+
+    /// An operation returned a different result than recorded.
+    DifferentResult {
+        /// The index of the step.
+        step: usize,
+
+        /// The result of the replay.
+        replay: Result<(), StoreError>,
+
+        /// The recorded result.
+        trace: Result<(), StoreError>,
+    },
+
+    /// An operation resulted in a different content than recorded.
+    DifferentContent {
+        /// The index of the step.
+        step: usize,
+    },
+
+    /// The store failed outside an operation.
+    StoreError(StoreError),
+}
+
+impl From<StoreError> for ReplayError {
+    fn from(error: StoreError) -> ReplayError {
+        ReplayError::StoreError(error)
+    }
+}
+
+impl Trace {
+    /// Returns the options of the storage.
+    fn options(&self) -> BufferOptions {
+        BufferOptions {
+            word_size: 4,
+            page_size: self.page_size,
+            max_word_writes: 2,
+            max_page_erases: self.max_page_erases,
+            strict_mode: false,
+        }
+    }
+
+    /// Records the storage operations and the final storage.
+    pub(crate) fn set_storage(&mut self, storage: &BufferStorage) {
+        self.records = storage.records().unwrap_or_default().to_vec();
+        self.storage = read_storage(storage);
+    }
+
+    /// Replays the trace.
+    ///
+    /// Returns the number of replayed steps. Replay stops early if the store reaches the end of
+    /// its lifetime.
+    pub fn replay(&self) -> Result<usize, ReplayError> {
+        self.replay_records()?;
+        self.replay_storage()?;
+        self.replay_steps()
+    }
+
+    /// Returns the final storage by applying the storage operations to a fresh storage.
+    ///
+    /// This reproduces the fuzzing run at the storage level, including corruptions.
+    pub fn replay_records(&self) -> Result<BufferStorage, ReplayError> {
+        let storage = vec![0xff; self.num_pages * self.page_size].into_boxed_slice();
+        let mut storage = BufferStorage::new(storage, self.options());
+        storage
+            .replay(&self.records)
+            .map_err(|_| ReplayError::InvalidTrace)?;
+        if !self.storage.is_empty() && read_storage(&storage) != self.storage {
+            return Err(ReplayError::DifferentFinalStorage);
+        }
+        Ok(storage)
+    }
+
+    /// Checks that the final storage can be mounted with the recorded content.
+    fn replay_storage(&self) -> Result<(), ReplayError> {
+        if self.storage.is_empty() {
+            return Ok(());
+        }
+        let storage = BufferStorage::new(self.storage.clone().into_boxed_slice(), self.options());
+        let migrations = &MIGRATIONS[..self.migrations];
+        let store = Store::new_with_migrations(storage, migrations)
+            .map_err(|(error, _)| ReplayError::MountFailed(error))?;
+        let expected = self
+            .steps
+            .last()
+            .map_or(digest(&HashMap::new()), |x| x.digest);
+        if digest(&content(&store)?) != expected {
+            return Err(ReplayError::DifferentFinalContent);
+        }
+        Ok(())
+    }
+
+    /// Applies the recorded operations to a fresh storage.
+    fn replay_steps(&self) -> Result<usize, ReplayError> {
+        let storage = vec![0xff; self.num_pages * self.page_size].into_boxed_slice();
+        let mut storage = BufferStorage::new(storage, self.options());
+        if let Some(cycle) = self.init_cycles {
+            Store::init_with_cycle(&mut storage, cycle);
+        }
+        let mut store =
+            Store::new(storage).map_err(|(error, _)| ReplayError::MountFailed(error))?;
+        if self.write_once {
+            store.set_write_once(Some(is_write_once));
+        }
+        let mut prev_digest = digest(&content(&store)?);
+        for (index, step) in self.steps.iter().enumerate() {
+            let backup = store.clone();
+            let result = store.apply(&step.operation).1;
+            if result == Err(StoreError::NoLifetime) {
+                return Ok(index);
+            }
+            let next_digest = digest(&content(&store)?);
+            match step.result {
+                Some(ref trace) if *trace != result => {
+                    return Err(ReplayError::DifferentResult {
+                        step: index,
+                        replay: result,
+                        trace: trace.clone(),
+                    })
+                }
+                // The interrupted operation was rolled back.
+                None if next_digest != step.digest && prev_digest == step.digest => {
+                    store = backup;
+                }
+                _ if next_digest != step.digest => {
+                    return Err(ReplayError::DifferentContent { step: index })
+                }
+                _ => (),
+            }
+            prev_digest = step.digest;
+        }
+        Ok(self.steps.len())
+    }
+
+    /// Encodes the trace.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut output = vec![TRACE_VERSION];
+        encode_nat(&mut output, self.page_size);
+        encode_nat(&mut output, self.num_pages);
+        encode_nat(&mut output, self.max_page_erases);
+        match self.init_cycles {
+            None => output.push(0),
+            Some(cycle) => {
+                output.push(1);
+                encode_nat(&mut output, cycle);
+            }
+        }
+        encode_nat(&mut output, self.migrations);
+        output.push(self.write_once as u8);
+        encode_nat(&mut output, self.steps.len());
+        for step in &self.steps {
+            encode_operation(&mut output, &step.operation);
+            output.push(match step.result {
+                None => 0,
+                Some(Ok(())) => 1,
+                Some(Err(ref error)) => 2 + encode_error(error),
+            });
+            output.extend_from_slice(&step.digest.to_le_bytes());
+        }
+        encode_nat(&mut output, self.records.len());
+        for record in &self.records {
+            encode_record(&mut output, record);
+        }
+        encode_bytes(&mut output, &self.storage);
+        output
+    }
+
+    /// Decodes a trace.
+    pub fn decode(input: &[u8]) -> Result<Trace, ReplayError> {
+        let mut input = Decoder { input };
+        if input.byte()? != TRACE_VERSION {
+            return Err(ReplayError::InvalidTrace);
+        }
+        let page_size = input.nat()?;
+        let num_pages = input.nat()?;
+        let max_page_erases = input.nat()?;
+        let init_cycles = match input.byte()? {
+            0 => None,
+            1 => Some(input.nat()?),
+            _ => return Err(ReplayError::InvalidTrace),
+        };
+        let migrations = input.nat()?;
+        if migrations > MIGRATIONS.len() {
+            return Err(ReplayError::InvalidTrace);
+        }
+        let write_once = match input.byte()? {
+            0 => false,
+            1 => true,
+            _ => return Err(ReplayError::InvalidTrace),
+        };
+        let count = input.nat()?;
+        let mut steps = Vec::new();
+        for _ in 0..count {
+            let operation = input.operation()?;
+            let result = match input.byte()? {
+                0 => None,
+                1 => Some(Ok(())),
+                x => Some(Err(decode_error(x - 2)?)),
+            };
+            let digest = u64::from_le_bytes(input.slice(8)?.try_into().unwrap());
+            steps.push(TraceStep {
+                operation,
+                result,
+                digest,
+            });
+        }
+        let count = input.nat()?;
+        let mut records = Vec::new();
+        for _ in 0..count {
+            records.push(input.record()?);
+        }
+        let storage = input.bytes()?;
+        if !input.input.is_empty() || !page_size.is_power_of_two() || page_size < 4 {
+            return Err(ReplayError::InvalidTrace);
+        }
+        if !storage.is_empty() && storage.len() != num_pages * page_size {
+            return Err(ReplayError::InvalidTrace);
+        }
+        Ok(Trace {
+            page_size,
+            num_pages,
+            max_page_erases,
+            init_cycles,
+            migrations,
+            write_once,
+            steps,
+            records,
+            storage,
+        })
+    }
+}
+
+/// Returns the content of a storage.
+fn read_storage(storage: &BufferStorage) -> Vec<u8> {
+    let mut result = Vec::new();
+    for page in 0..storage.num_pages() {
+        let index = StorageIndex { page, byte: 0 };
+        let page = storage.read_slice(index, storage.page_size()).unwrap();
+        result.extend_from_slice(page);
+    }
+    result
+}
+
+/// Returns the content of a store.
+pub(crate) fn content(
+    store: &Store<BufferStorage>,
+) -> Result<HashMap<usize, Box<[u8]>>, StoreError> {
+    let mut content = HashMap::new();
+    for handle in store.iter()? {
+        let handle = handle?;
+        let value = handle.get_value(store)?.into_boxed_slice();
+        content.insert(handle.get_key(), value);
+    }
+    Ok(content)
+}
+
+/// Returns a digest of a store content.
+///
+/// This is the 64-bits FNV-1a hash of the entries sorted by key. It is stable across versions.
+pub(crate) fn digest(content: &HashMap<usize, Box<[u8]>>) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    let mut update = |bytes: &[u8]| {
+        for &byte in bytes {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+    let mut keys: Vec<_> = content.keys().collect();
+    keys.sort();
+    for key in keys {
+        let value = &content[key];
+        update(&(*key as u32).to_le_bytes());
+        update(&(value.len() as u32).to_le_bytes());
+        update(value);
+    }
+    hash
+}
+
+fn encode_nat(output: &mut Vec<u8>, x: usize) {
+    output.extend_from_slice(&(x as u32).to_le_bytes());
+}
+
+fn encode_bytes(output: &mut Vec<u8>, x: &[u8]) {
+    encode_nat(output, x.len());
+    output.extend_from_slice(x);
+}
+
+fn encode_operation(output: &mut Vec<u8>, operation: &StoreOperation) {
+    match operation {
+        StoreOperation::Transaction { updates } => {
+            output.push(0);
+            encode_nat(output, updates.len());
+            for update in updates {
+                encode_update(output, update);
+            }
+        }
+        StoreOperation::Clear { min_key } => {
+            output.push(1);
+            encode_nat(output, *min_key);
+        }
+        StoreOperation::Prepare { length } => {
+            output.push(2);
+            encode_nat(output, *length);
+        }
+    }
+}
+
+fn encode_update(output: &mut Vec<u8>, update: &StoreUpdate) {
+    match update {
+        StoreUpdate::Insert { key, value } => {
+            output.push(0);
+            encode_nat(output, *key);
+            encode_bytes(output, value);
+        }
+        StoreUpdate::Remove { key } => {
+            output.push(1);
+            encode_nat(output, *key);
+        }
+        StoreUpdate::InsertIfAbsent { key, value } => {
+            output.push(2);
+            encode_nat(output, *key);
+            encode_bytes(output, value);
+        }
+        StoreUpdate::CompareAndSwap { key, old, new } => {
+            output.push(3);
+            encode_nat(output, *key);
+            encode_bytes(output, old);
+            encode_bytes(output, new);
+        }
+        StoreUpdate::RemoveRange { keys } => {
+            output.push(4);
+            encode_nat(output, keys.start);
+            encode_nat(output, keys.end);
+        }
+    }
+}
+
+fn encode_index(output: &mut Vec<u8>, index: StorageIndex) {
+    encode_nat(output, index.page);
+    encode_nat(output, index.byte);
+}
+
+fn encode_record(output: &mut Vec<u8>, record: &BufferRecord) {
+    match record {
+        BufferRecord::Write { index, value } => {
+            output.push(0);
+            encode_index(output, *index);
+            encode_bytes(output, value);
+        }
+        BufferRecord::Erase { page } => {
+            output.push(1);
+            encode_nat(output, *page);
+        }
+        BufferRecord::CorruptWrite {
+            index,
+            value,
+            corrupted,
+        } => {
+            output.push(2);
+            encode_index(output, *index);
+            encode_bytes(output, value);
+            encode_bytes(output, corrupted);
+        }
+        BufferRecord::CorruptErase { page, corrupted } => {
+            output.push(3);
+            encode_nat(output, *page);
+            encode_bytes(output, corrupted);
+        }
+        BufferRecord::SetPageErases { page, cycle } => {
+            output.push(4);
+            encode_nat(output, *page);
+            encode_nat(output, *cycle);
+        }
+    }
+}
+
+fn encode_error(error: &StoreError) -> u8 {
+    match error {
+        StoreError::InvalidArgument => 0,
+        StoreError::NoCapacity => 1,
+        StoreError::NoLifetime => 2,
+        StoreError::StorageError => 3,
+        StoreError::InvalidStorage => 4,
+        StoreError::ConditionFailed => 5,
+        StoreError::GeometryMismatch => 6,
+        StoreError::WriteOnce => 7,
+    }
+}
+
+fn decode_error(x: u8) -> Result<StoreError, ReplayError> {
+    Ok(match x {
+        0 => StoreError::InvalidArgument,
+        1 => StoreError::NoCapacity,
+        2 => StoreError::NoLifetime,
+        3 => StoreError::StorageError,
+        4 => StoreError::InvalidStorage,
+        5 => StoreError::ConditionFailed,
+        6 => StoreError::GeometryMismatch,
+        7 => StoreError::WriteOnce,
+        _ => return Err(ReplayError::InvalidTrace),
+    })
+}
+
+/// Decodes a trace from a byte slice.
+struct Decoder<'a> {
+    /// The remaining input.
+    input: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn slice(&mut self, length: usize) -> Result<&[u8], ReplayError> {
+        if self.input.len() < length {
+            return Err(ReplayError::InvalidTrace);
+        }
+        let (result, input) = self.input.split_at(length);
+        self.input = input;
+        Ok(result)
+    }
+
+    fn byte(&mut self) -> Result<u8, ReplayError> {
+        Ok(self.slice(1)?[0])
+    }
+
+    fn nat(&mut self) -> Result<usize, ReplayError> {
+        Ok(u32::from_le_bytes(self.slice(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, ReplayError> {
+        let length = self.nat()?;
+        Ok(self.slice(length)?.to_vec())
+    }
+
+    fn index(&mut self) -> Result<StorageIndex, ReplayError> {
+        Ok(StorageIndex {
+            page: self.nat()?,
+            byte: self.nat()?,
+        })
+    }
+
+    fn record(&mut self) -> Result<BufferRecord, ReplayError> {
+        Ok(match self.byte()? {
+            0 => BufferRecord::Write {
+                index: self.index()?,
+                value: self.bytes()?.into_boxed_slice(),
+            },
+            1 => BufferRecord::Erase { page: self.nat()? },
+            2 => BufferRecord::CorruptWrite {
+                index: self.index()?,
+                value: self.bytes()?.into_boxed_slice(),
+                corrupted: self.bytes()?.into_boxed_slice(),
+            },
+            3 => BufferRecord::CorruptErase {
+                page: self.nat()?,
+                corrupted: self.bytes()?.into_boxed_slice(),
+            },
+            4 => BufferRecord::SetPageErases {
+                page: self.nat()?,
+                cycle: self.nat()?,
+            },
+            _ => return Err(ReplayError::InvalidTrace),
+        })
+    }
+
+    fn operation(&mut self) -> Result<StoreOperation, ReplayError> {
+        Ok(match self.byte()? {
+            0 => {
+                let count = self.nat()?;
+                let mut updates = Vec::new();
+                for _ in 0..count {
+                    updates.push(self.update()?);
+                }
+                StoreOperation::Transaction { updates }
+            }
+            1 => StoreOperation::Clear {
+                min_key: self.nat()?,
+            },
+            2 => StoreOperation::Prepare {
+                length: self.nat()?,
+            },
+            _ => return Err(ReplayError::InvalidTrace),
+        })
+    }
+
+    fn update(&mut self) -> Result<StoreUpdate, ReplayError> {
+        Ok(match self.byte()? {
+            0 => StoreUpdate::Insert {
+                key: self.nat()?,
+                value: self.bytes()?,
+            },
+            1 => StoreUpdate::Remove { key: self.nat()? },
+            2 => StoreUpdate::InsertIfAbsent {
+                key: self.nat()?,
+                value: self.bytes()?,
+            },
+            3 => StoreUpdate::CompareAndSwap {
+                key: self.nat()?,
+                old: self.bytes()?,
+                new: self.bytes()?,
+            },
+            4 => StoreUpdate::RemoveRange {
+                keys: self.nat()?..self.nat()?,
+            },
+            _ => return Err(ReplayError::InvalidTrace),
+        })
+    }
+}
+
+#[test]
+fn replay_ok() {
+    let mut replayed = 0;
+    for byte in 0..=255u8 {
+        let mut trace = Trace::default();
+        crate::fuzz(&[byte; 200], false, None, Some(&mut trace));
+        if trace.page_size == 0 {
+            continue;
+        }
+        let mut trace = Trace::decode(&trace.encode()).unwrap();
+        assert_eq!(trace.replay(), Ok(trace.steps.len()));
+        replayed += 1;
+        // Tampering with the storage operations is detected.
+        let mut tampered = Trace::decode(&trace.encode()).unwrap();
+        if let Some(BufferRecord::Write { value, .. }) = tampered.records.last_mut() {
+            value[0] ^= 1;
+            assert_eq!(
+                tampered.replay_records().err(),
+                Some(ReplayError::DifferentFinalStorage)
+            );
+        }
+        // Tampering with the final digest is detected.
+        if let Some(step) = trace.steps.last_mut() {
+            step.digest ^= 1;
+            assert!(trace.replay().is_err());
+        }
+    }
+    assert!(replayed > 0);
+}
@@ -16,6 +16,7 @@ use crate::{Storage, StorageError, StorageIndex, StorageResult};
 use alloc::borrow::Borrow;
 use alloc::boxed::Box;
 use alloc::vec;
+use alloc::vec::Vec;
 
 /// Simulates a flash storage using a buffer in memory.
 ///
@@ -25,7 +26,8 @@ use alloc::vec;
 /// This storage tracks how many times words are written between page erase cycles, how many times
 /// pages are erased, and whether an operation flips bits in the wrong direction. Operations panic
 /// if those conditions are broken (optional). This storage also permits to interrupt operations for
-/// inspection or to corrupt the operation.
+/// inspection or to corrupt the operation, and to record mutable operations for later replay. It
+/// can also be made read-only, to inspect a flash dump without modifying it.
 #[derive(Clone)]
 pub struct BufferStorage {
     /// Content of the storage.
@@ -42,6 +44,14 @@ pub struct BufferStorage {
 
     /// Interruption state.
     interruption: Interruption,
+
+    /// Mutable operations applied since recording started.
+    ///
+    /// This is `None` if the storage is not recording.
+    records: Option<Vec<BufferRecord>>,
+
+    /// Whether mutable operations are rejected.
+    read_only: bool,
 }
 
 /// Options of a buffer storage.
@@ -79,6 +89,58 @@ pub struct BufferOptions {
 /// flip bits that differ between both slices.
 pub type BufferCorruptFunction<'a> = Box<dyn FnOnce(&mut [u8], &[u8]) + 'a>;
 
+/// Records a mutable operation applied to a buffer storage.
+///
+/// Interrupted operations are recorded with the content they left in the storage, such that
+/// corruptions can be replayed without the corruption function.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BufferRecord {
+    /// A write operation.
+    Write {
+        /// The storage index at which the write occurred.
+        index: StorageIndex,
+
+        /// The written slice.
+        value: Box<[u8]>,
+    },
+
+    /// An erase operation.
+    Erase {
+        /// The erased page.
+        page: usize,
+    },
+
+    /// An interrupted write operation.
+    CorruptWrite {
+        /// The storage index at which the write should have occurred.
+        index: StorageIndex,
+
+        /// The slice that should have been written.
+        value: Box<[u8]>,
+
+        /// The content of the written range after corruption.
+        corrupted: Box<[u8]>,
+    },
+
+    /// An interrupted erase operation.
+    CorruptErase {
+        /// The page that should have been erased.
+        page: usize,
+
+        /// The content of the page after corruption.
+        corrupted: Box<[u8]>,
+    },
+
+    /// A change of the erase counter of a page.
+    SetPageErases {
+        /// The page whose counter changed.
+        page: usize,
+
+        /// The new number of times the page was erased.
+        cycle: usize,
+    },
+}
+
 impl BufferStorage {
     /// Creates a buffer storage.
     ///
@@ -100,6 +162,8 @@ impl BufferStorage {
             word_writes: vec![0; num_words].into_boxed_slice(),
             page_erases: vec![0; num_pages].into_boxed_slice(),
             interruption: Interruption::Ready,
+            records: None,
+            read_only: false,
         };
         assert!(buffer.is_word_aligned(buffer.options.page_size));
         assert!(buffer.is_page_aligned(buffer.storage.len()));
@@ -170,10 +234,18 @@ impl BufferStorage {
         let operation = self.interruption.get().unwrap();
         let range = self.operation_range(&operation).unwrap();
         let mut before = self.storage[range.clone()].to_vec().into_boxed_slice();
-        match operation {
-            BufferOperation::Write { value: after, .. } => {
+        let record = match operation {
+            BufferOperation::Write {
+                index,
+                value: after,
+            } => {
                 corrupt(&mut before, &after);
                 self.incr_word_writes(range.start, &before, &after);
+                BufferRecord::CorruptWrite {
+                    index,
+                    value: after,
+                    corrupted: before.clone(),
+                }
             }
             BufferOperation::Erase { page } => {
                 let after = vec![0xff; self.page_size()].into_boxed_slice();
@@ -181,9 +253,86 @@ impl BufferStorage {
                 if before == after {
                     self.incr_page_erases(page);
                 }
+                BufferRecord::CorruptErase {
+                    page,
+                    corrupted: before.clone(),
+                }
             }
         };
         self.storage[range].copy_from_slice(&before);
+        self.record(record);
+    }
+
+    /// Starts recording mutable operations.
+    ///
+    /// Previously recorded operations are discarded. The recorded operations can be [replayed] on
+    /// a storage with the same content and options as this storage when recording started.
+    ///
+    /// [replayed]: struct.BufferStorage.html#method.replay
+    pub fn start_recording(&mut self) {
+        self.records = Some(Vec::new());
+    }
+
+    /// Returns the mutable operations applied since recording started.
+    ///
+    /// Returns `None` if the storage is not recording.
+    pub fn records(&self) -> Option<&[BufferRecord]> {
+        self.records.as_deref()
+    }
+
+    /// Replays recorded operations.
+    ///
+    /// Interrupted operations are replayed by corrupting them with their recorded content. If the
+    /// storage is recording, the replayed operations are recorded too.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an operation is invalid for this storage, in which case the previous
+    /// operations have been applied.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an interruption is armed, or if an operation would panic (see [strict mode]).
+    ///
+    /// [strict mode]: struct.BufferOptions.html#structfield.strict_mode
+    pub fn replay(&mut self, records: &[BufferRecord]) -> StorageResult<()> {
+        for record in records {
+            match record {
+                BufferRecord::Write { index, value } => self.write_slice(*index, value)?,
+                BufferRecord::Erase { page } => self.erase_page(*page)?,
+                BufferRecord::CorruptWrite {
+                    index,
+                    value,
+                    corrupted,
+                } => {
+                    let operation = BufferOperation::Write {
+                        index: *index,
+                        value: &value[..],
+                    };
+                    self.replay_corruption(&operation, corrupted)?;
+                }
+                BufferRecord::CorruptErase { page, corrupted } => {
+                    let operation = BufferOperation::Erase { page: *page };
+                    self.replay_corruption(&operation, corrupted)?;
+                }
+                BufferRecord::SetPageErases { page, cycle } => {
+                    if *page >= self.num_pages() {
+                        return Err(StorageError::OutOfBounds);
+                    }
+                    self.set_page_erases(*page, *cycle);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets whether mutable operations are rejected.
+    ///
+    /// When read-only, writes and erases fail with `StorageError::CustomError` and leave the
+    /// storage unchanged. In particular, a store only boots if it doesn't need to modify the
+    /// storage, e.g. to complete an interrupted operation.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
     }
 
     /// Returns the number of times a word was written.
@@ -199,6 +348,43 @@ impl BufferStorage {
     /// Sets the number of times a page was erased.
     pub fn set_page_erases(&mut self, page: usize, cycle: usize) {
         self.page_erases[page] = cycle;
+        self.record(BufferRecord::SetPageErases { page, cycle });
+    }
+
+    /// Fails if the storage is read-only.
+    fn check_writable(&self) -> StorageResult<()> {
+        if self.read_only {
+            return Err(StorageError::CustomError);
+        }
+        Ok(())
+    }
+
+    /// Records a mutable operation if recording.
+    fn record(&mut self, record: BufferRecord) {
+        if let Some(records) = &mut self.records {
+            records.push(record);
+        }
+    }
+
+    /// Interrupts an operation and corrupts it such that its range has a given content.
+    fn replay_corruption(
+        &mut self,
+        operation: &SharedBufferOperation,
+        corrupted: &[u8],
+    ) -> StorageResult<()> {
+        let range = self.operation_range(operation)?;
+        if let BufferOperation::Write { index, value } = *operation {
+            if !self.is_word_aligned(index.byte) || !self.is_word_aligned(value.len()) {
+                return Err(StorageError::NotAligned);
+            }
+        }
+        if corrupted.len() != range.len() {
+            return Err(StorageError::OutOfBounds);
+        }
+        self.interruption.arm(0);
+        self.interruption.tick(operation).unwrap_err();
+        self.corrupt_operation(Box::new(|before, _| before.copy_from_slice(corrupted)));
+        Ok(())
     }
 
     /// Returns whether a number is word-aligned.
@@ -313,6 +499,7 @@ impl Storage for BufferStorage {
         }
         let operation = BufferOperation::Write { index, value };
         let range = self.operation_range(&operation)?;
+        self.check_writable()?;
         // Interrupt operation if armed and delay expired.
         self.interruption.tick(&operation)?;
         // Check and update counters.
@@ -325,12 +512,17 @@ impl Storage for BufferStorage {
         }
         // Write to the storage.
         self.storage[range].copy_from_slice(value);
+        self.record(BufferRecord::Write {
+            index,
+            value: value.into(),
+        });
         Ok(())
     }
 
     fn erase_page(&mut self, page: usize) -> StorageResult<()> {
         let operation = BufferOperation::Erase { page };
         let range = self.operation_range(&operation)?;
+        self.check_writable()?;
         // Interrupt operation if armed and delay expired.
         self.interruption.tick(&operation)?;
         // Check and update counters.
@@ -339,6 +531,7 @@ impl Storage for BufferStorage {
         for byte in &mut self.storage[range] {
             *byte = 0xff;
         }
+        self.record(BufferRecord::Erase { page });
         Ok(())
     }
 }
@@ -691,4 +884,68 @@ mod tests {
         assert_eq!(&buffer.storage[..8], &[0x5c; 8]);
         assert!(buffer.storage[8..].iter().all(|&x| x == 0xff));
     }
+
+    #[test]
+    fn read_only_ok() {
+        let mut buffer = BufferStorage::new(new_storage(), OPTIONS);
+        let index = StorageIndex { page: 0, byte: 0 };
+        buffer.write_slice(index, FIRST_WORD).unwrap();
+        buffer.set_read_only(true);
+        assert_eq!(
+            buffer.write_slice(index, SECOND_WORD),
+            Err(StorageError::CustomError)
+        );
+        assert_eq!(buffer.erase_page(0), Err(StorageError::CustomError));
+        assert_eq!(buffer.read_slice(index, 4).unwrap(), FIRST_WORD);
+        assert_eq!(buffer.get_word_writes(0), 1);
+        assert_eq!(buffer.get_page_erases(0), 0);
+        buffer.set_read_only(false);
+        buffer.write_slice(index, SECOND_WORD).unwrap();
+        assert_eq!(buffer.read_slice(index, 4).unwrap(), SECOND_WORD);
+    }
+
+    #[test]
+    fn replay_ok() {
+        let mut buffer = BufferStorage::new(new_storage(), OPTIONS);
+        assert!(buffer.records().is_none());
+        buffer.start_recording();
+        let index = StorageIndex { page: 0, byte: 0 };
+        let next_index = StorageIndex { page: 0, byte: 4 };
+        buffer.write_slice(index, FIRST_WORD).unwrap();
+        buffer.erase_page(1).unwrap();
+        buffer.set_page_erases(1, 2);
+        // Interrupt a write after the first word and a byte of the second word are written.
+        buffer.arm_interruption(0);
+        let value = [SECOND_WORD, THIRD_WORD].concat();
+        assert!(buffer.write_slice(index, &value).is_err());
+        buffer.corrupt_operation(Box::new(|before, after| {
+            before[..4].copy_from_slice(&after[..4]);
+            before[4] = after[4];
+        }));
+        assert_eq!(buffer.records().unwrap().len(), 4);
+
+        // The operations are replayed onto a fresh storage without corruption function.
+        let mut replay = BufferStorage::new(new_storage(), OPTIONS);
+        replay.replay(buffer.records().unwrap()).unwrap();
+        assert_eq!(replay.storage, buffer.storage);
+        assert_eq!(replay.word_writes, buffer.word_writes);
+        assert_eq!(replay.page_erases, buffer.page_erases);
+        assert_eq!(replay.read_slice(index, 4).unwrap(), SECOND_WORD);
+        assert_eq!(replay.read_slice(next_index, 1).unwrap(), &[0x88]);
+        assert_eq!(replay.get_page_erases(1), 2);
+    }
+
+    #[test]
+    fn replay_invalid() {
+        let mut buffer = BufferStorage::new(new_storage(), OPTIONS);
+        let records = [BufferRecord::CorruptErase {
+            page: 0,
+            corrupted: vec![0xff; 4].into_boxed_slice(),
+        }];
+        assert_eq!(buffer.replay(&records), Err(StorageError::OutOfBounds));
+        let records = [BufferRecord::Erase { page: 2 }];
+        assert_eq!(buffer.replay(&records), Err(StorageError::OutOfBounds));
+        // The storage can still be used.
+        assert!(buffer.erase_page(0).is_ok());
+    }
 }
@@ -13,11 +13,13 @@
 // limitations under the License.
 
 use crate::format::{Format, Position};
+#[cfg(feature = "diagnostics")]
+use crate::StoreErrorContext;
 #[cfg(test)]
 use crate::StoreUpdate;
 use crate::{
-    BufferCorruptFunction, BufferOptions, BufferStorage, Nat, Store, StoreError, StoreHandle,
-    StoreModel, StoreOperation, StoreResult,
+    BufferCorruptFunction, BufferOptions, BufferStorage, Migration, Nat, Store, StoreCheck,
+    StoreError, StoreHandle, StoreModel, StoreOperation, StoreResult, StoreWriteOnce,
 };
 
 /// Tracks the store behavior against its model and its storage.
@@ -38,6 +40,9 @@ pub struct StoreDriverOn {
 
     /// The model associated to the store.
     model: StoreModel,
+
+    /// The migrations used to power on the store.
+    migrations: &'static [&'static dyn Migration],
 }
 
 /// Keeps a power-off store and its potential models in sync.
@@ -51,6 +56,9 @@ pub struct StoreDriverOff {
 
     /// In case of interrupted operation, the invariant after completion.
     complete: Option<Complete>,
+
+    /// The migrations used to power on the store.
+    migrations: &'static [&'static dyn Migration],
 }
 
 /// The invariant a store must satisfy if an interrupted operation completes.
@@ -72,6 +80,19 @@ pub struct StoreInterruption<'a> {
     pub corrupt: BufferCorruptFunction<'a>,
 }
 
+/// An entry with a different value in the store and in the model.
+#[derive(Debug)]
+pub struct DifferentValue {
+    /// The key of the entry.
+    pub key: usize,
+
+    /// The value of the entry in the store.
+    pub store: Box<[u8]>,
+
+    /// The value of the entry in the model.
+    pub model: Box<[u8]>,
+}
+
 /// Possible ways a driver operation may fail.
 #[derive(Debug)]
 pub enum StoreInvariant {
@@ -83,6 +104,16 @@ pub enum StoreInvariant {
     /// The store returned an unexpected error.
     StoreError(StoreError),
 
+    /// The store returned an unexpected error and knows where it was detected.
+    #[cfg(feature = "diagnostics")]
+    LocatedStoreError {
+        /// The error returned by the store.
+        error: StoreError,
+
+        /// Where the store detected the invalid storage.
+        context: StoreErrorContext,
+    },
+
     /// The store did not recover an interrupted operation.
     Interrupted {
         /// The reason why the store didn't rollback the operation.
@@ -110,37 +141,34 @@ pub enum StoreInvariant {
         value: Vec<u8>,
     },
 
-    /// The store has an entry not present in the model.
-    OnlyInStore {
-        /// The key of the additional entry.
-        key: usize,
+    /// The storage contains data of deleted entries.
+    NotScrubbed {
+        /// The number of words of deleted entries which are not wiped.
+        words: usize,
     },
 
-    /// The store has a different value than the model for an entry.
-    DifferentValue {
-        /// The key of the entry with a different value.
-        key: usize,
+    /// The store consistency check failed.
+    Inconsistent {
+        /// The report of the check.
+        check: StoreCheck,
+    },
 
-        /// The value of the entry in the store.
-        store: Box<[u8]>,
+    /// The store content differs from the model.
+    DifferentContent {
+        /// The keys of the entries only present in the store, in increasing order.
+        only_in_store: Vec<usize>,
 
-        /// The value of the entry in the model.
-        model: Box<[u8]>,
-    },
+        /// The keys of the entries only present in the model, in increasing order.
+        only_in_model: Vec<usize>,
 
-    /// The store is missing an entry from the model.
-    OnlyInModel {
-        /// The key of the missing entry.
-        key: usize,
-    },
+        /// The entries with a different value, in increasing key order.
+        different_values: Vec<DifferentValue>,
 
-    /// The store reports a different capacity than the model.
-    DifferentCapacity {
-        /// The capacity according to the store.
-        store: usize,
+        /// The remaining capacity according to the store.
+        store_capacity: usize,
 
-        /// The capacity according to the model.
-        model: usize,
+        /// The remaining capacity according to the model.
+        model_capacity: usize,
     },
 
     /// The store failed to track the number of erase cycles correctly.
@@ -187,6 +215,151 @@ impl From<StoreError> for StoreInvariant {
     }
 }
 
+/// Number of bytes per line when printing values in hexadecimal.
+const HEXDUMP_WIDTH: usize = 8;
+
+impl core::fmt::Display for StoreInvariant {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
+        match self {
+            StoreInvariant::NoLifetime => writeln!(f, "The store reached its lifetime."),
+            StoreInvariant::StoreError(error) => {
+                writeln!(f, "The store returned an unexpected error: {:?}", error)
+            }
+            #[cfg(feature = "diagnostics")]
+            StoreInvariant::LocatedStoreError { error, context } => writeln!(
+                f,
+                "The store returned an unexpected error: {:?} at {}",
+                error, context
+            ),
+            StoreInvariant::Interrupted { rollback, complete } => {
+                writeln!(f, "The store did not recover an interrupted operation.")?;
+                writeln!(f, "Not rolled back because:")?;
+                write_indented(f, rollback)?;
+                writeln!(f, "Not completed because:")?;
+                write_indented(f, complete)
+            }
+            StoreInvariant::DifferentResult { store, model } => {
+                writeln!(f, "The store returned a different result than the model.")?;
+                writeln!(f, "store: {:?}", store)?;
+                writeln!(f, "model: {:?}", model)
+            }
+            StoreInvariant::NotWiped { key, value } => {
+                writeln!(f, "The store did not wipe an entry with key {}:", key)?;
+                for offset in (0..value.len()).step_by(HEXDUMP_WIDTH) {
+                    writeln!(f, "    {:04x}  {}", offset, hexdump_line(value, offset))?;
+                }
+                Ok(())
+            }
+            StoreInvariant::NotScrubbed { words } => writeln!(
+                f,
+                "The storage contains {} words of deleted entries.",
+                words
+            ),
+            StoreInvariant::Inconsistent { check } => {
+                writeln!(f, "The store consistency check failed.")?;
+                writeln!(f, "{:#?}", check)
+            }
+            StoreInvariant::DifferentContent {
+                only_in_store,
+                only_in_model,
+                different_values,
+                store_capacity,
+                model_capacity,
+            } => {
+                writeln!(f, "The store content differs from the model.")?;
+                for key in only_in_store {
+                    writeln!(f, "- key {} is only in the store", key)?;
+                }
+                for key in only_in_model {
+                    writeln!(f, "+ key {} is only in the model", key)?;
+                }
+                for DifferentValue { key, store, model } in different_values {
+                    writeln!(f, "~ key {} has a different value:", key)?;
+                    write_hexdump(f, store, model)?;
+                }
+                if store_capacity != model_capacity {
+                    writeln!(
+                        f,
+                        "remaining capacity: store {} model {} (delta {})",
+                        store_capacity,
+                        model_capacity,
+                        *store_capacity as isize - *model_capacity as isize
+                    )?;
+                }
+                Ok(())
+            }
+            StoreInvariant::DifferentErase { page, store, model } => writeln!(
+                f,
+                "The store erased page {} {} times instead of {}.",
+                page, store, model
+            ),
+            StoreInvariant::DifferentWrite {
+                page,
+                word,
+                store,
+                model,
+            } => writeln!(
+                f,
+                "The store wrote word {} of page {} {} times instead of {}.",
+                word, page, store, model
+            ),
+        }
+    }
+}
+
+/// Writes a nested invariant with indentation.
+fn write_indented(
+    f: &mut core::fmt::Formatter,
+    invariant: &StoreInvariant,
+) -> Result<(), core::fmt::Error> {
+    for line in format!("{}", invariant).lines() {
+        writeln!(f, "  {}", line)?;
+    }
+    Ok(())
+}
+
+/// Writes the store and model values of an entry side by side in hexadecimal.
+///
+/// Lines where the values differ are marked with a `!`.
+fn write_hexdump(
+    f: &mut core::fmt::Formatter,
+    store: &[u8],
+    model: &[u8],
+) -> Result<(), core::fmt::Error> {
+    let column = 3 * HEXDUMP_WIDTH - 1;
+    writeln!(
+        f,
+        "  {:6}  {:<column$} | model",
+        "",
+        "store",
+        column = column
+    )?;
+    let length = core::cmp::max(store.len(), model.len());
+    for offset in (0..length).step_by(HEXDUMP_WIDTH) {
+        let store_line = hexdump_line(store, offset);
+        let model_line = hexdump_line(model, offset);
+        let marker = if store_line == model_line { ' ' } else { '!' };
+        writeln!(
+            f,
+            "  {} {:04x}  {:<column$} | {}",
+            marker,
+            offset,
+            store_line,
+            model_line,
+            column = column
+        )?;
+    }
+    Ok(())
+}
+
+/// Formats a line of a hexdump starting at a given offset.
+fn hexdump_line(value: &[u8], offset: usize) -> String {
+    let end = core::cmp::min(value.len(), offset + HEXDUMP_WIDTH);
+    let bytes = value.get(offset..end).unwrap_or(&[]);
+    let bytes: Vec<String> = bytes.iter().map(|x| format!("{:02x}", x)).collect();
+    bytes.join(" ")
+}
+
 impl StoreDriver {
     /// Provides read-only access to the storage.
     pub fn storage(&self) -> &BufferStorage {
@@ -236,9 +409,23 @@ impl StoreDriverOff {
             storage,
             model: StoreModel::new(format),
             complete: None,
+            migrations: &[],
         }
     }
 
+    /// Sets the migrations used to power on the store.
+    ///
+    /// The format version of the store is the number of migrations. Setting more migrations than
+    /// the current format version of the storage simulates a firmware upgrade.
+    pub fn set_migrations(&mut self, migrations: &'static [&'static dyn Migration]) {
+        self.migrations = migrations;
+    }
+
+    /// Sets the predicate of the write-once keys of the store and model.
+    pub fn set_write_once(&mut self, write_once: Option<StoreWriteOnce>) {
+        self.model.set_write_once(write_once);
+    }
+
     /// Provides read-only access to the storage.
     pub fn storage(&self) -> &BufferStorage {
         &self.storage
@@ -273,12 +460,13 @@ impl StoreDriverOff {
         interruption: StoreInterruption,
     ) -> Result<StoreDriver, (BufferStorage, StoreInvariant)> {
         self.storage.arm_interruption(interruption.delay);
-        Ok(match Store::new(self.storage) {
+        let migrations = self.migrations;
+        Ok(match Store::new_with_migrations(self.storage, migrations) {
             Ok(mut store) => {
                 store.storage_mut().disarm_interruption();
                 let mut error = None;
                 if let Some(complete) = self.complete {
-                    match StoreDriverOn::new(store, complete.model, &complete.deleted) {
+                    match StoreDriverOn::new(store, complete.model, &complete.deleted, migrations) {
                         Ok(driver) => return Ok(StoreDriver::On(driver)),
                         Err((e, x)) => {
                             error = Some(e);
@@ -286,19 +474,21 @@ impl StoreDriverOff {
                         }
                     }
                 };
-                StoreDriver::On(StoreDriverOn::new(store, self.model, &[]).map_err(
-                    |(rollback, store)| {
-                        let storage = store.extract_storage();
-                        match error {
-                            None => (storage, rollback),
-                            Some(complete) => {
-                                let rollback = Box::new(rollback);
-                                let complete = Box::new(complete);
-                                (storage, StoreInvariant::Interrupted { rollback, complete })
+                StoreDriver::On(
+                    StoreDriverOn::new(store, self.model, &[], migrations).map_err(
+                        |(rollback, store)| {
+                            let storage = store.extract_storage();
+                            match error {
+                                None => (storage, rollback),
+                                Some(complete) => {
+                                    let rollback = Box::new(rollback);
+                                    let complete = Box::new(complete);
+                                    (storage, StoreInvariant::Interrupted { rollback, complete })
+                                }
                             }
-                        }
-                    },
-                )?)
+                        },
+                    )?,
+                )
             }
             Err((StoreError::StorageError, mut storage)) => {
                 storage.corrupt_operation(interruption.corrupt);
@@ -318,7 +508,7 @@ impl StoreDriverOff {
         let initial_delay = usize::MAX;
         let mut storage = self.storage.clone();
         storage.arm_interruption(initial_delay);
-        let mut store = Store::new(storage).ok()?;
+        let mut store = Store::new_with_migrations(storage, self.migrations).ok()?;
         Some(initial_delay - store.storage_mut().disarm_interruption())
     }
 }
@@ -354,8 +544,11 @@ impl StoreDriverOn {
                 model: model_result,
             });
         }
-        self.check_deleted(&deleted)?;
-        Ok(())
+        if store_result.is_err() {
+            return Ok(());
+        }
+        self.check_deleted(&deleted)
+            .map_err(|invariant| self.locate(invariant))
     }
 
     /// Applies a store operation to the store and model with a possible interruption.
@@ -370,7 +563,11 @@ impl StoreDriverOn {
         let (deleted, store_result) = self.store.apply(&operation);
         Ok(match store_result {
             Err(StoreError::NoLifetime) => return Err((self.store, StoreInvariant::NoLifetime)),
-            Ok(()) | Err(StoreError::NoCapacity) | Err(StoreError::InvalidArgument) => {
+            Ok(())
+            | Err(StoreError::NoCapacity)
+            | Err(StoreError::InvalidArgument)
+            | Err(StoreError::ConditionFailed)
+            | Err(StoreError::WriteOnce) => {
                 self.store.storage_mut().disarm_interruption();
                 let model_result = self.model.apply(operation);
                 if store_result != model_result {
@@ -394,6 +591,7 @@ impl StoreDriverOn {
                     storage: self.store.extract_storage(),
                     model: self.model,
                     complete: None,
+                    migrations: self.migrations,
                 };
                 driver.storage.corrupt_operation(interruption.corrupt);
                 let mut model = driver.model.clone();
@@ -402,7 +600,10 @@ impl StoreDriverOn {
                 }
                 (None, StoreDriver::Off(driver))
             }
-            Err(error) => return Err((self.store, StoreInvariant::StoreError(error))),
+            Err(error) => {
+                let invariant = self.locate(StoreInvariant::StoreError(error));
+                return Err((self.store, invariant));
+            }
         })
     }
 
@@ -423,6 +624,7 @@ impl StoreDriverOn {
             storage: self.store.extract_storage(),
             model: self.model,
             complete: None,
+            migrations: self.migrations,
         }
     }
 
@@ -450,11 +652,17 @@ impl StoreDriverOn {
     ///
     /// Checks that the store and model are in sync and that the given deleted entries are wiped.
     fn new(
-        store: Store<BufferStorage>,
+        mut store: Store<BufferStorage>,
         model: StoreModel,
         deleted: &[StoreHandle],
+        migrations: &'static [&'static dyn Migration],
     ) -> Result<StoreDriverOn, (StoreInvariant, Store<BufferStorage>)> {
-        let driver = StoreDriverOn { store, model };
+        store.set_write_once(model.write_once());
+        let driver = StoreDriverOn {
+            store,
+            model,
+            migrations,
+        };
         match driver.recover_check(deleted) {
             Ok(()) => Ok(driver),
             Err(error) => Err((error, driver.store)),
@@ -463,13 +671,36 @@ impl StoreDriverOn {
 
     /// Checks that the store and model are in sync and that the given entries are wiped.
     fn recover_check(&self, deleted: &[StoreHandle]) -> Result<(), StoreInvariant> {
-        self.check_deleted(deleted)?;
-        self.check_model()?;
-        self.check_storage()?;
-        Ok(())
+        self.check_deleted(deleted)
+            .and_then(|()| self.check_model())
+            .and_then(|()| self.check_storage())
+            .and_then(|()| self.check_consistency())
+            .map_err(|invariant| self.locate(invariant))
+    }
+
+    /// Adds the location of an invalid storage to an unexpected store error, if known.
+    #[cfg(feature = "diagnostics")]
+    fn locate(&self, invariant: StoreInvariant) -> StoreInvariant {
+        match (invariant, self.store.take_error_context()) {
+            (StoreInvariant::StoreError(StoreError::InvalidStorage), Some(context)) => {
+                StoreInvariant::LocatedStoreError {
+                    error: StoreError::InvalidStorage,
+                    context,
+                }
+            }
+            (invariant, _) => invariant,
+        }
+    }
+
+    /// Adds the location of an invalid storage to an unexpected store error, if known.
+    #[cfg(not(feature = "diagnostics"))]
+    fn locate(&self, invariant: StoreInvariant) -> StoreInvariant {
+        invariant
     }
 
     /// Checks that the given entries are wiped from the storage.
+    ///
+    /// Also checks that the storage contains no data of other deleted entries.
     fn check_deleted(&self, deleted: &[StoreHandle]) -> Result<(), StoreInvariant> {
         for handle in deleted {
             let value = self.store.inspect_value(&handle);
@@ -480,45 +711,70 @@ impl StoreDriverOn {
                 });
             }
         }
+        let words = self.store.unwiped_words()?.len();
+        if words > 0 {
+            return Err(StoreInvariant::NotScrubbed { words });
+        }
         Ok(())
     }
 
     /// Checks that the store and model are in sync.
+    ///
+    /// All differences are reported, not only the first one.
     fn check_model(&self) -> Result<(), StoreInvariant> {
         let mut model_content = self.model.content().clone();
+        let mut only_in_store = Vec::new();
+        let mut different_values = Vec::new();
         for handle in self.store.iter()? {
             let handle = handle?;
-            let model_value = match model_content.remove(&handle.get_key()) {
+            let key = handle.get_key();
+            let model_value = match model_content.remove(&key) {
                 None => {
-                    return Err(StoreInvariant::OnlyInStore {
-                        key: handle.get_key(),
-                    })
+                    only_in_store.push(key);
+                    continue;
                 }
                 Some(x) => x,
             };
             let store_value = handle.get_value(&self.store)?.into_boxed_slice();
             if store_value != model_value {
-                return Err(StoreInvariant::DifferentValue {
-                    key: handle.get_key(),
+                different_values.push(DifferentValue {
+                    key,
                     store: store_value,
                     model: model_value,
                 });
             }
         }
-        if let Some(&key) = model_content.keys().next() {
-            return Err(StoreInvariant::OnlyInModel { key });
-        }
+        let mut only_in_model: Vec<usize> = model_content.keys().cloned().collect();
+        only_in_store.sort_unstable();
+        only_in_model.sort_unstable();
+        different_values.sort_unstable_by_key(|x| x.key);
         let store_capacity = self.store.capacity()?.remaining();
         let model_capacity = self.model.capacity().remaining();
-        if store_capacity != model_capacity {
-            return Err(StoreInvariant::DifferentCapacity {
-                store: store_capacity,
-                model: model_capacity,
+        if !only_in_store.is_empty()
+            || !only_in_model.is_empty()
+            || !different_values.is_empty()
+            || store_capacity != model_capacity
+        {
+            return Err(StoreInvariant::DifferentContent {
+                only_in_store,
+                only_in_model,
+                different_values,
+                store_capacity,
+                model_capacity,
             });
         }
         Ok(())
     }
 
+    /// Checks that the store reports itself as consistent.
+    fn check_consistency(&self) -> Result<(), StoreInvariant> {
+        let check = self.store.check()?;
+        if !check.is_consistent() || check.unwiped_words > 0 {
+            return Err(StoreInvariant::Inconsistent { check });
+        }
+        Ok(())
+    }
+
     /// Checks that the store is tracking lifetime correctly.
     fn check_storage(&self) -> Result<(), StoreInvariant> {
         let format = self.model.format();
@@ -599,3 +855,73 @@ impl<'a> StoreInterruption<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_different_content() {
+        let invariant = StoreInvariant::DifferentContent {
+            only_in_store: vec![3],
+            only_in_model: vec![5, 8],
+            different_values: vec![DifferentValue {
+                key: 7,
+                store: vec![0x00; 10].into_boxed_slice(),
+                model: vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]
+                    .into_boxed_slice(),
+            }],
+            store_capacity: 12,
+            model_capacity: 14,
+        };
+        assert_eq!(
+            format!("{}", invariant),
+            "\
+The store content differs from the model.
+- key 3 is only in the store
++ key 5 is only in the model
++ key 8 is only in the model
+~ key 7 has a different value:
+          store                   | model
+    0000  00 00 00 00 00 00 00 00 | 00 00 00 00 00 00 00 00
+  ! 0008  00 00                   | 01
+remaining capacity: store 12 model 14 (delta -2)
+"
+        );
+    }
+
+    #[test]
+    fn display_interrupted() {
+        let invariant = StoreInvariant::Interrupted {
+            rollback: Box::new(StoreInvariant::NoLifetime),
+            complete: Box::new(StoreInvariant::StoreError(StoreError::InvalidStorage)),
+        };
+        assert_eq!(
+            format!("{}", invariant),
+            "\
+The store did not recover an interrupted operation.
+Not rolled back because:
+  The store reached its lifetime.
+Not completed because:
+  The store returned an unexpected error: InvalidStorage
+"
+        );
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn display_located_store_error() {
+        let invariant = StoreInvariant::LocatedStoreError {
+            error: StoreError::InvalidStorage,
+            context: StoreErrorContext {
+                page: 3,
+                word: 7,
+                tag: "marker entry",
+            },
+        };
+        assert_eq!(
+            format!("{}", invariant),
+            "The store returned an unexpected error: InvalidStorage at page 3, word 7 (marker entry)\n"
+        );
+    }
+}
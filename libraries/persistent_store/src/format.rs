@@ -107,6 +107,18 @@ const MAX_VALUE_LEN: Nat = 1023;
 /// Currently, the store only supports transactions with at most 31 updates.
 const MAX_UPDATES: Nat = 31;
 
+/// Maximum number of keys removed by a single remove entry.
+///
+/// Currently, the store only supports removing ranges of at most 512 keys, because there are 9
+/// bits left in remove entries.
+const MAX_REMOVE_KEYS: Nat = 512;
+
+/// Maximum format version.
+///
+/// Currently, the store only supports 4 format versions, because there are 2 bits left in the init
+/// info of a page.
+pub const MAX_FORMAT_VERSION: Nat = 3;
+
 /// Maximum number of words per virtual page.
 const MAX_VIRT_PAGE_SIZE: Nat = div_ceil(MAX_PAGE_SIZE, WORD_SIZE) - CONTENT_WORD;
 
@@ -234,6 +246,11 @@ impl Format {
         MAX_UPDATES
     }
 
+    /// The maximum number of keys removed by a range removal.
+    pub fn max_remove_keys(&self) -> Nat {
+        MAX_REMOVE_KEYS
+    }
+
     /// The size of a virtual page in words, denoted by `Q`.
     ///
     /// A virtual page is stored in a physical page after the page header.
@@ -327,10 +344,15 @@ impl Format {
         } else {
             let cycle = INIT_CYCLE.get(word);
             let prefix = INIT_PREFIX.get(word);
+            let version = MAX_FORMAT_VERSION - INIT_VERSION.get(word);
             if cycle > self.max_page_erases() || prefix > self.max_prefix_len() {
                 return Err(StoreError::InvalidStorage);
             }
-            WordState::Valid(InitInfo { cycle, prefix })
+            WordState::Valid(InitInfo {
+                cycle,
+                prefix,
+                version,
+            })
         })
     }
 
@@ -339,6 +361,7 @@ impl Format {
         let mut word = ERASED_WORD;
         INIT_CYCLE.set(&mut word, init.cycle);
         INIT_PREFIX.set(&mut word, init.prefix);
+        INIT_VERSION.set(&mut word, MAX_FORMAT_VERSION - init.version);
         WORD_CHECKSUM.set(&mut word, 0);
         word.as_slice()
     }
@@ -395,6 +418,11 @@ impl Format {
                 ID_REMOVE.set(&mut word);
                 REMOVE_KEY.set(&mut word, key);
             }
+            InternalEntry::RemoveRange { min_key, max_key } => {
+                ID_REMOVE.set(&mut word);
+                REMOVE_KEY.set(&mut word, min_key);
+                REMOVE_EXTRA.set(&mut word, MAX_REMOVE_KEYS - 1 - (max_key - min_key));
+            }
         }
         WORD_CHECKSUM.set(&mut word, 0);
         word.as_slice()
@@ -435,7 +463,13 @@ impl Format {
             ParsedWord::Internal(InternalEntry::Marker { count })
         } else if ID_REMOVE.check(word) {
             let key = REMOVE_KEY.get(word);
-            ParsedWord::Internal(InternalEntry::Remove { key })
+            match MAX_REMOVE_KEYS - 1 - REMOVE_EXTRA.get(word) {
+                0 => ParsedWord::Internal(InternalEntry::Remove { key }),
+                extra => ParsedWord::Internal(InternalEntry::RemoveRange {
+                    min_key: key,
+                    max_key: key + extra,
+                }),
+            }
         } else if word == ERASED_WORD {
             return Ok(WordState::Erased);
         } else {
@@ -450,6 +484,7 @@ impl Format {
                 InternalEntry::Clear { min_key } => *min_key > self.max_key(),
                 InternalEntry::Marker { count } => *count > MAX_UPDATES,
                 InternalEntry::Remove { key } => *key > self.max_key(),
+                InternalEntry::RemoveRange { max_key, .. } => *max_key > self.max_key(),
             };
             if invalid {
                 return Err(StoreError::InvalidStorage);
@@ -497,10 +532,10 @@ impl Format {
             // An empty transaction doesn't consume anything.
             0 => 0,
             // Transactions with a single update are optimized by avoiding a marker entry.
-            1 => match &updates[0] {
-                StoreUpdate::Insert { value, .. } => self.entry_size(value),
+            1 => match updates[0].value() {
+                Some(value) => self.entry_size(value),
                 // Transactions with a single update which is a removal don't consume anything.
-                StoreUpdate::Remove { .. } => 0,
+                None => 0,
             },
             // A transaction consumes one word for the marker entry in addition to its updates.
             _ => 1 + updates.iter().map(|x| self.update_capacity(x)).sum::<Nat>(),
@@ -509,9 +544,9 @@ impl Format {
 
     /// Returns the capacity of an update.
     fn update_capacity(&self, update: &StoreUpdate) -> Nat {
-        match update {
-            StoreUpdate::Insert { value, .. } => self.entry_size(value),
-            StoreUpdate::Remove { .. } => 1,
+        match update.value() {
+            Some(value) => self.entry_size(value),
+            None => 1,
         }
     }
 
@@ -520,17 +555,20 @@ impl Format {
         1 + self.bytes_to_words(usize_to_nat(value.len()))
     }
 
-    /// Checks if a transaction is valid and returns its sorted keys.
+    /// Checks if a transaction is valid and returns its domain.
     ///
     /// Returns `None` if the transaction is invalid.
-    pub fn transaction_valid(&self, updates: &[StoreUpdate]) -> Option<Vec<Nat>> {
+    pub fn transaction_valid(&self, updates: &[StoreUpdate]) -> Option<KeyDomain> {
         if usize_to_nat(updates.len()) > self.max_updates() {
             return None;
         }
-        let mut sorted_keys = Vec::with_capacity(updates.len());
+        let mut domain = KeyDomain::with_capacity(updates.len());
         for update in updates {
-            let key = usize_to_nat(update.key());
-            if key > self.max_key() {
+            let keys = update.keys();
+            if keys.start >= keys.end
+                || keys.end > self.max_key() as usize + 1
+                || keys.len() > self.max_remove_keys() as usize
+            {
                 return None;
             }
             if let Some(value) = update.value() {
@@ -538,12 +576,13 @@ impl Format {
                     return None;
                 }
             }
-            match sorted_keys.binary_search(&key) {
-                Ok(_) => return None,
-                Err(pos) => sorted_keys.insert(pos, key),
+            let min_key = usize_to_nat(keys.start);
+            let max_key = usize_to_nat(keys.end - 1);
+            if !domain.insert(min_key, max_key) {
+                return None;
             }
         }
-        Some(sorted_keys)
+        Some(domain)
     }
 
     /// Returns the minimum number of words to represent a given number of bytes.
@@ -583,6 +622,13 @@ bitfield! {
     /// The word index of the first entry in this virtual page.
     INIT_PREFIX: Field <= div_ceil(MAX_VALUE_LEN, WORD_SIZE),
 
+    /// The format version of the page.
+    ///
+    /// The version is stored complemented, such that pages written before versioning was
+    /// introduced (with this field still erased) have version 0. The field uses all the bits left
+    /// before the checksum, such that it can't be widened later.
+    INIT_VERSION: Field <= MAX_FORMAT_VERSION,
+
     #[cfg(test)]
     LEN_INIT: Length,
 }
@@ -614,7 +660,7 @@ bitfield! {
 //   erase 11000...........
 //   clear 11001.................
 //  marker 11010..........
-//  remove 11011.................
+//  remove 11011..........................
 //
 // NOTE: We could pad the internal entries to the right by extending their identifier. This permits
 // to free some space for shorter identifier for future kind of entries.
@@ -703,8 +749,16 @@ bitfield! {
     ID_REMOVE: ConstField = [1 1 0 1 1],
 
     /// The key of the user entry to be removed.
+    ///
+    /// This is the minimum key if the entry removes a range of keys.
     REMOVE_KEY: Field <= MAX_KEY_INDEX,
 
+    /// The number of keys removed after the key of the entry.
+    ///
+    /// The number is stored complemented, such that remove entries written before ranges were
+    /// introduced (with this field still erased) remove a single key.
+    REMOVE_EXTRA: Field <= MAX_REMOVE_KEYS - 1,
+
     #[cfg(test)]
     LEN_REMOVE: Length,
 }
@@ -826,6 +880,9 @@ pub struct InitInfo {
 
     /// The word index of the first entry in this virtual page.
     pub prefix: Nat,
+
+    /// The format version with which this page was initialized.
+    pub version: Nat,
 }
 
 /// Information for a page being compacted.
@@ -912,6 +969,74 @@ pub enum InternalEntry {
         /// The key of the user entry to be removed.
         key: Nat,
     },
+
+    /// Indicates that user entries within a range of at least 2 keys should be deleted.
+    ///
+    /// This is stored as a remove entry. Contrary to removing a single entry, this is also valid
+    /// outside a transaction. It is then handled like a clear entry.
+    RemoveRange {
+        /// The minimum key a user entry should have to be deleted.
+        min_key: Nat,
+
+        /// The maximum key a user entry should have to be deleted.
+        max_key: Nat,
+    },
+}
+
+/// The set of keys affected by a transaction.
+///
+/// This is represented as a list of disjoint inclusive ranges sorted by their minimum key. Each
+/// update of the transaction contributes exactly one range.
+#[derive(Debug, Default)]
+pub struct KeyDomain {
+    ranges: Vec<(Nat, Nat)>,
+}
+
+impl KeyDomain {
+    /// Creates an empty domain for a given number of ranges.
+    pub fn with_capacity(capacity: usize) -> KeyDomain {
+        KeyDomain {
+            ranges: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Adds an inclusive range of keys to the domain.
+    ///
+    /// Returns whether the range is disjoint from the domain. The domain is not modified if the
+    /// range overlaps with it.
+    ///
+    /// # Preconditions
+    ///
+    /// - The range is not empty: `min_key <= max_key`.
+    pub fn insert(&mut self, min_key: Nat, max_key: Nat) -> bool {
+        debug_assert!(min_key <= max_key);
+        let pos = match self.ranges.binary_search_by_key(&min_key, |&(x, _)| x) {
+            Ok(_) => return false,
+            Err(pos) => pos,
+        };
+        if pos > 0 && self.ranges[pos - 1].1 >= min_key {
+            return false;
+        }
+        if pos < self.ranges.len() && self.ranges[pos].0 <= max_key {
+            return false;
+        }
+        self.ranges.insert(pos, (min_key, max_key));
+        true
+    }
+
+    /// Returns whether a key is in the domain.
+    pub fn contains(&self, key: Nat) -> bool {
+        match self.ranges.binary_search_by_key(&key, |&(x, _)| x) {
+            Ok(_) => true,
+            Err(0) => false,
+            Err(pos) => self.ranges[pos - 1].1 >= key,
+        }
+    }
+
+    /// Returns the number of ranges in the domain.
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
 }
 
 /// Returns whether a slice has all bits equal to one.
@@ -956,7 +1081,7 @@ mod tests {
             &LEN_REMOVE,
         ];
         for word in words {
-            assert!(word.pos < pos);
+            assert!(word.pos <= pos);
         }
     }
 
@@ -966,7 +1091,9 @@ mod tests {
         assert_eq!(INIT_CYCLE.len, 16);
         assert_eq!(INIT_PREFIX.pos, 16);
         assert_eq!(INIT_PREFIX.len, 9);
-        assert_eq!(LEN_INIT.pos, 25);
+        assert_eq!(INIT_VERSION.pos, 25);
+        assert_eq!(INIT_VERSION.len, 2);
+        assert_eq!(LEN_INIT.pos, 27);
     }
 
     #[test]
@@ -992,6 +1119,65 @@ mod tests {
         assert_eq!(LEN_HEADER.pos, 32);
     }
 
+    #[test]
+    fn empty_user_ok() {
+        let options = crate::BufferOptions {
+            word_size: 4,
+            page_size: 64,
+            max_word_writes: 2,
+            max_page_erases: 9,
+            strict_mode: true,
+        };
+        let storage = crate::BufferStorage::new(vec![0xff; 5 * 64].into_boxed_slice(), options);
+        let format = Format::new(&storage).unwrap();
+        assert_eq!(format.entry_size(&[]), 1);
+        let entry = format.build_user(42, &[]);
+        assert_eq!(entry.len(), 4);
+        let header = match format.parse_word(Word::from_slice(&entry)) {
+            Ok(WordState::Valid(ParsedWord::Header(header))) => header,
+            _ => panic!("Invalid header"),
+        };
+        assert_eq!(header.key, 42);
+        assert_eq!(header.length, 0);
+        assert!(header.check(None));
+    }
+
+    #[test]
+    fn init_version_ok() {
+        let options = crate::BufferOptions {
+            word_size: 4,
+            page_size: 64,
+            max_word_writes: 2,
+            max_page_erases: 9,
+            strict_mode: true,
+        };
+        let storage = crate::BufferStorage::new(vec![0xff; 5 * 64].into_boxed_slice(), options);
+        let format = Format::new(&storage).unwrap();
+        for version in 0..=MAX_FORMAT_VERSION {
+            let init = InitInfo {
+                cycle: 3,
+                prefix: 2,
+                version,
+            };
+            let word = Word::from_slice(&format.build_init(init));
+            let init = match format.parse_init(word) {
+                Ok(WordState::Valid(init)) => init,
+                _ => panic!("Invalid init info"),
+            };
+            assert_eq!(init.cycle, 3);
+            assert_eq!(init.prefix, 2);
+            assert_eq!(init.version, version);
+        }
+        // Pages written with a single version bit still have the same version.
+        let init = InitInfo {
+            cycle: 0,
+            prefix: 0,
+            version: 1,
+        };
+        let word = Word::from_slice(&format.build_init(init));
+        assert_eq!(word.0 >> 25 & 0b11, 0b10);
+    }
+
     #[test]
     fn erase_ok() {
         assert_eq!(ID_ERASE.field.pos, 0);
@@ -1029,7 +1215,27 @@ mod tests {
         assert_eq!(ID_REMOVE.value, 0b11011);
         assert_eq!(REMOVE_KEY.pos, 5);
         assert_eq!(REMOVE_KEY.len, 12);
-        assert_eq!(LEN_REMOVE.pos, 17);
+        assert_eq!(REMOVE_EXTRA.pos, 17);
+        assert_eq!(REMOVE_EXTRA.len, 9);
+        assert_eq!(LEN_REMOVE.pos, 26);
+    }
+
+    #[test]
+    fn key_domain_ok() {
+        let mut domain = KeyDomain::default();
+        assert!(domain.insert(5, 5));
+        assert!(domain.insert(10, 19));
+        assert!(domain.insert(0, 3));
+        assert!(!domain.insert(5, 5));
+        assert!(!domain.insert(4, 5));
+        assert!(!domain.insert(19, 20));
+        assert!(!domain.insert(6, 30));
+        assert!(domain.insert(6, 9));
+        assert_eq!(domain.len(), 4);
+        for key in 0..20 {
+            assert_eq!(domain.contains(key), key != 4);
+        }
+        assert!(!domain.contains(20));
     }
 
     #[test]
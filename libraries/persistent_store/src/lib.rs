@@ -35,7 +35,8 @@
 //!
 //! An _entry_ is a pair of a key and a value. A _key_ is a number between 0
 //! and 4095. A _value_ is a byte slice with a length between 0 and 1023 bytes (for
-//! large enough pages).
+//! large enough pages). Empty values are regular values: they are distinct from
+//! the absence of value and only cost one word, which makes them suited for flags.
 //!
 //! The store provides the following _updates_:
 //! -   Given a key and a value, `Insert` updates the store such that the value is
@@ -44,10 +45,22 @@
 //!     the key. The values for other keys are left unchanged. Additionally, if there
 //!     was a value associated with the key, the value is wiped from the storage
 //!     (all its bits are set to 0).
+//! -   Given a non-empty range of at most 512 keys, `RemoveRange` behaves like
+//!     `Remove` for all the keys in the range.
+//! -   Given a key and a value, `InsertIfAbsent` behaves like `Insert` if there is no
+//!     value associated with the key. Otherwise, it has no effect.
+//! -   Given a key, an old value, and a new value, `CompareAndSwap` behaves like
+//!     `Insert` with the new value if the old value is associated with the key.
+//!     Otherwise, the transaction fails. It has no effect if both values are equal.
+//!
+//! Conditional updates are evaluated against the store before the transaction. In
+//! particular, the condition and the update are atomic.
 //!
 //! The store provides the following _read-only operations_:
 //! -   `Iter` iterates through the store returning all entries exactly once. The
 //!     iteration order is not specified but stable between mutable operations.
+//! -   Given a key, `Contains` returns whether a value is associated with the key,
+//!     without reading the value.
 //! -   `Capacity` returns how many words can be stored before the store is full.
 //! -   `Lifetime` returns how many words can be written before the storage lifetime
 //!     is consumed.
@@ -61,6 +74,13 @@
 //!     many words can be written without compaction. This operation has no effect
 //!     on the store but may still mutate its storage. In particular, the store has
 //!     the same capacity but a possibly reduced lifetime.
+//! -   Given a key and a value, `Replace` behaves like `Insert` unless the value is
+//!     already associated with the key, in which case nothing is written.
+//! -   `Scrub` wipes the remaining data of deleted entries. This operation has no
+//!     effect on the store and only writes to the storage if it was modified
+//!     outside of the store: removed values are wiped before `Remove` returns and
+//!     interrupted wipes are completed when the store is mounted. This is checked
+//!     by the fuzzer after each operation.
 //!
 //! A mutable operation is _atomic_ if, when power is lost during the operation, the
 //! store is either updated (as if the operation succeeded) or left unchanged (as if
@@ -86,6 +106,8 @@
 //! -   `Remove` doesn't use capacity if alone in the transaction and 1 transient
 //!     word otherwise. If an entry was deleted, the words used by its insertion are
 //!     freed.
+//! -   `RemoveRange` uses capacity like `Remove` regardless of the number of
+//!     entries it deletes.
 //! -   `Transaction` uses 1 transient word. In addition, the updates of the
 //!     transaction use and free words as described above.
 //! -   `Clear` doesn't use capacity and frees the words used by the insertion of
@@ -142,7 +164,8 @@
 //!
 //! We build a virtual storage from the physical storage using the first 2 words of
 //! each page:
-//! -   The first word contains the number of times the page has been erased.
+//! -   The first word contains the number of times the page has been erased and the
+//!     format version with which the page was initialized.
 //! -   The second word contains the starting word to which this page is being moved
 //!     during compaction.
 //!
@@ -196,10 +219,19 @@
 //! -   Marker: A word used during the `Transaction` operation. It contains the
 //!     number of updates following the marker and a checksum.
 //! -   Remove: A word used during the `Transaction` operation. It contains the key
-//!     of the entry to be removed and a checksum.
+//!     of the entry to be removed, the number of following keys to be removed,
+//!     and a checksum. When removing more than one key, it is also used alone by
+//!     the `RemoveRange` update.
 //!
 //! Checksums are the number of bits equal to 0.
 //!
+//! The format version of the store is the oldest version of its initialized pages.
+//! When a store is mounted with an older format version, the migrations to the
+//! current version are applied to the storage and its pages are compacted until
+//! they are all initialized with the current version. Migrations must be
+//! idempotent, because an interrupted migration is applied again at the next
+//! mount.
+//!
 //! # Proofs
 //!
 //! ## Compaction
@@ -287,6 +319,43 @@
 //! ```
 //!
 //!
+//! ## Wear leveling
+//!
+//! The store does not need to relocate long-lived entries explicitly: compaction
+//! always erases the first page of the window, after copying its live entries
+//! (however old) to the tail. The window only shifts by one page at a time, so
+//! the pages are erased in turn and the erase counts of any two pages differ by
+//! at most one. In particular, page `p` has been erased `c + 1` times if `p` is
+//! before the head page and `c` times otherwise, where `c` is the erase cycle of
+//! the head page. The relocation cost is bounded by one page per compaction.
+//!
+//! For the same reason, a compaction policy (used to compact ahead of time, for
+//! example while the device is idle) only decides when the head page is compacted
+//! and never picks another page.
+//!
+//! This is checked by the fuzzer, which compares the erase count of each page
+//! with the one expected from the head of the window.
+//!
+//! ## Critical entries
+//!
+//! Entries that must never be lost, like cryptographic master keys, may opt in to
+//! a shadow-copy mode. A critical entry uses a pointer key and two shadow keys.
+//! An update writes the new value under the shadow key not holding the current
+//! value, then atomically flips the pointer entry to that shadow key. The previous
+//! value stays under the other shadow key until the next update. Reading a
+//! critical entry falls back to the previous value if the current shadow entry is
+//! missing, so a single lost entry can't lose the critical entry.
+//!
+//! ## Write-once entries
+//!
+//! Entries written once, like provisioned keys, may be protected against later
+//! mistakes. The user sets a predicate telling which keys are write-once. An
+//! update that would replace or remove a present write-once entry fails with
+//! `WriteOnce` before writing anything. This includes transactions, clear
+//! operations, and remove range updates covering such an entry. The predicate is
+//! not persisted and the model simulates the same rule, which is checked by the
+//! fuzzer.
+//!
 //! ## Checksum
 //!
 //! The main property we want is that all partially written/erased words are either
@@ -357,19 +426,27 @@ mod format;
 mod model;
 mod storage;
 mod store;
+#[cfg(feature = "std")]
+mod timed;
 
 #[cfg(feature = "std")]
-pub use self::buffer::{BufferCorruptFunction, BufferOptions, BufferStorage};
+pub use self::buffer::{BufferCorruptFunction, BufferOptions, BufferRecord, BufferStorage};
 #[cfg(feature = "std")]
 pub use self::driver::{
-    StoreDriver, StoreDriverOff, StoreDriverOn, StoreInterruption, StoreInvariant,
+    DifferentValue, StoreDriver, StoreDriverOff, StoreDriverOn, StoreInterruption, StoreInvariant,
 };
 #[cfg(feature = "std")]
 pub use self::model::{StoreModel, StoreOperation};
 pub use self::storage::{Storage, StorageError, StorageIndex, StorageResult};
+#[cfg(feature = "diagnostics")]
+pub use self::store::StoreErrorContext;
 pub use self::store::{
-    Store, StoreError, StoreHandle, StoreIter, StoreRatio, StoreResult, StoreUpdate,
+    CompactionPolicy, CriticalKeys, Migration, PageStats, ReclaimablePolicy, Store, StoreCheck,
+    StoreError, StoreHandle, StoreIter, StoreMutation, StoreObserver, StoreRatio, StoreResult,
+    StoreUpdate, StoreWriteOnce,
 };
+#[cfg(feature = "std")]
+pub use self::timed::{TimedOptions, TimedStorage};
 
 /// Internal representation of natural numbers.
 ///
@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use crate::format::Format;
-use crate::{usize_to_nat, StoreError, StoreRatio, StoreResult, StoreUpdate};
+use crate::{usize_to_nat, StoreError, StoreRatio, StoreResult, StoreUpdate, StoreWriteOnce};
 use std::collections::HashMap;
 
 /// Models the mutable operations of a store.
@@ -26,6 +26,9 @@ pub struct StoreModel {
 
     /// The modeled storage configuration.
     format: Format,
+
+    /// The modeled predicate of the write-once keys, if any.
+    write_once: Option<StoreWriteOnce>,
 }
 
 /// Mutable operations on a store.
@@ -54,7 +57,21 @@ impl StoreModel {
     /// Creates an empty model for a given storage configuration.
     pub fn new(format: Format) -> StoreModel {
         let content = HashMap::new();
-        StoreModel { content, format }
+        StoreModel {
+            content,
+            format,
+            write_once: None,
+        }
+    }
+
+    /// Sets the predicate of the write-once keys.
+    pub fn set_write_once(&mut self, write_once: Option<StoreWriteOnce>) {
+        self.write_once = write_once;
+    }
+
+    /// Returns the predicate of the write-once keys.
+    pub fn write_once(&self) -> Option<StoreWriteOnce> {
+        self.write_once
     }
 
     /// Returns the modeled content.
@@ -94,6 +111,19 @@ impl StoreModel {
         if self.format.transaction_valid(&updates).is_none() {
             return Err(StoreError::InvalidArgument);
         }
+        // Resolve the conditional updates. Fail if a condition does not hold.
+        let mut resolved = Vec::with_capacity(updates.len());
+        for update in updates {
+            let current = self.content.get(&update.key()).map(|x| &x[..]);
+            if let Some(update) = update.resolve(current)? {
+                resolved.push(update);
+            }
+        }
+        let updates = resolved;
+        // Fail if an update modifies a write-once entry.
+        for update in &updates {
+            self.check_write_once(update.keys())?;
+        }
         // Fail if there is not enough capacity.
         let capacity = self.format.transaction_capacity(&updates) as usize;
         if self.capacity().remaining() < capacity {
@@ -108,6 +138,12 @@ impl StoreModel {
                 StoreUpdate::Remove { key } => {
                     self.content.remove(&key);
                 }
+                StoreUpdate::RemoveRange { keys } => {
+                    self.content.retain(|k, _| !keys.contains(k));
+                }
+                StoreUpdate::InsertIfAbsent { .. } | StoreUpdate::CompareAndSwap { .. } => {
+                    unreachable!()
+                }
             }
         }
         Ok(())
@@ -118,10 +154,25 @@ impl StoreModel {
         if min_key > self.format.max_key() as usize {
             return Err(StoreError::InvalidArgument);
         }
+        self.check_write_once(min_key..usize::MAX)?;
         self.content.retain(|&k, _| k < min_key);
         Ok(())
     }
 
+    /// Fails if a range of keys contains a present write-once entry.
+    fn check_write_once(&self, keys: core::ops::Range<usize>) -> StoreResult<()> {
+        if let Some(write_once) = self.write_once {
+            if self
+                .content
+                .keys()
+                .any(|&k| keys.contains(&k) && write_once(k))
+            {
+                return Err(StoreError::WriteOnce);
+            }
+        }
+        Ok(())
+    }
+
     /// Applies a prepare operation.
     fn prepare(&self, length: usize) -> StoreResult<()> {
         if self.capacity().remaining() < length {
@@ -13,8 +13,8 @@
 // limitations under the License.
 
 use crate::format::{
-    is_erased, CompactInfo, Format, Header, InitInfo, InternalEntry, Padding, ParsedWord, Position,
-    Word, WordState,
+    is_erased, CompactInfo, Format, Header, InitInfo, InternalEntry, KeyDomain, Padding,
+    ParsedWord, Position, Word, WordState, MAX_FORMAT_VERSION,
 };
 #[cfg(feature = "std")]
 pub use crate::model::{StoreModel, StoreOperation};
@@ -22,14 +22,16 @@ use crate::{usize_to_nat, Nat, Storage, StorageError, StorageIndex};
 #[cfg(feature = "std")]
 pub use crate::{
     BufferStorage, StoreDriver, StoreDriverOff, StoreDriverOn, StoreInterruption, StoreInvariant,
+    TimedStorage,
 };
 use alloc::vec::Vec;
+#[cfg(feature = "diagnostics")]
+use core::cell::Cell;
 use core::cmp::{max, min, Ordering};
-#[cfg(feature = "std")]
-use std::collections::HashSet;
+use core::ops::Range;
 
 /// Errors returned by store operations.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum StoreError {
     /// Invalid argument.
     ///
@@ -63,6 +65,28 @@ pub enum StoreError {
     ///
     /// [recovered]: struct.Store.html#method.recover
     InvalidStorage,
+
+    /// A conditional update does not hold.
+    ///
+    /// The store is left unchanged. The operation will repeatedly fail until the entry is updated.
+    ConditionFailed,
+
+    /// Storage was written with a different geometry.
+    ///
+    /// The page size or number of pages of the storage differs from the one used when the storage
+    /// was written. The storage is left unchanged. It should either be mounted with its original
+    /// geometry or be [reformatted], in which case the store would be empty and have lost track of
+    /// lifetime.
+    ///
+    /// [reformatted]: struct.Store.html#method.new_or_reformat
+    GeometryMismatch,
+
+    /// An update would modify or remove a [write-once] entry.
+    ///
+    /// The store is left unchanged. The operation will repeatedly fail for this entry.
+    ///
+    /// [write-once]: struct.Store.html#method.set_write_once
+    WriteOnce,
 }
 
 impl From<StorageError> for StoreError {
@@ -75,6 +99,30 @@ impl From<StorageError> for StoreError {
     }
 }
 
+/// Location in the storage where the store detected an invalid storage.
+///
+/// This is only recorded with the `diagnostics` feature. The location is physical, such that it
+/// can be matched with a flash dump.
+#[cfg(feature = "diagnostics")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StoreErrorContext {
+    /// The physical page.
+    pub page: usize,
+
+    /// The word in the physical page.
+    pub word: usize,
+
+    /// What the store was parsing, for example the kind of entry found.
+    pub tag: &'static str,
+}
+
+#[cfg(feature = "diagnostics")]
+impl core::fmt::Display for StoreErrorContext {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "page {}, word {} ({})", self.page, self.word, self.tag)
+    }
+}
+
 /// Result of store operations.
 pub type StoreResult<T> = Result<T, StoreError>;
 
@@ -88,7 +136,7 @@ pub type StoreResult<T> = Result<T, StoreError>;
 ///
 /// [capacity]: struct.Store.html#method.capacity
 /// [lifetime]: struct.Store.html#method.lifetime
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
 pub struct StoreRatio {
     /// How much of the metric is used.
     pub(crate) used: Nat,
@@ -114,6 +162,60 @@ impl StoreRatio {
     }
 }
 
+/// Report of a consistency [check] of the store.
+///
+/// A store that was successfully opened or recovered is expected to be [consistent]. The other
+/// fields are informative.
+///
+/// [check]: struct.Store.html#method.check
+/// [consistent]: struct.StoreCheck.html#method.is_consistent
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreCheck {
+    /// The number of user entries.
+    pub entries: usize,
+
+    /// The number of user entries whose checksum does not match their footer.
+    pub corrupted_entries: usize,
+
+    /// The number of partially written entries.
+    pub partial_entries: usize,
+
+    /// The number of internal entries that are not completed.
+    pub internal_entries: usize,
+
+    /// The number of keys present in more than one user entry.
+    pub duplicate_keys: usize,
+
+    /// The number of words of deleted entries which are not wiped.
+    ///
+    /// Those words are expected after an operation was interrupted and are wiped by [scrub].
+    ///
+    /// [scrub]: struct.Store.html#method.scrub
+    pub unwiped_words: usize,
+
+    /// Whether the tail found by scanning the entries is the tail of the store.
+    pub tail_consistent: bool,
+
+    /// The number of written words after the tail.
+    pub written_after_tail: usize,
+
+    /// The capacity accounted from the user entries.
+    pub capacity: StoreRatio,
+}
+
+impl StoreCheck {
+    /// Returns whether the store is consistent.
+    pub fn is_consistent(&self) -> bool {
+        self.corrupted_entries == 0
+            && self.partial_entries == 0
+            && self.internal_entries == 0
+            && self.duplicate_keys == 0
+            && self.tail_consistent
+            && self.written_after_tail == 0
+            && self.capacity.used <= self.capacity.total
+    }
+}
+
 /// Safe pointer to an entry.
 ///
 /// A store handle stays valid at least until the next mutable operation. Store operations taking a
@@ -136,6 +238,11 @@ impl StoreHandle {
         self.key as usize
     }
 
+    /// Returns the length in bytes of the value of the entry.
+    pub fn get_length(&self) -> usize {
+        self.len as usize
+    }
+
     /// Returns the value of the entry.
     ///
     /// # Errors
@@ -154,14 +261,51 @@ pub enum StoreUpdate {
 
     /// Removes an entry from the store.
     Remove { key: usize },
+
+    /// Removes all entries with a key in a range from the store.
+    ///
+    /// The range must not be empty. It costs the same as a single removal regardless of the number
+    /// of entries it removes.
+    RemoveRange { keys: Range<usize> },
+
+    /// Inserts an entry in the store if there is no entry for the same key.
+    ///
+    /// If an entry for the same key is already present, the update has no effect.
+    InsertIfAbsent { key: usize, value: Vec<u8> },
+
+    /// Replaces an entry in the store if its current value is `old`.
+    ///
+    /// If there is no entry for the key or its value differs from `old`, the transaction fails
+    /// with `ConditionFailed`. If `old` and `new` are equal, the update has no effect.
+    CompareAndSwap {
+        key: usize,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    },
 }
 
 impl StoreUpdate {
     /// Returns the key affected by the update.
+    ///
+    /// For a range removal, this is the first key of the range.
     pub fn key(&self) -> usize {
         match *self {
             StoreUpdate::Insert { key, .. } => key,
             StoreUpdate::Remove { key } => key,
+            StoreUpdate::RemoveRange { ref keys } => keys.start,
+            StoreUpdate::InsertIfAbsent { key, .. } => key,
+            StoreUpdate::CompareAndSwap { key, .. } => key,
+        }
+    }
+
+    /// Returns the range of keys affected by the update.
+    pub fn keys(&self) -> Range<usize> {
+        match *self {
+            StoreUpdate::RemoveRange { ref keys } => keys.clone(),
+            _ => {
+                let key = self.key();
+                key..key.saturating_add(1)
+            }
         }
     }
 
@@ -169,11 +313,182 @@ impl StoreUpdate {
     pub fn value(&self) -> Option<&[u8]> {
         match self {
             StoreUpdate::Insert { value, .. } => Some(value),
-            StoreUpdate::Remove { .. } => None,
+            StoreUpdate::Remove { .. } | StoreUpdate::RemoveRange { .. } => None,
+            StoreUpdate::InsertIfAbsent { value, .. } => Some(value),
+            StoreUpdate::CompareAndSwap { new, .. } => Some(new),
+        }
+    }
+
+    /// Returns whether the update depends on the current value of its key.
+    pub fn is_conditional(&self) -> bool {
+        match self {
+            StoreUpdate::Insert { .. }
+            | StoreUpdate::Remove { .. }
+            | StoreUpdate::RemoveRange { .. } => false,
+            StoreUpdate::InsertIfAbsent { .. } | StoreUpdate::CompareAndSwap { .. } => true,
         }
     }
+
+    /// Resolves the update given the current value of its key.
+    ///
+    /// Returns an unconditional update, or `None` if the update has no effect.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ConditionFailed` if the condition of a compare-and-swap does not hold.
+    pub(crate) fn resolve(&self, current: Option<&[u8]>) -> StoreResult<Option<StoreUpdate>> {
+        Ok(match self {
+            StoreUpdate::Insert { .. }
+            | StoreUpdate::Remove { .. }
+            | StoreUpdate::RemoveRange { .. } => Some(self.clone()),
+            StoreUpdate::InsertIfAbsent { key, value } => match current {
+                Some(_) => None,
+                None => Some(StoreUpdate::Insert {
+                    key: *key,
+                    value: value.clone(),
+                }),
+            },
+            StoreUpdate::CompareAndSwap { key, old, new } => {
+                if current != Some(&old[..]) {
+                    return Err(StoreError::ConditionFailed);
+                }
+                if old == new {
+                    None
+                } else {
+                    Some(StoreUpdate::Insert {
+                        key: *key,
+                        value: new.clone(),
+                    })
+                }
+            }
+        })
+    }
+}
+
+/// Keys of a critical entry.
+///
+/// The value of a critical entry is stored under one of two shadow keys. The pointer key holds the
+/// index of the shadow key with the current value. The other shadow key holds the previous value,
+/// if any. The three keys must be distinct.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CriticalKeys {
+    /// The key of the pointer entry.
+    pub pointer: usize,
+
+    /// The keys of the shadow entries.
+    pub shadows: [usize; 2],
+}
+
+/// Upgrades a storage from a format version to the next one.
+///
+/// Migrations are applied in order when mounting a store, such that the migration at index `i`
+/// upgrades the storage from version `i` to version `i + 1`. The format version of a store is the
+/// number of its migrations.
+pub trait Migration {
+    /// Migrates the storage in place.
+    ///
+    /// The storage is in the previous format version and may contain an interrupted operation. The
+    /// migration may itself be interrupted, in which case it is applied again when the store is
+    /// mounted again. Migrations must thus be idempotent.
+    fn migrate(&self, storage: &mut dyn Storage) -> StoreResult<()>;
 }
 
+/// Word statistics of a page, as returned by [page_stats].
+///
+/// [page_stats]: struct.Store.html#method.page_stats
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PageStats {
+    /// The physical page.
+    pub page: usize,
+
+    /// The number of words of user entries, which compaction copies to the tail.
+    pub live_words: usize,
+
+    /// The number of words of deleted entries and padding, which compaction reclaims.
+    pub dead_words: usize,
+}
+
+/// Decides whether to compact ahead of time.
+///
+/// The store is a log, so compaction always applies to the oldest page (the head). This is also
+/// what levels the wear of the pages. A policy may only decide when to compact: for example while
+/// the device is idle and the head page is cheap to compact, such that operations are less likely
+/// to compact an expensive page when it is needed.
+pub trait CompactionPolicy {
+    /// Returns whether the head page should be compacted.
+    ///
+    /// The statistics are given for all pages in compaction order, starting with the head page.
+    fn should_compact(&self, pages: &[PageStats]) -> bool;
+}
+
+/// Compacts the head page when enough of its written words are reclaimable.
+#[derive(Clone, Copy, Debug)]
+pub struct ReclaimablePolicy {
+    /// Minimum percentage of dead words among the written words of the head page.
+    pub min_dead_percent: usize,
+}
+
+impl CompactionPolicy for ReclaimablePolicy {
+    fn should_compact(&self, pages: &[PageStats]) -> bool {
+        match pages.first() {
+            None => false,
+            Some(head) => {
+                let written = head.live_words + head.dead_words;
+                head.dead_words > 0 && head.dead_words * 100 >= self.min_dead_percent * written
+            }
+        }
+    }
+}
+
+/// Mutation of the store, as reported to an [observer].
+///
+/// [observer]: struct.Store.html#method.set_observer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StoreMutation {
+    /// An entry was inserted or replaced.
+    Insert {
+        /// The key of the entry.
+        key: usize,
+
+        /// The length in bytes of the value.
+        len: usize,
+    },
+
+    /// The entry of a key was removed, if it was present.
+    Remove {
+        /// The key of the entry.
+        key: usize,
+    },
+
+    /// The entries with a key in a range were removed.
+    RemoveRange {
+        /// The first key of the range.
+        min_key: usize,
+
+        /// The last key of the range (inclusive).
+        max_key: usize,
+    },
+}
+
+/// Observes the mutations of the store.
+///
+/// The observer is called after each successful mutation. A transaction reports each of its
+/// updates once it is applied. Mutations done while recovering an interrupted operation when the
+/// store is mounted are not reported.
+pub type StoreObserver = fn(&StoreMutation);
+
+/// Tells whether a key is write-once.
+///
+/// Once present, the entry of a write-once key can't be replaced nor removed: the mutation fails
+/// with `WriteOnce` and the store is left unchanged. Inserting the entry while it is absent is
+/// allowed.
+pub type StoreWriteOnce = fn(usize) -> bool;
+
+/// Length in bytes of the buffer used to wipe words.
+///
+/// This must be a multiple of the word size.
+const WIPE_CHUNK_LEN: usize = 64;
+
 /// Implements a store with a map interface over a storage.
 #[derive(Clone)]
 pub struct Store<S: Storage> {
@@ -182,6 +497,19 @@ pub struct Store<S: Storage> {
 
     /// The storage configuration.
     format: Format,
+
+    /// The format version of newly initialized pages.
+    version: Nat,
+
+    /// The location of the last invalid storage detected.
+    #[cfg(feature = "diagnostics")]
+    error_context: Cell<Option<StoreErrorContext>>,
+
+    /// The observer of the mutations, if any.
+    observer: Option<StoreObserver>,
+
+    /// The predicate of the write-once keys, if any.
+    write_once: Option<StoreWriteOnce>,
 }
 
 impl<S: Storage> Store<S> {
@@ -194,13 +522,73 @@ impl<S: Storage> Store<S> {
     /// # Errors
     ///
     /// Returns `InvalidArgument` if the storage is not supported.
+    ///
+    /// Returns `GeometryMismatch` if the storage was written with a different geometry.
     pub fn new(storage: S) -> Result<Store<S>, (StoreError, S)> {
+        Store::new_with_migrations(storage, &[])
+    }
+
+    /// Resumes or initializes a store, erasing the storage if its geometry does not match.
+    ///
+    /// This is like [`new`] but a storage written with a different geometry is erased and
+    /// initialized instead of being rejected. All entries are lost. This policy is meant for
+    /// development boards, where the storage layout may change between flashes.
+    ///
+    /// [`new`]: struct.Store.html#method.new
+    pub fn new_or_reformat(storage: S) -> Result<Store<S>, (StoreError, S)> {
+        match Store::new(storage) {
+            Err((StoreError::GeometryMismatch, mut storage)) => {
+                for page in 0..storage.num_pages() {
+                    if let Err(error) = storage.erase_page(page) {
+                        return Err((error.into(), storage));
+                    }
+                }
+                Store::new(storage)
+            }
+            result => result,
+        }
+    }
+
+    /// Resumes or initializes a store for a given storage and format history.
+    ///
+    /// This is like [`new`] but the format version is the number of migrations instead of 0. If
+    /// the storage has an older format version, the missing migrations are applied and all pages
+    /// are compacted until they are upgraded to the current format version.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidArgument` if the storage is not supported or there are too many migrations.
+    ///
+    /// Returns `InvalidStorage` if the storage has a newer format version.
+    ///
+    /// Returns `GeometryMismatch` if the storage was written with a different geometry.
+    ///
+    /// [`new`]: struct.Store.html#method.new
+    pub fn new_with_migrations(
+        storage: S,
+        migrations: &[&dyn Migration],
+    ) -> Result<Store<S>, (StoreError, S)> {
         let format = match Format::new(&storage) {
             None => return Err((StoreError::InvalidArgument, storage)),
             Some(x) => x,
         };
-        let mut store = Store { storage, format };
-        if let Err(error) = store.recover() {
+        if migrations.len() > MAX_FORMAT_VERSION as usize {
+            return Err((StoreError::InvalidArgument, storage));
+        }
+        let version = usize_to_nat(migrations.len());
+        let mut store = Store {
+            storage,
+            format,
+            version,
+            #[cfg(feature = "diagnostics")]
+            error_context: Cell::new(None),
+            observer: None,
+            write_once: None,
+        };
+        if let Err(error) = store.check_geometry() {
+            return Err((error, store.storage));
+        }
+        if let Err(error) = store.migrate(migrations) {
             return Err((error, store.storage));
         }
         Ok(store)
@@ -208,7 +596,17 @@ impl<S: Storage> Store<S> {
 
     /// Iterates over the entries.
     pub fn iter<'a>(&'a self) -> StoreResult<StoreIter<'a, S>> {
-        StoreIter::new(self)
+        StoreIter::new(self, 0..self.format.max_key() as usize + 1)
+    }
+
+    /// Iterates over the entries with a key in a given range.
+    ///
+    /// This is like [`iter`] but entries outside the range are skipped using their header only,
+    /// without creating a handle for them. This is useful to enumerate a partition of the keys.
+    ///
+    /// [`iter`]: struct.Store.html#method.iter
+    pub fn iter_range<'a>(&'a self, keys: Range<usize>) -> StoreResult<StoreIter<'a, S>> {
+        StoreIter::new(self, keys)
     }
 
     /// Returns the current capacity in words.
@@ -225,7 +623,7 @@ impl<S: Storage> Store<S> {
                 ParsedEntry::Tail => break,
                 ParsedEntry::Padding => (),
                 ParsedEntry::User(_) => used += pos - entry_pos,
-                _ => return Err(StoreError::InvalidStorage),
+                _ => return Err(self.unexpected_entry(entry_pos)),
             }
         }
         Ok(StoreRatio { used, total })
@@ -242,6 +640,105 @@ impl<S: Storage> Store<S> {
         Ok(StoreRatio { used, total })
     }
 
+    /// Returns the word statistics of all pages, starting with the head page.
+    ///
+    /// Pages are in compaction order. Words are attributed to the page where they are written,
+    /// such that an entry spanning two pages counts for both. Page headers are not counted, and
+    /// neither are the words before the head, which belong to an entry copied by compaction.
+    pub fn page_stats(&self) -> StoreResult<Vec<PageStats>> {
+        let num_pages = self.format.num_pages();
+        let head = self.head()?;
+        let head_page = head.page(&self.format);
+        let mut stats: Vec<PageStats> = (0..num_pages)
+            .map(|i| PageStats {
+                page: ((head_page + i) % num_pages) as usize,
+                ..PageStats::default()
+            })
+            .collect();
+        let mut pos = head;
+        let end = pos + self.format.virt_size();
+        while pos < end {
+            let entry_pos = pos;
+            let is_live = match self.parse_entry(&mut pos)? {
+                ParsedEntry::Tail => break,
+                ParsedEntry::Padding => false,
+                ParsedEntry::User(_) => true,
+                _ => return Err(self.unexpected_entry(entry_pos)),
+            };
+            let mut word_pos = entry_pos;
+            while word_pos < pos {
+                let page = word_pos.page(&self.format);
+                let page_stats = &mut stats[((page + num_pages - head_page) % num_pages) as usize];
+                if is_live {
+                    page_stats.live_words += 1;
+                } else {
+                    page_stats.dead_words += 1;
+                }
+                word_pos += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Scans the whole store for inconsistencies.
+    ///
+    /// This verifies the checksum of all entries, that nothing is written after the tail, and that
+    /// the user entries fit in the capacity. Contrary to other operations, this does not stop at
+    /// the first inconsistency but reports all of them.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidStorage` if the storage is too corrupted to be scanned.
+    pub fn check(&self) -> StoreResult<StoreCheck> {
+        let mut check = StoreCheck {
+            tail_consistent: true,
+            ..StoreCheck::default()
+        };
+        let mut keys = vec![false; self.format.max_key() as usize + 1];
+        let mut used = 0;
+        let mut pos = self.head()?;
+        let end = pos + self.format.virt_size();
+        while pos < end {
+            let entry_pos = pos;
+            match self.parse_entry(&mut pos)? {
+                ParsedEntry::Tail => break,
+                ParsedEntry::Padding => {
+                    let mut word_pos = entry_pos + 1;
+                    while word_pos < pos {
+                        if self.read_word(word_pos).iter().any(|&x| x != 0x00) {
+                            check.unwiped_words += 1;
+                        }
+                        word_pos += 1;
+                    }
+                }
+                ParsedEntry::User(header) => {
+                    check.entries += 1;
+                    used += pos - entry_pos;
+                    let seen = &mut keys[header.key as usize];
+                    if *seen {
+                        check.duplicate_keys += 1;
+                    }
+                    *seen = true;
+                }
+                ParsedEntry::Internal(_) => check.internal_entries += 1,
+                ParsedEntry::PartialUser => check.corrupted_entries += 1,
+                ParsedEntry::Partial => check.partial_entries += 1,
+            }
+        }
+        check.tail_consistent = pos == self.tail()?;
+        while pos < end {
+            if !is_erased(self.read_word(pos)) {
+                check.written_after_tail += 1;
+            }
+            pos += 1;
+        }
+        check.capacity = StoreRatio {
+            used,
+            total: self.format.total_capacity(),
+        };
+        Ok(check)
+    }
+
     /// Applies a sequence of updates as a single transaction.
     ///
     /// # Errors
@@ -250,7 +747,24 @@ impl<S: Storage> Store<S> {
     /// - There are too many updates.
     /// - The updates overlap, i.e. their keys are not disjoint.
     /// - The updates are invalid, e.g. key out of bound or value too long.
+    ///
+    /// Returns `ConditionFailed` if a conditional update does not hold. Conditions are evaluated
+    /// against the content of the store before the transaction.
+    ///
+    /// Returns `WriteOnce` if an update would modify or remove a present write-once entry.
     pub fn transaction(&mut self, updates: &[StoreUpdate]) -> StoreResult<()> {
+        if updates.iter().any(StoreUpdate::is_conditional) {
+            if self.format.transaction_valid(updates).is_none() {
+                return Err(StoreError::InvalidArgument);
+            }
+            let updates = self.resolve_updates(updates)?;
+            return self.transaction_unconditional(&updates);
+        }
+        self.transaction_unconditional(updates)
+    }
+
+    /// Applies a sequence of unconditional updates as a single transaction.
+    fn transaction_unconditional(&mut self, updates: &[StoreUpdate]) -> StoreResult<()> {
         let count = usize_to_nat(updates.len());
         if count == 0 {
             return Ok(());
@@ -259,13 +773,19 @@ impl<S: Storage> Store<S> {
             match updates[0] {
                 StoreUpdate::Insert { key, ref value } => return self.insert(key, value),
                 StoreUpdate::Remove { key } => return self.remove(key),
+                StoreUpdate::RemoveRange { ref keys } => return self.remove_range(keys.clone()),
+                _ => unreachable!(),
             }
         }
-        // Get the sorted keys. Fail if the transaction is invalid.
-        let sorted_keys = match self.format.transaction_valid(updates) {
+        // Get the domain. Fail if the transaction is invalid.
+        let domain = match self.format.transaction_valid(updates) {
             None => return Err(StoreError::InvalidArgument),
             Some(x) => x,
         };
+        // Fail if an update modifies a write-once entry.
+        for update in updates {
+            self.check_write_once(update.keys())?;
+        }
         // Reserve the capacity.
         self.reserve(self.format.transaction_capacity(updates))?;
         // Write the marker entry.
@@ -291,12 +811,65 @@ impl<S: Storage> Store<S> {
                     self.write_slice(tail, &remove)?;
                     0
                 }
+                StoreUpdate::RemoveRange { ref keys } => {
+                    let min_key = usize_to_nat(keys.start);
+                    let max_key = usize_to_nat(keys.end - 1);
+                    let remove = self
+                        .format
+                        .build_internal(InternalEntry::RemoveRange { min_key, max_key });
+                    self.write_slice(tail, &remove)?;
+                    0
+                }
+                _ => unreachable!(),
             };
             self.init_page(tail, tail + length)?;
             tail += 1 + length;
         }
         // Apply the transaction.
-        self.transaction_apply(&sorted_keys, marker)
+        self.transaction_apply(&domain, marker)?;
+        for update in updates {
+            self.notify(match *update {
+                StoreUpdate::Insert { key, ref value } => StoreMutation::Insert {
+                    key,
+                    len: value.len(),
+                },
+                StoreUpdate::Remove { key } => StoreMutation::Remove { key },
+                StoreUpdate::RemoveRange { ref keys } => StoreMutation::RemoveRange {
+                    min_key: keys.start,
+                    max_key: keys.end - 1,
+                },
+                _ => unreachable!(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Removes the entries with a key in a range as a single update.
+    fn remove_range(&mut self, keys: Range<usize>) -> StoreResult<()> {
+        let update = StoreUpdate::RemoveRange { keys };
+        if self
+            .format
+            .transaction_valid(core::slice::from_ref(&update))
+            .is_none()
+        {
+            return Err(StoreError::InvalidArgument);
+        }
+        let keys = update.keys();
+        if keys.len() == 1 {
+            return self.remove(keys.start);
+        }
+        self.check_write_once(keys.clone())?;
+        let min_key = usize_to_nat(keys.start);
+        let max_key = usize_to_nat(keys.end - 1);
+        let entry = self
+            .format
+            .build_internal(InternalEntry::RemoveRange { min_key, max_key });
+        self.write_internal(&entry)?;
+        self.notify(StoreMutation::RemoveRange {
+            min_key: keys.start,
+            max_key: keys.end - 1,
+        });
+        Ok(())
     }
 
     /// Removes multiple entries as part of a single transaction.
@@ -307,14 +880,25 @@ impl<S: Storage> Store<S> {
         if min_key > self.format.max_key() {
             return Err(StoreError::InvalidArgument);
         }
+        self.check_write_once(min_key as usize..self.format.max_key() as usize + 1)?;
         let clear = self.format.build_internal(InternalEntry::Clear { min_key });
+        self.write_internal(&clear)?;
+        self.notify(StoreMutation::RemoveRange {
+            min_key: min_key as usize,
+            max_key: self.format.max_key() as usize,
+        });
+        Ok(())
+    }
+
+    /// Writes a clear or remove range entry and deletes the entries it covers.
+    fn write_internal(&mut self, entry: &[u8]) -> StoreResult<()> {
         // We always have one word available. We can't use `reserve` because this is internal
         // capacity, not user capacity.
         while self.immediate_capacity()? < 1 {
             self.compact()?;
         }
         let tail = self.tail()?;
-        self.write_slice(tail, &clear)?;
+        self.write_slice(tail, entry)?;
         self.clear_delete(tail)
     }
 
@@ -332,6 +916,180 @@ impl<S: Storage> Store<S> {
         Ok(())
     }
 
+    /// Compacts the head page ahead of time if the policy decides so.
+    ///
+    /// This is meant to be called while the device is idle. Only a full head page is compacted,
+    /// since compacting a page being written would waste its remaining words. Like `prepare`, this
+    /// has no effect on the store but may reduce its lifetime. Returns whether a page was compacted.
+    pub fn compact_with(&mut self, policy: &dyn CompactionPolicy) -> StoreResult<bool> {
+        let head = self.head()?;
+        if self.tail()? < head.next_page(&self.format) {
+            return Ok(false);
+        }
+        if !policy.should_compact(&self.page_stats()?) {
+            return Ok(false);
+        }
+        self.compact()?;
+        Ok(true)
+    }
+
+    /// Wipes the remaining data of deleted entries.
+    ///
+    /// Removed values are wiped before the operation returns, and interrupted wipes are completed
+    /// when the store is mounted. So the storage only contains data of deleted entries if it was
+    /// modified outside of the store. Returns the number of words that were wiped.
+    pub fn scrub(&mut self) -> StoreResult<usize> {
+        let unwiped = self.unwiped_words()?;
+        for &pos in &unwiped {
+            self.wipe_span(pos, 1)?;
+        }
+        Ok(unwiped.len())
+    }
+
+    /// Returns and forgets the location of the last invalid storage detected by the store.
+    ///
+    /// Errors while mounting are not covered, since the store is not returned in that case.
+    #[cfg(feature = "diagnostics")]
+    pub fn take_error_context(&self) -> Option<StoreErrorContext> {
+        self.error_context.take()
+    }
+
+    /// Writes a compact description of the store state.
+    ///
+    /// This is meant to be attached to corruption reports. It describes the geometry, the head and
+    /// tail, the metadata and word statistics of each page, the location of each entry, and the
+    /// last invalid storage detected. What cannot be computed is replaced by its error, such that a
+    /// corrupted store can still be described. Values are not written.
+    #[cfg(feature = "diagnostics")]
+    pub fn dump_debug(&self, mut out: impl core::fmt::Write) -> core::fmt::Result {
+        // Describing the store may detect invalid storage too, so the last error is saved first.
+        let error_context = self.error_context.get();
+        let word_size = self.format.word_size();
+        writeln!(
+            out,
+            "geometry: {} pages of {} words of {} bytes, version {}",
+            self.format.num_pages(),
+            self.format.page_size() / word_size,
+            word_size,
+            self.version
+        )?;
+        self.dump_position(&mut out, "head", self.head())?;
+        self.dump_position(&mut out, "tail", self.tail())?;
+        match self.capacity() {
+            Ok(capacity) => writeln!(
+                out,
+                "capacity: {}/{} words",
+                capacity.used(),
+                capacity.total()
+            )?,
+            Err(error) => writeln!(out, "capacity: {:?}", error)?,
+        }
+        match self.lifetime() {
+            Ok(lifetime) => writeln!(
+                out,
+                "lifetime: {}/{} words",
+                lifetime.used(),
+                lifetime.total()
+            )?,
+            Err(error) => writeln!(out, "lifetime: {:?}", error)?,
+        }
+        let stats = self.page_stats();
+        for page in 0..self.format.num_pages() {
+            write!(out, "page {}: ", page)?;
+            match self.parse_init(page) {
+                Ok(WordState::Valid(init)) => write!(out, "cycle {}", init.cycle)?,
+                Ok(WordState::Erased) => write!(out, "erased")?,
+                Ok(WordState::Partial) => write!(out, "partial init")?,
+                Err(error) => write!(out, "{:?}", error)?,
+            }
+            if let Ok(WordState::Valid(_)) = self.parse_compact(page) {
+                write!(out, ", compacting")?;
+            }
+            let page_stats = stats
+                .as_ref()
+                .ok()
+                .and_then(|stats| stats.iter().find(|x| x.page == page as usize));
+            if let Some(page_stats) = page_stats {
+                write!(
+                    out,
+                    ", {} live words, {} dead words",
+                    page_stats.live_words, page_stats.dead_words
+                )?;
+            }
+            writeln!(out)?;
+        }
+        match self.iter() {
+            Ok(iter) => {
+                for handle in iter {
+                    match handle {
+                        Ok(handle) => {
+                            let index = handle.pos.index(&self.format);
+                            writeln!(
+                                out,
+                                "key {}: page {}, word {}, {} bytes",
+                                handle.key,
+                                index.page,
+                                index.byte / word_size as usize,
+                                handle.len
+                            )?;
+                        }
+                        Err(error) => {
+                            writeln!(out, "entries: {:?}", error)?;
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(error) => writeln!(out, "entries: {:?}", error)?,
+        }
+        match error_context {
+            None => writeln!(out, "last error: none"),
+            Some(context) => writeln!(out, "last error: {}", context),
+        }
+    }
+
+    /// Writes a named position for `dump_debug`.
+    #[cfg(feature = "diagnostics")]
+    fn dump_position(
+        &self,
+        out: &mut impl core::fmt::Write,
+        name: &str,
+        pos: StoreResult<Position>,
+    ) -> core::fmt::Result {
+        match pos {
+            Ok(pos) => {
+                let index = pos.index(&self.format);
+                let word = index.byte / self.format.word_size() as usize;
+                writeln!(out, "{}: page {}, word {}", name, index.page, word)
+            }
+            Err(error) => writeln!(out, "{}: {:?}", name, error),
+        }
+    }
+
+    /// Returns the position of the words of deleted entries which are not wiped.
+    pub(crate) fn unwiped_words(&self) -> StoreResult<Vec<Position>> {
+        let mut unwiped = Vec::new();
+        let mut pos = self.head()?;
+        let end = pos + self.format.virt_size();
+        while pos < end {
+            let entry_pos = pos;
+            match self.parse_entry(&mut pos)? {
+                ParsedEntry::Tail => break,
+                ParsedEntry::Padding => {
+                    let mut word_pos = entry_pos + 1;
+                    while word_pos < pos {
+                        if self.read_word(word_pos).iter().any(|&x| x != 0x00) {
+                            unwiped.push(word_pos);
+                        }
+                        word_pos += 1;
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(unwiped)
+    }
+
     /// Recovers a possible interrupted operation.
     ///
     /// If the storage is completely erased, it is initialized.
@@ -351,6 +1109,16 @@ impl<S: Storage> Store<S> {
         })
     }
 
+    /// Returns whether an entry is present given its key.
+    ///
+    /// This is like [`find`] but does not read the value. In particular, an entry with an empty
+    /// value is present.
+    ///
+    /// [`find`]: struct.Store.html#method.find
+    pub fn contains(&self, key: usize) -> StoreResult<bool> {
+        Ok(self.find_handle(key)?.is_some())
+    }
+
     /// Returns a handle to an entry given its key.
     pub fn find_handle(&self, key: usize) -> StoreResult<Option<StoreHandle>> {
         let key = usize_to_nat(key);
@@ -373,6 +1141,7 @@ impl<S: Storage> Store<S> {
         if key > self.format.max_key() || value_len > self.format.max_value_len() {
             return Err(StoreError::InvalidArgument);
         }
+        self.check_write_once(key as usize..key as usize + 1)?;
         let entry = self.format.build_user(key, value);
         let entry_len = usize_to_nat(entry.len());
         self.reserve(entry_len / self.format.word_size())?;
@@ -381,7 +1150,31 @@ impl<S: Storage> Store<S> {
         let footer = entry_len / word_size - 1;
         self.write_slice(tail, &entry[..(footer * word_size) as usize])?;
         self.write_slice(tail + footer, &entry[(footer * word_size) as usize..])?;
-        self.insert_init(tail, footer, key)
+        self.insert_init(tail, footer, key)?;
+        self.notify(StoreMutation::Insert {
+            key: key as usize,
+            len: value.len(),
+        });
+        Ok(())
+    }
+
+    /// Inserts an entry in the store, unless it is already present with the same value.
+    ///
+    /// This is like [`insert`] but doesn't consume lifetime when the value doesn't change, which
+    /// is common for settings that are written as a whole.
+    ///
+    /// Values are never updated in place, even when only clearing bits. Each word is written at
+    /// most twice between erase cycles (once with the value and once when wiped), and the checksum
+    /// of an entry covers its last word.
+    ///
+    /// [`insert`]: struct.Store.html#method.insert
+    pub fn replace(&mut self, key: usize, value: &[u8]) -> StoreResult<()> {
+        if let Some(handle) = self.find_handle(key)? {
+            if handle.len == usize_to_nat(value.len()) && self.get_value(&handle)? == value {
+                return Ok(());
+            }
+        }
+        self.insert(key, value)
     }
 
     /// Removes an entry given its key.
@@ -392,13 +1185,151 @@ impl<S: Storage> Store<S> {
         if key > self.format.max_key() {
             return Err(StoreError::InvalidArgument);
         }
-        self.delete_keys(&[key], self.tail()?)
+        self.check_write_once(key as usize..key as usize + 1)?;
+        self.delete_keys(|x| x == key, self.tail()?)?;
+        self.notify(StoreMutation::Remove { key: key as usize });
+        Ok(())
     }
 
     /// Removes an entry given a handle.
     pub fn remove_handle(&mut self, handle: &StoreHandle) -> StoreResult<()> {
         self.check_handle(handle)?;
-        self.delete_pos(handle.pos, self.format.bytes_to_words(handle.len))
+        let key = handle.key as usize;
+        self.check_write_once(key..key + 1)?;
+        self.delete_pos(handle.pos, self.format.bytes_to_words(handle.len))?;
+        self.notify(StoreMutation::Remove {
+            key: handle.key as usize,
+        });
+        Ok(())
+    }
+
+    /// Moves an entry to a new key as a single transaction.
+    ///
+    /// If an entry for the new key is already present, it is replaced. This is not an error if
+    /// there is no entry for the old key, in which case the store is unchanged. Because the insertion
+    /// and the removal are part of the same transaction, the entry is never lost nor duplicated if
+    /// the operation is interrupted.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidArgument` if a key is out of bound.
+    pub fn rekey(&mut self, old_key: usize, new_key: usize) -> StoreResult<()> {
+        let max_key = self.format.max_key() as usize;
+        if old_key > max_key || new_key > max_key {
+            return Err(StoreError::InvalidArgument);
+        }
+        if old_key == new_key {
+            return Ok(());
+        }
+        let value = match self.find(old_key)? {
+            None => return Ok(()),
+            Some(value) => value,
+        };
+        self.transaction(&[
+            StoreUpdate::Insert {
+                key: new_key,
+                value,
+            },
+            StoreUpdate::Remove { key: old_key },
+        ])
+    }
+
+    /// Sets the observer of the mutations, replacing the previous one.
+    ///
+    /// This is meant for debugging, for example to log the storage activity while interacting
+    /// with the device.
+    pub fn set_observer(&mut self, observer: Option<StoreObserver>) {
+        self.observer = observer;
+    }
+
+    /// Sets the predicate of the write-once keys, replacing the previous one.
+    ///
+    /// This protects entries written once, for example during provisioning, against buggy code
+    /// overwriting or removing them later. The predicate is not persisted and should be set each
+    /// time the store is mounted. Recovering an interrupted operation when mounting is not checked.
+    pub fn set_write_once(&mut self, write_once: Option<StoreWriteOnce>) {
+        self.write_once = write_once;
+    }
+
+    /// Fails if a range of keys contains a present write-once entry.
+    fn check_write_once(&self, keys: Range<usize>) -> StoreResult<()> {
+        let write_once = match self.write_once {
+            None => return Ok(()),
+            Some(x) => x,
+        };
+        for handle in self.iter_range(keys)? {
+            if write_once(handle?.key as usize) {
+                return Err(StoreError::WriteOnce);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports a mutation to the observer, if any.
+    fn notify(&self, mutation: StoreMutation) {
+        if let Some(observer) = self.observer {
+            observer(&mutation);
+        }
+    }
+
+    /// Returns the value of a critical entry.
+    ///
+    /// If the current shadow entry is missing, the previous value is returned instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidStorage` if the pointer entry is not a valid index.
+    pub fn find_critical(&self, keys: &CriticalKeys) -> StoreResult<Option<Vec<u8>>> {
+        let index = match self.critical_index(keys)? {
+            None => return Ok(None),
+            Some(index) => index,
+        };
+        match self.find(keys.shadows[index])? {
+            None => self.find(keys.shadows[1 - index]),
+            value => Ok(value),
+        }
+    }
+
+    /// Inserts a critical entry in the store.
+    ///
+    /// The value is written under the shadow key not holding the current value, then the pointer
+    /// entry is flipped to this shadow key. The current value is only overwritten by the next
+    /// insertion.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidStorage` if the pointer entry is not a valid index.
+    pub fn insert_critical(&mut self, keys: &CriticalKeys, value: &[u8]) -> StoreResult<()> {
+        let index = match self.critical_index(keys)? {
+            None => 0,
+            Some(index) => 1 - index,
+        };
+        // Fail before writing the shadow entry if the pointer entry is write-once.
+        self.check_write_once(keys.pointer..keys.pointer + 1)?;
+        self.insert(keys.shadows[index], value)?;
+        self.insert(keys.pointer, &[index as u8])
+    }
+
+    /// Removes a critical entry, including its previous value.
+    pub fn remove_critical(&mut self, keys: &CriticalKeys) -> StoreResult<()> {
+        self.transaction(&[
+            StoreUpdate::Remove { key: keys.pointer },
+            StoreUpdate::Remove {
+                key: keys.shadows[0],
+            },
+            StoreUpdate::Remove {
+                key: keys.shadows[1],
+            },
+        ])
+    }
+
+    /// Returns the index of the shadow key holding the current value of a critical entry.
+    fn critical_index(&self, keys: &CriticalKeys) -> StoreResult<Option<usize>> {
+        match self.find(keys.pointer)?.as_deref() {
+            None => Ok(None),
+            Some([index]) if *index < 2 => Ok(Some(*index as usize)),
+            Some(_) => Err(StoreError::InvalidStorage),
+        }
     }
 
     /// Returns the maximum length in bytes of a value.
@@ -406,6 +1337,24 @@ impl<S: Storage> Store<S> {
         self.format.max_value_len() as usize
     }
 
+    /// Resolves the conditional updates of a transaction against the current content.
+    ///
+    /// Updates without effect are dropped.
+    fn resolve_updates(&self, updates: &[StoreUpdate]) -> StoreResult<Vec<StoreUpdate>> {
+        let mut resolved = Vec::with_capacity(updates.len());
+        for update in updates {
+            let current = if update.is_conditional() {
+                self.find(update.key())?
+            } else {
+                None
+            };
+            if let Some(update) = update.resolve(current.as_deref())? {
+                resolved.push(update);
+            }
+        }
+        Ok(resolved)
+    }
+
     /// Returns the value of an entry given its handle.
     fn get_value(&self, handle: &StoreHandle) -> StoreResult<Vec<u8>> {
         self.check_handle(handle)?;
@@ -420,8 +1369,94 @@ impl<S: Storage> Store<S> {
                 Ok(result)
             }
             ParsedEntry::Padding => Err(StoreError::InvalidArgument),
-            _ => Err(StoreError::InvalidStorage),
+            _ => Err(self.unexpected_entry(handle.pos)),
+        }
+    }
+
+    /// Applies the missing migrations and recovers a possible interrupted operation.
+    ///
+    /// The version of the storage is only upgraded once all pages have been compacted. So if the
+    /// migration is interrupted, it is applied again from the beginning. The same happens if there
+    /// is not enough lifetime to compact all pages.
+    fn migrate(&mut self, migrations: &[&dyn Migration]) -> StoreResult<()> {
+        let version = match self.storage_version()? {
+            None => self.version,
+            Some(version) => version,
+        };
+        if version > self.version {
+            return Err(StoreError::InvalidStorage);
+        }
+        for migration in &migrations[version as usize..] {
+            migration.migrate(&mut self.storage)?;
+        }
+        self.recover()?;
+        while self.storage_version()?.is_some_and(|x| x < self.version) {
+            match self.compact() {
+                // The pages can't be upgraded without lifetime. They will be migrated again at the
+                // next mount, which is fine since migrations are idempotent.
+                Err(StoreError::NoLifetime) => break,
+                result => result?,
+            }
         }
+        Ok(())
+    }
+
+    /// Returns the oldest format version of the initialized pages.
+    ///
+    /// Returns `None` if no page is initialized.
+    fn storage_version(&self) -> StoreResult<Option<Nat>> {
+        let mut version = None;
+        for page in 0..self.format.num_pages() {
+            if let WordState::Valid(init) = self.parse_init(page)? {
+                version = Some(version.map_or(init.version, |x: Nat| x.min(init.version)));
+            }
+        }
+        Ok(version)
+    }
+
+    /// Checks that the page metadata is consistent with the storage geometry.
+    ///
+    /// The geometry is not written to the storage. Instead, a storage written with a different
+    /// page size or number of pages is detected by its page metadata. The init and compact info of
+    /// all pages must parse, and the initialized pages must follow each other in the virtual
+    /// storage starting from the head page. This detection is best effort: for example, a smaller
+    /// number of pages goes undetected when the remaining pages happen to be consistent.
+    ///
+    /// The pages are parsed twice instead of being collected, to keep the RAM usage of mounting
+    /// independent of the number of pages.
+    fn check_geometry(&self) -> StoreResult<()> {
+        let num_pages = self.format.num_pages();
+        let page_head = |page| match (self.parse_compact(page), self.parse_init(page)) {
+            (Err(_), _) | (_, Err(_)) => Err(StoreError::GeometryMismatch),
+            (_, Ok(WordState::Valid(init))) => Ok(Some(self.format.page_head(init, page))),
+            (_, Ok(_)) => Ok(None),
+        };
+        let mut head: Option<Position> = None;
+        for page in 0..num_pages {
+            if let Some(pos) = page_head(page)? {
+                head = Some(head.map_or(pos, |x| min(x, pos)));
+            }
+        }
+        let head = match head {
+            None => return Ok(()),
+            Some(x) => x,
+        };
+        let head_page = head.page(&self.format);
+        let mut virt_page = head.get() / self.format.virt_page_size();
+        let mut initialized = true;
+        for i in 0..num_pages {
+            let page = (head_page + i) % num_pages;
+            match page_head(page)? {
+                Some(pos)
+                    if initialized && pos.get() / self.format.virt_page_size() == virt_page =>
+                {
+                    virt_page += 1
+                }
+                Some(_) => return Err(StoreError::GeometryMismatch),
+                None => initialized = false,
+            }
+        }
+        Ok(())
     }
 
     /// Initializes the storage if completely erased or partially initialized.
@@ -433,12 +1468,7 @@ impl<S: Storage> Store<S> {
                 return Ok(());
             }
         }
-        let index = self.format.index_init(0);
-        let init_info = self.format.build_init(InitInfo {
-            cycle: 0,
-            prefix: 0,
-        });
-        self.storage_write_slice(index, &init_info)
+        self.write_init(0, 0, 0)
     }
 
     /// Recovers a possible compaction interrupted while erasing the page.
@@ -482,7 +1512,7 @@ impl<S: Storage> Store<S> {
                     self.wipe_span(entry_pos + 1, pos - entry_pos - 1)?;
                 }
                 ParsedEntry::Internal(InternalEntry::Erase { .. }) => {
-                    return Err(StoreError::InvalidStorage);
+                    return Err(self.unexpected_entry(entry_pos));
                 }
                 ParsedEntry::Internal(InternalEntry::Clear { .. }) => {
                     return self.clear_delete(entry_pos);
@@ -493,6 +1523,11 @@ impl<S: Storage> Store<S> {
                 ParsedEntry::Internal(InternalEntry::Remove { .. }) => {
                     self.set_padding(entry_pos)?;
                 }
+                ParsedEntry::Internal(InternalEntry::RemoveRange { .. }) => {
+                    // This is either a single update or the leftover of an applied transaction. In
+                    // both cases, deleting the entries is idempotent.
+                    self.clear_delete(entry_pos)?;
+                }
                 ParsedEntry::Partial => {
                     return self.recover_wipe_partial(entry_pos, pos - entry_pos - 1);
                 }
@@ -514,23 +1549,27 @@ impl<S: Storage> Store<S> {
         let mut pos = marker;
         let count = match self.parse_entry(&mut pos)? {
             ParsedEntry::Internal(InternalEntry::Marker { count }) => count,
-            _ => return Err(StoreError::InvalidStorage),
+            _ => return Err(self.unexpected_entry(marker)),
         };
-        let sorted_keys = self.recover_transaction_keys(count, pos, end)?;
-        match usize_to_nat(sorted_keys.len()).cmp(&count) {
+        let domain = self.recover_transaction_keys(count, pos, end)?;
+        match usize_to_nat(domain.len()).cmp(&count) {
             Ordering::Less => (),
-            Ordering::Equal => return self.transaction_apply(&sorted_keys, marker),
-            Ordering::Greater => return Err(StoreError::InvalidStorage),
+            Ordering::Equal => return self.transaction_apply(&domain, marker),
+            Ordering::Greater => return Err(self.unexpected_entry(marker)),
         }
         while pos < end {
             let entry_pos = pos;
             match self.parse_entry(&mut pos)? {
                 ParsedEntry::Tail => break,
-                ParsedEntry::Padding => (),
+                ParsedEntry::Padding => {
+                    // A previous recovery may have been interrupted while wiping this entry.
+                    self.wipe_span(entry_pos + 1, pos - entry_pos - 1)?;
+                }
                 ParsedEntry::User(_) => {
                     self.delete_pos(entry_pos, pos - entry_pos - 1)?;
                 }
-                ParsedEntry::Internal(InternalEntry::Remove { .. }) => {
+                ParsedEntry::Internal(InternalEntry::Remove { .. })
+                | ParsedEntry::Internal(InternalEntry::RemoveRange { .. }) => {
                     self.set_padding(entry_pos)?;
                 }
                 ParsedEntry::Partial => {
@@ -544,7 +1583,7 @@ impl<S: Storage> Store<S> {
                 ParsedEntry::Internal(InternalEntry::Erase { .. })
                 | ParsedEntry::Internal(InternalEntry::Clear { .. })
                 | ParsedEntry::Internal(InternalEntry::Marker { .. }) => {
-                    return Err(StoreError::InvalidStorage);
+                    return Err(self.unexpected_entry(entry_pos));
                 }
             }
         }
@@ -554,42 +1593,44 @@ impl<S: Storage> Store<S> {
     }
 
     /// Returns the domain of a possible interrupted transaction.
-    ///
-    /// The domain is returned as a sorted list of keys.
     fn recover_transaction_keys(
         &mut self,
         count: Nat,
         mut pos: Position,
         end: Position,
-    ) -> StoreResult<Vec<Nat>> {
-        let mut sorted_keys = Vec::with_capacity(count as usize);
+    ) -> StoreResult<KeyDomain> {
+        let mut domain = KeyDomain::with_capacity(count as usize);
         let mut prev_pos = pos;
         while pos < end {
             let entry_pos = pos;
-            let key = match self.parse_entry(&mut pos)? {
+            let (min_key, max_key) = match self.parse_entry(&mut pos)? {
                 ParsedEntry::Tail
                 | ParsedEntry::Padding
                 | ParsedEntry::Partial
                 | ParsedEntry::PartialUser => break,
-                ParsedEntry::User(header) => header.key,
-                ParsedEntry::Internal(InternalEntry::Remove { key }) => key,
-                ParsedEntry::Internal(_) => return Err(StoreError::InvalidStorage),
+                ParsedEntry::User(header) => (header.key, header.key),
+                ParsedEntry::Internal(InternalEntry::Remove { key }) => (key, key),
+                ParsedEntry::Internal(InternalEntry::RemoveRange { min_key, max_key }) => {
+                    (min_key, max_key)
+                }
+                ParsedEntry::Internal(_) => return Err(self.unexpected_entry(entry_pos)),
             };
-            match sorted_keys.binary_search(&key) {
-                Ok(_) => return Err(StoreError::InvalidStorage),
-                Err(pos) => sorted_keys.insert(pos, key),
+            if !domain.insert(min_key, max_key) {
+                return Err(self.unexpected_entry(entry_pos));
             }
             prev_pos = entry_pos;
         }
         pos = prev_pos;
         match self.parse_entry(&mut pos)? {
-            ParsedEntry::User(_) | ParsedEntry::Internal(InternalEntry::Remove { .. }) => {
+            ParsedEntry::User(_)
+            | ParsedEntry::Internal(InternalEntry::Remove { .. })
+            | ParsedEntry::Internal(InternalEntry::RemoveRange { .. }) => {
                 let length = pos - prev_pos - 1;
                 self.init_page(prev_pos, prev_pos + length)?;
             }
             _ => (),
         }
-        Ok(sorted_keys)
+        Ok(domain)
     }
 
     /// Completes a possible partial entry wipe.
@@ -634,7 +1675,7 @@ impl<S: Storage> Store<S> {
     /// Continues an entry insertion after it has been written.
     fn insert_init(&mut self, pos: Position, length: Nat, key: Nat) -> StoreResult<()> {
         self.init_page(pos, pos + length)?;
-        self.delete_keys(&[key], pos)?;
+        self.delete_keys(|x| x == key, pos)?;
         Ok(())
     }
 
@@ -658,10 +1699,10 @@ impl<S: Storage> Store<S> {
         let end = head.next_page(&self.format);
         let mut tail = match self.parse_compact(page)? {
             WordState::Valid(CompactInfo { tail }) => head + tail,
-            _ => return Err(StoreError::InvalidStorage),
+            _ => return Err(self.invalid_storage(self.format.index_compact(page), "compact info")),
         };
         if tail < end {
-            return Err(StoreError::InvalidStorage);
+            return Err(self.invalid_storage(self.format.index_compact(page), "compact tail"));
         }
         while head < end {
             let pos = head;
@@ -690,7 +1731,7 @@ impl<S: Storage> Store<S> {
     fn compact_erase(&mut self, erase: Position) -> StoreResult<()> {
         let page = match self.parse_entry(&mut erase.clone())? {
             ParsedEntry::Internal(InternalEntry::Erase { page }) => page,
-            _ => return Err(StoreError::InvalidStorage),
+            _ => return Err(self.unexpected_entry(erase)),
         };
         self.storage_erase_page(page)?;
         let head = self.head()?;
@@ -701,8 +1742,8 @@ impl<S: Storage> Store<S> {
     }
 
     /// Continues a transaction after it has been written.
-    fn transaction_apply(&mut self, sorted_keys: &[Nat], marker: Position) -> StoreResult<()> {
-        self.delete_keys(&sorted_keys, marker)?;
+    fn transaction_apply(&mut self, domain: &KeyDomain, marker: Position) -> StoreResult<()> {
+        self.delete_keys(|key| domain.contains(key), marker)?;
         self.set_padding(marker)?;
         let end = self.head()? + self.format.virt_size();
         let mut pos = marker + 1;
@@ -711,57 +1752,55 @@ impl<S: Storage> Store<S> {
             match self.parse_entry(&mut pos)? {
                 ParsedEntry::Tail => break,
                 ParsedEntry::User(_) => (),
-                ParsedEntry::Internal(InternalEntry::Remove { .. }) => {
-                    self.set_padding(entry_pos)?
-                }
-                _ => return Err(StoreError::InvalidStorage),
-            }
-        }
-        Ok(())
-    }
-
-    /// Continues a clear operation after its internal entry has been written.
-    fn clear_delete(&mut self, clear: Position) -> StoreResult<()> {
-        self.init_page(clear, clear)?;
-        let min_key = match self.parse_entry(&mut clear.clone())? {
-            ParsedEntry::Internal(InternalEntry::Clear { min_key }) => min_key,
-            _ => return Err(StoreError::InvalidStorage),
-        };
-        let mut pos = self.head()?;
-        let end = pos + self.format.virt_size();
-        while pos < end {
-            let entry_pos = pos;
-            match self.parse_entry(&mut pos)? {
-                ParsedEntry::Internal(InternalEntry::Clear { .. }) if entry_pos == clear => break,
-                ParsedEntry::User(header) if header.key >= min_key => {
-                    self.delete_pos(entry_pos, pos - entry_pos - 1)?;
+                ParsedEntry::Internal(InternalEntry::Remove { .. })
+                | ParsedEntry::Internal(InternalEntry::RemoveRange { .. }) => {
+                    self.set_padding(entry_pos)?
                 }
-                ParsedEntry::Padding | ParsedEntry::User(_) => (),
-                _ => return Err(StoreError::InvalidStorage),
+                _ => return Err(self.unexpected_entry(entry_pos)),
             }
         }
+        Ok(())
+    }
+
+    /// Continues a clear or remove range operation after its internal entry has been written.
+    fn clear_delete(&mut self, clear: Position) -> StoreResult<()> {
+        self.init_page(clear, clear)?;
+        let (min_key, max_key) = match self.parse_entry(&mut clear.clone())? {
+            ParsedEntry::Internal(InternalEntry::Clear { min_key }) => {
+                (min_key, self.format.max_key())
+            }
+            ParsedEntry::Internal(InternalEntry::RemoveRange { min_key, max_key }) => {
+                (min_key, max_key)
+            }
+            _ => return Err(self.unexpected_entry(clear)),
+        };
+        self.delete_keys(|key| min_key <= key && key <= max_key, clear)?;
         self.set_padding(clear)?;
         Ok(())
     }
 
-    /// Deletes a set of entries up to a certain position.
-    fn delete_keys(&mut self, sorted_keys: &[Nat], end: Position) -> StoreResult<()> {
+    /// Deletes the entries whose key satisfies a predicate up to a certain position.
+    fn delete_keys(&mut self, delete: impl Fn(Nat) -> bool, end: Position) -> StoreResult<()> {
         let mut pos = self.head()?;
         while pos < end {
             let entry_pos = pos;
             match self.parse_entry(&mut pos)? {
                 ParsedEntry::Tail => break,
-                ParsedEntry::User(header) if sorted_keys.binary_search(&header.key).is_ok() => {
+                ParsedEntry::User(header) if delete(header.key) => {
                     self.delete_pos(entry_pos, pos - entry_pos - 1)?;
                 }
                 ParsedEntry::Padding | ParsedEntry::User(_) => (),
-                _ => return Err(StoreError::InvalidStorage),
+                _ => return Err(self.unexpected_entry(entry_pos)),
             }
         }
         Ok(())
     }
 
     /// Deletes the entry at a given position.
+    ///
+    /// The entry is marked as deleted before its words are wiped, because wiping the footer of a
+    /// valid entry may validate a different checksum. If interrupted, the wipe is completed during
+    /// recovery, so the value is wiped before any other operation.
     fn delete_pos(&mut self, pos: Position, length: Nat) -> StoreResult<()> {
         self.set_deleted(pos)?;
         self.wipe_span(pos + 1, length)?;
@@ -788,13 +1827,38 @@ impl<S: Storage> Store<S> {
         if let WordState::Valid(_) = self.parse_init(page)? {
             return Ok(());
         }
+        self.write_init(
+            page,
+            new_first.cycle(&self.format),
+            new_first.word(&self.format),
+        )
+    }
+
+    /// Writes the init info of a page.
+    ///
+    /// A partial init info may have been written with an older format version, in which case we
+    /// complete it with that version. The pages are upgraded during migration.
+    fn write_init(&mut self, page: Nat, cycle: Nat, prefix: Nat) -> StoreResult<()> {
         let index = self.format.index_init(page);
-        let init_info = self.format.build_init(InitInfo {
-            cycle: new_first.cycle(&self.format),
-            prefix: new_first.word(&self.format),
-        });
-        self.storage_write_slice(index, &init_info)?;
-        Ok(())
+        let current = self.storage_read_slice(index, self.format.word_size());
+        let mut version = self.version;
+        let init_info = loop {
+            let init_info = self.format.build_init(InitInfo {
+                cycle,
+                prefix,
+                version,
+            });
+            if version == 0
+                || current
+                    .iter()
+                    .zip(init_info.iter())
+                    .all(|(&x, &y)| x & y == y)
+            {
+                break init_info;
+            }
+            version -= 1;
+        };
+        self.storage_write_slice(index, &init_info)
     }
 
     /// Sets the padding bit of a user header.
@@ -814,9 +1878,19 @@ impl<S: Storage> Store<S> {
     }
 
     /// Wipes a slice of words.
-    fn wipe_span(&mut self, pos: Position, length: Nat) -> StoreResult<()> {
-        let length = (length * self.format.word_size()) as usize;
-        self.write_slice(pos, &vec![0x00; length])
+    ///
+    /// The words are wiped by chunks, to avoid allocating a buffer as long as the slice. Wipes
+    /// happen during recovery, when the store is mounted.
+    fn wipe_span(&mut self, mut pos: Position, mut length: Nat) -> StoreResult<()> {
+        let zeros = [0x00; WIPE_CHUNK_LEN];
+        let chunk = usize_to_nat(WIPE_CHUNK_LEN) / self.format.word_size();
+        while length > 0 {
+            let count = min(length, chunk);
+            self.write_slice(pos, &zeros[..(count * self.format.word_size()) as usize])?;
+            pos += count;
+            length -= count;
+        }
+        Ok(())
     }
 
     /// Returns an extremum page.
@@ -887,7 +1961,7 @@ impl<S: Storage> Store<S> {
                 };
                 if header.check(footer) {
                     if header.key > self.format.max_key() {
-                        return Err(StoreError::InvalidStorage);
+                        return Err(self.unexpected_entry(*pos));
                     }
                     *pos += 1 + length;
                     ParsedEntry::User(header)
@@ -929,20 +2003,65 @@ impl<S: Storage> Store<S> {
     fn parse_init(&self, page: Nat) -> StoreResult<WordState<InitInfo>> {
         let index = self.format.index_init(page);
         let word = self.storage_read_slice(index, self.format.word_size());
-        self.format.parse_init(Word::from_slice(word))
+        self.format
+            .parse_init(Word::from_slice(word))
+            .map_err(|_| self.invalid_storage(index, "init info"))
     }
 
     /// Parses the compact info of a page.
     fn parse_compact(&self, page: Nat) -> StoreResult<WordState<CompactInfo>> {
         let index = self.format.index_compact(page);
         let word = self.storage_read_slice(index, self.format.word_size());
-        self.format.parse_compact(Word::from_slice(word))
+        self.format
+            .parse_compact(Word::from_slice(word))
+            .map_err(|_| self.invalid_storage(index, "compact info"))
     }
 
     /// Parses a word from the virtual storage.
     fn parse_word(&self, pos: Position) -> StoreResult<WordState<ParsedWord>> {
         self.format
             .parse_word(Word::from_slice(self.read_word(pos)))
+            .map_err(|_| self.invalid_storage(pos.index(&self.format), "invalid word"))
+    }
+
+    /// Returns an invalid storage error for the word at a physical location.
+    ///
+    /// With the `diagnostics` feature, the location is recorded for [`take_error_context`].
+    ///
+    /// [`take_error_context`]: struct.Store.html#method.take_error_context
+    #[cfg_attr(not(feature = "diagnostics"), allow(unused_variables))]
+    fn invalid_storage(&self, index: StorageIndex, tag: &'static str) -> StoreError {
+        #[cfg(feature = "diagnostics")]
+        self.error_context.set(Some(StoreErrorContext {
+            page: index.page,
+            word: index.byte / self.format.word_size() as usize,
+            tag,
+        }));
+        StoreError::InvalidStorage
+    }
+
+    /// Returns an invalid storage error for an unexpected entry at a given position.
+    ///
+    /// The entry is tagged with the kind of its first word.
+    fn unexpected_entry(&self, pos: Position) -> StoreError {
+        let tag = match self
+            .format
+            .parse_word(Word::from_slice(self.read_word(pos)))
+        {
+            Err(_) => "invalid word",
+            Ok(WordState::Erased) => "erased word",
+            Ok(WordState::Partial) => "partial word",
+            Ok(WordState::Valid(ParsedWord::Padding(_))) => "padding entry",
+            Ok(WordState::Valid(ParsedWord::Header(_))) => "user entry",
+            Ok(WordState::Valid(ParsedWord::Internal(internal))) => match internal {
+                InternalEntry::Erase { .. } => "erase entry",
+                InternalEntry::Clear { .. } => "clear entry",
+                InternalEntry::Marker { .. } => "marker entry",
+                InternalEntry::Remove { .. } => "remove entry",
+                InternalEntry::RemoveRange { .. } => "remove range entry",
+            },
+        };
+        self.invalid_storage(pos.index(&self.format), tag)
     }
 
     /// Reads a slice from the virtual storage.
@@ -1093,8 +2212,11 @@ impl Store<BufferStorage> {
         };
         match *operation {
             StoreOperation::Transaction { ref updates } => {
-                let keys: HashSet<usize> = updates.iter().map(|x| x.key()).collect();
-                let deleted = deleted(self, &|key| keys.contains(&key));
+                // Updates without effect don't delete their entry.
+                let resolved = self.resolve_updates(updates).unwrap_or_default();
+                let deleted = deleted(self, &|key| {
+                    resolved.iter().any(|x| x.keys().contains(&key))
+                });
                 (deleted, self.transaction(updates))
             }
             StoreOperation::Clear { min_key } => {
@@ -1113,6 +2235,7 @@ impl Store<BufferStorage> {
         let init_info = format.build_init(InitInfo {
             cycle: usize_to_nat(cycle),
             prefix: 0,
+            version: 0,
         });
         storage.write_slice(index, &init_info).unwrap();
         // Pad the first word of the page. This makes the store looks used, otherwise we may confuse
@@ -1128,6 +2251,25 @@ impl Store<BufferStorage> {
     }
 }
 
+// Those functions are not meant for production.
+#[cfg(feature = "std")]
+impl<S: Storage> Store<TimedStorage<S>> {
+    /// Accesses the storage.
+    pub fn storage(&self) -> &TimedStorage<S> {
+        &self.storage
+    }
+
+    /// Accesses the storage mutably.
+    pub fn storage_mut(&mut self) -> &mut TimedStorage<S> {
+        &mut self.storage
+    }
+
+    /// Extracts the storage.
+    pub fn extract_storage(self) -> TimedStorage<S> {
+        self.storage
+    }
+}
+
 /// Represents an entry in the store.
 #[derive(Debug)]
 enum ParsedEntry {
@@ -1175,14 +2317,22 @@ pub struct StoreIter<'a, S: Storage> {
 
     /// Iteration stops when reaching this position.
     end: Position,
+
+    /// Only entries with a key in this range are returned.
+    keys: Range<usize>,
 }
 
 impl<'a, S: Storage> StoreIter<'a, S> {
-    /// Creates an iterator over the entries of a store.
-    fn new(store: &'a Store<S>) -> StoreResult<StoreIter<'a, S>> {
+    /// Creates an iterator over the entries of a store with a key in a given range.
+    fn new(store: &'a Store<S>, keys: Range<usize>) -> StoreResult<StoreIter<'a, S>> {
         let pos = store.head()?;
         let end = pos + store.format.virt_size();
-        Ok(StoreIter { store, pos, end })
+        Ok(StoreIter {
+            store,
+            pos,
+            end,
+            keys,
+        })
     }
 }
 
@@ -1197,6 +2347,7 @@ impl<'a, S: Storage> StoreIter<'a, S> {
             match self.store.parse_entry(&mut self.pos)? {
                 ParsedEntry::Tail => break,
                 ParsedEntry::Padding => (),
+                ParsedEntry::User(header) if !self.keys.contains(&(header.key as usize)) => (),
                 ParsedEntry::User(header) => {
                     return Ok(Some(StoreHandle {
                         key: header.key,
@@ -1204,7 +2355,7 @@ impl<'a, S: Storage> StoreIter<'a, S> {
                         len: header.length,
                     }))
                 }
-                _ => return Err(StoreError::InvalidStorage),
+                _ => return Err(self.store.unexpected_entry(entry_pos)),
             }
         }
         self.pos = self.end;
@@ -1240,6 +2391,7 @@ fn is_write_needed(source: &[u8], target: &[u8]) -> StoreResult<bool> {
 mod tests {
     use super::*;
     use crate::BufferOptions;
+    use std::cell::Cell;
 
     #[derive(Clone)]
     struct Config {
@@ -1386,6 +2538,166 @@ mod tests {
         driver.check().unwrap();
     }
 
+    #[test]
+    fn remove_range_ok() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+        let remove_range = |keys| StoreOperation::Transaction {
+            updates: vec![StoreUpdate::RemoveRange { keys }],
+        };
+        for key in 0..6 {
+            driver.insert(key, &[key as u8; 5]).unwrap();
+        }
+        // Remove a range alone.
+        driver.apply(remove_range(1..3)).unwrap();
+        driver.check().unwrap();
+        assert_eq!(driver.store().find(0).unwrap().unwrap(), [0; 5]);
+        assert_eq!(driver.store().find(1).unwrap(), None);
+        assert_eq!(driver.store().find(2).unwrap(), None);
+        assert_eq!(driver.store().find(3).unwrap().unwrap(), [3; 5]);
+        // Remove a range of a single key.
+        driver.apply(remove_range(0..1)).unwrap();
+        driver.check().unwrap();
+        assert_eq!(driver.store().find(0).unwrap(), None);
+        // Remove the largest range up to the maximum key.
+        let format = driver.model().format();
+        let max_key = format.max_key() as usize;
+        let max_remove_keys = format.max_remove_keys() as usize;
+        driver.insert(max_key, &[0x5c; 3]).unwrap();
+        driver
+            .apply(remove_range(max_key + 1 - max_remove_keys..max_key + 1))
+            .unwrap();
+        driver.check().unwrap();
+        assert_eq!(driver.store().find(max_key).unwrap(), None);
+        // Mix with other updates.
+        let updates = vec![
+            StoreUpdate::Insert {
+                key: 1,
+                value: vec![0x93; 3],
+            },
+            StoreUpdate::RemoveRange { keys: 3..6 },
+            StoreUpdate::Remove { key: 0 },
+        ];
+        driver
+            .apply(StoreOperation::Transaction { updates })
+            .unwrap();
+        driver.check().unwrap();
+        assert_eq!(driver.store().iter().unwrap().count(), 1);
+        assert_eq!(driver.store().find(1).unwrap().unwrap(), [0x93; 3]);
+        // Empty, out of bound, too large, and overlapping ranges are invalid.
+        let invalid = [2..2, max_key..max_key + 2, 0..max_remove_keys + 1];
+        for keys in invalid.iter().cloned() {
+            assert_eq!(
+                driver
+                    .store_mut()
+                    .transaction(&[StoreUpdate::RemoveRange { keys }]),
+                Err(StoreError::InvalidArgument)
+            );
+        }
+        let updates = vec![
+            StoreUpdate::RemoveRange { keys: 0..2 },
+            StoreUpdate::Remove { key: 1 },
+        ];
+        assert_eq!(
+            driver.store_mut().transaction(&updates),
+            Err(StoreError::InvalidArgument)
+        );
+        driver.check().unwrap();
+    }
+
+    #[test]
+    fn insert_if_absent_ok() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+        let insert_if_absent = |key, value: &[u8]| StoreOperation::Transaction {
+            updates: vec![StoreUpdate::InsertIfAbsent {
+                key,
+                value: value.to_vec(),
+            }],
+        };
+        // Insert absent entry.
+        driver.apply(insert_if_absent(0, &[0x5c; 6])).unwrap();
+        driver.check().unwrap();
+        let tail = driver.store().tail().unwrap();
+        // Present entry is not replaced and nothing is written.
+        driver.apply(insert_if_absent(0, &[0x93; 3])).unwrap();
+        driver.check().unwrap();
+        assert_eq!(driver.store().find(0).unwrap().unwrap(), [0x5c; 6]);
+        assert_eq!(driver.store().tail().unwrap(), tail);
+        // Mix with other updates.
+        let updates = vec![
+            StoreUpdate::InsertIfAbsent {
+                key: 0,
+                value: vec![0x93; 3],
+            },
+            StoreUpdate::InsertIfAbsent {
+                key: 1,
+                value: vec![0x81; 5],
+            },
+            StoreUpdate::Insert {
+                key: 2,
+                value: vec![0x38; 2],
+            },
+        ];
+        driver
+            .apply(StoreOperation::Transaction { updates })
+            .unwrap();
+        driver.check().unwrap();
+        assert_eq!(driver.store().find(0).unwrap().unwrap(), [0x5c; 6]);
+        assert_eq!(driver.store().find(1).unwrap().unwrap(), [0x81; 5]);
+    }
+
+    #[test]
+    fn compare_and_swap_ok() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+        let compare_and_swap = |key, old: &[u8], new: &[u8]| StoreOperation::Transaction {
+            updates: vec![StoreUpdate::CompareAndSwap {
+                key,
+                old: old.to_vec(),
+                new: new.to_vec(),
+            }],
+        };
+        // Absent entry fails.
+        driver.apply(compare_and_swap(0, &[], &[0x5c])).unwrap();
+        assert_eq!(driver.store().find(0).unwrap(), None);
+        driver.insert(0, &[0x5c; 6]).unwrap();
+        // Different value fails.
+        driver
+            .apply(compare_and_swap(0, &[0x5c; 5], &[0x93]))
+            .unwrap();
+        driver.check().unwrap();
+        assert_eq!(driver.store().find(0).unwrap().unwrap(), [0x5c; 6]);
+        // Same value doesn't write anything.
+        let tail = driver.store().tail().unwrap();
+        driver
+            .apply(compare_and_swap(0, &[0x5c; 6], &[0x5c; 6]))
+            .unwrap();
+        driver.check().unwrap();
+        assert_eq!(driver.store().tail().unwrap(), tail);
+        // Matching value is replaced.
+        driver
+            .apply(compare_and_swap(0, &[0x5c; 6], &[0x93]))
+            .unwrap();
+        driver.check().unwrap();
+        assert_eq!(driver.store().find(0).unwrap().unwrap(), [0x93]);
+        // A failing condition aborts the whole transaction.
+        let updates = vec![
+            StoreUpdate::Insert {
+                key: 1,
+                value: vec![0x81; 5],
+            },
+            StoreUpdate::CompareAndSwap {
+                key: 0,
+                old: vec![0x5c; 6],
+                new: vec![0x38],
+            },
+        ];
+        assert_eq!(
+            driver.store_mut().transaction(&updates),
+            Err(StoreError::ConditionFailed)
+        );
+        driver.check().unwrap();
+        assert_eq!(driver.store().find(1).unwrap(), None);
+    }
+
     #[test]
     fn prepare_ok() {
         let mut driver = MINIMAL.new_driver().power_on().unwrap();
@@ -1421,6 +2733,75 @@ mod tests {
         assert_eq!(driver.store().capacity().unwrap().remaining(), 18);
     }
 
+    #[test]
+    fn page_stats_ok() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+        let stats = |page, live_words, dead_words| PageStats {
+            page,
+            live_words,
+            dead_words,
+        };
+
+        // The first page contains a removed entry and the beginning of the next one.
+        for key in 0..4 {
+            driver.insert(key, &[0x38; 28]).unwrap();
+        }
+        driver.remove(0).unwrap();
+        assert_eq!(
+            driver.store().page_stats().unwrap(),
+            vec![
+                stats(0, 6, 8),
+                stats(1, 14, 0),
+                stats(2, 4, 0),
+                stats(3, 0, 0),
+                stats(4, 0, 0)
+            ]
+        );
+
+        // After compaction, the statistics start with the new head page. The end of the entry
+        // copied from the previous head page is before the head.
+        driver.store_mut().prepare(8).unwrap();
+        driver.check().unwrap();
+        let page_stats = driver.store().page_stats().unwrap();
+        assert_eq!(page_stats[0], stats(1, 12, 0));
+        assert_eq!(page_stats[4], stats(0, 0, 0));
+    }
+
+    #[test]
+    fn compact_with_ok() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+        let always = ReclaimablePolicy {
+            min_dead_percent: 0,
+        };
+
+        // Don't compact a head page which is still written.
+        driver.insert(0, &[0x38; 28]).unwrap();
+        driver.remove(0).unwrap();
+        assert!(!driver.store_mut().compact_with(&always).unwrap());
+
+        // Compact the full head page when enough of it is reclaimable.
+        for key in 1..4 {
+            driver.insert(key, &[0x38; 28]).unwrap();
+        }
+        let head = driver.store().head().unwrap();
+        let strict = ReclaimablePolicy {
+            min_dead_percent: 60,
+        };
+        assert!(!driver.store_mut().compact_with(&strict).unwrap());
+        assert_eq!(driver.store().head().unwrap(), head);
+        let lenient = ReclaimablePolicy {
+            min_dead_percent: 50,
+        };
+        assert!(driver.store_mut().compact_with(&lenient).unwrap());
+        driver.check().unwrap();
+        assert_ne!(driver.store().head().unwrap(), head);
+        assert_eq!(driver.store().capacity().unwrap().remaining(), 10);
+
+        // Without dead words, there is nothing to reclaim.
+        assert_eq!(driver.store().page_stats().unwrap()[0].dead_words, 0);
+        assert!(!driver.store_mut().compact_with(&always).unwrap());
+    }
+
     #[test]
     fn reboot_ok() {
         let mut driver = MINIMAL.new_driver().power_on().unwrap();
@@ -1438,4 +2819,632 @@ mod tests {
         driver = driver.power_off().power_on().unwrap();
         driver.check().unwrap();
     }
+
+    #[test]
+    fn static_wear_leveling_ok() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+        let format = driver.model().format().clone();
+
+        // Insert a cold entry and keep updating a hot entry until all pages have been compacted.
+        driver.insert(0, &[0x38; 24]).unwrap();
+        let cold_position = driver.store().find_handle(0).unwrap().unwrap().pos;
+        while driver.store().head().unwrap().cycle(&format) < 2 {
+            driver.insert(1, &[0x5c; 13]).unwrap();
+            driver.check().unwrap();
+            // The pages are erased in turn, regardless of how long-lived their entries are.
+            let erases: Vec<usize> = (0..format.num_pages() as usize)
+                .map(|page| driver.store().storage().get_page_erases(page))
+                .collect();
+            let max_erases = erases.iter().max().unwrap();
+            let min_erases = erases.iter().min().unwrap();
+            assert!(max_erases - min_erases <= 1);
+        }
+
+        // The cold entry was relocated by compaction.
+        let handle = driver.store().find_handle(0).unwrap().unwrap();
+        assert_ne!(handle.pos, cold_position);
+        assert_eq!(handle.get_value(driver.store()).unwrap(), vec![0x38; 24]);
+    }
+
+    #[test]
+    fn iter_range_ok() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+        for key in 0..8 {
+            driver.insert(key, &[key as u8; 5]).unwrap();
+        }
+        driver.remove(4).unwrap();
+        let store = driver.store();
+        let keys = |range: Range<usize>| {
+            let mut keys = store
+                .iter_range(range)
+                .unwrap()
+                .map(|handle| handle.unwrap().get_key())
+                .collect::<Vec<_>>();
+            keys.sort_unstable();
+            keys
+        };
+        assert_eq!(keys(2..6), vec![2, 3, 5]);
+        assert_eq!(keys(7..100), vec![7]);
+        assert_eq!(keys(8..100), Vec::<usize>::new());
+        assert_eq!(keys(0..usize::MAX).len(), store.iter().unwrap().count());
+    }
+
+    const CRITICAL_KEYS: CriticalKeys = CriticalKeys {
+        pointer: 3,
+        shadows: [5, 4],
+    };
+
+    #[test]
+    fn critical_ok() {
+        let mut store = MINIMAL.new_driver().power_on().unwrap().extract_store();
+        assert_eq!(store.find_critical(&CRITICAL_KEYS), Ok(None));
+        store.insert_critical(&CRITICAL_KEYS, &[0x38; 24]).unwrap();
+        assert_eq!(
+            store.find_critical(&CRITICAL_KEYS),
+            Ok(Some(vec![0x38; 24]))
+        );
+        assert_eq!(store.find(3), Ok(Some(vec![0])));
+        assert_eq!(store.find(5), Ok(Some(vec![0x38; 24])));
+        assert_eq!(store.find(4), Ok(None));
+
+        // The new value goes to the other shadow key, keeping the previous value.
+        store.insert_critical(&CRITICAL_KEYS, &[0x5c; 13]).unwrap();
+        assert_eq!(
+            store.find_critical(&CRITICAL_KEYS),
+            Ok(Some(vec![0x5c; 13]))
+        );
+        assert_eq!(store.find(3), Ok(Some(vec![1])));
+        assert_eq!(store.find(5), Ok(Some(vec![0x38; 24])));
+        assert_eq!(store.find(4), Ok(Some(vec![0x5c; 13])));
+
+        store.insert_critical(&CRITICAL_KEYS, &[0x21; 7]).unwrap();
+        assert_eq!(store.find_critical(&CRITICAL_KEYS), Ok(Some(vec![0x21; 7])));
+        assert_eq!(store.find(5), Ok(Some(vec![0x21; 7])));
+        assert_eq!(store.find(4), Ok(Some(vec![0x5c; 13])));
+
+        store.remove_critical(&CRITICAL_KEYS).unwrap();
+        assert_eq!(store.find_critical(&CRITICAL_KEYS), Ok(None));
+        assert_eq!(store.iter().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn critical_lost_shadow() {
+        let mut store = MINIMAL.new_driver().power_on().unwrap().extract_store();
+        store.insert_critical(&CRITICAL_KEYS, &[0x38; 24]).unwrap();
+        store.insert_critical(&CRITICAL_KEYS, &[0x38; 24]).unwrap();
+        // Losing the current shadow entry falls back to the previous value.
+        store.remove(4).unwrap();
+        assert_eq!(
+            store.find_critical(&CRITICAL_KEYS),
+            Ok(Some(vec![0x38; 24]))
+        );
+        // The next insertion overwrites the previous value.
+        store.insert_critical(&CRITICAL_KEYS, &[0x5c; 13]).unwrap();
+        assert_eq!(
+            store.find_critical(&CRITICAL_KEYS),
+            Ok(Some(vec![0x5c; 13]))
+        );
+        assert_eq!(store.find(3), Ok(Some(vec![0])));
+    }
+
+    #[test]
+    fn critical_interrupted_insert() {
+        let mut store = MINIMAL.new_driver().power_on().unwrap().extract_store();
+        store.insert_critical(&CRITICAL_KEYS, &[0x38; 24]).unwrap();
+        // Simulate an interruption after writing the new value but before flipping the pointer.
+        store.insert(4, &[0x5c; 13]).unwrap();
+        assert_eq!(
+            store.find_critical(&CRITICAL_KEYS),
+            Ok(Some(vec![0x38; 24]))
+        );
+    }
+
+    #[test]
+    fn critical_invalid_pointer() {
+        let mut store = MINIMAL.new_driver().power_on().unwrap().extract_store();
+        store.insert(3, &[2]).unwrap();
+        assert_eq!(
+            store.find_critical(&CRITICAL_KEYS),
+            Err(StoreError::InvalidStorage)
+        );
+        assert_eq!(
+            store.insert_critical(&CRITICAL_KEYS, &[0x38; 24]),
+            Err(StoreError::InvalidStorage)
+        );
+    }
+
+    struct IdentityMigration;
+
+    impl Migration for IdentityMigration {
+        fn migrate(&self, _: &mut dyn Storage) -> StoreResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn migrate_ok() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+        driver.insert(0, &[0x38; 24]).unwrap();
+        driver.insert(1, &[0x5c; 13]).unwrap();
+        assert_eq!(driver.store().storage_version(), Ok(Some(0)));
+
+        // Upgrade the store and check that all pages are upgraded.
+        let mut driver = driver.power_off();
+        driver.set_migrations(&[&IdentityMigration]);
+        let mut driver = driver.power_on().unwrap();
+        assert_eq!(driver.store().storage_version(), Ok(Some(1)));
+        driver.insert(2, &[0x93; 1]).unwrap();
+        driver = driver.power_off().power_on().unwrap();
+        driver.check().unwrap();
+
+        // Downgrading the store is not supported.
+        let mut driver = driver.power_off();
+        driver.set_migrations(&[]);
+        assert!(driver.power_on().is_err());
+    }
+
+    /// Counts how many times it migrates a storage.
+    struct CountingMigration<'a>(&'a Cell<usize>);
+
+    impl Migration for CountingMigration<'_> {
+        fn migrate(&self, _: &mut dyn Storage) -> StoreResult<()> {
+            self.0.set(self.0.get() + 1);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn migrate_past_first_version() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+        driver.insert(0, &[0x38; 24]).unwrap();
+        driver.insert(1, &[0x5c; 13]).unwrap();
+        let mut driver = driver.power_off();
+        driver.set_migrations(&[&IdentityMigration]);
+        let driver = driver.power_on().unwrap();
+        assert_eq!(driver.store().storage_version(), Ok(Some(1)));
+        let storage = driver.power_off().storage().clone();
+
+        // Only the missing migrations are applied, and all pages are upgraded.
+        let count = Cell::new(0);
+        let migration = CountingMigration(&count);
+        let migrations: [&dyn Migration; 3] = [&migration, &migration, &migration];
+        let store = Store::new_with_migrations(storage.clone(), &migrations)
+            .ok()
+            .unwrap();
+        assert_eq!(count.get(), 2);
+        assert_eq!(store.storage_version(), Ok(Some(MAX_FORMAT_VERSION)));
+        assert_eq!(store.find(0), Ok(Some(vec![0x38; 24])));
+        assert_eq!(store.find(1), Ok(Some(vec![0x5c; 13])));
+
+        // There is no format version after the maximum one.
+        let migrations: [&dyn Migration; 4] = [&migration, &migration, &migration, &migration];
+        let (error, _) = Store::new_with_migrations(storage, &migrations)
+            .err()
+            .unwrap();
+        assert_eq!(error, StoreError::InvalidArgument);
+        assert_eq!(count.get(), 2);
+    }
+
+    /// Returns the content of a storage.
+    fn storage_content(storage: &BufferStorage) -> Vec<u8> {
+        let mut content = Vec::new();
+        for page in 0..storage.num_pages() {
+            let index = StorageIndex { page, byte: 0 };
+            content.extend_from_slice(storage.read_slice(index, storage.page_size()).unwrap());
+        }
+        content
+    }
+
+    /// Copies the content of a storage into a storage with a different geometry.
+    fn reshape_storage(
+        storage: &BufferStorage,
+        page_size: usize,
+        num_pages: usize,
+    ) -> BufferStorage {
+        let mut content = storage_content(storage);
+        content.resize(page_size * num_pages, 0xff);
+        let options = BufferOptions {
+            word_size: MINIMAL.word_size,
+            page_size,
+            max_word_writes: MINIMAL.max_word_writes,
+            max_page_erases: MINIMAL.max_page_erases,
+            strict_mode: true,
+        };
+        BufferStorage::new(content.into_boxed_slice(), options)
+    }
+
+    #[test]
+    fn geometry_mismatch_page_size() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+        driver.insert(0, &[0x38; 24]).unwrap();
+        driver.insert(1, &[0x5c; 13]).unwrap();
+        let storage = driver.power_off().storage().clone();
+
+        // The storage is rejected with smaller pages and left unchanged.
+        let page_size = MINIMAL.page_size / 2;
+        let num_pages = MINIMAL.num_pages * 2;
+        let reshaped = reshape_storage(&storage, page_size, num_pages);
+        let (error, rejected) = Store::new(reshaped.clone()).err().unwrap();
+        assert_eq!(error, StoreError::GeometryMismatch);
+        assert_eq!(storage_content(&rejected), storage_content(&reshaped));
+
+        // The storage is still valid with its original geometry.
+        let storage = reshape_storage(&rejected, MINIMAL.page_size, MINIMAL.num_pages);
+        let store = Store::new(storage).ok().unwrap();
+        assert_eq!(store.find(0).unwrap(), Some(vec![0x38; 24]));
+    }
+
+    #[test]
+    fn geometry_mismatch_num_pages() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+        // Write until the store wraps around the storage.
+        for _ in 0..MINIMAL.num_pages {
+            driver.insert(0, &[0x38; 24]).unwrap();
+            driver.insert(1, &[0x5c; 24]).unwrap();
+        }
+        let format = driver.model().format();
+        assert!(driver.store().tail().unwrap().cycle(format) > 0);
+        let storage = driver.power_off().storage().clone();
+
+        let reshaped = reshape_storage(&storage, MINIMAL.page_size, MINIMAL.num_pages + 2);
+        let (error, _) = Store::new(reshaped).err().unwrap();
+        assert_eq!(error, StoreError::GeometryMismatch);
+    }
+
+    #[test]
+    fn scrub_ok() {
+        let mut store = MINIMAL.new_driver().power_on().unwrap().extract_store();
+        store.insert(0, &[0x38; 24]).unwrap();
+        store.insert(1, &[0x5c; 13]).unwrap();
+        store.remove(0).unwrap();
+        assert_eq!(store.scrub(), Ok(0));
+
+        // Simulate a deletion that did not wipe the value.
+        let handle = store.find_handle(1).unwrap().unwrap();
+        store.set_deleted(handle.pos).unwrap();
+        assert_eq!(store.find(1), Ok(None));
+        assert!(store.inspect_value(&handle).iter().any(|&x| x != 0x00));
+        assert!(store.scrub().unwrap() > 0);
+        assert_eq!(store.inspect_value(&handle), vec![0x00; 13]);
+        assert_eq!(store.scrub(), Ok(0));
+    }
+
+    #[test]
+    fn contains_ok() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+        driver.insert(0, &[]).unwrap();
+        driver.insert(1, &[0x5c; 6]).unwrap();
+        driver.check().unwrap();
+        let store = driver.store();
+        assert_eq!(store.contains(0), Ok(true));
+        assert_eq!(store.find(0), Ok(Some(Vec::new())));
+        assert_eq!(store.contains(1), Ok(true));
+        assert_eq!(store.contains(2), Ok(false));
+        assert_eq!(store.find(2), Ok(None));
+        // An empty entry only costs its header.
+        assert_eq!(store.capacity().unwrap().used(), 1 + 3);
+
+        driver.remove(0).unwrap();
+        driver.check().unwrap();
+        assert_eq!(driver.store().contains(0), Ok(false));
+
+        // Empty values survive compaction.
+        driver.insert(0, &[]).unwrap();
+        for _ in 0..20 {
+            driver.insert(1, &[0x5c; 30]).unwrap();
+        }
+        driver.check().unwrap();
+        assert_eq!(driver.store().contains(0), Ok(true));
+        assert_eq!(driver.store().find(0), Ok(Some(Vec::new())));
+    }
+
+    #[test]
+    fn replace_ok() {
+        let mut store = MINIMAL.new_driver().power_on().unwrap().extract_store();
+        store.replace(0, &[0x5c; 6]).unwrap();
+        assert_eq!(store.find(0), Ok(Some(vec![0x5c; 6])));
+        let lifetime = store.lifetime().unwrap().used();
+
+        // The same value is not written again.
+        store.replace(0, &[0x5c; 6]).unwrap();
+        assert_eq!(store.lifetime().unwrap().used(), lifetime);
+
+        // A different value, even of the same length, is written as a new entry.
+        store.replace(0, &[0x58; 6]).unwrap();
+        assert_eq!(store.find(0), Ok(Some(vec![0x58; 6])));
+        assert!(store.lifetime().unwrap().used() > lifetime);
+        store.replace(0, &[0x58; 5]).unwrap();
+        assert_eq!(store.find(0), Ok(Some(vec![0x58; 5])));
+
+        // Empty values are replaced like any other value.
+        store.replace(1, &[]).unwrap();
+        let lifetime = store.lifetime().unwrap().used();
+        store.replace(1, &[]).unwrap();
+        assert_eq!(store.lifetime().unwrap().used(), lifetime);
+        assert_eq!(store.replace(4096, &[]), Err(StoreError::InvalidArgument));
+    }
+
+    #[test]
+    fn check_ok() {
+        let mut store = MINIMAL.new_driver().power_on().unwrap().extract_store();
+        store.insert(0, &[0x38; 24]).unwrap();
+        store.insert(1, &[0x5c; 13]).unwrap();
+        store.insert(2, &[]).unwrap();
+        store.remove(0).unwrap();
+        let check = store.check().unwrap();
+        assert!(check.is_consistent());
+        assert_eq!(check.entries, 2);
+        assert_eq!(check.unwiped_words, 0);
+        assert_eq!(check.capacity, store.capacity().unwrap());
+    }
+
+    #[test]
+    fn check_corrupted() {
+        let mut store = MINIMAL.new_driver().power_on().unwrap().extract_store();
+        store.insert(0, &[0x5c; 8]).unwrap();
+        store.insert(1, &[0x38; 4]).unwrap();
+
+        // Corrupt the footer of the first entry.
+        let handle = store.find_handle(0).unwrap().unwrap();
+        store.write_slice(handle.pos + 2, &[0x00; 4]).unwrap();
+        let check = store.check().unwrap();
+        assert!(!check.is_consistent());
+        assert_eq!(check.entries, 1);
+        assert_eq!(check.corrupted_entries, 1);
+
+        // Write a word far after the tail.
+        let last = store.head().unwrap() + (store.format.virt_size() - 1);
+        store.write_slice(last, &[0x00; 4]).unwrap();
+        let check = store.check().unwrap();
+        assert!(check.tail_consistent);
+        assert_eq!(check.written_after_tail, 1);
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn take_error_context_ok() {
+        let mut store = MINIMAL.new_driver().power_on().unwrap().extract_store();
+        store.insert(0, &[0x5c; 8]).unwrap();
+        assert_eq!(store.take_error_context(), None);
+
+        // Write a marker entry at the tail, where only user entries are expected.
+        let tail = store.tail().unwrap();
+        let marker = store
+            .format
+            .build_internal(InternalEntry::Marker { count: 1 });
+        store.write_slice(tail, &marker).unwrap();
+        assert_eq!(store.capacity(), Err(StoreError::InvalidStorage));
+        let index = tail.index(&store.format);
+        let context = StoreErrorContext {
+            page: index.page,
+            word: index.byte / 4,
+            tag: "marker entry",
+        };
+        assert_eq!(store.take_error_context(), Some(context));
+        assert_eq!(store.take_error_context(), None);
+    }
+
+    #[cfg(feature = "diagnostics")]
+    #[test]
+    fn dump_debug_ok() {
+        let mut store = MINIMAL.new_driver().power_on().unwrap().extract_store();
+        store.insert(3, &[0x5c; 8]).unwrap();
+        store.insert(5, &[0x38; 4]).unwrap();
+        store.remove(3).unwrap();
+        let mut dump = String::new();
+        store.dump_debug(&mut dump).unwrap();
+        assert_eq!(
+            dump,
+            "\
+geometry: 5 pages of 16 words of 4 bytes, version 0
+head: page 0, word 2
+tail: page 0, word 7
+capacity: 2/34 words
+lifetime: 5/686 words
+page 0: cycle 0, 2 live words, 3 dead words
+page 1: erased, 0 live words, 0 dead words
+page 2: erased, 0 live words, 0 dead words
+page 3: erased, 0 live words, 0 dead words
+page 4: erased, 0 live words, 0 dead words
+key 5: page 0, word 5, 4 bytes
+last error: none
+"
+        );
+    }
+
+    #[test]
+    fn observer_ok() {
+        use std::cell::RefCell;
+
+        thread_local! {
+            static MUTATIONS: RefCell<Vec<StoreMutation>> = const { RefCell::new(Vec::new()) };
+        }
+        fn observe(mutation: &StoreMutation) {
+            MUTATIONS.with(|mutations| mutations.borrow_mut().push(*mutation));
+        }
+        fn take_mutations() -> Vec<StoreMutation> {
+            MUTATIONS.with(|mutations| mutations.replace(Vec::new()))
+        }
+
+        let mut store = MINIMAL.new_driver().power_on().unwrap().extract_store();
+        store.insert(0, &[0x38; 4]).unwrap();
+        assert_eq!(take_mutations(), []);
+
+        store.set_observer(Some(observe));
+        store.insert(1, &[0x5c; 8]).unwrap();
+        store.remove(0).unwrap();
+        let invalid_key = store.format.max_key() as usize + 1;
+        assert_eq!(
+            store.insert(invalid_key, &[]),
+            Err(StoreError::InvalidArgument)
+        );
+        store
+            .transaction(&[
+                StoreUpdate::Insert {
+                    key: 2,
+                    value: vec![0xc5; 3],
+                },
+                StoreUpdate::RemoveRange { keys: 0..2 },
+            ])
+            .unwrap();
+        store.clear(3).unwrap();
+        assert_eq!(
+            take_mutations(),
+            [
+                StoreMutation::Insert { key: 1, len: 8 },
+                StoreMutation::Remove { key: 0 },
+                StoreMutation::Insert { key: 2, len: 3 },
+                StoreMutation::RemoveRange {
+                    min_key: 0,
+                    max_key: 1
+                },
+                StoreMutation::RemoveRange {
+                    min_key: 3,
+                    max_key: store.format.max_key() as usize
+                },
+            ]
+        );
+
+        store.set_observer(None);
+        store.insert(4, &[]).unwrap();
+        assert_eq!(take_mutations(), []);
+    }
+
+    #[test]
+    fn rekey_ok() {
+        let mut store = MINIMAL.new_driver().power_on().unwrap().extract_store();
+        store.insert(0, &[0x38; 4]).unwrap();
+        store.insert(1, &[0x5c; 8]).unwrap();
+
+        // Moving to a free key.
+        store.rekey(0, 2).unwrap();
+        assert_eq!(store.find(0).unwrap(), None);
+        assert_eq!(store.find(2).unwrap(), Some(vec![0x38; 4]));
+
+        // Moving to a used key replaces its entry.
+        store.rekey(2, 1).unwrap();
+        assert_eq!(store.find(2).unwrap(), None);
+        assert_eq!(store.find(1).unwrap(), Some(vec![0x38; 4]));
+
+        // Moving a missing entry or to the same key does nothing.
+        store.rekey(3, 1).unwrap();
+        store.rekey(1, 1).unwrap();
+        assert_eq!(store.find(1).unwrap(), Some(vec![0x38; 4]));
+        assert_eq!(store.iter().unwrap().count(), 1);
+
+        let invalid_key = store.format.max_key() as usize + 1;
+        assert_eq!(
+            store.rekey(1, invalid_key),
+            Err(StoreError::InvalidArgument)
+        );
+        assert_eq!(
+            store.rekey(invalid_key, 1),
+            Err(StoreError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn write_once_ok() {
+        fn write_once(key: usize) -> bool {
+            key < 6
+        }
+
+        let mut store = MINIMAL.new_driver().power_on().unwrap().extract_store();
+        store.set_write_once(Some(write_once));
+
+        // Absent write-once entries may be inserted.
+        store.insert(0, &[0x38; 4]).unwrap();
+        store.insert_critical(&CRITICAL_KEYS, &[0x5c; 8]).unwrap();
+        store.insert(6, &[0xc5; 3]).unwrap();
+        let lifetime = store.lifetime().unwrap().used();
+
+        // Present write-once entries can't be modified nor removed.
+        assert_eq!(store.insert(0, &[0x38; 4]), Err(StoreError::WriteOnce));
+        assert_eq!(store.remove(0), Err(StoreError::WriteOnce));
+        let handle = store.find_handle(0).unwrap().unwrap();
+        assert_eq!(store.remove_handle(&handle), Err(StoreError::WriteOnce));
+        assert_eq!(store.rekey(0, 7), Err(StoreError::WriteOnce));
+        assert_eq!(store.rekey(6, 0), Err(StoreError::WriteOnce));
+        assert_eq!(
+            store.insert_critical(&CRITICAL_KEYS, &[0x21; 7]),
+            Err(StoreError::WriteOnce)
+        );
+        assert_eq!(
+            store.remove_critical(&CRITICAL_KEYS),
+            Err(StoreError::WriteOnce)
+        );
+        assert_eq!(
+            store.transaction(&[
+                StoreUpdate::Insert {
+                    key: 7,
+                    value: vec![0x21; 7],
+                },
+                StoreUpdate::RemoveRange { keys: 0..2 },
+            ]),
+            Err(StoreError::WriteOnce)
+        );
+        assert_eq!(store.clear(0), Err(StoreError::WriteOnce));
+        assert_eq!(store.lifetime().unwrap().used(), lifetime);
+        assert_eq!(store.find(0).unwrap(), Some(vec![0x38; 4]));
+        assert_eq!(store.find_critical(&CRITICAL_KEYS), Ok(Some(vec![0x5c; 8])));
+        assert_eq!(store.find(7).unwrap(), None);
+
+        // Replacing with the same value doesn't write and succeeds.
+        store.replace(0, &[0x38; 4]).unwrap();
+
+        // Other entries and absent write-once entries are not protected.
+        store.remove(1).unwrap();
+        store
+            .transaction(&[StoreUpdate::RemoveRange { keys: 1..3 }])
+            .unwrap();
+        store.rekey(6, 7).unwrap();
+        store.clear(6).unwrap();
+        assert_eq!(store.find(7).unwrap(), None);
+
+        store.set_write_once(None);
+        store.clear(0).unwrap();
+        assert_eq!(store.iter().unwrap().count(), 0);
+    }
+
+    #[test]
+    fn write_once_model_ok() {
+        fn write_once(key: usize) -> bool {
+            key == 0
+        }
+
+        let mut driver = MINIMAL.new_driver();
+        driver.set_write_once(Some(write_once));
+        let mut driver = driver.power_on().unwrap();
+        driver.insert(0, &[0x38; 4]).unwrap();
+        driver.insert(1, &[0x5c; 8]).unwrap();
+        // The store and model both reject those operations.
+        driver.insert(0, &[0x21; 4]).unwrap();
+        driver.remove(0).unwrap();
+        driver.apply(StoreOperation::Clear { min_key: 0 }).unwrap();
+        driver.check().unwrap();
+        assert_eq!(driver.store().find(0).unwrap(), Some(vec![0x38; 4]));
+
+        // The predicate is set again when the store is powered on.
+        let mut driver = driver.power_off().power_on().unwrap();
+        assert_eq!(driver.store_mut().remove(0), Err(StoreError::WriteOnce));
+    }
+
+    #[test]
+    fn new_or_reformat_ok() {
+        let mut driver = MINIMAL.new_driver().power_on().unwrap();
+        driver.insert(0, &[0x38; 24]).unwrap();
+        let storage = driver.power_off().storage().clone();
+
+        // The store is resumed when the geometry matches.
+        let store = Store::new_or_reformat(storage.clone()).ok().unwrap();
+        assert_eq!(store.find(0).unwrap(), Some(vec![0x38; 24]));
+
+        // The store is erased when the geometry does not match.
+        let page_size = MINIMAL.page_size / 2;
+        let num_pages = MINIMAL.num_pages * 2;
+        let storage = reshape_storage(&storage, page_size, num_pages);
+        let store = Store::new_or_reformat(storage).ok().unwrap();
+        assert_eq!(store.find(0).unwrap(), None);
+        assert!(store.iter().unwrap().next().is_none());
+    }
 }
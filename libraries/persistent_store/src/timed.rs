@@ -0,0 +1,262 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{Storage, StorageIndex, StorageResult};
+use core::cell::Cell;
+use core::time::Duration;
+
+/// Simulates the latency of a flash storage.
+///
+/// This timed storage wraps another storage (usually a [buffer storage]) and accounts for the time
+/// the flash operations would take on actual hardware. This permits to measure the latency of
+/// store operations on the host, for example to catch regressions without a board.
+///
+/// Only flash operations are accounted for. The time spent by the CPU, in particular to parse the
+/// storage, is not simulated.
+///
+/// [buffer storage]: struct.BufferStorage.html
+#[derive(Clone)]
+pub struct TimedStorage<S: Storage> {
+    /// The simulated storage.
+    storage: S,
+
+    /// Options of the storage.
+    options: TimedOptions,
+
+    /// Number of words read since the last reset.
+    ///
+    /// Reads only borrow the storage, so this counter needs interior mutability.
+    word_reads: Cell<usize>,
+
+    /// Number of words written since the last reset.
+    word_writes: usize,
+
+    /// Number of pages erased since the last reset.
+    page_erases: usize,
+}
+
+/// Options of a timed storage.
+#[derive(Clone, Debug)]
+pub struct TimedOptions {
+    /// Latency of reading a word.
+    pub word_read: Duration,
+
+    /// Latency of writing a word.
+    pub word_write: Duration,
+
+    /// Latency of erasing a page.
+    pub page_erase: Duration,
+}
+
+impl TimedOptions {
+    /// Flash timings of the nRF52840.
+    ///
+    /// Those are the maximum write and erase times of the product specification. Reads are memory
+    /// mapped and considered free.
+    pub const NRF52840: TimedOptions = TimedOptions {
+        word_read: Duration::from_micros(0),
+        word_write: Duration::from_micros(41),
+        page_erase: Duration::from_millis(85),
+    };
+}
+
+impl<S: Storage> TimedStorage<S> {
+    /// Creates a timed storage.
+    ///
+    /// The simulated time starts at zero.
+    pub fn new(storage: S, options: TimedOptions) -> TimedStorage<S> {
+        TimedStorage {
+            storage,
+            options,
+            word_reads: Cell::new(0),
+            word_writes: 0,
+            page_erases: 0,
+        }
+    }
+
+    /// Returns the simulated time spent in flash operations since the last reset.
+    pub fn elapsed(&self) -> Duration {
+        self.options.word_read * self.word_reads.get() as u32
+            + self.options.word_write * self.word_writes as u32
+            + self.options.page_erase * self.page_erases as u32
+    }
+
+    /// Returns the number of words read since the last reset.
+    pub fn word_reads(&self) -> usize {
+        self.word_reads.get()
+    }
+
+    /// Returns the number of words written since the last reset.
+    pub fn word_writes(&self) -> usize {
+        self.word_writes
+    }
+
+    /// Returns the number of pages erased since the last reset.
+    pub fn page_erases(&self) -> usize {
+        self.page_erases
+    }
+
+    /// Resets the simulated time and the operation counters.
+    pub fn reset(&mut self) {
+        self.word_reads.set(0);
+        self.word_writes = 0;
+        self.page_erases = 0;
+    }
+
+    /// Accesses the simulated storage.
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Accesses the simulated storage mutably.
+    ///
+    /// Operations on the simulated storage are not accounted for.
+    pub fn storage_mut(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    /// Extracts the simulated storage.
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+
+    /// Returns the number of words covered by a byte length, rounding up.
+    fn num_words(&self, length: usize) -> usize {
+        let word_size = self.storage.word_size();
+        // A partial word costs as much as a full word.
+        length / word_size + core::cmp::min(length % word_size, 1)
+    }
+}
+
+impl<S: Storage> Storage for TimedStorage<S> {
+    fn word_size(&self) -> usize {
+        self.storage.word_size()
+    }
+
+    fn page_size(&self) -> usize {
+        self.storage.page_size()
+    }
+
+    fn num_pages(&self) -> usize {
+        self.storage.num_pages()
+    }
+
+    fn max_word_writes(&self) -> usize {
+        self.storage.max_word_writes()
+    }
+
+    fn max_page_erases(&self) -> usize {
+        self.storage.max_page_erases()
+    }
+
+    fn read_slice(&self, index: StorageIndex, length: usize) -> StorageResult<&[u8]> {
+        let result = self.storage.read_slice(index, length)?;
+        self.word_reads
+            .set(self.word_reads.get() + self.num_words(length));
+        Ok(result)
+    }
+
+    fn write_slice(&mut self, index: StorageIndex, value: &[u8]) -> StorageResult<()> {
+        self.storage.write_slice(index, value)?;
+        self.word_writes += self.num_words(value.len());
+        Ok(())
+    }
+
+    fn erase_page(&mut self, page: usize) -> StorageResult<()> {
+        self.storage.erase_page(page)?;
+        self.page_erases += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BufferOptions, BufferStorage, Store};
+
+    const NUM_PAGES: usize = 20;
+    const OPTIONS: BufferOptions = BufferOptions {
+        word_size: 4,
+        page_size: 0x1000,
+        max_word_writes: 2,
+        max_page_erases: 10000,
+        strict_mode: true,
+    };
+
+    fn new_storage() -> TimedStorage<BufferStorage> {
+        let storage = vec![0xff; NUM_PAGES * OPTIONS.page_size].into_boxed_slice();
+        TimedStorage::new(BufferStorage::new(storage, OPTIONS), TimedOptions::NRF52840)
+    }
+
+    #[test]
+    fn operations_are_timed() {
+        let mut storage = new_storage();
+        assert_eq!(storage.elapsed(), Duration::from_micros(0));
+        let index = StorageIndex { page: 1, byte: 8 };
+        storage.write_slice(index, &[0x5c; 8]).unwrap();
+        assert_eq!(storage.read_slice(index, 6).unwrap(), &[0x5c; 6]);
+        storage.erase_page(1).unwrap();
+        assert_eq!(storage.word_reads(), 2);
+        assert_eq!(storage.word_writes(), 2);
+        assert_eq!(storage.page_erases(), 1);
+        assert_eq!(storage.elapsed(), Duration::from_micros(85082));
+        storage.reset();
+        assert_eq!(storage.elapsed(), Duration::from_micros(0));
+    }
+
+    #[test]
+    fn failed_operations_are_not_timed() {
+        let mut storage = new_storage();
+        let index = StorageIndex {
+            page: NUM_PAGES,
+            byte: 0,
+        };
+        assert!(storage.read_slice(index, 4).is_err());
+        assert!(storage.write_slice(index, &[0x5c; 4]).is_err());
+        assert!(storage.erase_page(NUM_PAGES).is_err());
+        assert_eq!(storage.elapsed(), Duration::from_micros(0));
+    }
+
+    #[test]
+    fn store_latency() {
+        // Those counts catch latency regressions of the store operations.
+        let mut store = Store::new(new_storage()).ok().unwrap();
+        store.storage_mut().reset();
+        store.insert(1, &[0; 4 * 50]).unwrap();
+        assert_eq!(store.storage().word_writes(), 51);
+        assert_eq!(store.storage().page_erases(), 0);
+
+        store.storage_mut().reset();
+        store.remove(1).unwrap();
+        assert_eq!(store.storage().word_writes(), 1);
+        assert_eq!(store.storage().page_erases(), 0);
+
+        // Fill the store such that the next word needs a compaction.
+        let mut length =
+            store.capacity().unwrap().total() + NUM_PAGES - store.lifetime().unwrap().used();
+        let max_length = store.max_value_length() / 4;
+        while length > 0 {
+            let value_length = core::cmp::min(length - 1, max_length);
+            store.insert(0, &vec![0; 4 * value_length]).unwrap();
+            store.remove(0).unwrap();
+            length -= 1 + value_length;
+        }
+        assert_eq!(store.storage().page_erases(), 0);
+        store.storage_mut().reset();
+        store.prepare(1).unwrap();
+        assert_eq!(store.storage().word_writes(), 3);
+        assert_eq!(store.storage().page_erases(), 1);
+        assert_eq!(store.storage().elapsed(), Duration::from_micros(85123));
+    }
+}
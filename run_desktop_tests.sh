@@ -29,6 +29,12 @@ cd ../..
 cd tools/heapviz
 cargo fmt --all -- --check
 cd ../..
+cd tools/store-tool
+cargo fmt --all -- --check
+cd ../..
+cd tools/scenario-runner
+cargo fmt --all -- --check
+cd ../..
 
 echo "Running Clippy lints..."
 cargo clippy --all-targets --features std -- -A clippy::new_without_default -D warnings
@@ -40,6 +46,13 @@ echo "Checking that heapviz tool builds properly..."
 cargo build --manifest-path tools/heapviz/Cargo.toml
 echo "Testing heapviz tool..."
 cargo test --manifest-path tools/heapviz/Cargo.toml
+echo "Checking that store-tool builds properly..."
+cargo build --manifest-path tools/store-tool/Cargo.toml
+echo "Testing store-tool..."
+cargo test --manifest-path tools/store-tool/Cargo.toml
+echo "Running the scenarios..."
+cargo test --manifest-path tools/scenario-runner/Cargo.toml
+cargo test --manifest-path tools/scenario-runner/Cargo.toml --features with_ctap2_1
 
 echo "Checking that CTAP2 builds properly..."
 cargo check --release --target=thumbv7em-none-eabi
@@ -49,6 +62,7 @@ cargo check --release --target=thumbv7em-none-eabi --features debug_ctap
 cargo check --release --target=thumbv7em-none-eabi --features panic_console
 cargo check --release --target=thumbv7em-none-eabi --features debug_allocations
 cargo check --release --target=thumbv7em-none-eabi --features verbose
+cargo check --release --target=thumbv7em-none-eabi --features perf
 cargo check --release --target=thumbv7em-none-eabi --features debug_ctap,with_ctap1
 cargo check --release --target=thumbv7em-none-eabi --features debug_ctap,with_ctap1,panic_console,debug_allocations,verbose
 
@@ -128,4 +142,7 @@ then
 
   echo "Running unit tests on the desktop (debug mode + CTAP1 + CTAP2.1)..."
   cargo test --features std,with_ctap1,with_ctap2_1
+
+  echo "Running unit tests on the desktop (debug mode + perf)..."
+  cargo test --features std,perf
 fi
@@ -0,0 +1,147 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::command::Command;
+use super::data_formats::AuthenticatorTransport;
+use super::version::{self, CtapVersion};
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// What the authenticator supports, derived once from the build.
+///
+/// This is the only place where capabilities are derived from the features. GetInfo, the command
+/// dispatch and the tests read them from here instead of checking the features, so that they stay
+/// in sync.
+#[derive(Clone, Copy)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub struct Capabilities {
+    /// The latest implemented CTAP2 version.
+    pub ctap_version: CtapVersion,
+    /// Whether U2F is supported, i.e. CTAP1.
+    pub u2f: bool,
+    /// Whether the NFC transport is supported, in addition to USB.
+    pub nfc: bool,
+    /// Whether the large blobs command is supported.
+    pub large_blobs: bool,
+    /// Whether the authenticator config command is supported.
+    pub authenticator_config: bool,
+    /// Whether built-in user verification is supported, e.g. a fingerprint sensor.
+    pub bio_enrollment: bool,
+    /// Whether enterprise attestation is supported.
+    pub enterprise_attestation: bool,
+}
+
+/// The capabilities of this build.
+pub const CAPABILITIES: Capabilities = Capabilities {
+    ctap_version: version::CTAP_VERSION,
+    u2f: cfg!(feature = "with_ctap1"),
+    nfc: cfg!(feature = "with_nfc"),
+    large_blobs: cfg!(feature = "with_ctap2_1"),
+    authenticator_config: cfg!(feature = "with_ctap2_1"),
+    bio_enrollment: false,
+    enterprise_attestation: false,
+};
+
+impl Capabilities {
+    /// Returns whether a CTAP2 version is implemented.
+    pub fn supports_version(&self, version: CtapVersion) -> bool {
+        version <= self.ctap_version
+    }
+
+    /// Returns whether an option is reported in GetInfo.
+    ///
+    /// Options are reported if their version is implemented and their feature is supported.
+    pub fn supports_option(&self, option: &str) -> bool {
+        let is_supported = match option {
+            "largeBlobs" => self.large_blobs,
+            "authnrCfg" => self.authenticator_config,
+            "uv" | "bioEnroll" => self.bio_enrollment,
+            "ep" => self.enterprise_attestation,
+            _ => true,
+        };
+        is_supported && self.supports_version(version::option_version(option))
+    }
+
+    /// Returns whether a command is processed. Other commands are unknown.
+    #[cfg_attr(not(feature = "with_ctap2_1"), allow(clippy::match_single_binding))]
+    pub fn supports_command(&self, command: &Command) -> bool {
+        let is_supported = match command {
+            #[cfg(feature = "with_ctap2_1")]
+            Command::AuthenticatorLargeBlobs(_) => self.large_blobs,
+            #[cfg(feature = "with_ctap2_1")]
+            Command::AuthenticatorConfig(_) => self.authenticator_config,
+            _ => true,
+        };
+        is_supported && self.supports_version(command.version())
+    }
+
+    /// Returns the transports reported in GetInfo and stored in resident credentials.
+    pub fn transports(&self) -> Vec<AuthenticatorTransport> {
+        let mut transports = vec![AuthenticatorTransport::Usb];
+        if self.nfc {
+            transports.push(AuthenticatorTransport::Nfc);
+        }
+        transports
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_supports_option() {
+        assert!(CAPABILITIES.supports_option("rk"));
+        assert!(CAPABILITIES.supports_option("clientPin"));
+        assert_eq!(
+            CAPABILITIES.supports_option("largeBlobs"),
+            CAPABILITIES.large_blobs
+        );
+        assert!(!CAPABILITIES.supports_option("ep"));
+        assert!(!CAPABILITIES.supports_option("bioEnroll"));
+    }
+
+    #[test]
+    fn test_supports_option_needs_version() {
+        let capabilities = Capabilities {
+            ctap_version: CtapVersion::Fido2_0,
+            large_blobs: true,
+            ..CAPABILITIES
+        };
+        assert!(!capabilities.supports_option("largeBlobs"));
+    }
+
+    #[test]
+    fn test_supports_command() {
+        assert!(CAPABILITIES.supports_command(&Command::AuthenticatorGetInfo));
+        assert!(CAPABILITIES.supports_command(&Command::AuthenticatorVendorMetrics));
+    }
+
+    #[test]
+    fn test_transports() {
+        let capabilities = Capabilities {
+            nfc: false,
+            ..CAPABILITIES
+        };
+        assert_eq!(capabilities.transports(), vec![AuthenticatorTransport::Usb]);
+        let capabilities = Capabilities {
+            nfc: true,
+            ..CAPABILITIES
+        };
+        assert_eq!(
+            capabilities.transports(),
+            vec![AuthenticatorTransport::Usb, AuthenticatorTransport::Nfc]
+        );
+    }
+}
@@ -12,26 +12,72 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use super::constants::{
+    ClientPinParameter, CommandCode, GetAssertionParameter, MakeCredentialParameter,
+};
+#[cfg(feature = "with_ctap2_1")]
+use super::data_formats::ConfigSubCommand;
 use super::data_formats::{
     extract_array, extract_bool, extract_byte_string, extract_map, extract_text_string,
-    extract_unsigned, ok_or_missing, ClientPinSubCommand, CoseKey, GetAssertionExtensions,
-    GetAssertionOptions, MakeCredentialExtensions, MakeCredentialOptions,
+    extract_unsigned, ok_or_missing, AttestationFormat, ClientPinSubCommand, CoseKey,
+    GetAssertionExtensions, GetAssertionOptions, MakeCredentialExtensions, MakeCredentialOptions,
     PublicKeyCredentialDescriptor, PublicKeyCredentialParameter, PublicKeyCredentialRpEntity,
-    PublicKeyCredentialUserEntity,
+    PublicKeyCredentialUserEntity, UsbIdentity, VendorSubCommand,
+};
+#[cfg(feature = "heap_free_parsing")]
+use super::data_formats::{
+    extract_array_ref, extract_byte_string_ref, extract_map_ref, extract_text_string_ref,
+    extract_unsigned_ref, PublicKeyCredentialDescriptorRef,
 };
 use super::key_material;
 use super::status_code::Ctap2StatusCode;
+use super::version::CtapVersion;
+use super::Sha256;
+use alloc::collections::BTreeSet;
 use alloc::string::String;
+use alloc::vec;
 use alloc::vec::Vec;
 use arrayref::array_ref;
 use cbor::destructure_cbor_map;
 use core::convert::TryFrom;
+use crypto::Hash256;
 
 // Depending on your memory, you can use Some(n) to limit request sizes in
 // MakeCredential and GetAssertion. This affects allowList and excludeList.
+// Longer lists are rejected before any of their descriptors is processed.
 // You might also want to set the max credential size in process_get_info then.
 pub const MAX_CREDENTIAL_COUNT_IN_LIST: Option<usize> = None;
 
+// Bounds on the CBOR parameters of commands, so that a malicious host can't exhaust the stack or
+// the heap. The element count includes all map keys and values, so it needs to be larger than the
+// data items of the longest lists you accept, e.g. 4 per credential descriptor without transports.
+const CBOR_READ_LIMITS: cbor::ReadLimits = cbor::ReadLimits {
+    max_nesting: 4,
+    max_elements: Some(1024),
+};
+
+// Rejects credential lists longer than the limit, so that processing time stays bounded.
+fn check_credential_list_length(
+    length: usize,
+    max_length: Option<usize>,
+) -> Result<(), Ctap2StatusCode> {
+    match max_length {
+        Some(max_length) if length > max_length => Err(Ctap2StatusCode::CTAP2_ERR_LIMIT_EXCEEDED),
+        _ => Ok(()),
+    }
+}
+
+// Removes the descriptors whose key ID appeared earlier in the list, so that each credential is
+// only looked up once. Key IDs are compared by hash to bound the memory used.
+fn dedup_credential_list(
+    list: Vec<PublicKeyCredentialDescriptor>,
+) -> Vec<PublicKeyCredentialDescriptor> {
+    let mut key_id_hashes = BTreeSet::new();
+    list.into_iter()
+        .filter(|descriptor| key_id_hashes.insert(Sha256::hash(&descriptor.key_id)))
+        .collect()
+}
+
 // CTAP specification (version 20190130) section 6.1
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
 pub enum Command {
@@ -43,9 +89,19 @@ pub enum Command {
     AuthenticatorGetNextAssertion,
     #[cfg(feature = "with_ctap2_1")]
     AuthenticatorSelection,
+    #[cfg(feature = "with_ctap2_1")]
+    AuthenticatorLargeBlobs(AuthenticatorLargeBlobsParameters),
+    #[cfg(feature = "with_ctap2_1")]
+    AuthenticatorConfig(AuthenticatorConfigParameters),
     // TODO(kaczmarczyck) implement FIDO 2.1 commands (see below consts)
     // Vendor specific commands
     AuthenticatorVendorConfigure(AuthenticatorVendorConfigureParameters),
+    AuthenticatorVendorAuditLog(AuthenticatorVendorAuditLogParameters),
+    #[cfg(feature = "debug_ctap")]
+    AuthenticatorVendorUserPresence,
+    AuthenticatorVendorMetrics,
+    AuthenticatorVendorSelfTest,
+    AuthenticatorVendor(AuthenticatorVendorParameters),
 }
 
 impl From<cbor::reader::DecoderError> for Ctap2StatusCode {
@@ -54,77 +110,111 @@ impl From<cbor::reader::DecoderError> for Ctap2StatusCode {
     }
 }
 
-// TODO: Remove this `allow(dead_code)` once the constants are used.
-#[allow(dead_code)]
 impl Command {
-    const AUTHENTICATOR_MAKE_CREDENTIAL: u8 = 0x01;
-    const AUTHENTICATOR_GET_ASSERTION: u8 = 0x02;
-    const AUTHENTICATOR_GET_INFO: u8 = 0x04;
-    const AUTHENTICATOR_CLIENT_PIN: u8 = 0x06;
-    const AUTHENTICATOR_RESET: u8 = 0x07;
-    const AUTHENTICATOR_GET_NEXT_ASSERTION: u8 = 0x08;
-    // TODO(kaczmarczyck) use or remove those constants
-    const AUTHENTICATOR_BIO_ENROLLMENT: u8 = 0x09;
-    const AUTHENTICATOR_CREDENTIAL_MANAGEMENT: u8 = 0x0A;
-    const AUTHENTICATOR_SELECTION: u8 = 0x0B;
-    const AUTHENTICATOR_LARGE_BLOBS: u8 = 0x0C;
-    const AUTHENTICATOR_CONFIG: u8 = 0x0D;
-    const _AUTHENTICATOR_VENDOR_FIRST: u8 = 0x40;
-    const AUTHENTICATOR_VENDOR_CONFIGURE: u8 = 0x40;
-    const _AUTHENTICATOR_VENDOR_LAST: u8 = 0xBF;
-
     pub fn deserialize(bytes: &[u8]) -> Result<Command, Ctap2StatusCode> {
         if bytes.is_empty() {
             // The error to return is not specified, missing parameter seems to fit best.
             return Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER);
         }
 
-        let command_value = bytes[0];
-        match command_value {
-            Command::AUTHENTICATOR_MAKE_CREDENTIAL => {
-                let decoded_cbor = cbor::read(&bytes[1..])?;
+        match CommandCode::try_from(bytes[0]) {
+            Ok(CommandCode::MakeCredential) => {
+                let decoded_cbor = cbor::read_limited(&bytes[1..], CBOR_READ_LIMITS)?;
                 Ok(Command::AuthenticatorMakeCredential(
                     AuthenticatorMakeCredentialParameters::try_from(decoded_cbor)?,
                 ))
             }
-            Command::AUTHENTICATOR_GET_ASSERTION => {
-                let decoded_cbor = cbor::read(&bytes[1..])?;
+            Ok(CommandCode::GetAssertion) => {
+                let decoded_cbor = cbor::read_limited(&bytes[1..], CBOR_READ_LIMITS)?;
                 Ok(Command::AuthenticatorGetAssertion(
                     AuthenticatorGetAssertionParameters::try_from(decoded_cbor)?,
                 ))
             }
-            Command::AUTHENTICATOR_GET_INFO => {
+            Ok(CommandCode::GetInfo) => {
                 // Parameters are ignored.
                 Ok(Command::AuthenticatorGetInfo)
             }
-            Command::AUTHENTICATOR_CLIENT_PIN => {
-                let decoded_cbor = cbor::read(&bytes[1..])?;
+            Ok(CommandCode::ClientPin) => {
+                let decoded_cbor = cbor::read_limited(&bytes[1..], CBOR_READ_LIMITS)?;
                 Ok(Command::AuthenticatorClientPin(
                     AuthenticatorClientPinParameters::try_from(decoded_cbor)?,
                 ))
             }
-            Command::AUTHENTICATOR_RESET => {
+            Ok(CommandCode::Reset) => {
                 // Parameters are ignored.
                 Ok(Command::AuthenticatorReset)
             }
-            Command::AUTHENTICATOR_GET_NEXT_ASSERTION => {
+            Ok(CommandCode::GetNextAssertion) => {
                 // Parameters are ignored.
                 Ok(Command::AuthenticatorGetNextAssertion)
             }
             #[cfg(feature = "with_ctap2_1")]
-            Command::AUTHENTICATOR_SELECTION => {
+            Ok(CommandCode::Selection) => {
                 // Parameters are ignored.
                 Ok(Command::AuthenticatorSelection)
             }
-            Command::AUTHENTICATOR_VENDOR_CONFIGURE => {
-                let decoded_cbor = cbor::read(&bytes[1..])?;
+            #[cfg(feature = "with_ctap2_1")]
+            Ok(CommandCode::LargeBlobs) => {
+                let decoded_cbor = cbor::read_limited(&bytes[1..], CBOR_READ_LIMITS)?;
+                Ok(Command::AuthenticatorLargeBlobs(
+                    AuthenticatorLargeBlobsParameters::try_from(decoded_cbor)?,
+                ))
+            }
+            #[cfg(feature = "with_ctap2_1")]
+            Ok(CommandCode::Config) => {
+                let decoded_cbor = cbor::read_limited(&bytes[1..], CBOR_READ_LIMITS)?;
+                Ok(Command::AuthenticatorConfig(
+                    AuthenticatorConfigParameters::try_from(decoded_cbor)?,
+                ))
+            }
+            Ok(CommandCode::VendorConfigure) => {
+                let decoded_cbor = cbor::read_limited(&bytes[1..], CBOR_READ_LIMITS)?;
                 Ok(Command::AuthenticatorVendorConfigure(
                     AuthenticatorVendorConfigureParameters::try_from(decoded_cbor)?,
                 ))
             }
+            Ok(CommandCode::VendorAuditLog) => {
+                let decoded_cbor = cbor::read_limited(&bytes[1..], CBOR_READ_LIMITS)?;
+                Ok(Command::AuthenticatorVendorAuditLog(
+                    AuthenticatorVendorAuditLogParameters::try_from(decoded_cbor)?,
+                ))
+            }
+            #[cfg(feature = "debug_ctap")]
+            Ok(CommandCode::VendorUserPresence) => {
+                // Parameters are ignored.
+                Ok(Command::AuthenticatorVendorUserPresence)
+            }
+            Ok(CommandCode::VendorMetrics) => {
+                // Parameters are ignored.
+                Ok(Command::AuthenticatorVendorMetrics)
+            }
+            Ok(CommandCode::VendorSelfTest) => {
+                // Parameters are ignored.
+                Ok(Command::AuthenticatorVendorSelfTest)
+            }
+            Ok(CommandCode::Vendor) => {
+                let decoded_cbor = cbor::read_limited(&bytes[1..], CBOR_READ_LIMITS)?;
+                Ok(Command::AuthenticatorVendor(
+                    AuthenticatorVendorParameters::try_from(decoded_cbor)?,
+                ))
+            }
             _ => Err(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND),
         }
     }
+
+    /// Returns the version introducing the command.
+    ///
+    /// Vendor commands are available with all versions.
+    #[cfg_attr(not(feature = "with_ctap2_1"), allow(clippy::match_single_binding))]
+    pub fn version(&self) -> CtapVersion {
+        match self {
+            #[cfg(feature = "with_ctap2_1")]
+            Command::AuthenticatorSelection
+            | Command::AuthenticatorLargeBlobs(_)
+            | Command::AuthenticatorConfig(_) => CtapVersion::Fido2_1Pre,
+            _ => CtapVersion::Fido2_0,
+        }
+    }
 }
 
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
@@ -139,6 +229,8 @@ pub struct AuthenticatorMakeCredentialParameters {
     pub options: MakeCredentialOptions,
     pub pin_uv_auth_param: Option<Vec<u8>>,
     pub pin_uv_auth_protocol: Option<u64>,
+    // The attestation formats requested by the platform, most preferred first.
+    pub attestation_formats_preference: Option<Vec<AttestationFormat>>,
 }
 
 impl TryFrom<cbor::Value> for AuthenticatorMakeCredentialParameters {
@@ -147,15 +239,16 @@ impl TryFrom<cbor::Value> for AuthenticatorMakeCredentialParameters {
     fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
         destructure_cbor_map! {
             let {
-                1 => client_data_hash,
-                2 => rp,
-                3 => user,
-                4 => cred_param_vec,
-                5 => exclude_list,
-                6 => extensions,
-                7 => options,
-                8 => pin_uv_auth_param,
-                9 => pin_uv_auth_protocol,
+                MakeCredentialParameter::ClientDataHash => client_data_hash,
+                MakeCredentialParameter::Rp => rp,
+                MakeCredentialParameter::User => user,
+                MakeCredentialParameter::PubKeyCredParams => cred_param_vec,
+                MakeCredentialParameter::ExcludeList => exclude_list,
+                MakeCredentialParameter::Extensions => extensions,
+                MakeCredentialParameter::Options => options,
+                MakeCredentialParameter::PinUvAuthParam => pin_uv_auth_param,
+                MakeCredentialParameter::PinUvAuthProtocol => pin_uv_auth_protocol,
+                MakeCredentialParameter::AttestationFormatsPreference => attestation_formats,
             } = extract_map(cbor_value)?;
         }
 
@@ -172,10 +265,9 @@ impl TryFrom<cbor::Value> for AuthenticatorMakeCredentialParameters {
         let exclude_list = match exclude_list {
             Some(entry) => {
                 let exclude_list_vec = extract_array(entry)?;
-                let list_len = MAX_CREDENTIAL_COUNT_IN_LIST.unwrap_or(exclude_list_vec.len());
+                check_credential_list_length(exclude_list_vec.len(), MAX_CREDENTIAL_COUNT_IN_LIST)?;
                 let exclude_list = exclude_list_vec
                     .into_iter()
-                    .take(list_len)
                     .map(PublicKeyCredentialDescriptor::try_from)
                     .collect::<Result<Vec<PublicKeyCredentialDescriptor>, Ctap2StatusCode>>()?;
                 Some(exclude_list)
@@ -198,6 +290,21 @@ impl TryFrom<cbor::Value> for AuthenticatorMakeCredentialParameters {
         let pin_uv_auth_param = pin_uv_auth_param.map(extract_byte_string).transpose()?;
         let pin_uv_auth_protocol = pin_uv_auth_protocol.map(extract_unsigned).transpose()?;
 
+        // Formats unknown to the authenticator are skipped.
+        let attestation_formats_preference = match attestation_formats {
+            Some(entry) => {
+                let mut formats = Vec::new();
+                for format in extract_array(entry)? {
+                    let format = extract_text_string(format)?;
+                    if let Ok(format) = AttestationFormat::try_from(format.as_str()) {
+                        formats.push(format);
+                    }
+                }
+                Some(formats)
+            }
+            None => None,
+        };
+
         Ok(AuthenticatorMakeCredentialParameters {
             client_data_hash,
             rp,
@@ -208,6 +315,7 @@ impl TryFrom<cbor::Value> for AuthenticatorMakeCredentialParameters {
             options,
             pin_uv_auth_param,
             pin_uv_auth_protocol,
+            attestation_formats_preference,
         })
     }
 }
@@ -230,13 +338,13 @@ impl TryFrom<cbor::Value> for AuthenticatorGetAssertionParameters {
     fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
         destructure_cbor_map! {
             let {
-                1 => rp_id,
-                2 => client_data_hash,
-                3 => allow_list,
-                4 => extensions,
-                5 => options,
-                6 => pin_uv_auth_param,
-                7 => pin_uv_auth_protocol,
+                GetAssertionParameter::RpId => rp_id,
+                GetAssertionParameter::ClientDataHash => client_data_hash,
+                GetAssertionParameter::AllowList => allow_list,
+                GetAssertionParameter::Extensions => extensions,
+                GetAssertionParameter::Options => options,
+                GetAssertionParameter::PinUvAuthParam => pin_uv_auth_param,
+                GetAssertionParameter::PinUvAuthProtocol => pin_uv_auth_protocol,
             } = extract_map(cbor_value)?;
         }
 
@@ -246,13 +354,12 @@ impl TryFrom<cbor::Value> for AuthenticatorGetAssertionParameters {
         let allow_list = match allow_list {
             Some(entry) => {
                 let allow_list_vec = extract_array(entry)?;
-                let list_len = MAX_CREDENTIAL_COUNT_IN_LIST.unwrap_or(allow_list_vec.len());
+                check_credential_list_length(allow_list_vec.len(), MAX_CREDENTIAL_COUNT_IN_LIST)?;
                 let allow_list = allow_list_vec
                     .into_iter()
-                    .take(list_len)
                     .map(PublicKeyCredentialDescriptor::try_from)
                     .collect::<Result<Vec<PublicKeyCredentialDescriptor>, Ctap2StatusCode>>()?;
-                Some(allow_list)
+                Some(dedup_credential_list(allow_list))
             }
             None => None,
         };
@@ -284,6 +391,110 @@ impl TryFrom<cbor::Value> for AuthenticatorGetAssertionParameters {
     }
 }
 
+/// Borrowed version of `AuthenticatorGetAssertionParameters`.
+///
+/// All strings and lists point into the request buffer, so parsing does not allocate. Lists and
+/// extensions are validated, but only decoded when iterated.
+#[cfg(feature = "heap_free_parsing")]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct AuthenticatorGetAssertionParametersRef<'a> {
+    pub rp_id: &'a str,
+    pub client_data_hash: &'a [u8],
+    pub allow_list: Option<cbor::borrowed::ArrayRef<'a>>,
+    pub extensions: Option<cbor::borrowed::MapRef<'a>>,
+    // Even though options are optional, we can use the default if not present.
+    pub options: GetAssertionOptions,
+    pub pin_uv_auth_param: Option<&'a [u8]>,
+    pub pin_uv_auth_protocol: Option<u64>,
+}
+
+#[cfg(feature = "heap_free_parsing")]
+impl<'a> AuthenticatorGetAssertionParametersRef<'a> {
+    /// Parses the parameters of a GetAssertion command, without its command byte.
+    pub fn deserialize(bytes: &'a [u8]) -> Result<Self, Ctap2StatusCode> {
+        Self::try_from(cbor::read_ref(bytes)?)
+    }
+
+    /// Iterates over the allowList.
+    ///
+    /// Its length was checked against MAX_CREDENTIAL_COUNT_IN_LIST when parsing. Since parsing
+    /// does not allocate, duplicate descriptors are not removed.
+    pub fn allow_list(
+        &self,
+    ) -> impl Iterator<Item = Result<PublicKeyCredentialDescriptorRef<'a>, Ctap2StatusCode>> {
+        self.allow_list
+            .clone()
+            .into_iter()
+            .flat_map(|list| list.iter())
+            .map(PublicKeyCredentialDescriptorRef::try_from)
+    }
+}
+
+#[cfg(feature = "heap_free_parsing")]
+impl<'a> TryFrom<cbor::ValueRef<'a>> for AuthenticatorGetAssertionParametersRef<'a> {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::ValueRef<'a>) -> Result<Self, Ctap2StatusCode> {
+        let mut rp_id = None;
+        let mut client_data_hash = None;
+        let mut allow_list = None;
+        let mut extensions = None;
+        let mut options = None;
+        let mut pin_uv_auth_param = None;
+        let mut pin_uv_auth_protocol = None;
+        for (key, value) in extract_map_ref(cbor_value)?.iter() {
+            match key {
+                cbor::ValueRef::Unsigned(1) => rp_id = Some(value),
+                cbor::ValueRef::Unsigned(2) => client_data_hash = Some(value),
+                cbor::ValueRef::Unsigned(3) => allow_list = Some(value),
+                cbor::ValueRef::Unsigned(4) => extensions = Some(value),
+                cbor::ValueRef::Unsigned(5) => options = Some(value),
+                cbor::ValueRef::Unsigned(6) => pin_uv_auth_param = Some(value),
+                cbor::ValueRef::Unsigned(7) => pin_uv_auth_protocol = Some(value),
+                _ => (),
+            }
+        }
+
+        let rp_id = extract_text_string_ref(ok_or_missing(rp_id)?)?;
+        let client_data_hash = extract_byte_string_ref(ok_or_missing(client_data_hash)?)?;
+
+        let allow_list = match allow_list {
+            Some(entry) => {
+                let allow_list = extract_array_ref(entry)?;
+                check_credential_list_length(allow_list.len(), MAX_CREDENTIAL_COUNT_IN_LIST)?;
+                for descriptor in allow_list.iter() {
+                    PublicKeyCredentialDescriptorRef::try_from(descriptor)?;
+                }
+                Some(allow_list)
+            }
+            None => None,
+        };
+
+        let extensions = extensions.map(extract_map_ref).transpose()?;
+
+        let options = match options {
+            Some(entry) => GetAssertionOptions::try_from(entry)?,
+            None => GetAssertionOptions {
+                up: true,
+                uv: false,
+            },
+        };
+
+        let pin_uv_auth_param = pin_uv_auth_param.map(extract_byte_string_ref).transpose()?;
+        let pin_uv_auth_protocol = pin_uv_auth_protocol.map(extract_unsigned_ref).transpose()?;
+
+        Ok(AuthenticatorGetAssertionParametersRef {
+            rp_id,
+            client_data_hash,
+            allow_list,
+            extensions,
+            options,
+            pin_uv_auth_param,
+            pin_uv_auth_protocol,
+        })
+    }
+}
+
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
 pub struct AuthenticatorClientPinParameters {
     pub pin_protocol: u64,
@@ -309,27 +520,27 @@ impl TryFrom<cbor::Value> for AuthenticatorClientPinParameters {
         #[cfg(not(feature = "with_ctap2_1"))]
         destructure_cbor_map! {
             let {
-                1 => pin_protocol,
-                2 => sub_command,
-                3 => key_agreement,
-                4 => pin_auth,
-                5 => new_pin_enc,
-                6 => pin_hash_enc,
+                ClientPinParameter::PinProtocol => pin_protocol,
+                ClientPinParameter::SubCommand => sub_command,
+                ClientPinParameter::KeyAgreement => key_agreement,
+                ClientPinParameter::PinAuth => pin_auth,
+                ClientPinParameter::NewPinEnc => new_pin_enc,
+                ClientPinParameter::PinHashEnc => pin_hash_enc,
             } = extract_map(cbor_value)?;
         }
         #[cfg(feature = "with_ctap2_1")]
         destructure_cbor_map! {
             let {
-                1 => pin_protocol,
-                2 => sub_command,
-                3 => key_agreement,
-                4 => pin_auth,
-                5 => new_pin_enc,
-                6 => pin_hash_enc,
-                7 => min_pin_length,
-                8 => min_pin_length_rp_ids,
-                9 => permissions,
-                10 => permissions_rp_id,
+                ClientPinParameter::PinProtocol => pin_protocol,
+                ClientPinParameter::SubCommand => sub_command,
+                ClientPinParameter::KeyAgreement => key_agreement,
+                ClientPinParameter::PinAuth => pin_auth,
+                ClientPinParameter::NewPinEnc => new_pin_enc,
+                ClientPinParameter::PinHashEnc => pin_hash_enc,
+                ClientPinParameter::MinPinLength => min_pin_length,
+                ClientPinParameter::MinPinLengthRpIds => min_pin_length_rp_ids,
+                ClientPinParameter::Permissions => permissions,
+                ClientPinParameter::PermissionsRpId => permissions_rp_id,
             } = extract_map(cbor_value)?;
         }
 
@@ -385,10 +596,121 @@ impl TryFrom<cbor::Value> for AuthenticatorClientPinParameters {
     }
 }
 
+#[cfg(feature = "with_ctap2_1")]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct AuthenticatorLargeBlobsParameters {
+    pub get: Option<usize>,
+    pub set: Option<Vec<u8>>,
+    pub offset: usize,
+    pub length: Option<usize>,
+    pub pin_uv_auth_param: Option<Vec<u8>>,
+    pub pin_uv_auth_protocol: Option<u64>,
+}
+
+#[cfg(feature = "with_ctap2_1")]
+impl TryFrom<cbor::Value> for AuthenticatorLargeBlobsParameters {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        destructure_cbor_map! {
+            let {
+                1 => get,
+                2 => set,
+                3 => offset,
+                4 => length,
+                5 => pin_uv_auth_param,
+                6 => pin_uv_auth_protocol,
+            } = extract_map(cbor_value)?;
+        }
+
+        let get = get
+            .map(extract_unsigned)
+            .transpose()?
+            .map(unsigned_to_usize)
+            .transpose()?;
+        let set = set.map(extract_byte_string).transpose()?;
+        // The specification wants CTAP1_ERR_INVALID_PARAMETER instead of a missing parameter.
+        let offset = offset.ok_or(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)?;
+        let offset = unsigned_to_usize(extract_unsigned(offset)?)?;
+        let length = length
+            .map(extract_unsigned)
+            .transpose()?
+            .map(unsigned_to_usize)
+            .transpose()?;
+        let pin_uv_auth_param = pin_uv_auth_param.map(extract_byte_string).transpose()?;
+        let pin_uv_auth_protocol = pin_uv_auth_protocol.map(extract_unsigned).transpose()?;
+
+        // Exactly one of get and set is present. Reads have no length.
+        match (&get, &set) {
+            (Some(_), None) if length.is_none() => (),
+            (None, Some(_)) => (),
+            _ => return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER),
+        }
+
+        Ok(AuthenticatorLargeBlobsParameters {
+            get,
+            set,
+            offset,
+            length,
+            pin_uv_auth_param,
+            pin_uv_auth_protocol,
+        })
+    }
+}
+
+// Converts a length or offset parameter. Values that don't fit in memory can't be valid.
+#[cfg(feature = "with_ctap2_1")]
+fn unsigned_to_usize(value: u64) -> Result<usize, Ctap2StatusCode> {
+    usize::try_from(value).map_err(|_| Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH)
+}
+
+#[cfg(feature = "with_ctap2_1")]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct AuthenticatorConfigParameters {
+    pub sub_command: ConfigSubCommand,
+    pub pin_uv_auth_protocol: Option<u64>,
+    pub pin_uv_auth_param: Option<Vec<u8>>,
+}
+
+#[cfg(feature = "with_ctap2_1")]
+impl TryFrom<cbor::Value> for AuthenticatorConfigParameters {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        // The sub command parameters (key 2) are ignored, since none of the supported sub
+        // commands has any.
+        destructure_cbor_map! {
+            let {
+                1 => sub_command,
+                3 => pin_uv_auth_protocol,
+                4 => pin_uv_auth_param,
+            } = extract_map(cbor_value)?;
+        }
+
+        let sub_command = ConfigSubCommand::try_from(ok_or_missing(sub_command)?)?;
+        let pin_uv_auth_protocol = pin_uv_auth_protocol.map(extract_unsigned).transpose()?;
+        let pin_uv_auth_param = pin_uv_auth_param.map(extract_byte_string).transpose()?;
+
+        Ok(AuthenticatorConfigParameters {
+            sub_command,
+            pin_uv_auth_protocol,
+            pin_uv_auth_param,
+        })
+    }
+}
+
+/// Attestation material sent for vendor provisioning.
+///
+/// The private key is never sent in plaintext. It is encrypted under the shared secret of a key
+/// agreement with the authenticator, as done for PINs. The pinAuth covers the certificates of the
+/// chain followed by the encrypted private key.
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
 pub struct AuthenticatorAttestationMaterial {
-    pub certificate: Vec<u8>,
-    pub private_key: [u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH],
+    // The attestation certificate, followed by the optional intermediate certificates.
+    pub certificate_chain: Vec<Vec<u8>>,
+    pub private_key_enc: [u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH],
+    pub key_agreement: CoseKey,
+    pub pin_auth: Vec<u8>,
 }
 
 impl TryFrom<cbor::Value> for AuthenticatorAttestationMaterial {
@@ -398,18 +720,34 @@ impl TryFrom<cbor::Value> for AuthenticatorAttestationMaterial {
         destructure_cbor_map! {
             let {
                 1 => certificate,
-                2 => private_key,
+                2 => private_key_enc,
+                3 => key_agreement,
+                4 => pin_auth,
+                5 => intermediate_certificates,
             } = extract_map(cbor_value)?;
         }
-        let certificate = extract_byte_string(ok_or_missing(certificate)?)?;
-        let private_key = extract_byte_string(ok_or_missing(private_key)?)?;
-        if private_key.len() != key_material::ATTESTATION_PRIVATE_KEY_LENGTH {
+        let mut certificate_chain = vec![extract_byte_string(ok_or_missing(certificate)?)?];
+        if let Some(intermediate_certificates) = intermediate_certificates {
+            for certificate in extract_array(intermediate_certificates)? {
+                certificate_chain.push(extract_byte_string(certificate)?);
+            }
+        }
+        let private_key_enc = extract_byte_string(ok_or_missing(private_key_enc)?)?;
+        let key_agreement = CoseKey(extract_map(ok_or_missing(key_agreement)?)?);
+        let pin_auth = extract_byte_string(ok_or_missing(pin_auth)?)?;
+        if private_key_enc.len() != key_material::ATTESTATION_PRIVATE_KEY_LENGTH {
             return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
         }
-        let private_key = array_ref!(private_key, 0, key_material::ATTESTATION_PRIVATE_KEY_LENGTH);
+        let private_key_enc = array_ref!(
+            private_key_enc,
+            0,
+            key_material::ATTESTATION_PRIVATE_KEY_LENGTH
+        );
         Ok(AuthenticatorAttestationMaterial {
-            certificate,
-            private_key: *private_key,
+            certificate_chain,
+            private_key_enc: *private_key_enc,
+            key_agreement,
+            pin_auth,
         })
     }
 }
@@ -418,6 +756,9 @@ impl TryFrom<cbor::Value> for AuthenticatorAttestationMaterial {
 pub struct AuthenticatorVendorConfigureParameters {
     pub lockdown: bool,
     pub attestation_material: Option<AuthenticatorAttestationMaterial>,
+    pub usb_identity: Option<UsbIdentity>,
+    // A fresh random value that the authenticator signs with its attestation private key.
+    pub challenge: Option<[u8; 32]>,
 }
 
 impl TryFrom<cbor::Value> for AuthenticatorVendorConfigureParameters {
@@ -428,15 +769,172 @@ impl TryFrom<cbor::Value> for AuthenticatorVendorConfigureParameters {
             let {
                 1 => lockdown,
                 2 => attestation_material,
+                3 => usb_identity,
+                4 => challenge,
             } = extract_map(cbor_value)?;
         }
         let lockdown = lockdown.map_or(Ok(false), extract_bool)?;
         let attestation_material = attestation_material
             .map(AuthenticatorAttestationMaterial::try_from)
             .transpose()?;
+        let usb_identity = usb_identity.map(UsbIdentity::try_from).transpose()?;
+        let challenge = challenge.map(extract_byte_string).transpose()?;
+        let challenge = match challenge {
+            Some(challenge) if challenge.len() == 32 => Some(*array_ref!(challenge, 0, 32)),
+            Some(_) => return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER),
+            None => None,
+        };
         Ok(AuthenticatorVendorConfigureParameters {
             lockdown,
             attestation_material,
+            usb_identity,
+            challenge,
+        })
+    }
+}
+
+#[derive(Clone)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct AuthenticatorVendorAuditLogParameters {
+    pub clear: bool,
+    pub pin_uv_auth_param: Vec<u8>,
+    pub pin_uv_auth_protocol: u64,
+}
+
+impl TryFrom<cbor::Value> for AuthenticatorVendorAuditLogParameters {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        destructure_cbor_map! {
+            let {
+                1 => clear,
+                2 => pin_uv_auth_param,
+                3 => pin_uv_auth_protocol,
+            } = extract_map(cbor_value)?;
+        }
+        let clear = clear.map_or(Ok(false), extract_bool)?;
+        let pin_uv_auth_param = extract_byte_string(ok_or_missing(pin_uv_auth_param)?)?;
+        let pin_uv_auth_protocol = extract_unsigned(ok_or_missing(pin_uv_auth_protocol)?)?;
+        Ok(AuthenticatorVendorAuditLogParameters {
+            clear,
+            pin_uv_auth_param,
+            pin_uv_auth_protocol,
+        })
+    }
+}
+
+/// Sets or removes the display name of a resident credential.
+///
+/// The display name is chosen by the user on the authenticator, and returned when enumerating
+/// credentials.
+#[derive(Clone)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct AuthenticatorVendorDisplayNameParameters {
+    pub credential_id: Vec<u8>,
+    pub display_name: Option<String>,
+    pub pin_uv_auth_protocol: Option<u64>,
+    pub pin_uv_auth_param: Option<Vec<u8>>,
+}
+
+impl TryFrom<cbor::Value> for AuthenticatorVendorDisplayNameParameters {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        destructure_cbor_map! {
+            let {
+                1 => credential_id,
+                2 => display_name,
+                3 => pin_uv_auth_protocol,
+                4 => pin_uv_auth_param,
+            } = extract_map(cbor_value)?;
+        }
+        let credential_id = extract_byte_string(ok_or_missing(credential_id)?)?;
+        let display_name = display_name.map(extract_text_string).transpose()?;
+        let pin_uv_auth_protocol = pin_uv_auth_protocol.map(extract_unsigned).transpose()?;
+        let pin_uv_auth_param = pin_uv_auth_param.map(extract_byte_string).transpose()?;
+        Ok(AuthenticatorVendorDisplayNameParameters {
+            credential_id,
+            display_name,
+            pin_uv_auth_protocol,
+            pin_uv_auth_param,
+        })
+    }
+}
+
+/// Blinks the LEDs of the authenticator, to find it among many others.
+#[derive(Clone)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct AuthenticatorVendorIdentifyParameters {
+    /// How long to blink, in seconds. Zero stops blinking.
+    pub duration_s: u64,
+}
+
+impl TryFrom<cbor::Value> for AuthenticatorVendorIdentifyParameters {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        destructure_cbor_map! {
+            let {
+                1 => duration_s,
+            } = extract_map(cbor_value)?;
+        }
+        let duration_s = extract_unsigned(ok_or_missing(duration_s)?)?;
+        Ok(AuthenticatorVendorIdentifyParameters { duration_s })
+    }
+}
+
+/// Parameters of the vendor command, dispatched to its subcommands.
+///
+/// Subcommands without parameters ignore them.
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub enum AuthenticatorVendorParameters {
+    Configure(AuthenticatorVendorConfigureParameters),
+    AuditLog(AuthenticatorVendorAuditLogParameters),
+    Metrics,
+    SelfTest,
+    DisplayName(AuthenticatorVendorDisplayNameParameters),
+    #[cfg(feature = "perf")]
+    CommandTimings,
+    Identify(AuthenticatorVendorIdentifyParameters),
+}
+
+impl TryFrom<cbor::Value> for AuthenticatorVendorParameters {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        destructure_cbor_map! {
+            let {
+                1 => sub_command,
+                2 => sub_command_params,
+            } = extract_map(cbor_value)?;
+        }
+
+        let sub_command = VendorSubCommand::try_from(ok_or_missing(sub_command)?)?;
+        Ok(match sub_command {
+            VendorSubCommand::Configure => AuthenticatorVendorParameters::Configure(
+                AuthenticatorVendorConfigureParameters::try_from(ok_or_missing(
+                    sub_command_params,
+                )?)?,
+            ),
+            VendorSubCommand::AuditLog => AuthenticatorVendorParameters::AuditLog(
+                AuthenticatorVendorAuditLogParameters::try_from(ok_or_missing(
+                    sub_command_params,
+                )?)?,
+            ),
+            VendorSubCommand::Metrics => AuthenticatorVendorParameters::Metrics,
+            VendorSubCommand::SelfTest => AuthenticatorVendorParameters::SelfTest,
+            VendorSubCommand::DisplayName => AuthenticatorVendorParameters::DisplayName(
+                AuthenticatorVendorDisplayNameParameters::try_from(ok_or_missing(
+                    sub_command_params,
+                )?)?,
+            ),
+            #[cfg(feature = "perf")]
+            VendorSubCommand::CommandTimings => AuthenticatorVendorParameters::CommandTimings,
+            VendorSubCommand::Identify => AuthenticatorVendorParameters::Identify(
+                AuthenticatorVendorIdentifyParameters::try_from(ok_or_missing(
+                    sub_command_params,
+                )?)?,
+            ),
         })
     }
 }
@@ -471,6 +969,7 @@ mod test {
             5 => cbor_array![],
             8 => vec![0x12, 0x34],
             9 => 1,
+            11 => cbor_array!["tpm", "none", "packed"],
         };
         let returned_make_credential_parameters =
             AuthenticatorMakeCredentialParameters::try_from(cbor_value).unwrap();
@@ -504,6 +1003,10 @@ mod test {
             options,
             pin_uv_auth_param: Some(vec![0x12, 0x34]),
             pin_uv_auth_protocol: Some(1),
+            attestation_formats_preference: Some(vec![
+                AttestationFormat::None,
+                AttestationFormat::Packed,
+            ]),
         };
 
         assert_eq!(
@@ -559,38 +1062,154 @@ mod test {
     }
 
     #[test]
-    fn test_from_cbor_client_pin_parameters() {
-        // TODO(kaczmarczyck) inline the #cfg when #128 is resolved:
-        // https://github.com/google/OpenSK/issues/128
-        #[cfg(not(feature = "with_ctap2_1"))]
-        let cbor_value = cbor_map! {
-            1 => 1,
-            2 => ClientPinSubCommand::GetPinRetries,
-            3 => cbor_map!{},
-            4 => vec! [0xBB],
-            5 => vec! [0xCC],
-            6 => vec! [0xDD],
+    fn test_from_cbor_get_assertion_parameters_dedup_allow_list() {
+        let descriptor = |key_id: u8| {
+            cbor_map! {
+                "type" => "public-key",
+                "id" => vec![key_id; 4],
+            }
         };
-        #[cfg(feature = "with_ctap2_1")]
         let cbor_value = cbor_map! {
-            1 => 1,
-            2 => ClientPinSubCommand::GetPinRetries,
-            3 => cbor_map!{},
-            4 => vec! [0xBB],
-            5 => vec! [0xCC],
-            6 => vec! [0xDD],
-            7 => 4,
-            8 => cbor_array!["example.com"],
-            9 => 0x03,
-            10 => "example.com",
+            1 => "example.com",
+            2 => vec![0x00, 0x01, 0x02, 0x03],
+            3 => cbor_array![descriptor(0x2D), descriptor(0x3D), descriptor(0x2D), descriptor(0x3D)],
         };
-        let returned_pin_protocol_parameters =
-            AuthenticatorClientPinParameters::try_from(cbor_value).unwrap();
+        let allow_list = AuthenticatorGetAssertionParameters::try_from(cbor_value)
+            .unwrap()
+            .allow_list
+            .unwrap();
+        let key_ids = allow_list
+            .into_iter()
+            .map(|descriptor| descriptor.key_id)
+            .collect::<Vec<_>>();
+        assert_eq!(key_ids, vec![vec![0x2D; 4], vec![0x3D; 4]]);
+    }
 
-        let expected_pin_protocol_parameters = AuthenticatorClientPinParameters {
-            pin_protocol: 1,
-            sub_command: ClientPinSubCommand::GetPinRetries,
-            key_agreement: Some(CoseKey(BTreeMap::new())),
+    #[test]
+    fn test_check_credential_list_length() {
+        assert_eq!(check_credential_list_length(1000, None), Ok(()));
+        assert_eq!(check_credential_list_length(0, Some(0)), Ok(()));
+        assert_eq!(check_credential_list_length(4, Some(4)), Ok(()));
+        assert_eq!(
+            check_credential_list_length(5, Some(4)),
+            Err(Ctap2StatusCode::CTAP2_ERR_LIMIT_EXCEEDED)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "heap_free_parsing")]
+    fn test_from_cbor_get_assertion_parameters_ref() {
+        let cbor_value = cbor_map! {
+            1 => "example.com",
+            2 => vec![0x00, 0x01, 0x02, 0x03],
+            3 => cbor_array![ cbor_map! {
+                "type" => "public-key",
+                "id" => vec![0x2D, 0x2D, 0x2D, 0x2D],
+                "transports" => cbor_array!["usb"],
+            } ],
+            5 => cbor_map! {
+                "up" => false,
+            },
+            6 => vec![0x12, 0x34],
+            7 => 1,
+        };
+        let mut encoded_cbor = Vec::new();
+        assert!(cbor::write(cbor_value, &mut encoded_cbor));
+        let parameters =
+            AuthenticatorGetAssertionParametersRef::deserialize(&encoded_cbor).unwrap();
+
+        assert_eq!(parameters.rp_id, "example.com");
+        assert_eq!(parameters.client_data_hash, &[0x00, 0x01, 0x02, 0x03]);
+        assert_eq!(parameters.extensions, None);
+        assert_eq!(
+            parameters.options,
+            GetAssertionOptions {
+                up: false,
+                uv: false,
+            }
+        );
+        assert_eq!(parameters.pin_uv_auth_param, Some(&[0x12, 0x34][..]));
+        assert_eq!(parameters.pin_uv_auth_protocol, Some(1));
+
+        let mut allow_list = parameters.allow_list();
+        let descriptor = allow_list.next().unwrap().unwrap();
+        assert_eq!(descriptor.key_type, PublicKeyCredentialType::PublicKey);
+        assert_eq!(descriptor.key_id, &[0x2D, 0x2D, 0x2D, 0x2D]);
+        assert_eq!(
+            descriptor.transports.map(|transports| transports.len()),
+            Some(1)
+        );
+        assert!(allow_list.next().is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "heap_free_parsing")]
+    fn test_from_cbor_get_assertion_parameters_ref_errors() {
+        let cbor_value = cbor_map! {
+            2 => vec![0x00, 0x01, 0x02, 0x03],
+        };
+        let mut encoded_cbor = Vec::new();
+        assert!(cbor::write(cbor_value, &mut encoded_cbor));
+        assert_eq!(
+            AuthenticatorGetAssertionParametersRef::deserialize(&encoded_cbor),
+            Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
+        );
+
+        let cbor_value = cbor_map! {
+            1 => "example.com",
+            2 => vec![0x00, 0x01, 0x02, 0x03],
+            3 => cbor_array![ cbor_map! {
+                "type" => "public-key",
+                "id" => vec![0x2D, 0x2D, 0x2D, 0x2D],
+                "transports" => cbor_array!["carrier pigeon"],
+            } ],
+        };
+        let mut encoded_cbor = Vec::new();
+        assert!(cbor::write(cbor_value, &mut encoded_cbor));
+        assert_eq!(
+            AuthenticatorGetAssertionParametersRef::deserialize(&encoded_cbor),
+            Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE)
+        );
+
+        assert_eq!(
+            AuthenticatorGetAssertionParametersRef::deserialize(&[0xA1]),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR)
+        );
+    }
+
+    #[test]
+    fn test_from_cbor_client_pin_parameters() {
+        // TODO(kaczmarczyck) inline the #cfg when #128 is resolved:
+        // https://github.com/google/OpenSK/issues/128
+        #[cfg(not(feature = "with_ctap2_1"))]
+        let cbor_value = cbor_map! {
+            1 => 1,
+            2 => ClientPinSubCommand::GetPinRetries,
+            3 => cbor_map!{},
+            4 => vec! [0xBB],
+            5 => vec! [0xCC],
+            6 => vec! [0xDD],
+        };
+        #[cfg(feature = "with_ctap2_1")]
+        let cbor_value = cbor_map! {
+            1 => 1,
+            2 => ClientPinSubCommand::GetPinRetries,
+            3 => cbor_map!{},
+            4 => vec! [0xBB],
+            5 => vec! [0xCC],
+            6 => vec! [0xDD],
+            7 => 4,
+            8 => cbor_array!["example.com"],
+            9 => 0x03,
+            10 => "example.com",
+        };
+        let returned_pin_protocol_parameters =
+            AuthenticatorClientPinParameters::try_from(cbor_value).unwrap();
+
+        let expected_pin_protocol_parameters = AuthenticatorClientPinParameters {
+            pin_protocol: 1,
+            sub_command: ClientPinSubCommand::GetPinRetries,
+            key_agreement: Some(CoseKey(BTreeMap::new())),
             pin_auth: Some(vec![0xBB]),
             new_pin_enc: Some(vec![0xCC]),
             pin_hash_enc: Some(vec![0xDD]),
@@ -610,9 +1229,32 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_deserialize_too_much_nesting() {
+        // A map nesting 8 arrays exceeds the nesting limit of the CBOR decoder.
+        let mut cbor_bytes = vec![u8::from(CommandCode::MakeCredential), 0xA1, 0x01];
+        cbor_bytes.extend(&[0x81; 8]);
+        cbor_bytes.push(0x00);
+        let command = Command::deserialize(&cbor_bytes);
+        assert_eq!(command, Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR));
+    }
+
+    #[test]
+    fn test_deserialize_too_many_elements() {
+        // A map announcing more pairs than the element limit is rejected before decoding them.
+        let mut cbor_bytes = vec![u8::from(CommandCode::MakeCredential), 0xB9, 0x02, 0x00];
+        cbor_bytes.extend(&[0x00; 8]);
+        let command = Command::deserialize(&cbor_bytes);
+        assert_eq!(command, Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR));
+        assert_eq!(
+            cbor::read_limited(&cbor_bytes[1..], CBOR_READ_LIMITS),
+            Err(cbor::reader::DecoderError::TooManyElements)
+        );
+    }
+
     #[test]
     fn test_deserialize_get_info() {
-        let cbor_bytes = [Command::AUTHENTICATOR_GET_INFO];
+        let cbor_bytes = [u8::from(CommandCode::GetInfo)];
         let command = Command::deserialize(&cbor_bytes);
         assert_eq!(command, Ok(Command::AuthenticatorGetInfo));
     }
@@ -620,14 +1262,14 @@ mod test {
     #[test]
     fn test_deserialize_reset() {
         // Adding some random bytes to see if they are ignored.
-        let cbor_bytes = [Command::AUTHENTICATOR_RESET, 0xAB, 0xCD, 0xEF];
+        let cbor_bytes = [u8::from(CommandCode::Reset), 0xAB, 0xCD, 0xEF];
         let command = Command::deserialize(&cbor_bytes);
         assert_eq!(command, Ok(Command::AuthenticatorReset));
     }
 
     #[test]
     fn test_deserialize_get_next_assertion() {
-        let cbor_bytes = [Command::AUTHENTICATOR_GET_NEXT_ASSERTION];
+        let cbor_bytes = [u8::from(CommandCode::GetNextAssertion)];
         let command = Command::deserialize(&cbor_bytes);
         assert_eq!(command, Ok(Command::AuthenticatorGetNextAssertion));
     }
@@ -635,15 +1277,306 @@ mod test {
     #[cfg(feature = "with_ctap2_1")]
     #[test]
     fn test_deserialize_selection() {
-        let cbor_bytes = [Command::AUTHENTICATOR_SELECTION];
+        let cbor_bytes = [u8::from(CommandCode::Selection)];
         let command = Command::deserialize(&cbor_bytes);
         assert_eq!(command, Ok(Command::AuthenticatorSelection));
     }
 
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_from_cbor_large_blobs_parameters() {
+        let cbor_value = cbor_map! {
+            1 => 2,
+            3 => 4,
+        };
+        let returned_large_blobs_parameters =
+            AuthenticatorLargeBlobsParameters::try_from(cbor_value);
+        let expected_large_blobs_parameters = AuthenticatorLargeBlobsParameters {
+            get: Some(2),
+            set: None,
+            offset: 4,
+            length: None,
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        assert_eq!(
+            returned_large_blobs_parameters,
+            Ok(expected_large_blobs_parameters)
+        );
+
+        let cbor_value = cbor_map! {
+            2 => vec![0x5B],
+            3 => 0,
+            4 => 17,
+            5 => vec![0xBB],
+            6 => 1,
+        };
+        let returned_large_blobs_parameters =
+            AuthenticatorLargeBlobsParameters::try_from(cbor_value);
+        let expected_large_blobs_parameters = AuthenticatorLargeBlobsParameters {
+            get: None,
+            set: Some(vec![0x5B]),
+            offset: 0,
+            length: Some(17),
+            pin_uv_auth_param: Some(vec![0xBB]),
+            pin_uv_auth_protocol: Some(1),
+        };
+        assert_eq!(
+            returned_large_blobs_parameters,
+            Ok(expected_large_blobs_parameters)
+        );
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_from_cbor_large_blobs_parameters_invalid() {
+        let invalid_cbor_values = vec![
+            // Missing offset.
+            cbor_map! { 1 => 2 },
+            // Neither get nor set.
+            cbor_map! { 3 => 0 },
+            // Both get and set.
+            cbor_map! { 1 => 2, 2 => vec![0x5B], 3 => 0 },
+            // Get with a length.
+            cbor_map! { 1 => 2, 3 => 0, 4 => 17 },
+        ];
+        for cbor_value in invalid_cbor_values {
+            assert_eq!(
+                AuthenticatorLargeBlobsParameters::try_from(cbor_value),
+                Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+            );
+        }
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_deserialize_large_blobs() {
+        let mut cbor_bytes = vec![u8::from(CommandCode::LargeBlobs)];
+        let cbor_value = cbor_map! {
+            1 => 2,
+            3 => 0,
+        };
+        assert!(cbor::write(cbor_value, &mut cbor_bytes));
+        let command = Command::deserialize(&cbor_bytes);
+        let expected_command =
+            Command::AuthenticatorLargeBlobs(AuthenticatorLargeBlobsParameters {
+                get: Some(2),
+                set: None,
+                offset: 0,
+                length: None,
+                pin_uv_auth_param: None,
+                pin_uv_auth_protocol: None,
+            });
+        assert_eq!(command, Ok(expected_command));
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_from_cbor_config_parameters() {
+        let cbor_value = cbor_map! {
+            1 => ConfigSubCommand::ToggleAlwaysUv,
+            3 => 1,
+            4 => vec![0xBB],
+        };
+        let returned_config_parameters = AuthenticatorConfigParameters::try_from(cbor_value);
+        let expected_config_parameters = AuthenticatorConfigParameters {
+            sub_command: ConfigSubCommand::ToggleAlwaysUv,
+            pin_uv_auth_protocol: Some(1),
+            pin_uv_auth_param: Some(vec![0xBB]),
+        };
+        assert_eq!(returned_config_parameters, Ok(expected_config_parameters));
+
+        let cbor_value = cbor_map! {};
+        assert_eq!(
+            AuthenticatorConfigParameters::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
+        );
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_deserialize_config() {
+        let mut cbor_bytes = vec![u8::from(CommandCode::Config)];
+        let cbor_value = cbor_map! {
+            1 => ConfigSubCommand::ToggleAlwaysUv,
+        };
+        assert!(cbor::write(cbor_value, &mut cbor_bytes));
+        let command = Command::deserialize(&cbor_bytes);
+        let expected_command = Command::AuthenticatorConfig(AuthenticatorConfigParameters {
+            sub_command: ConfigSubCommand::ToggleAlwaysUv,
+            pin_uv_auth_protocol: None,
+            pin_uv_auth_param: None,
+        });
+        assert_eq!(command, Ok(expected_command));
+    }
+
+    #[cfg(feature = "debug_ctap")]
+    #[test]
+    fn test_deserialize_vendor_user_presence() {
+        let cbor_bytes = [u8::from(CommandCode::VendorUserPresence)];
+        let command = Command::deserialize(&cbor_bytes);
+        assert_eq!(command, Ok(Command::AuthenticatorVendorUserPresence));
+    }
+
+    #[test]
+    fn test_deserialize_vendor_metrics() {
+        let cbor_bytes = [u8::from(CommandCode::VendorMetrics)];
+        let command = Command::deserialize(&cbor_bytes);
+        assert_eq!(command, Ok(Command::AuthenticatorVendorMetrics));
+    }
+
+    #[test]
+    fn test_deserialize_vendor_self_test() {
+        let cbor_bytes = [u8::from(CommandCode::VendorSelfTest)];
+        let command = Command::deserialize(&cbor_bytes);
+        assert_eq!(command, Ok(Command::AuthenticatorVendorSelfTest));
+    }
+
+    #[test]
+    fn test_from_cbor_vendor_parameters() {
+        // Subcommands without parameters ignore them.
+        let cbor_value = cbor_map! {
+            1 => VendorSubCommand::Metrics,
+        };
+        assert_eq!(
+            AuthenticatorVendorParameters::try_from(cbor_value),
+            Ok(AuthenticatorVendorParameters::Metrics)
+        );
+        let cbor_value = cbor_map! {
+            1 => VendorSubCommand::SelfTest,
+            2 => cbor_map! {},
+        };
+        assert_eq!(
+            AuthenticatorVendorParameters::try_from(cbor_value),
+            Ok(AuthenticatorVendorParameters::SelfTest)
+        );
+
+        // Subcommand parameters are those of the legacy vendor commands.
+        let cbor_value = cbor_map! {
+            1 => VendorSubCommand::Configure,
+            2 => cbor_map! {
+                1 => true,
+            },
+        };
+        assert_eq!(
+            AuthenticatorVendorParameters::try_from(cbor_value),
+            Ok(AuthenticatorVendorParameters::Configure(
+                AuthenticatorVendorConfigureParameters {
+                    lockdown: true,
+                    attestation_material: None,
+                    usb_identity: None,
+                    challenge: None,
+                }
+            ))
+        );
+        let cbor_value = cbor_map! {
+            1 => VendorSubCommand::AuditLog,
+            2 => cbor_map! {
+                2 => vec![0x12, 0x34],
+                3 => 1,
+            },
+        };
+        assert_eq!(
+            AuthenticatorVendorParameters::try_from(cbor_value),
+            Ok(AuthenticatorVendorParameters::AuditLog(
+                AuthenticatorVendorAuditLogParameters {
+                    clear: false,
+                    pin_uv_auth_param: vec![0x12, 0x34],
+                    pin_uv_auth_protocol: 1,
+                }
+            ))
+        );
+        let cbor_value = cbor_map! {
+            1 => VendorSubCommand::DisplayName,
+            2 => cbor_map! {
+                1 => vec![0x1D],
+                2 => "work key",
+            },
+        };
+        assert_eq!(
+            AuthenticatorVendorParameters::try_from(cbor_value),
+            Ok(AuthenticatorVendorParameters::DisplayName(
+                AuthenticatorVendorDisplayNameParameters {
+                    credential_id: vec![0x1D],
+                    display_name: Some(String::from("work key")),
+                    pin_uv_auth_protocol: None,
+                    pin_uv_auth_param: None,
+                }
+            ))
+        );
+        let cbor_value = cbor_map! {
+            1 => VendorSubCommand::Identify,
+            2 => cbor_map! {
+                1 => 10,
+            },
+        };
+        assert_eq!(
+            AuthenticatorVendorParameters::try_from(cbor_value),
+            Ok(AuthenticatorVendorParameters::Identify(
+                AuthenticatorVendorIdentifyParameters { duration_s: 10 }
+            ))
+        );
+
+        // Missing subcommand parameters
+        let cbor_value = cbor_map! {
+            1 => VendorSubCommand::AuditLog,
+        };
+        assert_eq!(
+            AuthenticatorVendorParameters::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
+        );
+
+        // Unknown subcommand
+        let cbor_value = cbor_map! {
+            1 => 0x7F,
+        };
+        assert_eq!(
+            AuthenticatorVendorParameters::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn test_deserialize_vendor() {
+        let mut cbor_bytes = vec![u8::from(CommandCode::Vendor)];
+        cbor_bytes.extend(&[0xA1, 0x01, 0x03]);
+        let command = Command::deserialize(&cbor_bytes);
+        assert_eq!(
+            command,
+            Ok(Command::AuthenticatorVendor(
+                AuthenticatorVendorParameters::Metrics
+            ))
+        );
+    }
+
+    #[test]
+    fn test_deserialize_unknown_command() {
+        // Unassigned codes and codes of unimplemented commands are both rejected.
+        for &code in &[0x03, u8::from(CommandCode::BioEnrollment), 0xBF] {
+            assert_eq!(
+                Command::deserialize(&[code]),
+                Err(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND)
+            );
+        }
+    }
+
+    #[test]
+    fn test_command_version() {
+        let command = Command::deserialize(&[u8::from(CommandCode::GetInfo)]).unwrap();
+        assert_eq!(command.version(), CtapVersion::Fido2_0);
+        let command = Command::deserialize(&[u8::from(CommandCode::VendorMetrics)]).unwrap();
+        assert_eq!(command.version(), CtapVersion::Fido2_0);
+        #[cfg(feature = "with_ctap2_1")]
+        {
+            let command = Command::deserialize(&[u8::from(CommandCode::Selection)]).unwrap();
+            assert_eq!(command.version(), CtapVersion::Fido2_1Pre);
+        }
+    }
+
     #[test]
     fn test_vendor_configure() {
         // Incomplete command
-        let mut cbor_bytes = vec![Command::AUTHENTICATOR_VENDOR_CONFIGURE];
+        let mut cbor_bytes = vec![u8::from(CommandCode::VendorConfigure)];
         let command = Command::deserialize(&cbor_bytes);
         assert_eq!(command, Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR));
 
@@ -654,20 +1587,25 @@ mod test {
             Ok(Command::AuthenticatorVendorConfigure(
                 AuthenticatorVendorConfigureParameters {
                     lockdown: true,
-                    attestation_material: None
+                    attestation_material: None,
+                    usb_identity: None,
+                    challenge: None,
                 }
             ))
         );
 
         let dummy_cert = [0xddu8; 20];
         let dummy_pkey = [0x41u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH];
+        let dummy_pin_auth = [0x5Au8; 16];
 
         // Attestation key is too short.
         let cbor_value = cbor_map! {
             1 => false,
             2 => cbor_map! {
                 1 => dummy_cert,
-                2 => dummy_pkey[..key_material::ATTESTATION_PRIVATE_KEY_LENGTH - 1]
+                2 => dummy_pkey[..key_material::ATTESTATION_PRIVATE_KEY_LENGTH - 1],
+                3 => cbor_map! {},
+                4 => dummy_pin_auth,
             }
         };
         assert_eq!(
@@ -679,7 +1617,9 @@ mod test {
         let cbor_value = cbor_map! {
             1 => false,
             2 => cbor_map! {
-                1 => dummy_cert
+                1 => dummy_cert,
+                3 => cbor_map! {},
+                4 => dummy_pin_auth,
             }
         };
         assert_eq!(
@@ -691,7 +1631,36 @@ mod test {
         let cbor_value = cbor_map! {
             1 => false,
             2 => cbor_map! {
-                2 => dummy_pkey
+                2 => dummy_pkey,
+                3 => cbor_map! {},
+                4 => dummy_pin_auth,
+            }
+        };
+        assert_eq!(
+            AuthenticatorVendorConfigureParameters::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
+        );
+
+        // Plaintext material without key agreement
+        let cbor_value = cbor_map! {
+            1 => false,
+            2 => cbor_map! {
+                1 => dummy_cert,
+                2 => dummy_pkey,
+            }
+        };
+        assert_eq!(
+            AuthenticatorVendorConfigureParameters::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
+        );
+
+        // Missing pinAuth
+        let cbor_value = cbor_map! {
+            1 => false,
+            2 => cbor_map! {
+                1 => dummy_cert,
+                2 => dummy_pkey,
+                3 => cbor_map! {},
             }
         };
         assert_eq!(
@@ -704,7 +1673,9 @@ mod test {
             1 => false,
             2 => cbor_map! {
                 1 => dummy_cert,
-                2 => dummy_pkey
+                2 => dummy_pkey,
+                3 => cbor_map! {},
+                4 => dummy_pin_auth,
             }
         };
         assert_eq!(
@@ -712,9 +1683,142 @@ mod test {
             Ok(AuthenticatorVendorConfigureParameters {
                 lockdown: false,
                 attestation_material: Some(AuthenticatorAttestationMaterial {
-                    certificate: dummy_cert.to_vec(),
-                    private_key: dummy_pkey
-                })
+                    certificate_chain: vec![dummy_cert.to_vec()],
+                    private_key_enc: dummy_pkey,
+                    key_agreement: CoseKey(BTreeMap::new()),
+                    pin_auth: dummy_pin_auth.to_vec(),
+                }),
+                usb_identity: None,
+                challenge: None,
+            })
+        );
+
+        // Valid with intermediate certificates
+        let cbor_value = cbor_map! {
+            2 => cbor_map! {
+                1 => dummy_cert,
+                2 => dummy_pkey,
+                3 => cbor_map! {},
+                4 => dummy_pin_auth,
+                5 => cbor_array![vec![0xC2; 20], vec![0xC3; 20]],
+            }
+        };
+        assert_eq!(
+            AuthenticatorVendorConfigureParameters::try_from(cbor_value),
+            Ok(AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: Some(AuthenticatorAttestationMaterial {
+                    certificate_chain: vec![dummy_cert.to_vec(), vec![0xC2; 20], vec![0xC3; 20]],
+                    private_key_enc: dummy_pkey,
+                    key_agreement: CoseKey(BTreeMap::new()),
+                    pin_auth: dummy_pin_auth.to_vec(),
+                }),
+                usb_identity: None,
+                challenge: None,
+            })
+        );
+
+        // Intermediate certificates must be byte strings
+        let cbor_value = cbor_map! {
+            2 => cbor_map! {
+                1 => dummy_cert,
+                2 => dummy_pkey,
+                3 => cbor_map! {},
+                4 => dummy_pin_auth,
+                5 => cbor_array!["certificate"],
+            }
+        };
+        assert_eq!(
+            AuthenticatorVendorConfigureParameters::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE)
+        );
+
+        // Valid USB identity
+        let cbor_value = cbor_map! {
+            3 => cbor_map! {
+                1 => 0x1915,
+                2 => 0x521F,
+            }
+        };
+        assert_eq!(
+            AuthenticatorVendorConfigureParameters::try_from(cbor_value),
+            Ok(AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: None,
+                usb_identity: Some(UsbIdentity {
+                    vendor_id: 0x1915,
+                    product_id: 0x521F,
+                    serial_number: None,
+                }),
+                challenge: None,
+            })
+        );
+
+        // Challenges have a fixed length.
+        let cbor_value = cbor_map! {
+            4 => [0xC4; 32],
+        };
+        assert_eq!(
+            AuthenticatorVendorConfigureParameters::try_from(cbor_value),
+            Ok(AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: None,
+                usb_identity: None,
+                challenge: Some([0xC4; 32]),
+            })
+        );
+        let cbor_value = cbor_map! {
+            4 => [0xC4; 31],
+        };
+        assert_eq!(
+            AuthenticatorVendorConfigureParameters::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn test_vendor_audit_log() {
+        // Incomplete command
+        let mut cbor_bytes = vec![u8::from(CommandCode::VendorAuditLog)];
+        let command = Command::deserialize(&cbor_bytes);
+        assert_eq!(command, Err(Ctap2StatusCode::CTAP2_ERR_INVALID_CBOR));
+
+        // {2: h'0102', 3: 1}
+        cbor_bytes.extend(&[0xA2, 0x02, 0x42, 0x01, 0x02, 0x03, 0x01]);
+        let command = Command::deserialize(&cbor_bytes);
+        assert_eq!(
+            command,
+            Ok(Command::AuthenticatorVendorAuditLog(
+                AuthenticatorVendorAuditLogParameters {
+                    clear: false,
+                    pin_uv_auth_param: vec![0x01, 0x02],
+                    pin_uv_auth_protocol: 1,
+                }
+            ))
+        );
+
+        // Missing PIN auth
+        let cbor_value = cbor_map! {
+            1 => true,
+            3 => 1,
+        };
+        assert_eq!(
+            AuthenticatorVendorAuditLogParameters::try_from(cbor_value),
+            Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
+        );
+
+        // Valid
+        let cbor_value = cbor_map! {
+            1 => true,
+            2 => vec![0x01, 0x02],
+            3 => 1,
+        };
+        assert_eq!(
+            AuthenticatorVendorAuditLogParameters::try_from(cbor_value),
+            Ok(AuthenticatorVendorAuditLogParameters {
+                clear: true,
+                pin_uv_auth_param: vec![0x01, 0x02],
+                pin_uv_auth_protocol: 1,
             })
         );
     }
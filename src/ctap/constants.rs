@@ -0,0 +1,283 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Constants of the CTAP specification.
+//!
+//! Codes are only defined here, and converted from and to their value on the wire. This avoids
+//! literals drifting apart between parsing and response code.
+
+use core::convert::TryFrom;
+
+/// Defines an enum of byte codes with conversions from and to their value.
+///
+/// Converting an unknown value fails. The `ALL` constant lists the codes in the order of their
+/// definition, for exhaustive tests.
+macro_rules! make_codes {
+    (
+        $(#[$enum_attr: meta])*
+        pub enum $name: ident {
+            $(
+                $(#[$attr: meta])*
+                $variant: ident = $value: literal,
+            )*
+        }
+    ) => {
+        $(#[$enum_attr])*
+        pub enum $name {
+            $(
+                $(#[$attr])*
+                $variant = $value,
+            )*
+        }
+
+        impl $name {
+            #[cfg(test)]
+            #[allow(unused_doc_comments)]
+            pub const ALL: &'static [$name] = &[$( $(#[$attr])* $name::$variant, )*];
+        }
+
+        impl core::convert::TryFrom<u8> for $name {
+            type Error = ();
+
+            #[allow(unused_doc_comments)]
+            fn try_from(value: u8) -> Result<$name, ()> {
+                match value {
+                    $( $(#[$attr])* $value => Ok($name::$variant), )*
+                    _ => Err(()),
+                }
+            }
+        }
+    };
+}
+
+/// Defines the keys of a CBOR map with integer keys.
+macro_rules! make_keys {
+    (
+        $(#[$enum_attr: meta])*
+        pub enum $name: ident {
+            $( $variant: ident = $value: literal, )*
+        }
+    ) => {
+        make_codes! {
+            $(#[$enum_attr])*
+            #[derive(Clone, Copy, PartialEq, Eq)]
+            #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+            pub enum $name {
+                $( $variant = $value, )*
+            }
+        }
+
+        impl From<$name> for cbor::KeyType {
+            fn from(key: $name) -> cbor::KeyType {
+                cbor::KeyType::Unsigned(key as u64)
+            }
+        }
+    };
+}
+
+make_codes! {
+    /// Command codes, see CTAP specification (version 20190130) section 6.1.
+    ///
+    /// Vendor commands are in the range from 0x40 to 0xBF.
+    #[allow(dead_code)]
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+    pub enum CommandCode {
+        MakeCredential = 0x01,
+        GetAssertion = 0x02,
+        GetInfo = 0x04,
+        ClientPin = 0x06,
+        Reset = 0x07,
+        GetNextAssertion = 0x08,
+        BioEnrollment = 0x09,
+        CredentialManagement = 0x0A,
+        Selection = 0x0B,
+        LargeBlobs = 0x0C,
+        Config = 0x0D,
+        VendorConfigure = 0x40,
+        VendorAuditLog = 0x41,
+        VendorUserPresence = 0x42,
+        VendorMetrics = 0x43,
+        VendorSelfTest = 0x44,
+        Vendor = 0x45,
+    }
+}
+
+impl From<CommandCode> for u8 {
+    fn from(code: CommandCode) -> u8 {
+        code as u8
+    }
+}
+
+make_keys! {
+    /// Parameter keys of MakeCredential.
+    pub enum MakeCredentialParameter {
+        ClientDataHash = 0x01,
+        Rp = 0x02,
+        User = 0x03,
+        PubKeyCredParams = 0x04,
+        ExcludeList = 0x05,
+        Extensions = 0x06,
+        Options = 0x07,
+        PinUvAuthParam = 0x08,
+        PinUvAuthProtocol = 0x09,
+        AttestationFormatsPreference = 0x0B,
+    }
+}
+
+make_keys! {
+    /// Parameter keys of GetAssertion.
+    pub enum GetAssertionParameter {
+        RpId = 0x01,
+        ClientDataHash = 0x02,
+        AllowList = 0x03,
+        Extensions = 0x04,
+        Options = 0x05,
+        PinUvAuthParam = 0x06,
+        PinUvAuthProtocol = 0x07,
+    }
+}
+
+make_keys! {
+    /// Parameter keys of ClientPin.
+    ///
+    /// The keys from `MinPinLength` are only parsed with CTAP 2.1.
+    #[allow(dead_code)]
+    pub enum ClientPinParameter {
+        PinProtocol = 0x01,
+        SubCommand = 0x02,
+        KeyAgreement = 0x03,
+        PinAuth = 0x04,
+        NewPinEnc = 0x05,
+        PinHashEnc = 0x06,
+        MinPinLength = 0x07,
+        MinPinLengthRpIds = 0x08,
+        Permissions = 0x09,
+        PermissionsRpId = 0x0A,
+    }
+}
+
+/// Extension identifiers supported by the authenticator.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub enum Extension {
+    CredProtect,
+    HmacSecret,
+    MinPinLength,
+    TxAuthSimple,
+}
+
+impl Extension {
+    #[cfg(test)]
+    pub const ALL: &'static [Extension] = &[
+        Extension::CredProtect,
+        Extension::HmacSecret,
+        Extension::MinPinLength,
+        Extension::TxAuthSimple,
+    ];
+
+    /// Returns the identifier of the extension, as used in maps and GetInfo.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Extension::CredProtect => "credProtect",
+            Extension::HmacSecret => "hmac-secret",
+            Extension::MinPinLength => "minPinLength",
+            Extension::TxAuthSimple => "txAuthSimple",
+        }
+    }
+}
+
+impl TryFrom<&str> for Extension {
+    type Error = ();
+
+    fn try_from(identifier: &str) -> Result<Extension, ()> {
+        match identifier {
+            "credProtect" => Ok(Extension::CredProtect),
+            "hmac-secret" => Ok(Extension::HmacSecret),
+            "minPinLength" => Ok(Extension::MinPinLength),
+            "txAuthSimple" => Ok(Extension::TxAuthSimple),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<Extension> for cbor::KeyType {
+    fn from(extension: Extension) -> cbor::KeyType {
+        cbor::KeyType::TextString(extension.as_str().into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::status_code::Ctap2StatusCode;
+    use super::*;
+
+    /// Checks that exactly the defined codes parse, and that they emit the parsed value.
+    macro_rules! assert_codes {
+        ($name: ident) => {
+            let mut count = 0;
+            for value in 0..=0xFF {
+                if let Ok(code) = $name::try_from(value) {
+                    assert_eq!(code as u8, value);
+                    count += 1;
+                }
+            }
+            assert_eq!(count, $name::ALL.len());
+        };
+    }
+
+    #[test]
+    fn test_command_codes() {
+        assert_codes!(CommandCode);
+        assert_eq!(CommandCode::try_from(0x03), Err(()));
+        assert_eq!(u8::from(CommandCode::GetInfo), 0x04);
+    }
+
+    #[test]
+    fn test_parameter_keys() {
+        assert_codes!(MakeCredentialParameter);
+        assert_codes!(GetAssertionParameter);
+        assert_codes!(ClientPinParameter);
+        assert_eq!(
+            cbor::KeyType::from(MakeCredentialParameter::Options),
+            cbor::KeyType::Unsigned(7)
+        );
+    }
+
+    #[test]
+    fn test_status_codes() {
+        assert_codes!(Ctap2StatusCode);
+        assert_eq!(
+            Ctap2StatusCode::try_from(0x30),
+            Ok(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+        assert_eq!(Ctap2StatusCode::try_from(0x07), Err(()));
+    }
+
+    #[test]
+    fn test_extensions() {
+        for &extension in Extension::ALL {
+            assert_eq!(Extension::try_from(extension.as_str()), Ok(extension));
+            assert_eq!(
+                cbor::KeyType::from(extension),
+                cbor::KeyType::TextString(extension.as_str().into())
+            );
+        }
+        // The extensions are listed in canonical CBOR order.
+        for pair in Extension::ALL.windows(2) {
+            assert!(cbor::KeyType::from(pair[0]) < cbor::KeyType::from(pair[1]));
+        }
+        assert_eq!(Extension::try_from("credBlob"), Err(()));
+    }
+}
@@ -15,11 +15,12 @@
 use super::apdu::{ApduStatusCode, APDU};
 use super::hid::ChannelID;
 use super::status_code::Ctap2StatusCode;
-use super::CtapState;
+use super::{Crypto, CtapState};
 use alloc::vec::Vec;
 use arrayref::array_ref;
 use core::convert::Into;
 use core::convert::TryFrom;
+use crypto::provider::CryptoProvider;
 use crypto::rng256::Rng256;
 use libtock_drivers::timer::ClockValue;
 
@@ -191,7 +192,19 @@ impl Ctap1Command {
         CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
     {
         let command = U2fCommand::try_from(message)?;
-        match command {
+        // Like CTAP2 commands, U2F commands fail closed if the random number generator is broken,
+        // before and after processing.
+        if !ctap_state.rng.is_healthy() {
+            return Err(Ctap1StatusCode::SW_INTERNAL_EXCEPTION);
+        }
+        // U2F has no user verification, so it is disabled with the alwaysUv option.
+        #[cfg(feature = "with_ctap2_1")]
+        {
+            if ctap_state.persistent_store.always_uv() != Ok(false) {
+                return Err(Ctap1StatusCode::SW_INS_INVALID);
+            }
+        }
+        let response = match command {
             U2fCommand::Register {
                 challenge,
                 application,
@@ -228,7 +241,11 @@ impl Ctap1Command {
 
             // TODO: should we return an error instead such as SW_INS_NOT_SUPPORTED?
             U2fCommand::VendorSpecific { .. } => Err(Ctap1StatusCode::SW_SUCCESS),
+        };
+        if !ctap_state.rng.is_healthy() {
+            return Err(Ctap1StatusCode::SW_INTERNAL_EXCEPTION);
         }
+        response
     }
 
     // U2F raw message format specification (version 20170411) section 4.3
@@ -289,7 +306,7 @@ impl Ctap1Command {
         signature_data.extend_from_slice(&user_pk);
 
         let attestation_key = crypto::ecdsa::SecKey::from_bytes(&private_key).unwrap();
-        let signature = attestation_key.sign_rfc6979::<crypto::sha256::Sha256>(&signature_data);
+        let signature = Crypto::ecdsa_sign(&attestation_key, &signature_data);
 
         response.extend(signature.to_asn1_der());
         Ok(response)
@@ -334,9 +351,7 @@ impl Ctap1Command {
                 .generate_auth_data(&application, Ctap1Command::USER_PRESENCE_INDICATOR_BYTE)
                 .map_err(|_| Ctap1StatusCode::SW_WRONG_DATA)?;
             signature_data.extend(&challenge);
-            let signature = credential_source
-                .private_key
-                .sign_rfc6979::<crypto::sha256::Sha256>(&signature_data);
+            let signature = Crypto::ecdsa_sign(&credential_source.private_key, &signature_data);
 
             let mut response = signature_data[application.len()..application.len() + 5].to_vec();
             response.extend(signature.to_asn1_der());
@@ -349,7 +364,9 @@ impl Ctap1Command {
 
 #[cfg(test)]
 mod test {
-    use super::super::{key_material, CREDENTIAL_ID_SIZE, USE_SIGNATURE_COUNTER};
+    use super::super::{
+        key_material, CREDENTIAL_ID_SIZE, PADDED_CREDENTIAL_ID_SIZE, USE_SIGNATURE_COUNTER,
+    };
     use super::*;
     use crypto::rng256::ThreadRng256;
     use crypto::Hash256;
@@ -388,17 +405,33 @@ mod test {
             flags.into(),
             0x00,
             0x00,
-            0x00,
-            65 + CREDENTIAL_ID_SIZE as u8,
+            ((65 + key_handle.len()) >> 8) as u8,
+            (65 + key_handle.len()) as u8,
         ];
         let challenge = [0x0C; 32];
         message.extend(&challenge);
         message.extend(application);
-        message.push(CREDENTIAL_ID_SIZE as u8);
+        message.push(key_handle.len() as u8);
         message.extend(key_handle);
         message
     }
 
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_process_register_always_uv() {
+        let mut rng = ThreadRng256 {};
+        let dummy_user_presence = |_| panic!("Unexpected user presence check in CTAP1");
+        let mut ctap_state = CtapState::new(&mut rng, dummy_user_presence, START_CLOCK_VALUE);
+        ctap_state.persistent_store.toggle_always_uv().unwrap();
+
+        let application = [0x0A; 32];
+        let message = create_register_message(&application);
+        ctap_state.u2f_up_state.consume_up(START_CLOCK_VALUE);
+        ctap_state.u2f_up_state.grant_up(START_CLOCK_VALUE);
+        let response = Ctap1Command::process_command(&message, &mut ctap_state, START_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap1StatusCode::SW_INS_INVALID));
+    }
+
     #[test]
     fn test_process_register() {
         let mut rng = ThreadRng256 {};
@@ -427,19 +460,22 @@ mod test {
         let fake_cert = [0x99u8; 100]; // Arbitrary length
         assert!(ctap_state
             .persistent_store
-            .set_attestation_certificate(&fake_cert[..])
+            .set_attestation_certificate_chain(&[fake_cert.to_vec()])
             .is_ok());
         ctap_state.u2f_up_state.consume_up(START_CLOCK_VALUE);
         ctap_state.u2f_up_state.grant_up(START_CLOCK_VALUE);
         let response =
             Ctap1Command::process_command(&message, &mut ctap_state, START_CLOCK_VALUE).unwrap();
         assert_eq!(response[0], Ctap1Command::LEGACY_BYTE);
-        assert_eq!(response[66], CREDENTIAL_ID_SIZE as u8);
+        assert_eq!(response[66], PADDED_CREDENTIAL_ID_SIZE as u8);
         assert!(ctap_state
-            .decrypt_credential_source(response[67..67 + CREDENTIAL_ID_SIZE].to_vec(), &application)
+            .decrypt_credential_source(
+                response[67..67 + PADDED_CREDENTIAL_ID_SIZE].to_vec(),
+                &application
+            )
             .unwrap()
             .is_some());
-        const CERT_START: usize = 67 + CREDENTIAL_ID_SIZE;
+        const CERT_START: usize = 67 + PADDED_CREDENTIAL_ID_SIZE;
         assert_eq!(
             &response[CERT_START..CERT_START + fake_cert.len()],
             &fake_cert[..]
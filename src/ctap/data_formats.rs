@@ -12,12 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use super::constants::Extension;
 use super::status_code::Ctap2StatusCode;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
 use arrayref::array_ref;
-use cbor::{cbor_array_vec, cbor_bytes_lit, cbor_map_options, destructure_cbor_map};
+use cbor::{
+    cbor_array_vec, cbor_bool, cbor_bytes, cbor_bytes_lit, cbor_map, cbor_map_options,
+    destructure_cbor_map,
+};
 use core::convert::TryFrom;
 use crypto::{ecdh, ecdsa};
 #[cfg(test)]
@@ -128,9 +132,26 @@ impl TryFrom<cbor::Value> for PublicKeyCredentialType {
 
     fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
         let cred_type_string = extract_text_string(cbor_value)?;
-        match &cred_type_string[..] {
-            "public-key" => Ok(PublicKeyCredentialType::PublicKey),
-            _ => Ok(PublicKeyCredentialType::Unknown),
+        Ok(PublicKeyCredentialType::from_name(&cred_type_string))
+    }
+}
+
+#[cfg(feature = "heap_free_parsing")]
+impl<'a> TryFrom<cbor::ValueRef<'a>> for PublicKeyCredentialType {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::ValueRef<'a>) -> Result<Self, Ctap2StatusCode> {
+        Ok(PublicKeyCredentialType::from_name(extract_text_string_ref(
+            cbor_value,
+        )?))
+    }
+}
+
+impl PublicKeyCredentialType {
+    fn from_name(name: &str) -> PublicKeyCredentialType {
+        match name {
+            "public-key" => PublicKeyCredentialType::PublicKey,
+            _ => PublicKeyCredentialType::Unknown,
         }
     }
 }
@@ -170,7 +191,8 @@ impl From<PublicKeyCredentialParameter> for cbor::Value {
 }
 
 // https://www.w3.org/TR/webauthn/#enumdef-authenticatortransport
-#[cfg_attr(any(test, feature = "debug_ctap"), derive(Clone, Debug, PartialEq))]
+#[derive(Clone)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
 #[cfg_attr(test, derive(IntoEnumIterator))]
 pub enum AuthenticatorTransport {
     Usb,
@@ -196,7 +218,22 @@ impl TryFrom<cbor::Value> for AuthenticatorTransport {
 
     fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
         let transport_string = extract_text_string(cbor_value)?;
-        match &transport_string[..] {
+        AuthenticatorTransport::from_name(&transport_string)
+    }
+}
+
+#[cfg(feature = "heap_free_parsing")]
+impl<'a> TryFrom<cbor::ValueRef<'a>> for AuthenticatorTransport {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::ValueRef<'a>) -> Result<Self, Ctap2StatusCode> {
+        AuthenticatorTransport::from_name(extract_text_string_ref(cbor_value)?)
+    }
+}
+
+impl AuthenticatorTransport {
+    fn from_name(name: &str) -> Result<AuthenticatorTransport, Ctap2StatusCode> {
+        match name {
             "usb" => Ok(AuthenticatorTransport::Usb),
             "nfc" => Ok(AuthenticatorTransport::Nfc),
             "ble" => Ok(AuthenticatorTransport::Ble),
@@ -248,6 +285,54 @@ impl TryFrom<cbor::Value> for PublicKeyCredentialDescriptor {
     }
 }
 
+// Borrowed version of PublicKeyCredentialDescriptor, for the heap-free parsing path.
+#[cfg(feature = "heap_free_parsing")]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct PublicKeyCredentialDescriptorRef<'a> {
+    pub key_type: PublicKeyCredentialType,
+    pub key_id: &'a [u8],
+    // The transports are validated, but only decoded on demand.
+    pub transports: Option<cbor::borrowed::ArrayRef<'a>>,
+}
+
+#[cfg(feature = "heap_free_parsing")]
+impl<'a> TryFrom<cbor::ValueRef<'a>> for PublicKeyCredentialDescriptorRef<'a> {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::ValueRef<'a>) -> Result<Self, Ctap2StatusCode> {
+        let mut key_id = None;
+        let mut key_type = None;
+        let mut transports = None;
+        for (key, value) in extract_map_ref(cbor_value)?.iter() {
+            match key {
+                cbor::ValueRef::TextString("id") => key_id = Some(value),
+                cbor::ValueRef::TextString("type") => key_type = Some(value),
+                cbor::ValueRef::TextString("transports") => transports = Some(value),
+                _ => (),
+            }
+        }
+
+        let key_type = PublicKeyCredentialType::try_from(ok_or_missing(key_type)?)?;
+        let key_id = extract_byte_string_ref(ok_or_missing(key_id)?)?;
+        let transports = match transports {
+            Some(exclude_entry) => {
+                let transports = extract_array_ref(exclude_entry)?;
+                for transport in transports.iter() {
+                    AuthenticatorTransport::try_from(transport)?;
+                }
+                Some(transports)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            key_type,
+            key_id,
+            transports,
+        })
+    }
+}
+
 impl From<PublicKeyCredentialDescriptor> for cbor::Value {
     fn from(desc: PublicKeyCredentialDescriptor) -> Self {
         cbor_map_options! {
@@ -262,16 +347,27 @@ impl From<PublicKeyCredentialDescriptor> for cbor::Value {
 pub struct MakeCredentialExtensions {
     pub hmac_secret: bool,
     pub cred_protect: Option<CredentialProtectionPolicy>,
+    #[cfg(feature = "with_ctap2_1")]
+    pub min_pin_length: bool,
 }
 
 impl TryFrom<cbor::Value> for MakeCredentialExtensions {
     type Error = Ctap2StatusCode;
 
     fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        #[cfg(not(feature = "with_ctap2_1"))]
         destructure_cbor_map! {
             let {
-                "credProtect" => cred_protect,
-                "hmac-secret" => hmac_secret,
+                Extension::CredProtect => cred_protect,
+                Extension::HmacSecret => hmac_secret,
+            } = extract_map(cbor_value)?;
+        }
+        #[cfg(feature = "with_ctap2_1")]
+        destructure_cbor_map! {
+            let {
+                Extension::CredProtect => cred_protect,
+                Extension::HmacSecret => hmac_secret,
+                Extension::MinPinLength => min_pin_length,
             } = extract_map(cbor_value)?;
         }
 
@@ -279,9 +375,13 @@ impl TryFrom<cbor::Value> for MakeCredentialExtensions {
         let cred_protect = cred_protect
             .map(CredentialProtectionPolicy::try_from)
             .transpose()?;
+        #[cfg(feature = "with_ctap2_1")]
+        let min_pin_length = min_pin_length.map_or(Ok(false), extract_bool)?;
         Ok(Self {
             hmac_secret,
             cred_protect,
+            #[cfg(feature = "with_ctap2_1")]
+            min_pin_length,
         })
     }
 }
@@ -289,6 +389,7 @@ impl TryFrom<cbor::Value> for MakeCredentialExtensions {
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Clone, Debug, PartialEq))]
 pub struct GetAssertionExtensions {
     pub hmac_secret: Option<GetAssertionHmacSecretInput>,
+    pub tx_auth_simple: Option<String>,
 }
 
 impl TryFrom<cbor::Value> for GetAssertionExtensions {
@@ -297,14 +398,19 @@ impl TryFrom<cbor::Value> for GetAssertionExtensions {
     fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
         destructure_cbor_map! {
             let {
-                "hmac-secret" => hmac_secret,
+                Extension::HmacSecret => hmac_secret,
+                Extension::TxAuthSimple => tx_auth_simple,
             } = extract_map(cbor_value)?;
         }
 
         let hmac_secret = hmac_secret
             .map(GetAssertionHmacSecretInput::try_from)
             .transpose()?;
-        Ok(Self { hmac_secret })
+        let tx_auth_simple = tx_auth_simple.map(extract_text_string).transpose()?;
+        Ok(Self {
+            hmac_secret,
+            tx_auth_simple,
+        })
     }
 }
 
@@ -339,6 +445,148 @@ impl TryFrom<cbor::Value> for GetAssertionHmacSecretInput {
     }
 }
 
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+enum HmacSecretOutput {
+    // MakeCredential only signals that the extension was processed.
+    Created,
+    // GetAssertion returns the encrypted secrets.
+    Encrypted(Vec<u8>),
+}
+
+// Collects the extension outputs of the authenticator data.
+//
+// The extensions are assembled in a single CBOR map, which is in canonical order by construction.
+// Its encoded length is known without encoding it.
+#[derive(Default)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct ExtensionOutputs {
+    hmac_secret: Option<HmacSecretOutput>,
+    cred_protect: Option<CredentialProtectionPolicy>,
+    min_pin_length: Option<u8>,
+    tx_auth_simple: Option<String>,
+}
+
+impl ExtensionOutputs {
+    pub fn new() -> ExtensionOutputs {
+        ExtensionOutputs::default()
+    }
+
+    // Adds the hmac-secret output of MakeCredential, if the extension was requested.
+    pub fn hmac_secret_created(mut self, created: bool) -> ExtensionOutputs {
+        if created {
+            self.hmac_secret = Some(HmacSecretOutput::Created);
+        }
+        self
+    }
+
+    // Adds the hmac-secret output of GetAssertion.
+    pub fn hmac_secret_encrypted(mut self, encrypted_output: Vec<u8>) -> ExtensionOutputs {
+        self.hmac_secret = Some(HmacSecretOutput::Encrypted(encrypted_output));
+        self
+    }
+
+    // Adds the credProtect output, if a policy is set.
+    pub fn cred_protect(mut self, policy: Option<CredentialProtectionPolicy>) -> ExtensionOutputs {
+        self.cred_protect = policy;
+        self
+    }
+
+    // Adds the minPinLength output, if the RP is allowed to read the minimum PIN length.
+    #[cfg(feature = "with_ctap2_1")]
+    pub fn min_pin_length(mut self, min_pin_length: Option<u8>) -> ExtensionOutputs {
+        self.min_pin_length = min_pin_length;
+        self
+    }
+
+    // Adds the txAuthSimple output, which is the transaction text as it was displayed.
+    pub fn tx_auth_simple(mut self, displayed_text: Option<String>) -> ExtensionOutputs {
+        self.tx_auth_simple = displayed_text;
+        self
+    }
+
+    // Whether there is no extension output. The ED flag should be set otherwise.
+    pub fn is_empty(&self) -> bool {
+        self.hmac_secret.is_none()
+            && self.cred_protect.is_none()
+            && self.min_pin_length.is_none()
+            && self.tx_auth_simple.is_none()
+    }
+
+    // Returns the length of the encoded extensions map, which is 0 if there is no output.
+    pub fn encoded_len(&self) -> usize {
+        if self.is_empty() {
+            return 0;
+        }
+        let mut count = 0;
+        let mut length = 0;
+        if let Some(hmac_secret) = &self.hmac_secret {
+            count += 1;
+            length += cbor_item_len(Extension::HmacSecret.as_str().len());
+            length += match hmac_secret {
+                HmacSecretOutput::Created => 1,
+                HmacSecretOutput::Encrypted(output) => cbor_item_len(output.len()),
+            };
+        }
+        if self.cred_protect.is_some() {
+            count += 1;
+            // The policy is a small integer.
+            length += cbor_item_len(Extension::CredProtect.as_str().len()) + 1;
+        }
+        if let Some(min_pin_length) = self.min_pin_length {
+            count += 1;
+            length += cbor_item_len(Extension::MinPinLength.as_str().len());
+            length += cbor_header_len(min_pin_length as usize);
+        }
+        if let Some(displayed_text) = &self.tx_auth_simple {
+            count += 1;
+            length += cbor_item_len(Extension::TxAuthSimple.as_str().len());
+            length += cbor_item_len(displayed_text.len());
+        }
+        cbor_header_len(count) + length
+    }
+
+    // Appends the encoded extensions map to the authenticator data, if there is any output.
+    pub fn write(self, auth_data: &mut Vec<u8>) -> Result<(), Ctap2StatusCode> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        if !cbor::write(self.into(), auth_data) {
+            return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR);
+        }
+        Ok(())
+    }
+}
+
+impl From<ExtensionOutputs> for cbor::Value {
+    fn from(extension_outputs: ExtensionOutputs) -> Self {
+        let hmac_secret = extension_outputs.hmac_secret.map(|output| match output {
+            HmacSecretOutput::Created => cbor_bool!(true),
+            HmacSecretOutput::Encrypted(output) => cbor_bytes!(output),
+        });
+        cbor_map_options! {
+            Extension::HmacSecret => hmac_secret,
+            Extension::CredProtect => extension_outputs.cred_protect,
+            Extension::MinPinLength => extension_outputs.min_pin_length.map(|length| length as u64),
+            Extension::TxAuthSimple => extension_outputs.tx_auth_simple,
+        }
+    }
+}
+
+// Returns the length of the CBOR header of an item of the given size.
+fn cbor_header_len(size: usize) -> usize {
+    match size {
+        0..=23 => 1,
+        24..=0xFF => 2,
+        0x100..=0xFFFF => 3,
+        _ => 5,
+    }
+}
+
+// Returns the length of a CBOR byte or text string of the given length.
+fn cbor_item_len(length: usize) -> usize {
+    cbor_header_len(length) + length
+}
+
 // Even though options are optional, we can use the default if not present.
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
 pub struct MakeCredentialOptions {
@@ -362,8 +610,12 @@ impl TryFrom<cbor::Value> for MakeCredentialOptions {
             Some(options_entry) => extract_bool(options_entry)?,
             None => false,
         };
-        if up.is_some() {
-            return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION);
+        if let Some(options_entry) = up {
+            // User presence is always required for makeCredential. CTAP 2.1 still accepts the
+            // option if it matches, CTAP 2.0 rejects it altogether.
+            if !extract_bool(options_entry)? || cfg!(not(feature = "with_ctap2_1")) {
+                return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION);
+            }
         }
         let uv = match uv {
             Some(options_entry) => extract_bool(options_entry)?,
@@ -408,6 +660,102 @@ impl TryFrom<cbor::Value> for GetAssertionOptions {
     }
 }
 
+#[cfg(feature = "heap_free_parsing")]
+impl<'a> TryFrom<cbor::ValueRef<'a>> for GetAssertionOptions {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::ValueRef<'a>) -> Result<Self, Ctap2StatusCode> {
+        let mut rk = None;
+        let mut up = None;
+        let mut uv = None;
+        for (key, value) in extract_map_ref(cbor_value)?.iter() {
+            match key {
+                cbor::ValueRef::TextString("rk") => rk = Some(value),
+                cbor::ValueRef::TextString("up") => up = Some(value),
+                cbor::ValueRef::TextString("uv") => uv = Some(value),
+                _ => (),
+            }
+        }
+
+        if let Some(options_entry) = rk {
+            // This is only for returning the correct status code.
+            extract_bool_ref(options_entry)?;
+            return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION);
+        }
+        let up = match up {
+            Some(options_entry) => extract_bool_ref(options_entry)?,
+            None => true,
+        };
+        let uv = match uv {
+            Some(options_entry) => extract_bool_ref(options_entry)?,
+            None => false,
+        };
+        Ok(Self { up, uv })
+    }
+}
+
+// https://www.w3.org/TR/webauthn/#sctn-defined-attestation-formats
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub enum AttestationFormat {
+    None,
+    Packed,
+    FidoU2f,
+}
+
+impl AttestationFormat {
+    /// Returns the identifier of the format, as used in the fmt field.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AttestationFormat::None => "none",
+            AttestationFormat::Packed => "packed",
+            AttestationFormat::FidoU2f => "fido-u2f",
+        }
+    }
+}
+
+impl TryFrom<&str> for AttestationFormat {
+    type Error = ();
+
+    fn try_from(identifier: &str) -> Result<AttestationFormat, ()> {
+        match identifier {
+            "none" => Ok(AttestationFormat::None),
+            "packed" => Ok(AttestationFormat::Packed),
+            "fido-u2f" => Ok(AttestationFormat::FidoU2f),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg_attr(test, derive(PartialEq))]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub enum AttestationStatement {
+    None,
+    Packed(PackedAttestationStatement),
+    FidoU2f(FidoU2fAttestationStatement),
+}
+
+impl AttestationStatement {
+    pub fn format(&self) -> AttestationFormat {
+        match self {
+            AttestationStatement::None => AttestationFormat::None,
+            AttestationStatement::Packed(_) => AttestationFormat::Packed,
+            AttestationStatement::FidoU2f(_) => AttestationFormat::FidoU2f,
+        }
+    }
+}
+
+impl From<AttestationStatement> for cbor::Value {
+    fn from(att_stmt: AttestationStatement) -> Self {
+        match att_stmt {
+            // https://www.w3.org/TR/webauthn/#sctn-none-attestation
+            AttestationStatement::None => cbor_map! {},
+            AttestationStatement::Packed(att_stmt) => att_stmt.into(),
+            AttestationStatement::FidoU2f(att_stmt) => att_stmt.into(),
+        }
+    }
+}
+
 // https://www.w3.org/TR/webauthn/#packed-attestation
 #[cfg_attr(test, derive(PartialEq))]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
@@ -429,6 +777,24 @@ impl From<PackedAttestationStatement> for cbor::Value {
     }
 }
 
+// https://www.w3.org/TR/webauthn/#sctn-fido-u2f-attestation
+#[cfg_attr(test, derive(PartialEq))]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub struct FidoU2fAttestationStatement {
+    pub sig: Vec<u8>,
+    // Contains exactly one certificate.
+    pub x5c: Vec<Vec<u8>>,
+}
+
+impl From<FidoU2fAttestationStatement> for cbor::Value {
+    fn from(att_stmt: FidoU2fAttestationStatement) -> Self {
+        cbor_map! {
+            "sig" => att_stmt.sig,
+            "x5c" => cbor_array_vec!(att_stmt.x5c),
+        }
+    }
+}
+
 #[derive(PartialEq)]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
 pub enum SignatureAlgorithm {
@@ -502,6 +868,11 @@ pub struct PublicKeyCredentialSource {
     pub creation_order: u64,
     pub user_name: Option<String>,
     pub user_icon: Option<String>,
+    // A label chosen by the user on the authenticator, to tell credentials apart.
+    pub authenticator_display_name: Option<String>,
+    // The transports of the authenticator when the credential was created. They are returned as a
+    // hint in credential descriptors, so that platforms know how to reach the credential.
+    pub transports: Option<Vec<AuthenticatorTransport>>,
 }
 
 // We serialize credentials for the persistent storage using CBOR maps. Each field of a credential
@@ -516,6 +887,8 @@ enum PublicKeyCredentialSourceField {
     CreationOrder = 7,
     UserName = 8,
     UserIcon = 9,
+    AuthenticatorDisplayName = 10,
+    Transports = 11,
     // When a field is removed, its tag should be reserved and not used for new fields. We document
     // those reserved tags below.
     // Reserved tags:
@@ -542,6 +915,8 @@ impl From<PublicKeyCredentialSource> for cbor::Value {
             PublicKeyCredentialSourceField::CreationOrder => credential.creation_order,
             PublicKeyCredentialSourceField::UserName => credential.user_name,
             PublicKeyCredentialSourceField::UserIcon => credential.user_icon,
+            PublicKeyCredentialSourceField::AuthenticatorDisplayName => credential.authenticator_display_name,
+            PublicKeyCredentialSourceField::Transports => credential.transports.map(|vec| cbor_array_vec!(vec)),
         }
     }
 }
@@ -561,6 +936,8 @@ impl TryFrom<cbor::Value> for PublicKeyCredentialSource {
                 PublicKeyCredentialSourceField::CreationOrder => creation_order,
                 PublicKeyCredentialSourceField::UserName => user_name,
                 PublicKeyCredentialSourceField::UserIcon => user_icon,
+                PublicKeyCredentialSourceField::AuthenticatorDisplayName => authenticator_display_name,
+                PublicKeyCredentialSourceField::Transports => transports,
             } = extract_map(cbor_value)?;
         }
 
@@ -580,6 +957,17 @@ impl TryFrom<cbor::Value> for PublicKeyCredentialSource {
         let creation_order = creation_order.map(extract_unsigned).unwrap_or(Ok(0))?;
         let user_name = user_name.map(extract_text_string).transpose()?;
         let user_icon = user_icon.map(extract_text_string).transpose()?;
+        let authenticator_display_name = authenticator_display_name
+            .map(extract_text_string)
+            .transpose()?;
+        let transports = transports
+            .map(|transports| {
+                extract_array(transports)?
+                    .into_iter()
+                    .map(AuthenticatorTransport::try_from)
+                    .collect::<Result<Vec<AuthenticatorTransport>, Ctap2StatusCode>>()
+            })
+            .transpose()?;
         // We don't return whether there were unknown fields in the CBOR value. This means that
         // deserialization is not injective. In particular deserialization is only an inverse of
         // serialization at a given version of OpenSK. This is not a problem because:
@@ -601,6 +989,8 @@ impl TryFrom<cbor::Value> for PublicKeyCredentialSource {
             creation_order,
             user_name,
             user_icon,
+            authenticator_display_name,
+            transports,
         })
     }
 }
@@ -614,6 +1004,148 @@ impl PublicKeyCredentialSource {
     }
 }
 
+// Coarse events recorded in the audit log. Like credentials, audit events are an OpenSK-specific
+// data-structure, so we choose how to serialize them.
+#[derive(Clone, Copy, PartialEq)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+#[cfg_attr(test, derive(IntoEnumIterator))]
+pub enum AuditEventType {
+    MakeCredential = 0x01,
+    GetAssertion = 0x02,
+    PinFailure = 0x03,
+    Reset = 0x04,
+    AuditLogCleared = 0x05,
+}
+
+impl From<AuditEventType> for cbor::Value {
+    fn from(event_type: AuditEventType) -> Self {
+        (event_type as i64).into()
+    }
+}
+
+impl TryFrom<cbor::Value> for AuditEventType {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        match extract_integer(cbor_value)? {
+            0x01 => Ok(AuditEventType::MakeCredential),
+            0x02 => Ok(AuditEventType::GetAssertion),
+            0x03 => Ok(AuditEventType::PinFailure),
+            0x04 => Ok(AuditEventType::Reset),
+            0x05 => Ok(AuditEventType::AuditLogCleared),
+            _ => Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub struct AuditEvent {
+    // Increases with each recorded event, across power cycles and resets.
+    pub sequence: u64,
+    pub event_type: AuditEventType,
+    // Milliseconds since boot, as given by the clock driver.
+    pub timestamp_ms: u64,
+}
+
+impl From<AuditEvent> for cbor::Value {
+    fn from(event: AuditEvent) -> Self {
+        cbor_map! {
+            1 => event.sequence,
+            2 => event.event_type,
+            3 => event.timestamp_ms,
+        }
+    }
+}
+
+impl TryFrom<cbor::Value> for AuditEvent {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        destructure_cbor_map! {
+            let {
+                1 => sequence,
+                2 => event_type,
+                3 => timestamp_ms,
+            } = extract_map(cbor_value)?;
+        }
+
+        let sequence = extract_unsigned(ok_or_missing(sequence)?)?;
+        let event_type = AuditEventType::try_from(ok_or_missing(event_type)?)?;
+        let timestamp_ms = extract_unsigned(ok_or_missing(timestamp_ms)?)?;
+        Ok(AuditEvent {
+            sequence,
+            event_type,
+            timestamp_ms,
+        })
+    }
+}
+
+// The USB serial number is limited to keep its string descriptor in a single control packet.
+pub const MAX_USB_SERIAL_NUMBER_LENGTH: usize = 32;
+
+/// USB identity overriding the one of the firmware image.
+#[derive(Clone, PartialEq)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub struct UsbIdentity {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    // Replaces the serial number string of the firmware image if present.
+    pub serial_number: Option<String>,
+}
+
+impl From<UsbIdentity> for cbor::Value {
+    fn from(identity: UsbIdentity) -> Self {
+        cbor_map_options! {
+            1 => identity.vendor_id as u64,
+            2 => identity.product_id as u64,
+            3 => identity.serial_number,
+        }
+    }
+}
+
+impl TryFrom<cbor::Value> for UsbIdentity {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        destructure_cbor_map! {
+            let {
+                1 => vendor_id,
+                2 => product_id,
+                3 => serial_number,
+            } = extract_map(cbor_value)?;
+        }
+
+        let vendor_id = extract_usb_id(ok_or_missing(vendor_id)?)?;
+        let product_id = extract_usb_id(ok_or_missing(product_id)?)?;
+        let serial_number = serial_number.map(extract_text_string).transpose()?;
+        if let Some(serial_number) = &serial_number {
+            // The serial number is sent as is in a string descriptor, so we only accept printable
+            // ASCII characters.
+            if serial_number.is_empty()
+                || serial_number.len() > MAX_USB_SERIAL_NUMBER_LENGTH
+                || !serial_number.bytes().all(|c| (0x20..0x7F).contains(&c))
+            {
+                return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+            }
+        }
+        Ok(UsbIdentity {
+            vendor_id,
+            product_id,
+            serial_number,
+        })
+    }
+}
+
+// Vendor and product IDs are 16 bits, and 0 is not a valid ID.
+fn extract_usb_id(cbor_value: cbor::Value) -> Result<u16, Ctap2StatusCode> {
+    match extract_unsigned(cbor_value)? {
+        0 => Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER),
+        id if id <= core::u16::MAX as u64 => Ok(id as u16),
+        _ => Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER),
+    }
+}
+
 // TODO(kaczmarczyck) we could decide to split this data type up
 // It depends on the algorithm though, I think.
 // So before creating a mess, this is my workaround.
@@ -747,6 +1279,79 @@ impl TryFrom<cbor::Value> for ClientPinSubCommand {
     }
 }
 
+#[cfg(feature = "with_ctap2_1")]
+#[derive(Clone, Copy)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+#[cfg_attr(test, derive(IntoEnumIterator))]
+pub enum ConfigSubCommand {
+    ToggleAlwaysUv = 0x02,
+}
+
+#[cfg(feature = "with_ctap2_1")]
+impl From<ConfigSubCommand> for cbor::Value {
+    fn from(subcommand: ConfigSubCommand) -> Self {
+        (subcommand as u64).into()
+    }
+}
+
+#[cfg(feature = "with_ctap2_1")]
+impl TryFrom<cbor::Value> for ConfigSubCommand {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let subcommand_int = extract_unsigned(cbor_value)?;
+        match subcommand_int {
+            // Enterprise attestation (0x01) and setting the minimum PIN length (0x03) are not
+            // supported through this command.
+            0x02 => Ok(ConfigSubCommand::ToggleAlwaysUv),
+            _ => Err(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND),
+        }
+    }
+}
+
+/// Subcommands of the vendor command.
+///
+/// Each subcommand has the same parameters and response as its legacy vendor command code, if it
+/// has one.
+#[derive(Clone, Copy)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+#[cfg_attr(test, derive(IntoEnumIterator))]
+pub enum VendorSubCommand {
+    Configure = 0x01,
+    AuditLog = 0x02,
+    Metrics = 0x03,
+    SelfTest = 0x04,
+    DisplayName = 0x05,
+    #[cfg(feature = "perf")]
+    CommandTimings = 0x06,
+    Identify = 0x07,
+}
+
+impl From<VendorSubCommand> for cbor::Value {
+    fn from(subcommand: VendorSubCommand) -> Self {
+        (subcommand as u64).into()
+    }
+}
+
+impl TryFrom<cbor::Value> for VendorSubCommand {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(cbor_value: cbor::Value) -> Result<Self, Ctap2StatusCode> {
+        let subcommand_int = extract_unsigned(cbor_value)?;
+        match subcommand_int {
+            0x01 => Ok(VendorSubCommand::Configure),
+            0x02 => Ok(VendorSubCommand::AuditLog),
+            0x03 => Ok(VendorSubCommand::Metrics),
+            0x04 => Ok(VendorSubCommand::SelfTest),
+            0x05 => Ok(VendorSubCommand::DisplayName),
+            #[cfg(feature = "perf")]
+            0x06 => Ok(VendorSubCommand::CommandTimings),
+            0x07 => Ok(VendorSubCommand::Identify),
+            _ => Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER),
+        }
+    }
+}
+
 pub(super) fn extract_unsigned(cbor_value: cbor::Value) -> Result<u64, Ctap2StatusCode> {
     match cbor_value {
         cbor::Value::KeyValue(cbor::KeyType::Unsigned(unsigned)) => Ok(unsigned),
@@ -810,14 +1415,71 @@ pub(super) fn ok_or_missing<T>(value_option: Option<T>) -> Result<T, Ctap2Status
     value_option.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
 }
 
+#[cfg(feature = "heap_free_parsing")]
+pub(super) fn extract_unsigned_ref(cbor_value: cbor::ValueRef) -> Result<u64, Ctap2StatusCode> {
+    match cbor_value {
+        cbor::ValueRef::Unsigned(unsigned) => Ok(unsigned),
+        _ => Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+    }
+}
+
+#[cfg(feature = "heap_free_parsing")]
+pub(super) fn extract_byte_string_ref(
+    cbor_value: cbor::ValueRef<'_>,
+) -> Result<&[u8], Ctap2StatusCode> {
+    match cbor_value {
+        cbor::ValueRef::ByteString(byte_string) => Ok(byte_string),
+        _ => Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+    }
+}
+
+#[cfg(feature = "heap_free_parsing")]
+pub(super) fn extract_text_string_ref(
+    cbor_value: cbor::ValueRef<'_>,
+) -> Result<&str, Ctap2StatusCode> {
+    match cbor_value {
+        cbor::ValueRef::TextString(text_string) => Ok(text_string),
+        _ => Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+    }
+}
+
+#[cfg(feature = "heap_free_parsing")]
+pub(super) fn extract_array_ref(
+    cbor_value: cbor::ValueRef<'_>,
+) -> Result<cbor::borrowed::ArrayRef<'_>, Ctap2StatusCode> {
+    match cbor_value {
+        cbor::ValueRef::Array(array) => Ok(array),
+        _ => Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+    }
+}
+
+#[cfg(feature = "heap_free_parsing")]
+pub(super) fn extract_map_ref(
+    cbor_value: cbor::ValueRef<'_>,
+) -> Result<cbor::borrowed::MapRef<'_>, Ctap2StatusCode> {
+    match cbor_value {
+        cbor::ValueRef::Map(map) => Ok(map),
+        _ => Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+    }
+}
+
+#[cfg(feature = "heap_free_parsing")]
+pub(super) fn extract_bool_ref(cbor_value: cbor::ValueRef) -> Result<bool, Ctap2StatusCode> {
+    match cbor_value {
+        cbor::ValueRef::Simple(cbor::SimpleValue::FalseValue) => Ok(false),
+        cbor::ValueRef::Simple(cbor::SimpleValue::TrueValue) => Ok(true),
+        _ => Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use self::Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE;
     use super::*;
     use alloc::collections::BTreeMap;
     use cbor::{
-        cbor_array, cbor_bool, cbor_bytes, cbor_false, cbor_int, cbor_map, cbor_null, cbor_text,
-        cbor_unsigned,
+        cbor_array, cbor_bool, cbor_bytes, cbor_false, cbor_int, cbor_key_int, cbor_map, cbor_null,
+        cbor_text, cbor_unsigned,
     };
     use crypto::rng256::{Rng256, ThreadRng256};
 
@@ -1251,11 +1913,14 @@ mod test {
         let cbor_extensions = cbor_map! {
             "hmac-secret" => true,
             "credProtect" => CredentialProtectionPolicy::UserVerificationRequired,
+            "minPinLength" => true,
         };
         let extensions = MakeCredentialExtensions::try_from(cbor_extensions);
         let expected_extensions = MakeCredentialExtensions {
             hmac_secret: true,
             cred_protect: Some(CredentialProtectionPolicy::UserVerificationRequired),
+            #[cfg(feature = "with_ctap2_1")]
+            min_pin_length: true,
         };
         assert_eq!(extensions, Ok(expected_extensions));
     }
@@ -1272,6 +1937,7 @@ mod test {
                 2 => vec![0x02; 32],
                 3 => vec![0x03; 16],
             },
+            "txAuthSimple" => "Pay 10 EUR to Bob?",
         };
         let extensions = GetAssertionExtensions::try_from(cbor_extensions);
         let expected_input = GetAssertionHmacSecretInput {
@@ -1281,10 +1947,131 @@ mod test {
         };
         let expected_extensions = GetAssertionExtensions {
             hmac_secret: Some(expected_input),
+            tx_auth_simple: Some(String::from("Pay 10 EUR to Bob?")),
         };
         assert_eq!(extensions, Ok(expected_extensions));
     }
 
+    #[test]
+    fn test_from_get_assertion_extensions_invalid_tx_auth_simple() {
+        let cbor_extensions = cbor_map! {
+            "txAuthSimple" => vec![0x50, 0x61, 0x79],
+        };
+        assert_eq!(
+            GetAssertionExtensions::try_from(cbor_extensions),
+            Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE)
+        );
+    }
+
+    #[test]
+    fn test_extension_outputs_empty() {
+        let outputs = ExtensionOutputs::new()
+            .hmac_secret_created(false)
+            .cred_protect(None)
+            .tx_auth_simple(None);
+        assert!(outputs.is_empty());
+        assert_eq!(outputs.encoded_len(), 0);
+        let mut auth_data = vec![0xAA];
+        assert_eq!(outputs.write(&mut auth_data), Ok(()));
+        assert_eq!(auth_data, vec![0xAA]);
+    }
+
+    #[test]
+    fn test_extension_outputs_hmac_secret_created() {
+        let outputs = ExtensionOutputs::new().hmac_secret_created(true);
+        assert!(!outputs.is_empty());
+        let length = outputs.encoded_len();
+        let mut auth_data = vec![];
+        assert_eq!(outputs.write(&mut auth_data), Ok(()));
+        assert_eq!(auth_data.len(), length);
+        assert_eq!(
+            cbor::read(&auth_data),
+            Ok(cbor_map! { "hmac-secret" => true })
+        );
+    }
+
+    #[test]
+    fn test_extension_outputs_hmac_secret_encrypted() {
+        for size in &[0, 23, 24, 32, 64, 255, 256] {
+            let outputs = ExtensionOutputs::new().hmac_secret_encrypted(vec![0x55; *size]);
+            let length = outputs.encoded_len();
+            let mut auth_data = vec![];
+            assert_eq!(outputs.write(&mut auth_data), Ok(()));
+            assert_eq!(auth_data.len(), length);
+            assert_eq!(
+                cbor::read(&auth_data),
+                Ok(cbor_map! { "hmac-secret" => vec![0x55; *size] })
+            );
+        }
+    }
+
+    #[test]
+    fn test_extension_outputs_cred_protect() {
+        for policy in CredentialProtectionPolicy::into_enum_iter() {
+            let outputs = ExtensionOutputs::new().cred_protect(Some(policy));
+            let length = outputs.encoded_len();
+            let mut auth_data = vec![];
+            assert_eq!(outputs.write(&mut auth_data), Ok(()));
+            assert_eq!(auth_data.len(), length);
+            assert_eq!(
+                cbor::read(&auth_data),
+                Ok(cbor_map! { "credProtect" => policy })
+            );
+        }
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_extension_outputs_min_pin_length() {
+        for min_pin_length in &[4, 23, 24, 63, 255] {
+            let outputs = ExtensionOutputs::new().min_pin_length(Some(*min_pin_length));
+            assert!(!outputs.is_empty());
+            let length = outputs.encoded_len();
+            let mut auth_data = vec![];
+            assert_eq!(outputs.write(&mut auth_data), Ok(()));
+            assert_eq!(auth_data.len(), length);
+            assert_eq!(
+                cbor::read(&auth_data),
+                Ok(cbor_map! { "minPinLength" => *min_pin_length as u64 })
+            );
+        }
+    }
+
+    #[test]
+    fn test_extension_outputs_tx_auth_simple() {
+        for text in &["", "Pay 10 EUR to Bob?", "\u{5bc6}\u{7801}\n\u{1f600}"] {
+            let outputs = ExtensionOutputs::new().tx_auth_simple(Some(String::from(*text)));
+            assert!(!outputs.is_empty());
+            let length = outputs.encoded_len();
+            let mut auth_data = vec![];
+            assert_eq!(outputs.write(&mut auth_data), Ok(()));
+            assert_eq!(auth_data.len(), length);
+            assert_eq!(
+                cbor::read(&auth_data),
+                Ok(cbor_map! { "txAuthSimple" => *text })
+            );
+        }
+    }
+
+    #[test]
+    fn test_extension_outputs_canonical_order() {
+        let outputs = ExtensionOutputs::new()
+            .hmac_secret_created(true)
+            .cred_protect(Some(CredentialProtectionPolicy::UserVerificationRequired));
+        let length = outputs.encoded_len();
+        let mut auth_data = vec![];
+        assert_eq!(outputs.write(&mut auth_data), Ok(()));
+        assert_eq!(auth_data.len(), length);
+        // The shorter key "credProtect" comes first in canonical CBOR.
+        let mut expected = vec![0xA2, 0x6B];
+        expected.extend_from_slice(b"credProtect");
+        expected.push(0x03);
+        expected.push(0x6B);
+        expected.extend_from_slice(b"hmac-secret");
+        expected.push(0xF5);
+        assert_eq!(auth_data, expected);
+    }
+
     #[test]
     fn test_from_make_credential_options() {
         let cbor_make_options = cbor_map! {
@@ -1299,6 +2086,53 @@ mod test {
         assert_eq!(make_options, Ok(expected_make_options));
     }
 
+    #[test]
+    fn test_from_make_credential_options_up() {
+        let cbor_make_options = cbor_map! {
+            "up" => false,
+        };
+        assert_eq!(
+            MakeCredentialOptions::try_from(cbor_make_options),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION)
+        );
+        let cbor_make_options = cbor_map! {
+            "up" => true,
+        };
+        #[cfg(feature = "with_ctap2_1")]
+        let expected_make_options = Ok(MakeCredentialOptions {
+            rk: false,
+            uv: false,
+        });
+        #[cfg(not(feature = "with_ctap2_1"))]
+        let expected_make_options = Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION);
+        assert_eq!(
+            MakeCredentialOptions::try_from(cbor_make_options),
+            expected_make_options
+        );
+        let cbor_make_options = cbor_map! {
+            "up" => 1,
+        };
+        assert_eq!(
+            MakeCredentialOptions::try_from(cbor_make_options),
+            Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE)
+        );
+    }
+
+    #[test]
+    fn test_from_make_credential_options_unknown_key() {
+        // Unknown options are treated as absent, whatever their type.
+        let cbor_make_options = cbor_map! {
+            "rk" => true,
+            "unknown" => "option",
+        };
+        let make_options = MakeCredentialOptions::try_from(cbor_make_options);
+        let expected_make_options = MakeCredentialOptions {
+            rk: true,
+            uv: false,
+        };
+        assert_eq!(make_options, Ok(expected_make_options));
+    }
+
     #[test]
     fn test_from_get_assertion_options() {
         let cbor_get_assertion = cbor_map! {
@@ -1332,6 +2166,52 @@ mod test {
         assert_eq!(created_cbor, cbor_packed_attestation_statement);
     }
 
+    #[test]
+    fn test_into_fido_u2f_attestation_statement() {
+        let certificate: cbor::values::KeyType = cbor_bytes![vec![0x5C, 0x5C, 0x5C, 0x5C]];
+        let cbor_fido_u2f_attestation_statement = cbor_map! {
+            "sig" => vec![0x55, 0x55, 0x55, 0x55],
+            "x5c" => cbor_array_vec![vec![certificate]],
+        };
+        let fido_u2f_attestation_statement = FidoU2fAttestationStatement {
+            sig: vec![0x55, 0x55, 0x55, 0x55],
+            x5c: vec![vec![0x5C, 0x5C, 0x5C, 0x5C]],
+        };
+        let created_cbor: cbor::Value = fido_u2f_attestation_statement.into();
+        assert_eq!(created_cbor, cbor_fido_u2f_attestation_statement);
+    }
+
+    #[test]
+    fn test_into_attestation_statement() {
+        let created_cbor: cbor::Value = AttestationStatement::None.into();
+        assert_eq!(created_cbor, cbor_map! {});
+        let att_stmt = AttestationStatement::FidoU2f(FidoU2fAttestationStatement {
+            sig: vec![0x55],
+            x5c: vec![vec![0x5C]],
+        });
+        assert_eq!(att_stmt.format(), AttestationFormat::FidoU2f);
+        let created_cbor: cbor::Value = att_stmt.into();
+        assert_eq!(
+            created_cbor,
+            cbor_map! {
+                "sig" => vec![0x55],
+                "x5c" => cbor_array_vec!(vec![vec![0x5Cu8]]),
+            }
+        );
+    }
+
+    #[test]
+    fn test_attestation_format() {
+        for &format in &[
+            AttestationFormat::None,
+            AttestationFormat::Packed,
+            AttestationFormat::FidoU2f,
+        ] {
+            assert_eq!(AttestationFormat::try_from(format.as_str()), Ok(format));
+        }
+        assert_eq!(AttestationFormat::try_from("tpm"), Err(()));
+    }
+
     #[test]
     fn test_from_into_cose_key() {
         let mut rng = ThreadRng256 {};
@@ -1342,6 +2222,40 @@ mod test {
         assert_eq!(created_pk, Ok(pk));
     }
 
+    #[test]
+    fn test_from_cose_key_invalid_point() {
+        let mut rng = ThreadRng256 {};
+        let pk = crypto::ecdh::SecKey::gensk(&mut rng).genpk();
+        let mut x_bytes = [0; ecdh::NBYTES];
+        let mut y_bytes = [0; ecdh::NBYTES];
+        pk.to_coordinates(&mut x_bytes, &mut y_bytes);
+        // The point (1, y) is on the quadratic twist of the curve.
+        let mut twist_x_bytes = [0x00; ecdh::NBYTES];
+        twist_x_bytes[ecdh::NBYTES - 1] = 0x01;
+        let twist_y_bytes: [u8; ecdh::NBYTES] = [
+            0xDE, 0x17, 0xB2, 0x67, 0x10, 0x45, 0xFE, 0x58, 0x74, 0x02, 0x48, 0xC1, 0x7A, 0x8B,
+            0xD2, 0xFB, 0x2E, 0xF7, 0xC3, 0xC4, 0x9B, 0x01, 0xDA, 0x9F, 0x90, 0x3E, 0xFA, 0x3D,
+            0xCD, 0xA6, 0x69, 0xC7,
+        ];
+        let mut off_curve_y_bytes = y_bytes;
+        off_curve_y_bytes[0] ^= 0x01;
+        let invalid_points = [
+            (twist_x_bytes, twist_y_bytes),
+            (x_bytes, off_curve_y_bytes),
+            ([0x00; ecdh::NBYTES], [0x00; ecdh::NBYTES]),
+            (x_bytes, [0xFF; ecdh::NBYTES]),
+        ];
+        for (x, y) in invalid_points.iter() {
+            let mut cose_key = CoseKey::from(pk.clone());
+            cose_key.0.insert(cbor_key_int!(-2), cbor_bytes_lit!(x));
+            cose_key.0.insert(cbor_key_int!(-3), cbor_bytes_lit!(y));
+            assert_eq!(
+                ecdh::PubKey::try_from(cose_key),
+                Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+            );
+        }
+    }
+
     #[test]
     fn test_from_into_client_pin_sub_command() {
         let cbor_sub_command: cbor::Value = cbor_int!(0x01);
@@ -1358,6 +2272,154 @@ mod test {
         }
     }
 
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_from_into_config_sub_command() {
+        let cbor_sub_command: cbor::Value = cbor_int!(0x02);
+        let sub_command = ConfigSubCommand::try_from(cbor_sub_command.clone());
+        assert_eq!(sub_command, Ok(ConfigSubCommand::ToggleAlwaysUv));
+        let created_cbor: cbor::Value = sub_command.unwrap().into();
+        assert_eq!(created_cbor, cbor_sub_command);
+
+        for command in ConfigSubCommand::into_enum_iter() {
+            let created_cbor: cbor::Value = command.into();
+            let reconstructed = ConfigSubCommand::try_from(created_cbor).unwrap();
+            assert_eq!(command, reconstructed);
+        }
+
+        let cbor_sub_command: cbor::Value = cbor_int!(0x01);
+        assert_eq!(
+            ConfigSubCommand::try_from(cbor_sub_command),
+            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_SUBCOMMAND)
+        );
+    }
+
+    #[test]
+    fn test_from_into_vendor_sub_command() {
+        let cbor_sub_command: cbor::Value = cbor_int!(0x02);
+        let sub_command = VendorSubCommand::try_from(cbor_sub_command.clone());
+        assert_eq!(sub_command, Ok(VendorSubCommand::AuditLog));
+        let created_cbor: cbor::Value = sub_command.unwrap().into();
+        assert_eq!(created_cbor, cbor_sub_command);
+
+        for command in VendorSubCommand::into_enum_iter() {
+            let created_cbor: cbor::Value = command.into();
+            let reconstructed = VendorSubCommand::try_from(created_cbor).unwrap();
+            assert_eq!(command, reconstructed);
+        }
+
+        let cbor_sub_command: cbor::Value = cbor_int!(0x00);
+        assert_eq!(
+            VendorSubCommand::try_from(cbor_sub_command),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+    }
+
+    #[test]
+    fn test_from_into_audit_event_type() {
+        let cbor_event_type: cbor::Value = cbor_int!(0x03);
+        let event_type = AuditEventType::try_from(cbor_event_type);
+        assert_eq!(event_type, Ok(AuditEventType::PinFailure));
+
+        let cbor_event_type_error: cbor::Value = cbor_int!(0);
+        let event_type_error = AuditEventType::try_from(cbor_event_type_error);
+        let expected_error = Err(Ctap2StatusCode::CTAP2_ERR_CBOR_UNEXPECTED_TYPE);
+        assert_eq!(event_type_error, expected_error);
+
+        for event_type in AuditEventType::into_enum_iter() {
+            let created_cbor: cbor::Value = event_type.into();
+            let reconstructed = AuditEventType::try_from(created_cbor).unwrap();
+            assert_eq!(event_type, reconstructed);
+        }
+    }
+
+    #[test]
+    fn test_audit_event_cbor_round_trip() {
+        let event = AuditEvent {
+            sequence: 42,
+            event_type: AuditEventType::Reset,
+            timestamp_ms: 1234,
+        };
+        let cbor_event: cbor::Value = event.clone().into();
+        let expected_cbor = cbor_map! {
+            1 => 42,
+            2 => 0x04,
+            3 => 1234,
+        };
+        assert_eq!(cbor_event, expected_cbor);
+        assert_eq!(AuditEvent::try_from(cbor_event), Ok(event));
+    }
+
+    #[test]
+    fn test_usb_identity_cbor_round_trip() {
+        let identity = UsbIdentity {
+            vendor_id: 0x1915,
+            product_id: 0x521F,
+            serial_number: Some(String::from("WL-0001")),
+        };
+        let cbor_identity: cbor::Value = identity.clone().into();
+        let expected_cbor = cbor_map! {
+            1 => 0x1915,
+            2 => 0x521F,
+            3 => "WL-0001",
+        };
+        assert_eq!(cbor_identity, expected_cbor);
+        assert_eq!(UsbIdentity::try_from(cbor_identity), Ok(identity));
+
+        let identity = UsbIdentity {
+            vendor_id: 0x1915,
+            product_id: 0x521F,
+            serial_number: None,
+        };
+        let cbor_identity: cbor::Value = identity.clone().into();
+        assert_eq!(UsbIdentity::try_from(cbor_identity), Ok(identity));
+    }
+
+    #[test]
+    fn test_usb_identity_invalid() {
+        let cbor_identity = cbor_map! {
+            1 => 0,
+            2 => 0x521F,
+        };
+        assert_eq!(
+            UsbIdentity::try_from(cbor_identity),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+        let cbor_identity = cbor_map! {
+            1 => 0x1915,
+            2 => 0x10000,
+        };
+        assert_eq!(
+            UsbIdentity::try_from(cbor_identity),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+        let cbor_identity = cbor_map! {
+            1 => 0x1915,
+            2 => 0x521F,
+            3 => "tab\tinside",
+        };
+        assert_eq!(
+            UsbIdentity::try_from(cbor_identity),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+        let cbor_identity = cbor_map! {
+            1 => 0x1915,
+            2 => 0x521F,
+            3 => "0".repeat(MAX_USB_SERIAL_NUMBER_LENGTH + 1),
+        };
+        assert_eq!(
+            UsbIdentity::try_from(cbor_identity),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+        let cbor_identity = cbor_map! {
+            1 => 0x1915,
+        };
+        assert_eq!(
+            UsbIdentity::try_from(cbor_identity),
+            Err(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)
+        );
+    }
+
     #[test]
     fn test_credential_source_cbor_round_trip() {
         let mut rng = ThreadRng256 {};
@@ -1372,6 +2434,8 @@ mod test {
             creation_order: 0,
             user_name: None,
             user_icon: None,
+            authenticator_display_name: None,
+            transports: None,
         };
 
         assert_eq!(
@@ -1414,6 +2478,29 @@ mod test {
             ..credential
         };
 
+        assert_eq!(
+            PublicKeyCredentialSource::try_from(cbor::Value::from(credential.clone())),
+            Ok(credential.clone())
+        );
+
+        let credential = PublicKeyCredentialSource {
+            authenticator_display_name: Some("work key".to_string()),
+            ..credential
+        };
+
+        assert_eq!(
+            PublicKeyCredentialSource::try_from(cbor::Value::from(credential.clone())),
+            Ok(credential.clone())
+        );
+
+        let credential = PublicKeyCredentialSource {
+            transports: Some(vec![
+                AuthenticatorTransport::Usb,
+                AuthenticatorTransport::Nfc,
+            ]),
+            ..credential
+        };
+
         assert_eq!(
             PublicKeyCredentialSource::try_from(cbor::Value::from(credential.clone())),
             Ok(credential)
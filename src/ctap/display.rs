@@ -0,0 +1,162 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// What the user is asked to approve while user presence is checked.
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub enum Prompt<'a> {
+    /// A new credential is about to be created.
+    MakeCredential {
+        rp_id: &'a str,
+        rp_name: Option<&'a str>,
+        user_name: Option<&'a str>,
+    },
+    /// An existing credential is about to be used.
+    ///
+    /// The user name is only known if a single credential applies and user verification
+    /// revealed it.
+    GetAssertion {
+        rp_id: &'a str,
+        user_name: Option<&'a str>,
+    },
+    /// All credentials are about to be deleted.
+    Reset,
+}
+
+impl<'a> Prompt<'a> {
+    /// Returns the prompt as lines of text, from the most to the least important.
+    ///
+    /// The RP ID comes before the RP name, because only the former is checked by the platform.
+    pub fn lines(&self) -> Vec<&'a str> {
+        let mut lines = Vec::new();
+        match *self {
+            Prompt::MakeCredential {
+                rp_id,
+                rp_name,
+                user_name,
+            } => {
+                lines.push("Register");
+                lines.push(rp_id);
+                lines.extend(rp_name);
+                lines.extend(user_name);
+            }
+            Prompt::GetAssertion { rp_id, user_name } => {
+                lines.push("Sign in");
+                lines.push(rp_id);
+                lines.extend(user_name);
+            }
+            Prompt::Reset => {
+                lines.push("Reset");
+                lines.push("Delete all credentials");
+            }
+        }
+        lines
+    }
+}
+
+/// A screen showing what the user is asked to approve, e.g. a small OLED display.
+///
+/// The prompt is shown while the authenticator waits for user presence, and cleared afterwards.
+pub trait Display {
+    /// Shows a prompt until the next call to `clear`.
+    fn show(&mut self, prompt: &Prompt);
+
+    /// Clears the screen.
+    fn clear(&mut self);
+
+    /// Returns the transaction confirmation support of the display, if any.
+    fn transaction_confirmation(&mut self) -> Option<&mut dyn TransactionConfirmation> {
+        None
+    }
+
+    /// Starts or stops showing that the authenticator is being identified among others.
+    ///
+    /// The LEDs blink in any case, so displays don't need to show anything.
+    fn identify(&mut self, _active: bool) {}
+}
+
+/// Rendering of transaction texts, as needed by the txAuthSimple extension.
+pub trait TransactionConfirmation {
+    /// Shows the transaction text of an RP until the display is cleared.
+    ///
+    /// Returns whether the whole text is legible. If it isn't, the user can't knowingly confirm
+    /// the transaction and it must be refused.
+    fn show_transaction(&mut self, rp_id: &str, text: &str) -> bool;
+}
+
+/// Fits a line of untrusted text on a screen of the given width in characters.
+///
+/// Control characters are replaced, so that they can't alter the layout. Lines that are too long
+/// are truncated with an ellipsis.
+pub fn fit_to_width(text: &str, width: usize) -> String {
+    let truncated = text.chars().count() > width;
+    let kept = if truncated {
+        width.saturating_sub(1)
+    } else {
+        width
+    };
+    let mut line: String = text
+        .chars()
+        .take(kept)
+        .map(|c| if c.is_control() { '\u{fffd}' } else { c })
+        .collect();
+    if truncated && width > 0 {
+        line.push('\u{2026}');
+    }
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_prompt_lines() {
+        let prompt = Prompt::MakeCredential {
+            rp_id: "example.com",
+            rp_name: Some("Example"),
+            user_name: Some("alice"),
+        };
+        assert_eq!(
+            prompt.lines(),
+            vec!["Register", "example.com", "Example", "alice"]
+        );
+        let prompt = Prompt::GetAssertion {
+            rp_id: "example.com",
+            user_name: None,
+        };
+        assert_eq!(prompt.lines(), vec!["Sign in", "example.com"]);
+        assert_eq!(
+            Prompt::Reset.lines(),
+            vec!["Reset", "Delete all credentials"]
+        );
+    }
+
+    #[test]
+    fn test_fit_to_width() {
+        assert_eq!(fit_to_width("", 4), "");
+        assert_eq!(fit_to_width("abcd", 4), "abcd");
+        assert_eq!(fit_to_width("abcde", 4), "abc\u{2026}");
+        assert_eq!(fit_to_width("\u{e9}t\u{e9}s", 4), "\u{e9}t\u{e9}s");
+        assert_eq!(
+            fit_to_width("\u{5bc6}\u{7801}\u{5bc6}\u{7801}!", 3),
+            "\u{5bc6}\u{7801}\u{2026}"
+        );
+        assert_eq!(fit_to_width("a\nb", 4), "a\u{fffd}b");
+        assert_eq!(fit_to_width("abc", 0), "");
+    }
+}
@@ -14,14 +14,18 @@
 
 pub mod receive;
 pub mod send;
+#[cfg(feature = "debug_ctap")]
+mod stats;
 
 use self::receive::MessageAssembler;
 use self::send::HidPacketIterator;
+#[cfg(feature = "debug_ctap")]
+use self::stats::HidStats;
 #[cfg(feature = "with_ctap1")]
 use super::ctap1;
 use super::status_code::Ctap2StatusCode;
 use super::timed_permission::TimedPermission;
-use super::CtapState;
+use super::{CtapState, MAX_MSG_SIZE};
 use alloc::vec;
 use alloc::vec::Vec;
 use arrayref::{array_ref, array_refs};
@@ -72,6 +76,11 @@ pub struct CtapHid {
     // u32::to/from_be_bytes methods).
     allocated_cids: usize,
     pub wink_permission: TimedPermission,
+    // The channel holding the device exclusively, if any. The lock expires with its permission.
+    locked_cid: Option<ChannelID>,
+    lock_permission: TimedPermission,
+    #[cfg(feature = "debug_ctap")]
+    stats: HidStats,
 }
 
 #[allow(dead_code)]
@@ -97,11 +106,12 @@ impl CtapHid {
     pub const COMMAND_CANCEL: u8 = 0x11;
     const COMMAND_KEEPALIVE: u8 = 0x3B;
     const COMMAND_ERROR: u8 = 0x3F;
-    // TODO: optional lock command
     const COMMAND_LOCK: u8 = 0x04;
     const COMMAND_WINK: u8 = 0x08;
     const COMMAND_VENDOR_FIRST: u8 = 0x40;
     const COMMAND_VENDOR_LAST: u8 = 0x7F;
+    // Reports the traffic counters of the transport, with the debug_ctap feature.
+    const COMMAND_VENDOR_STATS: u8 = 0x40;
 
     // CTAP specification (version 20190130) section 8.1.9.1.6
     const ERR_INVALID_CMD: u8 = 0x01;
@@ -123,24 +133,67 @@ impl CtapHid {
     const DEVICE_VERSION_BUILD: u8 = 0;
 
     const CAPABILITY_WINK: u8 = 0x01;
+    const CAPABILITY_LOCK: u8 = 0x02;
     const CAPABILITY_CBOR: u8 = 0x04;
     const CAPABILITY_NMSG: u8 = 0x08;
     // Capabilitites currently supported by this device.
     #[cfg(feature = "with_ctap1")]
-    const CAPABILITIES: u8 = CtapHid::CAPABILITY_WINK | CtapHid::CAPABILITY_CBOR;
-    #[cfg(not(feature = "with_ctap1"))]
     const CAPABILITIES: u8 =
-        CtapHid::CAPABILITY_WINK | CtapHid::CAPABILITY_CBOR | CtapHid::CAPABILITY_NMSG;
+        CtapHid::CAPABILITY_WINK | CtapHid::CAPABILITY_LOCK | CtapHid::CAPABILITY_CBOR;
+    #[cfg(not(feature = "with_ctap1"))]
+    const CAPABILITIES: u8 = CtapHid::CAPABILITY_WINK
+        | CtapHid::CAPABILITY_LOCK
+        | CtapHid::CAPABILITY_CBOR
+        | CtapHid::CAPABILITY_NMSG;
 
     // TODO: Is this timeout duration specified?
     const TIMEOUT_DURATION: Duration<isize> = Duration::from_ms(100);
     const WINK_TIMEOUT_DURATION: Duration<isize> = Duration::from_ms(5000);
+    // CTAP specification (version 20190130) section 8.1.9.2.2
+    const MAX_LOCK_SECONDS: u8 = 10;
 
     pub fn new() -> CtapHid {
         CtapHid {
-            assembler: MessageAssembler::new(),
+            assembler: MessageAssembler::with_max_payload_len(MAX_MSG_SIZE),
             allocated_cids: 0,
             wink_permission: TimedPermission::waiting(),
+            locked_cid: None,
+            lock_permission: TimedPermission::waiting(),
+            #[cfg(feature = "debug_ctap")]
+            stats: HidStats::default(),
+        }
+    }
+
+    // Counts a reply packet that the caller sent.
+    #[cfg(feature = "debug_ctap")]
+    pub fn record_sent_packet(&mut self, now: ClockValue) {
+        self.stats.record_sent(now);
+    }
+
+    // Counts a reply packet that the caller failed to send.
+    #[cfg(feature = "debug_ctap")]
+    pub fn record_dropped_packet(&mut self) {
+        self.stats.record_dropped();
+    }
+
+    // Handles the host suspending the USB bus, e.g. when the computer goes to sleep. Winking
+    // stops, since the user can't act on it while the host sleeps.
+    pub fn suspend(&mut self) {
+        self.wink_permission = TimedPermission::waiting();
+    }
+
+    // Handles the host resuming the USB bus after a suspension, and returns the packets to send.
+    //
+    // The host aborts its transfers when suspending the bus, so a message that was partially
+    // received can't be completed. Its channel is answered with a timeout error, like when the
+    // host stops sending packets, and the next packet must start a new message.
+    pub fn resume(&mut self) -> HidPacketIterator {
+        match self.assembler.pending_channel() {
+            Some(cid) => {
+                self.assembler.reset();
+                CtapHid::error_message(cid, CtapHid::ERR_MSG_TIMEOUT)
+            }
+            None => HidPacketIterator::none(),
         }
     }
 
@@ -157,6 +210,21 @@ impl CtapHid {
         CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
     {
         // TODO: Send COMMAND_KEEPALIVE every 100ms?
+        // While a channel holds the lock, packets of other channels don't reach the assembler, so
+        // that they can't interrupt the messages of the locking channel.
+        #[cfg(feature = "debug_ctap")]
+        self.stats.record_received(clock_value);
+        let (packet_cid, processed_packet) = CtapHid::process_single_packet(packet);
+        if self.is_locked_by_other_channel(*packet_cid, clock_value) {
+            #[cfg(feature = "debug_ctap")]
+            self.stats.record_dropped();
+            return match processed_packet {
+                ProcessedPacket::InitPacket { .. } => {
+                    CtapHid::error_message(*packet_cid, CtapHid::ERR_CHANNEL_BUSY)
+                }
+                ProcessedPacket::ContinuationPacket { .. } => HidPacketIterator::none(),
+            };
+        }
         match self
             .assembler
             .parse_packet(packet, Timestamp::<isize>::from_clock_value(clock_value))
@@ -286,7 +354,46 @@ impl CtapHid {
                         .unwrap()
                     }
                     // CTAP specification (version 20190130) section 8.1.9.2.2
-                    // TODO: implement LOCK
+                    CtapHid::COMMAND_LOCK => {
+                        if message.payload.len() != 1 {
+                            return CtapHid::error_message(cid, CtapHid::ERR_INVALID_LEN);
+                        }
+                        let lock_seconds = message.payload[0];
+                        if lock_seconds > CtapHid::MAX_LOCK_SECONDS {
+                            return CtapHid::error_message(cid, CtapHid::ERR_INVALID_PAR);
+                        }
+                        // A lock time of 0 releases the lock, other values replace it.
+                        if lock_seconds == 0 {
+                            self.locked_cid = None;
+                            self.lock_permission = TimedPermission::waiting();
+                        } else {
+                            self.locked_cid = Some(cid);
+                            self.lock_permission = TimedPermission::granted(
+                                clock_value,
+                                Duration::from_ms(lock_seconds as isize * 1000),
+                            );
+                        }
+                        CtapHid::split_message(Message {
+                            cid,
+                            cmd: CtapHid::COMMAND_LOCK,
+                            payload: vec![],
+                        })
+                        .unwrap()
+                    }
+                    #[cfg(feature = "debug_ctap")]
+                    CtapHid::COMMAND_VENDOR_STATS => {
+                        if !message.payload.is_empty() {
+                            return CtapHid::error_message(cid, CtapHid::ERR_INVALID_LEN);
+                        }
+                        // Reading the counters resets them, so that each report covers the
+                        // traffic since the previous one.
+                        CtapHid::split_message(Message {
+                            cid,
+                            cmd: CtapHid::COMMAND_VENDOR_STATS,
+                            payload: self.stats.report(clock_value),
+                        })
+                        .unwrap()
+                    }
                     _ => {
                         // Unknown or unsupported command.
                         CtapHid::error_message(cid, CtapHid::ERR_INVALID_CMD)
@@ -298,6 +405,8 @@ impl CtapHid {
                 HidPacketIterator::none()
             }
             Err((cid, error)) => {
+                #[cfg(feature = "debug_ctap")]
+                self.stats.record_dropped();
                 if !self.is_allocated_channel(cid)
                     && error != receive::Error::UnexpectedContinuation
                 {
@@ -325,6 +434,9 @@ impl CtapHid {
                         receive::Error::Timeout => {
                             CtapHid::error_message(cid, CtapHid::ERR_MSG_TIMEOUT)
                         }
+                        receive::Error::InvalidLength => {
+                            CtapHid::error_message(cid, CtapHid::ERR_INVALID_LEN)
+                        }
                     }
                 }
             }
@@ -340,6 +452,18 @@ impl CtapHid {
         }
     }
 
+    // Whether another channel holds the lock. Expired locks are released.
+    fn is_locked_by_other_channel(&mut self, cid: ChannelID, clock_value: ClockValue) -> bool {
+        self.lock_permission = self.lock_permission.check_expiration(clock_value);
+        if !self.lock_permission.is_granted(clock_value) {
+            self.locked_cid = None;
+        }
+        match self.locked_cid {
+            Some(locked_cid) => locked_cid != cid,
+            None => false,
+        }
+    }
+
     fn is_allocated_channel(&self, cid: ChannelID) -> bool {
         cid != CtapHid::CHANNEL_RESERVED && u32::from_be_bytes(cid) as usize <= self.allocated_cids
     }
@@ -442,6 +566,18 @@ mod test {
         ctap_state: &mut CtapState<ThreadRng256, CheckUserPresence>,
         request: Vec<Message>,
     ) -> Option<Vec<Message>>
+    where
+        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+    {
+        process_messages_at(ctap_hid, ctap_state, request, DUMMY_CLOCK_VALUE)
+    }
+
+    fn process_messages_at<CheckUserPresence>(
+        ctap_hid: &mut CtapHid,
+        ctap_state: &mut CtapState<ThreadRng256, CheckUserPresence>,
+        request: Vec<Message>,
+        clock_value: ClockValue,
+    ) -> Option<Vec<Message>>
     where
         CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
     {
@@ -449,8 +585,7 @@ mod test {
         let mut assembler_reply = MessageAssembler::new();
         for msg_request in request {
             for pkt_request in HidPacketIterator::new(msg_request).unwrap() {
-                for pkt_reply in
-                    ctap_hid.process_hid_packet(&pkt_request, DUMMY_CLOCK_VALUE, ctap_state)
+                for pkt_reply in ctap_hid.process_hid_packet(&pkt_request, clock_value, ctap_state)
                 {
                     match assembler_reply.parse_packet(&pkt_reply, DUMMY_TIMESTAMP) {
                         Ok(Some(message)) => result.push(message),
@@ -669,4 +804,384 @@ mod test {
             }])
         );
     }
+
+    #[test]
+    fn test_command_ping_max_length() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let mut ctap_hid = CtapHid::new();
+        let cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+
+        // The payload is longer than the maximum message size, and fills 129 packets.
+        let payload: Vec<u8> = (0..MessageAssembler::MAX_HID_PAYLOAD_LEN)
+            .map(|i| i as u8)
+            .collect();
+        assert!(payload.len() > MAX_MSG_SIZE);
+        let reply = process_messages(
+            &mut ctap_hid,
+            &mut ctap_state,
+            vec![Message {
+                cid,
+                cmd: CtapHid::COMMAND_PING,
+                payload: payload.clone(),
+            }],
+        );
+
+        assert_eq!(
+            reply,
+            Some(vec![Message {
+                cid,
+                cmd: CtapHid::COMMAND_PING,
+                payload
+            }])
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "debug_ctap")]
+    fn test_command_vendor_stats() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let mut ctap_hid = CtapHid::new();
+        let cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        // A continuation packet without message is dropped.
+        let mut packet = [0x00; 64];
+        packet[..4].copy_from_slice(&cid);
+        assert_eq!(
+            ctap_hid
+                .process_hid_packet(&packet, DUMMY_CLOCK_VALUE, &mut ctap_state)
+                .count(),
+            0
+        );
+        ctap_hid.record_sent_packet(DUMMY_CLOCK_VALUE);
+
+        let stats_message = Message {
+            cid,
+            cmd: CtapHid::COMMAND_VENDOR_STATS,
+            payload: vec![],
+        };
+        let reply = process_messages(&mut ctap_hid, &mut ctap_state, vec![stats_message.clone()]);
+        // The INIT, continuation and stats packets were received, and no time elapsed.
+        let mut expected_payload = vec![0x00; 12];
+        expected_payload.extend(&[0x00, 0x00, 0x00, 0x03]);
+        expected_payload.extend(&[0x00, 0x00, 0x00, 0x01]);
+        expected_payload.extend(&[0x00, 0x00, 0x00, 0x01]);
+        assert_eq!(
+            reply,
+            Some(vec![Message {
+                cid,
+                cmd: CtapHid::COMMAND_VENDOR_STATS,
+                payload: expected_payload
+            }])
+        );
+
+        // The counters are reset by the report.
+        let reply = process_messages(&mut ctap_hid, &mut ctap_state, vec![stats_message]);
+        let mut expected_payload = vec![0x00; 12];
+        expected_payload.extend(&[0x00, 0x00, 0x00, 0x01]);
+        expected_payload.extend(&[0x00; 8]);
+        assert_eq!(
+            reply,
+            Some(vec![Message {
+                cid,
+                cmd: CtapHid::COMMAND_VENDOR_STATS,
+                payload: expected_payload
+            }])
+        );
+    }
+
+    #[test]
+    fn test_message_too_long() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let mut ctap_hid = CtapHid::new();
+        let cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+
+        let reply = process_messages(
+            &mut ctap_hid,
+            &mut ctap_state,
+            vec![Message {
+                cid,
+                cmd: CtapHid::COMMAND_CBOR,
+                payload: vec![0x00; MAX_MSG_SIZE + 1],
+            }],
+        );
+
+        // Only the init packet gets a reply, the continuation packets are ignored.
+        assert_eq!(
+            reply,
+            Some(vec![Message {
+                cid,
+                cmd: CtapHid::COMMAND_ERROR,
+                payload: vec![CtapHid::ERR_INVALID_LEN]
+            }])
+        );
+    }
+
+    fn ping_message(cid: ChannelID) -> Message {
+        Message {
+            cid,
+            cmd: CtapHid::COMMAND_PING,
+            payload: vec![0x99, 0x99],
+        }
+    }
+
+    fn lock_message(cid: ChannelID, lock_seconds: u8) -> Message {
+        Message {
+            cid,
+            cmd: CtapHid::COMMAND_LOCK,
+            payload: vec![lock_seconds],
+        }
+    }
+
+    fn channel_busy_message(cid: ChannelID) -> Message {
+        Message {
+            cid,
+            cmd: CtapHid::COMMAND_ERROR,
+            payload: vec![CtapHid::ERR_CHANNEL_BUSY],
+        }
+    }
+
+    #[test]
+    fn test_command_lock() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let mut ctap_hid = CtapHid::new();
+        let cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        let other_cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+
+        let reply = process_messages(&mut ctap_hid, &mut ctap_state, vec![lock_message(cid, 10)]);
+        assert_eq!(
+            reply,
+            Some(vec![Message {
+                cid,
+                cmd: CtapHid::COMMAND_LOCK,
+                payload: vec![]
+            }])
+        );
+
+        // Only the locking channel is served.
+        let reply = process_messages(
+            &mut ctap_hid,
+            &mut ctap_state,
+            vec![ping_message(other_cid), ping_message(cid)],
+        );
+        assert_eq!(
+            reply,
+            Some(vec![channel_busy_message(other_cid), ping_message(cid)])
+        );
+        // New channels can't be allocated either.
+        let reply = process_messages(
+            &mut ctap_hid,
+            &mut ctap_state,
+            vec![Message {
+                cid: CtapHid::CHANNEL_BROADCAST,
+                cmd: CtapHid::COMMAND_INIT,
+                payload: vec![0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0],
+            }],
+        );
+        assert_eq!(
+            reply,
+            Some(vec![channel_busy_message(CtapHid::CHANNEL_BROADCAST)])
+        );
+
+        // A lock time of 0 releases the lock.
+        let reply = process_messages(
+            &mut ctap_hid,
+            &mut ctap_state,
+            vec![lock_message(cid, 0), ping_message(other_cid)],
+        );
+        assert_eq!(
+            reply,
+            Some(vec![
+                Message {
+                    cid,
+                    cmd: CtapHid::COMMAND_LOCK,
+                    payload: vec![]
+                },
+                ping_message(other_cid)
+            ])
+        );
+    }
+
+    #[test]
+    fn test_command_lock_expires() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let mut ctap_hid = CtapHid::new();
+        let cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        let other_cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+
+        process_messages(&mut ctap_hid, &mut ctap_state, vec![lock_message(cid, 2)]);
+        let reply = process_messages_at(
+            &mut ctap_hid,
+            &mut ctap_state,
+            vec![ping_message(other_cid)],
+            DUMMY_CLOCK_VALUE.wrapping_add(Duration::from_ms(1999)),
+        );
+        assert_eq!(reply, Some(vec![channel_busy_message(other_cid)]));
+        let reply = process_messages_at(
+            &mut ctap_hid,
+            &mut ctap_state,
+            vec![ping_message(other_cid)],
+            DUMMY_CLOCK_VALUE.wrapping_add(Duration::from_ms(2000)),
+        );
+        assert_eq!(reply, Some(vec![ping_message(other_cid)]));
+    }
+
+    #[test]
+    fn test_command_lock_busy_during_message() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let mut ctap_hid = CtapHid::new();
+        let cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        let other_cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        process_messages(&mut ctap_hid, &mut ctap_state, vec![lock_message(cid, 10)]);
+
+        // The other channel can't start a message, and doesn't disturb the locking channel.
+        let long_ping = Message {
+            cid,
+            cmd: CtapHid::COMMAND_PING,
+            payload: vec![0x99; 100],
+        };
+        let mut packets = HidPacketIterator::new(long_ping.clone()).unwrap();
+        let mut other_packets = HidPacketIterator::new(Message {
+            cid: other_cid,
+            cmd: CtapHid::COMMAND_PING,
+            payload: vec![0x99; 100],
+        })
+        .unwrap();
+        let mut replies = Vec::new();
+        let mut assembler_reply = MessageAssembler::new();
+        let requests = vec![
+            packets.next().unwrap(),
+            other_packets.next().unwrap(),
+            other_packets.next().unwrap(),
+            packets.next().unwrap(),
+        ];
+        for pkt_request in &requests {
+            for pkt_reply in
+                ctap_hid.process_hid_packet(pkt_request, DUMMY_CLOCK_VALUE, &mut ctap_state)
+            {
+                if let Some(message) = assembler_reply
+                    .parse_packet(&pkt_reply, DUMMY_TIMESTAMP)
+                    .unwrap()
+                {
+                    replies.push(message);
+                }
+            }
+        }
+        assert_eq!(replies, vec![channel_busy_message(other_cid), long_ping]);
+    }
+
+    #[test]
+    fn test_resume_drops_partial_message() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let mut ctap_hid = CtapHid::new();
+        let cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        // Nothing is sent if no message was pending.
+        ctap_hid.suspend();
+        assert_eq!(ctap_hid.resume().count(), 0);
+
+        let mut packets = HidPacketIterator::new(Message {
+            cid,
+            cmd: CtapHid::COMMAND_PING,
+            payload: vec![0x99; 100],
+        })
+        .unwrap();
+        let first_packet = packets.next().unwrap();
+        assert_eq!(
+            ctap_hid
+                .process_hid_packet(&first_packet, DUMMY_CLOCK_VALUE, &mut ctap_state)
+                .count(),
+            0
+        );
+        ctap_hid.suspend();
+        let mut assembler_reply = MessageAssembler::new();
+        let mut replies = Vec::new();
+        for pkt_reply in ctap_hid.resume() {
+            if let Some(message) = assembler_reply
+                .parse_packet(&pkt_reply, DUMMY_TIMESTAMP)
+                .unwrap()
+            {
+                replies.push(message);
+            }
+        }
+        assert_eq!(
+            replies,
+            vec![Message {
+                cid,
+                cmd: CtapHid::COMMAND_ERROR,
+                payload: vec![CtapHid::ERR_MSG_TIMEOUT]
+            }]
+        );
+
+        // The rest of the dropped message is ignored and new messages are processed.
+        let last_packet = packets.next().unwrap();
+        assert_eq!(
+            ctap_hid
+                .process_hid_packet(&last_packet, DUMMY_CLOCK_VALUE, &mut ctap_state)
+                .count(),
+            0
+        );
+        let reply = process_messages(&mut ctap_hid, &mut ctap_state, vec![ping_message(cid)]);
+        assert_eq!(reply, Some(vec![ping_message(cid)]));
+    }
+
+    #[test]
+    fn test_suspend_stops_wink() {
+        let mut ctap_hid = CtapHid::new();
+        ctap_hid.wink_permission =
+            TimedPermission::granted(DUMMY_CLOCK_VALUE, CtapHid::WINK_TIMEOUT_DURATION);
+        ctap_hid.suspend();
+        assert!(!ctap_hid.wink_permission.is_granted(DUMMY_CLOCK_VALUE));
+    }
+
+    #[test]
+    fn test_command_lock_invalid() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let mut ctap_hid = CtapHid::new();
+        let cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+        let other_cid = cid_from_init(&mut ctap_hid, &mut ctap_state);
+
+        let reply = process_messages(
+            &mut ctap_hid,
+            &mut ctap_state,
+            vec![
+                lock_message(cid, 11),
+                Message {
+                    cid,
+                    cmd: CtapHid::COMMAND_LOCK,
+                    payload: vec![],
+                },
+                ping_message(other_cid),
+            ],
+        );
+        assert_eq!(
+            reply,
+            Some(vec![
+                Message {
+                    cid,
+                    cmd: CtapHid::COMMAND_ERROR,
+                    payload: vec![CtapHid::ERR_INVALID_PAR]
+                },
+                Message {
+                    cid,
+                    cmd: CtapHid::COMMAND_ERROR,
+                    payload: vec![CtapHid::ERR_INVALID_LEN]
+                },
+                ping_message(other_cid)
+            ])
+        );
+    }
 }
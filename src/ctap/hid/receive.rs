@@ -33,6 +33,8 @@ pub struct MessageAssembler {
     remaining_payload_len: usize,
     // Buffer for the current payload.
     payload: Vec<u8>,
+    // Longest payload accepted, longer messages are rejected before being buffered.
+    max_payload_len: usize,
 }
 
 #[derive(PartialEq, Debug)]
@@ -47,10 +49,21 @@ pub enum Error {
     UnexpectedSeq,
     // This packet arrived after a timeout.
     Timeout,
+    // Got an init packet announcing a payload longer than the maximum payload length.
+    InvalidLength,
 }
 
 impl MessageAssembler {
+    // Longest payload that fits in a sequence of HID packets: an initialization packet and 128
+    // continuation packets.
+    pub const MAX_HID_PAYLOAD_LEN: usize = 7609;
+
+    // Creates an assembler for any message that fits in a sequence of HID packets.
     pub fn new() -> MessageAssembler {
+        MessageAssembler::with_max_payload_len(MessageAssembler::MAX_HID_PAYLOAD_LEN)
+    }
+
+    pub fn with_max_payload_len(max_payload_len: usize) -> MessageAssembler {
         MessageAssembler {
             idle: true,
             cid: [0, 0, 0, 0],
@@ -59,6 +72,7 @@ impl MessageAssembler {
             seq: 0,
             remaining_payload_len: 0,
             payload: Vec::new(),
+            max_payload_len,
         }
     }
 
@@ -74,6 +88,16 @@ impl MessageAssembler {
         self.payload.clear();
     }
 
+    // Returns the channel of the message being assembled, if a message was started but not
+    // completed.
+    pub fn pending_channel(&self) -> Option<ChannelID> {
+        if self.idle {
+            None
+        } else {
+            Some(self.cid)
+        }
+    }
+
     // Returns:
     // - An Ok() result if the packet was parsed correctly. This contains either Some(Vec<u8>) if a
     // full message was assembled after this packet, or None if more packets are needed to fill the
@@ -107,7 +131,7 @@ impl MessageAssembler {
             // Expecting an initialization packet.
             match processed_packet {
                 ProcessedPacket::InitPacket { cmd, len, data } => {
-                    Ok(self.accept_init_packet(*cid, cmd, len, data, timestamp))
+                    self.accept_init_packet(*cid, cmd, len, data, timestamp)
                 }
                 ProcessedPacket::ContinuationPacket { .. } => {
                     // CTAP specification (version 20190130) section 8.1.5.4
@@ -129,7 +153,7 @@ impl MessageAssembler {
                 ProcessedPacket::InitPacket { cmd, len, data } => {
                     self.reset();
                     if cmd == CtapHid::COMMAND_INIT {
-                        Ok(self.accept_init_packet(*cid, cmd, len, data, timestamp))
+                        self.accept_init_packet(*cid, cmd, len, data, timestamp)
                     } else {
                         Err((*cid, Error::UnexpectedInit))
                     }
@@ -158,17 +182,27 @@ impl MessageAssembler {
         len: usize,
         data: &[u8],
         timestamp: Timestamp<isize>,
-    ) -> Option<Message> {
-        // TODO: Should invalid commands/payload lengths be rejected early, i.e. as soon as the
-        // initialization packet is received, or should we build a message and then catch the
-        // error?
+    ) -> Result<Option<Message>, (ChannelID, Error)> {
+        // Payloads longer than what we can process are rejected early, so that no buffer is
+        // allocated for them. The assembler stays idle and ignores the continuation packets.
+        // PING exercises the transport, so it accepts the longest payload the packets can carry.
+        let max_payload_len = if cmd == CtapHid::COMMAND_PING {
+            MessageAssembler::MAX_HID_PAYLOAD_LEN
+        } else {
+            self.max_payload_len
+        };
+        if len > max_payload_len {
+            return Err((cid, Error::InvalidLength));
+        }
+        // TODO: Should invalid commands be rejected early, i.e. as soon as the initialization
+        // packet is received, or should we build a message and then catch the error?
         // The specification (version 20190130) isn't clear on this point.
         self.cid = cid;
         self.last_timestamp = timestamp;
         self.cmd = cmd;
         self.seq = 0;
         self.remaining_payload_len = len;
-        self.append_payload(data)
+        Ok(self.append_payload(data))
     }
 
     fn append_payload(&mut self, data: &[u8]) -> Option<Message> {
@@ -352,6 +386,84 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_payload_too_long() {
+        let mut assembler = MessageAssembler::with_max_payload_len(0x40);
+        assert_eq!(
+            assembler.parse_packet(
+                &zero_extend(&[0x12, 0x34, 0x56, 0x78, 0x90, 0x00, 0x41]),
+                DUMMY_TIMESTAMP
+            ),
+            Err(([0x12, 0x34, 0x56, 0x78], Error::InvalidLength))
+        );
+        // The continuation packets of the rejected message are ignored.
+        assert_eq!(
+            assembler.parse_packet(
+                &zero_extend(&[0x12, 0x34, 0x56, 0x78, 0x00]),
+                DUMMY_TIMESTAMP
+            ),
+            Err(([0x12, 0x34, 0x56, 0x78], Error::UnexpectedContinuation))
+        );
+        assert_eq!(
+            assembler.parse_packet(
+                &zero_extend(&[0x12, 0x34, 0x56, 0x78, 0x90, 0x00, 0x40]),
+                DUMMY_TIMESTAMP
+            ),
+            Ok(None)
+        );
+        assert_eq!(
+            assembler.parse_packet(
+                &zero_extend(&[0x12, 0x34, 0x56, 0x78, 0x00]),
+                DUMMY_TIMESTAMP
+            ),
+            Ok(Some(Message {
+                cid: [0x12, 0x34, 0x56, 0x78],
+                cmd: 0x10,
+                payload: vec![0x00; 0x40]
+            }))
+        );
+    }
+
+    #[test]
+    fn test_ping_ignores_max_payload_len() {
+        let mut assembler = MessageAssembler::with_max_payload_len(0x40);
+        assert_eq!(
+            assembler.parse_packet(
+                &zero_extend(&[0x12, 0x34, 0x56, 0x78, 0x81, 0x1D, 0xB9]),
+                DUMMY_TIMESTAMP
+            ),
+            Ok(None)
+        );
+        for seq in 0..0x7F {
+            assert_eq!(
+                assembler.parse_packet(
+                    &zero_extend(&[0x12, 0x34, 0x56, 0x78, seq]),
+                    DUMMY_TIMESTAMP
+                ),
+                Ok(None)
+            );
+        }
+        assert_eq!(
+            assembler.parse_packet(
+                &zero_extend(&[0x12, 0x34, 0x56, 0x78, 0x7F]),
+                DUMMY_TIMESTAMP
+            ),
+            Ok(Some(Message {
+                cid: [0x12, 0x34, 0x56, 0x78],
+                cmd: 0x01,
+                payload: vec![0x00; 0x1DB9]
+            }))
+        );
+        // Longer payloads don't fit in the HID packets.
+        assert_eq!(
+            assembler.parse_packet(
+                &zero_extend(&[0x12, 0x34, 0x56, 0x78, 0x81, 0x1D, 0xBA]),
+                DUMMY_TIMESTAMP
+            ),
+            Err(([0x12, 0x34, 0x56, 0x78], Error::InvalidLength))
+        );
+    }
+
     #[test]
     fn test_multiple_messages() {
         // Check that after yielding a message, the assembler is ready to process new messages.
@@ -0,0 +1,129 @@
+// Copyright 2019 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::HidPacket;
+use alloc::vec::Vec;
+use core::mem::size_of;
+use libtock_drivers::timer::ClockValue;
+
+// Traffic counters of the HID transport, to diagnose flaky hosts.
+//
+// The counters cover the window since the last report, which starts with the first packet.
+#[derive(Default)]
+pub struct HidStats {
+    // Time of the first packet of the window.
+    start: Option<ClockValue>,
+    packets_received: u32,
+    packets_sent: u32,
+    // Packets that were received but not assembled, or that could not be sent.
+    packets_dropped: u32,
+}
+
+impl HidStats {
+    // Length of the report payload: 6 big-endian counters.
+    pub const REPORT_LEN: usize = 6 * size_of::<u32>();
+
+    pub fn record_received(&mut self, now: ClockValue) {
+        self.start.get_or_insert(now);
+        self.packets_received = self.packets_received.saturating_add(1);
+    }
+
+    pub fn record_sent(&mut self, now: ClockValue) {
+        self.start.get_or_insert(now);
+        self.packets_sent = self.packets_sent.saturating_add(1);
+    }
+
+    pub fn record_dropped(&mut self) {
+        self.packets_dropped = self.packets_dropped.saturating_add(1);
+    }
+
+    // Returns the report of the current window, and starts a new one.
+    //
+    // The report contains, in order:
+    // - the duration of the window in milliseconds,
+    // - the bytes received and sent per second,
+    // - the number of packets received, sent and dropped.
+    pub fn report(&mut self, now: ClockValue) -> Vec<u8> {
+        let elapsed_ms = self
+            .start
+            .and_then(|start| now.wrapping_sub(start))
+            .map_or(0, |duration| duration.ms().max(0) as u64);
+        let bytes_per_second = |packets: u32| {
+            let bytes = packets as u64 * size_of::<HidPacket>() as u64;
+            match elapsed_ms {
+                0 => 0,
+                _ => bytes * 1000 / elapsed_ms,
+            }
+        };
+        let counters = [
+            elapsed_ms,
+            bytes_per_second(self.packets_received),
+            bytes_per_second(self.packets_sent),
+            self.packets_received as u64,
+            self.packets_sent as u64,
+            self.packets_dropped as u64,
+        ];
+        *self = HidStats::default();
+        let mut report = Vec::with_capacity(HidStats::REPORT_LEN);
+        for counter in counters.iter() {
+            let counter = core::cmp::min(*counter, u32::MAX as u64) as u32;
+            report.extend_from_slice(&counter.to_be_bytes());
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLOCK_FREQUENCY_HZ: usize = 32768;
+
+    fn clock_value(ms: isize) -> ClockValue {
+        ClockValue::new(ms * CLOCK_FREQUENCY_HZ as isize / 1000, CLOCK_FREQUENCY_HZ)
+    }
+
+    fn counters(report: &[u8]) -> Vec<u32> {
+        assert_eq!(report.len(), HidStats::REPORT_LEN);
+        report
+            .chunks(4)
+            .map(|chunk| u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect()
+    }
+
+    #[test]
+    fn test_report() {
+        let mut stats = HidStats::default();
+        assert_eq!(counters(&stats.report(clock_value(1000))), vec![0; 6]);
+
+        for _ in 0..100 {
+            stats.record_received(clock_value(1000));
+        }
+        for _ in 0..50 {
+            stats.record_sent(clock_value(1500));
+        }
+        stats.record_dropped();
+        assert_eq!(
+            counters(&stats.report(clock_value(3000))),
+            vec![2000, 3200, 1600, 100, 50, 1]
+        );
+
+        // The report starts a new window.
+        stats.record_received(clock_value(4000));
+        assert_eq!(
+            counters(&stats.report(clock_value(4500))),
+            vec![500, 128, 0, 1, 0, 0]
+        );
+    }
+}
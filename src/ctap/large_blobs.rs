@@ -0,0 +1,372 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::command::AuthenticatorLargeBlobsParameters;
+use super::response::{AuthenticatorLargeBlobsResponse, ResponseData};
+use super::status_code::Ctap2StatusCode;
+use super::storage::PersistentStore;
+use super::{Sha256, MAX_MSG_SIZE};
+use alloc::vec::Vec;
+use core::mem;
+use crypto::Hash256;
+
+// The maximum size of the serialized large blob array, advertised in GetInfo. The specification
+// requires at least 1024 bytes. The array is kept in flash, and in RAM while it is written.
+pub const MAX_LARGE_BLOB_ARRAY_SIZE: usize = 2048;
+// Fragments leave 64 bytes of the message for the rest of the command.
+const MAX_FRAGMENT_LENGTH: usize = MAX_MSG_SIZE - 64;
+// The serialized array ends with the first 16 bytes of the SHA-256 of the preceding bytes.
+const TRUNCATED_HASH_LENGTH: usize = 16;
+
+/// Reads and writes of the large blob array, which span several commands.
+#[derive(Default)]
+pub struct LargeBlobs {
+    // The fragments received so far.
+    buffer: Vec<u8>,
+    // The length announced in the first fragment.
+    expected_length: usize,
+}
+
+impl LargeBlobs {
+    pub fn new() -> LargeBlobs {
+        LargeBlobs::default()
+    }
+
+    /// Processes the authenticatorLargeBlobs command.
+    ///
+    /// Writes must be authorized by the caller beforehand.
+    pub fn process_command(
+        &mut self,
+        persistent_store: &mut PersistentStore,
+        large_blobs_params: AuthenticatorLargeBlobsParameters,
+    ) -> Result<ResponseData, Ctap2StatusCode> {
+        let AuthenticatorLargeBlobsParameters {
+            get,
+            set,
+            offset,
+            length,
+            ..
+        } = large_blobs_params;
+
+        match (get, set) {
+            (Some(get), None) => {
+                if get > MAX_FRAGMENT_LENGTH {
+                    return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH);
+                }
+                let large_blob_array = persistent_store.large_blob_array()?;
+                if offset > large_blob_array.len() {
+                    return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+                }
+                let end = core::cmp::min(offset + get, large_blob_array.len());
+                Ok(ResponseData::AuthenticatorLargeBlobs(Some(
+                    AuthenticatorLargeBlobsResponse {
+                        config: large_blob_array[offset..end].to_vec(),
+                    },
+                )))
+            }
+            (None, Some(set)) => {
+                self.write_fragment(persistent_store, set, offset, length)?;
+                Ok(ResponseData::AuthenticatorLargeBlobs(None))
+            }
+            // Parsing ensures that exactly one of get and set is present.
+            _ => Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER),
+        }
+    }
+
+    // Appends a fragment to the array being written, and commits the array once complete.
+    fn write_fragment(
+        &mut self,
+        persistent_store: &mut PersistentStore,
+        mut fragment: Vec<u8>,
+        offset: usize,
+        length: Option<usize>,
+    ) -> Result<(), Ctap2StatusCode> {
+        if fragment.len() > MAX_FRAGMENT_LENGTH {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH);
+        }
+        if offset == 0 {
+            // The first fragment starts a new write, discarding any unfinished one.
+            let length = length.ok_or(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)?;
+            if length > MAX_LARGE_BLOB_ARRAY_SIZE {
+                return Err(Ctap2StatusCode::CTAP2_ERR_LARGE_BLOB_STORAGE_FULL);
+            }
+            if length <= TRUNCATED_HASH_LENGTH {
+                return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+            }
+            self.buffer = Vec::with_capacity(length);
+            self.expected_length = length;
+        } else if length.is_some() {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+        }
+        if offset != self.buffer.len() {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_SEQ);
+        }
+        if offset + fragment.len() > self.expected_length {
+            return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+        }
+        self.buffer.append(&mut fragment);
+        if self.buffer.len() < self.expected_length {
+            return Ok(());
+        }
+
+        let large_blob_array = mem::take(&mut self.buffer);
+        self.expected_length = 0;
+        let hash_offset = large_blob_array.len() - TRUNCATED_HASH_LENGTH;
+        let (array, truncated_hash) = large_blob_array.split_at(hash_offset);
+        if truncated_hash != &Sha256::hash(array)[..TRUNCATED_HASH_LENGTH] {
+            return Err(Ctap2StatusCode::CTAP2_ERR_INTEGRITY_FAILURE);
+        }
+        persistent_store.commit_large_blob_array(&large_blob_array)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+    use crypto::rng256::ThreadRng256;
+
+    fn get_params(get: usize, offset: usize) -> AuthenticatorLargeBlobsParameters {
+        AuthenticatorLargeBlobsParameters {
+            get: Some(get),
+            set: None,
+            offset,
+            length: None,
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        }
+    }
+
+    fn set_params(
+        set: &[u8],
+        offset: usize,
+        length: Option<usize>,
+    ) -> AuthenticatorLargeBlobsParameters {
+        AuthenticatorLargeBlobsParameters {
+            get: None,
+            set: Some(set.to_vec()),
+            offset,
+            length,
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        }
+    }
+
+    // Returns a serialized large blob array of the given length, with a valid hash.
+    fn create_large_blob_array(length: usize) -> Vec<u8> {
+        let mut large_blob_array = vec![0x5B; length - TRUNCATED_HASH_LENGTH];
+        let hash = Sha256::hash(&large_blob_array);
+        large_blob_array.extend_from_slice(&hash[..TRUNCATED_HASH_LENGTH]);
+        large_blob_array
+    }
+
+    fn read(large_blobs: &mut LargeBlobs, persistent_store: &mut PersistentStore) -> Vec<u8> {
+        let mut large_blob_array = Vec::new();
+        loop {
+            let params = get_params(MAX_FRAGMENT_LENGTH, large_blob_array.len());
+            match large_blobs.process_command(persistent_store, params) {
+                Ok(ResponseData::AuthenticatorLargeBlobs(Some(response))) => {
+                    if response.config.is_empty() {
+                        return large_blob_array;
+                    }
+                    large_blob_array.extend(response.config);
+                }
+                _ => panic!("Invalid response"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_initial_array() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut large_blobs = LargeBlobs::new();
+
+        let large_blob_array = read(&mut large_blobs, &mut persistent_store);
+        assert_eq!(
+            large_blob_array,
+            persistent_store.large_blob_array().unwrap()
+        );
+        assert_eq!(
+            large_blobs.process_command(&mut persistent_store, get_params(1, 18)),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+        assert_eq!(
+            large_blobs.process_command(
+                &mut persistent_store,
+                get_params(MAX_FRAGMENT_LENGTH + 1, 0)
+            ),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH)
+        );
+    }
+
+    #[test]
+    fn test_write_in_fragments() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut large_blobs = LargeBlobs::new();
+
+        let large_blob_array = create_large_blob_array(MAX_LARGE_BLOB_ARRAY_SIZE);
+        let mut offset = 0;
+        for fragment in large_blob_array.chunks(MAX_FRAGMENT_LENGTH) {
+            let length = if offset == 0 {
+                Some(large_blob_array.len())
+            } else {
+                None
+            };
+            assert_eq!(
+                large_blobs
+                    .process_command(&mut persistent_store, set_params(fragment, offset, length)),
+                Ok(ResponseData::AuthenticatorLargeBlobs(None))
+            );
+            offset += fragment.len();
+        }
+        assert_eq!(
+            read(&mut large_blobs, &mut persistent_store),
+            large_blob_array
+        );
+    }
+
+    #[test]
+    fn test_write_restarts_at_offset_zero() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut large_blobs = LargeBlobs::new();
+
+        let large_blob_array = create_large_blob_array(40);
+        assert!(large_blobs
+            .process_command(&mut persistent_store, set_params(&[0x00; 10], 0, Some(100)))
+            .is_ok());
+        assert!(large_blobs
+            .process_command(
+                &mut persistent_store,
+                set_params(&large_blob_array, 0, Some(40))
+            )
+            .is_ok());
+        assert_eq!(
+            read(&mut large_blobs, &mut persistent_store),
+            large_blob_array
+        );
+    }
+
+    #[test]
+    fn test_write_invalid_sequence() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut large_blobs = LargeBlobs::new();
+        let large_blob_array = create_large_blob_array(40);
+
+        // A write can't start after offset 0.
+        assert_eq!(
+            large_blobs.process_command(
+                &mut persistent_store,
+                set_params(&large_blob_array[20..], 20, None)
+            ),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_SEQ)
+        );
+        assert!(large_blobs
+            .process_command(
+                &mut persistent_store,
+                set_params(&large_blob_array[..20], 0, Some(40))
+            )
+            .is_ok());
+        // Fragments can't be skipped or repeated.
+        assert_eq!(
+            large_blobs.process_command(
+                &mut persistent_store,
+                set_params(&large_blob_array[30..], 30, None)
+            ),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_SEQ)
+        );
+        assert_eq!(
+            large_blobs.process_command(
+                &mut persistent_store,
+                set_params(&large_blob_array[10..20], 10, None)
+            ),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_SEQ)
+        );
+        // Only the first fragment has a length.
+        assert_eq!(
+            large_blobs.process_command(
+                &mut persistent_store,
+                set_params(&large_blob_array[20..], 20, Some(40))
+            ),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+        // Fragments can't exceed the announced length.
+        assert_eq!(
+            large_blobs.process_command(&mut persistent_store, set_params(&[0x00; 21], 20, None)),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+        assert!(large_blobs
+            .process_command(
+                &mut persistent_store,
+                set_params(&large_blob_array[20..], 20, None)
+            )
+            .is_ok());
+        assert_eq!(
+            read(&mut large_blobs, &mut persistent_store),
+            large_blob_array
+        );
+    }
+
+    #[test]
+    fn test_write_invalid_length() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut large_blobs = LargeBlobs::new();
+
+        assert_eq!(
+            large_blobs.process_command(&mut persistent_store, set_params(&[0x80], 0, None)),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+        assert_eq!(
+            large_blobs.process_command(&mut persistent_store, set_params(&[0x80], 0, Some(16))),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+        assert_eq!(
+            large_blobs.process_command(
+                &mut persistent_store,
+                set_params(&[0x80], 0, Some(MAX_LARGE_BLOB_ARRAY_SIZE + 1))
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_LARGE_BLOB_STORAGE_FULL)
+        );
+        assert_eq!(
+            large_blobs.process_command(
+                &mut persistent_store,
+                set_params(&[0x80; MAX_FRAGMENT_LENGTH + 1], 0, Some(2000))
+            ),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_LENGTH)
+        );
+    }
+
+    #[test]
+    fn test_write_integrity_failure() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut large_blobs = LargeBlobs::new();
+        let initial_array = persistent_store.large_blob_array().unwrap();
+
+        let mut large_blob_array = create_large_blob_array(40);
+        large_blob_array[0] ^= 0x01;
+        assert_eq!(
+            large_blobs.process_command(
+                &mut persistent_store,
+                set_params(&large_blob_array, 0, Some(40))
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_INTEGRITY_FAILURE)
+        );
+        assert_eq!(read(&mut large_blobs, &mut persistent_store), initial_array);
+    }
+}
@@ -13,59 +13,101 @@
 // limitations under the License.
 
 pub mod apdu;
+mod capabilities;
 pub mod command;
+#[macro_use]
+mod constants;
 #[cfg(feature = "with_ctap1")]
 mod ctap1;
 pub mod data_formats;
+pub mod display;
 pub mod hid;
 mod key_material;
+#[cfg(feature = "with_ctap2_1")]
+mod large_blobs;
+#[cfg(feature = "perf")]
+pub mod perf;
+pub mod pin_policy;
 mod pin_protocol_v1;
+mod pin_token;
 pub mod response;
+mod self_test;
 pub mod status_code;
 mod storage;
 mod timed_permission;
+#[cfg(feature = "with_ctap2_1")]
+mod token_state;
+pub mod user_presence;
+mod version;
+pub mod watchdog;
 
+use self::capabilities::CAPABILITIES;
 #[cfg(feature = "with_ctap2_1")]
 use self::command::MAX_CREDENTIAL_COUNT_IN_LIST;
 use self::command::{
     AuthenticatorClientPinParameters, AuthenticatorGetAssertionParameters,
-    AuthenticatorMakeCredentialParameters, AuthenticatorVendorConfigureParameters, Command,
+    AuthenticatorMakeCredentialParameters, AuthenticatorVendorAuditLogParameters,
+    AuthenticatorVendorConfigureParameters, AuthenticatorVendorDisplayNameParameters,
+    AuthenticatorVendorIdentifyParameters, AuthenticatorVendorParameters, Command,
 };
 #[cfg(feature = "with_ctap2_1")]
-use self::data_formats::AuthenticatorTransport;
+use self::command::{AuthenticatorConfigParameters, AuthenticatorLargeBlobsParameters};
+use self::constants::{CommandCode, Extension};
+#[cfg(feature = "with_ctap2_1")]
+use self::data_formats::ConfigSubCommand;
 use self::data_formats::{
-    CredentialProtectionPolicy, GetAssertionHmacSecretInput, PackedAttestationStatement,
-    PublicKeyCredentialDescriptor, PublicKeyCredentialParameter, PublicKeyCredentialSource,
-    PublicKeyCredentialType, PublicKeyCredentialUserEntity, SignatureAlgorithm,
+    AttestationFormat, AttestationStatement, AuditEventType, CredentialProtectionPolicy,
+    ExtensionOutputs, FidoU2fAttestationStatement, GetAssertionHmacSecretInput,
+    PackedAttestationStatement, PublicKeyCredentialDescriptor, PublicKeyCredentialParameter,
+    PublicKeyCredentialSource, PublicKeyCredentialType, PublicKeyCredentialUserEntity,
+    SignatureAlgorithm, UsbIdentity, VendorSubCommand,
 };
+use self::display::{Display, Prompt};
 use self::hid::ChannelID;
 #[cfg(feature = "with_ctap2_1")]
+use self::large_blobs::{LargeBlobs, MAX_LARGE_BLOB_ARRAY_SIZE};
+#[cfg(feature = "perf")]
+use self::perf::{CommandTimings, PerfClock};
+use self::pin_policy::PinPolicy;
+#[cfg(feature = "with_ctap2_1")]
 use self::pin_protocol_v1::PinPermission;
 use self::pin_protocol_v1::PinProtocolV1;
+#[cfg(feature = "perf")]
+use self::response::AuthenticatorVendorCommandTimingsResponse;
 use self::response::{
     AuthenticatorGetAssertionResponse, AuthenticatorGetInfoResponse,
-    AuthenticatorMakeCredentialResponse, AuthenticatorVendorResponse, ResponseData,
+    AuthenticatorMakeCredentialResponse, AuthenticatorVendorAuditLogResponse,
+    AuthenticatorVendorConfigureResponse, AuthenticatorVendorMetricsResponse,
+    AuthenticatorVendorResponse, AuthenticatorVendorSelfTestResponse, ResponseData,
+    StoreCheckReport,
 };
 use self::status_code::Ctap2StatusCode;
 use self::storage::PersistentStore;
+#[cfg(feature = "with_ctap2_1")]
+use self::storage::MAX_RP_IDS_LENGTH;
 use self::timed_permission::TimedPermission;
 #[cfg(feature = "with_ctap1")]
 use self::timed_permission::U2fUserPresenceState;
+#[cfg(feature = "debug_ctap")]
+use self::user_presence::DebugUserPresence;
+use self::watchdog::Watchdog;
+use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::string::{String, ToString};
 use alloc::vec;
 use alloc::vec::Vec;
 use arrayref::array_ref;
 use byteorder::{BigEndian, ByteOrder};
-use cbor::{cbor_map, cbor_map_options};
-#[cfg(feature = "debug_ctap")]
+use cbor::cbor_map_options;
+use core::convert::TryFrom;
+#[cfg(any(feature = "debug_ctap", feature = "perf"))]
 use core::fmt::Write;
 use crypto::cbc::{cbc_decrypt, cbc_encrypt};
 use crypto::hmac::{hmac_256, verify_hmac_256};
+use crypto::provider::CryptoProvider;
 use crypto::rng256::Rng256;
-use crypto::sha256::Sha256;
 use crypto::Hash256;
-#[cfg(feature = "debug_ctap")]
+#[cfg(any(feature = "debug_ctap", feature = "perf"))]
 use libtock_drivers::console::Console;
 use libtock_drivers::crp;
 use libtock_drivers::timer::{ClockValue, Duration};
@@ -75,6 +117,14 @@ use libtock_drivers::timer::{ClockValue, Duration};
 // as a batch key. Turn it on if you want attestation. In this case, be aware that
 // it is your responsibility to generate your own key material and keep it secret.
 const USE_BATCH_ATTESTATION: bool = false;
+// The attestation formats of MakeCredential, in order of preference. Platforms may ask for another
+// format of this list. Remove AttestationFormat::Packed to never attest the model of the device.
+// The fido-u2f format is only used with batch attestation.
+const ATTESTATION_FORMATS: &[AttestationFormat] = &[
+    AttestationFormat::Packed,
+    AttestationFormat::None,
+    AttestationFormat::FidoU2f,
+];
 // The signature counter is currently implemented as a global counter, if you set
 // this flag to true. The spec strongly suggests to have per-credential-counters,
 // but it means you can't have an infinite amount of credentials anymore. Also,
@@ -82,34 +132,78 @@ const USE_BATCH_ATTESTATION: bool = false;
 // need a flash storage friendly way to implement this feature. The implemented
 // solution is a compromise to be compatible with U2F and not wasting storage.
 const USE_SIGNATURE_COUNTER: bool = true;
+// Set this flag to true to record coarse events (successful registrations, assertions and resets,
+// as well as PIN failures) in a ring buffer in flash. Each event costs a flash write, which wears
+// the storage faster. The log can be read and cleared with a PIN-protected vendor command.
+const USE_AUDIT_LOG: bool = false;
+// Set this flag to false to stop counting registrations and assertions. Those aggregate counters
+// persist CTAP resets and can be read with a vendor command. They are updated with each successful
+// MakeCredential and GetAssertion command, like the global signature counter.
+const USE_METRICS: bool = true;
+// Set this flag to true to scan the persistent store for inconsistencies at boot, and refuse to
+// boot if any is found. The scan reads the whole storage, which slows down the boot. The same scan
+// is always part of the vendor self test.
+const CHECK_STORE_AT_BOOT: bool = false;
+// Set this flag to false to answer GetAssertion with CTAP2_ERR_NO_CREDENTIALS without asking for
+// user presence, as in CTAP 2.1. By default, user presence is requested first, as in CTAP 2.0, even
+// if no credential matches. Then a missing account and a declining user both wait for the user,
+// and observers can't tell from the timing whether the user has an account at the relying party.
+const USER_PRESENCE_WITHOUT_CREDENTIALS: bool = true;
+// The maximum length of an incoming CTAP message, advertised in GetInfo. Messages are reassembled
+// in RAM, so longer ones are rejected by the HID layer before any buffer is allocated. Must not
+// exceed 7609 bytes, the longest message that fits in a sequence of HID packets.
+pub const MAX_MSG_SIZE: usize = 1024;
 pub const INITIAL_SIGNATURE_COUNTER: u32 = 1;
+// Increase this version with each release that fixes a vulnerability. The highest version that ran
+// is stored in flash, and older firmware refuses to boot, such that an attacker can't roll back to a
+// vulnerable release. It is reported in GetInfo.
+pub const FIRMWARE_VERSION: u64 = 1;
 // Our credential ID consists of
 // - 16 byte initialization vector for AES-256,
 // - 32 byte ECDSA private key for the credential,
 // - 32 byte relying party ID hashed with SHA256,
 // - 32 byte HMAC-SHA256 over everything else.
 pub const CREDENTIAL_ID_SIZE: usize = 112;
+// Set this value to pad credential IDs with random bytes up to a fixed length. The length of
+// credential IDs fingerprints the authenticator model, so choosing a length shared by other
+// authenticators improves privacy. It is advertised in GetInfo. Values from CREDENTIAL_ID_SIZE to
+// 255 are allowed, the longest key handle in U2F.
+const PADDED_CREDENTIAL_ID_SIZE: usize = CREDENTIAL_ID_SIZE;
+// Prefix of the message signed with the attestation private key to prove its possession at
+// provisioning.
+const ATTESTATION_CHALLENGE_PREFIX: &[u8] = b"OpenSK attestation key possession";
+// User name, display name and icon are truncated to this many bytes before being stored, as
+// allowed for authenticators with limited storage. The stored values are returned as is.
+const MAX_USER_FIELD_LENGTH: usize = 64;
+// Display names of credentials are truncated to this many bytes, like user fields in storage.
+const MAX_DISPLAY_NAME_LENGTH: usize = 64;
 // Set this bit when checking user presence.
 const UP_FLAG: u8 = 0x01;
 // Set this bit when checking user verification.
 const UV_FLAG: u8 = 0x04;
+// Set this bit when credentials are eligible for backup.
+const BE_FLAG: u8 = 0x08;
+// Set this bit when credentials are currently backed up.
+const BS_FLAG: u8 = 0x10;
 // Set this bit when performing attestation.
 const AT_FLAG: u8 = 0x40;
 // Set this bit when an extension is used.
 const ED_FLAG: u8 = 0x80;
+// Credentials never leave this authenticator, so they are neither eligible for backup nor backed
+// up. Builds that sync or back up credentials can change these values. A credential can only be
+// backed up if it is eligible, so BACKUP_STATE requires BACKUP_ELIGIBILITY.
+const BACKUP_ELIGIBILITY: bool = false;
+const BACKUP_STATE: bool = false;
 
 pub const TOUCH_TIMEOUT_MS: isize = 30000;
 #[cfg(feature = "with_ctap1")]
 const U2F_UP_PROMPT_TIMEOUT: Duration<isize> = Duration::from_ms(10000);
 const RESET_TIMEOUT_DURATION: Duration<isize> = Duration::from_ms(10000);
 const STATEFUL_COMMAND_TIMEOUT_DURATION: Duration<isize> = Duration::from_ms(30000);
+// The identify vendor command blinks the LEDs for at most this long, whatever it asks for.
+const MAX_IDENTIFY_DURATION: Duration<isize> = Duration::from_ms(60000);
 
-pub const FIDO2_VERSION_STRING: &str = "FIDO_2_0";
-#[cfg(feature = "with_ctap1")]
 pub const U2F_VERSION_STRING: &str = "U2F_V2";
-// TODO(#106) change to final string when ready
-#[cfg(feature = "with_ctap2_1")]
-pub const FIDO2_1_VERSION_STRING: &str = "FIDO_2_1_PRE";
 
 // We currently only support one algorithm for signatures: ES256.
 // This algorithm is requested in MakeCredential and advertized in GetInfo.
@@ -121,6 +215,33 @@ pub const ES256_CRED_PARAM: PublicKeyCredentialParameter = PublicKeyCredentialPa
 // - Some(CredentialProtectionPolicy::UserVerificationOptionalWithCredentialIdList)
 // - Some(CredentialProtectionPolicy::UserVerificationRequired)
 const DEFAULT_CRED_PROTECT: Option<CredentialProtectionPolicy> = None;
+// The implementation of the cryptographic primitives. Boards with a hardware accelerator can
+// select their own provider here, see `crypto::provider::CryptoProvider`. Desktop builds use the
+// host provider, which cross-validates the software implementation with the ring crate.
+#[cfg(not(feature = "std"))]
+type Crypto = crypto::provider::SoftwareCryptoProvider;
+#[cfg(feature = "std")]
+type Crypto = crypto::provider::HostCryptoProvider;
+type Sha256 = <Crypto as CryptoProvider>::Sha256;
+// Those values are only reported in GetInfo, for a complete CTAP 2.1 response. Fill them in to
+// describe your product, for example before certification:
+// - PREFERRED_PLATFORM_UV_ATTEMPTS: how often the platform should retry built-in user
+//   verification before falling back to the PIN, e.g. Some(3).
+// - UV_MODALITY: the user verification methods as a bit field from the FIDO registry, e.g.
+//   Some(0x02) for a fingerprint sensor. OpenSK has no built-in user verification.
+// - CERTIFICATIONS: pairs of certification name and level, e.g. &[("FIDO", 1)].
+// Set this flag to allow non-discoverable credentials without user verification, even if a PIN is
+// set. It is reported as the makeCredUvNotRqd option in GetInfo, so that platforms can register
+// second factor credentials with a touch only. Discoverable credentials still require the PIN, and
+// the alwaysUv option takes precedence over this flag.
+#[cfg(feature = "with_ctap2_1")]
+const MAKE_CRED_UV_NOT_RQD: bool = true;
+#[cfg(feature = "with_ctap2_1")]
+const PREFERRED_PLATFORM_UV_ATTEMPTS: Option<u64> = None;
+#[cfg(feature = "with_ctap2_1")]
+const UV_MODALITY: Option<u64> = None;
+#[cfg(feature = "with_ctap2_1")]
+const CERTIFICATIONS: &[(&str, u64)] = &[];
 
 // This function is adapted from https://doc.rust-lang.org/nightly/src/core/str/mod.rs.html#2110
 // (as of 2020-01-20) and truncates to "max" bytes, not breaking the encoding.
@@ -136,11 +257,63 @@ fn truncate_to_char_boundary(s: &str, mut max: usize) -> &str {
     }
 }
 
+// Crops a user provided entity field for storage, keeping the UTF-8 encoding valid.
+fn truncate_user_field(field: Option<String>) -> Option<String> {
+    field.map(|mut s| {
+        let length = truncate_to_char_boundary(&s, MAX_USER_FIELD_LENGTH).len();
+        s.truncate(length);
+        s
+    })
+}
+
+// Returns the BE and BS bits of the authenticator data flags, as configured above.
+fn backup_flags() -> u8 {
+    let mut flags = 0;
+    if BACKUP_ELIGIBILITY {
+        flags |= BE_FLAG;
+    }
+    if BACKUP_STATE {
+        flags |= BS_FLAG;
+    }
+    flags
+}
+
+// Returns the first format of the platform preference that the policy allows, or else the first
+// allowed format of the policy. Without any allowed format, the attestation is "none".
+fn select_attestation_format(
+    policy: &[AttestationFormat],
+    preference: &[AttestationFormat],
+    has_batch_attestation: bool,
+) -> AttestationFormat {
+    let is_allowed = |format: &&AttestationFormat| {
+        policy.contains(format) && (**format != AttestationFormat::FidoU2f || has_batch_attestation)
+    };
+    preference
+        .iter()
+        .find(is_allowed)
+        .or_else(|| policy.iter().find(is_allowed))
+        .cloned()
+        .unwrap_or(AttestationFormat::None)
+}
+
+// Returns the audit event to record for a command response, if any.
+fn audit_event_type(response: &Result<ResponseData, Ctap2StatusCode>) -> Option<AuditEventType> {
+    match response {
+        Ok(ResponseData::AuthenticatorMakeCredential(_)) => Some(AuditEventType::MakeCredential),
+        Ok(ResponseData::AuthenticatorGetAssertion(_)) => Some(AuditEventType::GetAssertion),
+        Ok(ResponseData::AuthenticatorReset) => Some(AuditEventType::Reset),
+        Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID)
+        | Err(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED) => Some(AuditEventType::PinFailure),
+        _ => None,
+    }
+}
+
 #[derive(Clone)]
 struct AssertionInput {
     client_data_hash: Vec<u8>,
     auth_data: Vec<u8>,
     hmac_secret_input: Option<GetAssertionHmacSecretInput>,
+    tx_auth_simple: Option<String>,
     has_uv: bool,
 }
 
@@ -163,13 +336,31 @@ pub struct CtapState<'a, R: Rng256, CheckUserPresence: Fn(ChannelID) -> Result<(
     // A function to check user presence, ultimately returning true if user presence was detected,
     // false otherwise.
     check_user_presence: CheckUserPresence,
+    // User presence granted by a vendor command, checked before the function above.
+    #[cfg(feature = "debug_ctap")]
+    debug_user_presence: DebugUserPresence,
+    // The screen showing what user presence is checked for, if the board has one.
+    display: Option<Box<dyn Display>>,
+    // Resets the device if the firmware hangs, if the board has one.
+    watchdog: Option<Box<dyn Watchdog>>,
+    // Reads the time to measure the latency of commands, if set.
+    #[cfg(feature = "perf")]
+    perf_clock: Option<Box<dyn PerfClock>>,
+    // The latency of each command since boot.
+    #[cfg(feature = "perf")]
+    command_timings: CommandTimings,
     persistent_store: PersistentStore,
     pin_protocol_v1: PinProtocolV1,
+    // The large blob array being written, which may span several commands.
+    #[cfg(feature = "with_ctap2_1")]
+    large_blobs: LargeBlobs,
     #[cfg(feature = "with_ctap1")]
     pub u2f_up_state: U2fUserPresenceState,
     // The state initializes to Reset and its timeout, and never goes back to Reset.
     stateful_command_permission: TimedPermission,
     stateful_command_type: Option<StatefulCommand>,
+    // The LEDs blink to identify the authenticator until this expires.
+    identify_permission: TimedPermission,
 }
 
 impl<'a, R, CheckUserPresence> CtapState<'a, R, CheckUserPresence>
@@ -185,12 +376,61 @@ where
         now: ClockValue,
     ) -> CtapState<'a, R, CheckUserPresence> {
         let persistent_store = PersistentStore::new(rng);
+        CtapState::boot(rng, check_user_presence, persistent_store, now)
+    }
+
+    /// Simulates a power cycle of the authenticator.
+    ///
+    /// Only the persistent store survives, all volatile state (e.g. the PIN token) is lost.
+    #[cfg(feature = "std")]
+    pub fn reboot(self, now: ClockValue) -> CtapState<'a, R, CheckUserPresence> {
+        let persistent_store = self.persistent_store.reboot(self.rng);
+        let mut ctap_state =
+            CtapState::boot(self.rng, self.check_user_presence, persistent_store, now);
+        ctap_state.display = self.display;
+        ctap_state.watchdog = self.watchdog;
+        #[cfg(feature = "perf")]
+        {
+            ctap_state.perf_clock = self.perf_clock;
+        }
+        ctap_state
+    }
+
+    fn boot(
+        rng: &'a mut R,
+        check_user_presence: CheckUserPresence,
+        mut persistent_store: PersistentStore,
+        now: ClockValue,
+    ) -> CtapState<'a, R, CheckUserPresence> {
+        match persistent_store.check_firmware_version(FIRMWARE_VERSION) {
+            Ok(()) => (),
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED) => {
+                panic!("Refusing to boot a firmware older than the one that last ran.")
+            }
+            Err(_) => panic!("Failed to record the firmware version."),
+        }
+        if CHECK_STORE_AT_BOOT {
+            match persistent_store.check() {
+                Ok(check) if check.is_consistent() => (),
+                _ => panic!("The persistent store failed its consistency check."),
+            }
+        }
         let pin_protocol_v1 = PinProtocolV1::new(rng);
         CtapState {
             rng,
             check_user_presence,
+            #[cfg(feature = "debug_ctap")]
+            debug_user_presence: DebugUserPresence::default(),
+            display: None,
+            watchdog: None,
+            #[cfg(feature = "perf")]
+            perf_clock: None,
+            #[cfg(feature = "perf")]
+            command_timings: CommandTimings::default(),
             persistent_store,
             pin_protocol_v1,
+            #[cfg(feature = "with_ctap2_1")]
+            large_blobs: LargeBlobs::new(),
             #[cfg(feature = "with_ctap1")]
             u2f_up_state: U2fUserPresenceState::new(
                 U2F_UP_PROMPT_TIMEOUT,
@@ -198,11 +438,96 @@ where
             ),
             stateful_command_permission: TimedPermission::granted(now, RESET_TIMEOUT_DURATION),
             stateful_command_type: Some(StatefulCommand::Reset),
+            identify_permission: TimedPermission::waiting(),
+        }
+    }
+
+    /// Returns the USB identity override, if any.
+    ///
+    /// The identity of the firmware image is used if the override can't be read.
+    pub fn usb_identity(&self) -> Option<UsbIdentity> {
+        self.persistent_store.usb_identity().unwrap_or(None)
+    }
+
+    /// Sets the screen showing what the user approves when touching the device.
+    pub fn set_display(&mut self, display: Box<dyn Display>) {
+        self.display = Some(display);
+    }
+
+    /// Sets the watchdog, petted by the main loop and before long operations.
+    pub fn set_watchdog(&mut self, watchdog: Box<dyn Watchdog>) {
+        self.watchdog = Some(watchdog);
+    }
+
+    /// Restarts the countdown of the watchdog, if any.
+    pub fn pet_watchdog(&mut self) {
+        if let Some(watchdog) = &mut self.watchdog {
+            watchdog.pet();
+        }
+    }
+
+    /// Sets the clock measuring the latency of each command.
+    #[cfg(feature = "perf")]
+    pub fn set_perf_clock(&mut self, perf_clock: Box<dyn PerfClock>) {
+        self.perf_clock = Some(perf_clock);
+    }
+
+    /// Records the latency of a command started at the given time, and prints its statistics.
+    #[cfg(feature = "perf")]
+    fn record_command_timing(&mut self, command_cbor: &[u8], start: ClockValue) {
+        let (perf_clock, command_code) = match (&self.perf_clock, command_cbor.first()) {
+            (Some(perf_clock), Some(&command_code)) => (perf_clock, command_code),
+            _ => return,
+        };
+        let end = perf_clock.now();
+        if let Some(timing) = self.command_timings.record(command_code, start, end) {
+            writeln!(
+                Console::new(),
+                "Command 0x{:02x}: {} ms (min {} ms, avg {} ms, max {} ms, count {})",
+                command_code,
+                end.wrapping_sub(start).map_or(0, |latency| latency.ms()),
+                timing.min_ms,
+                timing.average_ms(),
+                timing.max_ms,
+                timing.count,
+            )
+            .unwrap();
         }
     }
 
+    /// Sets the policy that new PINs have to satisfy, instead of the one of the specification.
+    pub fn set_pin_policy(&mut self, pin_policy: Box<dyn PinPolicy>) {
+        self.pin_protocol_v1.set_pin_policy(pin_policy);
+    }
+
     pub fn update_command_permission(&mut self, now: ClockValue) {
         self.stateful_command_permission = self.stateful_command_permission.check_expiration(now);
+        // Expired stateful commands are dropped, so that the remaining credentials of an assertion
+        // batch don't stay in memory until the next command.
+        if !self.stateful_command_permission.is_granted(now) {
+            self.stateful_command_type = None;
+        }
+        self.pin_protocol_v1.update_pin_backoff(now);
+        #[cfg(feature = "with_ctap2_1")]
+        self.pin_protocol_v1.update_pin_uv_auth_token_state(now);
+        if !self.is_identifying(now) {
+            self.stop_identify();
+        }
+    }
+
+    /// Returns whether the LEDs should blink to identify the authenticator.
+    pub fn is_identifying(&self, now: ClockValue) -> bool {
+        self.identify_permission.is_granted(now)
+    }
+
+    // Stops identifying the authenticator, if it was. The display is only notified once.
+    fn stop_identify(&mut self) {
+        if let TimedPermission::Granted(_) = self.identify_permission {
+            self.identify_permission = TimedPermission::waiting();
+            if let Some(display) = &mut self.display {
+                display.identify(false);
+            }
+        }
     }
 
     fn check_command_permission(&mut self, now: ClockValue) -> Result<(), Ctap2StatusCode> {
@@ -214,6 +539,93 @@ where
         }
     }
 
+    // Checks user presence for a command, unless the pinUvAuthToken it used cached it. The cached
+    // flags and permissions of the token are consumed either way, as CTAP 2.1 requires.
+    fn check_user_presence_with_token(
+        &mut self,
+        cid: ChannelID,
+        uses_token: bool,
+        prompt: &Prompt,
+    ) -> Result<(), Ctap2StatusCode> {
+        #[cfg(feature = "with_ctap2_1")]
+        {
+            if uses_token {
+                if !self.pin_protocol_v1.get_user_present_flag_value() {
+                    self.check_user_presence_with_prompt(cid, prompt)?;
+                }
+                self.pin_protocol_v1.clear_token_flags_and_permissions();
+                return Ok(());
+            }
+        }
+        #[cfg(not(feature = "with_ctap2_1"))]
+        let _ = uses_token;
+        self.check_user_presence_with_prompt(cid, prompt)
+    }
+
+    // Checks user presence while the display, if any, shows what the user approves.
+    fn check_user_presence_with_prompt(
+        &mut self,
+        cid: ChannelID,
+        prompt: &Prompt,
+    ) -> Result<(), Ctap2StatusCode> {
+        if let Some(display) = &mut self.display {
+            display.show(prompt);
+        }
+        let result = self.check_user_presence(cid);
+        if let Some(display) = &mut self.display {
+            display.clear();
+        }
+        result
+    }
+
+    // Checks user presence while the display shows the transaction text of the RP. The user has to
+    // touch the device even if a pinUvAuthToken cached user presence, since the text is new.
+    fn check_user_presence_with_transaction(
+        &mut self,
+        cid: ChannelID,
+        rp_id: &str,
+        text: &str,
+    ) -> Result<(), Ctap2StatusCode> {
+        let legible = match self
+            .display
+            .as_mut()
+            .and_then(|display| display.transaction_confirmation())
+        {
+            Some(transaction_confirmation) => {
+                transaction_confirmation.show_transaction(rp_id, text)
+            }
+            None => false,
+        };
+        let result = if legible {
+            self.check_user_presence(cid)
+        } else {
+            Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED)
+        };
+        if let Some(display) = &mut self.display {
+            display.clear();
+        }
+        result
+    }
+
+    // Whether the display can show transaction texts, as needed for txAuthSimple.
+    fn supports_tx_auth_simple(&mut self) -> bool {
+        match &mut self.display {
+            Some(display) => display.transaction_confirmation().is_some(),
+            None => false,
+        }
+    }
+
+    // Checks user presence, from the vendor command grants first and then from the hardware.
+    fn check_user_presence(&mut self, cid: ChannelID) -> Result<(), Ctap2StatusCode> {
+        #[cfg(feature = "debug_ctap")]
+        {
+            if self.debug_user_presence.consume() {
+                return Ok(());
+            }
+        }
+        (self.check_user_presence)(cid)
+    }
+
     pub fn increment_global_signature_counter(&mut self) -> Result<(), Ctap2StatusCode> {
         if USE_SIGNATURE_COUNTER {
             let increment = self.rng.gen_uniform_u32x8()[0] % 8 + 1;
@@ -233,7 +645,7 @@ where
         application: &[u8; 32],
     ) -> Result<Vec<u8>, Ctap2StatusCode> {
         let master_keys = self.persistent_store.master_keys()?;
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(&master_keys.encryption);
+        let aes_enc_key = Crypto::aes256_encryption_key(&master_keys.encryption);
         let mut sk_bytes = [0; 32];
         private_key.to_bytes(&mut sk_bytes);
         let mut iv = [0; 16];
@@ -246,29 +658,39 @@ where
         blocks[3].copy_from_slice(&application[16..]);
         cbc_encrypt(&aes_enc_key, iv, &mut blocks);
 
-        let mut encrypted_id = Vec::with_capacity(0x70);
+        let mut encrypted_id = Vec::with_capacity(PADDED_CREDENTIAL_ID_SIZE);
         encrypted_id.extend(&iv);
         for b in &blocks {
             encrypted_id.extend(b);
         }
         let id_hmac = hmac_256::<Sha256>(&master_keys.hmac, &encrypted_id[..]);
         encrypted_id.extend(&id_hmac);
+        while encrypted_id.len() < PADDED_CREDENTIAL_ID_SIZE {
+            let padding = self.rng.gen_uniform_u8x32();
+            let padding_length = core::cmp::min(
+                padding.len(),
+                PADDED_CREDENTIAL_ID_SIZE - encrypted_id.len(),
+            );
+            encrypted_id.extend(&padding[..padding_length]);
+        }
         Ok(encrypted_id)
     }
 
     // Decrypts a credential ID and writes the private key into a PublicKeyCredentialSource.
     // None is returned if the HMAC test fails or the relying party does not match the
-    // decrypted relying party ID hash.
+    // decrypted relying party ID hash. Padding after the HMAC is ignored.
     pub fn decrypt_credential_source(
         &self,
         credential_id: Vec<u8>,
         rp_id_hash: &[u8],
     ) -> Result<Option<PublicKeyCredentialSource>, Ctap2StatusCode> {
-        if credential_id.len() != CREDENTIAL_ID_SIZE {
+        if credential_id.len() < CREDENTIAL_ID_SIZE
+            || credential_id.len() > PADDED_CREDENTIAL_ID_SIZE
+        {
             return Ok(None);
         }
         let master_keys = self.persistent_store.master_keys()?;
-        let payload_size = credential_id.len() - 32;
+        let payload_size = CREDENTIAL_ID_SIZE - 32;
         if !verify_hmac_256::<Sha256>(
             &master_keys.hmac,
             &credential_id[..payload_size],
@@ -276,8 +698,8 @@ where
         ) {
             return Ok(None);
         }
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(&master_keys.encryption);
-        let aes_dec_key = crypto::aes256::DecryptionKey::new(&aes_enc_key);
+        let aes_enc_key = Crypto::aes256_encryption_key(&master_keys.encryption);
+        let aes_dec_key = Crypto::aes256_decryption_key(&aes_enc_key);
         let mut iv = [0; 16];
         iv.copy_from_slice(&credential_id[..16]);
         let mut blocks = [[0u8; 16]; 4];
@@ -308,6 +730,8 @@ where
             creation_order: 0,
             user_name: None,
             user_icon: None,
+            authenticator_display_name: None,
+            transports: None,
         }))
     }
 
@@ -316,6 +740,24 @@ where
         command_cbor: &[u8],
         cid: ChannelID,
         now: ClockValue,
+    ) -> Vec<u8> {
+        #[cfg(feature = "perf")]
+        let start = self.perf_clock.as_ref().map(|perf_clock| perf_clock.now());
+        let response = self.dispatch_command(command_cbor, cid, now);
+        #[cfg(feature = "perf")]
+        {
+            if let Some(start) = start {
+                self.record_command_timing(command_cbor, start);
+            }
+        }
+        response
+    }
+
+    fn dispatch_command(
+        &mut self,
+        command_cbor: &[u8],
+        cid: ChannelID,
+        now: ClockValue,
     ) -> Vec<u8> {
         let cmd = Command::deserialize(command_cbor);
         #[cfg(feature = "debug_ctap")]
@@ -341,11 +783,27 @@ where
                     // AuthenticatorSelection does not reset stateful commands.
                     #[cfg(feature = "with_ctap2_1")]
                     (Command::AuthenticatorSelection, _) => (),
+                    // Granting user presence must not interrupt the command it is meant for.
+                    #[cfg(feature = "debug_ctap")]
+                    (Command::AuthenticatorVendorUserPresence, _) => (),
                     (_, _) => {
                         self.stateful_command_type = None;
                     }
                 }
                 let response = match command {
+                    // The self test reports an unhealthy generator instead of failing.
+                    Command::AuthenticatorVendorSelfTest => self.process_vendor_self_test(),
+                    Command::AuthenticatorVendor(AuthenticatorVendorParameters::SelfTest) => {
+                        self.process_vendor(AuthenticatorVendorParameters::SelfTest, cid, now)
+                    }
+                    // Commands fail closed if the random number generator is broken. They also
+                    // fail if it breaks while processing, so that its output never leaves the
+                    // authenticator.
+                    _ if !self.rng.is_healthy() => Err(Ctap2StatusCode::CTAP2_ERR_PROCESSING),
+                    // Commands that are not advertised are unknown.
+                    _ if !CAPABILITIES.supports_command(&command) => {
+                        Err(Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND)
+                    }
                     Command::AuthenticatorMakeCredential(params) => {
                         self.process_make_credential(params, cid)
                     }
@@ -354,22 +812,70 @@ where
                     }
                     Command::AuthenticatorGetNextAssertion => self.process_get_next_assertion(now),
                     Command::AuthenticatorGetInfo => self.process_get_info(),
-                    Command::AuthenticatorClientPin(params) => self.process_client_pin(params),
+                    Command::AuthenticatorClientPin(params) => self.process_client_pin(params, now),
                     Command::AuthenticatorReset => self.process_reset(cid, now),
                     #[cfg(feature = "with_ctap2_1")]
                     Command::AuthenticatorSelection => self.process_selection(cid),
+                    #[cfg(feature = "with_ctap2_1")]
+                    Command::AuthenticatorLargeBlobs(params) => self.process_large_blobs(params),
+                    #[cfg(feature = "with_ctap2_1")]
+                    Command::AuthenticatorConfig(params) => self.process_config(params, cid),
                     // TODO(kaczmarczyck) implement FIDO 2.1 commands
                     // Vendor specific commands
                     Command::AuthenticatorVendorConfigure(params) => {
                         self.process_vendor_configure(params, cid)
                     }
+                    Command::AuthenticatorVendorAuditLog(params) => {
+                        self.process_vendor_audit_log(params, now)
+                    }
+                    #[cfg(feature = "debug_ctap")]
+                    Command::AuthenticatorVendorUserPresence => self.process_vendor_user_presence(),
+                    Command::AuthenticatorVendorMetrics => self.process_vendor_metrics(),
+                    Command::AuthenticatorVendor(params) => self.process_vendor(params, cid, now),
+                };
+                let rng_failed = match &response {
+                    Ok(ResponseData::AuthenticatorVendorSelfTest(_))
+                    | Ok(ResponseData::AuthenticatorVendor(
+                        AuthenticatorVendorResponse::SelfTest(_),
+                    )) => false,
+                    _ => !self.rng.is_healthy(),
+                };
+                let response = if rng_failed {
+                    Err(Ctap2StatusCode::CTAP2_ERR_PROCESSING)
+                } else {
+                    response
                 };
+                if USE_AUDIT_LOG {
+                    if let Some(event_type) = audit_event_type(&response) {
+                        // The audit log is best effort: failing to record an event does not fail
+                        // the command.
+                        let _ = self
+                            .persistent_store
+                            .append_audit_event(event_type, now.ms() as u64);
+                    }
+                }
+                if USE_METRICS {
+                    // Like the audit log, metrics are best effort.
+                    let _ = match &response {
+                        Ok(ResponseData::AuthenticatorMakeCredential(_)) => {
+                            self.persistent_store.incr_metrics(1, 0)
+                        }
+                        Ok(ResponseData::AuthenticatorGetAssertion(_)) => {
+                            self.persistent_store.incr_metrics(0, 1)
+                        }
+                        _ => Ok(()),
+                    };
+                }
                 #[cfg(feature = "debug_ctap")]
                 writeln!(&mut Console::new(), "Sending response: {:#?}", response).unwrap();
                 match response {
                     Ok(response_data) => {
-                        let mut response_vec = vec![0x00];
-                        if let Some(value) = response_data.into() {
+                        let value: Option<cbor::Value> = response_data.into();
+                        // The response is allocated once, with the status byte and the encoding.
+                        let length = value.as_ref().and_then(cbor::encoded_len).unwrap_or(0);
+                        let mut response_vec = Vec::with_capacity(1 + length);
+                        response_vec.push(0x00);
+                        if let Some(value) = value {
                             if !cbor::write(value, &mut response_vec) {
                                 response_vec = vec![
                                     Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR
@@ -395,7 +901,7 @@ where
         if let Some(auth_param) = &pin_uv_auth_param {
             // This case was added in FIDO 2.1.
             if auth_param.is_empty() {
-                (self.check_user_presence)(cid)?;
+                self.check_user_presence(cid)?;
                 if self.persistent_store.pin_hash()?.is_none() {
                     return Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET);
                 } else {
@@ -413,6 +919,27 @@ where
         }
     }
 
+    // With the alwaysUv option, commands that would otherwise accept user presence alone
+    // require PIN auth instead.
+    #[cfg(feature = "with_ctap2_1")]
+    fn check_always_uv(&self, pin_uv_auth_param: &Option<Vec<u8>>) -> Result<(), Ctap2StatusCode> {
+        if pin_uv_auth_param.is_some() || !self.persistent_store.always_uv()? {
+            return Ok(());
+        }
+        if self.persistent_store.pin_hash()?.is_some() {
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED)
+        } else {
+            // Platforms are expected to ask the user to set a PIN.
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET)
+        }
+    }
+
+    // Returns whether non-discoverable credentials can be made without PIN auth when a PIN is set.
+    #[cfg(feature = "with_ctap2_1")]
+    fn make_cred_uv_not_rqd(&self) -> Result<bool, Ctap2StatusCode> {
+        Ok(MAKE_CRED_UV_NOT_RQD && !self.persistent_store.always_uv()?)
+    }
+
     fn process_make_credential(
         &mut self,
         make_credential_params: AuthenticatorMakeCredentialParameters,
@@ -428,6 +955,7 @@ where
             options,
             pin_uv_auth_param,
             pin_uv_auth_protocol,
+            attestation_formats_preference,
         } = make_credential_params;
 
         self.pin_uv_auth_precheck(&pin_uv_auth_param, pin_uv_auth_protocol, cid)?;
@@ -436,6 +964,26 @@ where
             return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM);
         }
 
+        // The options are checked before any PIN requirement, as in the specification. We have no
+        // built-in user verification, and the uv option is ignored when pinUvAuthParam is present.
+        if options.uv && pin_uv_auth_param.is_none() {
+            return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION);
+        }
+        #[cfg(feature = "with_ctap2_1")]
+        self.check_always_uv(&pin_uv_auth_param)?;
+
+        let rp_id = rp.rp_id;
+        let rp_id_hash = Sha256::hash(rp_id.as_bytes());
+
+        // The minimum PIN length is only returned to RPs allowed by the platform.
+        #[cfg(feature = "with_ctap2_1")]
+        let min_pin_length = if extensions.as_ref().map_or(false, |e| e.min_pin_length)
+            && self.persistent_store.is_min_pin_length_rp_id(&rp_id_hash)?
+        {
+            Some(self.persistent_store.min_pin_length()?)
+        } else {
+            None
+        };
         let (use_hmac_extension, cred_protect_policy) = if let Some(extensions) = extensions {
             let mut cred_protect = extensions.cred_protect;
             if cred_protect.unwrap_or(CredentialProtectionPolicy::UserVerificationOptional)
@@ -449,10 +997,12 @@ where
             (false, DEFAULT_CRED_PROTECT)
         };
 
-        let has_extension_output = use_hmac_extension || cred_protect_policy.is_some();
+        let extension_outputs = ExtensionOutputs::new()
+            .hmac_secret_created(use_hmac_extension)
+            .cred_protect(cred_protect_policy);
+        #[cfg(feature = "with_ctap2_1")]
+        let extension_outputs = extension_outputs.min_pin_length(min_pin_length);
 
-        let rp_id = rp.rp_id;
-        let rp_id_hash = Sha256::hash(rp_id.as_bytes());
         if let Some(exclude_list) = exclude_list {
             for cred_desc in exclude_list {
                 if self
@@ -465,7 +1015,7 @@ where
                 {
                     // Perform this check, so bad actors can't brute force exclude_list
                     // without user interaction.
-                    (self.check_user_presence)(cid)?;
+                    self.check_user_presence(cid)?;
                     return Err(Ctap2StatusCode::CTAP2_ERR_CREDENTIAL_EXCLUDED);
                 }
             }
@@ -473,7 +1023,12 @@ where
 
         // MakeCredential always requires user presence.
         // User verification depends on the PIN auth inputs, which are checked here.
-        let ed_flag = if has_extension_output { ED_FLAG } else { 0 };
+        let ed_flag = if extension_outputs.is_empty() {
+            0
+        } else {
+            ED_FLAG
+        };
+        let has_uv = pin_uv_auth_param.is_some();
         let flags = match pin_uv_auth_param {
             Some(pin_auth) => {
                 if self.persistent_store.pin_hash()?.is_none() {
@@ -491,22 +1046,32 @@ where
                     self.pin_protocol_v1
                         .has_permission(PinPermission::MakeCredential)?;
                     self.pin_protocol_v1.has_permission_for_rp_id(&rp_id)?;
+                    if !self.pin_protocol_v1.get_user_verified_flag_value() {
+                        return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
+                    }
                 }
                 UP_FLAG | UV_FLAG | AT_FLAG | ed_flag
             }
             None => {
-                if self.persistent_store.pin_hash()?.is_some() {
+                #[cfg(feature = "with_ctap2_1")]
+                let uv_not_required = !options.rk && self.make_cred_uv_not_rqd()?;
+                #[cfg(not(feature = "with_ctap2_1"))]
+                let uv_not_required = false;
+                if self.persistent_store.pin_hash()?.is_some() && !uv_not_required {
                     return Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED);
                 }
-                if options.uv {
-                    return Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION);
-                }
                 UP_FLAG | AT_FLAG | ed_flag
             }
-        };
+        } | backup_flags();
 
-        (self.check_user_presence)(cid)?;
+        let prompt = Prompt::MakeCredential {
+            rp_id: &rp_id,
+            rp_name: rp.rp_name.as_deref(),
+            user_name: user.user_name.as_deref(),
+        };
+        self.check_user_presence_with_token(cid, has_uv, &prompt)?;
 
+        self.pet_watchdog();
         let sk = crypto::ecdsa::SecKey::gensk(self.rng);
         let pk = sk.genpk();
 
@@ -518,89 +1083,111 @@ where
                 private_key: sk.clone(),
                 rp_id,
                 user_handle: user.user_id,
-                // This input is user provided, so we crop it to 64 byte for storage.
+                // This input is user provided, so we crop it for storage.
                 // The UTF8 encoding is always preserved, so the string might end up shorter.
-                user_display_name: user
-                    .user_display_name
-                    .map(|s| truncate_to_char_boundary(&s, 64).to_string()),
+                user_display_name: truncate_user_field(user.user_display_name),
                 cred_protect_policy,
                 creation_order: self.persistent_store.new_creation_order()?,
-                user_name: user
-                    .user_name
-                    .map(|s| truncate_to_char_boundary(&s, 64).to_string()),
-                user_icon: user
-                    .user_icon
-                    .map(|s| truncate_to_char_boundary(&s, 64).to_string()),
+                user_name: truncate_user_field(user.user_name),
+                user_icon: truncate_user_field(user.user_icon),
+                authenticator_display_name: None,
+                transports: Some(CAPABILITIES.transports()),
             };
+            // Storing may compact the store.
+            self.pet_watchdog();
             self.persistent_store.store_credential(credential_source)?;
             random_id
         } else {
             self.encrypt_key_handle(sk.clone(), &rp_id_hash)?
         };
 
-        let mut auth_data = self.generate_auth_data(&rp_id_hash, flags)?;
-        auth_data.extend(&self.persistent_store.aaguid()?);
         // The length is fixed to 0x20 or 0x70 and fits one byte.
         if credential_id.len() > 0xFF {
             return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_TOO_LONG);
         }
-        auth_data.extend(vec![0x00, credential_id.len() as u8]);
-        auth_data.extend(&credential_id);
         let cose_key = match pk.to_cose_key() {
             Some(cose_key) => cose_key,
             None => return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR),
         };
+        let mut auth_data = self.generate_auth_data(&rp_id_hash, flags)?;
+        // The attested credential data and extensions are appended without growing again.
+        auth_data.reserve_exact(
+            key_material::AAGUID_LENGTH
+                + 2
+                + credential_id.len()
+                + cose_key.len()
+                + extension_outputs.encoded_len(),
+        );
+        auth_data.extend(&self.persistent_store.aaguid());
+        auth_data.extend(&[0x00, credential_id.len() as u8]);
+        auth_data.extend(&credential_id);
         auth_data.extend(cose_key);
-        if has_extension_output {
-            let hmac_secret_output = if use_hmac_extension { Some(true) } else { None };
-            let extensions_output = cbor_map_options! {
-                "hmac-secret" => hmac_secret_output,
-                "credProtect" => cred_protect_policy,
-            };
-            if !cbor::write(extensions_output, &mut auth_data) {
-                return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR);
-            }
-        }
-
-        let mut signature_data = auth_data.clone();
-        signature_data.extend(client_data_hash);
+        extension_outputs.write(&mut auth_data)?;
 
-        let (signature, x5c) = if USE_BATCH_ATTESTATION {
-            let attestation_private_key = self
-                .persistent_store
-                .attestation_private_key()?
-                .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
-            let attestation_key =
-                crypto::ecdsa::SecKey::from_bytes(&attestation_private_key).unwrap();
-            let attestation_certificate = self
-                .persistent_store
-                .attestation_certificate()?
-                .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
-            (
-                attestation_key.sign_rfc6979::<crypto::sha256::Sha256>(&signature_data),
-                Some(vec![attestation_certificate]),
-            )
-        } else {
-            (
-                sk.sign_rfc6979::<crypto::sha256::Sha256>(&signature_data),
-                None,
-            )
-        };
-        let attestation_statement = PackedAttestationStatement {
-            alg: SignatureAlgorithm::ES256 as i64,
-            sig: signature.to_asn1_der(),
-            x5c,
-            ecdaa_key_id: None,
+        let attestation_format = select_attestation_format(
+            ATTESTATION_FORMATS,
+            attestation_formats_preference.as_deref().unwrap_or(&[]),
+            USE_BATCH_ATTESTATION,
+        );
+        let attestation_statement = match attestation_format {
+            AttestationFormat::None => AttestationStatement::None,
+            AttestationFormat::Packed => {
+                let mut signature_data = auth_data.clone();
+                signature_data.extend(client_data_hash);
+                let (signature, x5c) = if USE_BATCH_ATTESTATION {
+                    let (attestation_key, certificate_chain) = self.batch_attestation()?;
+                    (
+                        Crypto::ecdsa_sign(&attestation_key, &signature_data),
+                        Some(certificate_chain),
+                    )
+                } else {
+                    (Crypto::ecdsa_sign(&sk, &signature_data), None)
+                };
+                AttestationStatement::Packed(PackedAttestationStatement {
+                    alg: SignatureAlgorithm::ES256 as i64,
+                    sig: signature.to_asn1_der(),
+                    x5c,
+                    ecdaa_key_id: None,
+                })
+            }
+            AttestationFormat::FidoU2f => {
+                // The signed data is the same as for U2F registrations.
+                let mut signature_data = vec![0x00];
+                signature_data.extend(&rp_id_hash);
+                signature_data.extend(&client_data_hash);
+                signature_data.extend(&credential_id);
+                signature_data.extend_from_slice(&pk.to_uncompressed());
+                let (attestation_key, mut certificate_chain) = self.batch_attestation()?;
+                // The fido-u2f format only allows the attestation certificate.
+                certificate_chain.truncate(1);
+                AttestationStatement::FidoU2f(FidoU2fAttestationStatement {
+                    sig: Crypto::ecdsa_sign(&attestation_key, &signature_data).to_asn1_der(),
+                    x5c: certificate_chain,
+                })
+            }
         };
         Ok(ResponseData::AuthenticatorMakeCredential(
             AuthenticatorMakeCredentialResponse {
-                fmt: String::from("packed"),
                 auth_data,
                 att_stmt: attestation_statement,
             },
         ))
     }
 
+    // Returns the batch attestation key and its certificate chain.
+    fn batch_attestation(&self) -> Result<(crypto::ecdsa::SecKey, Vec<Vec<u8>>), Ctap2StatusCode> {
+        let attestation_private_key = self
+            .persistent_store
+            .attestation_private_key()?
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
+        let attestation_key = crypto::ecdsa::SecKey::from_bytes(&attestation_private_key).unwrap();
+        let certificate_chain = self
+            .persistent_store
+            .attestation_certificate_chain()?
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
+        Ok((attestation_key, certificate_chain))
+    }
+
     // Generates a different per-credential secret for each UV mode.
     // The computation is deterministic, and private_key expected to be unique.
     fn generate_cred_random(
@@ -626,33 +1213,29 @@ where
             client_data_hash,
             mut auth_data,
             hmac_secret_input,
+            tx_auth_simple,
             has_uv,
         } = assertion_input;
 
         // Process extensions.
+        let mut extension_outputs = ExtensionOutputs::new().tx_auth_simple(tx_auth_simple);
         if let Some(hmac_secret_input) = hmac_secret_input {
             let cred_random = self.generate_cred_random(&credential.private_key, has_uv)?;
             let encrypted_output = self
                 .pin_protocol_v1
                 .process_hmac_secret(hmac_secret_input, &cred_random)?;
-            let extensions_output = cbor_map! {
-                "hmac-secret" => encrypted_output,
-            };
-            if !cbor::write(extensions_output, &mut auth_data) {
-                return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR);
-            }
+            extension_outputs = extension_outputs.hmac_secret_encrypted(encrypted_output);
         }
+        extension_outputs.write(&mut auth_data)?;
 
         let mut signature_data = auth_data.clone();
         signature_data.extend(client_data_hash);
-        let signature = credential
-            .private_key
-            .sign_rfc6979::<crypto::sha256::Sha256>(&signature_data);
+        let signature = Crypto::ecdsa_sign(&credential.private_key, &signature_data);
 
         let cred_desc = PublicKeyCredentialDescriptor {
             key_type: PublicKeyCredentialType::PublicKey,
             key_id: credential.credential_id,
-            transports: None, // You can set USB as a hint here.
+            transports: credential.transports,
         };
         let user = if !credential.user_handle.is_empty() {
             Some(PublicKeyCredentialUserEntity {
@@ -718,9 +1301,21 @@ where
         } = get_assertion_params;
 
         self.pin_uv_auth_precheck(&pin_uv_auth_param, pin_uv_auth_protocol, cid)?;
+        // Silent assertions don't authenticate the user, so they are allowed without PIN auth.
+        #[cfg(feature = "with_ctap2_1")]
+        {
+            if options.up {
+                self.check_always_uv(&pin_uv_auth_param)?;
+            }
+        }
 
-        let hmac_secret_input = extensions.map(|e| e.hmac_secret).flatten();
-        if hmac_secret_input.is_some() && !options.up {
+        let (hmac_secret_input, tx_auth_simple) = match extensions {
+            Some(extensions) => (extensions.hmac_secret, extensions.tx_auth_simple),
+            None => (None, None),
+        };
+        // Without a display for transactions, txAuthSimple is ignored like unknown extensions.
+        let tx_auth_simple = tx_auth_simple.filter(|_| self.supports_tx_auth_simple());
+        if (hmac_secret_input.is_some() || tx_auth_simple.is_some()) && !options.up {
             // The extension is actually supported, but we need user presence.
             return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_EXTENSION);
         }
@@ -745,6 +1340,9 @@ where
                     self.pin_protocol_v1
                         .has_permission(PinPermission::GetAssertion)?;
                     self.pin_protocol_v1.has_permission_for_rp_id(&rp_id)?;
+                    if !self.pin_protocol_v1.get_user_verified_flag_value() {
+                        return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
+                    }
                 }
                 UV_FLAG
             }
@@ -759,11 +1357,13 @@ where
         if options.up {
             flags |= UP_FLAG;
         }
-        if hmac_secret_input.is_some() {
+        if hmac_secret_input.is_some() || tx_auth_simple.is_some() {
             flags |= ED_FLAG;
         }
+        flags |= backup_flags();
 
         let rp_id_hash = Sha256::hash(rp_id.as_bytes());
+        // Credential protection requires UV even for silent assertions.
         let mut applicable_credentials = if let Some(allow_list) = allow_list {
             if let Some(credential) =
                 self.get_any_credential_from_allow_list(allow_list, &rp_id, &rp_id_hash, has_uv)?
@@ -785,10 +1385,31 @@ where
         }
         applicable_credentials.sort_unstable_by_key(|c| c.creation_order);
 
+        if applicable_credentials.is_empty() && !USER_PRESENCE_WITHOUT_CREDENTIALS {
+            return Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS);
+        }
         // This check comes before CTAP2_ERR_NO_CREDENTIALS in CTAP 2.0.
         // For CTAP 2.1, it was moved to a later protocol step.
         if options.up {
-            (self.check_user_presence)(cid)?;
+            if let Some(text) = &tx_auth_simple {
+                self.check_user_presence_with_transaction(cid, &rp_id, text)?;
+                #[cfg(feature = "with_ctap2_1")]
+                {
+                    if has_uv {
+                        self.pin_protocol_v1.clear_token_flags_and_permissions();
+                    }
+                }
+            } else {
+                let user_name = match applicable_credentials.as_slice() {
+                    [credential] => credential.user_name.as_deref(),
+                    _ => None,
+                };
+                let prompt = Prompt::GetAssertion {
+                    rp_id: &rp_id,
+                    user_name,
+                };
+                self.check_user_presence_with_token(cid, has_uv, &prompt)?;
+            }
         }
 
         let credential = applicable_credentials
@@ -801,6 +1422,7 @@ where
             client_data_hash,
             auth_data: self.generate_auth_data(&rp_id_hash, flags)?,
             hmac_secret_input,
+            tx_auth_simple,
             has_uv,
         };
         let number_of_credentials = if applicable_credentials.is_empty() {
@@ -835,31 +1457,58 @@ where
             } else {
                 return Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED);
             };
+        // The timeout is counted from the last GetAssertion or GetNextAssertion.
+        self.stateful_command_permission =
+            TimedPermission::granted(now, STATEFUL_COMMAND_TIMEOUT_DURATION);
         self.assertion_response(credential, assertion_input, None)
     }
 
-    fn process_get_info(&self) -> Result<ResponseData, Ctap2StatusCode> {
+    fn process_get_info(&mut self) -> Result<ResponseData, Ctap2StatusCode> {
         let mut options_map = BTreeMap::new();
-        // TODO(kaczmarczyck) add authenticatorConfig and credProtect options
-        options_map.insert(String::from("rk"), true);
-        options_map.insert(String::from("up"), true);
-        options_map.insert(
-            String::from("clientPin"),
-            self.persistent_store.pin_hash()?.is_some(),
-        );
+        // Options are only reported if they are supported.
+        let mut insert_option = |option: &str, value: bool| {
+            if CAPABILITIES.supports_option(option) {
+                options_map.insert(String::from(option), value);
+            }
+        };
+        // TODO(kaczmarczyck) add credProtect option
+        insert_option("rk", true);
+        insert_option("up", true);
+        insert_option("clientPin", self.persistent_store.pin_hash()?.is_some());
+        #[cfg(feature = "with_ctap2_1")]
+        let always_uv = self.persistent_store.always_uv()?;
+        #[cfg(feature = "with_ctap2_1")]
+        {
+            insert_option("alwaysUv", always_uv);
+            insert_option("authnrCfg", true);
+            insert_option("largeBlobs", true);
+            insert_option("makeCredUvNotRqd", self.make_cred_uv_not_rqd()?);
+        }
+        let mut versions = Vec::new();
+        if CAPABILITIES.u2f {
+            versions.push(String::from(U2F_VERSION_STRING));
+        }
+        versions.extend(version::ctap_versions());
+        // U2F has no user verification, so it is disabled with the alwaysUv option.
+        #[cfg(feature = "with_ctap2_1")]
+        {
+            if always_uv {
+                versions.retain(|version| *version != U2F_VERSION_STRING);
+            }
+        }
+        let mut extensions = vec![String::from(Extension::HmacSecret.as_str())];
+        #[cfg(feature = "with_ctap2_1")]
+        extensions.push(String::from(Extension::MinPinLength.as_str()));
+        if self.supports_tx_auth_simple() {
+            extensions.push(String::from(Extension::TxAuthSimple.as_str()));
+        }
         Ok(ResponseData::AuthenticatorGetInfo(
             AuthenticatorGetInfoResponse {
-                versions: vec![
-                    #[cfg(feature = "with_ctap1")]
-                    String::from(U2F_VERSION_STRING),
-                    String::from(FIDO2_VERSION_STRING),
-                    #[cfg(feature = "with_ctap2_1")]
-                    String::from(FIDO2_1_VERSION_STRING),
-                ],
-                extensions: Some(vec![String::from("hmac-secret")]),
-                aaguid: self.persistent_store.aaguid()?,
+                versions,
+                extensions: Some(extensions),
+                aaguid: self.persistent_store.aaguid(),
                 options: Some(options_map),
-                max_msg_size: Some(1024),
+                max_msg_size: Some(MAX_MSG_SIZE as u64),
                 pin_protocols: Some(vec![
                     CtapState::<R, CheckUserPresence>::PIN_PROTOCOL_VERSION,
                 ]),
@@ -867,16 +1516,35 @@ where
                 max_credential_count_in_list: MAX_CREDENTIAL_COUNT_IN_LIST.map(|c| c as u64),
                 // #TODO(106) update with version 2.1 of HMAC-secret
                 #[cfg(feature = "with_ctap2_1")]
-                max_credential_id_length: Some(CREDENTIAL_ID_SIZE as u64),
+                max_credential_id_length: Some(PADDED_CREDENTIAL_ID_SIZE as u64),
                 #[cfg(feature = "with_ctap2_1")]
-                transports: Some(vec![AuthenticatorTransport::Usb]),
+                transports: Some(CAPABILITIES.transports()),
                 #[cfg(feature = "with_ctap2_1")]
                 algorithms: Some(vec![ES256_CRED_PARAM]),
+                #[cfg(feature = "with_ctap2_1")]
+                max_serialized_large_blob_array: Some(MAX_LARGE_BLOB_ARRAY_SIZE as u64),
                 default_cred_protect: DEFAULT_CRED_PROTECT,
                 #[cfg(feature = "with_ctap2_1")]
                 min_pin_length: self.persistent_store.min_pin_length()?,
                 #[cfg(feature = "with_ctap2_1")]
-                firmware_version: None,
+                firmware_version: Some(self.persistent_store.firmware_version()?),
+                #[cfg(feature = "with_ctap2_1")]
+                max_rp_ids_for_set_min_pin_length: Some(MAX_RP_IDS_LENGTH as u64),
+                #[cfg(feature = "with_ctap2_1")]
+                preferred_platform_uv_attempts: PREFERRED_PLATFORM_UV_ATTEMPTS,
+                #[cfg(feature = "with_ctap2_1")]
+                uv_modality: UV_MODALITY,
+                #[cfg(feature = "with_ctap2_1")]
+                certifications: if CERTIFICATIONS.is_empty() {
+                    None
+                } else {
+                    Some(
+                        CERTIFICATIONS
+                            .iter()
+                            .map(|(name, level)| (String::from(*name), *level))
+                            .collect(),
+                    )
+                },
             },
         ))
     }
@@ -884,11 +1552,13 @@ where
     fn process_client_pin(
         &mut self,
         client_pin_params: AuthenticatorClientPinParameters,
+        now: ClockValue,
     ) -> Result<ResponseData, Ctap2StatusCode> {
         self.pin_protocol_v1.process_subcommand(
             self.rng,
             &mut self.persistent_store,
             client_pin_params,
+            now,
         )
     }
 
@@ -904,8 +1574,9 @@ where
             Some(StatefulCommand::Reset) => (),
             _ => return Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED),
         }
-        (self.check_user_presence)(cid)?;
+        self.check_user_presence_with_prompt(cid, &Prompt::Reset)?;
 
+        self.pet_watchdog();
         self.persistent_store.reset(self.rng)?;
         self.pin_protocol_v1.reset(self.rng);
         #[cfg(feature = "with_ctap1")]
@@ -918,62 +1589,199 @@ where
         Ok(ResponseData::AuthenticatorReset)
     }
 
+    // Platforms show a different UI depending on the error: CTAP2_ERR_OPERATION_DENIED if the
+    // user declined this authenticator, CTAP2_ERR_USER_ACTION_TIMEOUT if nobody touched it.
     #[cfg(feature = "with_ctap2_1")]
-    fn process_selection(&self, cid: ChannelID) -> Result<ResponseData, Ctap2StatusCode> {
-        (self.check_user_presence)(cid)?;
+    fn process_selection(&mut self, cid: ChannelID) -> Result<ResponseData, Ctap2StatusCode> {
+        self.check_user_presence(cid)?;
         Ok(ResponseData::AuthenticatorSelection)
     }
 
-    fn process_vendor_configure(
+    #[cfg(feature = "with_ctap2_1")]
+    fn process_large_blobs(
         &mut self,
-        params: AuthenticatorVendorConfigureParameters,
-        cid: ChannelID,
+        large_blobs_params: AuthenticatorLargeBlobsParameters,
     ) -> Result<ResponseData, Ctap2StatusCode> {
-        (self.check_user_presence)(cid)?;
-
-        // Sanity checks
-        let current_priv_key = self.persistent_store.attestation_private_key()?;
-        let current_cert = self.persistent_store.attestation_certificate()?;
-
-        let response = match params.attestation_material {
-            // Only reading values.
-            None => AuthenticatorVendorResponse {
-                cert_programmed: current_cert.is_some(),
-                pkey_programmed: current_priv_key.is_some(),
-            },
-            // Device is already fully programmed. We don't leak information.
-            Some(_) if current_cert.is_some() && current_priv_key.is_some() => {
-                AuthenticatorVendorResponse {
-                    cert_programmed: true,
-                    pkey_programmed: true,
+        // Reads are not protected. Writes need the largeBlobWrite permission once the
+        // authenticator is protected by user verification.
+        if let Some(set) = &large_blobs_params.set {
+            if self.persistent_store.pin_hash()?.is_some() || self.persistent_store.always_uv()? {
+                let pin_uv_auth_param = large_blobs_params
+                    .pin_uv_auth_param
+                    .as_ref()
+                    .ok_or(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED)?;
+                if large_blobs_params.pin_uv_auth_protocol
+                    != Some(CtapState::<R, CheckUserPresence>::PIN_PROTOCOL_VERSION)
+                {
+                    return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
+                }
+                let offset = u32::try_from(large_blobs_params.offset)
+                    .map_err(|_| Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)?;
+                // The authenticated message is 32 times 0xFF, the command byte, a zero byte, the
+                // offset as a little endian 32 bit integer and the hash of the fragment. Each
+                // fragment is authorized on its own, so that fragments can't be replayed at
+                // another offset.
+                let mut large_blob_message = vec![0xFF; 32];
+                large_blob_message.push(u8::from(CommandCode::LargeBlobs));
+                large_blob_message.push(0x00);
+                large_blob_message.extend_from_slice(&offset.to_le_bytes());
+                large_blob_message.extend_from_slice(&Sha256::hash(set));
+                if !self
+                    .pin_protocol_v1
+                    .verify_pin_auth_token(&large_blob_message, pin_uv_auth_param)
+                {
+                    return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
+                }
+                self.pin_protocol_v1
+                    .has_permission(PinPermission::LargeBlobWrite)?;
+            }
+        }
+        self.large_blobs
+            .process_command(&mut self.persistent_store, large_blobs_params)
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    fn process_config(
+        &mut self,
+        params: AuthenticatorConfigParameters,
+        cid: ChannelID,
+    ) -> Result<ResponseData, Ctap2StatusCode> {
+        let AuthenticatorConfigParameters {
+            sub_command,
+            pin_uv_auth_protocol,
+            pin_uv_auth_param,
+        } = params;
+
+        if self.persistent_store.pin_hash()?.is_some() {
+            let pin_uv_auth_param =
+                pin_uv_auth_param.ok_or(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED)?;
+            if pin_uv_auth_protocol != Some(CtapState::<R, CheckUserPresence>::PIN_PROTOCOL_VERSION)
+            {
+                return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
+            }
+            // The authenticated message is 32 times 0xFF, the command byte and the sub command.
+            let mut config_message = vec![0xFF; 32];
+            config_message.push(u8::from(CommandCode::Config));
+            config_message.push(sub_command as u8);
+            if !self
+                .pin_protocol_v1
+                .verify_pin_auth_token(&config_message, &pin_uv_auth_param)
+            {
+                return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
+            }
+            self.pin_protocol_v1
+                .has_permission(PinPermission::AuthenticatorConfiguration)?;
+        } else {
+            // Without a PIN, only a user physically present may change the configuration.
+            self.check_user_presence(cid)?;
+        }
+
+        match sub_command {
+            ConfigSubCommand::ToggleAlwaysUv => self.persistent_store.toggle_always_uv()?,
+        }
+        Ok(ResponseData::AuthenticatorConfig)
+    }
+
+    /// Dispatches a vendor subcommand and tags its response with the subcommand.
+    ///
+    /// Subcommands behave like their legacy vendor command codes, which are kept for backward
+    /// compatibility.
+    fn process_vendor(
+        &mut self,
+        params: AuthenticatorVendorParameters,
+        cid: ChannelID,
+        now: ClockValue,
+    ) -> Result<ResponseData, Ctap2StatusCode> {
+        let response = match params {
+            AuthenticatorVendorParameters::Configure(params) => {
+                self.process_vendor_configure(params, cid)?
+            }
+            AuthenticatorVendorParameters::AuditLog(params) => {
+                self.process_vendor_audit_log(params, now)?
+            }
+            AuthenticatorVendorParameters::Metrics => self.process_vendor_metrics()?,
+            AuthenticatorVendorParameters::SelfTest => self.process_vendor_self_test()?,
+            AuthenticatorVendorParameters::DisplayName(params) => {
+                self.process_vendor_display_name(params, cid)?
+            }
+            #[cfg(feature = "perf")]
+            AuthenticatorVendorParameters::CommandTimings => self.process_vendor_command_timings(),
+            AuthenticatorVendorParameters::Identify(params) => {
+                self.process_vendor_identify(params, now)
+            }
+        };
+        Ok(ResponseData::AuthenticatorVendor(
+            AuthenticatorVendorResponse::try_from(response)?,
+        ))
+    }
+
+    fn process_vendor_configure(
+        &mut self,
+        params: AuthenticatorVendorConfigureParameters,
+        cid: ChannelID,
+    ) -> Result<ResponseData, Ctap2StatusCode> {
+        self.check_user_presence(cid)?;
+
+        // Sanity checks
+        let current_priv_key = self.persistent_store.attestation_private_key()?;
+        let current_cert = self.persistent_store.attestation_certificate_chain()?;
+
+        let mut response = match params.attestation_material {
+            // Only reading values.
+            None => AuthenticatorVendorConfigureResponse {
+                cert_programmed: current_cert.is_some(),
+                pkey_programmed: current_priv_key.is_some(),
+                attestation_signature: None,
+            },
+            // Device is already fully programmed. We don't leak information.
+            Some(_) if current_cert.is_some() && current_priv_key.is_some() => {
+                AuthenticatorVendorConfigureResponse {
+                    cert_programmed: true,
+                    pkey_programmed: true,
+                    attestation_signature: None,
                 }
             }
             // Device is partially or not programmed. We complete the process.
             Some(data) => {
+                let private_key = self.pin_protocol_v1.decrypt_attestation_private_key(
+                    data.key_agreement,
+                    &data.pin_auth,
+                    &data.certificate_chain,
+                    &data.private_key_enc,
+                )?;
                 if let Some(current_cert) = &current_cert {
-                    if current_cert != &data.certificate {
+                    if current_cert != &data.certificate_chain {
                         return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
                     }
                 }
                 if let Some(current_priv_key) = &current_priv_key {
-                    if current_priv_key != &data.private_key {
+                    if current_priv_key != &private_key {
                         return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
                     }
                 }
                 if current_cert.is_none() {
                     self.persistent_store
-                        .set_attestation_certificate(&data.certificate)?;
+                        .set_attestation_certificate_chain(&data.certificate_chain)?;
                 }
                 if current_priv_key.is_none() {
                     self.persistent_store
-                        .set_attestation_private_key(&data.private_key)?;
+                        .set_attestation_private_key(&private_key)?;
                 }
-                AuthenticatorVendorResponse {
+                AuthenticatorVendorConfigureResponse {
                     cert_programmed: true,
                     pkey_programmed: true,
+                    attestation_signature: None,
                 }
             }
         };
+        if let Some(challenge) = params.challenge {
+            response.attestation_signature = self.sign_attestation_challenge(&challenge)?;
+        }
+        // The USB identity is written before a possible lockdown, so both can be provisioned with a
+        // single command. It is applied at the next boot.
+        if let Some(usb_identity) = params.usb_identity {
+            self.persistent_store.set_usb_identity(usb_identity)?;
+        }
         if params.lockdown {
             // To avoid bricking the authenticator, we only allow lockdown
             // to happen if both values are programmed or if both U2F/CTAP1 and
@@ -988,8 +1796,193 @@ where
             {
                 return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
             }
+            self.persistent_store.lock_vendor()?;
+        }
+        Ok(ResponseData::AuthenticatorVendorConfigure(response))
+    }
+
+    // Signs the challenge of a vendor configuration with the attestation private key, if any.
+    //
+    // The signed message starts with a fixed prefix, so that it can't be mistaken for attestation
+    // data. Attestation data starts with an RP ID hash for CTAP2, and with 0x00 for U2F.
+    fn sign_attestation_challenge(
+        &self,
+        challenge: &[u8; 32],
+    ) -> Result<Option<Vec<u8>>, Ctap2StatusCode> {
+        let private_key = match self.persistent_store.attestation_private_key()? {
+            Some(private_key) => private_key,
+            None => return Ok(None),
+        };
+        let attestation_key = crypto::ecdsa::SecKey::from_bytes(&private_key)
+            .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
+        let mut message = ATTESTATION_CHALLENGE_PREFIX.to_vec();
+        message.extend_from_slice(challenge);
+        Ok(Some(
+            Crypto::ecdsa_sign(&attestation_key, &message).to_asn1_der(),
+        ))
+    }
+
+    fn process_vendor_audit_log(
+        &mut self,
+        params: AuthenticatorVendorAuditLogParameters,
+        now: ClockValue,
+    ) -> Result<ResponseData, Ctap2StatusCode> {
+        let AuthenticatorVendorAuditLogParameters {
+            clear,
+            pin_uv_auth_param,
+            pin_uv_auth_protocol,
+        } = params;
+
+        if self.persistent_store.pin_hash()?.is_none() {
+            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET);
+        }
+        if pin_uv_auth_protocol != CtapState::<R, CheckUserPresence>::PIN_PROTOCOL_VERSION {
+            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
+        }
+        // The authenticated message follows the authenticatorConfig convention:
+        // 32 bytes of 0xFF, the command byte and the parameters.
+        let mut auth_message = vec![0xFF; 32];
+        auth_message.push(u8::from(CommandCode::VendorAuditLog));
+        auth_message.push(clear as u8);
+        if !self
+            .pin_protocol_v1
+            .verify_pin_auth_token(&auth_message, &pin_uv_auth_param)
+        {
+            return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
+        }
+
+        let events = self.persistent_store.audit_log()?;
+        if clear {
+            self.persistent_store.clear_audit_log(now.ms() as u64)?;
+        }
+        Ok(ResponseData::AuthenticatorVendorAuditLog(
+            AuthenticatorVendorAuditLogResponse { events },
+        ))
+    }
+
+    fn process_vendor_display_name(
+        &mut self,
+        params: AuthenticatorVendorDisplayNameParameters,
+        cid: ChannelID,
+    ) -> Result<ResponseData, Ctap2StatusCode> {
+        let AuthenticatorVendorDisplayNameParameters {
+            credential_id,
+            display_name,
+            pin_uv_auth_protocol,
+            pin_uv_auth_param,
+        } = params;
+
+        if self.persistent_store.pin_hash()?.is_some() {
+            let pin_uv_auth_param =
+                pin_uv_auth_param.ok_or(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED)?;
+            if pin_uv_auth_protocol != Some(CtapState::<R, CheckUserPresence>::PIN_PROTOCOL_VERSION)
+            {
+                return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
+            }
+            // The authenticated message is 32 times 0xFF, the command byte, the sub command and
+            // the CBOR encoding of the parameters, as for credential management.
+            let mut auth_message = vec![0xFF; 32];
+            auth_message.push(u8::from(CommandCode::Vendor));
+            auth_message.push(VendorSubCommand::DisplayName as u8);
+            let auth_params = cbor_map_options! {
+                1 => credential_id.clone(),
+                2 => display_name.clone(),
+            };
+            if !cbor::write(auth_params, &mut auth_message) {
+                return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
+            }
+            if !self
+                .pin_protocol_v1
+                .verify_pin_auth_token(&auth_message, &pin_uv_auth_param)
+            {
+                return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
+            }
+            #[cfg(feature = "with_ctap2_1")]
+            self.pin_protocol_v1
+                .has_permission(PinPermission::CredentialManagement)?;
+        } else {
+            // Without a PIN, only a user physically present may rename credentials.
+            self.check_user_presence(cid)?;
+        }
+
+        let display_name = display_name
+            .map(|s| truncate_to_char_boundary(&s, MAX_DISPLAY_NAME_LENGTH).to_string());
+        self.persistent_store
+            .set_authenticator_display_name(&credential_id, display_name)?;
+        Ok(ResponseData::AuthenticatorVendorDisplayName)
+    }
+
+    #[cfg(feature = "debug_ctap")]
+    fn process_vendor_user_presence(&mut self) -> Result<ResponseData, Ctap2StatusCode> {
+        self.debug_user_presence.grant();
+        Ok(ResponseData::AuthenticatorVendorUserPresence)
+    }
+
+    fn process_vendor_metrics(&self) -> Result<ResponseData, Ctap2StatusCode> {
+        let metrics = self.persistent_store.metrics()?;
+        Ok(ResponseData::AuthenticatorVendorMetrics(
+            AuthenticatorVendorMetricsResponse {
+                registrations: metrics.registrations as u64,
+                assertions: metrics.assertions as u64,
+            },
+        ))
+    }
+
+    #[cfg(feature = "perf")]
+    fn process_vendor_command_timings(&self) -> ResponseData {
+        let timings = self
+            .command_timings
+            .iter()
+            .map(|(command_code, timing)| (command_code, *timing))
+            .collect();
+        ResponseData::AuthenticatorVendorCommandTimings(AuthenticatorVendorCommandTimingsResponse {
+            timings,
+        })
+    }
+
+    fn process_vendor_identify(
+        &mut self,
+        params: AuthenticatorVendorIdentifyParameters,
+        now: ClockValue,
+    ) -> ResponseData {
+        let duration_ms = core::cmp::min(
+            params.duration_s.saturating_mul(1000),
+            MAX_IDENTIFY_DURATION.ms() as u64,
+        );
+        if duration_ms == 0 {
+            self.stop_identify();
+        } else {
+            let duration = Duration::from_ms(duration_ms as isize);
+            self.identify_permission = TimedPermission::granted(now, duration);
+            if let Some(display) = &mut self.display {
+                display.identify(true);
+            }
         }
-        Ok(ResponseData::AuthenticatorVendor(response))
+        ResponseData::AuthenticatorVendorIdentify
+    }
+
+    fn process_vendor_self_test(&mut self) -> Result<ResponseData, Ctap2StatusCode> {
+        // A storage error fails the self test instead of the command, so that the other results
+        // are still reported.
+        let storage = self.persistent_store.self_test(self.rng).unwrap_or(false);
+        let store_check = self
+            .persistent_store
+            .check()
+            .ok()
+            .map(|check| StoreCheckReport::from(&check));
+        Ok(ResponseData::AuthenticatorVendorSelfTest(
+            AuthenticatorVendorSelfTestResponse {
+                rng: self_test::rng_health(self.rng) && self.rng.is_healthy(),
+                crypto: self_test::crypto_known_answers::<Crypto>(),
+                storage: storage && store_check.as_ref().map_or(false, |check| check.consistent),
+                store_check,
+            },
+        ))
+    }
+
+    /// Returns whether the random number generator passed its health tests so far.
+    pub fn is_rng_healthy(&self) -> bool {
+        self.rng.is_healthy()
     }
 
     pub fn generate_auth_data(
@@ -997,7 +1990,7 @@ where
         rp_id_hash: &[u8],
         flag_byte: u8,
     ) -> Result<Vec<u8>, Ctap2StatusCode> {
-        let mut auth_data = vec![];
+        let mut auth_data = Vec::with_capacity(rp_id_hash.len() + 5);
         auth_data.extend(rp_id_hash);
         auth_data.push(flag_byte);
         // The global counter is only increased if USE_SIGNATURE_COUNTER is true.
@@ -1019,9 +2012,15 @@ mod test {
         CoseKey, GetAssertionExtensions, GetAssertionOptions, MakeCredentialExtensions,
         MakeCredentialOptions, PublicKeyCredentialRpEntity, PublicKeyCredentialUserEntity,
     };
+    use super::display::TransactionConfirmation;
+    #[cfg(feature = "perf")]
+    use super::perf::CommandTiming;
     use super::*;
-    use cbor::cbor_array;
-    use crypto::rng256::ThreadRng256;
+    use alloc::format;
+    use alloc::rc::Rc;
+    use cbor::{cbor_array, cbor_map};
+    use core::cell::{Cell, RefCell};
+    use crypto::rng256::{HealthCheckedRng256, ThreadRng256};
 
     const CLOCK_FREQUENCY_HZ: usize = 32768;
     const DUMMY_CLOCK_VALUE: ClockValue = ClockValue::new(0, CLOCK_FREQUENCY_HZ);
@@ -1031,6 +2030,47 @@ mod test {
     // ID is irrelevant, so we pass this (dummy but valid) value.
     const DUMMY_CHANNEL_ID: ChannelID = [0x12, 0x34, 0x56, 0x78];
 
+    #[test]
+    fn test_truncate_to_char_boundary() {
+        assert_eq!(truncate_to_char_boundary("abc", 3), "abc");
+        assert_eq!(truncate_to_char_boundary("abc", 4), "abc");
+        assert_eq!(truncate_to_char_boundary("abc", 2), "ab");
+        assert_eq!(truncate_to_char_boundary("a\u{e9}", 2), "a");
+        assert_eq!(truncate_to_char_boundary("a\u{e9}", 3), "a\u{e9}");
+        assert_eq!(truncate_to_char_boundary("a\u{20ac}", 2), "a");
+        assert_eq!(truncate_to_char_boundary("a\u{20ac}", 3), "a");
+        assert_eq!(
+            truncate_to_char_boundary("\u{1f511}\u{1f511}", 7),
+            "\u{1f511}"
+        );
+        assert_eq!(truncate_to_char_boundary("\u{1f511}", 3), "");
+    }
+
+    #[test]
+    fn test_truncate_user_field() {
+        assert_eq!(truncate_user_field(None), None);
+        let short = "\u{e9}".repeat(MAX_USER_FIELD_LENGTH / 2);
+        assert_eq!(
+            truncate_user_field(Some(short.clone())),
+            Some(short.clone())
+        );
+        let long = format!("a{}", short);
+        assert_eq!(
+            truncate_user_field(Some(long)),
+            Some(format!("a{}", &short[..MAX_USER_FIELD_LENGTH - 2]))
+        );
+    }
+
+    #[test]
+    fn test_backup_flags() {
+        let flags = backup_flags();
+        // A credential can't be backed up without being eligible for backup.
+        assert!(flags & BS_FLAG == 0 || flags & BE_FLAG != 0);
+        assert_eq!(flags & BE_FLAG != 0, BACKUP_ELIGIBILITY);
+        assert_eq!(flags & BS_FLAG != 0, BACKUP_STATE);
+        assert_eq!(flags & !(BE_FLAG | BS_FLAG), 0);
+    }
+
     #[test]
     fn test_get_info() {
         let mut rng = ThreadRng256 {};
@@ -1039,7 +2079,7 @@ mod test {
         let info_reponse = ctap_state.process_command(&[0x04], DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
 
         #[cfg(feature = "with_ctap2_1")]
-        let mut expected_response = vec![0x00, 0xAA, 0x01];
+        let mut expected_response = vec![0x00, 0xAD, 0x01];
         #[cfg(not(feature = "with_ctap2_1"))]
         let mut expected_response = vec![0x00, 0xA6, 0x01];
         // The difference here is a longer array of supported versions.
@@ -1062,28 +2102,105 @@ mod test {
         expected_response.extend(&[
             0x6C, 0x46, 0x49, 0x44, 0x4F, 0x5F, 0x32, 0x5F, 0x31, 0x5F, 0x50, 0x52, 0x45,
         ]);
+        #[cfg(not(feature = "with_ctap2_1"))]
+        expected_response.extend(&[0x02, 0x81]);
+        #[cfg(feature = "with_ctap2_1")]
+        expected_response.extend(&[0x02, 0x82]);
+        expected_response.extend(&[
+            0x6B, 0x68, 0x6D, 0x61, 0x63, 0x2D, 0x73, 0x65, 0x63, 0x72, 0x65, 0x74,
+        ]);
+        #[cfg(feature = "with_ctap2_1")]
         expected_response.extend(&[
-            0x02, 0x81, 0x6B, 0x68, 0x6D, 0x61, 0x63, 0x2D, 0x73, 0x65, 0x63, 0x72, 0x65, 0x74,
-            0x03, 0x50,
+            0x6C, 0x6D, 0x69, 0x6E, 0x50, 0x69, 0x6E, 0x4C, 0x65, 0x6E, 0x67, 0x74, 0x68,
         ]);
-        expected_response.extend(&ctap_state.persistent_store.aaguid().unwrap());
+        expected_response.extend(&[0x03, 0x50]);
+        expected_response.extend(&ctap_state.persistent_store.aaguid());
+        #[cfg(not(feature = "with_ctap2_1"))]
         expected_response.extend(&[
             0x04, 0xA3, 0x62, 0x72, 0x6B, 0xF5, 0x62, 0x75, 0x70, 0xF5, 0x69, 0x63, 0x6C, 0x69,
-            0x65, 0x6E, 0x74, 0x50, 0x69, 0x6E, 0xF4, 0x05, 0x19, 0x04, 0x00, 0x06, 0x81, 0x01,
+            0x65, 0x6E, 0x74, 0x50, 0x69, 0x6E, 0xF4,
         ]);
         #[cfg(feature = "with_ctap2_1")]
-        expected_response.extend(
-            [
-                0x08, 0x18, 0x70, 0x09, 0x81, 0x63, 0x75, 0x73, 0x62, 0x0A, 0x81, 0xA2, 0x63, 0x61,
-                0x6C, 0x67, 0x26, 0x64, 0x74, 0x79, 0x70, 0x65, 0x6A, 0x70, 0x75, 0x62, 0x6C, 0x69,
-                0x63, 0x2D, 0x6B, 0x65, 0x79, 0x0D, 0x04,
-            ]
-            .iter(),
-        );
+        expected_response.extend(&[
+            0x04, 0xA7, 0x62, 0x72, 0x6B, 0xF5, 0x62, 0x75, 0x70, 0xF5, 0x68, 0x61, 0x6C, 0x77,
+            0x61, 0x79, 0x73, 0x55, 0x76, 0xF4, 0x69, 0x61, 0x75, 0x74, 0x68, 0x6E, 0x72, 0x43,
+            0x66, 0x67, 0xF5, 0x69, 0x63, 0x6C, 0x69, 0x65, 0x6E, 0x74, 0x50, 0x69, 0x6E, 0xF4,
+            0x6A, 0x6C, 0x61, 0x72, 0x67, 0x65, 0x42, 0x6C, 0x6F, 0x62, 0x73, 0xF5, 0x70, 0x6D,
+            0x61, 0x6B, 0x65, 0x43, 0x72, 0x65, 0x64, 0x55, 0x76, 0x4E, 0x6F, 0x74, 0x52, 0x71,
+            0x64, 0xF5,
+        ]);
+        expected_response.extend(&[0x05, 0x19, 0x04, 0x00, 0x06, 0x81, 0x01]);
+        #[cfg(feature = "with_ctap2_1")]
+        {
+            expected_response.extend(&[0x08, 0x18, 0x70, 0x09]);
+            if CAPABILITIES.nfc {
+                expected_response.extend(&[0x82, 0x63, 0x75, 0x73, 0x62, 0x63, 0x6E, 0x66, 0x63]);
+            } else {
+                expected_response.extend(&[0x81, 0x63, 0x75, 0x73, 0x62]);
+            }
+            expected_response.extend(
+                [
+                    0x0A, 0x81, 0xA2, 0x63, 0x61, 0x6C, 0x67, 0x26, 0x64, 0x74, 0x79, 0x70, 0x65,
+                    0x6A, 0x70, 0x75, 0x62, 0x6C, 0x69, 0x63, 0x2D, 0x6B, 0x65, 0x79, 0x0B, 0x19,
+                    0x08, 0x00, 0x0D, 0x04, 0x0E, 0x01, 0x10, 0x08,
+                ]
+                .iter(),
+            );
+        }
 
         assert_eq!(info_reponse, expected_response);
     }
 
+    #[test]
+    fn test_get_info_follows_capabilities() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let info = match ctap_state.process_get_info().unwrap() {
+            ResponseData::AuthenticatorGetInfo(info) => info,
+            _ => panic!("Invalid response type"),
+        };
+        assert_eq!(
+            info.versions.contains(&String::from(U2F_VERSION_STRING)),
+            CAPABILITIES.u2f
+        );
+        assert_eq!(
+            info.versions.last().unwrap(),
+            CAPABILITIES.ctap_version.as_str()
+        );
+        for option in info.options.unwrap().keys() {
+            assert!(CAPABILITIES.supports_option(option));
+        }
+        #[cfg(feature = "with_ctap2_1")]
+        assert_eq!(info.transports, Some(CAPABILITIES.transports()));
+    }
+
+    #[test]
+    fn test_unsupported_command_is_unknown() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        // The large blobs command, with an offset and a length to read.
+        let large_blobs_cbor = [
+            u8::from(CommandCode::LargeBlobs),
+            0xA2,
+            0x01,
+            0x01,
+            0x03,
+            0x00,
+        ];
+        let response =
+            ctap_state.process_command(&large_blobs_cbor, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        if CAPABILITIES.large_blobs {
+            assert_eq!(response[0], 0x00);
+        } else {
+            assert_eq!(
+                response,
+                vec![Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND as u8]
+            );
+        }
+    }
+
     fn create_minimal_make_credential_parameters() -> AuthenticatorMakeCredentialParameters {
         let client_data_hash = vec![0xCD];
         let rp = PublicKeyCredentialRpEntity {
@@ -1112,6 +2229,7 @@ mod test {
             options,
             pin_uv_auth_param: None,
             pin_uv_auth_protocol: None,
+            attestation_formats_preference: None,
         }
     }
 
@@ -1135,6 +2253,8 @@ mod test {
         let extensions = Some(MakeCredentialExtensions {
             hmac_secret: false,
             cred_protect: Some(policy),
+            #[cfg(feature = "with_ctap2_1")]
+            min_pin_length: false,
         });
         let mut make_credential_params = create_minimal_make_credential_parameters();
         make_credential_params.extensions = extensions;
@@ -1154,25 +2274,29 @@ mod test {
         match make_credential_response.unwrap() {
             ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
                 let AuthenticatorMakeCredentialResponse {
-                    fmt,
                     auth_data,
                     att_stmt,
                 } = make_credential_response;
+                let flags = UP_FLAG | AT_FLAG | backup_flags();
                 // The expected response is split to only assert the non-random parts.
-                assert_eq!(fmt, "packed");
                 let mut expected_auth_data = vec![
                     0xA3, 0x79, 0xA6, 0xF6, 0xEE, 0xAF, 0xB9, 0xA5, 0x5E, 0x37, 0x8C, 0x11, 0x80,
                     0x34, 0xE2, 0x75, 0x1E, 0x68, 0x2F, 0xAB, 0x9F, 0x2D, 0x30, 0xAB, 0x13, 0xD2,
-                    0x12, 0x55, 0x86, 0xCE, 0x19, 0x47, 0x41, 0x00, 0x00, 0x00,
+                    0x12, 0x55, 0x86, 0xCE, 0x19, 0x47, flags, 0x00, 0x00, 0x00,
                 ];
                 expected_auth_data.push(INITIAL_SIGNATURE_COUNTER as u8);
-                expected_auth_data.extend(&ctap_state.persistent_store.aaguid().unwrap());
+                expected_auth_data.extend(&ctap_state.persistent_store.aaguid());
                 expected_auth_data.extend(&[0x00, 0x20]);
                 assert_eq!(
                     auth_data[0..expected_auth_data.len()],
                     expected_auth_data[..]
                 );
-                assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64);
+                match att_stmt {
+                    AttestationStatement::Packed(att_stmt) => {
+                        assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64)
+                    }
+                    _ => panic!("Invalid attestation statement"),
+                }
             }
             _ => panic!("Invalid response type"),
         }
@@ -1192,89 +2316,292 @@ mod test {
         match make_credential_response.unwrap() {
             ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
                 let AuthenticatorMakeCredentialResponse {
-                    fmt,
                     auth_data,
                     att_stmt,
                 } = make_credential_response;
+                let flags = UP_FLAG | AT_FLAG | backup_flags();
                 // The expected response is split to only assert the non-random parts.
-                assert_eq!(fmt, "packed");
                 let mut expected_auth_data = vec![
                     0xA3, 0x79, 0xA6, 0xF6, 0xEE, 0xAF, 0xB9, 0xA5, 0x5E, 0x37, 0x8C, 0x11, 0x80,
                     0x34, 0xE2, 0x75, 0x1E, 0x68, 0x2F, 0xAB, 0x9F, 0x2D, 0x30, 0xAB, 0x13, 0xD2,
-                    0x12, 0x55, 0x86, 0xCE, 0x19, 0x47, 0x41, 0x00, 0x00, 0x00,
+                    0x12, 0x55, 0x86, 0xCE, 0x19, 0x47, flags, 0x00, 0x00, 0x00,
                 ];
                 expected_auth_data.push(INITIAL_SIGNATURE_COUNTER as u8);
-                expected_auth_data.extend(&ctap_state.persistent_store.aaguid().unwrap());
-                expected_auth_data.extend(&[0x00, CREDENTIAL_ID_SIZE as u8]);
+                expected_auth_data.extend(&ctap_state.persistent_store.aaguid());
+                expected_auth_data.extend(&[0x00, PADDED_CREDENTIAL_ID_SIZE as u8]);
                 assert_eq!(
                     auth_data[0..expected_auth_data.len()],
                     expected_auth_data[..]
                 );
-                assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64);
+                match att_stmt {
+                    AttestationStatement::Packed(att_stmt) => {
+                        assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64)
+                    }
+                    _ => panic!("Invalid attestation statement"),
+                }
             }
             _ => panic!("Invalid response type"),
         }
     }
 
     #[test]
-    fn test_process_make_credential_unsupported_algorithm() {
+    fn test_process_make_credential_none_attestation() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
         let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
 
         let mut make_credential_params = create_minimal_make_credential_parameters();
-        make_credential_params.pub_key_cred_params = vec![];
+        make_credential_params.attestation_formats_preference =
+            Some(vec![AttestationFormat::FidoU2f, AttestationFormat::None]);
         let make_credential_response =
             ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
 
+        match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                assert_eq!(
+                    make_credential_response.att_stmt,
+                    AttestationStatement::None
+                );
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[test]
+    fn test_select_attestation_format() {
         assert_eq!(
-            make_credential_response,
-            Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM)
+            select_attestation_format(ATTESTATION_FORMATS, &[], false),
+            AttestationFormat::Packed
+        );
+        assert_eq!(
+            select_attestation_format(
+                ATTESTATION_FORMATS,
+                &[AttestationFormat::None, AttestationFormat::Packed],
+                false
+            ),
+            AttestationFormat::None
+        );
+        // The fido-u2f format needs batch attestation.
+        assert_eq!(
+            select_attestation_format(ATTESTATION_FORMATS, &[AttestationFormat::FidoU2f], false),
+            AttestationFormat::Packed
+        );
+        assert_eq!(
+            select_attestation_format(ATTESTATION_FORMATS, &[AttestationFormat::FidoU2f], true),
+            AttestationFormat::FidoU2f
+        );
+        // The policy wins over the platform.
+        assert_eq!(
+            select_attestation_format(
+                &[AttestationFormat::None],
+                &[AttestationFormat::Packed],
+                true
+            ),
+            AttestationFormat::None
+        );
+        assert_eq!(
+            select_attestation_format(&[AttestationFormat::FidoU2f], &[], false),
+            AttestationFormat::None
+        );
+        assert_eq!(
+            select_attestation_format(&[], &[AttestationFormat::Packed], true),
+            AttestationFormat::None
         );
     }
 
     #[test]
-    fn test_process_make_credential_credential_excluded() {
+    fn test_process_make_credential_uv_not_required() {
         let mut rng = ThreadRng256 {};
-        let excluded_private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
         let user_immediately_present = |_| Ok(());
         let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
-
-        let excluded_credential_id = vec![0x01, 0x23, 0x45, 0x67];
-        let make_credential_params =
-            create_make_credential_parameters_with_exclude_list(&excluded_credential_id);
-        let excluded_credential_source = PublicKeyCredentialSource {
-            key_type: PublicKeyCredentialType::PublicKey,
-            credential_id: excluded_credential_id,
-            private_key: excluded_private_key,
-            rp_id: String::from("example.com"),
-            user_handle: vec![],
-            user_display_name: None,
-            cred_protect_policy: None,
-            creation_order: 0,
-            user_name: None,
-            user_icon: None,
-        };
-        assert!(ctap_state
+        ctap_state
             .persistent_store
-            .store_credential(excluded_credential_source)
-            .is_ok());
+            .set_pin_hash(&[0u8; 16])
+            .unwrap();
 
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.options.rk = false;
         let make_credential_response =
             ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+        #[cfg(feature = "with_ctap2_1")]
+        match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                // Only the UP and AT flags are set, besides the backup flags.
+                assert_eq!(
+                    make_credential_response.auth_data[32],
+                    UP_FLAG | AT_FLAG | backup_flags()
+                );
+            }
+            _ => panic!("Invalid response type"),
+        }
+        #[cfg(not(feature = "with_ctap2_1"))]
         assert_eq!(
             make_credential_response,
-            Err(Ctap2StatusCode::CTAP2_ERR_CREDENTIAL_EXCLUDED)
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED)
+        );
+
+        // Discoverable credentials always need the PIN.
+        let make_credential_params = create_minimal_make_credential_parameters();
+        assert_eq!(
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED)
         );
     }
 
+    #[cfg(feature = "with_ctap2_1")]
     #[test]
-    fn test_process_make_credential_credential_with_cred_protect() {
+    fn test_process_make_credential_uv_not_required_always_uv() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
         let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        ctap_state
+            .persistent_store
+            .set_pin_hash(&[0u8; 16])
+            .unwrap();
+        assert!(ctap_state.make_cred_uv_not_rqd().unwrap());
 
-        let test_policy = CredentialProtectionPolicy::UserVerificationOptionalWithCredentialIdList;
+        ctap_state.persistent_store.toggle_always_uv().unwrap();
+        assert!(!ctap_state.make_cred_uv_not_rqd().unwrap());
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.options.rk = false;
+        assert_eq!(
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED)
+        );
+    }
+
+    #[test]
+    fn test_process_make_credential_option_matrix() {
+        let mut rng = ThreadRng256 {};
+        let pin_uv_auth_token = [0x91; 32];
+        for &rk in &[false, true] {
+            for &uv in &[false, true] {
+                for &pin_is_set in &[false, true] {
+                    for &has_pin_uv_auth_param in &[false, true] {
+                        let key_agreement_key = crypto::ecdh::SecKey::gensk(&mut rng);
+                        let pin_protocol_v1 =
+                            PinProtocolV1::new_test(key_agreement_key, pin_uv_auth_token);
+                        let user_immediately_present = |_| Ok(());
+                        let mut ctap_state =
+                            CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+                        ctap_state.pin_protocol_v1 = pin_protocol_v1;
+                        if pin_is_set {
+                            ctap_state
+                                .persistent_store
+                                .set_pin_hash(&[0u8; 16])
+                                .unwrap();
+                        }
+
+                        let mut make_credential_params =
+                            create_minimal_make_credential_parameters();
+                        make_credential_params.options = MakeCredentialOptions { rk, uv };
+                        if has_pin_uv_auth_param {
+                            let pin_auth = hmac_256::<Sha256>(
+                                &pin_uv_auth_token,
+                                &make_credential_params.client_data_hash,
+                            )[..16]
+                                .to_vec();
+                            make_credential_params.pin_uv_auth_param = Some(pin_auth);
+                            make_credential_params.pin_uv_auth_protocol = Some(1);
+                        }
+                        let flags = ctap_state
+                            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+                            .map(|response| match response {
+                                ResponseData::AuthenticatorMakeCredential(response) => {
+                                    response.auth_data[32]
+                                }
+                                _ => panic!("Invalid response type"),
+                            });
+
+                        #[cfg(feature = "with_ctap2_1")]
+                        let uv_not_required = !rk && MAKE_CRED_UV_NOT_RQD;
+                        #[cfg(not(feature = "with_ctap2_1"))]
+                        let uv_not_required = false;
+                        let expected_flags = if has_pin_uv_auth_param {
+                            if pin_is_set {
+                                Ok(UP_FLAG | UV_FLAG | AT_FLAG | backup_flags())
+                            } else {
+                                Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET)
+                            }
+                        } else if uv {
+                            Err(Ctap2StatusCode::CTAP2_ERR_INVALID_OPTION)
+                        } else if pin_is_set && !uv_not_required {
+                            Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED)
+                        } else {
+                            Ok(UP_FLAG | AT_FLAG | backup_flags())
+                        };
+                        assert_eq!(
+                            flags, expected_flags,
+                            "rk: {}, uv: {}, pin_is_set: {}, has_pin_uv_auth_param: {}",
+                            rk, uv, pin_is_set, has_pin_uv_auth_param
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_process_make_credential_unsupported_algorithm() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.pub_key_cred_params = vec![];
+        let make_credential_response =
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+
+        assert_eq!(
+            make_credential_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_ALGORITHM)
+        );
+    }
+
+    #[test]
+    fn test_process_make_credential_credential_excluded() {
+        let mut rng = ThreadRng256 {};
+        let excluded_private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        let excluded_credential_id = vec![0x01, 0x23, 0x45, 0x67];
+        let make_credential_params =
+            create_make_credential_parameters_with_exclude_list(&excluded_credential_id);
+        let excluded_credential_source = PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id: excluded_credential_id,
+            private_key: excluded_private_key,
+            rp_id: String::from("example.com"),
+            user_handle: vec![],
+            user_display_name: None,
+            cred_protect_policy: None,
+            creation_order: 0,
+            user_name: None,
+            user_icon: None,
+            authenticator_display_name: None,
+            transports: None,
+        };
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(excluded_credential_source)
+            .is_ok());
+
+        let make_credential_response =
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+        assert_eq!(
+            make_credential_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_CREDENTIAL_EXCLUDED)
+        );
+    }
+
+    #[test]
+    fn test_process_make_credential_credential_with_cred_protect() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        let test_policy = CredentialProtectionPolicy::UserVerificationOptionalWithCredentialIdList;
         let make_credential_params =
             create_make_credential_parameters_with_cred_protect_policy(test_policy);
         let make_credential_response =
@@ -1331,6 +2658,8 @@ mod test {
         let extensions = Some(MakeCredentialExtensions {
             hmac_secret: true,
             cred_protect: None,
+            #[cfg(feature = "with_ctap2_1")]
+            min_pin_length: false,
         });
         let mut make_credential_params = create_minimal_make_credential_parameters();
         make_credential_params.options.rk = false;
@@ -1341,20 +2670,19 @@ mod test {
         match make_credential_response.unwrap() {
             ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
                 let AuthenticatorMakeCredentialResponse {
-                    fmt,
                     auth_data,
                     att_stmt,
                 } = make_credential_response;
+                let flags = UP_FLAG | AT_FLAG | ED_FLAG | backup_flags();
                 // The expected response is split to only assert the non-random parts.
-                assert_eq!(fmt, "packed");
                 let mut expected_auth_data = vec![
                     0xA3, 0x79, 0xA6, 0xF6, 0xEE, 0xAF, 0xB9, 0xA5, 0x5E, 0x37, 0x8C, 0x11, 0x80,
                     0x34, 0xE2, 0x75, 0x1E, 0x68, 0x2F, 0xAB, 0x9F, 0x2D, 0x30, 0xAB, 0x13, 0xD2,
-                    0x12, 0x55, 0x86, 0xCE, 0x19, 0x47, 0xC1, 0x00, 0x00, 0x00,
+                    0x12, 0x55, 0x86, 0xCE, 0x19, 0x47, flags, 0x00, 0x00, 0x00,
                 ];
                 expected_auth_data.push(INITIAL_SIGNATURE_COUNTER as u8);
-                expected_auth_data.extend(&ctap_state.persistent_store.aaguid().unwrap());
-                expected_auth_data.extend(&[0x00, CREDENTIAL_ID_SIZE as u8]);
+                expected_auth_data.extend(&ctap_state.persistent_store.aaguid());
+                expected_auth_data.extend(&[0x00, PADDED_CREDENTIAL_ID_SIZE as u8]);
                 assert_eq!(
                     auth_data[0..expected_auth_data.len()],
                     expected_auth_data[..]
@@ -1367,12 +2695,64 @@ mod test {
                     auth_data[auth_data.len() - expected_extension_cbor.len()..auth_data.len()],
                     expected_extension_cbor[..]
                 );
-                assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64);
+                match att_stmt {
+                    AttestationStatement::Packed(att_stmt) => {
+                        assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64)
+                    }
+                    _ => panic!("Invalid attestation statement"),
+                }
             }
             _ => panic!("Invalid response type"),
         }
     }
 
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_process_make_credential_min_pin_length() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let create_make_credential_parameters = || {
+            let mut make_credential_params = create_minimal_make_credential_parameters();
+            make_credential_params.extensions = Some(MakeCredentialExtensions {
+                hmac_secret: false,
+                cred_protect: None,
+                min_pin_length: true,
+            });
+            make_credential_params
+        };
+
+        // The RP is not allowed to read the minimum PIN length, so there is no output.
+        let make_credential_response = ctap_state
+            .process_make_credential(create_make_credential_parameters(), DUMMY_CHANNEL_ID);
+        let auth_data = match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(response) => response.auth_data,
+            _ => panic!("Invalid response type"),
+        };
+        assert_eq!(auth_data[32] & ED_FLAG, 0);
+
+        // Once the platform allows the RP, the minimum PIN length is returned.
+        ctap_state
+            .persistent_store
+            .set_min_pin_length_rp_ids(&[String::from("example.com")])
+            .unwrap();
+        let make_credential_response = ctap_state
+            .process_make_credential(create_make_credential_parameters(), DUMMY_CHANNEL_ID);
+        let auth_data = match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(response) => response.auth_data,
+            _ => panic!("Invalid response type"),
+        };
+        assert_eq!(auth_data[32] & ED_FLAG, ED_FLAG);
+        let expected_extension_cbor = vec![
+            0xA1, 0x6C, 0x6D, 0x69, 0x6E, 0x50, 0x69, 0x6E, 0x4C, 0x65, 0x6E, 0x67, 0x74, 0x68,
+            0x04,
+        ];
+        assert_eq!(
+            auth_data[auth_data.len() - expected_extension_cbor.len()..],
+            expected_extension_cbor[..]
+        );
+    }
+
     #[test]
     fn test_process_make_credential_hmac_secret_resident_key() {
         let mut rng = ThreadRng256 {};
@@ -1382,6 +2762,8 @@ mod test {
         let extensions = Some(MakeCredentialExtensions {
             hmac_secret: true,
             cred_protect: None,
+            #[cfg(feature = "with_ctap2_1")]
+            min_pin_length: false,
         });
         let mut make_credential_params = create_minimal_make_credential_parameters();
         make_credential_params.extensions = extensions;
@@ -1391,19 +2773,18 @@ mod test {
         match make_credential_response.unwrap() {
             ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
                 let AuthenticatorMakeCredentialResponse {
-                    fmt,
                     auth_data,
                     att_stmt,
                 } = make_credential_response;
+                let flags = UP_FLAG | AT_FLAG | ED_FLAG | backup_flags();
                 // The expected response is split to only assert the non-random parts.
-                assert_eq!(fmt, "packed");
                 let mut expected_auth_data = vec![
                     0xA3, 0x79, 0xA6, 0xF6, 0xEE, 0xAF, 0xB9, 0xA5, 0x5E, 0x37, 0x8C, 0x11, 0x80,
                     0x34, 0xE2, 0x75, 0x1E, 0x68, 0x2F, 0xAB, 0x9F, 0x2D, 0x30, 0xAB, 0x13, 0xD2,
-                    0x12, 0x55, 0x86, 0xCE, 0x19, 0x47, 0xC1, 0x00, 0x00, 0x00,
+                    0x12, 0x55, 0x86, 0xCE, 0x19, 0x47, flags, 0x00, 0x00, 0x00,
                 ];
                 expected_auth_data.push(INITIAL_SIGNATURE_COUNTER as u8);
-                expected_auth_data.extend(&ctap_state.persistent_store.aaguid().unwrap());
+                expected_auth_data.extend(&ctap_state.persistent_store.aaguid());
                 expected_auth_data.extend(&[0x00, 0x20]);
                 assert_eq!(
                     auth_data[0..expected_auth_data.len()],
@@ -1417,7 +2798,12 @@ mod test {
                     auth_data[auth_data.len() - expected_extension_cbor.len()..auth_data.len()],
                     expected_extension_cbor[..]
                 );
-                assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64);
+                match att_stmt {
+                    AttestationStatement::Packed(att_stmt) => {
+                        assert_eq!(att_stmt.alg, SignatureAlgorithm::ES256 as i64)
+                    }
+                    _ => panic!("Invalid attestation statement"),
+                }
             }
             _ => panic!("Invalid response type"),
         }
@@ -1440,244 +2826,345 @@ mod test {
         );
     }
 
-    fn check_assertion_response_with_user(
-        response: Result<ResponseData, Ctap2StatusCode>,
-        expected_user: PublicKeyCredentialUserEntity,
-        flags: u8,
-        signature_counter: u32,
-        expected_number_of_credentials: Option<u64>,
-    ) {
-        match response.unwrap() {
-            ResponseData::AuthenticatorGetAssertion(get_assertion_response) => {
-                let AuthenticatorGetAssertionResponse {
-                    auth_data,
-                    user,
-                    number_of_credentials,
-                    ..
-                } = get_assertion_response;
-                let mut expected_auth_data = vec![
-                    0xA3, 0x79, 0xA6, 0xF6, 0xEE, 0xAF, 0xB9, 0xA5, 0x5E, 0x37, 0x8C, 0x11, 0x80,
-                    0x34, 0xE2, 0x75, 0x1E, 0x68, 0x2F, 0xAB, 0x9F, 0x2D, 0x30, 0xAB, 0x13, 0xD2,
-                    0x12, 0x55, 0x86, 0xCE, 0x19, 0x47, flags, 0x00, 0x00, 0x00, 0x00,
-                ];
-                let signature_counter_position = expected_auth_data.len() - 4;
-                BigEndian::write_u32(
-                    &mut expected_auth_data[signature_counter_position..],
-                    signature_counter,
-                );
-                assert_eq!(auth_data, expected_auth_data);
-                assert_eq!(user, Some(expected_user));
-                assert_eq!(number_of_credentials, expected_number_of_credentials);
-            }
-            _ => panic!("Invalid response type"),
+    // Records the prompts it shows, as well as the clears and identifications.
+    struct RecordingDisplay {
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Display for RecordingDisplay {
+        fn show(&mut self, prompt: &Prompt) {
+            self.log.borrow_mut().push(prompt.lines().join("|"));
+        }
+
+        fn clear(&mut self) {
+            self.log.borrow_mut().push(String::from("<clear>"));
+        }
+
+        fn identify(&mut self, active: bool) {
+            let entry = if active {
+                "<identify>"
+            } else {
+                "<stop identify>"
+            };
+            self.log.borrow_mut().push(String::from(entry));
         }
     }
 
-    fn check_assertion_response(
-        response: Result<ResponseData, Ctap2StatusCode>,
-        expected_user_id: Vec<u8>,
-        signature_counter: u32,
-        expected_number_of_credentials: Option<u64>,
-    ) {
-        let expected_user = PublicKeyCredentialUserEntity {
-            user_id: expected_user_id,
-            user_name: None,
-            user_display_name: None,
-            user_icon: None,
-        };
-        check_assertion_response_with_user(
+    fn create_vendor_identify_parameters(duration_s: u64) -> AuthenticatorVendorParameters {
+        AuthenticatorVendorParameters::Identify(AuthenticatorVendorIdentifyParameters {
+            duration_s,
+        })
+    }
+
+    #[test]
+    fn test_process_vendor_identify() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        ctap_state.set_display(Box::new(RecordingDisplay { log: log.clone() }));
+        assert!(!ctap_state.is_identifying(DUMMY_CLOCK_VALUE));
+
+        let response = ctap_state.process_vendor(
+            create_vendor_identify_parameters(10),
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        assert_eq!(
             response,
-            expected_user,
-            0x00,
-            signature_counter,
-            expected_number_of_credentials,
+            Ok(ResponseData::AuthenticatorVendor(
+                AuthenticatorVendorResponse::Identify
+            ))
         );
+        let before_timeout = DUMMY_CLOCK_VALUE.wrapping_add(Duration::from_ms(9000));
+        assert!(ctap_state.is_identifying(before_timeout));
+        ctap_state.update_command_permission(before_timeout);
+        assert_eq!(*log.borrow(), vec!["<identify>"]);
+
+        // The display is notified once when the blinking times out.
+        let after_timeout = DUMMY_CLOCK_VALUE.wrapping_add(Duration::from_ms(11000));
+        assert!(!ctap_state.is_identifying(after_timeout));
+        ctap_state.update_command_permission(after_timeout);
+        ctap_state.update_command_permission(after_timeout);
+        assert_eq!(*log.borrow(), vec!["<identify>", "<stop identify>"]);
     }
 
     #[test]
-    fn test_residential_process_get_assertion() {
+    fn test_process_vendor_identify_cancel() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
         let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        ctap_state.set_display(Box::new(RecordingDisplay { log: log.clone() }));
 
-        let make_credential_params = create_minimal_make_credential_parameters();
+        // Long durations are capped.
+        assert!(ctap_state
+            .process_vendor(
+                create_vendor_identify_parameters(u64::MAX),
+                DUMMY_CHANNEL_ID,
+                DUMMY_CLOCK_VALUE,
+            )
+            .is_ok());
+        let capped = DUMMY_CLOCK_VALUE.wrapping_add(MAX_IDENTIFY_DURATION);
+        assert!(!ctap_state.is_identifying(capped));
+
+        // A zero duration stops blinking right away.
+        assert!(ctap_state
+            .process_vendor(
+                create_vendor_identify_parameters(0),
+                DUMMY_CHANNEL_ID,
+                DUMMY_CLOCK_VALUE,
+            )
+            .is_ok());
+        assert!(!ctap_state.is_identifying(DUMMY_CLOCK_VALUE));
+        assert_eq!(*log.borrow(), vec!["<identify>", "<stop identify>"]);
+    }
+
+    #[test]
+    fn test_process_make_credential_shows_prompt() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        ctap_state.set_display(Box::new(RecordingDisplay { log: log.clone() }));
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.rp.rp_name = Some(String::from("Example"));
+        make_credential_params.user.user_name = Some(String::from("alice"));
         assert!(ctap_state
             .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
             .is_ok());
+        assert_eq!(
+            *log.borrow(),
+            vec!["Register|example.com|Example|alice", "<clear>"]
+        );
+    }
 
-        let get_assertion_params = AuthenticatorGetAssertionParameters {
+    #[test]
+    fn test_process_reset_clears_prompt_without_user() {
+        let mut rng = ThreadRng256 {};
+        let user_not_present = |_| Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT);
+        let mut ctap_state = CtapState::new(&mut rng, user_not_present, DUMMY_CLOCK_VALUE);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        ctap_state.set_display(Box::new(RecordingDisplay { log: log.clone() }));
+
+        assert_eq!(
+            ctap_state.process_reset(DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE),
+            Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT)
+        );
+        assert_eq!(
+            *log.borrow(),
+            vec!["Reset|Delete all credentials", "<clear>"]
+        );
+    }
+
+    // Records the transactions it shows, and only finds short texts legible.
+    struct TransactionDisplay {
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Display for TransactionDisplay {
+        fn show(&mut self, prompt: &Prompt) {
+            self.log.borrow_mut().push(prompt.lines().join("|"));
+        }
+
+        fn clear(&mut self) {
+            self.log.borrow_mut().push(String::from("<clear>"));
+        }
+
+        fn transaction_confirmation(&mut self) -> Option<&mut dyn TransactionConfirmation> {
+            Some(self)
+        }
+    }
+
+    impl TransactionConfirmation for TransactionDisplay {
+        fn show_transaction(&mut self, rp_id: &str, text: &str) -> bool {
+            self.log.borrow_mut().push(format!("{}|{}", rp_id, text));
+            text.chars().count() <= 32
+        }
+    }
+
+    fn create_get_assertion_parameters_with_tx_auth_simple(
+        text: &str,
+    ) -> AuthenticatorGetAssertionParameters {
+        AuthenticatorGetAssertionParameters {
             rp_id: String::from("example.com"),
             client_data_hash: vec![0xCD],
             allow_list: None,
-            extensions: None,
+            extensions: Some(GetAssertionExtensions {
+                hmac_secret: None,
+                tx_auth_simple: Some(String::from(text)),
+            }),
             options: GetAssertionOptions {
-                up: false,
+                up: true,
                 uv: false,
             },
             pin_uv_auth_param: None,
             pin_uv_auth_protocol: None,
-        };
+        }
+    }
+
+    #[test]
+    fn test_process_get_assertion_tx_auth_simple() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        ctap_state.set_display(Box::new(TransactionDisplay { log: log.clone() }));
+
+        let make_credential_params = create_minimal_make_credential_parameters();
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+        log.borrow_mut().clear();
+
+        let get_assertion_params =
+            create_get_assertion_parameters_with_tx_auth_simple("Pay 10 EUR to Bob?");
         let get_assertion_response = ctap_state.process_get_assertion(
             get_assertion_params,
             DUMMY_CHANNEL_ID,
             DUMMY_CLOCK_VALUE,
         );
-        let signature_counter = ctap_state
-            .persistent_store
-            .global_signature_counter()
-            .unwrap();
-        check_assertion_response(get_assertion_response, vec![0x1D], signature_counter, None);
+        match get_assertion_response.unwrap() {
+            ResponseData::AuthenticatorGetAssertion(get_assertion_response) => {
+                let auth_data = get_assertion_response.auth_data;
+                assert_eq!(auth_data[32] & ED_FLAG, ED_FLAG);
+                assert_eq!(
+                    cbor::read(&auth_data[37..]),
+                    Ok(cbor_map! { "txAuthSimple" => "Pay 10 EUR to Bob?" })
+                );
+            }
+            _ => panic!("Invalid response type"),
+        }
+        assert_eq!(
+            *log.borrow(),
+            vec!["example.com|Pay 10 EUR to Bob?", "<clear>"]
+        );
     }
 
     #[test]
-    fn test_process_get_assertion_hmac_secret() {
+    fn test_process_get_assertion_tx_auth_simple_illegible() {
         let mut rng = ThreadRng256 {};
-        let sk = crypto::ecdh::SecKey::gensk(&mut rng);
         let user_immediately_present = |_| Ok(());
         let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        ctap_state.set_display(Box::new(TransactionDisplay { log: log.clone() }));
 
-        let make_extensions = Some(MakeCredentialExtensions {
-            hmac_secret: true,
-            cred_protect: None,
-        });
-        let mut make_credential_params = create_minimal_make_credential_parameters();
-        make_credential_params.options.rk = false;
-        make_credential_params.extensions = make_extensions;
-        let make_credential_response =
-            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
-        assert!(make_credential_response.is_ok());
-        let credential_id = match make_credential_response.unwrap() {
-            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
-                let auth_data = make_credential_response.auth_data;
-                let offset = 37 + ctap_state.persistent_store.aaguid().unwrap().len();
-                assert_eq!(auth_data[offset], 0x00);
-                assert_eq!(auth_data[offset + 1] as usize, CREDENTIAL_ID_SIZE);
-                auth_data[offset + 2..offset + 2 + CREDENTIAL_ID_SIZE].to_vec()
-            }
-            _ => panic!("Invalid response type"),
-        };
-
-        let pk = sk.genpk();
-        let hmac_secret_input = GetAssertionHmacSecretInput {
-            key_agreement: CoseKey::from(pk),
-            salt_enc: vec![0x02; 32],
-            salt_auth: vec![0x03; 16],
-        };
-        let get_extensions = Some(GetAssertionExtensions {
-            hmac_secret: Some(hmac_secret_input),
-        });
+        let make_credential_params = create_minimal_make_credential_parameters();
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+        log.borrow_mut().clear();
 
-        let cred_desc = PublicKeyCredentialDescriptor {
-            key_type: PublicKeyCredentialType::PublicKey,
-            key_id: credential_id,
-            transports: None,
-        };
-        let get_assertion_params = AuthenticatorGetAssertionParameters {
-            rp_id: String::from("example.com"),
-            client_data_hash: vec![0xCD],
-            allow_list: Some(vec![cred_desc]),
-            extensions: get_extensions,
-            options: GetAssertionOptions {
-                up: false,
-                uv: false,
-            },
-            pin_uv_auth_param: None,
-            pin_uv_auth_protocol: None,
-        };
-        let get_assertion_response = ctap_state.process_get_assertion(
-            get_assertion_params,
-            DUMMY_CHANNEL_ID,
-            DUMMY_CLOCK_VALUE,
+        let text = "Pay 10 EUR to Bob, and also 20 EUR to Eve?";
+        let get_assertion_params = create_get_assertion_parameters_with_tx_auth_simple(text);
+        assert_eq!(
+            ctap_state.process_get_assertion(
+                get_assertion_params,
+                DUMMY_CHANNEL_ID,
+                DUMMY_CLOCK_VALUE,
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED)
         );
-
         assert_eq!(
-            get_assertion_response,
-            Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_EXTENSION)
+            *log.borrow(),
+            vec![format!("example.com|{}", text), String::from("<clear>")]
         );
     }
 
     #[test]
-    fn test_residential_process_get_assertion_hmac_secret() {
+    fn test_process_get_assertion_tx_auth_simple_without_support() {
         let mut rng = ThreadRng256 {};
-        let sk = crypto::ecdh::SecKey::gensk(&mut rng);
         let user_immediately_present = |_| Ok(());
         let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        ctap_state.set_display(Box::new(RecordingDisplay { log: log.clone() }));
 
-        let make_extensions = Some(MakeCredentialExtensions {
-            hmac_secret: true,
-            cred_protect: None,
-        });
-        let mut make_credential_params = create_minimal_make_credential_parameters();
-        make_credential_params.extensions = make_extensions;
+        let make_credential_params = create_minimal_make_credential_parameters();
         assert!(ctap_state
             .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
             .is_ok());
 
-        let pk = sk.genpk();
-        let hmac_secret_input = GetAssertionHmacSecretInput {
-            key_agreement: CoseKey::from(pk),
-            salt_enc: vec![0x02; 32],
-            salt_auth: vec![0x03; 16],
-        };
-        let get_extensions = Some(GetAssertionExtensions {
-            hmac_secret: Some(hmac_secret_input),
-        });
-
-        let get_assertion_params = AuthenticatorGetAssertionParameters {
-            rp_id: String::from("example.com"),
-            client_data_hash: vec![0xCD],
-            allow_list: None,
-            extensions: get_extensions,
-            options: GetAssertionOptions {
-                up: false,
-                uv: false,
-            },
-            pin_uv_auth_param: None,
-            pin_uv_auth_protocol: None,
-        };
+        // The extension is ignored, and the usual prompt is shown.
+        let get_assertion_params =
+            create_get_assertion_parameters_with_tx_auth_simple("Pay 10 EUR to Bob?");
         let get_assertion_response = ctap_state.process_get_assertion(
             get_assertion_params,
             DUMMY_CHANNEL_ID,
             DUMMY_CLOCK_VALUE,
         );
-
-        assert_eq!(
+        let signature_counter = ctap_state
+            .persistent_store
+            .global_signature_counter()
+            .unwrap();
+        let expected_user = PublicKeyCredentialUserEntity {
+            user_id: vec![0x1D],
+            user_name: None,
+            user_display_name: None,
+            user_icon: None,
+        };
+        check_assertion_response_with_user(
             get_assertion_response,
-            Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_EXTENSION)
+            expected_user,
+            UP_FLAG,
+            signature_counter,
+            None,
+        );
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                "Register|example.com",
+                "<clear>",
+                "Sign in|example.com",
+                "<clear>"
+            ]
         );
     }
 
-    #[test]
-    fn test_residential_process_get_assertion_with_cred_protect() {
+    // Runs a GetAssertion with user presence, with or without a matching credential. Returns the
+    // response, the number of user presence checks and the shown prompts.
+    fn get_assertion_with_user_presence(
+        has_credential: bool,
+        user_declines: bool,
+    ) -> (Result<(), Ctap2StatusCode>, usize, Vec<String>) {
         let mut rng = ThreadRng256 {};
         let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let credential_id = rng.gen_uniform_u8x32().to_vec();
-        let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
-
-        let cred_desc = PublicKeyCredentialDescriptor {
-            key_type: PublicKeyCredentialType::PublicKey,
-            key_id: credential_id.clone(),
-            transports: None, // You can set USB as a hint here.
-        };
-        let credential = PublicKeyCredentialSource {
-            key_type: PublicKeyCredentialType::PublicKey,
-            credential_id: credential_id.clone(),
-            private_key: private_key.clone(),
-            rp_id: String::from("example.com"),
-            user_handle: vec![0x1D],
-            user_display_name: None,
-            cred_protect_policy: Some(
-                CredentialProtectionPolicy::UserVerificationOptionalWithCredentialIdList,
-            ),
-            creation_order: 0,
-            user_name: None,
-            user_icon: None,
+        let checks = Rc::new(Cell::new(0));
+        let check_user_presence = {
+            let checks = checks.clone();
+            move |_| {
+                checks.set(checks.get() + 1);
+                if user_declines {
+                    Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED)
+                } else {
+                    Ok(())
+                }
+            }
         };
-        assert!(ctap_state
-            .persistent_store
-            .store_credential(credential)
-            .is_ok());
+        let mut ctap_state = CtapState::new(&mut rng, check_user_presence, DUMMY_CLOCK_VALUE);
+        let log = Rc::new(RefCell::new(Vec::new()));
+        ctap_state.set_display(Box::new(RecordingDisplay { log: log.clone() }));
+
+        if has_credential {
+            let credential_source = PublicKeyCredentialSource {
+                key_type: PublicKeyCredentialType::PublicKey,
+                credential_id: vec![0x1D; 32],
+                private_key,
+                rp_id: String::from("example.com"),
+                user_handle: vec![0x1D],
+                user_display_name: None,
+                cred_protect_policy: None,
+                creation_order: 0,
+                user_name: Some(String::from("alice")),
+                user_icon: None,
+                authenticator_display_name: None,
+                transports: None,
+            };
+            assert!(ctap_state
+                .persistent_store
+                .store_credential(credential_source)
+                .is_ok());
+        }
 
         let get_assertion_params = AuthenticatorGetAssertionParameters {
             rp_id: String::from("example.com"),
@@ -1685,200 +3172,505 @@ mod test {
             allow_list: None,
             extensions: None,
             options: GetAssertionOptions {
-                up: false,
+                up: true,
                 uv: false,
             },
             pin_uv_auth_param: None,
             pin_uv_auth_protocol: None,
         };
-        let get_assertion_response = ctap_state.process_get_assertion(
-            get_assertion_params,
-            DUMMY_CHANNEL_ID,
-            DUMMY_CLOCK_VALUE,
-        );
-        assert_eq!(
-            get_assertion_response,
-            Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS),
-        );
+        let response = ctap_state
+            .process_get_assertion(get_assertion_params, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE)
+            .map(|_| ());
+        let prompts = log.borrow().clone();
+        (response, checks.get(), prompts)
+    }
 
-        let get_assertion_params = AuthenticatorGetAssertionParameters {
-            rp_id: String::from("example.com"),
-            client_data_hash: vec![0xCD],
-            allow_list: Some(vec![cred_desc.clone()]),
-            extensions: None,
-            options: GetAssertionOptions {
-                up: false,
-                uv: false,
-            },
-            pin_uv_auth_param: None,
-            pin_uv_auth_protocol: None,
-        };
-        let get_assertion_response = ctap_state.process_get_assertion(
-            get_assertion_params,
-            DUMMY_CHANNEL_ID,
-            DUMMY_CLOCK_VALUE,
-        );
-        let signature_counter = ctap_state
+    #[test]
+    fn test_process_get_assertion_user_presence_without_credentials() {
+        let (response, checks, prompts) = get_assertion_with_user_presence(true, false);
+        assert_eq!(response, Ok(()));
+        assert_eq!(checks, 1);
+        assert_eq!(prompts, vec!["Sign in|example.com", "<clear>"]);
+
+        // Without a matching credential, the user is asked the same way before the error.
+        let (response, no_credential_checks, no_credential_prompts) =
+            get_assertion_with_user_presence(false, false);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS));
+        assert_eq!(no_credential_checks, checks);
+        assert_eq!(no_credential_prompts, prompts);
+    }
+
+    #[test]
+    fn test_process_get_assertion_declined_without_credentials() {
+        // When the user declines, the response doesn't reveal whether a credential matches.
+        let declined = get_assertion_with_user_presence(true, true);
+        assert_eq!(declined.0, Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED));
+        assert_eq!(get_assertion_with_user_presence(false, true), declined);
+    }
+
+    #[test]
+    fn test_get_info_tx_auth_simple() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let log = Rc::new(RefCell::new(Vec::new()));
+
+        let mut expected_extensions = vec![String::from("hmac-secret")];
+        #[cfg(feature = "with_ctap2_1")]
+        expected_extensions.push(String::from("minPinLength"));
+        ctap_state.set_display(Box::new(RecordingDisplay { log: log.clone() }));
+        match ctap_state.process_get_info().unwrap() {
+            ResponseData::AuthenticatorGetInfo(info) => {
+                assert_eq!(info.extensions, Some(expected_extensions.clone()));
+            }
+            _ => panic!("Invalid response type"),
+        }
+        expected_extensions.push(String::from("txAuthSimple"));
+        ctap_state.set_display(Box::new(TransactionDisplay { log }));
+        match ctap_state.process_get_info().unwrap() {
+            ResponseData::AuthenticatorGetInfo(info) => {
+                assert_eq!(info.extensions, Some(expected_extensions));
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_process_make_credential_consumes_pin_uv_auth_token() {
+        let mut rng = ThreadRng256 {};
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(&mut rng);
+        let pin_uv_auth_token = [0x91; 32];
+        let pin_protocol_v1 = PinProtocolV1::new_test(key_agreement_key, pin_uv_auth_token);
+
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        ctap_state.pin_protocol_v1 = pin_protocol_v1;
+        ctap_state
             .persistent_store
-            .global_signature_counter()
+            .set_pin_hash(&[0u8; 16])
             .unwrap();
-        check_assertion_response(get_assertion_response, vec![0x1D], signature_counter, None);
 
-        let credential = PublicKeyCredentialSource {
-            key_type: PublicKeyCredentialType::PublicKey,
-            credential_id,
-            private_key,
-            rp_id: String::from("example.com"),
-            user_handle: vec![0x1D],
-            user_display_name: None,
-            cred_protect_policy: Some(CredentialProtectionPolicy::UserVerificationRequired),
-            creation_order: 0,
-            user_name: None,
-            user_icon: None,
+        let make_credential_params_with_pin_auth = || {
+            let mut make_credential_params = create_minimal_make_credential_parameters();
+            let pin_auth =
+                hmac_256::<Sha256>(&pin_uv_auth_token, &make_credential_params.client_data_hash)
+                    [..16]
+                    .to_vec();
+            make_credential_params.pin_uv_auth_param = Some(pin_auth);
+            make_credential_params.pin_uv_auth_protocol = Some(1);
+            make_credential_params
         };
         assert!(ctap_state
-            .persistent_store
-            .store_credential(credential)
+            .process_make_credential(make_credential_params_with_pin_auth(), DUMMY_CHANNEL_ID)
             .is_ok());
-
-        let get_assertion_params = AuthenticatorGetAssertionParameters {
-            rp_id: String::from("example.com"),
-            client_data_hash: vec![0xCD],
-            allow_list: Some(vec![cred_desc]),
-            extensions: None,
-            options: GetAssertionOptions {
-                up: false,
-                uv: false,
-            },
-            pin_uv_auth_param: None,
-            pin_uv_auth_protocol: None,
-        };
-        let get_assertion_response = ctap_state.process_get_assertion(
-            get_assertion_params,
-            DUMMY_CHANNEL_ID,
-            DUMMY_CLOCK_VALUE,
-        );
+        // The first credential consumed the cached flags and permissions of the token.
         assert_eq!(
-            get_assertion_response,
-            Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS),
+            ctap_state
+                .process_make_credential(make_credential_params_with_pin_auth(), DUMMY_CHANNEL_ID),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
         );
     }
 
+    #[cfg(feature = "with_ctap2_1")]
     #[test]
-    fn test_process_get_next_assertion_two_credentials_with_uv() {
+    fn test_process_config_toggle_always_uv() {
         let mut rng = ThreadRng256 {};
         let key_agreement_key = crypto::ecdh::SecKey::gensk(&mut rng);
-        let pin_uv_auth_token = [0x88; 32];
+        let pin_uv_auth_token = [0x91; 32];
         let pin_protocol_v1 = PinProtocolV1::new_test(key_agreement_key, pin_uv_auth_token);
-
         let user_immediately_present = |_| Ok(());
         let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
         ctap_state.pin_protocol_v1 = pin_protocol_v1;
 
-        let mut make_credential_params = create_minimal_make_credential_parameters();
-        let user1 = PublicKeyCredentialUserEntity {
-            user_id: vec![0x01],
-            user_name: Some("user1".to_string()),
-            user_display_name: Some("User One".to_string()),
-            user_icon: Some("icon1".to_string()),
-        };
-        make_credential_params.user = user1.clone();
-        assert!(ctap_state
-            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
-            .is_ok());
-        let mut make_credential_params = create_minimal_make_credential_parameters();
-        let user2 = PublicKeyCredentialUserEntity {
-            user_id: vec![0x02],
-            user_name: Some("user2".to_string()),
-            user_display_name: Some("User Two".to_string()),
-            user_icon: Some("icon2".to_string()),
+        // Without a PIN, user presence is enough to toggle the option.
+        let config_params = AuthenticatorConfigParameters {
+            sub_command: ConfigSubCommand::ToggleAlwaysUv,
+            pin_uv_auth_protocol: None,
+            pin_uv_auth_param: None,
         };
-        make_credential_params.user = user2.clone();
-        assert!(ctap_state
-            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
-            .is_ok());
+        let response = ctap_state.process_config(config_params, DUMMY_CHANNEL_ID);
+        assert_eq!(response, Ok(ResponseData::AuthenticatorConfig));
+        assert!(ctap_state.persistent_store.always_uv().unwrap());
 
+        // Credentials can't be made without PIN auth anymore.
+        let make_credential_params = create_minimal_make_credential_parameters();
+        assert_eq!(
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET)
+        );
+
+        // With a PIN, the toggle requires PIN auth.
         ctap_state
             .persistent_store
             .set_pin_hash(&[0u8; 16])
             .unwrap();
-        let pin_uv_auth_param = Some(vec![
-            0x6F, 0x52, 0x83, 0xBF, 0x1A, 0x91, 0xEE, 0x67, 0xE9, 0xD4, 0x4C, 0x80, 0x08, 0x79,
-            0x90, 0x8D,
-        ]);
+        let config_params = AuthenticatorConfigParameters {
+            sub_command: ConfigSubCommand::ToggleAlwaysUv,
+            pin_uv_auth_protocol: None,
+            pin_uv_auth_param: None,
+        };
+        let response = ctap_state.process_config(config_params, DUMMY_CHANNEL_ID);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED));
 
-        let get_assertion_params = AuthenticatorGetAssertionParameters {
-            rp_id: String::from("example.com"),
-            client_data_hash: vec![0xCD],
-            allow_list: None,
-            extensions: None,
-            options: GetAssertionOptions {
-                up: false,
-                uv: true,
-            },
+        let config_params = AuthenticatorConfigParameters {
+            sub_command: ConfigSubCommand::ToggleAlwaysUv,
+            pin_uv_auth_protocol: Some(1),
+            pin_uv_auth_param: Some(vec![0x00; 16]),
+        };
+        let response = ctap_state.process_config(config_params, DUMMY_CHANNEL_ID);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID));
+
+        let mut config_message = vec![0xFF; 32];
+        config_message.extend(&[u8::from(CommandCode::Config), 0x02]);
+        let pin_uv_auth_param =
+            hmac_256::<Sha256>(&pin_uv_auth_token, &config_message)[..16].to_vec();
+        let config_params = AuthenticatorConfigParameters {
+            sub_command: ConfigSubCommand::ToggleAlwaysUv,
+            pin_uv_auth_protocol: Some(1),
+            pin_uv_auth_param: Some(pin_uv_auth_param),
+        };
+        let response = ctap_state.process_config(config_params, DUMMY_CHANNEL_ID);
+        assert_eq!(response, Ok(ResponseData::AuthenticatorConfig));
+        assert!(!ctap_state.persistent_store.always_uv().unwrap());
+    }
+
+    // Returns the large blob array of the given length filled with a byte, with a valid hash.
+    #[cfg(feature = "with_ctap2_1")]
+    fn create_large_blob_array(length: usize, byte: u8) -> Vec<u8> {
+        let mut large_blob_array = vec![byte; length - 16];
+        let hash = Sha256::hash(&large_blob_array);
+        large_blob_array.extend_from_slice(&hash[..16]);
+        large_blob_array
+    }
+
+    // Authorizes writing a fragment at an offset of the large blob array.
+    #[cfg(feature = "with_ctap2_1")]
+    fn large_blob_pin_auth(pin_uv_auth_token: &[u8], offset: u32, fragment: &[u8]) -> Vec<u8> {
+        let mut large_blob_message = vec![0xFF; 32];
+        large_blob_message.extend_from_slice(&[0x0C, 0x00]);
+        large_blob_message.extend_from_slice(&offset.to_le_bytes());
+        large_blob_message.extend_from_slice(&Sha256::hash(fragment));
+        hmac_256::<Sha256>(pin_uv_auth_token, &large_blob_message)[..16].to_vec()
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    fn create_large_blobs_write_parameters(
+        fragment: &[u8],
+        offset: usize,
+        length: Option<usize>,
+        pin_uv_auth_param: Option<Vec<u8>>,
+    ) -> AuthenticatorLargeBlobsParameters {
+        AuthenticatorLargeBlobsParameters {
+            get: None,
+            set: Some(fragment.to_vec()),
+            offset,
+            length,
             pin_uv_auth_param,
             pin_uv_auth_protocol: Some(1),
+        }
+    }
+
+    // Reads the large blob array in a single fragment, so it must be shorter than 64 bytes.
+    #[cfg(feature = "with_ctap2_1")]
+    fn read_large_blob_array<R, CheckUserPresence>(
+        ctap_state: &mut CtapState<R, CheckUserPresence>,
+    ) -> Vec<u8>
+    where
+        R: Rng256,
+        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+    {
+        let large_blobs_params = AuthenticatorLargeBlobsParameters {
+            get: Some(64),
+            set: None,
+            offset: 0,
+            length: None,
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
         };
-        let get_assertion_response = ctap_state.process_get_assertion(
-            get_assertion_params,
-            DUMMY_CHANNEL_ID,
-            DUMMY_CLOCK_VALUE,
+        match ctap_state.process_large_blobs(large_blobs_params) {
+            Ok(ResponseData::AuthenticatorLargeBlobs(Some(response))) => response.config,
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_process_large_blobs_write_without_pin() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        let large_blob_array = create_large_blob_array(40, 0x5B);
+        let large_blobs_params =
+            create_large_blobs_write_parameters(&large_blob_array, 0, Some(40), None);
+        assert_eq!(
+            ctap_state.process_large_blobs(large_blobs_params),
+            Ok(ResponseData::AuthenticatorLargeBlobs(None))
         );
-        let signature_counter = ctap_state
+        assert_eq!(read_large_blob_array(&mut ctap_state), large_blob_array);
+
+        // With alwaysUv, writes need a PIN even if none is set.
+        ctap_state.persistent_store.toggle_always_uv().unwrap();
+        let large_blobs_params =
+            create_large_blobs_write_parameters(&large_blob_array, 0, Some(40), None);
+        assert_eq!(
+            ctap_state.process_large_blobs(large_blobs_params),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED)
+        );
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_process_large_blobs_write_with_pin() {
+        let mut rng = ThreadRng256 {};
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(&mut rng);
+        let pin_uv_auth_token = [0x91; 32];
+        let pin_protocol_v1 = PinProtocolV1::new_test(key_agreement_key, pin_uv_auth_token);
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        ctap_state.pin_protocol_v1 = pin_protocol_v1;
+        ctap_state
             .persistent_store
-            .global_signature_counter()
+            .set_pin_hash(&[0u8; 16])
             .unwrap();
-        check_assertion_response_with_user(
-            get_assertion_response,
-            user2,
-            0x04,
-            signature_counter,
-            Some(2),
-        );
 
-        let get_assertion_response = ctap_state.process_get_next_assertion(DUMMY_CLOCK_VALUE);
-        check_assertion_response_with_user(
-            get_assertion_response,
-            user1,
-            0x04,
-            signature_counter,
-            None,
+        let large_blob_array = create_large_blob_array(40, 0x5B);
+        let (first_fragment, second_fragment) = large_blob_array.split_at(20);
+        let pin_uv_auth_param = large_blob_pin_auth(&pin_uv_auth_token, 0, first_fragment);
+        let large_blobs_params = create_large_blobs_write_parameters(
+            first_fragment,
+            0,
+            Some(40),
+            Some(pin_uv_auth_param),
         );
-
-        let get_assertion_response = ctap_state.process_get_next_assertion(DUMMY_CLOCK_VALUE);
         assert_eq!(
-            get_assertion_response,
-            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+            ctap_state.process_large_blobs(large_blobs_params),
+            Ok(ResponseData::AuthenticatorLargeBlobs(None))
+        );
+        let pin_uv_auth_param = large_blob_pin_auth(&pin_uv_auth_token, 20, second_fragment);
+        let large_blobs_params =
+            create_large_blobs_write_parameters(second_fragment, 20, None, Some(pin_uv_auth_param));
+        assert_eq!(
+            ctap_state.process_large_blobs(large_blobs_params),
+            Ok(ResponseData::AuthenticatorLargeBlobs(None))
         );
+        assert_eq!(read_large_blob_array(&mut ctap_state), large_blob_array);
     }
 
+    #[cfg(feature = "with_ctap2_1")]
     #[test]
-    fn test_process_get_next_assertion_three_credentials_no_uv() {
+    fn test_process_large_blobs_write_after_make_credential() {
         let mut rng = ThreadRng256 {};
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(&mut rng);
+        let pin_uv_auth_token = [0x91; 32];
+        let mut pin_protocol_v1 = PinProtocolV1::new_test(key_agreement_key, pin_uv_auth_token);
+        pin_protocol_v1.set_permissions(
+            PinPermission::MakeCredential as u8 | PinPermission::LargeBlobWrite as u8,
+        );
         let user_immediately_present = |_| Ok(());
         let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        ctap_state.pin_protocol_v1 = pin_protocol_v1;
+        ctap_state
+            .persistent_store
+            .set_pin_hash(&[0u8; 16])
+            .unwrap();
 
         let mut make_credential_params = create_minimal_make_credential_parameters();
-        make_credential_params.user.user_id = vec![0x01];
-        make_credential_params.user.user_name = Some("removed".to_string());
-        make_credential_params.user.user_display_name = Some("removed".to_string());
-        make_credential_params.user.user_icon = Some("removed".to_string());
-        assert!(ctap_state
-            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
-            .is_ok());
-        let mut make_credential_params = create_minimal_make_credential_parameters();
-        make_credential_params.user.user_id = vec![0x02];
-        make_credential_params.user.user_name = Some("removed".to_string());
-        make_credential_params.user.user_display_name = Some("removed".to_string());
-        make_credential_params.user.user_icon = Some("removed".to_string());
+        let pin_auth =
+            hmac_256::<Sha256>(&pin_uv_auth_token, &make_credential_params.client_data_hash)[..16]
+                .to_vec();
+        make_credential_params.pin_uv_auth_param = Some(pin_auth);
+        make_credential_params.pin_uv_auth_protocol = Some(1);
         assert!(ctap_state
             .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
             .is_ok());
-        let mut make_credential_params = create_minimal_make_credential_parameters();
-        make_credential_params.user.user_id = vec![0x03];
-        make_credential_params.user.user_name = Some("removed".to_string());
-        make_credential_params.user.user_display_name = Some("removed".to_string());
-        make_credential_params.user.user_icon = Some("removed".to_string());
+
+        // The same token still writes the large blob.
+        let large_blob_array = create_large_blob_array(40, 0x5B);
+        let pin_uv_auth_param = large_blob_pin_auth(&pin_uv_auth_token, 0, &large_blob_array);
+        let large_blobs_params = create_large_blobs_write_parameters(
+            &large_blob_array,
+            0,
+            Some(40),
+            Some(pin_uv_auth_param),
+        );
+        assert_eq!(
+            ctap_state.process_large_blobs(large_blobs_params),
+            Ok(ResponseData::AuthenticatorLargeBlobs(None))
+        );
+        assert_eq!(read_large_blob_array(&mut ctap_state), large_blob_array);
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_process_large_blobs_write_rejects_pin_auth() {
+        let mut rng = ThreadRng256 {};
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(&mut rng);
+        let pin_uv_auth_token = [0x91; 32];
+        let pin_protocol_v1 = PinProtocolV1::new_test(key_agreement_key, pin_uv_auth_token);
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        ctap_state.pin_protocol_v1 = pin_protocol_v1;
+        ctap_state
+            .persistent_store
+            .set_pin_hash(&[0u8; 16])
+            .unwrap();
+        let initial_array = read_large_blob_array(&mut ctap_state);
+
+        let large_blob_array = create_large_blob_array(40, 0x5B);
+        let (first_fragment, second_fragment) = large_blob_array.split_at(20);
+        let first_pin_uv_auth_param = large_blob_pin_auth(&pin_uv_auth_token, 0, first_fragment);
+
+        // The parameter is required.
+        let large_blobs_params =
+            create_large_blobs_write_parameters(first_fragment, 0, Some(40), None);
+        assert_eq!(
+            ctap_state.process_large_blobs(large_blobs_params),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED)
+        );
+
+        // The protocol must be supported.
+        let mut large_blobs_params = create_large_blobs_write_parameters(
+            first_fragment,
+            0,
+            Some(40),
+            Some(first_pin_uv_auth_param.clone()),
+        );
+        large_blobs_params.pin_uv_auth_protocol = Some(2);
+        assert_eq!(
+            ctap_state.process_large_blobs(large_blobs_params),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+
+        // A truncated parameter is invalid.
+        let large_blobs_params = create_large_blobs_write_parameters(
+            first_fragment,
+            0,
+            Some(40),
+            Some(first_pin_uv_auth_param[..15].to_vec()),
+        );
+        assert_eq!(
+            ctap_state.process_large_blobs(large_blobs_params),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+
+        // A parameter can't be replayed for other contents.
+        let other_fragment = create_large_blob_array(40, 0x5C);
+        let large_blobs_params = create_large_blobs_write_parameters(
+            &other_fragment[..20],
+            0,
+            Some(40),
+            Some(first_pin_uv_auth_param.clone()),
+        );
+        assert_eq!(
+            ctap_state.process_large_blobs(large_blobs_params),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+
+        let large_blobs_params = create_large_blobs_write_parameters(
+            first_fragment,
+            0,
+            Some(40),
+            Some(first_pin_uv_auth_param.clone()),
+        );
+        assert!(ctap_state.process_large_blobs(large_blobs_params).is_ok());
+
+        // A parameter can't be replayed at another offset, even for the same contents.
+        let replayed_pin_uv_auth_param =
+            large_blob_pin_auth(&pin_uv_auth_token, 0, second_fragment);
+        let large_blobs_params = create_large_blobs_write_parameters(
+            second_fragment,
+            20,
+            None,
+            Some(replayed_pin_uv_auth_param),
+        );
+        assert_eq!(
+            ctap_state.process_large_blobs(large_blobs_params),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+
+        // The token needs the largeBlobWrite permission.
+        ctap_state
+            .pin_protocol_v1
+            .set_permissions(PinPermission::MakeCredential as u8);
+        let second_pin_uv_auth_param = large_blob_pin_auth(&pin_uv_auth_token, 20, second_fragment);
+        let large_blobs_params = create_large_blobs_write_parameters(
+            second_fragment,
+            20,
+            None,
+            Some(second_pin_uv_auth_param),
+        );
+        assert_eq!(
+            ctap_state.process_large_blobs(large_blobs_params),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+
+        assert_eq!(read_large_blob_array(&mut ctap_state), initial_array);
+    }
+
+    fn check_assertion_response_with_user(
+        response: Result<ResponseData, Ctap2StatusCode>,
+        expected_user: PublicKeyCredentialUserEntity,
+        flags: u8,
+        signature_counter: u32,
+        expected_number_of_credentials: Option<u64>,
+    ) {
+        match response.unwrap() {
+            ResponseData::AuthenticatorGetAssertion(get_assertion_response) => {
+                let AuthenticatorGetAssertionResponse {
+                    auth_data,
+                    user,
+                    number_of_credentials,
+                    ..
+                } = get_assertion_response;
+                let flags = flags | backup_flags();
+                let mut expected_auth_data = vec![
+                    0xA3, 0x79, 0xA6, 0xF6, 0xEE, 0xAF, 0xB9, 0xA5, 0x5E, 0x37, 0x8C, 0x11, 0x80,
+                    0x34, 0xE2, 0x75, 0x1E, 0x68, 0x2F, 0xAB, 0x9F, 0x2D, 0x30, 0xAB, 0x13, 0xD2,
+                    0x12, 0x55, 0x86, 0xCE, 0x19, 0x47, flags, 0x00, 0x00, 0x00, 0x00,
+                ];
+                let signature_counter_position = expected_auth_data.len() - 4;
+                BigEndian::write_u32(
+                    &mut expected_auth_data[signature_counter_position..],
+                    signature_counter,
+                );
+                assert_eq!(auth_data, expected_auth_data);
+                assert_eq!(user, Some(expected_user));
+                assert_eq!(number_of_credentials, expected_number_of_credentials);
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    fn check_assertion_response(
+        response: Result<ResponseData, Ctap2StatusCode>,
+        expected_user_id: Vec<u8>,
+        signature_counter: u32,
+        expected_number_of_credentials: Option<u64>,
+    ) {
+        let expected_user = PublicKeyCredentialUserEntity {
+            user_id: expected_user_id,
+            user_name: None,
+            user_display_name: None,
+            user_icon: None,
+        };
+        check_assertion_response_with_user(
+            response,
+            expected_user,
+            0x00,
+            signature_counter,
+            expected_number_of_credentials,
+        );
+    }
+
+    #[test]
+    fn test_residential_process_get_assertion() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        let make_credential_params = create_minimal_make_credential_parameters();
         assert!(ctap_state
             .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
             .is_ok());
@@ -1904,54 +3696,136 @@ mod test {
             .persistent_store
             .global_signature_counter()
             .unwrap();
-        check_assertion_response(
-            get_assertion_response,
-            vec![0x03],
-            signature_counter,
-            Some(3),
-        );
-
-        let get_assertion_response = ctap_state.process_get_next_assertion(DUMMY_CLOCK_VALUE);
-        check_assertion_response(get_assertion_response, vec![0x02], signature_counter, None);
-
-        let get_assertion_response = ctap_state.process_get_next_assertion(DUMMY_CLOCK_VALUE);
-        check_assertion_response(get_assertion_response, vec![0x01], signature_counter, None);
-
-        let get_assertion_response = ctap_state.process_get_next_assertion(DUMMY_CLOCK_VALUE);
-        assert_eq!(
-            get_assertion_response,
-            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
-        );
+        check_assertion_response(get_assertion_response, vec![0x1D], signature_counter, None);
     }
 
     #[test]
-    fn test_process_get_next_assertion_not_allowed() {
+    fn test_residential_process_get_assertion_transports() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
         let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
 
-        let get_assertion_response = ctap_state.process_get_next_assertion(DUMMY_CLOCK_VALUE);
-        assert_eq!(
-            get_assertion_response,
-            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
-        );
-
-        let mut make_credential_params = create_minimal_make_credential_parameters();
-        make_credential_params.user.user_id = vec![0x01];
+        let make_credential_params = create_minimal_make_credential_parameters();
         assert!(ctap_state
             .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
             .is_ok());
+
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: None,
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        match get_assertion_response.unwrap() {
+            ResponseData::AuthenticatorGetAssertion(get_assertion_response) => {
+                let credential = get_assertion_response.credential.unwrap();
+                assert_eq!(credential.transports, Some(CAPABILITIES.transports()));
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    #[test]
+    fn test_residential_process_make_credential_truncates_user_fields() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        // The 2-byte character straddles the limit, the 3-byte and 4-byte characters end on it.
+        let user_name = format!("{}\u{e9}", "a".repeat(MAX_USER_FIELD_LENGTH - 1));
+        let user_display_name = format!("{}\u{20ac}b", "a".repeat(MAX_USER_FIELD_LENGTH - 3));
+        let user_icon = "\u{1f511}".repeat(MAX_USER_FIELD_LENGTH / 4 + 1);
         let mut make_credential_params = create_minimal_make_credential_parameters();
-        make_credential_params.user.user_id = vec![0x02];
+        make_credential_params.user.user_name = Some(user_name.clone());
+        make_credential_params.user.user_display_name = Some(user_display_name.clone());
+        make_credential_params.user.user_icon = Some(user_icon);
         assert!(ctap_state
             .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
             .is_ok());
 
+        // GetAssertion omits those fields without user verification, so check the credential.
+        let credentials = ctap_state
+            .persistent_store
+            .filter_credential("example.com", false)
+            .unwrap();
+        assert_eq!(credentials.len(), 1);
+        let credential = &credentials[0];
+        assert_eq!(
+            credential.user_name,
+            Some(user_name[..MAX_USER_FIELD_LENGTH - 1].to_string())
+        );
+        assert_eq!(
+            credential.user_display_name,
+            Some(user_display_name[..MAX_USER_FIELD_LENGTH].to_string())
+        );
+        assert_eq!(
+            credential.user_icon,
+            Some("\u{1f511}".repeat(MAX_USER_FIELD_LENGTH / 4))
+        );
+    }
+
+    #[test]
+    fn test_process_get_assertion_hmac_secret() {
+        let mut rng = ThreadRng256 {};
+        let sk = crypto::ecdh::SecKey::gensk(&mut rng);
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        let make_extensions = Some(MakeCredentialExtensions {
+            hmac_secret: true,
+            cred_protect: None,
+            #[cfg(feature = "with_ctap2_1")]
+            min_pin_length: false,
+        });
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.options.rk = false;
+        make_credential_params.extensions = make_extensions;
+        let make_credential_response =
+            ctap_state.process_make_credential(make_credential_params, DUMMY_CHANNEL_ID);
+        assert!(make_credential_response.is_ok());
+        let credential_id = match make_credential_response.unwrap() {
+            ResponseData::AuthenticatorMakeCredential(make_credential_response) => {
+                let auth_data = make_credential_response.auth_data;
+                let offset = 37 + ctap_state.persistent_store.aaguid().len();
+                assert_eq!(auth_data[offset], 0x00);
+                assert_eq!(auth_data[offset + 1] as usize, PADDED_CREDENTIAL_ID_SIZE);
+                auth_data[offset + 2..offset + 2 + PADDED_CREDENTIAL_ID_SIZE].to_vec()
+            }
+            _ => panic!("Invalid response type"),
+        };
+
+        let pk = sk.genpk();
+        let hmac_secret_input = GetAssertionHmacSecretInput {
+            key_agreement: CoseKey::from(pk),
+            salt_enc: vec![0x02; 32],
+            salt_auth: vec![0x03; 16],
+        };
+        let get_extensions = Some(GetAssertionExtensions {
+            hmac_secret: Some(hmac_secret_input),
+            tx_auth_simple: None,
+        });
+
+        let cred_desc = PublicKeyCredentialDescriptor {
+            key_type: PublicKeyCredentialType::PublicKey,
+            key_id: credential_id,
+            transports: None,
+        };
         let get_assertion_params = AuthenticatorGetAssertionParameters {
             rp_id: String::from("example.com"),
             client_data_hash: vec![0xCD],
-            allow_list: None,
-            extensions: None,
+            allow_list: Some(vec![cred_desc]),
+            extensions: get_extensions,
             options: GetAssertionOptions {
                 up: false,
                 uv: false,
@@ -1964,286 +3838,1718 @@ mod test {
             DUMMY_CHANNEL_ID,
             DUMMY_CLOCK_VALUE,
         );
-        assert!(get_assertion_response.is_ok());
 
-        // This is a MakeCredential command.
-        let mut command_cbor = vec![0x01];
-        let cbor_value = cbor_map! {
-            1 => vec![0xCD; 16],
-            2 => cbor_map! {
-                "id" => "example.com",
-            },
-            3 => cbor_map! {
-                "id" => vec![0x1D, 0x1D, 0x1D, 0x1D],
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_EXTENSION)
+        );
+    }
+
+    #[test]
+    fn test_residential_process_get_assertion_hmac_secret() {
+        let mut rng = ThreadRng256 {};
+        let sk = crypto::ecdh::SecKey::gensk(&mut rng);
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        let make_extensions = Some(MakeCredentialExtensions {
+            hmac_secret: true,
+            cred_protect: None,
+            #[cfg(feature = "with_ctap2_1")]
+            min_pin_length: false,
+        });
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.extensions = make_extensions;
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+
+        let pk = sk.genpk();
+        let hmac_secret_input = GetAssertionHmacSecretInput {
+            key_agreement: CoseKey::from(pk),
+            salt_enc: vec![0x02; 32],
+            salt_auth: vec![0x03; 16],
+        };
+        let get_extensions = Some(GetAssertionExtensions {
+            hmac_secret: Some(hmac_secret_input),
+            tx_auth_simple: None,
+        });
+
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: get_extensions,
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
             },
-            4 => cbor_array![ES256_CRED_PARAM],
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
         };
-        assert!(cbor::write(cbor_value, &mut command_cbor));
-        ctap_state.process_command(&command_cbor, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
 
-        let get_assertion_response = ctap_state.process_get_next_assertion(DUMMY_CLOCK_VALUE);
         assert_eq!(
             get_assertion_response,
-            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+            Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_EXTENSION)
         );
     }
 
     #[test]
-    fn test_process_reset() {
+    fn test_residential_process_get_assertion_with_cred_protect() {
         let mut rng = ThreadRng256 {};
-        let user_immediately_present = |_| Ok(());
         let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
+        let credential_id = rng.gen_uniform_u8x32().to_vec();
+        let user_immediately_present = |_| Ok(());
         let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
 
-        let credential_id = vec![0x01, 0x23, 0x45, 0x67];
-        let credential_source = PublicKeyCredentialSource {
+        let cred_desc = PublicKeyCredentialDescriptor {
+            key_type: PublicKeyCredentialType::PublicKey,
+            key_id: credential_id.clone(),
+            transports: None, // You can set USB as a hint here.
+        };
+        let credential = PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id: credential_id.clone(),
+            private_key: private_key.clone(),
+            rp_id: String::from("example.com"),
+            user_handle: vec![0x1D],
+            user_display_name: None,
+            cred_protect_policy: Some(
+                CredentialProtectionPolicy::UserVerificationOptionalWithCredentialIdList,
+            ),
+            creation_order: 0,
+            user_name: None,
+            user_icon: None,
+            authenticator_display_name: None,
+            transports: None,
+        };
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(credential)
+            .is_ok());
+
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: None,
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS),
+        );
+
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: Some(vec![cred_desc.clone()]),
+            extensions: None,
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        let signature_counter = ctap_state
+            .persistent_store
+            .global_signature_counter()
+            .unwrap();
+        check_assertion_response(get_assertion_response, vec![0x1D], signature_counter, None);
+
+        let credential = PublicKeyCredentialSource {
             key_type: PublicKeyCredentialType::PublicKey,
             credential_id,
             private_key,
             rp_id: String::from("example.com"),
-            user_handle: vec![],
+            user_handle: vec![0x1D],
             user_display_name: None,
-            cred_protect_policy: None,
+            cred_protect_policy: Some(CredentialProtectionPolicy::UserVerificationRequired),
             creation_order: 0,
             user_name: None,
             user_icon: None,
+            authenticator_display_name: None,
+            transports: None,
         };
         assert!(ctap_state
             .persistent_store
-            .store_credential(credential_source)
+            .store_credential(credential)
             .is_ok());
-        assert!(ctap_state.persistent_store.count_credentials().unwrap() > 0);
-
-        let reset_reponse =
-            ctap_state.process_command(&[0x07], DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
-        let expected_response = vec![0x00];
-        assert_eq!(reset_reponse, expected_response);
-        assert!(ctap_state.persistent_store.count_credentials().unwrap() == 0);
-    }
 
-    #[test]
-    fn test_process_reset_cancelled() {
-        let mut rng = ThreadRng256 {};
-        let user_presence_always_cancel = |_| Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL);
-        let mut ctap_state =
-            CtapState::new(&mut rng, user_presence_always_cancel, DUMMY_CLOCK_VALUE);
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: Some(vec![cred_desc]),
+            extensions: None,
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS),
+        );
+    }
+
+    // Returns the parameters of a GetAssertion for example.com, with or without user presence.
+    fn create_get_assertion_parameters(
+        up: bool,
+        pin_uv_auth_param: Option<Vec<u8>>,
+    ) -> AuthenticatorGetAssertionParameters {
+        let pin_uv_auth_protocol = pin_uv_auth_param.as_ref().map(|_| 1);
+        AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: None,
+            options: GetAssertionOptions { up, uv: false },
+            pin_uv_auth_param,
+            pin_uv_auth_protocol,
+        }
+    }
+
+    #[test]
+    fn test_process_get_assertion_silent() {
+        let mut rng = ThreadRng256 {};
+        let checks = Rc::new(Cell::new(0));
+        let check_user_presence = {
+            let checks = checks.clone();
+            move |_| {
+                checks.set(checks.get() + 1);
+                Ok(())
+            }
+        };
+        let mut ctap_state = CtapState::new(&mut rng, check_user_presence, DUMMY_CLOCK_VALUE);
+        let log = Rc::new(RefCell::new(Vec::new()));
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.user.user_name = Some(String::from("alice"));
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+        checks.set(0);
+        ctap_state.set_display(Box::new(RecordingDisplay { log: log.clone() }));
+
+        // A silent assertion neither asks the user nor sets the UP flag.
+        let get_assertion_response = ctap_state.process_get_assertion(
+            create_get_assertion_parameters(false, None),
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        let signature_counter = ctap_state
+            .persistent_store
+            .global_signature_counter()
+            .unwrap();
+        check_assertion_response(get_assertion_response, vec![0x1D], signature_counter, None);
+        assert_eq!(checks.get(), 0);
+        assert!(log.borrow().is_empty());
+
+        // An interactive assertion asks the user once and sets the UP flag.
+        let get_assertion_response = ctap_state.process_get_assertion(
+            create_get_assertion_parameters(true, None),
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        let signature_counter = ctap_state
+            .persistent_store
+            .global_signature_counter()
+            .unwrap();
+        let expected_user = PublicKeyCredentialUserEntity {
+            user_id: vec![0x1D],
+            user_name: None,
+            user_display_name: None,
+            user_icon: None,
+        };
+        check_assertion_response_with_user(
+            get_assertion_response,
+            expected_user,
+            UP_FLAG,
+            signature_counter,
+            None,
+        );
+        assert_eq!(checks.get(), 1);
+        assert_eq!(*log.borrow(), vec!["Sign in|example.com", "<clear>"]);
+    }
+
+    #[test]
+    fn test_process_get_assertion_silent_with_pin_uv_auth() {
+        let mut rng = ThreadRng256 {};
+        let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(&mut rng);
+        let pin_uv_auth_token = [0x88; 32];
+        let pin_protocol_v1 = PinProtocolV1::new_test(key_agreement_key, pin_uv_auth_token);
+        let checks = Rc::new(Cell::new(0));
+        let check_user_presence = {
+            let checks = checks.clone();
+            move |_| {
+                checks.set(checks.get() + 1);
+                Ok(())
+            }
+        };
+        let mut ctap_state = CtapState::new(&mut rng, check_user_presence, DUMMY_CLOCK_VALUE);
+        ctap_state.pin_protocol_v1 = pin_protocol_v1;
+        ctap_state
+            .persistent_store
+            .set_pin_hash(&[0u8; 16])
+            .unwrap();
+
+        let credential = PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id: vec![0x1D; 32],
+            private_key,
+            rp_id: String::from("example.com"),
+            user_handle: vec![0x1D],
+            user_display_name: None,
+            cred_protect_policy: Some(CredentialProtectionPolicy::UserVerificationRequired),
+            creation_order: 0,
+            user_name: Some(String::from("alice")),
+            user_icon: None,
+            authenticator_display_name: None,
+            transports: None,
+        };
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(credential)
+            .is_ok());
+
+        // Skipping user presence doesn't lift the UV requirement of the credential.
+        assert_eq!(
+            ctap_state.process_get_assertion(
+                create_get_assertion_parameters(false, None),
+                DUMMY_CHANNEL_ID,
+                DUMMY_CLOCK_VALUE,
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)
+        );
+
+        let pin_uv_auth_param = hmac_256::<Sha256>(&pin_uv_auth_token, &[0xCD])[..16].to_vec();
+        let expected_user = PublicKeyCredentialUserEntity {
+            user_id: vec![0x1D],
+            user_name: Some(String::from("alice")),
+            user_display_name: None,
+            user_icon: None,
+        };
+        // Silent assertions with PIN auth set only the UV flag and keep the token usable.
+        for _ in 0..2 {
+            let get_assertion_response = ctap_state.process_get_assertion(
+                create_get_assertion_parameters(false, Some(pin_uv_auth_param.clone())),
+                DUMMY_CHANNEL_ID,
+                DUMMY_CLOCK_VALUE,
+            );
+            let signature_counter = ctap_state
+                .persistent_store
+                .global_signature_counter()
+                .unwrap();
+            check_assertion_response_with_user(
+                get_assertion_response,
+                expected_user.clone(),
+                UV_FLAG,
+                signature_counter,
+                None,
+            );
+        }
+        assert_eq!(checks.get(), 0);
+
+        let get_assertion_response = ctap_state.process_get_assertion(
+            create_get_assertion_parameters(true, Some(pin_uv_auth_param.clone())),
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        let signature_counter = ctap_state
+            .persistent_store
+            .global_signature_counter()
+            .unwrap();
+        check_assertion_response_with_user(
+            get_assertion_response,
+            expected_user,
+            UP_FLAG | UV_FLAG,
+            signature_counter,
+            None,
+        );
+        assert_eq!(checks.get(), 1);
+
+        // The interactive assertion consumed the token.
+        #[cfg(feature = "with_ctap2_1")]
+        assert_eq!(
+            ctap_state.process_get_assertion(
+                create_get_assertion_parameters(false, Some(pin_uv_auth_param)),
+                DUMMY_CHANNEL_ID,
+                DUMMY_CLOCK_VALUE,
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
+        );
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_process_get_assertion_silent_with_always_uv() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        let make_credential_params = create_minimal_make_credential_parameters();
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+        ctap_state.persistent_store.toggle_always_uv().unwrap();
+
+        // Silent assertions don't authenticate the user, so alwaysUv doesn't apply.
+        let get_assertion_response = ctap_state.process_get_assertion(
+            create_get_assertion_parameters(false, None),
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        let signature_counter = ctap_state
+            .persistent_store
+            .global_signature_counter()
+            .unwrap();
+        check_assertion_response(get_assertion_response, vec![0x1D], signature_counter, None);
+
+        assert_eq!(
+            ctap_state.process_get_assertion(
+                create_get_assertion_parameters(true, None),
+                DUMMY_CHANNEL_ID,
+                DUMMY_CLOCK_VALUE,
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET)
+        );
+    }
+
+    #[test]
+    fn test_process_get_next_assertion_two_credentials_with_uv() {
+        let mut rng = ThreadRng256 {};
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(&mut rng);
+        let pin_uv_auth_token = [0x88; 32];
+        let pin_protocol_v1 = PinProtocolV1::new_test(key_agreement_key, pin_uv_auth_token);
+
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        ctap_state.pin_protocol_v1 = pin_protocol_v1;
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        let user1 = PublicKeyCredentialUserEntity {
+            user_id: vec![0x01],
+            user_name: Some("user1".to_string()),
+            user_display_name: Some("User One".to_string()),
+            user_icon: Some("icon1".to_string()),
+        };
+        make_credential_params.user = user1.clone();
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        let user2 = PublicKeyCredentialUserEntity {
+            user_id: vec![0x02],
+            user_name: Some("user2".to_string()),
+            user_display_name: Some("User Two".to_string()),
+            user_icon: Some("icon2".to_string()),
+        };
+        make_credential_params.user = user2.clone();
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+
+        ctap_state
+            .persistent_store
+            .set_pin_hash(&[0u8; 16])
+            .unwrap();
+        let pin_uv_auth_param = Some(vec![
+            0x6F, 0x52, 0x83, 0xBF, 0x1A, 0x91, 0xEE, 0x67, 0xE9, 0xD4, 0x4C, 0x80, 0x08, 0x79,
+            0x90, 0x8D,
+        ]);
+
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: None,
+            options: GetAssertionOptions {
+                up: false,
+                uv: true,
+            },
+            pin_uv_auth_param,
+            pin_uv_auth_protocol: Some(1),
+        };
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        let signature_counter = ctap_state
+            .persistent_store
+            .global_signature_counter()
+            .unwrap();
+        check_assertion_response_with_user(
+            get_assertion_response,
+            user2,
+            0x04,
+            signature_counter,
+            Some(2),
+        );
+
+        let get_assertion_response = ctap_state.process_get_next_assertion(DUMMY_CLOCK_VALUE);
+        check_assertion_response_with_user(
+            get_assertion_response,
+            user1,
+            0x04,
+            signature_counter,
+            None,
+        );
+
+        let get_assertion_response = ctap_state.process_get_next_assertion(DUMMY_CLOCK_VALUE);
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+    }
+
+    #[test]
+    fn test_process_get_next_assertion_three_credentials_no_uv() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.user.user_id = vec![0x01];
+        make_credential_params.user.user_name = Some("removed".to_string());
+        make_credential_params.user.user_display_name = Some("removed".to_string());
+        make_credential_params.user.user_icon = Some("removed".to_string());
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.user.user_id = vec![0x02];
+        make_credential_params.user.user_name = Some("removed".to_string());
+        make_credential_params.user.user_display_name = Some("removed".to_string());
+        make_credential_params.user.user_icon = Some("removed".to_string());
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.user.user_id = vec![0x03];
+        make_credential_params.user.user_name = Some("removed".to_string());
+        make_credential_params.user.user_display_name = Some("removed".to_string());
+        make_credential_params.user.user_icon = Some("removed".to_string());
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: None,
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        let signature_counter = ctap_state
+            .persistent_store
+            .global_signature_counter()
+            .unwrap();
+        check_assertion_response(
+            get_assertion_response,
+            vec![0x03],
+            signature_counter,
+            Some(3),
+        );
+
+        let get_assertion_response = ctap_state.process_get_next_assertion(DUMMY_CLOCK_VALUE);
+        check_assertion_response(get_assertion_response, vec![0x02], signature_counter, None);
+
+        let get_assertion_response = ctap_state.process_get_next_assertion(DUMMY_CLOCK_VALUE);
+        check_assertion_response(get_assertion_response, vec![0x01], signature_counter, None);
+
+        let get_assertion_response = ctap_state.process_get_next_assertion(DUMMY_CLOCK_VALUE);
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+    }
+
+    #[test]
+    fn test_process_get_next_assertion_timeout() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let clock_value = |ms: isize| {
+            ClockValue::new(ms * CLOCK_FREQUENCY_HZ as isize / 1000, CLOCK_FREQUENCY_HZ)
+        };
+
+        for user_id in 0x01..=0x03 {
+            let mut make_credential_params = create_minimal_make_credential_parameters();
+            make_credential_params.user.user_id = vec![user_id];
+            assert!(ctap_state
+                .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+                .is_ok());
+        }
+        let get_assertion_params = || AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: None,
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params(),
+            DUMMY_CHANNEL_ID,
+            clock_value(0),
+        );
+        assert!(get_assertion_response.is_ok());
+
+        // Each GetNextAssertion restarts the timeout.
+        let get_assertion_response = ctap_state.process_get_next_assertion(clock_value(20000));
+        assert!(get_assertion_response.is_ok());
+        let get_assertion_response = ctap_state.process_get_next_assertion(clock_value(45000));
+        assert!(get_assertion_response.is_ok());
+
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params(),
+            DUMMY_CHANNEL_ID,
+            clock_value(50000),
+        );
+        assert!(get_assertion_response.is_ok());
+        ctap_state.update_command_permission(clock_value(70000));
+        assert!(ctap_state.stateful_command_type.is_some());
+        // The timer drops the remaining credentials once expired.
+        ctap_state.update_command_permission(clock_value(80001));
+        assert!(ctap_state.stateful_command_type.is_none());
+        let get_assertion_response = ctap_state.process_get_next_assertion(clock_value(80001));
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+    }
+
+    #[test]
+    fn test_process_get_next_assertion_not_allowed() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        let get_assertion_response = ctap_state.process_get_next_assertion(DUMMY_CLOCK_VALUE);
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.user.user_id = vec![0x01];
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+        let mut make_credential_params = create_minimal_make_credential_parameters();
+        make_credential_params.user.user_id = vec![0x02];
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+
+        let get_assertion_params = AuthenticatorGetAssertionParameters {
+            rp_id: String::from("example.com"),
+            client_data_hash: vec![0xCD],
+            allow_list: None,
+            extensions: None,
+            options: GetAssertionOptions {
+                up: false,
+                uv: false,
+            },
+            pin_uv_auth_param: None,
+            pin_uv_auth_protocol: None,
+        };
+        let get_assertion_response = ctap_state.process_get_assertion(
+            get_assertion_params,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        assert!(get_assertion_response.is_ok());
+
+        // This is a MakeCredential command.
+        let mut command_cbor = vec![0x01];
+        let cbor_value = cbor_map! {
+            1 => vec![0xCD; 16],
+            2 => cbor_map! {
+                "id" => "example.com",
+            },
+            3 => cbor_map! {
+                "id" => vec![0x1D, 0x1D, 0x1D, 0x1D],
+            },
+            4 => cbor_array![ES256_CRED_PARAM],
+        };
+        assert!(cbor::write(cbor_value, &mut command_cbor));
+        ctap_state.process_command(&command_cbor, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+
+        let get_assertion_response = ctap_state.process_get_next_assertion(DUMMY_CLOCK_VALUE);
+        assert_eq!(
+            get_assertion_response,
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+    }
+
+    #[test]
+    fn test_process_reset() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        let credential_id = vec![0x01, 0x23, 0x45, 0x67];
+        let credential_source = PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id,
+            private_key,
+            rp_id: String::from("example.com"),
+            user_handle: vec![],
+            user_display_name: None,
+            cred_protect_policy: None,
+            creation_order: 0,
+            user_name: None,
+            user_icon: None,
+            authenticator_display_name: None,
+            transports: None,
+        };
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(credential_source)
+            .is_ok());
+        assert!(ctap_state.persistent_store.count_credentials().unwrap() > 0);
+
+        let reset_reponse =
+            ctap_state.process_command(&[0x07], DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        let expected_response = vec![0x00];
+        assert_eq!(reset_reponse, expected_response);
+        assert!(ctap_state.persistent_store.count_credentials().unwrap() == 0);
+    }
+
+    // Counts how often it is petted.
+    struct CountingWatchdog(Rc<Cell<usize>>);
+
+    impl Watchdog for CountingWatchdog {
+        fn pet(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn test_long_operations_pet_watchdog() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let pets = Rc::new(Cell::new(0));
+        ctap_state.set_watchdog(Box::new(CountingWatchdog(pets.clone())));
+
+        // The key generation and the storage of a resident key are petted separately.
+        let make_credential_params = create_minimal_make_credential_parameters();
+        assert!(ctap_state
+            .process_make_credential(make_credential_params, DUMMY_CHANNEL_ID)
+            .is_ok());
+        assert_eq!(pets.get(), 2);
+
+        assert!(ctap_state
+            .process_reset(DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE)
+            .is_ok());
+        assert_eq!(pets.get(), 3);
+    }
+
+    // Advances by 125 ms each time it is read.
+    #[cfg(feature = "perf")]
+    struct SteppingClock(Cell<isize>);
+
+    #[cfg(feature = "perf")]
+    impl PerfClock for SteppingClock {
+        fn now(&self) -> ClockValue {
+            let ticks = self.0.get();
+            self.0.set(ticks + CLOCK_FREQUENCY_HZ as isize / 8);
+            ClockValue::new(ticks, CLOCK_FREQUENCY_HZ)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "perf")]
+    fn test_process_vendor_command_timings() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        ctap_state.set_perf_clock(Box::new(SteppingClock(Cell::new(0))));
+
+        let get_info = [u8::from(CommandCode::GetInfo)];
+        ctap_state.process_command(&get_info, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        ctap_state.process_command(&get_info, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        let response = ctap_state.process_vendor(
+            AuthenticatorVendorParameters::CommandTimings,
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        let expected_timing = CommandTiming {
+            count: 2,
+            min_ms: 125,
+            max_ms: 125,
+            total_ms: 250,
+        };
+        assert_eq!(
+            response,
+            Ok(ResponseData::AuthenticatorVendor(
+                AuthenticatorVendorResponse::CommandTimings(
+                    AuthenticatorVendorCommandTimingsResponse {
+                        timings: vec![(u8::from(CommandCode::GetInfo), expected_timing)],
+                    }
+                )
+            ))
+        );
+    }
+
+    #[test]
+    fn test_process_reset_cancelled() {
+        let mut rng = ThreadRng256 {};
+        let user_presence_always_cancel = |_| Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL);
+        let mut ctap_state =
+            CtapState::new(&mut rng, user_presence_always_cancel, DUMMY_CLOCK_VALUE);
+
+        let reset_reponse = ctap_state.process_reset(DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+
+        assert_eq!(
+            reset_reponse,
+            Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL)
+        );
+    }
+
+    #[test]
+    fn test_process_reset_not_first() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        // This is a GetNextAssertion command.
+        ctap_state.process_command(&[0x08], DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+
+        let reset_reponse = ctap_state.process_reset(DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(reset_reponse, Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED));
+    }
+
+    #[test]
+    fn test_reboot() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        ctap_state
+            .persistent_store
+            .set_pin_hash(&[0x88; 16])
+            .unwrap();
+        // This is a GetNextAssertion command.
+        ctap_state.process_command(&[0x08], DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+
+        // The PIN persists, and the reset is allowed again after booting.
+        let mut ctap_state = ctap_state.reboot(DUMMY_CLOCK_VALUE);
+        assert!(ctap_state
+            .persistent_store
+            .pin_hash()
+            .unwrap()
+            .unwrap()
+            .ct_eq(&[0x88; 16]));
+        assert_eq!(
+            ctap_state.process_reset(DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE),
+            Ok(ResponseData::AuthenticatorReset)
+        );
+        assert!(ctap_state.persistent_store.pin_hash().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_process_unknown_command() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        // This command does not exist.
+        let reset_reponse =
+            ctap_state.process_command(&[0xDF], DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        let expected_response = vec![Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND as u8];
+        assert_eq!(reset_reponse, expected_response);
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_credential() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        // Usually, the relying party ID or its hash is provided by the client.
+        // We are not testing the correctness of our SHA256 here, only if it is checked.
+        let rp_id_hash = [0x55; 32];
+        let encrypted_id = ctap_state
+            .encrypt_key_handle(private_key.clone(), &rp_id_hash)
+            .unwrap();
+        let decrypted_source = ctap_state
+            .decrypt_credential_source(encrypted_id, &rp_id_hash)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(private_key, decrypted_source.private_key);
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_bad_hmac() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        // Same as above.
+        let rp_id_hash = [0x55; 32];
+        let encrypted_id = ctap_state
+            .encrypt_key_handle(private_key, &rp_id_hash)
+            .unwrap();
+        // The padding is not authenticated.
+        for i in 0..CREDENTIAL_ID_SIZE {
+            let mut modified_id = encrypted_id.clone();
+            modified_id[i] ^= 0x01;
+            assert!(ctap_state
+                .decrypt_credential_source(modified_id, &rp_id_hash)
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[test]
+    #[allow(clippy::assertions_on_constants)]
+    fn test_padded_credential_id_size() {
+        assert!(CREDENTIAL_ID_SIZE <= PADDED_CREDENTIAL_ID_SIZE);
+        assert!(PADDED_CREDENTIAL_ID_SIZE <= 0xFF);
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_padded_credential() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        let rp_id_hash = [0x55; 32];
+        let encrypted_id = ctap_state
+            .encrypt_key_handle(private_key.clone(), &rp_id_hash)
+            .unwrap();
+        assert_eq!(encrypted_id.len(), PADDED_CREDENTIAL_ID_SIZE);
+
+        // Credential IDs are accepted with any padding up to the padded size.
+        for length in CREDENTIAL_ID_SIZE..=PADDED_CREDENTIAL_ID_SIZE {
+            let mut padded_id = encrypted_id[..CREDENTIAL_ID_SIZE].to_vec();
+            padded_id.resize(length, 0xA5);
+            let decrypted_source = ctap_state
+                .decrypt_credential_source(padded_id.clone(), &rp_id_hash)
+                .unwrap()
+                .unwrap();
+            assert_eq!(private_key, decrypted_source.private_key);
+            assert_eq!(decrypted_source.credential_id, padded_id);
+        }
+        let mut too_long_id = encrypted_id.clone();
+        too_long_id.push(0xA5);
+        assert!(ctap_state
+            .decrypt_credential_source(too_long_id, &rp_id_hash)
+            .unwrap()
+            .is_none());
+        assert!(ctap_state
+            .decrypt_credential_source(encrypted_id[..CREDENTIAL_ID_SIZE - 1].to_vec(), &rp_id_hash)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_signature_counter() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        let mut last_counter = ctap_state
+            .persistent_store
+            .global_signature_counter()
+            .unwrap();
+        assert!(last_counter > 0);
+        for _ in 0..100 {
+            assert!(ctap_state.increment_global_signature_counter().is_ok());
+            let next_counter = ctap_state
+                .persistent_store
+                .global_signature_counter()
+                .unwrap();
+            assert!(next_counter > last_counter);
+            last_counter = next_counter;
+        }
+    }
+
+    // Encrypts the attestation private key for the authenticator owning the key agreement key.
+    fn create_attestation_material(
+        rng: &mut impl Rng256,
+        key_agreement_pk: &crypto::ecdh::PubKey,
+        certificate_chain: &[Vec<u8>],
+        private_key: &[u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH],
+    ) -> AuthenticatorAttestationMaterial {
+        let platform_key = crypto::ecdh::SecKey::gensk(rng);
+        let shared_secret = platform_key.exchange_x_sha256(key_agreement_pk);
+        let mut blocks = [[0u8; 16]; key_material::ATTESTATION_PRIVATE_KEY_LENGTH / 16];
+        for (block, chunk) in blocks.iter_mut().zip(private_key.chunks(16)) {
+            block.copy_from_slice(chunk);
+        }
+        let aes_enc_key = Crypto::aes256_encryption_key(&shared_secret);
+        cbc_encrypt(&aes_enc_key, [0u8; 16], &mut blocks);
+        let mut private_key_enc = [0u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH];
+        for (chunk, block) in private_key_enc.chunks_mut(16).zip(blocks.iter()) {
+            chunk.copy_from_slice(block);
+        }
+        let mut authenticated_message = certificate_chain.concat();
+        authenticated_message.extend_from_slice(&private_key_enc);
+        let pin_auth = hmac_256::<Sha256>(&shared_secret, &authenticated_message)[..16].to_vec();
+        AuthenticatorAttestationMaterial {
+            certificate_chain: certificate_chain.to_vec(),
+            private_key_enc,
+            key_agreement: CoseKey::from(platform_key.genpk()),
+            pin_auth,
+        }
+    }
+
+    #[test]
+    fn test_vendor_configure() {
+        let mut rng = ThreadRng256 {};
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(&mut rng);
+        let key_agreement_pk = key_agreement_key.genpk();
+        let pin_protocol_v1 = PinProtocolV1::new_test(key_agreement_key, [0x91; 32]);
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        ctap_state.pin_protocol_v1 = pin_protocol_v1;
+
+        // Nothing should be configured at the beginning
+        let response = ctap_state.process_vendor_configure(
+            AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: None,
+                usb_identity: None,
+                challenge: None,
+            },
+            DUMMY_CHANNEL_ID,
+        );
+        assert_eq!(
+            response,
+            Ok(ResponseData::AuthenticatorVendorConfigure(
+                AuthenticatorVendorConfigureResponse {
+                    cert_programmed: false,
+                    pkey_programmed: false,
+                    attestation_signature: None,
+                }
+            ))
+        );
+
+        // Inject dummy values
+        let dummy_key = [0x41u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH];
+        let dummy_cert = [0xddu8; 20];
+
+        // Material with a wrong authentication is rejected and not stored.
+        let mut attestation_material = create_attestation_material(
+            &mut ThreadRng256 {},
+            &key_agreement_pk,
+            &[dummy_cert.to_vec()],
+            &dummy_key,
+        );
+        attestation_material.pin_auth[0] ^= 0x01;
+        let response = ctap_state.process_vendor_configure(
+            AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: Some(attestation_material),
+                usb_identity: None,
+                challenge: None,
+            },
+            DUMMY_CHANNEL_ID,
+        );
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID));
+        assert_eq!(
+            ctap_state.persistent_store.attestation_certificate(),
+            Ok(None)
+        );
+        assert_eq!(
+            ctap_state.persistent_store.attestation_private_key(),
+            Ok(None)
+        );
+
+        let response = ctap_state.process_vendor_configure(
+            AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: Some(create_attestation_material(
+                    &mut ThreadRng256 {},
+                    &key_agreement_pk,
+                    &[dummy_cert.to_vec()],
+                    &dummy_key,
+                )),
+                usb_identity: None,
+                challenge: None,
+            },
+            DUMMY_CHANNEL_ID,
+        );
+        assert_eq!(
+            response,
+            Ok(ResponseData::AuthenticatorVendorConfigure(
+                AuthenticatorVendorConfigureResponse {
+                    cert_programmed: true,
+                    pkey_programmed: true,
+                    attestation_signature: None,
+                }
+            ))
+        );
+        assert_eq!(
+            ctap_state
+                .persistent_store
+                .attestation_certificate()
+                .unwrap()
+                .unwrap(),
+            dummy_cert
+        );
+        assert_eq!(
+            ctap_state
+                .persistent_store
+                .attestation_private_key()
+                .unwrap()
+                .unwrap(),
+            dummy_key
+        );
+
+        // Try to inject other dummy values and check that initial values are retained.
+        let other_dummy_key = [0x44u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH];
+        let response = ctap_state.process_vendor_configure(
+            AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: Some(create_attestation_material(
+                    &mut ThreadRng256 {},
+                    &key_agreement_pk,
+                    &[dummy_cert.to_vec()],
+                    &other_dummy_key,
+                )),
+                usb_identity: None,
+                challenge: None,
+            },
+            DUMMY_CHANNEL_ID,
+        );
+        assert_eq!(
+            response,
+            Ok(ResponseData::AuthenticatorVendorConfigure(
+                AuthenticatorVendorConfigureResponse {
+                    cert_programmed: true,
+                    pkey_programmed: true,
+                    attestation_signature: None,
+                }
+            ))
+        );
+        assert_eq!(
+            ctap_state
+                .persistent_store
+                .attestation_certificate()
+                .unwrap()
+                .unwrap(),
+            dummy_cert
+        );
+        assert_eq!(
+            ctap_state
+                .persistent_store
+                .attestation_private_key()
+                .unwrap()
+                .unwrap(),
+            dummy_key
+        );
+
+        // Now try to lock the device
+        let response = ctap_state.process_vendor_configure(
+            AuthenticatorVendorConfigureParameters {
+                lockdown: true,
+                attestation_material: None,
+                usb_identity: None,
+                challenge: None,
+            },
+            DUMMY_CHANNEL_ID,
+        );
+        assert_eq!(
+            response,
+            Ok(ResponseData::AuthenticatorVendorConfigure(
+                AuthenticatorVendorConfigureResponse {
+                    cert_programmed: true,
+                    pkey_programmed: true,
+                    attestation_signature: None,
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_vendor_configure_certificate_chain() {
+        let mut rng = ThreadRng256 {};
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(&mut rng);
+        let key_agreement_pk = key_agreement_key.genpk();
+        let pin_protocol_v1 = PinProtocolV1::new_test(key_agreement_key, [0x91; 32]);
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        ctap_state.pin_protocol_v1 = pin_protocol_v1;
+        let dummy_key = [0x41u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH];
+        let dummy_chain = vec![vec![0xdd; 20], vec![0xC2; 30], vec![0xC3; 40]];
+
+        // The pinAuth must cover the intermediate certificates.
+        let mut attestation_material = create_attestation_material(
+            &mut ThreadRng256 {},
+            &key_agreement_pk,
+            &dummy_chain[..1],
+            &dummy_key,
+        );
+        attestation_material.certificate_chain = dummy_chain.clone();
+        let response = ctap_state.process_vendor_configure(
+            AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: Some(attestation_material),
+                usb_identity: None,
+                challenge: None,
+            },
+            DUMMY_CHANNEL_ID,
+        );
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID));
+
+        let response = ctap_state.process_vendor_configure(
+            AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: Some(create_attestation_material(
+                    &mut ThreadRng256 {},
+                    &key_agreement_pk,
+                    &dummy_chain,
+                    &dummy_key,
+                )),
+                usb_identity: None,
+                challenge: None,
+            },
+            DUMMY_CHANNEL_ID,
+        );
+        assert!(response.is_ok());
+        assert_eq!(
+            ctap_state.persistent_store.attestation_certificate(),
+            Ok(Some(dummy_chain[0].clone()))
+        );
+        let (_, certificate_chain) = ctap_state.batch_attestation().unwrap();
+        assert_eq!(certificate_chain, dummy_chain);
+    }
+
+    #[test]
+    fn test_vendor_configure_challenge() {
+        let mut rng = ThreadRng256 {};
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(&mut rng);
+        let key_agreement_pk = key_agreement_key.genpk();
+        let pin_protocol_v1 = PinProtocolV1::new_test(key_agreement_key, [0x91; 32]);
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        ctap_state.pin_protocol_v1 = pin_protocol_v1;
+        let challenge = [0xC4; 32];
+
+        // Without a private key, there is nothing to prove.
+        let response = ctap_state.process_vendor_configure(
+            AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: None,
+                usb_identity: None,
+                challenge: Some(challenge),
+            },
+            DUMMY_CHANNEL_ID,
+        );
+        match response {
+            Ok(ResponseData::AuthenticatorVendorConfigure(response)) => {
+                assert_eq!(response.attestation_signature, None)
+            }
+            _ => panic!("Invalid response type"),
+        }
+
+        // The signature covers the prefix and the challenge, with the injected key.
+        let dummy_key = [0x41u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH];
+        let dummy_cert = [0xddu8; 20];
+        let response = ctap_state.process_vendor_configure(
+            AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: Some(create_attestation_material(
+                    &mut ThreadRng256 {},
+                    &key_agreement_pk,
+                    &[dummy_cert.to_vec()],
+                    &dummy_key,
+                )),
+                usb_identity: None,
+                challenge: Some(challenge),
+            },
+            DUMMY_CHANNEL_ID,
+        );
+        let mut message = ATTESTATION_CHALLENGE_PREFIX.to_vec();
+        message.extend_from_slice(&challenge);
+        let attestation_key = crypto::ecdsa::SecKey::from_bytes(&dummy_key).unwrap();
+        let expected_signature = Crypto::ecdsa_sign(&attestation_key, &message).to_asn1_der();
+        assert_eq!(
+            response,
+            Ok(ResponseData::AuthenticatorVendorConfigure(
+                AuthenticatorVendorConfigureResponse {
+                    cert_programmed: true,
+                    pkey_programmed: true,
+                    attestation_signature: Some(expected_signature),
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_vendor_configure_usb_identity() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        assert_eq!(ctap_state.usb_identity(), None);
+
+        let identity = UsbIdentity {
+            vendor_id: 0x1915,
+            product_id: 0x521F,
+            serial_number: Some(String::from("WL-0001")),
+        };
+        let response = ctap_state.process_vendor_configure(
+            AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: None,
+                usb_identity: Some(identity.clone()),
+                challenge: None,
+            },
+            DUMMY_CHANNEL_ID,
+        );
+        assert!(response.is_ok());
+        assert_eq!(ctap_state.usb_identity(), Some(identity.clone()));
+
+        // Program the attestation material, such that the lockdown conditions are met.
+        ctap_state
+            .persistent_store
+            .set_attestation_certificate_chain(&[vec![0xdd; 20]])
+            .unwrap();
+        ctap_state
+            .persistent_store
+            .set_attestation_private_key(&[0x41; key_material::ATTESTATION_PRIVATE_KEY_LENGTH])
+            .unwrap();
+        let response = ctap_state.process_vendor_configure(
+            AuthenticatorVendorConfigureParameters {
+                lockdown: true,
+                attestation_material: None,
+                usb_identity: None,
+                challenge: None,
+            },
+            DUMMY_CHANNEL_ID,
+        );
+        assert!(response.is_ok());
+
+        // The identity can't change after the lockdown.
+        let response = ctap_state.process_vendor_configure(
+            AuthenticatorVendorConfigureParameters {
+                lockdown: false,
+                attestation_material: None,
+                usb_identity: Some(UsbIdentity {
+                    vendor_id: 0x1234,
+                    product_id: 0x5678,
+                    serial_number: None,
+                }),
+                challenge: None,
+            },
+            DUMMY_CHANNEL_ID,
+        );
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED));
+        assert_eq!(ctap_state.usb_identity(), Some(identity));
+    }
+
+    #[test]
+    fn test_vendor_audit_log() {
+        let mut rng = ThreadRng256 {};
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(&mut rng);
+        let pin_uv_auth_token = [0x88; 32];
+        let pin_protocol_v1 = PinProtocolV1::new_test(key_agreement_key, pin_uv_auth_token);
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        ctap_state.pin_protocol_v1 = pin_protocol_v1;
+        ctap_state
+            .persistent_store
+            .append_audit_event(AuditEventType::MakeCredential, 10)
+            .unwrap();
+
+        let mut auth_message = vec![0xFF; 32];
+        auth_message.extend(&[u8::from(CommandCode::VendorAuditLog), 0x01]);
+        let pin_uv_auth_param =
+            hmac_256::<Sha256>(&pin_uv_auth_token, &auth_message)[..16].to_vec();
+        let audit_log_params = AuthenticatorVendorAuditLogParameters {
+            clear: true,
+            pin_uv_auth_param,
+            pin_uv_auth_protocol: 1,
+        };
 
-        let reset_reponse = ctap_state.process_reset(DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        // The log is only accessible with a PIN.
+        let response =
+            ctap_state.process_vendor_audit_log(audit_log_params.clone(), DUMMY_CLOCK_VALUE);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_NOT_SET));
 
-        assert_eq!(
-            reset_reponse,
-            Err(Ctap2StatusCode::CTAP2_ERR_KEEPALIVE_CANCEL)
+        ctap_state
+            .persistent_store
+            .set_pin_hash(&[0u8; 16])
+            .unwrap();
+
+        // The PIN auth covers the clear flag.
+        let response = ctap_state.process_vendor_audit_log(
+            AuthenticatorVendorAuditLogParameters {
+                clear: false,
+                ..audit_log_params.clone()
+            },
+            DUMMY_CLOCK_VALUE,
         );
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID));
+
+        let response = ctap_state.process_vendor_audit_log(audit_log_params, DUMMY_CLOCK_VALUE);
+        let events = match response {
+            Ok(ResponseData::AuthenticatorVendorAuditLog(response)) => response.events,
+            _ => panic!("Invalid response type"),
+        };
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_type, AuditEventType::MakeCredential);
+        let events = ctap_state.persistent_store.audit_log().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event_type, AuditEventType::AuditLogCleared);
     }
 
+    #[cfg(feature = "with_ctap2_1")]
     #[test]
-    fn test_process_reset_not_first() {
+    fn test_process_selection() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
         let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let response = ctap_state.process_command(&[0x0B], DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, vec![0x00]);
 
-        // This is a GetNextAssertion command.
-        ctap_state.process_command(&[0x08], DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
-
-        let reset_reponse = ctap_state.process_reset(DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
-        assert_eq!(reset_reponse, Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED));
-    }
-
-    #[test]
-    fn test_process_unknown_command() {
         let mut rng = ThreadRng256 {};
-        let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let user_denies = |_| Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED);
+        let mut ctap_state = CtapState::new(&mut rng, user_denies, DUMMY_CLOCK_VALUE);
+        let response = ctap_state.process_command(&[0x0B], DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, vec![0x27]);
 
-        // This command does not exist.
-        let reset_reponse =
-            ctap_state.process_command(&[0xDF], DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
-        let expected_response = vec![Ctap2StatusCode::CTAP1_ERR_INVALID_COMMAND as u8];
-        assert_eq!(reset_reponse, expected_response);
+        let mut rng = ThreadRng256 {};
+        let user_never_present = |_| Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT);
+        let mut ctap_state = CtapState::new(&mut rng, user_never_present, DUMMY_CLOCK_VALUE);
+        let response = ctap_state.process_command(&[0x0B], DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, vec![0x2F]);
     }
 
+    #[cfg(feature = "debug_ctap")]
     #[test]
-    fn test_encrypt_decrypt_credential() {
+    fn test_vendor_display_name() {
         let mut rng = ThreadRng256 {};
-        let user_immediately_present = |_| Ok(());
         let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
+        let credential_id = rng.gen_uniform_u8x32().to_vec();
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(&mut rng);
+        let pin_uv_auth_token = [0x88; 32];
+        let pin_protocol_v1 = PinProtocolV1::new_test(key_agreement_key, pin_uv_auth_token);
+        let user_immediately_present = |_| Ok(());
         let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        ctap_state.pin_protocol_v1 = pin_protocol_v1;
 
-        // Usually, the relying party ID or its hash is provided by the client.
-        // We are not testing the correctness of our SHA256 here, only if it is checked.
-        let rp_id_hash = [0x55; 32];
-        let encrypted_id = ctap_state
-            .encrypt_key_handle(private_key.clone(), &rp_id_hash)
-            .unwrap();
-        let decrypted_source = ctap_state
-            .decrypt_credential_source(encrypted_id, &rp_id_hash)
-            .unwrap()
+        let credential = PublicKeyCredentialSource {
+            key_type: PublicKeyCredentialType::PublicKey,
+            credential_id: credential_id.clone(),
+            private_key,
+            rp_id: String::from("example.com"),
+            user_handle: vec![0x1D],
+            user_display_name: None,
+            cred_protect_policy: None,
+            creation_order: 0,
+            user_name: None,
+            user_icon: None,
+            authenticator_display_name: None,
+            transports: None,
+        };
+        assert!(ctap_state
+            .persistent_store
+            .store_credential(credential)
+            .is_ok());
+
+        // Without a PIN, user presence is enough.
+        let display_name_params = AuthenticatorVendorDisplayNameParameters {
+            credential_id: credential_id.clone(),
+            display_name: Some(String::from("work key")),
+            pin_uv_auth_protocol: None,
+            pin_uv_auth_param: None,
+        };
+        let response = ctap_state.process_vendor(
+            AuthenticatorVendorParameters::DisplayName(display_name_params.clone()),
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        assert_eq!(
+            response,
+            Ok(ResponseData::AuthenticatorVendor(
+                AuthenticatorVendorResponse::DisplayName
+            ))
+        );
+        // The display name is returned when enumerating credentials.
+        let credentials = ctap_state
+            .persistent_store
+            .filter_credential("example.com", false)
             .unwrap();
+        assert_eq!(credentials.len(), 1);
+        let credential = &credentials[0];
+        assert_eq!(
+            credential.authenticator_display_name,
+            Some(String::from("work key"))
+        );
 
-        assert_eq!(private_key, decrypted_source.private_key);
-    }
+        let response = ctap_state.process_vendor_display_name(
+            AuthenticatorVendorDisplayNameParameters {
+                credential_id: vec![0xCC; 32],
+                ..display_name_params
+            },
+            DUMMY_CHANNEL_ID,
+        );
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS));
 
-    #[test]
-    fn test_encrypt_decrypt_bad_hmac() {
-        let mut rng = ThreadRng256 {};
-        let user_immediately_present = |_| Ok(());
-        let private_key = crypto::ecdsa::SecKey::gensk(&mut rng);
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        // With a PIN, the display name is removed only with a PIN auth over the parameters.
+        ctap_state
+            .persistent_store
+            .set_pin_hash(&[0u8; 16])
+            .unwrap();
+        let display_name_params = AuthenticatorVendorDisplayNameParameters {
+            credential_id: credential_id.clone(),
+            display_name: None,
+            pin_uv_auth_protocol: Some(1),
+            pin_uv_auth_param: None,
+        };
+        let response =
+            ctap_state.process_vendor_display_name(display_name_params.clone(), DUMMY_CHANNEL_ID);
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_REQUIRED));
+
+        let mut auth_message = vec![0xFF; 32];
+        auth_message.extend(&[u8::from(CommandCode::Vendor), 0x05]);
+        assert!(cbor::write(
+            cbor_map! { 1 => credential_id.clone() },
+            &mut auth_message
+        ));
+        let pin_uv_auth_param =
+            hmac_256::<Sha256>(&pin_uv_auth_token, &auth_message)[..16].to_vec();
+        let response = ctap_state.process_vendor_display_name(
+            AuthenticatorVendorDisplayNameParameters {
+                display_name: Some(String::from("other key")),
+                pin_uv_auth_param: Some(pin_uv_auth_param.clone()),
+                ..display_name_params.clone()
+            },
+            DUMMY_CHANNEL_ID,
+        );
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID));
 
-        // Same as above.
-        let rp_id_hash = [0x55; 32];
-        let encrypted_id = ctap_state
-            .encrypt_key_handle(private_key, &rp_id_hash)
+        let response = ctap_state.process_vendor_display_name(
+            AuthenticatorVendorDisplayNameParameters {
+                pin_uv_auth_param: Some(pin_uv_auth_param),
+                ..display_name_params
+            },
+            DUMMY_CHANNEL_ID,
+        );
+        assert_eq!(response, Ok(ResponseData::AuthenticatorVendorDisplayName));
+        let credentials = ctap_state
+            .persistent_store
+            .filter_credential("example.com", false)
             .unwrap();
-        for i in 0..encrypted_id.len() {
-            let mut modified_id = encrypted_id.clone();
-            modified_id[i] ^= 0x01;
-            assert!(ctap_state
-                .decrypt_credential_source(modified_id, &rp_id_hash)
-                .unwrap()
-                .is_none());
-        }
+        assert_eq!(credentials.len(), 1);
+        let credential = &credentials[0];
+        assert_eq!(credential.authenticator_display_name, None);
     }
 
+    #[cfg(feature = "debug_ctap")]
     #[test]
-    fn test_signature_counter() {
+    fn test_vendor_user_presence() {
         let mut rng = ThreadRng256 {};
-        let user_immediately_present = |_| Ok(());
-        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        let user_never_present = |_| Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT);
+        let mut ctap_state = CtapState::new(&mut rng, user_never_present, DUMMY_CLOCK_VALUE);
 
-        let mut last_counter = ctap_state
-            .persistent_store
-            .global_signature_counter()
-            .unwrap();
-        assert!(last_counter > 0);
-        for _ in 0..100 {
-            assert!(ctap_state.increment_global_signature_counter().is_ok());
-            let next_counter = ctap_state
-                .persistent_store
-                .global_signature_counter()
-                .unwrap();
-            assert!(next_counter > last_counter);
-            last_counter = next_counter;
-        }
+        assert_eq!(
+            ctap_state.check_user_presence(DUMMY_CHANNEL_ID),
+            Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT)
+        );
+        let response = ctap_state.process_command(&[0x42], DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, vec![0x00]);
+        // The grant is consumed by the first check.
+        assert_eq!(ctap_state.check_user_presence(DUMMY_CHANNEL_ID), Ok(()));
+        assert_eq!(
+            ctap_state.check_user_presence(DUMMY_CHANNEL_ID),
+            Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT)
+        );
     }
 
     #[test]
-    fn test_vendor_configure() {
+    fn test_vendor_metrics() {
         let mut rng = ThreadRng256 {};
         let user_immediately_present = |_| Ok(());
         let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
 
-        // Nothing should be configured at the beginning
-        let response = ctap_state.process_vendor_configure(
-            AuthenticatorVendorConfigureParameters {
-                lockdown: false,
-                attestation_material: None,
+        let mut command_cbor = vec![0x01];
+        assert!(cbor::write(
+            cbor_map! {
+                1 => vec![0xCD],
+                2 => cbor_map! {"id" => "example.com"},
+                3 => cbor_map! {"id" => vec![0x1D]},
+                4 => cbor_array![ES256_CRED_PARAM],
             },
-            DUMMY_CHANNEL_ID,
-        );
+            &mut command_cbor
+        ));
+        let response =
+            ctap_state.process_command(&command_cbor, DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response[0], 0x00);
+
+        let response = ctap_state.process_vendor_metrics();
+        let expected_registrations = if USE_METRICS { 1 } else { 0 };
         assert_eq!(
             response,
-            Ok(ResponseData::AuthenticatorVendor(
-                AuthenticatorVendorResponse {
-                    cert_programmed: false,
-                    pkey_programmed: false,
+            Ok(ResponseData::AuthenticatorVendorMetrics(
+                AuthenticatorVendorMetricsResponse {
+                    registrations: expected_registrations,
+                    assertions: 0,
                 }
             ))
         );
+    }
 
-        // Inject dummy values
-        let dummy_key = [0x41u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH];
-        let dummy_cert = [0xddu8; 20];
-        let response = ctap_state.process_vendor_configure(
-            AuthenticatorVendorConfigureParameters {
-                lockdown: false,
-                attestation_material: Some(AuthenticatorAttestationMaterial {
-                    certificate: dummy_cert.to_vec(),
-                    private_key: dummy_key,
-                }),
-            },
+    #[test]
+    fn test_vendor_subcommands() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        let response = ctap_state.process_vendor(
+            AuthenticatorVendorParameters::Metrics,
             DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
         );
         assert_eq!(
             response,
             Ok(ResponseData::AuthenticatorVendor(
-                AuthenticatorVendorResponse {
-                    cert_programmed: true,
-                    pkey_programmed: true,
-                }
+                AuthenticatorVendorResponse::Metrics(AuthenticatorVendorMetricsResponse {
+                    registrations: 0,
+                    assertions: 0,
+                })
             ))
         );
-        assert_eq!(
-            ctap_state
-                .persistent_store
-                .attestation_certificate()
-                .unwrap()
-                .unwrap(),
-            dummy_cert
-        );
-        assert_eq!(
-            ctap_state
-                .persistent_store
-                .attestation_private_key()
-                .unwrap()
-                .unwrap(),
-            dummy_key
-        );
 
-        // Try to inject other dummy values and check that initial values are retained.
-        let other_dummy_key = [0x44u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH];
-        let response = ctap_state.process_vendor_configure(
-            AuthenticatorVendorConfigureParameters {
+        let response = ctap_state.process_vendor(
+            AuthenticatorVendorParameters::Configure(AuthenticatorVendorConfigureParameters {
                 lockdown: false,
-                attestation_material: Some(AuthenticatorAttestationMaterial {
-                    certificate: dummy_cert.to_vec(),
-                    private_key: other_dummy_key,
-                }),
-            },
+                attestation_material: None,
+                usb_identity: None,
+                challenge: None,
+            }),
             DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
         );
         assert_eq!(
             response,
             Ok(ResponseData::AuthenticatorVendor(
-                AuthenticatorVendorResponse {
-                    cert_programmed: true,
-                    pkey_programmed: true,
-                }
+                AuthenticatorVendorResponse::Configure(AuthenticatorVendorConfigureResponse {
+                    cert_programmed: false,
+                    pkey_programmed: false,
+                    attestation_signature: None,
+                })
             ))
         );
-        assert_eq!(
-            ctap_state
-                .persistent_store
-                .attestation_certificate()
-                .unwrap()
-                .unwrap(),
-            dummy_cert
-        );
-        assert_eq!(
-            ctap_state
-                .persistent_store
-                .attestation_private_key()
-                .unwrap()
-                .unwrap(),
-            dummy_key
-        );
 
-        // Now try to lock the device
-        let response = ctap_state.process_vendor_configure(
-            AuthenticatorVendorConfigureParameters {
-                lockdown: true,
-                attestation_material: None,
-            },
+        // The legacy command code answers with the untagged response.
+        let response = ctap_state.process_command(
+            &[u8::from(CommandCode::VendorConfigure), 0xA0],
+            DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
+        );
+        assert_eq!(response, vec![0x00, 0xA2, 0x01, 0xF4, 0x02, 0xF4]);
+        // The vendor command code answers with the tagged response.
+        let response = ctap_state.process_command(
+            &[u8::from(CommandCode::Vendor), 0xA2, 0x01, 0x01, 0x02, 0xA0],
             DUMMY_CHANNEL_ID,
+            DUMMY_CLOCK_VALUE,
         );
         assert_eq!(
             response,
-            Ok(ResponseData::AuthenticatorVendor(
-                AuthenticatorVendorResponse {
-                    cert_programmed: true,
-                    pkey_programmed: true,
-                }
-            ))
+            vec![0x00, 0xA2, 0x01, 0x01, 0x02, 0xA2, 0x01, 0xF4, 0x02, 0xF4]
         );
     }
+
+    #[test]
+    fn test_vendor_self_test() {
+        let mut rng = ThreadRng256 {};
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+
+        let response = ctap_state.process_vendor_self_test();
+        match response {
+            Ok(ResponseData::AuthenticatorVendorSelfTest(response)) => {
+                assert!(response.rng);
+                assert!(response.crypto);
+                assert!(response.storage);
+                assert!(response.store_check.unwrap().consistent);
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
+
+    struct StuckRng256;
+
+    impl Rng256 for StuckRng256 {
+        fn gen_uniform_u8x32(&mut self) -> [u8; 32] {
+            [0x55; 32]
+        }
+    }
+
+    #[test]
+    fn test_unhealthy_rng_fails_closed() {
+        let mut rng = HealthCheckedRng256::new(StuckRng256);
+        let user_immediately_present = |_| Ok(());
+        let mut ctap_state = CtapState::new(&mut rng, user_immediately_present, DUMMY_CLOCK_VALUE);
+        assert!(!ctap_state.is_rng_healthy());
+
+        let response = ctap_state.process_command(&[0x04], DUMMY_CHANNEL_ID, DUMMY_CLOCK_VALUE);
+        assert_eq!(response, vec![Ctap2StatusCode::CTAP2_ERR_PROCESSING as u8]);
+
+        // The self test still reports its results.
+        let response = ctap_state.process_vendor_self_test();
+        match response {
+            Ok(ResponseData::AuthenticatorVendorSelfTest(response)) => {
+                assert!(!response.rng);
+                assert!(response.crypto);
+                assert!(response.storage);
+            }
+            _ => panic!("Invalid response type"),
+        }
+    }
 }
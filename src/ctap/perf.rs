@@ -0,0 +1,141 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::collections::BTreeMap;
+use libtock_drivers::timer::ClockValue;
+
+/// Reads the current time, to measure how long commands take on the device.
+pub trait PerfClock {
+    /// Returns the current time.
+    fn now(&self) -> ClockValue;
+}
+
+/// Latency statistics of a command, in milliseconds.
+#[derive(Clone, Copy, Default)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug, PartialEq))]
+pub struct CommandTiming {
+    /// Number of measured executions.
+    pub count: u64,
+    /// Fastest execution.
+    pub min_ms: u64,
+    /// Slowest execution.
+    pub max_ms: u64,
+    /// Sum of all executions, for the average.
+    pub total_ms: u64,
+}
+
+impl CommandTiming {
+    /// Returns the average execution time, rounded down.
+    pub fn average_ms(&self) -> u64 {
+        self.total_ms.checked_div(self.count).unwrap_or(0)
+    }
+
+    fn add(&mut self, latency_ms: u64) {
+        if self.count == 0 || latency_ms < self.min_ms {
+            self.min_ms = latency_ms;
+        }
+        self.max_ms = core::cmp::max(self.max_ms, latency_ms);
+        self.count = self.count.saturating_add(1);
+        self.total_ms = self.total_ms.saturating_add(latency_ms);
+    }
+}
+
+/// Latency statistics per command code, since boot.
+///
+/// They only live in RAM, so that measuring does not wear the flash.
+#[derive(Default)]
+pub struct CommandTimings {
+    timings: BTreeMap<u8, CommandTiming>,
+}
+
+impl CommandTimings {
+    /// Records an execution of a command between two instants.
+    ///
+    /// Returns the updated statistics of the command, or `None` if the latency can't be computed,
+    /// for example because the clock wrapped around.
+    pub fn record(
+        &mut self,
+        command_code: u8,
+        start: ClockValue,
+        end: ClockValue,
+    ) -> Option<CommandTiming> {
+        let latency_ms = end.wrapping_sub(start)?.ms();
+        if latency_ms < 0 {
+            return None;
+        }
+        let timing = self.timings.entry(command_code).or_default();
+        timing.add(latency_ms as u64);
+        Some(*timing)
+    }
+
+    /// Returns the statistics of all measured commands, ordered by command code.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &CommandTiming)> {
+        self.timings.iter().map(|(code, timing)| (*code, timing))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    const CLOCK_FREQUENCY_HZ: usize = 32768;
+
+    fn clock_value(ms: isize) -> ClockValue {
+        ClockValue::new(ms * CLOCK_FREQUENCY_HZ as isize / 1000, CLOCK_FREQUENCY_HZ)
+    }
+
+    #[test]
+    fn test_command_timing() {
+        let mut timing = CommandTiming::default();
+        assert_eq!(timing.average_ms(), 0);
+        timing.add(30);
+        timing.add(10);
+        timing.add(20);
+        let expected = CommandTiming {
+            count: 3,
+            min_ms: 10,
+            max_ms: 30,
+            total_ms: 60,
+        };
+        assert_eq!(timing, expected);
+        assert_eq!(timing.average_ms(), 20);
+    }
+
+    #[test]
+    fn test_record() {
+        let mut timings = CommandTimings::default();
+        let timing = timings.record(0x01, clock_value(1000), clock_value(1250));
+        assert_eq!(timing.map(|t| t.max_ms), Some(250));
+        timings.record(0x04, clock_value(2000), clock_value(2002));
+        timings.record(0x01, clock_value(3000), clock_value(3125));
+        let codes: Vec<u8> = timings.iter().map(|(code, _)| code).collect();
+        assert_eq!(codes, vec![0x01, 0x04]);
+        let (_, timing) = timings.iter().next().unwrap();
+        assert_eq!(timing.count, 2);
+        assert_eq!(timing.min_ms, 125);
+        assert_eq!(timing.average_ms(), 187);
+    }
+
+    #[test]
+    fn test_record_wrapped_clock() {
+        let mut timings = CommandTimings::default();
+        assert_eq!(
+            timings.record(0x01, clock_value(1000), clock_value(500)),
+            None
+        );
+        assert_eq!(timings.iter().count(), 0);
+    }
+}
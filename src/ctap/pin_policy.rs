@@ -0,0 +1,196 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Decides which new PINs are allowed when setting or changing the PIN.
+///
+/// A PIN that is not allowed is rejected with CTAP2_ERR_PIN_POLICY_VIOLATION. The PIN is given
+/// without padding. It is already checked to be valid UTF-8 without NUL character, and to be at
+/// most 63 bytes long.
+pub trait PinPolicy {
+    /// Returns whether the PIN is allowed.
+    ///
+    /// The minimum PIN length is in Unicode code points, as configured by the platform.
+    fn is_allowed(&self, pin: &str, min_pin_length: usize) -> bool;
+}
+
+/// The policy of the specification: a PIN only needs the minimum PIN length.
+pub struct DefaultPinPolicy;
+
+impl PinPolicy for DefaultPinPolicy {
+    fn is_allowed(&self, pin: &str, min_pin_length: usize) -> bool {
+        pin.chars().count() >= min_pin_length
+    }
+}
+
+/// A stricter policy for vendors, on top of the specification.
+///
+/// The default value is as permissive as `DefaultPinPolicy`, each field enables a check.
+#[derive(Default)]
+pub struct StrictPinPolicy {
+    /// Minimum PIN length in code points, if it is longer than the one of the platform.
+    pub min_length: usize,
+    /// PINs that are too common, like "123456" or "password".
+    pub banned_pins: &'static [&'static str],
+    /// Rejects PINs made of a single repeated character, like "0000".
+    pub forbid_repeated_character: bool,
+    /// Rejects PINs of consecutive characters, like "1234" or "dcba".
+    pub forbid_sequence: bool,
+    /// Requires alphanumeric PINs to contain at least a letter and a digit.
+    ///
+    /// Numeric PINs are accepted, since their character class is imposed by the keypad.
+    pub require_letter_and_digit: bool,
+}
+
+impl PinPolicy for StrictPinPolicy {
+    fn is_allowed(&self, pin: &str, min_pin_length: usize) -> bool {
+        let min_pin_length = core::cmp::max(min_pin_length, self.min_length);
+        DefaultPinPolicy.is_allowed(pin, min_pin_length)
+            && !self.banned_pins.contains(&pin)
+            && !(self.forbid_repeated_character && is_repeated_character(pin))
+            && !(self.forbid_sequence && is_sequence(pin))
+            && !(self.require_letter_and_digit && !has_letter_and_digit(pin))
+    }
+}
+
+/// Returns whether all characters of the PIN are the same.
+fn is_repeated_character(pin: &str) -> bool {
+    let mut chars = pin.chars();
+    let first = chars.next();
+    chars.all(|c| Some(c) == first)
+}
+
+/// Returns whether the code points of the PIN increase or decrease by one at each step.
+fn is_sequence(pin: &str) -> bool {
+    let steps = || {
+        pin.chars()
+            .zip(pin.chars().skip(1))
+            .map(|(a, b)| b as i64 - a as i64)
+    };
+    steps().all(|step| step == 1) || steps().all(|step| step == -1)
+}
+
+/// Returns whether the PIN is numeric, or contains both a letter and a digit.
+fn has_letter_and_digit(pin: &str) -> bool {
+    let has_digit = pin.chars().any(|c| c.is_ascii_digit());
+    let is_numeric = pin.chars().all(|c| c.is_ascii_digit());
+    let has_letter = pin.chars().any(char::is_alphabetic);
+    is_numeric || (has_letter && has_digit)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_pin_policy() {
+        assert!(DefaultPinPolicy.is_allowed("1234", 4));
+        assert!(DefaultPinPolicy.is_allowed("0000", 4));
+        assert!(!DefaultPinPolicy.is_allowed("123", 4));
+        // The length is counted in code points, not in bytes.
+        assert!(DefaultPinPolicy.is_allowed("\u{e9}t\u{e9}s", 4));
+        assert!(!DefaultPinPolicy.is_allowed("\u{e9}\u{e9}\u{e9}", 4));
+        // Code points are counted as is, without normalization.
+        assert!(DefaultPinPolicy.is_allowed("e\u{301}", 2));
+    }
+
+    #[test]
+    fn test_strict_pin_policy_default() {
+        let policy = StrictPinPolicy::default();
+        assert!(policy.is_allowed("0000", 4));
+        assert!(policy.is_allowed("1234", 4));
+        assert!(!policy.is_allowed("123", 4));
+    }
+
+    #[test]
+    fn test_strict_pin_policy_min_length() {
+        let policy = StrictPinPolicy {
+            min_length: 6,
+            ..StrictPinPolicy::default()
+        };
+        assert!(!policy.is_allowed("13579", 4));
+        assert!(policy.is_allowed("135790", 4));
+        // The minimum PIN length of the platform still applies.
+        assert!(!policy.is_allowed("135790", 8));
+    }
+
+    #[test]
+    fn test_strict_pin_policy_banned_pins() {
+        let policy = StrictPinPolicy {
+            banned_pins: &["123456", "password"],
+            ..StrictPinPolicy::default()
+        };
+        assert!(!policy.is_allowed("123456", 4));
+        assert!(!policy.is_allowed("password", 4));
+        assert!(policy.is_allowed("1234567", 4));
+        assert!(policy.is_allowed("Password", 4));
+    }
+
+    #[test]
+    fn test_strict_pin_policy_repeated_character() {
+        let policy = StrictPinPolicy {
+            forbid_repeated_character: true,
+            ..StrictPinPolicy::default()
+        };
+        assert!(policy.is_allowed("1234", 4));
+        assert!(policy.is_allowed("1112", 4));
+        assert!(!policy.is_allowed("0000", 4));
+        assert!(!policy.is_allowed("\u{e9}\u{e9}\u{e9}\u{e9}", 4));
+    }
+
+    #[test]
+    fn test_strict_pin_policy_sequence() {
+        let policy = StrictPinPolicy {
+            forbid_sequence: true,
+            ..StrictPinPolicy::default()
+        };
+        assert!(!policy.is_allowed("1234", 4));
+        assert!(!policy.is_allowed("987654", 4));
+        assert!(!policy.is_allowed("abcd", 4));
+        assert!(!policy.is_allowed("dcba", 4));
+        assert!(policy.is_allowed("1235", 4));
+        assert!(policy.is_allowed("1212", 4));
+        // A repeated character is not a sequence.
+        assert!(policy.is_allowed("0000", 4));
+    }
+
+    #[test]
+    fn test_strict_pin_policy_letter_and_digit() {
+        let policy = StrictPinPolicy {
+            require_letter_and_digit: true,
+            ..StrictPinPolicy::default()
+        };
+        assert!(policy.is_allowed("1234", 4));
+        assert!(policy.is_allowed("pass1234", 4));
+        assert!(policy.is_allowed("\u{e9}t\u{e9}2020", 4));
+        assert!(!policy.is_allowed("password", 4));
+        assert!(!policy.is_allowed("12-34", 4));
+    }
+
+    #[test]
+    fn test_is_repeated_character() {
+        assert!(is_repeated_character(""));
+        assert!(is_repeated_character("0"));
+        assert!(is_repeated_character("0000"));
+        assert!(!is_repeated_character("0001"));
+    }
+
+    #[test]
+    fn test_is_sequence() {
+        assert!(is_sequence("5"));
+        assert!(is_sequence("34"));
+        assert!(is_sequence("43"));
+        assert!(!is_sequence("1243"));
+        assert!(!is_sequence("1357"));
+    }
+}
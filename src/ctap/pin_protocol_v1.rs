@@ -14,32 +14,48 @@
 
 use super::command::AuthenticatorClientPinParameters;
 use super::data_formats::{ClientPinSubCommand, CoseKey, GetAssertionHmacSecretInput};
+use super::key_material;
+use super::pin_policy::{DefaultPinPolicy, PinPolicy};
+use super::pin_token::{PinToken, MAX_PIN_TOKEN_LENGTH};
 use super::response::{AuthenticatorClientPinResponse, ResponseData};
 use super::status_code::Ctap2StatusCode;
 use super::storage::PersistentStore;
 #[cfg(feature = "with_ctap2_1")]
+use super::storage::MAX_RP_IDS_LENGTH;
+use super::timed_permission::TimedPermission;
+use super::{Crypto, Sha256};
+use alloc::boxed::Box;
+#[cfg(feature = "with_ctap2_1")]
 use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use arrayref::array_ref;
+#[cfg(feature = "with_ctap2_1")]
+use cbor::cbor_array_vec;
 use core::convert::TryInto;
 use crypto::cbc::{cbc_decrypt, cbc_encrypt};
 use crypto::hmac::{hmac_256, verify_hmac_256_first_128bits};
+use crypto::provider::CryptoProvider;
 use crypto::rng256::Rng256;
-use crypto::sha256::Sha256;
 use crypto::Hash256;
 #[cfg(all(test, feature = "with_ctap2_1"))]
 use enum_iterator::IntoEnumIterator;
-use subtle::ConstantTimeEq;
+use libtock_drivers::timer::{ClockValue, Duration};
 
 // Those constants have to be multiples of 16, the AES block size.
 pub const PIN_AUTH_LENGTH: usize = 16;
 const PIN_PADDED_LENGTH: usize = 64;
+// The pinUvAuthToken length may be modified before compilation, to either 16 or 32 bytes.
 const PIN_TOKEN_LENGTH: usize = 32;
 
+// Number of consecutive PIN mismatches after which a power cycle is required.
+const MAX_CONSECUTIVE_PIN_MISMATCHES: u8 = 3;
+// Delay after the first PIN mismatch of a boot session. It doubles with each consecutive mismatch.
+const PIN_BACKOFF_BASE_DELAY_MS: isize = 1000;
+
 /// Checks the given pin_auth against the truncated output of HMAC-SHA256.
 /// Returns LEFT(HMAC(hmac_key, hmac_contents), 16) == pin_auth).
-fn verify_pin_auth(hmac_key: &[u8], hmac_contents: &[u8], pin_auth: &[u8]) -> bool {
+pub fn verify_pin_auth(hmac_key: &[u8], hmac_contents: &[u8], pin_auth: &[u8]) -> bool {
     if pin_auth.len() != PIN_AUTH_LENGTH {
         return false;
     }
@@ -61,8 +77,8 @@ fn encrypt_hmac_secret_output(
     if salt_enc.len() != 32 && salt_enc.len() != 64 {
         return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_EXTENSION);
     }
-    let aes_enc_key = crypto::aes256::EncryptionKey::new(shared_secret);
-    let aes_dec_key = crypto::aes256::DecryptionKey::new(&aes_enc_key);
+    let aes_enc_key = Crypto::aes256_encryption_key(shared_secret);
+    let aes_dec_key = Crypto::aes256_decryption_key(&aes_enc_key);
     // The specification specifically asks for a zero IV.
     let iv = [0u8; 16];
 
@@ -100,9 +116,9 @@ fn encrypt_hmac_secret_output(
     Ok(encrypted_output)
 }
 
-/// Decrypts the new_pin_enc and outputs the found PIN.
+/// Decrypts the new_pin_enc and outputs the found PIN, stripped from its trailing 0x00 padding.
 fn decrypt_pin(
-    aes_dec_key: &crypto::aes256::DecryptionKey,
+    aes_dec_key: &<Crypto as CryptoProvider>::Aes256DecryptionKey,
     new_pin_enc: Vec<u8>,
 ) -> Option<Vec<u8>> {
     if new_pin_enc.len() != PIN_PADDED_LENGTH {
@@ -116,26 +132,35 @@ fn decrypt_pin(
         blocks[i].copy_from_slice(&new_pin_enc[i * 16..(i + 1) * 16]);
     }
     cbc_decrypt(aes_dec_key, iv, &mut blocks);
-    // In CTAP 2.1, the specification changed. The new wording might lead to
-    // different behavior when there are non-zero bytes after zero bytes.
-    // This implementation consistently ignores those degenerate cases.
-    Some(
-        blocks
-            .iter()
-            .flatten()
-            .cloned()
-            .take_while(|&c| c != 0)
-            .collect::<Vec<u8>>(),
-    )
+    let mut pin = blocks.iter().flatten().cloned().collect::<Vec<u8>>();
+    // Only trailing zeros are padding. Zeros followed by non-zero bytes are part of the PIN, and
+    // make it invalid.
+    while pin.last() == Some(&0) {
+        pin.pop();
+    }
+    Some(pin)
+}
+
+/// Returns the PIN as a string.
+///
+/// PINs are UTF-8 strings. The platform is responsible for their NFC normalization. Invalid
+/// UTF-8 and the NUL character are rejected.
+fn pin_as_str(pin: &[u8]) -> Option<&str> {
+    let pin = core::str::from_utf8(pin).ok()?;
+    if pin.contains('\0') {
+        return None;
+    }
+    Some(pin)
 }
 
 /// Stores the encrypted new PIN in the persistent storage, if it satisfies the
 /// PIN policy. The PIN is decrypted and stripped from its padding. Next, the
-/// length of the PIN is checked to fulfill policy requirements. Last, the PIN
-/// is hashed, truncated to 16 bytes and persistently stored.
+/// encoding of the PIN and the PIN policy are checked. Last, the PIN is hashed,
+/// truncated to 16 bytes and persistently stored.
 fn check_and_store_new_pin(
     persistent_store: &mut PersistentStore,
-    aes_dec_key: &crypto::aes256::DecryptionKey,
+    pin_policy: &dyn PinPolicy,
+    aes_dec_key: &<Crypto as CryptoProvider>::Aes256DecryptionKey,
     new_pin_enc: Vec<u8>,
 ) -> Result<(), Ctap2StatusCode> {
     let pin = decrypt_pin(aes_dec_key, new_pin_enc)
@@ -145,8 +170,12 @@ fn check_and_store_new_pin(
     let min_pin_length = persistent_store.min_pin_length()? as usize;
     #[cfg(not(feature = "with_ctap2_1"))]
     let min_pin_length = 4;
-    if pin.len() < min_pin_length || pin.len() == PIN_PADDED_LENGTH {
-        // TODO(kaczmarczyck) check 4 code point minimum instead
+    // The PIN needs at least one byte of padding, so it is at most 63 bytes long.
+    if pin.len() >= PIN_PADDED_LENGTH {
+        return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION);
+    }
+    let pin_str = pin_as_str(&pin).ok_or(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION)?;
+    if !pin_policy.is_allowed(pin_str, min_pin_length) {
         return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION);
     }
     let mut pin_hash = [0u8; 16];
@@ -165,50 +194,68 @@ pub enum PinPermission {
     GetAssertion = 0x02,
     CredentialManagement = 0x04,
     BioEnrollment = 0x08,
-    PlatformConfiguration = 0x10,
+    LargeBlobWrite = 0x10,
     AuthenticatorConfiguration = 0x20,
 }
 
 pub struct PinProtocolV1 {
     key_agreement_key: crypto::ecdh::SecKey,
-    pin_uv_auth_token: [u8; PIN_TOKEN_LENGTH],
+    pin_uv_auth_token: PinToken,
     consecutive_pin_mismatches: u8,
+    // PIN attempts are refused until this timeout after a mismatch. The permission is granted
+    // while the backoff lasts, so granted means that attempts are refused.
+    pin_backoff_until: TimedPermission,
     #[cfg(feature = "with_ctap2_1")]
     permissions: u8,
     #[cfg(feature = "with_ctap2_1")]
     permissions_rp_id: Option<String>,
+    pin_policy: Box<dyn PinPolicy>,
 }
 
 impl PinProtocolV1 {
     pub fn new(rng: &mut impl Rng256) -> PinProtocolV1 {
         let key_agreement_key = crypto::ecdh::SecKey::gensk(rng);
-        let pin_uv_auth_token = rng.gen_uniform_u8x32();
+        let pin_uv_auth_token = PinToken::new(rng, PIN_TOKEN_LENGTH);
         PinProtocolV1 {
             key_agreement_key,
             pin_uv_auth_token,
             consecutive_pin_mismatches: 0,
+            pin_backoff_until: TimedPermission::waiting(),
             #[cfg(feature = "with_ctap2_1")]
             permissions: 0,
             #[cfg(feature = "with_ctap2_1")]
             permissions_rp_id: None,
+            pin_policy: Box::new(DefaultPinPolicy),
         }
     }
 
+    /// Sets the policy that new PINs have to satisfy.
+    pub fn set_pin_policy(&mut self, pin_policy: Box<dyn PinPolicy>) {
+        self.pin_policy = pin_policy;
+    }
+
     /// Decrypts the encrypted pin_hash and compares it to the stored pin_hash.
     /// Resets or decreases the PIN retries, depending on success or failure.
-    /// Also, in case of failure, the key agreement key is randomly reset.
+    /// Also, in case of failure, the key agreement key is randomly reset and further attempts are
+    /// refused for an escalating delay, without decreasing the PIN retries.
     fn verify_pin_hash_enc(
         &mut self,
         rng: &mut impl Rng256,
         persistent_store: &mut PersistentStore,
-        aes_dec_key: &crypto::aes256::DecryptionKey,
+        aes_dec_key: &<Crypto as CryptoProvider>::Aes256DecryptionKey,
         pin_hash_enc: Vec<u8>,
+        now: ClockValue,
     ) -> Result<(), Ctap2StatusCode> {
         match persistent_store.pin_hash()? {
             Some(pin_hash) => {
-                if self.consecutive_pin_mismatches >= 3 {
+                if self.consecutive_pin_mismatches >= MAX_CONSECUTIVE_PIN_MISMATCHES {
                     return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_BLOCKED);
                 }
+                // The specification has no status code for this delay. Unlike PIN_AUTH_BLOCKED,
+                // the platform can retry without a power cycle. See the README for this choice.
+                if self.pin_backoff_until.is_granted(now) {
+                    return Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED);
+                }
                 persistent_store.decr_pin_retries()?;
                 if pin_hash_enc.len() != PIN_AUTH_LENGTH {
                     return Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID);
@@ -219,15 +266,19 @@ impl PinProtocolV1 {
                 blocks[0].copy_from_slice(&pin_hash_enc);
                 cbc_decrypt(aes_dec_key, iv, &mut blocks);
 
-                if !bool::from(pin_hash.ct_eq(&blocks[0])) {
+                if !pin_hash.ct_eq(&blocks[0]) {
                     self.key_agreement_key = crypto::ecdh::SecKey::gensk(rng);
                     if persistent_store.pin_retries()? == 0 {
                         return Err(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED);
                     }
                     self.consecutive_pin_mismatches += 1;
-                    if self.consecutive_pin_mismatches >= 3 {
+                    if self.consecutive_pin_mismatches >= MAX_CONSECUTIVE_PIN_MISMATCHES {
                         return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_BLOCKED);
                     }
+                    let delay_ms =
+                        PIN_BACKOFF_BASE_DELAY_MS << (self.consecutive_pin_mismatches - 1);
+                    self.pin_backoff_until =
+                        TimedPermission::granted(now, Duration::from_ms(delay_ms));
                     return Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID);
                 }
             }
@@ -236,6 +287,7 @@ impl PinProtocolV1 {
         }
         persistent_store.reset_pin_retries()?;
         self.consecutive_pin_mismatches = 0;
+        self.pin_backoff_until = TimedPermission::waiting();
         Ok(())
     }
 
@@ -246,7 +298,7 @@ impl PinProtocolV1 {
         key_agreement: CoseKey,
         pin_auth: &[u8],
         authenticated_message: &[u8],
-    ) -> Result<crypto::aes256::DecryptionKey, Ctap2StatusCode> {
+    ) -> Result<<Crypto as CryptoProvider>::Aes256DecryptionKey, Ctap2StatusCode> {
         let pk: crypto::ecdh::PubKey = CoseKey::try_into(key_agreement)?;
         let shared_secret = self.key_agreement_key.exchange_x_sha256(&pk);
 
@@ -254,8 +306,8 @@ impl PinProtocolV1 {
             return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
         }
 
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(&shared_secret);
-        let aes_dec_key = crypto::aes256::DecryptionKey::new(&aes_enc_key);
+        let aes_enc_key = Crypto::aes256_encryption_key(&shared_secret);
+        let aes_dec_key = Crypto::aes256_decryption_key(&aes_enc_key);
         Ok(aes_dec_key)
     }
 
@@ -291,7 +343,12 @@ impl PinProtocolV1 {
         }
         let pin_decryption_key =
             self.exchange_decryption_key(key_agreement, &pin_auth, &new_pin_enc)?;
-        check_and_store_new_pin(persistent_store, &pin_decryption_key, new_pin_enc)?;
+        check_and_store_new_pin(
+            persistent_store,
+            self.pin_policy.as_ref(),
+            &pin_decryption_key,
+            new_pin_enc,
+        )?;
         persistent_store.reset_pin_retries()?;
         Ok(())
     }
@@ -304,6 +361,7 @@ impl PinProtocolV1 {
         pin_auth: Vec<u8>,
         new_pin_enc: Vec<u8>,
         pin_hash_enc: Vec<u8>,
+        now: ClockValue,
     ) -> Result<(), Ctap2StatusCode> {
         if persistent_store.pin_retries()? == 0 {
             return Err(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED);
@@ -312,10 +370,22 @@ impl PinProtocolV1 {
         auth_param_data.extend(&pin_hash_enc);
         let pin_decryption_key =
             self.exchange_decryption_key(key_agreement, &pin_auth, &auth_param_data)?;
-        self.verify_pin_hash_enc(rng, persistent_store, &pin_decryption_key, pin_hash_enc)?;
+        self.verify_pin_hash_enc(
+            rng,
+            persistent_store,
+            &pin_decryption_key,
+            pin_hash_enc,
+            now,
+        )?;
 
-        check_and_store_new_pin(persistent_store, &pin_decryption_key, new_pin_enc)?;
-        self.pin_uv_auth_token = rng.gen_uniform_u8x32();
+        check_and_store_new_pin(
+            persistent_store,
+            self.pin_policy.as_ref(),
+            &pin_decryption_key,
+            new_pin_enc,
+        )?;
+        // Tokens issued for the old PIN must not authorize anything anymore.
+        self.reset_pin_uv_auth_token(rng);
         Ok(())
     }
 
@@ -325,6 +395,7 @@ impl PinProtocolV1 {
         persistent_store: &mut PersistentStore,
         key_agreement: CoseKey,
         pin_hash_enc: Vec<u8>,
+        now: ClockValue,
     ) -> Result<AuthenticatorClientPinResponse, Ctap2StatusCode> {
         if persistent_store.pin_retries()? == 0 {
             return Err(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED);
@@ -332,24 +403,37 @@ impl PinProtocolV1 {
         let pk: crypto::ecdh::PubKey = CoseKey::try_into(key_agreement)?;
         let shared_secret = self.key_agreement_key.exchange_x_sha256(&pk);
 
-        let token_encryption_key = crypto::aes256::EncryptionKey::new(&shared_secret);
-        let pin_decryption_key = crypto::aes256::DecryptionKey::new(&token_encryption_key);
-        self.verify_pin_hash_enc(rng, persistent_store, &pin_decryption_key, pin_hash_enc)?;
+        let token_encryption_key = Crypto::aes256_encryption_key(&shared_secret);
+        let pin_decryption_key = Crypto::aes256_decryption_key(&token_encryption_key);
+        self.verify_pin_hash_enc(
+            rng,
+            persistent_store,
+            &pin_decryption_key,
+            pin_hash_enc,
+            now,
+        )?;
 
-        // Assuming PIN_TOKEN_LENGTH % block_size == 0 here.
+        // The token length is a multiple of the block size.
         let iv = [0u8; 16];
-        let mut blocks = [[0u8; 16]; PIN_TOKEN_LENGTH / 16];
-        for (i, item) in blocks.iter_mut().take(PIN_TOKEN_LENGTH / 16).enumerate() {
-            item.copy_from_slice(&self.pin_uv_auth_token[i * 16..(i + 1) * 16]);
+        let mut blocks = [[0u8; 16]; MAX_PIN_TOKEN_LENGTH / 16];
+        let blocks = &mut blocks[..self.pin_uv_auth_token.value().len() / 16];
+        for (block, chunk) in blocks
+            .iter_mut()
+            .zip(self.pin_uv_auth_token.value().chunks(16))
+        {
+            block.copy_from_slice(chunk);
         }
-        cbc_encrypt(&token_encryption_key, iv, &mut blocks);
+        cbc_encrypt(&token_encryption_key, iv, blocks);
         let pin_token: Vec<u8> = blocks.iter().flatten().cloned().collect();
 
         #[cfg(feature = "with_ctap2_1")]
         {
             self.permissions = 0x03;
             self.permissions_rp_id = None;
+            self.pin_uv_auth_token.begin_using(false, now);
         }
+        #[cfg(not(feature = "with_ctap2_1"))]
+        let _ = now;
 
         Ok(AuthenticatorClientPinResponse {
             key_agreement: None,
@@ -399,13 +483,10 @@ impl PinProtocolV1 {
         min_pin_length_rp_ids: Option<Vec<String>>,
         pin_auth: Option<Vec<u8>>,
     ) -> Result<(), Ctap2StatusCode> {
-        if min_pin_length_rp_ids.is_some() {
-            return Err(Ctap2StatusCode::CTAP2_ERR_UNSUPPORTED_EXTENSION);
-        }
         if persistent_store.pin_hash()?.is_some() {
             match pin_auth {
                 Some(pin_auth) => {
-                    if self.consecutive_pin_mismatches >= 3 {
+                    if self.consecutive_pin_mismatches >= MAX_CONSECUTIVE_PIN_MISMATCHES {
                         return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_BLOCKED);
                     }
                     // TODO(kaczmarczyck) Values are taken from the (not yet public) new revision
@@ -416,12 +497,17 @@ impl PinProtocolV1 {
                     let mut message = vec![0xFF; 32];
                     message.extend(&[0x06, 0x08]);
                     message.extend(&[min_pin_length as u8, 0x00, 0x00, 0x00]);
-                    // TODO(kaczmarczyck) commented code is useful for the extension
-                    // https://github.com/google/OpenSK/issues/129
-                    // if !cbor::write(cbor_array_vec!(min_pin_length_rp_ids), &mut message) {
-                    //     return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR);
-                    // }
-                    if !verify_pin_auth(&self.pin_uv_auth_token, &message, &pin_auth) {
+                    if let Some(min_pin_length_rp_ids) = &min_pin_length_rp_ids {
+                        if !cbor::write(
+                            cbor_array_vec!(min_pin_length_rp_ids.clone()),
+                            &mut message,
+                        ) {
+                            return Err(
+                                Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR,
+                            );
+                        }
+                    }
+                    if !self.pin_uv_auth_token.verify(&message, &pin_auth) {
                         return Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID);
                     }
                 }
@@ -431,12 +517,16 @@ impl PinProtocolV1 {
         if min_pin_length < persistent_store.min_pin_length()? {
             return Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION);
         }
-        persistent_store.set_min_pin_length(min_pin_length)?;
-        // TODO(kaczmarczyck) commented code is useful for the extension
-        // https://github.com/google/OpenSK/issues/129
-        // if let Some(min_pin_length_rp_ids) = min_pin_length_rp_ids {
-        //     persistent_store.set_min_pin_length_rp_ids(min_pin_length_rp_ids)?;
-        // }
+        if let Some(min_pin_length_rp_ids) = min_pin_length_rp_ids {
+            // Check the list before changing anything, so that a failed command has no effect.
+            if min_pin_length_rp_ids.len() > MAX_RP_IDS_LENGTH {
+                return Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL);
+            }
+            persistent_store.set_min_pin_length(min_pin_length)?;
+            persistent_store.set_min_pin_length_rp_ids(&min_pin_length_rp_ids)?;
+        } else {
+            persistent_store.set_min_pin_length(min_pin_length)?;
+        }
         Ok(())
     }
 
@@ -449,6 +539,7 @@ impl PinProtocolV1 {
         pin_hash_enc: Vec<u8>,
         permissions: u8,
         permissions_rp_id: Option<String>,
+        now: ClockValue,
     ) -> Result<AuthenticatorClientPinResponse, Ctap2StatusCode> {
         if permissions == 0 {
             return Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER);
@@ -459,7 +550,7 @@ impl PinProtocolV1 {
         }
 
         let response =
-            self.process_get_pin_token(rng, persistent_store, key_agreement, pin_hash_enc)?;
+            self.process_get_pin_token(rng, persistent_store, key_agreement, pin_hash_enc, now)?;
 
         self.permissions = permissions;
         self.permissions_rp_id = permissions_rp_id;
@@ -472,6 +563,7 @@ impl PinProtocolV1 {
         rng: &mut impl Rng256,
         persistent_store: &mut PersistentStore,
         client_pin_params: AuthenticatorClientPinParameters,
+        now: ClockValue,
     ) -> Result<ResponseData, Ctap2StatusCode> {
         let AuthenticatorClientPinParameters {
             pin_protocol,
@@ -519,6 +611,7 @@ impl PinProtocolV1 {
                     pin_auth.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
                     new_pin_enc.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
                     pin_hash_enc.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
+                    now,
                 )?;
                 None
             }
@@ -527,6 +620,7 @@ impl PinProtocolV1 {
                 persistent_store,
                 key_agreement.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
                 pin_hash_enc.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
+                now,
             )?),
             #[cfg(feature = "with_ctap2_1")]
             ClientPinSubCommand::GetPinUvAuthTokenUsingUvWithPermissions => Some(
@@ -557,20 +651,27 @@ impl PinProtocolV1 {
                     pin_hash_enc.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
                     permissions.ok_or(Ctap2StatusCode::CTAP2_ERR_MISSING_PARAMETER)?,
                     permissions_rp_id,
+                    now,
                 )?,
             ),
         };
         Ok(ResponseData::AuthenticatorClientPin(response))
     }
 
-    pub fn verify_pin_auth_token(&self, hmac_contents: &[u8], pin_auth: &[u8]) -> bool {
-        verify_pin_auth(&self.pin_uv_auth_token, &hmac_contents, &pin_auth)
+    pub fn verify_pin_auth_token(&mut self, hmac_contents: &[u8], pin_auth: &[u8]) -> bool {
+        self.pin_uv_auth_token.verify(hmac_contents, pin_auth)
     }
 
     pub fn reset(&mut self, rng: &mut impl Rng256) {
         self.key_agreement_key = crypto::ecdh::SecKey::gensk(rng);
-        self.pin_uv_auth_token = rng.gen_uniform_u8x32();
+        self.reset_pin_uv_auth_token(rng);
         self.consecutive_pin_mismatches = 0;
+        self.pin_backoff_until = TimedPermission::waiting();
+    }
+
+    // Invalidates all issued pinUvAuthTokens and their permissions.
+    fn reset_pin_uv_auth_token(&mut self, rng: &mut impl Rng256) {
+        self.pin_uv_auth_token.reset(rng);
         #[cfg(feature = "with_ctap2_1")]
         {
             self.permissions = 0;
@@ -578,6 +679,44 @@ impl PinProtocolV1 {
         }
     }
 
+    /// Expires the delay after a PIN mismatch. Called regularly with the current time.
+    pub fn update_pin_backoff(&mut self, now: ClockValue) {
+        self.pin_backoff_until = self.pin_backoff_until.check_expiration(now);
+    }
+
+    /// Expires the pinUvAuthToken and its cached flags. Called regularly with the current time.
+    #[cfg(feature = "with_ctap2_1")]
+    pub fn update_pin_uv_auth_token_state(&mut self, now: ClockValue) {
+        self.pin_uv_auth_token.update(now);
+        if !self.pin_uv_auth_token.is_in_use() {
+            self.permissions = 0;
+            self.permissions_rp_id = None;
+        }
+    }
+
+    /// Returns whether the user was present when the pinUvAuthToken was issued, recently enough.
+    #[cfg(feature = "with_ctap2_1")]
+    pub fn get_user_present_flag_value(&self) -> bool {
+        self.pin_uv_auth_token.get_user_present_flag_value()
+    }
+
+    /// Returns whether the pinUvAuthToken still vouches for user verification.
+    #[cfg(feature = "with_ctap2_1")]
+    pub fn get_user_verified_flag_value(&self) -> bool {
+        self.pin_uv_auth_token.get_user_verified_flag_value()
+    }
+
+    /// Consumes the cached flags and the permissions after a command used the pinUvAuthToken.
+    ///
+    /// The token stays in use, but it needs a new permission for any command but writing the
+    /// large blob, so that the platform can write the large blob of a credential with the token
+    /// that made it.
+    #[cfg(feature = "with_ctap2_1")]
+    pub fn clear_token_flags_and_permissions(&mut self) {
+        self.pin_uv_auth_token.clear_flags();
+        self.permissions &= PinPermission::LargeBlobWrite as u8;
+    }
+
     pub fn process_hmac_secret(
         &self,
         hmac_secret_input: GetAssertionHmacSecretInput,
@@ -598,6 +737,35 @@ impl PinProtocolV1 {
         encrypt_hmac_secret_output(&shared_secret, &salt_enc[..], cred_random)
     }
 
+    /// Decrypts the attestation private key sent for vendor provisioning.
+    ///
+    /// The key is encrypted like a new PIN, under the shared secret of the key agreement. The
+    /// pinAuth covers the certificates of the chain followed by the encrypted private key.
+    pub fn decrypt_attestation_private_key(
+        &self,
+        key_agreement: CoseKey,
+        pin_auth: &[u8],
+        certificate_chain: &[Vec<u8>],
+        private_key_enc: &[u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH],
+    ) -> Result<[u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH], Ctap2StatusCode> {
+        let mut authenticated_message = certificate_chain.concat();
+        authenticated_message.extend_from_slice(private_key_enc);
+        let aes_dec_key =
+            self.exchange_decryption_key(key_agreement, pin_auth, &authenticated_message)?;
+        let iv = [0u8; 16];
+        const BLOCK_COUNT: usize = key_material::ATTESTATION_PRIVATE_KEY_LENGTH / 16;
+        let mut blocks = [[0u8; 16]; BLOCK_COUNT];
+        for i in 0..BLOCK_COUNT {
+            blocks[i].copy_from_slice(&private_key_enc[i * 16..(i + 1) * 16]);
+        }
+        cbc_decrypt(&aes_dec_key, iv, &mut blocks);
+        let mut private_key = [0u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH];
+        for i in 0..BLOCK_COUNT {
+            private_key[i * 16..(i + 1) * 16].copy_from_slice(&blocks[i]);
+        }
+        Ok(private_key)
+    }
+
     #[cfg(feature = "with_ctap2_1")]
     pub fn has_permission(&self, permission: PinPermission) -> Result<(), Ctap2StatusCode> {
         // Relies on the fact that all permissions are represented by powers of two.
@@ -627,21 +795,32 @@ impl PinProtocolV1 {
     ) -> PinProtocolV1 {
         PinProtocolV1 {
             key_agreement_key,
-            pin_uv_auth_token,
+            pin_uv_auth_token: PinToken::new_test(pin_uv_auth_token),
             consecutive_pin_mismatches: 0,
+            pin_backoff_until: TimedPermission::waiting(),
             #[cfg(feature = "with_ctap2_1")]
             permissions: 0xFF,
             #[cfg(feature = "with_ctap2_1")]
             permissions_rp_id: None,
+            pin_policy: Box::new(DefaultPinPolicy),
         }
     }
+
+    #[cfg(all(test, feature = "with_ctap2_1"))]
+    pub fn set_permissions(&mut self, permissions: u8) {
+        self.permissions = permissions;
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use cbor::{cbor_bytes_lit, cbor_key_int};
     use crypto::rng256::ThreadRng256;
 
+    const CLOCK_FREQUENCY_HZ: usize = 32768;
+    const DUMMY_CLOCK_VALUE: ClockValue = ClockValue::new(0, CLOCK_FREQUENCY_HZ);
+
     // Stores a PIN hash corresponding to the dummy PIN "1234".
     fn set_standard_pin(persistent_store: &mut PersistentStore) {
         let mut pin = [0u8; 64];
@@ -659,7 +838,7 @@ mod test {
         for i in 0..block_len {
             blocks[i][..].copy_from_slice(&message[i * 16..(i + 1) * 16]);
         }
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(shared_secret);
+        let aes_enc_key = Crypto::aes256_encryption_key(shared_secret);
         let iv = [0u8; 16];
         cbc_encrypt(&aes_enc_key, iv, &mut blocks);
         blocks.iter().flatten().cloned().collect::<Vec<u8>>()
@@ -673,8 +852,8 @@ mod test {
         for i in 0..block_len {
             blocks[i][..].copy_from_slice(&message[i * 16..(i + 1) * 16]);
         }
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(shared_secret);
-        let aes_dec_key = crypto::aes256::DecryptionKey::new(&aes_enc_key);
+        let aes_enc_key = Crypto::aes256_encryption_key(shared_secret);
+        let aes_dec_key = Crypto::aes256_decryption_key(&aes_enc_key);
         let iv = [0u8; 16];
         cbc_decrypt(&aes_dec_key, iv, &mut blocks);
         blocks.iter().flatten().cloned().collect::<Vec<u8>>()
@@ -712,8 +891,8 @@ mod test {
         ];
         persistent_store.set_pin_hash(&pin_hash).unwrap();
         let shared_secret = [0x88; 32];
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(&shared_secret);
-        let aes_dec_key = crypto::aes256::DecryptionKey::new(&aes_enc_key);
+        let aes_enc_key = Crypto::aes256_encryption_key(&shared_secret);
+        let aes_dec_key = Crypto::aes256_decryption_key(&aes_enc_key);
 
         let mut pin_protocol_v1 = PinProtocolV1::new(&mut rng);
         let pin_hash_enc = vec![
@@ -725,7 +904,8 @@ mod test {
                 &mut rng,
                 &mut persistent_store,
                 &aes_dec_key,
-                pin_hash_enc
+                pin_hash_enc,
+                DUMMY_CLOCK_VALUE
             ),
             Ok(())
         );
@@ -736,7 +916,8 @@ mod test {
                 &mut rng,
                 &mut persistent_store,
                 &aes_dec_key,
-                pin_hash_enc
+                pin_hash_enc,
+                DUMMY_CLOCK_VALUE
             ),
             Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID)
         );
@@ -751,11 +932,13 @@ mod test {
                 &mut rng,
                 &mut persistent_store,
                 &aes_dec_key,
-                pin_hash_enc
+                pin_hash_enc,
+                DUMMY_CLOCK_VALUE
             ),
             Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_BLOCKED)
         );
         pin_protocol_v1.consecutive_pin_mismatches = 0;
+        pin_protocol_v1.pin_backoff_until = TimedPermission::waiting();
 
         let pin_hash_enc = vec![0x77; PIN_AUTH_LENGTH - 1];
         assert_eq!(
@@ -763,7 +946,8 @@ mod test {
                 &mut rng,
                 &mut persistent_store,
                 &aes_dec_key,
-                pin_hash_enc
+                pin_hash_enc,
+                DUMMY_CLOCK_VALUE
             ),
             Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID)
         );
@@ -774,12 +958,121 @@ mod test {
                 &mut rng,
                 &mut persistent_store,
                 &aes_dec_key,
-                pin_hash_enc
+                pin_hash_enc,
+                DUMMY_CLOCK_VALUE
             ),
             Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID)
         );
     }
 
+    #[test]
+    fn test_verify_pin_hash_enc_backoff() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        set_standard_pin(&mut persistent_store);
+        let shared_secret = [0x88; 32];
+        let aes_enc_key = Crypto::aes256_encryption_key(&shared_secret);
+        let aes_dec_key = Crypto::aes256_decryption_key(&aes_enc_key);
+        let pin_hash_enc = encrypt_standard_pin_hash(&shared_secret);
+        let bad_pin_hash_enc = vec![0xEE; 16];
+        let after_ms = |ms| DUMMY_CLOCK_VALUE.wrapping_add(Duration::from_ms(ms));
+        let initial_retries = persistent_store.pin_retries().unwrap();
+
+        let mut pin_protocol_v1 = PinProtocolV1::new(&mut rng);
+        assert_eq!(
+            pin_protocol_v1.verify_pin_hash_enc(
+                &mut rng,
+                &mut persistent_store,
+                &aes_dec_key,
+                bad_pin_hash_enc.clone(),
+                after_ms(0)
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID)
+        );
+        // Attempts during the delay don't decrease the retries, even with the correct PIN.
+        assert_eq!(
+            pin_protocol_v1.verify_pin_hash_enc(
+                &mut rng,
+                &mut persistent_store,
+                &aes_dec_key,
+                pin_hash_enc.clone(),
+                after_ms(999)
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+        assert_eq!(persistent_store.pin_retries().unwrap(), initial_retries - 1);
+        assert_eq!(
+            pin_protocol_v1.verify_pin_hash_enc(
+                &mut rng,
+                &mut persistent_store,
+                &aes_dec_key,
+                bad_pin_hash_enc.clone(),
+                after_ms(1000)
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID)
+        );
+        // The delay doubles with each consecutive mismatch.
+        assert_eq!(
+            pin_protocol_v1.verify_pin_hash_enc(
+                &mut rng,
+                &mut persistent_store,
+                &aes_dec_key,
+                pin_hash_enc.clone(),
+                after_ms(2999)
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+        assert_eq!(
+            pin_protocol_v1.verify_pin_hash_enc(
+                &mut rng,
+                &mut persistent_store,
+                &aes_dec_key,
+                bad_pin_hash_enc.clone(),
+                after_ms(3000)
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_BLOCKED)
+        );
+        // After 3 consecutive mismatches, only a power cycle unblocks the PIN.
+        assert_eq!(
+            pin_protocol_v1.verify_pin_hash_enc(
+                &mut rng,
+                &mut persistent_store,
+                &aes_dec_key,
+                pin_hash_enc.clone(),
+                after_ms(60000)
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_BLOCKED)
+        );
+        assert_eq!(persistent_store.pin_retries().unwrap(), initial_retries - 3);
+
+        // The retries persist across reboots, but the consecutive mismatches don't.
+        let mut persistent_store = persistent_store.reboot(&mut rng);
+        let mut pin_protocol_v1 = PinProtocolV1::new(&mut rng);
+        assert_eq!(
+            pin_protocol_v1.verify_pin_hash_enc(
+                &mut rng,
+                &mut persistent_store,
+                &aes_dec_key,
+                bad_pin_hash_enc,
+                after_ms(0)
+            ),
+            Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID)
+        );
+        assert_eq!(persistent_store.pin_retries().unwrap(), initial_retries - 4);
+        pin_protocol_v1.update_pin_backoff(after_ms(1000));
+        assert_eq!(
+            pin_protocol_v1.verify_pin_hash_enc(
+                &mut rng,
+                &mut persistent_store,
+                &aes_dec_key,
+                pin_hash_enc,
+                after_ms(1000)
+            ),
+            Ok(())
+        );
+        assert_eq!(persistent_store.pin_retries().unwrap(), initial_retries);
+    }
+
     #[test]
     fn test_process_get_pin_retries() {
         let mut rng = ThreadRng256 {};
@@ -833,6 +1126,42 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_process_set_pin_invalid_key_agreement() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut pin_protocol_v1 = PinProtocolV1::new(&mut rng);
+        let pk = pin_protocol_v1.key_agreement_key.genpk();
+        let shared_secret = pin_protocol_v1.key_agreement_key.exchange_x_sha256(&pk);
+        let mut key_agreement = CoseKey::from(pk);
+        // Replace the coordinates with a point on the quadratic twist of the curve.
+        let mut twist_x_bytes = [0x00u8; 32];
+        twist_x_bytes[31] = 0x01;
+        let twist_y_bytes: [u8; 32] = [
+            0xDE, 0x17, 0xB2, 0x67, 0x10, 0x45, 0xFE, 0x58, 0x74, 0x02, 0x48, 0xC1, 0x7A, 0x8B,
+            0xD2, 0xFB, 0x2E, 0xF7, 0xC3, 0xC4, 0x9B, 0x01, 0xDA, 0x9F, 0x90, 0x3E, 0xFA, 0x3D,
+            0xCD, 0xA6, 0x69, 0xC7,
+        ];
+        key_agreement
+            .0
+            .insert(cbor_key_int!(-2), cbor_bytes_lit!(&twist_x_bytes));
+        key_agreement
+            .0
+            .insert(cbor_key_int!(-3), cbor_bytes_lit!(&twist_y_bytes));
+        let new_pin_enc = encrypt_standard_pin(&shared_secret);
+        let pin_auth = hmac_256::<Sha256>(&shared_secret, &new_pin_enc[..])[..16].to_vec();
+        assert_eq!(
+            pin_protocol_v1.process_set_pin(
+                &mut persistent_store,
+                key_agreement,
+                pin_auth,
+                new_pin_enc
+            ),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+        assert!(persistent_store.pin_hash().unwrap().is_none());
+    }
+
     #[test]
     fn test_process_change_pin() {
         let mut rng = ThreadRng256 {};
@@ -847,6 +1176,7 @@ mod test {
         let mut auth_param_data = new_pin_enc.clone();
         auth_param_data.extend(&pin_hash_enc);
         let pin_auth = hmac_256::<Sha256>(&shared_secret, &auth_param_data[..])[..16].to_vec();
+        let old_pin_uv_auth_token = pin_protocol_v1.pin_uv_auth_token.value().to_vec();
         assert_eq!(
             pin_protocol_v1.process_change_pin(
                 &mut rng,
@@ -854,10 +1184,16 @@ mod test {
                 key_agreement.clone(),
                 pin_auth.clone(),
                 new_pin_enc.clone(),
-                pin_hash_enc.clone()
+                pin_hash_enc.clone(),
+                DUMMY_CLOCK_VALUE
             ),
             Ok(())
         );
+        // Changing the PIN invalidates the pinUvAuthToken.
+        assert_ne!(
+            pin_protocol_v1.pin_uv_auth_token.value(),
+            &old_pin_uv_auth_token[..]
+        );
 
         let bad_pin_hash_enc = vec![0xEE; 16];
         assert_eq!(
@@ -867,7 +1203,8 @@ mod test {
                 key_agreement.clone(),
                 pin_auth.clone(),
                 new_pin_enc.clone(),
-                bad_pin_hash_enc
+                bad_pin_hash_enc,
+                DUMMY_CLOCK_VALUE
             ),
             Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID)
         );
@@ -883,6 +1220,7 @@ mod test {
                 pin_auth,
                 new_pin_enc,
                 pin_hash_enc,
+                DUMMY_CLOCK_VALUE
             ),
             Err(Ctap2StatusCode::CTAP2_ERR_PIN_BLOCKED)
         );
@@ -903,7 +1241,8 @@ mod test {
                 &mut rng,
                 &mut persistent_store,
                 key_agreement.clone(),
-                pin_hash_enc
+                pin_hash_enc,
+                DUMMY_CLOCK_VALUE
             )
             .is_ok());
 
@@ -913,7 +1252,8 @@ mod test {
                 &mut rng,
                 &mut persistent_store,
                 key_agreement,
-                pin_hash_enc
+                pin_hash_enc,
+                DUMMY_CLOCK_VALUE
             ),
             Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID)
         );
@@ -938,6 +1278,7 @@ mod test {
                 pin_hash_enc.clone(),
                 0x03,
                 Some(String::from("example.com")),
+                DUMMY_CLOCK_VALUE,
             )
             .is_ok());
         assert_eq!(pin_protocol_v1.permissions, 0x03);
@@ -954,6 +1295,7 @@ mod test {
                 pin_hash_enc.clone(),
                 0x00,
                 Some(String::from("example.com")),
+                DUMMY_CLOCK_VALUE,
             ),
             Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
         );
@@ -966,6 +1308,7 @@ mod test {
                 pin_hash_enc.clone(),
                 0x03,
                 None,
+                DUMMY_CLOCK_VALUE,
             ),
             Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
         );
@@ -979,11 +1322,50 @@ mod test {
                 pin_hash_enc,
                 0x03,
                 Some(String::from("example.com")),
+                DUMMY_CLOCK_VALUE,
             ),
             Err(Ctap2StatusCode::CTAP2_ERR_PIN_INVALID)
         );
     }
 
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_pin_uv_auth_token_expiration() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        set_standard_pin(&mut persistent_store);
+        let mut pin_protocol_v1 = PinProtocolV1::new(&mut rng);
+        let message = [0xAA];
+        let pin_auth = hmac_256::<Sha256>(pin_protocol_v1.pin_uv_auth_token.value(), &message)
+            [..PIN_AUTH_LENGTH]
+            .to_vec();
+        // No token was issued yet.
+        assert!(!pin_protocol_v1.verify_pin_auth_token(&message, &pin_auth));
+
+        let pk = pin_protocol_v1.key_agreement_key.genpk();
+        let shared_secret = pin_protocol_v1.key_agreement_key.exchange_x_sha256(&pk);
+        let key_agreement = CoseKey::from(pk);
+        let pin_hash_enc = encrypt_standard_pin_hash(&shared_secret);
+        assert!(pin_protocol_v1
+            .process_get_pin_token(
+                &mut rng,
+                &mut persistent_store,
+                key_agreement,
+                pin_hash_enc,
+                DUMMY_CLOCK_VALUE
+            )
+            .is_ok());
+        assert!(pin_protocol_v1.get_user_verified_flag_value());
+        assert!(!pin_protocol_v1.get_user_present_flag_value());
+
+        // The token is not used within the initial usage time limit.
+        let later = DUMMY_CLOCK_VALUE.wrapping_add(Duration::from_ms(30001));
+        pin_protocol_v1.update_pin_uv_auth_token_state(later);
+        assert!(!pin_protocol_v1.verify_pin_auth_token(&message, &pin_auth));
+        assert!(!pin_protocol_v1.get_user_verified_flag_value());
+        assert_eq!(pin_protocol_v1.permissions, 0x00);
+    }
+
     #[cfg(feature = "with_ctap2_1")]
     #[test]
     fn test_process_set_min_pin_length() {
@@ -991,13 +1373,11 @@ mod test {
         let mut persistent_store = PersistentStore::new(&mut rng);
         let mut pin_protocol_v1 = PinProtocolV1::new(&mut rng);
         let min_pin_length = 8;
-        pin_protocol_v1.pin_uv_auth_token = [0x55; PIN_TOKEN_LENGTH];
+        pin_protocol_v1.pin_uv_auth_token = PinToken::new_test([0x55; MAX_PIN_TOKEN_LENGTH]);
         let pin_auth = vec![
             0x94, 0x86, 0xEF, 0x4C, 0xB3, 0x84, 0x2C, 0x85, 0x72, 0x02, 0xBF, 0xE4, 0x36, 0x22,
             0xFE, 0xC9,
         ];
-        // TODO(kaczmarczyck) implement test for the min PIN length extension
-        // https://github.com/google/OpenSK/issues/129
         let response = pin_protocol_v1.process_set_min_pin_length(
             &mut persistent_store,
             min_pin_length,
@@ -1019,6 +1399,77 @@ mod test {
         assert_eq!(persistent_store.min_pin_length().unwrap(), min_pin_length);
     }
 
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_process_set_min_pin_length_rp_ids() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let mut pin_protocol_v1 = PinProtocolV1::new(&mut rng);
+        pin_protocol_v1.pin_uv_auth_token = PinToken::new_test([0x55; MAX_PIN_TOKEN_LENGTH]);
+        let rp_id_hash = Sha256::hash(b"example.com");
+        let rp_ids = vec![String::from("example.com")];
+
+        // Without a PIN, no authentication is needed.
+        let response = pin_protocol_v1.process_set_min_pin_length(
+            &mut persistent_store,
+            6,
+            Some(rp_ids.clone()),
+            None,
+        );
+        assert_eq!(response, Ok(()));
+        assert_eq!(persistent_store.min_pin_length().unwrap(), 6);
+        assert_eq!(
+            persistent_store.is_min_pin_length_rp_id(&rp_id_hash),
+            Ok(true)
+        );
+
+        // With a PIN, the RP IDs are part of the authenticated message.
+        persistent_store.set_pin_hash(&[0x88; 16]).unwrap();
+        let rp_ids = vec![String::from("other.com")];
+        let mut message = vec![0xFF; 32];
+        message.extend(&[0x06, 0x08, 0x08, 0x00, 0x00, 0x00]);
+        let pin_auth_without_rp_ids =
+            hmac_256::<Sha256>(pin_protocol_v1.pin_uv_auth_token.value(), &message)[..16].to_vec();
+        assert!(cbor::write(cbor_array_vec!(rp_ids.clone()), &mut message));
+        let pin_auth =
+            hmac_256::<Sha256>(pin_protocol_v1.pin_uv_auth_token.value(), &message)[..16].to_vec();
+        let response = pin_protocol_v1.process_set_min_pin_length(
+            &mut persistent_store,
+            8,
+            Some(rp_ids.clone()),
+            Some(pin_auth_without_rp_ids),
+        );
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_PIN_AUTH_INVALID));
+        let response = pin_protocol_v1.process_set_min_pin_length(
+            &mut persistent_store,
+            8,
+            Some(rp_ids),
+            Some(pin_auth),
+        );
+        assert_eq!(response, Ok(()));
+        assert_eq!(persistent_store.min_pin_length().unwrap(), 8);
+        assert_eq!(
+            persistent_store.is_min_pin_length_rp_id(&rp_id_hash),
+            Ok(false)
+        );
+        assert_eq!(
+            persistent_store.is_min_pin_length_rp_id(&Sha256::hash(b"other.com")),
+            Ok(true)
+        );
+
+        // Too many RP IDs are rejected without changing the minimum PIN length.
+        persistent_store.reset(&mut rng).unwrap();
+        let rp_ids = vec![String::from("example.com"); MAX_RP_IDS_LENGTH + 1];
+        let response = pin_protocol_v1.process_set_min_pin_length(
+            &mut persistent_store,
+            8,
+            Some(rp_ids),
+            None,
+        );
+        assert_eq!(response, Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL));
+        assert_eq!(persistent_store.min_pin_length().unwrap(), 4);
+    }
+
     #[test]
     fn test_process() {
         let mut rng = ThreadRng256 {};
@@ -1041,7 +1492,12 @@ mod test {
             permissions_rp_id: None,
         };
         assert!(pin_protocol_v1
-            .process_subcommand(&mut rng, &mut persistent_store, client_pin_params)
+            .process_subcommand(
+                &mut rng,
+                &mut persistent_store,
+                client_pin_params,
+                DUMMY_CLOCK_VALUE
+            )
             .is_ok());
 
         let client_pin_params = AuthenticatorClientPinParameters {
@@ -1065,7 +1521,12 @@ mod test {
         #[cfg(feature = "with_ctap2_1")]
         let error_code = Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER;
         assert_eq!(
-            pin_protocol_v1.process_subcommand(&mut rng, &mut persistent_store, client_pin_params),
+            pin_protocol_v1.process_subcommand(
+                &mut rng,
+                &mut persistent_store,
+                client_pin_params,
+                DUMMY_CLOCK_VALUE
+            ),
             Err(error_code)
         );
     }
@@ -1073,8 +1534,8 @@ mod test {
     #[test]
     fn test_decrypt_pin() {
         let shared_secret = [0x88; 32];
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(&shared_secret);
-        let aes_dec_key = crypto::aes256::DecryptionKey::new(&aes_enc_key);
+        let aes_enc_key = Crypto::aes256_encryption_key(&shared_secret);
+        let aes_dec_key = Crypto::aes256_decryption_key(&aes_enc_key);
 
         // "1234"
         let new_pin_enc = vec![
@@ -1116,8 +1577,8 @@ mod test {
         let mut rng = ThreadRng256 {};
         let mut persistent_store = PersistentStore::new(&mut rng);
         let shared_secret = [0x88; 32];
-        let aes_enc_key = crypto::aes256::EncryptionKey::new(&shared_secret);
-        let aes_dec_key = crypto::aes256::DecryptionKey::new(&aes_enc_key);
+        let aes_enc_key = Crypto::aes256_encryption_key(&shared_secret);
+        let aes_dec_key = Crypto::aes256_decryption_key(&aes_enc_key);
 
         let test_cases = vec![
             // Accept PIN "1234".
@@ -1132,27 +1593,83 @@ mod test {
                 b"12\04".to_vec(),
                 Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION),
             ),
+            // Reject PIN "1234'\0'5" (a zero byte followed by a non-zero byte).
+            (
+                b"1234\05".to_vec(),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION),
+            ),
             // PINs must be at most 63 bytes long, to allow for a trailing 0u8 padding.
             (
                 vec![0x30; 64],
                 Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION),
             ),
+            (vec![0x31; 63], Ok(())),
+            // The length is counted in code points, not in bytes.
+            ("\u{e9}t\u{e9}s".as_bytes().to_vec(), Ok(())),
+            (
+                "\u{e9}\u{e9}\u{e9}".as_bytes().to_vec(),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION),
+            ),
+            (
+                "\u{5bc6}\u{7801}\u{5bc6}\u{7801}".as_bytes().to_vec(),
+                Ok(()),
+            ),
+            (
+                "\u{5bc6}\u{7801}\u{5bc6}".as_bytes().to_vec(),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION),
+            ),
+            // 15 code points of 4 bytes fit in 63 bytes, 16 don't.
+            ("\u{1f511}".repeat(15).into_bytes(), Ok(())),
+            (
+                "\u{1f511}".repeat(16).into_bytes(),
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION),
+            ),
+            // Reject invalid UTF-8.
+            (
+                vec![0x31, 0x32, 0x33, 0xFF],
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION),
+            ),
+            (
+                vec![0x31, 0x32, 0x33, 0xE9],
+                Err(Ctap2StatusCode::CTAP2_ERR_PIN_POLICY_VIOLATION),
+            ),
         ];
         for (pin, result) in test_cases {
-            let old_pin_hash = persistent_store.pin_hash().unwrap();
+            let stored_pin_hash =
+                |store: &PersistentStore| store.pin_hash().unwrap().map(|hash| *hash.expose());
+            let old_pin_hash = stored_pin_hash(&persistent_store);
             let new_pin_enc = encrypt_pin(&shared_secret, pin);
             assert_eq!(
-                check_and_store_new_pin(&mut persistent_store, &aes_dec_key, new_pin_enc),
+                check_and_store_new_pin(
+                    &mut persistent_store,
+                    &DefaultPinPolicy,
+                    &aes_dec_key,
+                    new_pin_enc
+                ),
                 result
             );
             if result.is_ok() {
-                assert_ne!(old_pin_hash, persistent_store.pin_hash().unwrap());
+                assert_ne!(old_pin_hash, stored_pin_hash(&persistent_store));
             } else {
-                assert_eq!(old_pin_hash, persistent_store.pin_hash().unwrap());
+                assert_eq!(old_pin_hash, stored_pin_hash(&persistent_store));
             }
         }
     }
 
+    #[test]
+    fn test_pin_as_str() {
+        assert_eq!(pin_as_str(b""), Some(""));
+        assert_eq!(pin_as_str(b"1234"), Some("1234"));
+        assert_eq!(
+            pin_as_str("\u{e9}t\u{e9}".as_bytes()),
+            Some("\u{e9}t\u{e9}")
+        );
+        assert_eq!(pin_as_str("\u{1f511}".as_bytes()), Some("\u{1f511}"));
+        assert_eq!(pin_as_str(b"12\04"), None);
+        assert_eq!(pin_as_str(&[0xC3]), None);
+        assert_eq!(pin_as_str(&[0xC0, 0x80]), None);
+    }
+
     #[test]
     fn test_verify_pin_auth() {
         let hmac_key = [0x88; 16];
@@ -1252,6 +1769,26 @@ mod test {
         }
     }
 
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_clear_token_flags_and_permissions() {
+        let mut rng = ThreadRng256 {};
+        let key_agreement_key = crypto::ecdh::SecKey::gensk(&mut rng);
+        let mut pin_protocol_v1 = PinProtocolV1::new_test(key_agreement_key, [0x55; 32]);
+        pin_protocol_v1.permissions_rp_id = Some(String::from("example.com"));
+        pin_protocol_v1.clear_token_flags_and_permissions();
+        assert!(pin_protocol_v1.pin_uv_auth_token.is_in_use());
+        assert!(!pin_protocol_v1.get_user_verified_flag_value());
+        assert_eq!(
+            pin_protocol_v1.permissions,
+            PinPermission::LargeBlobWrite as u8
+        );
+        assert_eq!(
+            pin_protocol_v1.permissions_rp_id,
+            Some(String::from("example.com"))
+        );
+    }
+
     #[cfg(feature = "with_ctap2_1")]
     #[test]
     fn test_has_permission_for_rp_id() {
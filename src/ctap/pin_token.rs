@@ -0,0 +1,201 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::pin_protocol_v1::verify_pin_auth;
+#[cfg(feature = "with_ctap2_1")]
+use super::token_state::PinUvAuthTokenState;
+use crypto::rng256::Rng256;
+#[cfg(feature = "with_ctap2_1")]
+use libtock_drivers::timer::ClockValue;
+
+/// Maximum length of a pinUvAuthToken in bytes.
+pub const MAX_PIN_TOKEN_LENGTH: usize = 32;
+
+/// The pinUvAuthToken, a secret shared with the platform after it proved knowledge of the PIN.
+///
+/// PIN protocol 1 allows tokens of any multiple of 16 bytes, we support 16 and 32 bytes. Resetting
+/// the token generates a new value, so that all copies held by platforms become useless. With CTAP
+/// 2.1, the token additionally tracks whether it is in use, so that it also expires with time.
+pub struct PinToken {
+    value: [u8; MAX_PIN_TOKEN_LENGTH],
+    length: usize,
+    #[cfg(feature = "with_ctap2_1")]
+    state: PinUvAuthTokenState,
+}
+
+impl PinToken {
+    /// Generates a random token of the given length, which must be 16 or 32.
+    pub fn new(rng: &mut impl Rng256, length: usize) -> PinToken {
+        assert!(length == 16 || length == MAX_PIN_TOKEN_LENGTH);
+        PinToken {
+            value: rng.gen_uniform_u8x32(),
+            length,
+            #[cfg(feature = "with_ctap2_1")]
+            state: PinUvAuthTokenState::new(),
+        }
+    }
+
+    /// Returns the secret value, to be sent encrypted to the platform.
+    pub fn value(&self) -> &[u8] {
+        &self.value[..self.length]
+    }
+
+    /// Invalidates the token by generating a new value, as after a reset or a PIN change.
+    pub fn reset(&mut self, rng: &mut impl Rng256) {
+        self.value = rng.gen_uniform_u8x32();
+        #[cfg(feature = "with_ctap2_1")]
+        self.state.stop_using_pin_uv_auth_token();
+    }
+
+    /// Checks the pinUvAuthParam of a message, authenticated with the token.
+    ///
+    /// With CTAP 2.1, a token that is not in use never verifies, and a successful check counts as
+    /// a use of the token.
+    pub fn verify(&mut self, message: &[u8], pin_auth: &[u8]) -> bool {
+        #[cfg(feature = "with_ctap2_1")]
+        {
+            if !self.state.is_in_use() {
+                return false;
+            }
+        }
+        let verified = verify_pin_auth(self.value(), message, pin_auth);
+        #[cfg(feature = "with_ctap2_1")]
+        {
+            if verified {
+                self.state.mark_used();
+            }
+        }
+        verified
+    }
+
+    /// Starts using the token after it was sent to a platform.
+    #[cfg(feature = "with_ctap2_1")]
+    pub fn begin_using(&mut self, user_is_present: bool, now: ClockValue) {
+        self.state
+            .begin_using_pin_uv_auth_token(user_is_present, now);
+    }
+
+    /// Clears the cached user present and verified flags. The token stays in use.
+    #[cfg(feature = "with_ctap2_1")]
+    pub fn clear_flags(&mut self) {
+        self.state.clear_user_present_flag();
+        self.state.clear_user_verified_flag();
+    }
+
+    /// Expires the token and its cached flags. Called regularly with the current time.
+    #[cfg(feature = "with_ctap2_1")]
+    pub fn update(&mut self, now: ClockValue) {
+        self.state.pin_uv_auth_token_usage_timer_observer(now);
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    pub fn is_in_use(&self) -> bool {
+        self.state.is_in_use()
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    pub fn get_user_present_flag_value(&self) -> bool {
+        self.state.get_user_present_flag_value()
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    pub fn get_user_verified_flag_value(&self) -> bool {
+        self.state.get_user_verified_flag_value()
+    }
+
+    /// Returns a token with a known value, already in use with CTAP 2.1.
+    #[cfg(test)]
+    pub fn new_test(value: [u8; MAX_PIN_TOKEN_LENGTH]) -> PinToken {
+        #[cfg_attr(not(feature = "with_ctap2_1"), allow(unused_mut))]
+        let mut pin_token = PinToken {
+            value,
+            length: MAX_PIN_TOKEN_LENGTH,
+            #[cfg(feature = "with_ctap2_1")]
+            state: PinUvAuthTokenState::new(),
+        };
+        // The token starts at the origin of the 32kHz clock used in tests.
+        #[cfg(feature = "with_ctap2_1")]
+        pin_token.begin_using(false, ClockValue::new(0, 32768));
+        pin_token
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ctap::Sha256;
+    use alloc::vec::Vec;
+    use crypto::hmac::hmac_256;
+    use crypto::rng256::ThreadRng256;
+
+    fn pin_auth(value: &[u8], message: &[u8]) -> Vec<u8> {
+        hmac_256::<Sha256>(value, message)[..16].to_vec()
+    }
+
+    #[test]
+    fn test_length() {
+        let mut rng = ThreadRng256 {};
+        assert_eq!(PinToken::new(&mut rng, 16).value().len(), 16);
+        assert_eq!(PinToken::new(&mut rng, 32).value().len(), 32);
+        let mut pin_token = PinToken::new_test([0x55; MAX_PIN_TOKEN_LENGTH]);
+        pin_token.length = 16;
+        let message = [0xAA];
+        assert!(!pin_token.verify(&message, &pin_auth(&[0x55; 32], &message)));
+        assert!(pin_token.verify(&message, &pin_auth(&[0x55; 16], &message)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_unsupported_length() {
+        let mut rng = ThreadRng256 {};
+        PinToken::new(&mut rng, 24);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut rng = ThreadRng256 {};
+        let mut pin_token = PinToken::new_test([0x55; MAX_PIN_TOKEN_LENGTH]);
+        let message = [0xAA];
+        let old_pin_auth = pin_auth(pin_token.value(), &message);
+        assert!(pin_token.verify(&message, &old_pin_auth));
+
+        pin_token.reset(&mut rng);
+        assert_ne!(pin_token.value(), &[0x55; MAX_PIN_TOKEN_LENGTH][..]);
+        assert!(!pin_token.verify(&message, &old_pin_auth));
+        // After a reset, even the new value is only accepted once the token is issued again.
+        let new_pin_auth = pin_auth(pin_token.value(), &message);
+        #[cfg(feature = "with_ctap2_1")]
+        {
+            assert!(!pin_token.is_in_use());
+            assert!(!pin_token.verify(&message, &new_pin_auth));
+            pin_token.begin_using(false, ClockValue::new(0, 32768));
+        }
+        assert!(pin_token.verify(&message, &new_pin_auth));
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_clear_flags() {
+        let mut pin_token = PinToken::new_test([0x55; MAX_PIN_TOKEN_LENGTH]);
+        let message = [0xAA];
+        let pin_auth_param = pin_auth(pin_token.value(), &message);
+        assert!(pin_token.get_user_verified_flag_value());
+
+        pin_token.clear_flags();
+        assert!(pin_token.is_in_use());
+        assert!(!pin_token.get_user_present_flag_value());
+        assert!(!pin_token.get_user_verified_flag_value());
+        assert!(pin_token.verify(&message, &pin_auth_param));
+    }
+}
@@ -12,16 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(feature = "with_ctap2_1")]
-use super::data_formats::{AuthenticatorTransport, PublicKeyCredentialParameter};
 use super::data_formats::{
-    CoseKey, CredentialProtectionPolicy, PackedAttestationStatement, PublicKeyCredentialDescriptor,
-    PublicKeyCredentialUserEntity,
+    AttestationStatement, AuditEvent, CoseKey, CredentialProtectionPolicy,
+    PublicKeyCredentialDescriptor, PublicKeyCredentialUserEntity, VendorSubCommand,
 };
+#[cfg(feature = "with_ctap2_1")]
+use super::data_formats::{AuthenticatorTransport, PublicKeyCredentialParameter};
+#[cfg(feature = "perf")]
+use super::perf::CommandTiming;
+use super::status_code::Ctap2StatusCode;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
+#[cfg(feature = "with_ctap2_1")]
+use cbor::cbor_unsigned;
 use cbor::{cbor_array_vec, cbor_bool, cbor_map_btree, cbor_map_options, cbor_text};
+use core::convert::TryFrom;
 
 #[cfg_attr(test, derive(PartialEq))]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
@@ -34,6 +40,20 @@ pub enum ResponseData {
     AuthenticatorReset,
     #[cfg(feature = "with_ctap2_1")]
     AuthenticatorSelection,
+    #[cfg(feature = "with_ctap2_1")]
+    AuthenticatorLargeBlobs(Option<AuthenticatorLargeBlobsResponse>),
+    #[cfg(feature = "with_ctap2_1")]
+    AuthenticatorConfig,
+    AuthenticatorVendorConfigure(AuthenticatorVendorConfigureResponse),
+    AuthenticatorVendorAuditLog(AuthenticatorVendorAuditLogResponse),
+    #[cfg(feature = "debug_ctap")]
+    AuthenticatorVendorUserPresence,
+    AuthenticatorVendorMetrics(AuthenticatorVendorMetricsResponse),
+    AuthenticatorVendorSelfTest(AuthenticatorVendorSelfTestResponse),
+    AuthenticatorVendorDisplayName,
+    #[cfg(feature = "perf")]
+    AuthenticatorVendorCommandTimings(AuthenticatorVendorCommandTimingsResponse),
+    AuthenticatorVendorIdentify,
     AuthenticatorVendor(AuthenticatorVendorResponse),
 }
 
@@ -49,6 +69,22 @@ impl From<ResponseData> for Option<cbor::Value> {
             ResponseData::AuthenticatorReset => None,
             #[cfg(feature = "with_ctap2_1")]
             ResponseData::AuthenticatorSelection => None,
+            #[cfg(feature = "with_ctap2_1")]
+            ResponseData::AuthenticatorLargeBlobs(Some(data)) => Some(data.into()),
+            #[cfg(feature = "with_ctap2_1")]
+            ResponseData::AuthenticatorLargeBlobs(None) => None,
+            #[cfg(feature = "with_ctap2_1")]
+            ResponseData::AuthenticatorConfig => None,
+            ResponseData::AuthenticatorVendorConfigure(data) => Some(data.into()),
+            ResponseData::AuthenticatorVendorAuditLog(data) => Some(data.into()),
+            #[cfg(feature = "debug_ctap")]
+            ResponseData::AuthenticatorVendorUserPresence => None,
+            ResponseData::AuthenticatorVendorMetrics(data) => Some(data.into()),
+            ResponseData::AuthenticatorVendorSelfTest(data) => Some(data.into()),
+            ResponseData::AuthenticatorVendorDisplayName => None,
+            #[cfg(feature = "perf")]
+            ResponseData::AuthenticatorVendorCommandTimings(data) => Some(data.into()),
+            ResponseData::AuthenticatorVendorIdentify => None,
             ResponseData::AuthenticatorVendor(data) => Some(data.into()),
         }
     }
@@ -57,21 +93,20 @@ impl From<ResponseData> for Option<cbor::Value> {
 #[cfg_attr(test, derive(PartialEq))]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
 pub struct AuthenticatorMakeCredentialResponse {
-    pub fmt: String,
     pub auth_data: Vec<u8>,
-    pub att_stmt: PackedAttestationStatement,
+    // The fmt field is derived from the statement.
+    pub att_stmt: AttestationStatement,
 }
 
 impl From<AuthenticatorMakeCredentialResponse> for cbor::Value {
     fn from(make_credential_response: AuthenticatorMakeCredentialResponse) -> Self {
         let AuthenticatorMakeCredentialResponse {
-            fmt,
             auth_data,
             att_stmt,
         } = make_credential_response;
 
         cbor_map_options! {
-            1 => fmt,
+            1 => att_stmt.format().as_str(),
             2 => auth_data,
             3 => att_stmt,
         }
@@ -126,11 +161,21 @@ pub struct AuthenticatorGetInfoResponse {
     pub transports: Option<Vec<AuthenticatorTransport>>,
     #[cfg(feature = "with_ctap2_1")]
     pub algorithms: Option<Vec<PublicKeyCredentialParameter>>,
+    #[cfg(feature = "with_ctap2_1")]
+    pub max_serialized_large_blob_array: Option<u64>,
     pub default_cred_protect: Option<CredentialProtectionPolicy>,
     #[cfg(feature = "with_ctap2_1")]
     pub min_pin_length: u8,
     #[cfg(feature = "with_ctap2_1")]
     pub firmware_version: Option<u64>,
+    #[cfg(feature = "with_ctap2_1")]
+    pub max_rp_ids_for_set_min_pin_length: Option<u64>,
+    #[cfg(feature = "with_ctap2_1")]
+    pub preferred_platform_uv_attempts: Option<u64>,
+    #[cfg(feature = "with_ctap2_1")]
+    pub uv_modality: Option<u64>,
+    #[cfg(feature = "with_ctap2_1")]
+    pub certifications: Option<BTreeMap<String, u64>>,
 }
 
 impl From<AuthenticatorGetInfoResponse> for cbor::Value {
@@ -147,9 +192,14 @@ impl From<AuthenticatorGetInfoResponse> for cbor::Value {
             max_credential_id_length,
             transports,
             algorithms,
+            max_serialized_large_blob_array,
             default_cred_protect,
             min_pin_length,
             firmware_version,
+            max_rp_ids_for_set_min_pin_length,
+            preferred_platform_uv_attempts,
+            uv_modality,
+            certifications,
         } = get_info_response;
 
         let options_cbor: Option<cbor::Value> = options.map(|options| {
@@ -159,6 +209,13 @@ impl From<AuthenticatorGetInfoResponse> for cbor::Value {
                 .collect();
             cbor_map_btree!(option_map)
         });
+        let certifications_cbor: Option<cbor::Value> = certifications.map(|certifications| {
+            let certification_map: BTreeMap<_, _> = certifications
+                .into_iter()
+                .map(|(key, value)| (cbor_text!(key), cbor_unsigned!(value)))
+                .collect();
+            cbor_map_btree!(certification_map)
+        });
 
         cbor_map_options! {
             0x01 => cbor_array_vec!(versions),
@@ -171,9 +228,14 @@ impl From<AuthenticatorGetInfoResponse> for cbor::Value {
             0x08 => max_credential_id_length,
             0x09 => transports.map(|vec| cbor_array_vec!(vec)),
             0x0A => algorithms.map(|vec| cbor_array_vec!(vec)),
+            0x0B => max_serialized_large_blob_array,
             0x0C => default_cred_protect.map(|p| p as u64),
             0x0D => min_pin_length as u64,
             0x0E => firmware_version,
+            0x10 => max_rp_ids_for_set_min_pin_length,
+            0x11 => preferred_platform_uv_attempts,
+            0x12 => uv_modality,
+            0x13 => certifications_cbor,
         }
     }
 
@@ -235,28 +297,286 @@ impl From<AuthenticatorClientPinResponse> for cbor::Value {
 
 #[cfg_attr(test, derive(PartialEq))]
 #[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
-pub struct AuthenticatorVendorResponse {
+pub struct AuthenticatorVendorConfigureResponse {
     pub cert_programmed: bool,
     pub pkey_programmed: bool,
+    // Proves that the attestation private key is programmed, if a challenge was sent.
+    pub attestation_signature: Option<Vec<u8>>,
 }
 
-impl From<AuthenticatorVendorResponse> for cbor::Value {
-    fn from(vendor_response: AuthenticatorVendorResponse) -> Self {
-        let AuthenticatorVendorResponse {
+impl From<AuthenticatorVendorConfigureResponse> for cbor::Value {
+    fn from(vendor_response: AuthenticatorVendorConfigureResponse) -> Self {
+        let AuthenticatorVendorConfigureResponse {
             cert_programmed,
             pkey_programmed,
+            attestation_signature,
         } = vendor_response;
 
         cbor_map_options! {
             1 => cert_programmed,
             2 => pkey_programmed,
+            3 => attestation_signature,
+        }
+    }
+}
+
+#[cfg_attr(test, derive(PartialEq))]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub struct AuthenticatorVendorAuditLogResponse {
+    pub events: Vec<AuditEvent>,
+}
+
+impl From<AuthenticatorVendorAuditLogResponse> for cbor::Value {
+    fn from(audit_log_response: AuthenticatorVendorAuditLogResponse) -> Self {
+        let AuthenticatorVendorAuditLogResponse { events } = audit_log_response;
+
+        cbor_map_options! {
+            1 => cbor_array_vec!(events),
+        }
+    }
+}
+
+#[cfg(feature = "with_ctap2_1")]
+#[cfg_attr(test, derive(PartialEq))]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub struct AuthenticatorLargeBlobsResponse {
+    pub config: Vec<u8>,
+}
+
+#[cfg(feature = "with_ctap2_1")]
+impl From<AuthenticatorLargeBlobsResponse> for cbor::Value {
+    fn from(large_blobs_response: AuthenticatorLargeBlobsResponse) -> Self {
+        let AuthenticatorLargeBlobsResponse { config } = large_blobs_response;
+
+        cbor_map_options! {
+            0x01 => config,
+        }
+    }
+}
+
+#[cfg_attr(test, derive(PartialEq))]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub struct AuthenticatorVendorMetricsResponse {
+    pub registrations: u64,
+    pub assertions: u64,
+}
+
+impl From<AuthenticatorVendorMetricsResponse> for cbor::Value {
+    fn from(metrics_response: AuthenticatorVendorMetricsResponse) -> Self {
+        let AuthenticatorVendorMetricsResponse {
+            registrations,
+            assertions,
+        } = metrics_response;
+
+        cbor_map_options! {
+            1 => registrations,
+            2 => assertions,
+        }
+    }
+}
+
+/// The latency statistics of each command since boot, ordered by command code.
+#[cfg(feature = "perf")]
+#[cfg_attr(test, derive(PartialEq))]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub struct AuthenticatorVendorCommandTimingsResponse {
+    pub timings: Vec<(u8, CommandTiming)>,
+}
+
+#[cfg(feature = "perf")]
+impl From<AuthenticatorVendorCommandTimingsResponse> for cbor::Value {
+    fn from(command_timings_response: AuthenticatorVendorCommandTimingsResponse) -> Self {
+        let timings = command_timings_response
+            .timings
+            .into_iter()
+            .map(|(command_code, timing)| {
+                cbor_map_options! {
+                    1 => command_code as u64,
+                    2 => timing.count,
+                    3 => timing.min_ms,
+                    4 => timing.average_ms(),
+                    5 => timing.max_ms,
+                }
+            })
+            .collect::<Vec<_>>();
+        cbor_array_vec!(timings)
+    }
+}
+
+#[cfg_attr(test, derive(PartialEq))]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub struct AuthenticatorVendorSelfTestResponse {
+    pub rng: bool,
+    pub crypto: bool,
+    pub storage: bool,
+    // Missing if the store could not be scanned.
+    pub store_check: Option<StoreCheckReport>,
+}
+
+impl From<AuthenticatorVendorSelfTestResponse> for cbor::Value {
+    fn from(self_test_response: AuthenticatorVendorSelfTestResponse) -> Self {
+        let AuthenticatorVendorSelfTestResponse {
+            rng,
+            crypto,
+            storage,
+            store_check,
+        } = self_test_response;
+
+        cbor_map_options! {
+            1 => rng,
+            2 => crypto,
+            3 => storage,
+            4 => store_check,
+        }
+    }
+}
+
+/// Consistency report of the persistent store, see `persistent_store::StoreCheck`.
+#[derive(Clone)]
+#[cfg_attr(test, derive(PartialEq))]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub struct StoreCheckReport {
+    pub consistent: bool,
+    pub entries: u64,
+    pub corrupted_entries: u64,
+    pub partial_entries: u64,
+    pub internal_entries: u64,
+    pub duplicate_keys: u64,
+    pub unwiped_words: u64,
+    pub tail_consistent: bool,
+    pub written_after_tail: u64,
+    pub used_words: u64,
+    pub total_words: u64,
+}
+
+impl From<&persistent_store::StoreCheck> for StoreCheckReport {
+    fn from(check: &persistent_store::StoreCheck) -> Self {
+        StoreCheckReport {
+            consistent: check.is_consistent(),
+            entries: check.entries as u64,
+            corrupted_entries: check.corrupted_entries as u64,
+            partial_entries: check.partial_entries as u64,
+            internal_entries: check.internal_entries as u64,
+            duplicate_keys: check.duplicate_keys as u64,
+            unwiped_words: check.unwiped_words as u64,
+            tail_consistent: check.tail_consistent,
+            written_after_tail: check.written_after_tail as u64,
+            used_words: check.capacity.used() as u64,
+            total_words: check.capacity.total() as u64,
+        }
+    }
+}
+
+impl From<StoreCheckReport> for cbor::Value {
+    fn from(report: StoreCheckReport) -> Self {
+        let StoreCheckReport {
+            consistent,
+            entries,
+            corrupted_entries,
+            partial_entries,
+            internal_entries,
+            duplicate_keys,
+            unwiped_words,
+            tail_consistent,
+            written_after_tail,
+            used_words,
+            total_words,
+        } = report;
+
+        cbor_map_options! {
+            1 => consistent,
+            2 => entries,
+            3 => corrupted_entries,
+            4 => partial_entries,
+            5 => internal_entries,
+            6 => duplicate_keys,
+            7 => unwiped_words,
+            8 => tail_consistent,
+            9 => written_after_tail,
+            10 => used_words,
+            11 => total_words,
+        }
+    }
+}
+
+/// Response of the vendor command, tagged with the subcommand it answers.
+#[cfg_attr(test, derive(PartialEq))]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub enum AuthenticatorVendorResponse {
+    Configure(AuthenticatorVendorConfigureResponse),
+    AuditLog(AuthenticatorVendorAuditLogResponse),
+    Metrics(AuthenticatorVendorMetricsResponse),
+    SelfTest(AuthenticatorVendorSelfTestResponse),
+    DisplayName,
+    #[cfg(feature = "perf")]
+    CommandTimings(AuthenticatorVendorCommandTimingsResponse),
+    Identify,
+}
+
+impl TryFrom<ResponseData> for AuthenticatorVendorResponse {
+    type Error = Ctap2StatusCode;
+
+    fn try_from(response: ResponseData) -> Result<Self, Ctap2StatusCode> {
+        match response {
+            ResponseData::AuthenticatorVendorConfigure(data) => {
+                Ok(AuthenticatorVendorResponse::Configure(data))
+            }
+            ResponseData::AuthenticatorVendorAuditLog(data) => {
+                Ok(AuthenticatorVendorResponse::AuditLog(data))
+            }
+            ResponseData::AuthenticatorVendorMetrics(data) => {
+                Ok(AuthenticatorVendorResponse::Metrics(data))
+            }
+            ResponseData::AuthenticatorVendorSelfTest(data) => {
+                Ok(AuthenticatorVendorResponse::SelfTest(data))
+            }
+            ResponseData::AuthenticatorVendorDisplayName => {
+                Ok(AuthenticatorVendorResponse::DisplayName)
+            }
+            #[cfg(feature = "perf")]
+            ResponseData::AuthenticatorVendorCommandTimings(data) => {
+                Ok(AuthenticatorVendorResponse::CommandTimings(data))
+            }
+            ResponseData::AuthenticatorVendorIdentify => Ok(AuthenticatorVendorResponse::Identify),
+            _ => Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR),
+        }
+    }
+}
+
+impl From<AuthenticatorVendorResponse> for cbor::Value {
+    fn from(vendor_response: AuthenticatorVendorResponse) -> Self {
+        let (sub_command, response) = match vendor_response {
+            AuthenticatorVendorResponse::Configure(data) => {
+                (VendorSubCommand::Configure, Some(cbor::Value::from(data)))
+            }
+            AuthenticatorVendorResponse::AuditLog(data) => {
+                (VendorSubCommand::AuditLog, Some(cbor::Value::from(data)))
+            }
+            AuthenticatorVendorResponse::Metrics(data) => {
+                (VendorSubCommand::Metrics, Some(cbor::Value::from(data)))
+            }
+            AuthenticatorVendorResponse::SelfTest(data) => {
+                (VendorSubCommand::SelfTest, Some(cbor::Value::from(data)))
+            }
+            AuthenticatorVendorResponse::DisplayName => (VendorSubCommand::DisplayName, None),
+            #[cfg(feature = "perf")]
+            AuthenticatorVendorResponse::CommandTimings(data) => (
+                VendorSubCommand::CommandTimings,
+                Some(cbor::Value::from(data)),
+            ),
+            AuthenticatorVendorResponse::Identify => (VendorSubCommand::Identify, None),
+        };
+
+        cbor_map_options! {
+            1 => sub_command,
+            2 => response,
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::super::data_formats::PackedAttestationStatement;
+    use super::super::data_formats::{AuditEventType, PackedAttestationStatement};
     #[cfg(feature = "with_ctap2_1")]
     use super::super::ES256_CRED_PARAM;
     use super::*;
@@ -279,9 +599,8 @@ mod test {
         };
 
         let make_credential_response = AuthenticatorMakeCredentialResponse {
-            fmt: "packed".to_string(),
             auth_data: vec![0xAD],
-            att_stmt,
+            att_stmt: AttestationStatement::Packed(att_stmt),
         };
         let response_cbor: Option<cbor::Value> =
             ResponseData::AuthenticatorMakeCredential(make_credential_response).into();
@@ -293,6 +612,22 @@ mod test {
         assert_eq!(response_cbor, Some(expected_cbor));
     }
 
+    #[test]
+    fn test_make_credential_none_attestation_into_cbor() {
+        let make_credential_response = AuthenticatorMakeCredentialResponse {
+            auth_data: vec![0xAD],
+            att_stmt: AttestationStatement::None,
+        };
+        let response_cbor: Option<cbor::Value> =
+            ResponseData::AuthenticatorMakeCredential(make_credential_response).into();
+        let expected_cbor = cbor_map_options! {
+            1 => "none",
+            2 => vec![0xAD],
+            3 => cbor_map! {},
+        };
+        assert_eq!(response_cbor, Some(expected_cbor));
+    }
+
     #[test]
     fn test_get_assertion_into_cbor() {
         let get_assertion_response = AuthenticatorGetAssertionResponse {
@@ -329,11 +664,21 @@ mod test {
             transports: None,
             #[cfg(feature = "with_ctap2_1")]
             algorithms: None,
+            #[cfg(feature = "with_ctap2_1")]
+            max_serialized_large_blob_array: None,
             default_cred_protect: None,
             #[cfg(feature = "with_ctap2_1")]
             min_pin_length: 4,
             #[cfg(feature = "with_ctap2_1")]
             firmware_version: None,
+            #[cfg(feature = "with_ctap2_1")]
+            max_rp_ids_for_set_min_pin_length: None,
+            #[cfg(feature = "with_ctap2_1")]
+            preferred_platform_uv_attempts: None,
+            #[cfg(feature = "with_ctap2_1")]
+            uv_modality: None,
+            #[cfg(feature = "with_ctap2_1")]
+            certifications: None,
         };
         let response_cbor: Option<cbor::Value> =
             ResponseData::AuthenticatorGetInfo(get_info_response).into();
@@ -356,6 +701,8 @@ mod test {
     fn test_get_info_optionals_into_cbor() {
         let mut options_map = BTreeMap::new();
         options_map.insert(String::from("rk"), true);
+        let mut certifications = BTreeMap::new();
+        certifications.insert(String::from("FIDO"), 1);
         let get_info_response = AuthenticatorGetInfoResponse {
             versions: vec!["FIDO_2_0".to_string()],
             extensions: Some(vec!["extension".to_string()]),
@@ -367,9 +714,14 @@ mod test {
             max_credential_id_length: Some(256),
             transports: Some(vec![AuthenticatorTransport::Usb]),
             algorithms: Some(vec![ES256_CRED_PARAM]),
+            max_serialized_large_blob_array: Some(1024),
             default_cred_protect: Some(CredentialProtectionPolicy::UserVerificationRequired),
             min_pin_length: 4,
             firmware_version: Some(0),
+            max_rp_ids_for_set_min_pin_length: Some(8),
+            preferred_platform_uv_attempts: Some(3),
+            uv_modality: Some(0x02),
+            certifications: Some(certifications),
         };
         let response_cbor: Option<cbor::Value> =
             ResponseData::AuthenticatorGetInfo(get_info_response).into();
@@ -384,9 +736,14 @@ mod test {
             0x08 => 256,
             0x09 => cbor_array_vec![vec!["usb"]],
             0x0A => cbor_array_vec![vec![ES256_CRED_PARAM]],
+            0x0B => 1024,
             0x0C => CredentialProtectionPolicy::UserVerificationRequired as u64,
             0x0D => 4,
             0x0E => 0,
+            0x10 => 8,
+            0x11 => 3,
+            0x12 => 0x02,
+            0x13 => cbor_map! {"FIDO" => 1},
         };
         assert_eq!(response_cbor, Some(expected_cbor));
     }
@@ -425,12 +782,38 @@ mod test {
         assert_eq!(response_cbor, None);
     }
 
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_large_blobs_into_cbor() {
+        let large_blobs_response = AuthenticatorLargeBlobsResponse {
+            config: vec![0x80, 0x76],
+        };
+        let response_cbor: Option<cbor::Value> =
+            ResponseData::AuthenticatorLargeBlobs(Some(large_blobs_response)).into();
+        assert_eq!(
+            response_cbor,
+            Some(cbor_map_options! {
+                0x01 => vec![0x80, 0x76],
+            })
+        );
+        let response_cbor: Option<cbor::Value> = ResponseData::AuthenticatorLargeBlobs(None).into();
+        assert_eq!(response_cbor, None);
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_config_into_cbor() {
+        let response_cbor: Option<cbor::Value> = ResponseData::AuthenticatorConfig.into();
+        assert_eq!(response_cbor, None);
+    }
+
     #[test]
     fn test_vendor_response_into_cbor() {
         let response_cbor: Option<cbor::Value> =
-            ResponseData::AuthenticatorVendor(AuthenticatorVendorResponse {
+            ResponseData::AuthenticatorVendorConfigure(AuthenticatorVendorConfigureResponse {
                 cert_programmed: true,
                 pkey_programmed: false,
+                attestation_signature: None,
             })
             .into();
         assert_eq!(
@@ -441,9 +824,10 @@ mod test {
             })
         );
         let response_cbor: Option<cbor::Value> =
-            ResponseData::AuthenticatorVendor(AuthenticatorVendorResponse {
+            ResponseData::AuthenticatorVendorConfigure(AuthenticatorVendorConfigureResponse {
                 cert_programmed: false,
                 pkey_programmed: true,
+                attestation_signature: None,
             })
             .into();
         assert_eq!(
@@ -453,5 +837,185 @@ mod test {
                 2 => true,
             })
         );
+        let response_cbor: Option<cbor::Value> =
+            ResponseData::AuthenticatorVendorConfigure(AuthenticatorVendorConfigureResponse {
+                cert_programmed: true,
+                pkey_programmed: true,
+                attestation_signature: Some(vec![0x30, 0x44]),
+            })
+            .into();
+        assert_eq!(
+            response_cbor,
+            Some(cbor_map_options! {
+                1 => true,
+                2 => true,
+                3 => vec![0x30, 0x44],
+            })
+        );
+    }
+
+    #[test]
+    fn test_vendor_metrics_response_into_cbor() {
+        let response_cbor: Option<cbor::Value> =
+            ResponseData::AuthenticatorVendorMetrics(AuthenticatorVendorMetricsResponse {
+                registrations: 3,
+                assertions: 7,
+            })
+            .into();
+        assert_eq!(
+            response_cbor,
+            Some(cbor_map_options! {
+                1 => 3,
+                2 => 7,
+            })
+        );
+    }
+
+    #[test]
+    fn test_vendor_self_test_response_into_cbor() {
+        let response_cbor: Option<cbor::Value> =
+            ResponseData::AuthenticatorVendorSelfTest(AuthenticatorVendorSelfTestResponse {
+                rng: true,
+                crypto: true,
+                storage: false,
+                store_check: None,
+            })
+            .into();
+        assert_eq!(
+            response_cbor,
+            Some(cbor_map_options! {
+                1 => true,
+                2 => true,
+                3 => false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_store_check_report_into_cbor() {
+        let report = StoreCheckReport {
+            consistent: false,
+            entries: 12,
+            corrupted_entries: 1,
+            partial_entries: 0,
+            internal_entries: 0,
+            duplicate_keys: 0,
+            unwiped_words: 3,
+            tail_consistent: true,
+            written_after_tail: 0,
+            used_words: 40,
+            total_words: 4315,
+        };
+        let report_cbor: cbor::Value = report.into();
+        assert_eq!(
+            report_cbor,
+            cbor_map_options! {
+                1 => false,
+                2 => 12,
+                3 => 1,
+                4 => 0,
+                5 => 0,
+                6 => 0,
+                7 => 3,
+                8 => true,
+                9 => 0,
+                10 => 40,
+                11 => 4315,
+            }
+        );
+    }
+
+    #[test]
+    fn test_vendor_tagged_response_into_cbor() {
+        let metrics_response = AuthenticatorVendorMetricsResponse {
+            registrations: 3,
+            assertions: 7,
+        };
+        let vendor_response = AuthenticatorVendorResponse::try_from(
+            ResponseData::AuthenticatorVendorMetrics(metrics_response),
+        )
+        .unwrap();
+        let response_cbor: Option<cbor::Value> =
+            ResponseData::AuthenticatorVendor(vendor_response).into();
+        assert_eq!(
+            response_cbor,
+            Some(cbor_map_options! {
+                1 => VendorSubCommand::Metrics,
+                2 => cbor_map_options! {
+                    1 => 3,
+                    2 => 7,
+                },
+            })
+        );
+
+        let response_cbor: Option<cbor::Value> = ResponseData::AuthenticatorVendor(
+            AuthenticatorVendorResponse::Configure(AuthenticatorVendorConfigureResponse {
+                cert_programmed: true,
+                pkey_programmed: false,
+                attestation_signature: None,
+            }),
+        )
+        .into();
+        assert_eq!(
+            response_cbor,
+            Some(cbor_map_options! {
+                1 => VendorSubCommand::Configure,
+                2 => cbor_map_options! {
+                    1 => true,
+                    2 => false,
+                },
+            })
+        );
+
+        // Only vendor responses can be tagged.
+        assert_eq!(
+            AuthenticatorVendorResponse::try_from(ResponseData::AuthenticatorReset),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "perf")]
+    fn test_vendor_command_timings_response_into_cbor() {
+        let timing = CommandTiming {
+            count: 2,
+            min_ms: 100,
+            max_ms: 300,
+            total_ms: 400,
+        };
+        let response_cbor: Option<cbor::Value> = ResponseData::AuthenticatorVendorCommandTimings(
+            AuthenticatorVendorCommandTimingsResponse {
+                timings: vec![(0x01, timing)],
+            },
+        )
+        .into();
+        let expected_cbor = cbor_map_options! {
+            1 => 0x01,
+            2 => 2,
+            3 => 100,
+            4 => 200,
+            5 => 300,
+        };
+        assert_eq!(response_cbor, Some(cbor_array_vec!(vec![expected_cbor])));
+    }
+
+    #[test]
+    fn test_vendor_audit_log_response_into_cbor() {
+        let event = AuditEvent {
+            sequence: 3,
+            event_type: AuditEventType::GetAssertion,
+            timestamp_ms: 500,
+        };
+        let response_cbor: Option<cbor::Value> =
+            ResponseData::AuthenticatorVendorAuditLog(AuthenticatorVendorAuditLogResponse {
+                events: vec![event.clone()],
+            })
+            .into();
+        assert_eq!(
+            response_cbor,
+            Some(cbor_map_options! {
+                1 => cbor_array_vec![vec![event]],
+            })
+        );
     }
 }
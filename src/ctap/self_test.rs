@@ -0,0 +1,133 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Built-in self tests, run on demand with a vendor command.
+//!
+//! They are meant for manufacturing, to check a unit over USB with the production firmware. The
+//! storage self test lives with the storage, see `PersistentStore::self_test`.
+
+use crypto::hmac::hmac_256;
+use crypto::provider::CryptoProvider;
+use crypto::rng256::Rng256;
+use crypto::{ecdsa, Decrypt16BytesBlock, Encrypt16BytesBlock, Hash256};
+
+// SHA-256 of "abc", from FIPS 180-2 appendix B.1.
+const SHA256_MESSAGE: &[u8] = b"abc";
+const SHA256_DIGEST: [u8; 32] = [
+    0xBA, 0x78, 0x16, 0xBF, 0x8F, 0x01, 0xCF, 0xEA, 0x41, 0x41, 0x40, 0xDE, 0x5D, 0xAE, 0x22, 0x23,
+    0xB0, 0x03, 0x61, 0xA3, 0x96, 0x17, 0x7A, 0x9C, 0xB4, 0x10, 0xFF, 0x61, 0xF2, 0x00, 0x15, 0xAD,
+];
+
+// HMAC-SHA-256 test case 2 from RFC 4231.
+const HMAC_KEY: &[u8] = b"Jefe";
+const HMAC_MESSAGE: &[u8] = b"what do ya want for nothing?";
+const HMAC_MAC: [u8; 32] = [
+    0x5B, 0xDC, 0xC1, 0x46, 0xBF, 0x60, 0x75, 0x4E, 0x6A, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75, 0xC7,
+    0x5A, 0x00, 0x3F, 0x08, 0x9D, 0x27, 0x39, 0x83, 0x9D, 0xEC, 0x58, 0xB9, 0x64, 0xEC, 0x38, 0x43,
+];
+
+// AES-256 example vector from FIPS 197 appendix C.3.
+const AES_KEY: [u8; 32] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F,
+    0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E, 0x1F,
+];
+const AES_PLAINTEXT: [u8; 16] = [
+    0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF,
+];
+const AES_CIPHERTEXT: [u8; 16] = [
+    0x8E, 0xA2, 0xB7, 0xCA, 0x51, 0x67, 0x45, 0xBF, 0xEA, 0xFC, 0x49, 0x90, 0x4B, 0x49, 0x60, 0x89,
+];
+
+// Deterministic ECDSA P-256 with SHA-256 of "sample", from RFC 6979 section A.2.5.
+const ECDSA_KEY: [u8; 32] = [
+    0xC9, 0xAF, 0xA9, 0xD8, 0x45, 0xBA, 0x75, 0x16, 0x6B, 0x5C, 0x21, 0x57, 0x67, 0xB1, 0xD6, 0x93,
+    0x4E, 0x50, 0xC3, 0xDB, 0x36, 0xE8, 0x9B, 0x12, 0x7B, 0x8A, 0x62, 0x2B, 0x12, 0x0F, 0x67, 0x21,
+];
+const ECDSA_MESSAGE: &[u8] = b"sample";
+const ECDSA_SIGNATURE: [u8; 72] = [
+    0x30, 0x46, 0x02, 0x21, 0x00, 0xEF, 0xD4, 0x8B, 0x2A, 0xAC, 0xB6, 0xA8, 0xFD, 0x11, 0x40, 0xDD,
+    0x9C, 0xD4, 0x5E, 0x81, 0xD6, 0x9D, 0x2C, 0x87, 0x7B, 0x56, 0xAA, 0xF9, 0x91, 0xC3, 0x4D, 0x0E,
+    0xA8, 0x4E, 0xAF, 0x37, 0x16, 0x02, 0x21, 0x00, 0xF7, 0xCB, 0x1C, 0x94, 0x2D, 0x65, 0x7C, 0x41,
+    0xD4, 0x36, 0xC7, 0xA1, 0xB6, 0xE2, 0x9F, 0x65, 0xF3, 0xE9, 0x00, 0xDB, 0xB9, 0xAF, 0xF4, 0x06,
+    0x4D, 0xC4, 0xAB, 0x2F, 0x84, 0x3A, 0xCD, 0xA8,
+];
+
+/// Runs the known-answer tests of the cryptographic primitives of a provider.
+///
+/// Returns whether all primitives produced the expected outputs.
+pub fn crypto_known_answers<C: CryptoProvider>() -> bool {
+    if C::Sha256::hash(SHA256_MESSAGE) != SHA256_DIGEST {
+        return false;
+    }
+    if hmac_256::<C::Sha256>(HMAC_KEY, HMAC_MESSAGE) != HMAC_MAC {
+        return false;
+    }
+    let encryption_key = C::aes256_encryption_key(&AES_KEY);
+    let mut block = AES_PLAINTEXT;
+    encryption_key.encrypt_block(&mut block);
+    if block != AES_CIPHERTEXT {
+        return false;
+    }
+    C::aes256_decryption_key(&encryption_key).decrypt_block(&mut block);
+    if block != AES_PLAINTEXT {
+        return false;
+    }
+    let signing_key = match ecdsa::SecKey::from_bytes(&ECDSA_KEY) {
+        Some(key) => key,
+        None => return false,
+    };
+    C::ecdsa_sign(&signing_key, ECDSA_MESSAGE).to_asn1_der() == &ECDSA_SIGNATURE[..]
+}
+
+/// Checks that the random number generator does not output obviously broken values.
+///
+/// This only detects a stuck generator: constant outputs or repeated blocks.
+pub fn rng_health(rng: &mut impl Rng256) -> bool {
+    let first = rng.gen_uniform_u8x32();
+    let second = rng.gen_uniform_u8x32();
+    let is_constant = |block: &[u8; 32]| block.iter().all(|&x| x == block[0]);
+    first != second && !is_constant(&first) && !is_constant(&second)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crypto::provider::{HostCryptoProvider, SoftwareCryptoProvider};
+    use crypto::rng256::ThreadRng256;
+
+    #[test]
+    fn test_crypto_known_answers() {
+        assert!(crypto_known_answers::<SoftwareCryptoProvider>());
+        assert!(crypto_known_answers::<HostCryptoProvider>());
+    }
+
+    #[test]
+    fn test_rng_health() {
+        let mut rng = ThreadRng256 {};
+        assert!(rng_health(&mut rng));
+    }
+
+    struct StuckRng256;
+
+    impl Rng256 for StuckRng256 {
+        fn gen_uniform_u8x32(&mut self) -> [u8; 32] {
+            [0x55; 32]
+        }
+    }
+
+    #[test]
+    fn test_rng_health_stuck() {
+        assert!(!rng_health(&mut StuckRng256));
+    }
+}
@@ -14,12 +14,15 @@
 
 mod key;
 
-#[cfg(feature = "with_ctap2_1")]
-use crate::ctap::data_formats::{extract_array, extract_text_string};
-use crate::ctap::data_formats::{CredentialProtectionPolicy, PublicKeyCredentialSource};
+use crate::ctap::data_formats::{
+    extract_array, extract_byte_string, AuditEvent, AuditEventType, CredentialProtectionPolicy,
+    PublicKeyCredentialSource, UsbIdentity,
+};
 use crate::ctap::key_material;
 use crate::ctap::pin_protocol_v1::PIN_AUTH_LENGTH;
 use crate::ctap::status_code::Ctap2StatusCode;
+#[cfg(feature = "with_ctap2_1")]
+use crate::ctap::Sha256;
 use crate::ctap::INITIAL_SIGNATURE_COUNTER;
 use crate::embedded_flash::{new_storage, Storage};
 #[cfg(feature = "with_ctap2_1")]
@@ -27,42 +30,72 @@ use alloc::string::String;
 use alloc::vec;
 use alloc::vec::Vec;
 use arrayref::array_ref;
-#[cfg(feature = "with_ctap2_1")]
 use cbor::cbor_array_vec;
+use core::cmp::max;
 use core::convert::TryInto;
+#[cfg(feature = "debug_ctap")]
+use core::fmt::Write;
+use core::ops::Range;
+use crypto::constant_time::Secret;
 use crypto::rng256::Rng256;
+#[cfg(feature = "with_ctap2_1")]
+use crypto::Hash256;
+#[cfg(feature = "debug_ctap")]
+use libtock_drivers::console::Console;
+use persistent_store::{CriticalKeys, StoreError, StoreUpdate};
 
 // Those constants may be modified before compilation to tune the behavior of the key.
 //
-// The number of pages should be at least 3 and at most what the flash can hold. There should be no
-// reason to put a small number here, except that the latency of flash operations is linear in the
-// number of pages. This may improve in the future. Currently, using 20 pages gives between 20ms and
-// 240ms per operation. The rule of thumb is between 1ms and 12ms per additional page.
+// The store fills the storage locations provided by the kernel, up to a maximum number of pages.
+// Boards with less flash thus get a smaller store instead of failing to boot. The maximum should be
+// at least 3 and at most 64. There should be no reason to put a small number here, except that the
+// latency of flash operations is linear in the number of pages. This may improve in the future.
+// Currently, using 20 pages gives between 20ms and 240ms per operation. The rule of thumb is
+// between 1ms and 12ms per additional page. Changing the number of pages of a deployed device is a
+// geometry mismatch, so raising the maximum only applies to new devices.
 //
-// Limiting the number of residential keys permits to ensure a minimum number of counter increments.
-// Let:
-// - P the number of pages (NUM_PAGES)
-// - K the maximum number of residential keys (MAX_SUPPORTED_RESIDENTIAL_KEYS)
-// - S the maximum size of a residential key (about 500)
-// - C the number of erase cycles (10000)
+// The maximum number of residential keys is computed from the store capacity, such that boards
+// with more pages support more residential keys. Part of the capacity is reserved to ensure a
+// minimum number of counter increments. Let:
+// - C the total capacity of the store in words (P * 1020 - 1277 for P pages of 4kB)
+// - R the reserved capacity in words (RESERVED_CAPACITY)
+// - S the maximum size of a residential key in words (RESIDENTIAL_KEY_CAPACITY)
+// - E the number of erase cycles (10000)
+// - K the maximum number of residential keys
 // - I the minimum number of counter increments
 //
-// We have: I = (P * 4084 - 5107 - K * S) / 8 * C
+// We have: K = (C - R) / S and I = R / 2 * E
 //
-// With P=20 and K=150, we have I=2M which is enough for 500 increments per day for 10 years.
-const NUM_PAGES: usize = 20;
-const MAX_SUPPORTED_RESIDENTIAL_KEYS: usize = 150;
+// With P=20, we have K=150 and I=1.8M which is enough for 500 increments per day for 10 years.
+// The number of residential keys is also bounded by their key range, which is reached at P=39.
+const MAX_NUM_PAGES: usize = 20;
+const RESERVED_CAPACITY: usize = 360;
+const RESIDENTIAL_KEY_CAPACITY: usize = 125;
+
+// Development boards may be flashed with a different number of pages than the storage was written
+// with. The storage is then erased instead of failing to mount. This loses all credentials, so it
+// is only done for debug builds.
+const REFORMAT_ON_GEOMETRY_MISMATCH: bool = cfg!(feature = "debug_ctap");
 
 const MAX_PIN_RETRIES: u8 = 8;
 #[cfg(feature = "with_ctap2_1")]
 const DEFAULT_MIN_PIN_LENGTH: u8 = 4;
-// TODO(kaczmarczyck) use this for the minPinLength extension
-// https://github.com/google/OpenSK/issues/129
+// RP IDs that are always allowed to read the minimum PIN length with the minPinLength extension, in
+// addition to the ones set by the platform. They are not stored, so they survive a reset.
+#[cfg(feature = "with_ctap2_1")]
+const DEFAULT_MIN_PIN_LENGTH_RP_IDS: &[&str] = &[];
+// Maximum number of RP IDs the platform can allow to read the minimum PIN length. Their hashes are
+// stored in a single entry of RP_ID_HASH_LENGTH bytes each. It is reported in GetInfo.
 #[cfg(feature = "with_ctap2_1")]
-const _DEFAULT_MIN_PIN_LENGTH_RP_IDS: Vec<String> = Vec::new();
-// TODO(kaczmarczyck) Check whether this constant is necessary, or replace it accordingly.
+pub const MAX_RP_IDS_LENGTH: usize = 8;
 #[cfg(feature = "with_ctap2_1")]
-const _MAX_RP_IDS_LENGTH: usize = 8;
+const RP_ID_HASH_LENGTH: usize = 32;
+// Optional limit of residential keys per RP ID, such that a single RP can't fill the store. When
+// an RP ID reaches its quota, a new credential replaces its oldest one if eviction is enabled, and
+// fails with a full key store otherwise. For example, a quota of 8 with eviction keeps the 8 most
+// recent credentials of each RP ID.
+const MAX_CREDENTIALS_PER_RP_ID: Option<usize> = None;
+const EVICT_OLDEST_CREDENTIAL_OF_RP_ID: bool = true;
 
 /// Wrapper for master keys.
 pub struct MasterKeys {
@@ -73,9 +106,29 @@ pub struct MasterKeys {
     pub hmac: [u8; 32],
 }
 
+/// Aggregate usage counters since manufacture.
+#[derive(Clone, Copy, Default, PartialEq)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub struct Metrics {
+    /// Number of successful credential registrations.
+    pub registrations: u32,
+
+    /// Number of successful assertions.
+    pub assertions: u32,
+}
+
 /// CTAP persistent storage.
 pub struct PersistentStore {
     store: persistent_store::Store<Storage>,
+
+    /// The AAGUID, cached to avoid a flash read for each use.
+    aaguid: [u8; key_material::AAGUID_LENGTH],
+
+    /// The number of remaining PIN retries, mirrored to only write it to flash when it changes.
+    pin_retries: u8,
+
+    /// The sequence number of the next audit event, mirrored to not read the log for each event.
+    audit_sequence: u64,
 }
 
 impl PersistentStore {
@@ -85,24 +138,60 @@ impl PersistentStore {
     ///
     /// This should be at most one instance of persistent store per program lifetime.
     pub fn new(rng: &mut impl Rng256) -> PersistentStore {
-        let storage = new_storage(NUM_PAGES);
+        PersistentStore::open(new_storage(MAX_NUM_PAGES), rng)
+    }
+
+    /// Simulates a power cycle by opening the store again from its storage.
+    #[cfg(feature = "std")]
+    pub fn reboot(self, rng: &mut impl Rng256) -> PersistentStore {
+        PersistentStore::open(self.store.extract_storage(), rng)
+    }
+
+    /// Opens the store on the given storage.
+    fn open(storage: Storage, rng: &mut impl Rng256) -> PersistentStore {
         let mut store = PersistentStore {
-            store: persistent_store::Store::new(storage).ok().unwrap(),
+            store: if REFORMAT_ON_GEOMETRY_MISMATCH {
+                persistent_store::Store::new_or_reformat(storage)
+            } else {
+                persistent_store::Store::new(storage)
+            }
+            .ok()
+            .unwrap(),
+            aaguid: *key_material::AAGUID,
+            pin_retries: MAX_PIN_RETRIES,
+            audit_sequence: 0,
         };
+        store.store.set_write_once(Some(key::is_write_once));
+        #[cfg(feature = "debug_ctap")]
+        store.store.set_observer(Some(log_store_mutation));
         store.init(rng).unwrap();
         store
     }
 
     /// Initializes the store by creating missing objects.
     fn init(&mut self, rng: &mut impl Rng256) -> Result<(), Ctap2StatusCode> {
+        // Migrate the legacy entries of the critical keys. The legacy entry is only removed once
+        // the critical entry is written, such that an interrupted migration is resumed.
+        self.migrate_critical(key::_ATTESTATION_PRIVATE_KEY, key::ATTESTATION_PRIVATE_KEY)?;
+        self.migrate_critical(key::_MASTER_KEYS, key::MASTER_KEYS)?;
+
+        // Move the credentials to the keys used by the current firmware, in case the credential
+        // range or the maximum number of residential keys changed.
+        self.migrate_credentials()?;
+
         // Generate and store the master keys if they are missing.
-        if self.store.find_handle(key::MASTER_KEYS)?.is_none() {
+        if self
+            .store
+            .find_critical(&critical_keys(key::MASTER_KEYS))?
+            .is_none()
+        {
             let master_encryption_key = rng.gen_uniform_u8x32();
             let master_hmac_key = rng.gen_uniform_u8x32();
             let mut master_keys = Vec::with_capacity(64);
             master_keys.extend_from_slice(&master_encryption_key);
             master_keys.extend_from_slice(&master_hmac_key);
-            self.store.insert(key::MASTER_KEYS, &master_keys)?;
+            self.store
+                .insert_critical(&critical_keys(key::MASTER_KEYS), &master_keys)?;
         }
 
         // Generate and store the CredRandom secrets if they are missing.
@@ -115,8 +204,94 @@ impl PersistentStore {
             self.store.insert(key::CRED_RANDOM_SECRET, &cred_random)?;
         }
 
-        if self.store.find_handle(key::AAGUID)?.is_none() {
-            self.set_aaguid(key_material::AAGUID)?;
+        // Load the AAGUID override if it was provisioned. A malformed entry can't be written by
+        // `set_aaguid` and is ignored.
+        if let Some(aaguid) = self.store.find(key::AAGUID)? {
+            if aaguid.len() == key_material::AAGUID_LENGTH {
+                self.aaguid = *array_ref![aaguid, 0, key_material::AAGUID_LENGTH];
+            }
+        }
+
+        // Mirror the PIN retries. A malformed entry can't be written by `decr_pin_retries` and
+        // blocks the PIN, instead of granting more attempts.
+        self.pin_retries = match self.store.find(key::PIN_RETRIES)? {
+            None => MAX_PIN_RETRIES,
+            Some(value) if value.len() == 1 => value[0],
+            _ => 0,
+        };
+
+        // Mirror the sequence number of the next audit event. Malformed events can't be written
+        // by `append_audit_event` and are ignored.
+        self.audit_sequence = 0;
+        for handle in self.store.iter_range(key::AUDIT_LOG)? {
+            let value = handle?.get_value(&self.store)?;
+            if let Some(event) = deserialize_audit_event(&value) {
+                self.audit_sequence = max(self.audit_sequence, event.sequence.wrapping_add(1));
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves a legacy entry to a critical entry, unless the critical entry is already present.
+    fn migrate_critical(
+        &mut self,
+        legacy_key: usize,
+        keys: Range<usize>,
+    ) -> Result<(), Ctap2StatusCode> {
+        let value = match self.store.find(legacy_key)? {
+            None => return Ok(()),
+            Some(value) => value,
+        };
+        let keys = critical_keys(keys);
+        if self.store.find_critical(&keys)?.is_none() {
+            self.store.insert_critical(&keys, &value)?;
+        }
+        Ok(self.store.remove(legacy_key)?)
+    }
+
+    /// Moves the credentials to the first keys of the credential range.
+    ///
+    /// Only the first `max_supported_residential_keys` keys of `CREDENTIALS` are allocated. This
+    /// prefix shrinks or grows when the store capacity or the constants change between firmware
+    /// versions, and the range itself may grow into `_RESERVED_CREDENTIALS`. Credentials found
+    /// outside the credential range are moved first, possibly after the prefix, such that they are
+    /// visible again. Credentials after the prefix are then moved to free keys of the prefix.
+    /// Credentials that don't fit are left in place: they are not lost and count as used keys.
+    ///
+    /// Each credential is moved with a single transaction, so an interrupted migration is resumed
+    /// at the next boot.
+    fn migrate_credentials(&mut self) -> Result<(), Ctap2StatusCode> {
+        let min_key = key::CREDENTIALS.start;
+        let end_prefix = min_key + self.max_supported_residential_keys()?;
+        // Holds whether a key is used (indices are shifted by min_key).
+        let mut keys = vec![false; key::CREDENTIALS.end - min_key];
+        let mut outside = Vec::new();
+        let mut after_prefix = Vec::new();
+        for handle in self
+            .store
+            .iter_range(key::_RESERVED_CREDENTIALS.start..key::CREDENTIALS.end)?
+        {
+            let key = handle?.get_key();
+            if key < min_key {
+                outside.push(key);
+            } else {
+                keys[key - min_key] = true;
+                if key >= end_prefix {
+                    after_prefix.push(key);
+                }
+            }
+        }
+        let mut free_keys = key::CREDENTIALS.filter(|key| !keys[key - min_key]);
+        for old_key in outside.into_iter().chain(after_prefix) {
+            let new_key = match free_keys.next() {
+                Some(key) if old_key < min_key || key < end_prefix => key,
+                _ => break,
+            };
+            match self.store.rekey(old_key, new_key) {
+                // The migration continues when capacity is available, typically at next boot.
+                Err(StoreError::NoCapacity) => break,
+                result => result?,
+            }
         }
         Ok(())
     }
@@ -151,29 +326,52 @@ impl PersistentStore {
 
     /// Stores or updates a credential.
     ///
-    /// If a credential with the same RP id and user handle already exists, it is replaced.
+    /// If a credential with the same RP id and user handle already exists, it is replaced. If the
+    /// RP id reached its quota, the oldest credential of the RP id may be replaced instead.
     pub fn store_credential(
         &mut self,
         new_credential: PublicKeyCredentialSource,
+    ) -> Result<(), Ctap2StatusCode> {
+        self.store_credential_with_quota(
+            new_credential,
+            MAX_CREDENTIALS_PER_RP_ID,
+            EVICT_OLDEST_CREDENTIAL_OF_RP_ID,
+        )
+    }
+
+    /// Stores or updates a credential, enforcing an optional quota per RP id.
+    fn store_credential_with_quota(
+        &mut self,
+        new_credential: PublicKeyCredentialSource,
+        max_credentials: Option<usize>,
+        evict_oldest: bool,
     ) -> Result<(), Ctap2StatusCode> {
         // Holds the key of the existing credential if this is an update.
         let mut old_key = None;
+        // Holds the number of credentials of the RP id, and the creation order and key of the
+        // oldest one.
+        let mut rp_credentials = 0;
+        let mut oldest: Option<(u64, usize)> = None;
         let min_key = key::CREDENTIALS.start;
-        // Holds whether a key is used (indices are shifted by min_key).
-        let mut keys = vec![false; MAX_SUPPORTED_RESIDENTIAL_KEYS];
+        let max_keys = self.max_supported_residential_keys()?;
+        // Holds whether a key is used (indices are shifted by min_key). Keys after the first
+        // max_keys are only used by credentials that didn't fit during migration.
+        let mut keys = vec![false; key::CREDENTIALS.end - min_key];
         let mut iter_result = Ok(());
         let iter = self.iter_credentials(&mut iter_result)?;
         for (key, credential) in iter {
-            if key < min_key
-                || key - min_key >= MAX_SUPPORTED_RESIDENTIAL_KEYS
-                || keys[key - min_key]
-            {
+            if key < min_key || key >= key::CREDENTIALS.end || keys[key - min_key] {
                 return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
             }
             keys[key - min_key] = true;
-            if credential.rp_id == new_credential.rp_id
-                && credential.user_handle == new_credential.user_handle
-            {
+            if credential.rp_id != new_credential.rp_id {
+                continue;
+            }
+            rp_credentials += 1;
+            if oldest.map_or(true, |(order, _)| credential.creation_order < order) {
+                oldest = Some((credential.creation_order, key));
+            }
+            if credential.user_handle == new_credential.user_handle {
                 if old_key.is_some() {
                     return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
                 }
@@ -181,24 +379,126 @@ impl PersistentStore {
             }
         }
         iter_result?;
-        if old_key.is_none()
-            && keys.iter().filter(|&&x| x).count() >= MAX_SUPPORTED_RESIDENTIAL_KEYS
-        {
+        if let Some(max_credentials) = max_credentials {
+            if old_key.is_none() && rp_credentials >= max_credentials {
+                match oldest {
+                    // The oldest credential of the RP id is overwritten by the new one.
+                    Some((_, key)) if evict_oldest => old_key = Some(key),
+                    _ => return Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL),
+                }
+            }
+        }
+        if old_key.is_none() && keys.iter().filter(|&&x| x).count() >= max_keys {
             return Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL);
         }
         let key = match old_key {
             // This is a new credential being added, we need to allocate a free key. We choose the
             // first available key.
             None => key::CREDENTIALS
-                .take(MAX_SUPPORTED_RESIDENTIAL_KEYS)
+                .take(max_keys)
                 .find(|key| !keys[key - min_key])
                 .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?,
-            // This is an existing credential being updated, we reuse its key.
+            // This is an existing credential being updated or evicted, we reuse its key.
             Some(x) => x,
         };
         let value = serialize_credential(new_credential)?;
-        self.store.insert(key, &value)?;
-        Ok(())
+        // Low-value entries are evicted by increasing value until the credential fits.
+        let mut priority = 0;
+        loop {
+            match self.store.insert(key, &value) {
+                Err(StoreError::NoCapacity) => (),
+                result => return Ok(result?),
+            }
+            if !self.evict_low_value_entries(priority)? {
+                return Err(self.store_full_error()?);
+            }
+            priority += 1;
+        }
+    }
+
+    /// Evicts the entries of the given priority.
+    ///
+    /// Entries are evicted by increasing priority when the store is out of capacity. Returns
+    /// whether the priority exists. Entries are removed one by one, because a transaction needs
+    /// capacity.
+    fn evict_low_value_entries(&mut self, priority: usize) -> Result<bool, Ctap2StatusCode> {
+        match priority {
+            // The scratch entry of the self test is only present if the self test was interrupted.
+            0 => self.store.remove(key::SELF_TEST)?,
+            // The audit log keeps its last event, such that sequence numbers keep increasing.
+            1 => {
+                let events = self.audit_log()?;
+                let num_slots = (key::AUDIT_LOG.end - key::AUDIT_LOG.start) as u64;
+                for event in &events[..events.len().saturating_sub(1)] {
+                    let key = key::AUDIT_LOG.start + (event.sequence % num_slots) as usize;
+                    self.store.remove(key)?;
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Returns the error of a store out of capacity for a credential.
+    ///
+    /// The store is full of credentials if the other entries fit in their reserved capacity.
+    /// Otherwise it is full of metadata.
+    fn store_full_error(&self) -> Result<Ctap2StatusCode, Ctap2StatusCode> {
+        let (_, credentials_used) = self.credentials_capacity()?;
+        let others_used = self
+            .store
+            .capacity()?
+            .used()
+            .saturating_sub(credentials_used);
+        if others_used > RESERVED_CAPACITY {
+            Ok(Ctap2StatusCode::CTAP2_ERR_VENDOR_METADATA_FULL)
+        } else {
+            Ok(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL)
+        }
+    }
+
+    /// Returns the number of credentials and the capacity in words they use.
+    fn credentials_capacity(&self) -> Result<(usize, usize), Ctap2StatusCode> {
+        let mut count = 0;
+        let mut used = 0;
+        for handle in self.store.iter_range(key::CREDENTIALS)? {
+            used += entry_capacity(handle?.get_length());
+            count += 1;
+        }
+        Ok((count, used))
+    }
+
+    /// Returns the maximum number of residential keys.
+    ///
+    /// This is the number of residential keys of maximum size that fit in the store capacity,
+    /// after reserving capacity for the other entries.
+    pub fn max_supported_residential_keys(&self) -> Result<usize, Ctap2StatusCode> {
+        let capacity = self.store.capacity()?.total();
+        let max_keys = capacity.saturating_sub(RESERVED_CAPACITY) / RESIDENTIAL_KEY_CAPACITY;
+        Ok(core::cmp::min(
+            max_keys,
+            key::CREDENTIALS.end - key::CREDENTIALS.start,
+        ))
+    }
+
+    /// Returns the number of residential keys of maximum size that can still be stored.
+    ///
+    /// This is computed from the live store capacity: the capacity not used by credentials, minus
+    /// the capacity used by the other entries or reserved for them, divided by the maximum size of a
+    /// residential key. Other entries, like large blobs, may use more than the reserved capacity. The
+    /// result is also bounded by the number of free credential keys.
+    pub fn remaining_credentials(&self) -> Result<usize, Ctap2StatusCode> {
+        let capacity = self.store.capacity()?;
+        let (count, credentials_used) = self.credentials_capacity()?;
+        let others_used = capacity.used().saturating_sub(credentials_used);
+        let available = capacity
+            .total()
+            .saturating_sub(credentials_used + core::cmp::max(others_used, RESERVED_CAPACITY));
+        let free_keys = self.max_supported_residential_keys()?.saturating_sub(count);
+        Ok(core::cmp::min(
+            available / RESIDENTIAL_KEY_CAPACITY,
+            free_keys,
+        ))
     }
 
     /// Returns the list of matching credentials.
@@ -226,7 +526,6 @@ impl PersistentStore {
     }
 
     /// Returns the number of credentials.
-    #[cfg(test)]
     pub fn count_credentials(&self) -> Result<usize, Ctap2StatusCode> {
         let mut iter_result = Ok(());
         let iter = self.iter_credentials(&mut iter_result)?;
@@ -235,6 +534,22 @@ impl PersistentStore {
         Ok(result)
     }
 
+    /// Sets or removes the display name of the credential with the given ID.
+    pub fn set_authenticator_display_name(
+        &mut self,
+        credential_id: &[u8],
+        display_name: Option<String>,
+    ) -> Result<(), Ctap2StatusCode> {
+        let mut iter_result = Ok(());
+        let mut iter = self.iter_credentials(&mut iter_result)?;
+        let found = iter.find(|(_, credential)| credential.credential_id == credential_id);
+        iter_result?;
+        let (key, mut credential) = found.ok_or(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)?;
+        credential.authenticator_display_name = display_name;
+        let value = serialize_credential(credential)?;
+        Ok(self.store.insert(key, &value)?)
+    }
+
     /// Iterates through the credentials.
     ///
     /// If an error is encountered during iteration, it is written to `result`.
@@ -273,11 +588,65 @@ impl PersistentStore {
         Ok(())
     }
 
+    /// Returns the usage metrics.
+    pub fn metrics(&self) -> Result<Metrics, Ctap2StatusCode> {
+        match self.store.find(key::METRICS)? {
+            None => Ok(Metrics::default()),
+            Some(value) if value.len() == 8 => Ok(Metrics {
+                registrations: u32::from_ne_bytes(*array_ref!(&value, 0, 4)),
+                assertions: u32::from_ne_bytes(*array_ref!(&value, 4, 4)),
+            }),
+            Some(_) => Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR),
+        }
+    }
+
+    /// Increments the usage metrics.
+    ///
+    /// The counters saturate instead of wrapping.
+    pub fn incr_metrics(
+        &mut self,
+        registrations: u32,
+        assertions: u32,
+    ) -> Result<(), Ctap2StatusCode> {
+        let old_value = self.metrics()?;
+        let mut new_value = [0u8; 8];
+        new_value[..4].copy_from_slice(
+            &old_value
+                .registrations
+                .saturating_add(registrations)
+                .to_ne_bytes(),
+        );
+        new_value[4..].copy_from_slice(
+            &old_value
+                .assertions
+                .saturating_add(assertions)
+                .to_ne_bytes(),
+        );
+        self.store.insert(key::METRICS, &new_value)?;
+        Ok(())
+    }
+
+    /// Checks that an entry can be written, read back, and removed.
+    ///
+    /// A random value is written to a scratch entry, which is removed before returning.
+    pub fn self_test(&mut self, rng: &mut impl Rng256) -> Result<bool, Ctap2StatusCode> {
+        let value = rng.gen_uniform_u8x32();
+        self.store.insert(key::SELF_TEST, &value)?;
+        let read_value = self.store.find(key::SELF_TEST)?;
+        self.store.remove(key::SELF_TEST)?;
+        Ok(read_value.as_deref() == Some(&value[..]) && self.store.find(key::SELF_TEST)?.is_none())
+    }
+
+    /// Scans the store for inconsistencies.
+    pub fn check(&self) -> Result<persistent_store::StoreCheck, Ctap2StatusCode> {
+        Ok(self.store.check()?)
+    }
+
     /// Returns the master keys.
     pub fn master_keys(&self) -> Result<MasterKeys, Ctap2StatusCode> {
         let master_keys = self
             .store
-            .find(key::MASTER_KEYS)?
+            .find_critical(&critical_keys(key::MASTER_KEYS))?
             .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
         if master_keys.len() != 64 {
             return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
@@ -302,7 +671,9 @@ impl PersistentStore {
     }
 
     /// Returns the PIN hash if defined.
-    pub fn pin_hash(&self) -> Result<Option<[u8; PIN_AUTH_LENGTH]>, Ctap2StatusCode> {
+    ///
+    /// The PIN hash is wrapped to only be compared in constant time.
+    pub fn pin_hash(&self) -> Result<Option<Secret<[u8; PIN_AUTH_LENGTH]>>, Ctap2StatusCode> {
         let pin_hash = match self.store.find(key::PIN_HASH)? {
             None => return Ok(None),
             Some(pin_hash) => pin_hash,
@@ -310,7 +681,7 @@ impl PersistentStore {
         if pin_hash.len() != PIN_AUTH_LENGTH {
             return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
         }
-        Ok(Some(*array_ref![pin_hash, 0, PIN_AUTH_LENGTH]))
+        Ok(Some(Secret::new(*array_ref![pin_hash, 0, PIN_AUTH_LENGTH])))
     }
 
     /// Sets the PIN hash.
@@ -325,26 +696,32 @@ impl PersistentStore {
 
     /// Returns the number of remaining PIN retries.
     pub fn pin_retries(&self) -> Result<u8, Ctap2StatusCode> {
-        match self.store.find(key::PIN_RETRIES)? {
-            None => Ok(MAX_PIN_RETRIES),
-            Some(value) if value.len() == 1 => Ok(value[0]),
-            _ => Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR),
-        }
+        Ok(self.pin_retries)
     }
 
     /// Decrements the number of remaining PIN retries.
+    ///
+    /// Nothing is written once the PIN retries reach zero. The mirror is only updated after the
+    /// write succeeded, so that it never grants more attempts than the flash.
     pub fn decr_pin_retries(&mut self) -> Result<(), Ctap2StatusCode> {
-        let old_value = self.pin_retries()?;
-        let new_value = old_value.saturating_sub(1);
-        if new_value != old_value {
+        let new_value = self.pin_retries.saturating_sub(1);
+        if new_value != self.pin_retries {
             self.store.insert(key::PIN_RETRIES, &[new_value])?;
+            self.pin_retries = new_value;
         }
         Ok(())
     }
 
     /// Resets the number of remaining PIN retries.
+    ///
+    /// Nothing is written if the PIN retries are already at the maximum, which is the case for
+    /// most successful PIN checks.
     pub fn reset_pin_retries(&mut self) -> Result<(), Ctap2StatusCode> {
-        Ok(self.store.remove(key::PIN_RETRIES)?)
+        if self.pin_retries != MAX_PIN_RETRIES {
+            self.store.remove(key::PIN_RETRIES)?;
+            self.pin_retries = MAX_PIN_RETRIES;
+        }
+        Ok(())
     }
 
     /// Returns the minimum PIN length.
@@ -360,49 +737,118 @@ impl PersistentStore {
     /// Sets the minimum PIN length.
     #[cfg(feature = "with_ctap2_1")]
     pub fn set_min_pin_length(&mut self, min_pin_length: u8) -> Result<(), Ctap2StatusCode> {
-        Ok(self.store.insert(key::MIN_PIN_LENGTH, &[min_pin_length])?)
+        Ok(self.store.replace(key::MIN_PIN_LENGTH, &[min_pin_length])?)
     }
 
-    /// Returns the list of RP IDs that are used to check if reading the minimum PIN length is
-    /// allowed.
+    /// Returns whether the alwaysUv option is enabled.
     #[cfg(feature = "with_ctap2_1")]
-    pub fn _min_pin_length_rp_ids(&self) -> Result<Vec<String>, Ctap2StatusCode> {
-        let rp_ids = self
-            .store
-            .find(key::_MIN_PIN_LENGTH_RP_IDS)?
-            .map_or(Some(_DEFAULT_MIN_PIN_LENGTH_RP_IDS), |value| {
-                _deserialize_min_pin_length_rp_ids(&value)
-            });
-        debug_assert!(rp_ids.is_some());
-        Ok(rp_ids.unwrap_or(vec![]))
+    pub fn always_uv(&self) -> Result<bool, Ctap2StatusCode> {
+        Ok(self.store.contains(key::ALWAYS_UV)?)
+    }
+
+    /// Enables the alwaysUv option if disabled, and disables it otherwise.
+    #[cfg(feature = "with_ctap2_1")]
+    pub fn toggle_always_uv(&mut self) -> Result<(), Ctap2StatusCode> {
+        if self.always_uv()? {
+            Ok(self.store.remove(key::ALWAYS_UV)?)
+        } else {
+            Ok(self.store.insert(key::ALWAYS_UV, &[])?)
+        }
+    }
+
+    /// Returns the serialized large blob array.
+    ///
+    /// Until an array is written, this is the initial array: an empty CBOR array followed by the
+    /// first 16 bytes of its SHA-256.
+    #[cfg(feature = "with_ctap2_1")]
+    pub fn large_blob_array(&self) -> Result<Vec<u8>, Ctap2StatusCode> {
+        let mut large_blob_array = Vec::new();
+        for key in key::LARGE_BLOB_SHARDS {
+            match self.store.find(key)? {
+                None => break,
+                Some(shard) => large_blob_array.extend(shard),
+            }
+        }
+        if large_blob_array.is_empty() {
+            large_blob_array = vec![
+                0x80, 0x76, 0xBE, 0x8B, 0x52, 0x8D, 0x00, 0x75, 0xF7, 0xAA, 0xE9, 0x8D, 0x6F, 0xA5,
+                0x7A, 0x6D, 0x3C,
+            ];
+        }
+        Ok(large_blob_array)
     }
 
-    /// Sets the list of RP IDs that are used to check if reading the minimum PIN length is allowed.
+    /// Replaces the serialized large blob array.
+    ///
+    /// The shards are written in a single transaction, so that a power loss never leaves a mix of
+    /// the old and new arrays.
     #[cfg(feature = "with_ctap2_1")]
-    pub fn _set_min_pin_length_rp_ids(
+    pub fn commit_large_blob_array(
         &mut self,
-        min_pin_length_rp_ids: Vec<String>,
+        large_blob_array: &[u8],
     ) -> Result<(), Ctap2StatusCode> {
-        let mut min_pin_length_rp_ids = min_pin_length_rp_ids;
-        for rp_id in _DEFAULT_MIN_PIN_LENGTH_RP_IDS {
-            if !min_pin_length_rp_ids.contains(&rp_id) {
-                min_pin_length_rp_ids.push(rp_id);
+        let mut shards = large_blob_array.chunks(self.store.max_value_length());
+        let mut updates = Vec::new();
+        for key in key::LARGE_BLOB_SHARDS {
+            match shards.next() {
+                Some(shard) => updates.push(StoreUpdate::Insert {
+                    key,
+                    value: shard.to_vec(),
+                }),
+                None => updates.push(StoreUpdate::Remove { key }),
             }
         }
-        if min_pin_length_rp_ids.len() > _MAX_RP_IDS_LENGTH {
+        if shards.next().is_some() {
+            return Err(Ctap2StatusCode::CTAP2_ERR_LARGE_BLOB_STORAGE_FULL);
+        }
+        Ok(self.store.transaction(&updates)?)
+    }
+
+    /// Returns whether the RP is allowed to read the minimum PIN length.
+    ///
+    /// The RP is given by the hash of its ID. The default RP IDs are always allowed.
+    #[cfg(feature = "with_ctap2_1")]
+    pub fn is_min_pin_length_rp_id(&self, rp_id_hash: &[u8]) -> Result<bool, Ctap2StatusCode> {
+        if DEFAULT_MIN_PIN_LENGTH_RP_IDS
+            .iter()
+            .any(|rp_id| Sha256::hash(rp_id.as_bytes())[..] == *rp_id_hash)
+        {
+            return Ok(true);
+        }
+        let rp_id_hashes = self
+            .store
+            .find(key::MIN_PIN_LENGTH_RP_IDS)?
+            .unwrap_or_default();
+        Ok(rp_id_hashes
+            .chunks(RP_ID_HASH_LENGTH)
+            .any(|hash| hash == rp_id_hash))
+    }
+
+    /// Sets the RP IDs that are allowed to read the minimum PIN length.
+    ///
+    /// The list replaces the previous one. Only the hashes of the RP IDs are stored.
+    #[cfg(feature = "with_ctap2_1")]
+    pub fn set_min_pin_length_rp_ids(&mut self, rp_ids: &[String]) -> Result<(), Ctap2StatusCode> {
+        if rp_ids.len() > MAX_RP_IDS_LENGTH {
             return Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL);
         }
-        Ok(self.store.insert(
-            key::_MIN_PIN_LENGTH_RP_IDS,
-            &_serialize_min_pin_length_rp_ids(min_pin_length_rp_ids)?,
-        )?)
+        let mut rp_id_hashes = Vec::with_capacity(rp_ids.len() * RP_ID_HASH_LENGTH);
+        for rp_id in rp_ids {
+            rp_id_hashes.extend_from_slice(&Sha256::hash(rp_id.as_bytes()));
+        }
+        Ok(self
+            .store
+            .replace(key::MIN_PIN_LENGTH_RP_IDS, &rp_id_hashes)?)
     }
 
     /// Returns the attestation private key if defined.
     pub fn attestation_private_key(
         &self,
     ) -> Result<Option<[u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH]>, Ctap2StatusCode> {
-        match self.store.find(key::ATTESTATION_PRIVATE_KEY)? {
+        match self
+            .store
+            .find_critical(&critical_keys(key::ATTESTATION_PRIVATE_KEY))?
+        {
             None => Ok(None),
             Some(key) if key.len() == key_material::ATTESTATION_PRIVATE_KEY_LENGTH => {
                 Ok(Some(*array_ref![
@@ -422,10 +868,9 @@ impl PersistentStore {
         &mut self,
         attestation_private_key: &[u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH],
     ) -> Result<(), Ctap2StatusCode> {
-        match self.store.find(key::ATTESTATION_PRIVATE_KEY)? {
-            None => Ok(self
-                .store
-                .insert(key::ATTESTATION_PRIVATE_KEY, attestation_private_key)?),
+        let keys = critical_keys(key::ATTESTATION_PRIVATE_KEY);
+        match self.store.find_critical(&keys)? {
+            None => Ok(self.store.insert_critical(&keys, attestation_private_key)?),
             Some(_) => Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR),
         }
     }
@@ -435,41 +880,211 @@ impl PersistentStore {
         Ok(self.store.find(key::ATTESTATION_CERTIFICATE)?)
     }
 
-    /// Sets the attestation certificate.
+    /// Returns the attestation certificate chain if defined.
     ///
-    /// If it is already defined, it is overwritten.
-    pub fn set_attestation_certificate(
+    /// The chain starts with the attestation certificate, followed by the intermediate
+    /// certificates in the order of the chain.
+    pub fn attestation_certificate_chain(&self) -> Result<Option<Vec<Vec<u8>>>, Ctap2StatusCode> {
+        let attestation_certificate = match self.attestation_certificate()? {
+            None => return Ok(None),
+            Some(attestation_certificate) => attestation_certificate,
+        };
+        let mut intermediate_certificates = Vec::new();
+        for key in key::ATTESTATION_INTERMEDIATE_CERTIFICATES {
+            match self.store.find(key)? {
+                None => break,
+                Some(shard) => intermediate_certificates.extend(shard),
+            }
+        }
+        let mut chain = vec![attestation_certificate];
+        if !intermediate_certificates.is_empty() {
+            chain.extend(
+                deserialize_certificates(&intermediate_certificates)
+                    .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?,
+            );
+        }
+        Ok(Some(chain))
+    }
+
+    /// Sets the attestation certificate chain.
+    ///
+    /// The chain starts with the attestation certificate, followed by the intermediate
+    /// certificates. All certificates are written in a single transaction. It is an error if the
+    /// attestation certificate is already defined.
+    pub fn set_attestation_certificate_chain(
         &mut self,
-        attestation_certificate: &[u8],
+        chain: &[Vec<u8>],
     ) -> Result<(), Ctap2StatusCode> {
-        match self.store.find(key::ATTESTATION_CERTIFICATE)? {
-            None => Ok(self
-                .store
-                .insert(key::ATTESTATION_CERTIFICATE, attestation_certificate)?),
-            Some(_) => Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR),
+        let (attestation_certificate, intermediate_certificates) = chain
+            .split_first()
+            .ok_or(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)?;
+        if self.store.find(key::ATTESTATION_CERTIFICATE)?.is_some() {
+            return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
+        }
+        let mut updates = vec![StoreUpdate::Insert {
+            key: key::ATTESTATION_CERTIFICATE,
+            value: attestation_certificate.clone(),
+        }];
+        let intermediate_certificates = if intermediate_certificates.is_empty() {
+            Vec::new()
+        } else {
+            serialize_certificates(intermediate_certificates)?
+        };
+        let mut shards = intermediate_certificates.chunks(self.store.max_value_length());
+        for key in key::ATTESTATION_INTERMEDIATE_CERTIFICATES {
+            match shards.next() {
+                Some(shard) => updates.push(StoreUpdate::Insert {
+                    key,
+                    value: shard.to_vec(),
+                }),
+                None => break,
+            }
+        }
+        if shards.next().is_some() {
+            return Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL);
         }
+        Ok(self.store.transaction(&updates)?)
     }
 
     /// Returns the AAGUID.
-    pub fn aaguid(&self) -> Result<[u8; key_material::AAGUID_LENGTH], Ctap2StatusCode> {
-        let aaguid = self
-            .store
-            .find(key::AAGUID)?
-            .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
-        if aaguid.len() != key_material::AAGUID_LENGTH {
-            return Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR);
-        }
-        Ok(*array_ref![aaguid, 0, key_material::AAGUID_LENGTH])
+    ///
+    /// This is the compile-time AAGUID, unless it was overridden with `set_aaguid`.
+    pub fn aaguid(&self) -> [u8; key_material::AAGUID_LENGTH] {
+        self.aaguid
     }
 
-    /// Sets the AAGUID.
+    /// Overrides the AAGUID.
     ///
-    /// If it is already defined, it is overwritten.
+    /// This is meant for provisioning. It is an error if an override is already defined.
     pub fn set_aaguid(
         &mut self,
         aaguid: &[u8; key_material::AAGUID_LENGTH],
     ) -> Result<(), Ctap2StatusCode> {
-        Ok(self.store.insert(key::AAGUID, aaguid)?)
+        self.store.insert(key::AAGUID, aaguid)?;
+        self.aaguid = *aaguid;
+        Ok(())
+    }
+
+    /// Returns the USB identity override, if any.
+    pub fn usb_identity(&self) -> Result<Option<UsbIdentity>, Ctap2StatusCode> {
+        match self.store.find(key::USB_IDENTITY)? {
+            None => Ok(None),
+            Some(value) => Ok(Some(
+                deserialize_usb_identity(&value)
+                    .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?,
+            )),
+        }
+    }
+
+    /// Overrides the USB identity.
+    ///
+    /// This is meant for provisioning. The identity can't change once the vendor configuration is
+    /// locked.
+    pub fn set_usb_identity(&mut self, identity: UsbIdentity) -> Result<(), Ctap2StatusCode> {
+        if self.vendor_locked()? {
+            return Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED);
+        }
+        let value = serialize_usb_identity(identity)?;
+        Ok(self.store.insert(key::USB_IDENTITY, &value)?)
+    }
+
+    /// Returns whether the vendor configuration is locked.
+    pub fn vendor_locked(&self) -> Result<bool, Ctap2StatusCode> {
+        Ok(self.store.contains(key::VENDOR_LOCKED)?)
+    }
+
+    /// Locks the vendor configuration.
+    pub fn lock_vendor(&mut self) -> Result<(), Ctap2StatusCode> {
+        Ok(self.store.replace(key::VENDOR_LOCKED, &[])?)
+    }
+
+    /// Returns the highest firmware version that ran on the device.
+    ///
+    /// If no version was recorded yet, returns 0.
+    pub fn firmware_version(&self) -> Result<u64, Ctap2StatusCode> {
+        match self.store.find(key::FIRMWARE_VERSION)? {
+            None => Ok(0),
+            Some(value) if value.len() == 8 => Ok(u64::from_ne_bytes(*array_ref!(&value, 0, 8))),
+            Some(_) => Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR),
+        }
+    }
+
+    /// Records the version of the running firmware.
+    ///
+    /// The recorded version never decreases. Returns `CTAP2_ERR_NOT_ALLOWED` if the version is
+    /// older than the recorded one, in which case the firmware must not run.
+    pub fn check_firmware_version(&mut self, version: u64) -> Result<(), Ctap2StatusCode> {
+        let recorded_version = self.firmware_version()?;
+        if version < recorded_version {
+            return Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED);
+        }
+        if version > recorded_version {
+            self.store
+                .insert(key::FIRMWARE_VERSION, &version.to_ne_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the events of the audit log, oldest first.
+    pub fn audit_log(&self) -> Result<Vec<AuditEvent>, Ctap2StatusCode> {
+        let mut events = Vec::new();
+        for handle in self.store.iter_range(key::AUDIT_LOG)? {
+            let handle = handle?;
+            let value = handle.get_value(&self.store)?;
+            let event = deserialize_audit_event(&value)
+                .ok_or(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)?;
+            events.push(event);
+        }
+        events.sort_by_key(|event| event.sequence);
+        Ok(events)
+    }
+
+    /// Appends an event to the audit log.
+    ///
+    /// When the log is full, the oldest event is overwritten.
+    pub fn append_audit_event(
+        &mut self,
+        event_type: AuditEventType,
+        timestamp_ms: u64,
+    ) -> Result<(), Ctap2StatusCode> {
+        let (key, value) = self.next_audit_event(event_type, timestamp_ms)?;
+        self.store.insert(key, &value)?;
+        self.audit_sequence = self.audit_sequence.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Clears the audit log.
+    ///
+    /// The log is left with a single event recording the clearing. Sequence numbers are not reset.
+    pub fn clear_audit_log(&mut self, timestamp_ms: u64) -> Result<(), Ctap2StatusCode> {
+        let (key, value) = self.next_audit_event(AuditEventType::AuditLogCleared, timestamp_ms)?;
+        let mut updates = vec![StoreUpdate::Insert { key, value }];
+        for handle in self.store.iter_range(key::AUDIT_LOG)? {
+            let handle_key = handle?.get_key();
+            if handle_key != key {
+                updates.push(StoreUpdate::Remove { key: handle_key });
+            }
+        }
+        self.store.transaction(&updates)?;
+        self.audit_sequence = self.audit_sequence.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Returns the key and serialized value of the next audit event.
+    fn next_audit_event(
+        &self,
+        event_type: AuditEventType,
+        timestamp_ms: u64,
+    ) -> Result<(usize, Vec<u8>), Ctap2StatusCode> {
+        let sequence = self.audit_sequence;
+        let num_slots = (key::AUDIT_LOG.end - key::AUDIT_LOG.start) as u64;
+        let key = key::AUDIT_LOG.start + (sequence % num_slots) as usize;
+        let event = AuditEvent {
+            sequence,
+            event_type,
+            timestamp_ms,
+        };
+        Ok((key, serialize_audit_event(event)?))
     }
 
     /// Resets the store as for a CTAP reset.
@@ -482,9 +1097,23 @@ impl PersistentStore {
     }
 }
 
-impl From<persistent_store::StoreError> for Ctap2StatusCode {
-    fn from(error: persistent_store::StoreError) -> Ctap2StatusCode {
-        use persistent_store::StoreError;
+/// Logs the storage activity to the console, for example to follow capacity while using a browser.
+#[cfg(feature = "debug_ctap")]
+fn log_store_mutation(mutation: &persistent_store::StoreMutation) {
+    writeln!(&mut Console::new(), "Store mutation: {:?}", mutation).unwrap();
+}
+
+/// Returns the keys of a critical entry given its range of keys.
+fn critical_keys(keys: Range<usize>) -> CriticalKeys {
+    debug_assert_eq!(keys.len(), 3);
+    CriticalKeys {
+        pointer: keys.start,
+        shadows: [keys.start + 1, keys.start + 2],
+    }
+}
+
+impl From<StoreError> for Ctap2StatusCode {
+    fn from(error: StoreError) -> Ctap2StatusCode {
         match error {
             // This error is expected. The store is full.
             StoreError::NoCapacity => Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL,
@@ -493,11 +1122,18 @@ impl From<persistent_store::StoreError> for Ctap2StatusCode {
             // This error is expected if we don't satisfy the store preconditions. For example we
             // try to store a credential which is too long.
             StoreError::InvalidArgument => Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR,
+            // This error is expected if we don't check the conditions of conditional updates
+            // before applying them.
+            StoreError::ConditionFailed => Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR,
             // This error is not expected. The storage has been tempered with. We could erase the
             // storage.
             StoreError::InvalidStorage => Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE,
             // This error is not expected. The kernel is failing our syscalls.
             StoreError::StorageError => Ctap2StatusCode::CTAP1_ERR_OTHER,
+            // This error is not expected. The storage was written with another layout.
+            StoreError::GeometryMismatch => Ctap2StatusCode::CTAP2_ERR_VENDOR_HARDWARE_FAILURE,
+            // This error is not expected. We tried to overwrite a provisioned entry.
+            StoreError::WriteOnce => Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR,
         }
     }
 }
@@ -523,7 +1159,7 @@ impl<'a> IterCredentials<'a> {
         store: &'a persistent_store::Store<Storage>,
         result: &'a mut Result<(), Ctap2StatusCode>,
     ) -> Result<IterCredentials<'a>, Ctap2StatusCode> {
-        let iter = store.iter()?;
+        let iter = store.iter_range(key::CREDENTIALS)?;
         Ok(IterCredentials {
             store,
             iter,
@@ -551,17 +1187,12 @@ impl<'a> Iterator for IterCredentials<'a> {
         if self.result.is_err() {
             return None;
         }
-        while let Some(next) = self.iter.next() {
-            let handle = self.unwrap(next.ok())?;
-            let key = handle.get_key();
-            if !key::CREDENTIALS.contains(&key) {
-                continue;
-            }
-            let value = self.unwrap(handle.get_value(&self.store).ok())?;
-            let credential = self.unwrap(deserialize_credential(&value))?;
-            return Some((key, credential));
-        }
-        None
+        let handle = self.iter.next()?;
+        let handle = self.unwrap(handle.ok())?;
+        let key = handle.get_key();
+        let value = self.unwrap(handle.get_value(&self.store).ok())?;
+        let credential = self.unwrap(deserialize_credential(&value))?;
+        Some((key, credential))
     }
 }
 
@@ -581,29 +1212,65 @@ fn serialize_credential(credential: PublicKeyCredentialSource) -> Result<Vec<u8>
     }
 }
 
-/// Deserializes a list of RP IDs from storage representation.
-#[cfg(feature = "with_ctap2_1")]
-fn _deserialize_min_pin_length_rp_ids(data: &[u8]) -> Option<Vec<String>> {
+/// Deserializes certificates from storage representation.
+fn deserialize_certificates(data: &[u8]) -> Option<Vec<Vec<u8>>> {
     let cbor = cbor::read(data).ok()?;
     extract_array(cbor)
         .ok()?
         .into_iter()
-        .map(extract_text_string)
-        .collect::<Result<Vec<String>, Ctap2StatusCode>>()
-        .ok()
+        .map(|certificate| extract_byte_string(certificate).ok())
+        .collect()
 }
 
-/// Serializes a list of RP IDs to storage representation.
-#[cfg(feature = "with_ctap2_1")]
-fn _serialize_min_pin_length_rp_ids(rp_ids: Vec<String>) -> Result<Vec<u8>, Ctap2StatusCode> {
+/// Serializes certificates to storage representation.
+fn serialize_certificates(certificates: &[Vec<u8>]) -> Result<Vec<u8>, Ctap2StatusCode> {
+    let mut data = Vec::new();
+    if cbor::write(cbor_array_vec!(certificates.to_vec()), &mut data) {
+        Ok(data)
+    } else {
+        Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR)
+    }
+}
+
+/// Deserializes an audit event from storage representation.
+fn deserialize_audit_event(data: &[u8]) -> Option<AuditEvent> {
+    let cbor = cbor::read(data).ok()?;
+    cbor.try_into().ok()
+}
+
+/// Serializes an audit event to storage representation.
+fn serialize_audit_event(event: AuditEvent) -> Result<Vec<u8>, Ctap2StatusCode> {
+    let mut data = Vec::new();
+    if cbor::write(event.into(), &mut data) {
+        Ok(data)
+    } else {
+        Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR)
+    }
+}
+
+/// Deserializes a USB identity from storage representation.
+fn deserialize_usb_identity(data: &[u8]) -> Option<UsbIdentity> {
+    let cbor = cbor::read(data).ok()?;
+    cbor.try_into().ok()
+}
+
+/// Serializes a USB identity to storage representation.
+fn serialize_usb_identity(identity: UsbIdentity) -> Result<Vec<u8>, Ctap2StatusCode> {
     let mut data = Vec::new();
-    if cbor::write(cbor_array_vec!(rp_ids), &mut data) {
+    if cbor::write(identity.into(), &mut data) {
         Ok(data)
     } else {
         Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_RESPONSE_CANNOT_WRITE_CBOR)
     }
 }
 
+/// Returns the capacity in words used by the insertion of a value of the given length.
+///
+/// An entry uses a header word followed by the words of its value.
+fn entry_capacity(length: usize) -> usize {
+    1 + (length + 3) / 4
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -627,14 +1294,36 @@ mod test {
             creation_order: 0,
             user_name: None,
             user_icon: None,
+            authenticator_display_name: None,
+            transports: None,
         }
     }
 
-    #[test]
-    fn test_store() {
-        let mut rng = ThreadRng256 {};
-        let mut persistent_store = PersistentStore::new(&mut rng);
-        assert_eq!(persistent_store.count_credentials().unwrap(), 0);
+    // Pads the user icon such that the credential uses the given capacity in words.
+    fn create_credential_with_capacity(
+        rng: &mut ThreadRng256,
+        user_handle: Vec<u8>,
+        capacity: usize,
+    ) -> PublicKeyCredentialSource {
+        let mut credential_source = create_credential_source(rng, "example.com", user_handle);
+        let mut user_icon = String::new();
+        loop {
+            credential_source.user_icon = Some(user_icon.clone());
+            let length = serialize_credential(credential_source.clone())
+                .unwrap()
+                .len();
+            if entry_capacity(length) == capacity {
+                return credential_source;
+            }
+            user_icon.push('a');
+        }
+    }
+
+    #[test]
+    fn test_store() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        assert_eq!(persistent_store.count_credentials().unwrap(), 0);
         let credential_source = create_credential_source(&mut rng, "example.com", vec![]);
         assert!(persistent_store.store_credential(credential_source).is_ok());
         assert!(persistent_store.count_credentials().unwrap() > 0);
@@ -656,37 +1345,216 @@ mod test {
     }
 
     #[test]
-    #[allow(clippy::assertions_on_constants)]
+    fn test_max_supported_residential_keys() {
+        let mut rng = ThreadRng256 {};
+        let persistent_store = PersistentStore::new(&mut rng);
+        // This is the minimum number of residential keys guaranteed with the default number of
+        // pages. Reducing it may break users with many residential keys.
+        assert_eq!(
+            persistent_store.max_supported_residential_keys().unwrap(),
+            150
+        );
+    }
+
+    #[test]
     fn test_fill_store() {
         let mut rng = ThreadRng256 {};
         let mut persistent_store = PersistentStore::new(&mut rng);
         assert_eq!(persistent_store.count_credentials().unwrap(), 0);
+        let max_keys = persistent_store.max_supported_residential_keys().unwrap();
 
         // To make this test work for bigger storages, implement better int -> Vec conversion.
-        assert!(MAX_SUPPORTED_RESIDENTIAL_KEYS < 256);
-        for i in 0..MAX_SUPPORTED_RESIDENTIAL_KEYS {
+        assert!(max_keys < 256);
+        for i in 0..max_keys {
             let credential_source =
                 create_credential_source(&mut rng, "example.com", vec![i as u8]);
             assert!(persistent_store.store_credential(credential_source).is_ok());
             assert_eq!(persistent_store.count_credentials().unwrap(), i + 1);
         }
-        let credential_source = create_credential_source(
-            &mut rng,
-            "example.com",
-            vec![MAX_SUPPORTED_RESIDENTIAL_KEYS as u8],
-        );
+        let credential_source =
+            create_credential_source(&mut rng, "example.com", vec![max_keys as u8]);
         assert_eq!(
             persistent_store.store_credential(credential_source),
             Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL)
         );
+        assert_eq!(persistent_store.count_credentials().unwrap(), max_keys);
+    }
+
+    #[test]
+    fn test_rp_id_quota() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        for i in 0..3 {
+            let credential_source = create_credential_source(&mut rng, "example.com", vec![i]);
+            assert!(persistent_store
+                .store_credential_with_quota(credential_source, Some(3), false)
+                .is_ok());
+        }
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x03]);
+        assert_eq!(
+            persistent_store.store_credential_with_quota(credential_source, Some(3), false),
+            Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL)
+        );
+        // Updating a credential and storing for another RP id still work.
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x00]);
+        assert!(persistent_store
+            .store_credential_with_quota(credential_source, Some(3), false)
+            .is_ok());
+        let credential_source = create_credential_source(&mut rng, "another.example.com", vec![]);
+        assert!(persistent_store
+            .store_credential_with_quota(credential_source, Some(3), false)
+            .is_ok());
+        assert_eq!(persistent_store.count_credentials().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_rp_id_quota_evicts_oldest() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let other_credential = create_credential_source(&mut rng, "another.example.com", vec![]);
+        assert!(persistent_store.store_credential(other_credential).is_ok());
+        // The creation order doesn't follow the user handle, to check that it decides eviction.
+        for &(user_handle, creation_order) in &[(0x00, 2), (0x01, 1), (0x02, 3)] {
+            let mut credential_source =
+                create_credential_source(&mut rng, "example.com", vec![user_handle]);
+            credential_source.creation_order = creation_order;
+            assert!(persistent_store
+                .store_credential_with_quota(credential_source, Some(3), true)
+                .is_ok());
+        }
+        let mut credential_source = create_credential_source(&mut rng, "example.com", vec![0x03]);
+        credential_source.creation_order = 4;
+        assert!(persistent_store
+            .store_credential_with_quota(credential_source, Some(3), true)
+            .is_ok());
+        assert_eq!(persistent_store.count_credentials().unwrap(), 4);
+        let mut user_handles = persistent_store
+            .filter_credential("example.com", false)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.user_handle)
+            .collect::<Vec<_>>();
+        user_handles.sort();
+        assert_eq!(user_handles, vec![vec![0x00], vec![0x02], vec![0x03]]);
         assert_eq!(
-            persistent_store.count_credentials().unwrap(),
-            MAX_SUPPORTED_RESIDENTIAL_KEYS
+            persistent_store
+                .filter_credential("another.example.com", false)
+                .unwrap()
+                .len(),
+            1
         );
     }
 
     #[test]
-    #[allow(clippy::assertions_on_constants)]
+    fn test_remaining_credentials() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let max_keys = persistent_store.max_supported_residential_keys().unwrap();
+        assert_eq!(persistent_store.remaining_credentials().unwrap(), max_keys);
+
+        // Small credentials are bounded by the number of keys.
+        assert!(max_keys < 256);
+        for i in 0..max_keys {
+            let credential_source =
+                create_credential_source(&mut rng, "example.com", vec![i as u8]);
+            assert!(persistent_store.store_credential(credential_source).is_ok());
+            assert_eq!(
+                persistent_store.remaining_credentials().unwrap(),
+                max_keys - i - 1
+            );
+        }
+        assert_eq!(persistent_store.count_credentials().unwrap(), max_keys);
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_remaining_credentials_nearly_full() {
+        use crate::ctap::large_blobs::MAX_LARGE_BLOB_ARRAY_SIZE;
+
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let max_keys = persistent_store.max_supported_residential_keys().unwrap();
+
+        // A large blob array uses more than the reserved capacity.
+        let large_array = vec![0x55; MAX_LARGE_BLOB_ARRAY_SIZE];
+        persistent_store
+            .commit_large_blob_array(&large_array)
+            .unwrap();
+        let remaining = persistent_store.remaining_credentials().unwrap();
+        assert!(remaining < max_keys);
+
+        // Credentials of maximum size are bounded by the capacity.
+        for i in 0..remaining {
+            let credential_source =
+                create_credential_with_capacity(&mut rng, vec![i as u8], RESIDENTIAL_KEY_CAPACITY);
+            assert!(persistent_store.store_credential(credential_source).is_ok());
+            assert_eq!(
+                persistent_store.remaining_credentials().unwrap(),
+                remaining - i - 1
+            );
+        }
+
+        // The store is nearly full: free keys are left, but not enough capacity. The large blob
+        // array uses more than the reserved capacity.
+        assert!(persistent_store.count_credentials().unwrap() < max_keys);
+        let credential_source = create_credential_with_capacity(
+            &mut rng,
+            vec![remaining as u8],
+            RESIDENTIAL_KEY_CAPACITY,
+        );
+        assert_eq!(
+            persistent_store.store_credential(credential_source),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_METADATA_FULL)
+        );
+        assert_eq!(persistent_store.remaining_credentials().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_store_credential_evicts_low_value_entries() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let num_events = key::AUDIT_LOG.end - key::AUDIT_LOG.start;
+        for i in 0..num_events {
+            persistent_store
+                .append_audit_event(AuditEventType::GetAssertion, i as u64)
+                .unwrap();
+        }
+
+        // Credentials larger than the maximum size fill the store before the keys run out. The
+        // audit log is evicted before failing, except for its last event.
+        let mut count = 0;
+        let error = loop {
+            let credential_source =
+                create_credential_with_capacity(&mut rng, vec![count as u8], 200);
+            match persistent_store.store_credential(credential_source) {
+                Ok(()) => count += 1,
+                Err(error) => break error,
+            }
+        };
+        assert_eq!(error, Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL);
+        assert!(count < persistent_store.max_supported_residential_keys().unwrap());
+        let events = persistent_store.audit_log().unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].sequence, num_events as u64 - 1);
+
+        // The scratch entry of an interrupted self test is evicted to fit a credential. It uses the
+        // capacity of the removed credential.
+        persistent_store
+            .store
+            .remove(key::CREDENTIALS.start)
+            .unwrap();
+        assert_eq!(entry_capacity(796), 200);
+        persistent_store
+            .store
+            .insert(key::SELF_TEST, &[0x55; 796])
+            .unwrap();
+        let credential_source = create_credential_with_capacity(&mut rng, vec![0xFF], 200);
+        assert!(persistent_store.store_credential(credential_source).is_ok());
+        assert_eq!(persistent_store.store.find(key::SELF_TEST).unwrap(), None);
+        assert_eq!(persistent_store.count_credentials().unwrap(), count);
+    }
+
+    #[test]
     fn test_overwrite() {
         let mut rng = ThreadRng256 {};
         let mut persistent_store = PersistentStore::new(&mut rng);
@@ -711,26 +1579,21 @@ mod test {
         );
 
         // To make this test work for bigger storages, implement better int -> Vec conversion.
-        assert!(MAX_SUPPORTED_RESIDENTIAL_KEYS < 256);
-        for i in 0..MAX_SUPPORTED_RESIDENTIAL_KEYS {
+        let max_keys = persistent_store.max_supported_residential_keys().unwrap();
+        assert!(max_keys < 256);
+        for i in 0..max_keys {
             let credential_source =
                 create_credential_source(&mut rng, "example.com", vec![i as u8]);
             assert!(persistent_store.store_credential(credential_source).is_ok());
             assert_eq!(persistent_store.count_credentials().unwrap(), i + 1);
         }
-        let credential_source = create_credential_source(
-            &mut rng,
-            "example.com",
-            vec![MAX_SUPPORTED_RESIDENTIAL_KEYS as u8],
-        );
+        let credential_source =
+            create_credential_source(&mut rng, "example.com", vec![max_keys as u8]);
         assert_eq!(
             persistent_store.store_credential(credential_source),
             Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL)
         );
-        assert_eq!(
-            persistent_store.count_credentials().unwrap(),
-            MAX_SUPPORTED_RESIDENTIAL_KEYS
-        );
+        assert_eq!(persistent_store.count_credentials().unwrap(), max_keys);
     }
 
     #[test]
@@ -766,6 +1629,32 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_set_authenticator_display_name() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0x00]);
+        let credential_id = credential_source.credential_id.clone();
+        assert!(persistent_store.store_credential(credential_source).is_ok());
+
+        let display_name = Some(String::from("work key"));
+        assert!(persistent_store
+            .set_authenticator_display_name(&credential_id, display_name.clone())
+            .is_ok());
+        let credential = persistent_store
+            .find_credential("example.com", &credential_id, false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(credential.authenticator_display_name, display_name);
+        assert_eq!(credential.user_handle, vec![0x00]);
+        assert_eq!(persistent_store.count_credentials().unwrap(), 1);
+
+        assert_eq!(
+            persistent_store.set_authenticator_display_name(&[0xCC; 32], None),
+            Err(Ctap2StatusCode::CTAP2_ERR_NO_CREDENTIALS)
+        );
+    }
+
     #[test]
     fn test_filter_with_cred_protect() {
         let mut rng = ThreadRng256 {};
@@ -785,6 +1674,8 @@ mod test {
             creation_order: 0,
             user_name: None,
             user_icon: None,
+            authenticator_display_name: None,
+            transports: None,
         };
         assert!(persistent_store.store_credential(credential).is_ok());
 
@@ -828,6 +1719,8 @@ mod test {
             creation_order: 0,
             user_name: None,
             user_icon: None,
+            authenticator_display_name: None,
+            transports: None,
         };
         assert_eq!(found_credential, Some(expected_credential));
     }
@@ -849,6 +1742,8 @@ mod test {
             creation_order: 0,
             user_name: None,
             user_icon: None,
+            authenticator_display_name: None,
+            transports: None,
         };
         assert!(persistent_store.store_credential(credential).is_ok());
 
@@ -879,6 +1774,126 @@ mod test {
         assert!(master_keys_3.hmac != master_hmac_key.as_slice());
     }
 
+    #[test]
+    fn test_master_keys_lost_shadow() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let master_keys = persistent_store.master_keys().unwrap();
+
+        // Losing the shadow entry of the current value falls back to the previous value.
+        let keys = critical_keys(key::MASTER_KEYS);
+        let mut value = master_keys.encryption.to_vec();
+        value.extend_from_slice(&master_keys.hmac);
+        persistent_store
+            .store
+            .insert_critical(&keys, &value)
+            .unwrap();
+        persistent_store.store.remove(keys.shadows[1]).unwrap();
+        let master_keys_after_loss = persistent_store.master_keys().unwrap();
+        assert_eq!(master_keys_after_loss.encryption, master_keys.encryption);
+        assert_eq!(master_keys_after_loss.hmac, master_keys.hmac);
+    }
+
+    #[test]
+    fn test_migrate_critical_keys() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        persistent_store.reset(&mut rng).unwrap();
+        persistent_store
+            .store
+            .remove_critical(&critical_keys(key::MASTER_KEYS))
+            .unwrap();
+        let legacy_master_keys = [0x5C; 64];
+        let legacy_attestation_private_key = [0x41; key_material::ATTESTATION_PRIVATE_KEY_LENGTH];
+        persistent_store
+            .store
+            .insert(key::_MASTER_KEYS, &legacy_master_keys)
+            .unwrap();
+        persistent_store
+            .store
+            .insert(
+                key::_ATTESTATION_PRIVATE_KEY,
+                &legacy_attestation_private_key,
+            )
+            .unwrap();
+
+        persistent_store.init(&mut rng).unwrap();
+        let master_keys = persistent_store.master_keys().unwrap();
+        assert_eq!(master_keys.encryption, [0x5C; 32]);
+        assert_eq!(master_keys.hmac, [0x5C; 32]);
+        assert_eq!(
+            persistent_store.attestation_private_key().unwrap(),
+            Some(legacy_attestation_private_key)
+        );
+        assert!(persistent_store
+            .store
+            .find(key::_MASTER_KEYS)
+            .unwrap()
+            .is_none());
+        assert!(persistent_store
+            .store
+            .find(key::_ATTESTATION_PRIVATE_KEY)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_migrate_credentials() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let min_key = key::CREDENTIALS.start;
+        let max_keys = persistent_store.max_supported_residential_keys().unwrap();
+        let insert_credential = |persistent_store: &mut PersistentStore,
+                                 rng: &mut ThreadRng256,
+                                 key: usize,
+                                 user_handle: u8| {
+            let credential = create_credential_source(rng, "example.com", vec![user_handle]);
+            let value = serialize_credential(credential).unwrap();
+            persistent_store.store.insert(key, &value).unwrap();
+        };
+        let credential_keys = |persistent_store: &PersistentStore| {
+            let mut iter_result = Ok(());
+            let iter = persistent_store.iter_credentials(&mut iter_result).unwrap();
+            let mut keys: Vec<usize> = iter.map(|(key, _)| key).collect();
+            iter_result.unwrap();
+            keys.sort_unstable();
+            keys
+        };
+
+        // Credentials outside the prefix are moved to its first free keys.
+        insert_credential(&mut persistent_store, &mut rng, min_key + 1, 0);
+        insert_credential(&mut persistent_store, &mut rng, key::CREDENTIALS.end - 1, 1);
+        let legacy_key = key::_RESERVED_CREDENTIALS.start;
+        insert_credential(&mut persistent_store, &mut rng, legacy_key, 2);
+        persistent_store.init(&mut rng).unwrap();
+        assert_eq!(
+            credential_keys(&persistent_store),
+            vec![min_key, min_key + 1, min_key + 2]
+        );
+
+        // Credentials that don't fit in the prefix are kept.
+        for i in 3..max_keys {
+            insert_credential(&mut persistent_store, &mut rng, min_key + i, i as u8);
+        }
+        insert_credential(
+            &mut persistent_store,
+            &mut rng,
+            key::CREDENTIALS.end - 1,
+            0xF0,
+        );
+        insert_credential(&mut persistent_store, &mut rng, legacy_key, 0xF1);
+        persistent_store.init(&mut rng).unwrap();
+        let keys = credential_keys(&persistent_store);
+        assert_eq!(keys.len(), max_keys + 2);
+        let expected_keys = [min_key + max_keys, key::CREDENTIALS.end - 1];
+        assert_eq!(&keys[max_keys..], &expected_keys);
+        let credential_source = create_credential_source(&mut rng, "example.com", vec![0xF2]);
+        assert_eq!(
+            persistent_store.store_credential(credential_source),
+            Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL)
+        );
+    }
+
     #[test]
     fn test_cred_random_secret() {
         let mut rng = ThreadRng256 {};
@@ -915,11 +1930,27 @@ mod test {
         let pin_hash_1 = *array_ref!(random_data, 0, PIN_AUTH_LENGTH);
         let pin_hash_2 = *array_ref!(random_data, PIN_AUTH_LENGTH, PIN_AUTH_LENGTH);
         persistent_store.set_pin_hash(&pin_hash_1).unwrap();
-        assert_eq!(persistent_store.pin_hash().unwrap(), Some(pin_hash_1));
-        assert_eq!(persistent_store.pin_hash().unwrap(), Some(pin_hash_1));
+        assert!(persistent_store
+            .pin_hash()
+            .unwrap()
+            .unwrap()
+            .ct_eq(&pin_hash_1));
+        assert!(persistent_store
+            .pin_hash()
+            .unwrap()
+            .unwrap()
+            .ct_eq(&pin_hash_1));
         persistent_store.set_pin_hash(&pin_hash_2).unwrap();
-        assert_eq!(persistent_store.pin_hash().unwrap(), Some(pin_hash_2));
-        assert_eq!(persistent_store.pin_hash().unwrap(), Some(pin_hash_2));
+        assert!(persistent_store
+            .pin_hash()
+            .unwrap()
+            .unwrap()
+            .ct_eq(&pin_hash_2));
+        assert!(persistent_store
+            .pin_hash()
+            .unwrap()
+            .unwrap()
+            .ct_eq(&pin_hash_2));
 
         // Resetting the storage resets the pin hash.
         persistent_store.reset(&mut rng).unwrap();
@@ -949,6 +1980,57 @@ mod test {
         assert_eq!(persistent_store.pin_retries(), Ok(MAX_PIN_RETRIES));
     }
 
+    #[test]
+    fn test_pin_retries_writes_on_change() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let lifetime = |store: &PersistentStore| store.store.lifetime().unwrap().used();
+
+        // Resetting the maximum pin retries doesn't write.
+        let used = lifetime(&persistent_store);
+        persistent_store.reset_pin_retries().unwrap();
+        assert_eq!(lifetime(&persistent_store), used);
+
+        // Decrementing writes the entry and resetting removes it.
+        persistent_store.decr_pin_retries().unwrap();
+        assert!(lifetime(&persistent_store) > used);
+        assert!(persistent_store
+            .store
+            .find(key::PIN_RETRIES)
+            .unwrap()
+            .is_some());
+        persistent_store.reset_pin_retries().unwrap();
+        assert!(persistent_store
+            .store
+            .find(key::PIN_RETRIES)
+            .unwrap()
+            .is_none());
+        assert_eq!(persistent_store.pin_retries(), Ok(MAX_PIN_RETRIES));
+
+        // Decrementing zero pin retries doesn't write.
+        for _ in 0..MAX_PIN_RETRIES {
+            persistent_store.decr_pin_retries().unwrap();
+        }
+        let used = lifetime(&persistent_store);
+        persistent_store.decr_pin_retries().unwrap();
+        assert_eq!(lifetime(&persistent_store), used);
+        assert_eq!(persistent_store.pin_retries(), Ok(0));
+    }
+
+    #[test]
+    fn test_pin_retries_survive_reboot_and_reset() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        persistent_store.decr_pin_retries().unwrap();
+        persistent_store.decr_pin_retries().unwrap();
+
+        let mut persistent_store = persistent_store.reboot(&mut rng);
+        assert_eq!(persistent_store.pin_retries(), Ok(MAX_PIN_RETRIES - 2));
+
+        persistent_store.reset(&mut rng).unwrap();
+        assert_eq!(persistent_store.pin_retries(), Ok(MAX_PIN_RETRIES));
+    }
+
     #[test]
     fn test_persistent_keys() {
         let mut rng = ThreadRng256 {};
@@ -971,9 +2053,14 @@ mod test {
             .set_attestation_private_key(&dummy_key)
             .unwrap();
         persistent_store
-            .set_attestation_certificate(&dummy_cert)
+            .set_attestation_certificate_chain(&[dummy_cert.to_vec()])
             .unwrap();
-        assert_eq!(&persistent_store.aaguid().unwrap(), key_material::AAGUID);
+        assert_eq!(&persistent_store.aaguid(), key_material::AAGUID);
+        // The AAGUID is not written to the store unless overridden.
+        assert!(persistent_store.store.find(key::AAGUID).unwrap().is_none());
+        let dummy_aaguid = [0xaau8; key_material::AAGUID_LENGTH];
+        persistent_store.set_aaguid(&dummy_aaguid).unwrap();
+        assert_eq!(persistent_store.aaguid(), dummy_aaguid);
 
         // The persistent keys stay initialized and preserve their value after a reset.
         persistent_store.reset(&mut rng).unwrap();
@@ -985,7 +2072,235 @@ mod test {
             persistent_store.attestation_certificate().unwrap().unwrap(),
             &dummy_cert
         );
-        assert_eq!(&persistent_store.aaguid().unwrap(), key_material::AAGUID);
+        assert_eq!(persistent_store.aaguid(), dummy_aaguid);
+    }
+
+    #[test]
+    fn test_write_once_keys() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let dummy_key = [0x41u8; key_material::ATTESTATION_PRIVATE_KEY_LENGTH];
+        let dummy_aaguid = [0xaau8; key_material::AAGUID_LENGTH];
+        persistent_store
+            .set_attestation_private_key(&dummy_key)
+            .unwrap();
+        persistent_store.set_aaguid(&dummy_aaguid).unwrap();
+        persistent_store.lock_vendor().unwrap();
+        // Locking again doesn't write.
+        persistent_store.lock_vendor().unwrap();
+
+        // The provisioned entries can't be overwritten nor removed, even after a reboot.
+        let mut persistent_store = persistent_store.reboot(&mut rng);
+        assert_eq!(
+            persistent_store.set_aaguid(&[0x55u8; key_material::AAGUID_LENGTH]),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)
+        );
+        assert_eq!(persistent_store.aaguid(), dummy_aaguid);
+        assert_eq!(
+            persistent_store
+                .store
+                .insert_critical(&critical_keys(key::ATTESTATION_PRIVATE_KEY), &[0x55; 32]),
+            Err(StoreError::WriteOnce)
+        );
+        assert_eq!(
+            persistent_store.store.remove(key::AAGUID),
+            Err(StoreError::WriteOnce)
+        );
+        assert_eq!(
+            persistent_store.store.remove(key::VENDOR_LOCKED),
+            Err(StoreError::WriteOnce)
+        );
+        assert_eq!(persistent_store.store.clear(0), Err(StoreError::WriteOnce));
+        assert_eq!(
+            persistent_store.attestation_private_key().unwrap(),
+            Some(dummy_key)
+        );
+        assert_eq!(persistent_store.vendor_locked(), Ok(true));
+    }
+
+    #[test]
+    fn test_attestation_certificate_chain() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        assert_eq!(persistent_store.attestation_certificate_chain(), Ok(None));
+        assert_eq!(
+            persistent_store.set_attestation_certificate_chain(&[]),
+            Err(Ctap2StatusCode::CTAP1_ERR_INVALID_PARAMETER)
+        );
+
+        // The intermediate certificates don't fit in a single entry.
+        let max_value_length = persistent_store.store.max_value_length();
+        let chain = vec![
+            vec![0xC1; 20],
+            vec![0xC2; max_value_length],
+            vec![0xC3; 100],
+        ];
+        assert!(persistent_store
+            .set_attestation_certificate_chain(&chain)
+            .is_ok());
+        assert!(persistent_store
+            .store
+            .find(key::ATTESTATION_INTERMEDIATE_CERTIFICATES.start + 1)
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            persistent_store.attestation_certificate(),
+            Ok(Some(vec![0xC1; 20]))
+        );
+        assert_eq!(
+            persistent_store.attestation_certificate_chain(),
+            Ok(Some(chain.clone()))
+        );
+
+        // The chain is never overwritten and survives a reset.
+        assert_eq!(
+            persistent_store.set_attestation_certificate_chain(&[vec![0xC4; 20]]),
+            Err(Ctap2StatusCode::CTAP2_ERR_VENDOR_INTERNAL_ERROR)
+        );
+        persistent_store.reset(&mut rng).unwrap();
+        assert_eq!(
+            persistent_store.attestation_certificate_chain(),
+            Ok(Some(chain))
+        );
+    }
+
+    #[test]
+    fn test_attestation_certificate_chain_too_long() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        let num_shards = key::ATTESTATION_INTERMEDIATE_CERTIFICATES.end
+            - key::ATTESTATION_INTERMEDIATE_CERTIFICATES.start;
+        let max_value_length = persistent_store.store.max_value_length();
+        let chain = vec![vec![0xC1; 20], vec![0xC2; num_shards * max_value_length]];
+        assert_eq!(
+            persistent_store.set_attestation_certificate_chain(&chain),
+            Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL)
+        );
+        assert_eq!(persistent_store.attestation_certificate_chain(), Ok(None));
+    }
+
+    #[test]
+    fn test_firmware_version() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        assert_eq!(persistent_store.firmware_version(), Ok(0));
+
+        persistent_store.check_firmware_version(2).unwrap();
+        assert_eq!(persistent_store.firmware_version(), Ok(2));
+        persistent_store.check_firmware_version(2).unwrap();
+        persistent_store.check_firmware_version(3).unwrap();
+        assert_eq!(persistent_store.firmware_version(), Ok(3));
+
+        // Older firmware is refused, even after a reset.
+        persistent_store.reset(&mut rng).unwrap();
+        assert_eq!(
+            persistent_store.check_firmware_version(2),
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+        assert_eq!(persistent_store.firmware_version(), Ok(3));
+    }
+
+    #[test]
+    fn test_usb_identity() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        assert_eq!(persistent_store.usb_identity(), Ok(None));
+        assert_eq!(persistent_store.vendor_locked(), Ok(false));
+
+        let identity = UsbIdentity {
+            vendor_id: 0x1915,
+            product_id: 0x521F,
+            serial_number: Some(String::from("WL-0001")),
+        };
+        persistent_store.set_usb_identity(identity.clone()).unwrap();
+        assert_eq!(persistent_store.usb_identity(), Ok(Some(identity.clone())));
+
+        // The identity survives a reset.
+        persistent_store.reset(&mut rng).unwrap();
+        assert_eq!(persistent_store.usb_identity(), Ok(Some(identity.clone())));
+
+        // The identity can't change once locked.
+        persistent_store.lock_vendor().unwrap();
+        assert_eq!(persistent_store.vendor_locked(), Ok(true));
+        let other_identity = UsbIdentity {
+            vendor_id: 0x1234,
+            product_id: 0x5678,
+            serial_number: None,
+        };
+        assert_eq!(
+            persistent_store.set_usb_identity(other_identity),
+            Err(Ctap2StatusCode::CTAP2_ERR_NOT_ALLOWED)
+        );
+        persistent_store.reset(&mut rng).unwrap();
+        assert_eq!(persistent_store.vendor_locked(), Ok(true));
+        assert_eq!(persistent_store.usb_identity(), Ok(Some(identity)));
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        assert!(persistent_store.audit_log().unwrap().is_empty());
+
+        // Events are returned in order.
+        persistent_store
+            .append_audit_event(AuditEventType::MakeCredential, 10)
+            .unwrap();
+        persistent_store
+            .append_audit_event(AuditEventType::GetAssertion, 20)
+            .unwrap();
+        let events = persistent_store.audit_log().unwrap();
+        assert_eq!(
+            events,
+            vec![
+                AuditEvent {
+                    sequence: 0,
+                    event_type: AuditEventType::MakeCredential,
+                    timestamp_ms: 10,
+                },
+                AuditEvent {
+                    sequence: 1,
+                    event_type: AuditEventType::GetAssertion,
+                    timestamp_ms: 20,
+                },
+            ]
+        );
+
+        // The log survives a reset.
+        persistent_store.reset(&mut rng).unwrap();
+        assert_eq!(persistent_store.audit_log().unwrap().len(), 2);
+
+        // The oldest events are overwritten when the log is full.
+        let num_slots = key::AUDIT_LOG.end - key::AUDIT_LOG.start;
+        for _ in 0..num_slots {
+            persistent_store
+                .append_audit_event(AuditEventType::Reset, 30)
+                .unwrap();
+        }
+        let events = persistent_store.audit_log().unwrap();
+        assert_eq!(events.len(), num_slots);
+        assert_eq!(events[0].sequence, 2);
+        assert_eq!(events[num_slots - 1].sequence, num_slots as u64 + 1);
+
+        // Clearing the log keeps the sequence going.
+        persistent_store.clear_audit_log(40).unwrap();
+        let events = persistent_store.audit_log().unwrap();
+        assert_eq!(
+            events,
+            vec![AuditEvent {
+                sequence: num_slots as u64 + 2,
+                event_type: AuditEventType::AuditLogCleared,
+                timestamp_ms: 40,
+            }]
+        );
+
+        // The sequence also keeps going after a reboot.
+        let mut persistent_store = persistent_store.reboot(&mut rng);
+        persistent_store
+            .append_audit_event(AuditEventType::Reset, 50)
+            .unwrap();
+        let events = persistent_store.audit_log().unwrap();
+        assert_eq!(events[1].sequence, num_slots as u64 + 3);
     }
 
     #[cfg(feature = "with_ctap2_1")]
@@ -1011,30 +2326,129 @@ mod test {
         );
     }
 
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_always_uv() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+
+        // The alwaysUv option is initially disabled.
+        assert!(!persistent_store.always_uv().unwrap());
+
+        // Toggling flips the option.
+        persistent_store.toggle_always_uv().unwrap();
+        assert!(persistent_store.always_uv().unwrap());
+        persistent_store.toggle_always_uv().unwrap();
+        assert!(!persistent_store.always_uv().unwrap());
+
+        // The option is disabled by a reset.
+        persistent_store.toggle_always_uv().unwrap();
+        persistent_store.reset(&mut rng).unwrap();
+        assert!(!persistent_store.always_uv().unwrap());
+    }
+
+    #[cfg(feature = "with_ctap2_1")]
+    #[test]
+    fn test_large_blob_array() {
+        use crate::ctap::large_blobs::MAX_LARGE_BLOB_ARRAY_SIZE;
+        use crate::ctap::Sha256;
+        use crypto::Hash256;
+
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+
+        // The initial array is empty.
+        let initial_array = persistent_store.large_blob_array().unwrap();
+        assert_eq!(initial_array[0], 0x80);
+        assert_eq!(initial_array[1..], Sha256::hash(&[0x80])[..16]);
+
+        // The largest array spans multiple shards.
+        let large_array: Vec<u8> = (0..MAX_LARGE_BLOB_ARRAY_SIZE).map(|i| i as u8).collect();
+        persistent_store
+            .commit_large_blob_array(&large_array)
+            .unwrap();
+        assert_eq!(persistent_store.large_blob_array().unwrap(), large_array);
+
+        // A shorter array removes the shards it doesn't need.
+        let small_array = vec![0x55; 20];
+        persistent_store
+            .commit_large_blob_array(&small_array)
+            .unwrap();
+        assert_eq!(persistent_store.large_blob_array().unwrap(), small_array);
+
+        // Arrays larger than the shards are rejected.
+        let num_shards = key::LARGE_BLOB_SHARDS.end - key::LARGE_BLOB_SHARDS.start;
+        let too_large_array =
+            vec![0x55; num_shards * persistent_store.store.max_value_length() + 1];
+        assert_eq!(
+            persistent_store.commit_large_blob_array(&too_large_array),
+            Err(Ctap2StatusCode::CTAP2_ERR_LARGE_BLOB_STORAGE_FULL)
+        );
+        assert_eq!(persistent_store.large_blob_array().unwrap(), small_array);
+
+        // The array is cleared by a reset.
+        persistent_store.reset(&mut rng).unwrap();
+        assert_eq!(persistent_store.large_blob_array().unwrap(), initial_array);
+    }
+
     #[cfg(feature = "with_ctap2_1")]
     #[test]
     fn test_min_pin_length_rp_ids() {
+        use alloc::format;
+
         let mut rng = ThreadRng256 {};
         let mut persistent_store = PersistentStore::new(&mut rng);
+        let example_hash = Sha256::hash(b"example.com");
+        let other_hash = Sha256::hash(b"other.com");
+
+        // Initially, only the default RP IDs are allowed.
+        assert!(DEFAULT_MIN_PIN_LENGTH_RP_IDS.is_empty());
+        assert_eq!(
+            persistent_store.is_min_pin_length_rp_id(&example_hash),
+            Ok(false)
+        );
+
+        // Changes by the setter are reflected by the check.
+        let rp_ids = vec![String::from("example.com")];
+        assert_eq!(persistent_store.set_min_pin_length_rp_ids(&rp_ids), Ok(()));
+        assert_eq!(
+            persistent_store.is_min_pin_length_rp_id(&example_hash),
+            Ok(true)
+        );
+        assert_eq!(
+            persistent_store.is_min_pin_length_rp_id(&other_hash),
+            Ok(false)
+        );
 
-        // The minimum PIN length RP IDs are initially at the default.
+        // A new list replaces the previous one.
+        let rp_ids = vec![String::from("other.com")];
+        assert_eq!(persistent_store.set_min_pin_length_rp_ids(&rp_ids), Ok(()));
+        assert_eq!(
+            persistent_store.is_min_pin_length_rp_id(&example_hash),
+            Ok(false)
+        );
         assert_eq!(
-            persistent_store._min_pin_length_rp_ids().unwrap(),
-            _DEFAULT_MIN_PIN_LENGTH_RP_IDS
+            persistent_store.is_min_pin_length_rp_id(&other_hash),
+            Ok(true)
         );
 
-        // Changes by the setter are reflected by the getter.
-        let mut rp_ids = vec![String::from("example.com")];
+        // Too many RP IDs are rejected and don't change the list.
+        let rp_ids: Vec<String> = (0..=MAX_RP_IDS_LENGTH)
+            .map(|i| format!("example{}.com", i))
+            .collect();
+        assert_eq!(
+            persistent_store.set_min_pin_length_rp_ids(&rp_ids),
+            Err(Ctap2StatusCode::CTAP2_ERR_KEY_STORE_FULL)
+        );
         assert_eq!(
-            persistent_store._set_min_pin_length_rp_ids(rp_ids.clone()),
+            persistent_store.set_min_pin_length_rp_ids(&rp_ids[..MAX_RP_IDS_LENGTH]),
             Ok(())
         );
-        for rp_id in _DEFAULT_MIN_PIN_LENGTH_RP_IDS {
-            if !rp_ids.contains(&rp_id) {
-                rp_ids.push(rp_id);
-            }
-        }
-        assert_eq!(persistent_store._min_pin_length_rp_ids().unwrap(), rp_ids);
+        let last_hash = Sha256::hash(rp_ids[MAX_RP_IDS_LENGTH - 1].as_bytes());
+        assert_eq!(
+            persistent_store.is_min_pin_length_rp_id(&last_hash),
+            Ok(true)
+        );
     }
 
     #[test]
@@ -1059,6 +2473,47 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_metrics() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        assert_eq!(persistent_store.metrics(), Ok(Metrics::default()));
+
+        persistent_store.incr_metrics(1, 0).unwrap();
+        persistent_store.incr_metrics(0, 2).unwrap();
+        let expected_metrics = Metrics {
+            registrations: 1,
+            assertions: 2,
+        };
+        assert_eq!(persistent_store.metrics(), Ok(expected_metrics));
+
+        // Metrics survive a reset.
+        persistent_store.reset(&mut rng).unwrap();
+        assert_eq!(persistent_store.metrics(), Ok(expected_metrics));
+
+        // Metrics saturate.
+        persistent_store.incr_metrics(u32::MAX, 0).unwrap();
+        assert_eq!(persistent_store.metrics().unwrap().registrations, u32::MAX);
+    }
+
+    #[test]
+    fn test_self_test() {
+        let mut rng = ThreadRng256 {};
+        let mut persistent_store = PersistentStore::new(&mut rng);
+        assert_eq!(persistent_store.self_test(&mut rng), Ok(true));
+        assert_eq!(persistent_store.store.find(key::SELF_TEST), Ok(None));
+    }
+
+    #[test]
+    fn test_check() {
+        let mut rng = ThreadRng256 {};
+        let persistent_store = PersistentStore::new(&mut rng);
+        let check = persistent_store.check().unwrap();
+        assert!(check.is_consistent());
+        // The store is initialized with at least the master keys and the firmware version.
+        assert!(check.entries > 0);
+    }
+
     #[test]
     fn test_serialize_deserialize_credential() {
         let mut rng = ThreadRng256 {};
@@ -1074,18 +2529,11 @@ mod test {
             creation_order: 0,
             user_name: None,
             user_icon: None,
+            authenticator_display_name: None,
+            transports: None,
         };
         let serialized = serialize_credential(credential.clone()).unwrap();
         let reconstructed = deserialize_credential(&serialized).unwrap();
         assert_eq!(credential, reconstructed);
     }
-
-    #[cfg(feature = "with_ctap2_1")]
-    #[test]
-    fn test_serialize_deserialize_min_pin_length_rp_ids() {
-        let rp_ids = vec![String::from("example.com")];
-        let serialized = _serialize_min_pin_length_rp_ids(rp_ids.clone()).unwrap();
-        let reconstructed = _deserialize_min_pin_length_rp_ids(&serialized).unwrap();
-        assert_eq!(rp_ids, reconstructed);
-    }
 }
@@ -61,15 +61,62 @@ make_partition! {
 
     // WARNING: Keys should not be deleted but prefixed with `_` to avoid accidentally reusing them.
 
-    /// The attestation private key.
-    ATTESTATION_PRIVATE_KEY = 1;
+    /// The legacy attestation private key.
+    ///
+    /// The entry is migrated to `ATTESTATION_PRIVATE_KEY` at startup.
+    _ATTESTATION_PRIVATE_KEY = 1;
 
     /// The attestation certificate.
     ATTESTATION_CERTIFICATE = 2;
 
-    /// The aaguid.
+    /// The AAGUID override.
+    ///
+    /// If the entry is absent, the AAGUID is the compile-time `key_material::AAGUID`. The entry is
+    /// write-once.
     AAGUID = 3;
 
+    /// The usage metrics.
+    ///
+    /// If the entry is absent, all metrics are 0.
+    METRICS = 4;
+
+    /// The attestation private key.
+    ///
+    /// This is a critical entry: the first key is the pointer entry and the other two keys are the
+    /// shadow entries. The entries are write-once.
+    ATTESTATION_PRIVATE_KEY = 5..8;
+
+    /// The USB identity override.
+    ///
+    /// If the entry is absent, the USB identity is the one of the firmware image.
+    USB_IDENTITY = 8;
+
+    /// Whether the vendor configuration is locked.
+    ///
+    /// If the entry is absent, the vendor configuration is not locked. The entry is written by the
+    /// lockdown of the vendor configure command and never removed. The entry is write-once.
+    VENDOR_LOCKED = 9;
+
+    /// The highest firmware version that ran on the device.
+    ///
+    /// If the entry is absent, no version was recorded yet. The entry only increases, such that
+    /// older firmware refuses to boot.
+    FIRMWARE_VERSION = 10;
+
+    /// The serialized intermediate certificates of the attestation certificate chain.
+    ///
+    /// The CBOR array of certificates is split in shards of the maximum value length, stored at
+    /// consecutive keys from the start of the range. If the entries are absent, the attestation
+    /// certificate has no intermediate certificates.
+    ATTESTATION_INTERMEDIATE_CERTIFICATES = 11..15;
+
+    /// The audit log.
+    ///
+    /// Events are written in a ring buffer: the event with sequence number `n` is stored at key
+    /// `AUDIT_LOG.start + n % AUDIT_LOG.len()`, such that the log holds the last 5 events. The log
+    /// survives CTAP resets.
+    AUDIT_LOG = 15..20;
+
     // This is the persistent key limit:
     // - When adding a (persistent) key above this message, make sure its value is smaller than
     //   NUM_PERSISTENT_KEYS.
@@ -84,16 +131,43 @@ make_partition! {
 
     /// The credentials.
     ///
-    /// Depending on `MAX_SUPPORTED_RESIDENTIAL_KEYS`, only a prefix of those keys is used. Each
-    /// board may configure `MAX_SUPPORTED_RESIDENTIAL_KEYS` depending on the storage size.
+    /// Only a prefix of those keys is used, depending on the store capacity. Boards with more
+    /// storage automatically use more keys.
+    ///
+    /// Credentials outside this prefix, for example after the range or the capacity changed, are
+    /// moved to free keys of the prefix at startup.
     CREDENTIALS = 1700..2000;
 
+    /// The serialized large blob array.
+    ///
+    /// The array is split in shards of the maximum value length, stored at consecutive keys from
+    /// the start of the range. If the entries are absent, the array is the initial empty array.
+    #[cfg(feature = "with_ctap2_1")]
+    LARGE_BLOB_SHARDS = 2000..2004;
+
+    /// The encryption and hmac keys.
+    ///
+    /// This is a critical entry: the first key is the pointer entry and the other two keys are the
+    /// shadow entries. It is always present and generated at startup if absent.
+    MASTER_KEYS = 2036..2039;
+
+    /// Whether user verification is always required.
+    ///
+    /// If the entry is absent, the alwaysUv option is disabled. It is enabled otherwise.
+    #[cfg(feature = "with_ctap2_1")]
+    ALWAYS_UV = 2039;
+
+    /// Scratch entry of the storage self test.
+    ///
+    /// The entry is only present while the self test runs.
+    SELF_TEST = 2040;
+
     /// The secret of the CredRandom feature.
     CRED_RANDOM_SECRET = 2041;
 
-    /// List of RP IDs allowed to read the minimum PIN length.
+    /// The SHA-256 hashes of the RP IDs allowed to read the minimum PIN length, concatenated.
     #[cfg(feature = "with_ctap2_1")]
-    _MIN_PIN_LENGTH_RP_IDS = 2042;
+    MIN_PIN_LENGTH_RP_IDS = 2042;
 
     /// The minimum PIN length.
     ///
@@ -111,10 +185,10 @@ make_partition! {
     /// If the entry is absent, there is no PIN set.
     PIN_HASH = 2045;
 
-    /// The encryption and hmac keys.
+    /// The legacy encryption and hmac keys.
     ///
-    /// This entry is always present. It is generated at startup if absent.
-    MASTER_KEYS = 2046;
+    /// The entry is migrated to `MASTER_KEYS` at startup.
+    _MASTER_KEYS = 2046;
 
     /// The global signature counter.
     ///
@@ -122,16 +196,18 @@ make_partition! {
     GLOBAL_SIGNATURE_COUNTER = 2047;
 }
 
+/// Returns whether the entry of a key is write-once.
+///
+/// Those entries are written when provisioning the device. Once present, the store refuses to
+/// overwrite or remove them.
+pub fn is_write_once(key: usize) -> bool {
+    key == AAGUID || key == VENDOR_LOCKED || ATTESTATION_PRIVATE_KEY.contains(&key)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
-    #[test]
-    fn enough_credentials() {
-        use super::super::MAX_SUPPORTED_RESIDENTIAL_KEYS;
-        assert!(MAX_SUPPORTED_RESIDENTIAL_KEYS <= CREDENTIALS.end - CREDENTIALS.start);
-    }
-
     #[test]
     fn keys_are_disjoint() {
         // Check that keys are in the range.
@@ -0,0 +1,196 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::timed_permission::TimedPermission;
+use libtock_drivers::timer::{ClockValue, Duration};
+
+// A token that is not used within this time after being issued is invalidated.
+const INITIAL_USAGE_TIME_LIMIT: Duration<isize> = Duration::from_ms(30000);
+// The cached user presence of a token expires after this time.
+const USER_PRESENT_TIME_LIMIT: Duration<isize> = Duration::from_ms(30000);
+// A token is invalidated after this time, even if it is regularly used.
+// These lifetimes may be modified before compilation, within the limits of the specification.
+const MAX_USAGE_TIME_PERIOD: Duration<isize> = Duration::from_ms(600000);
+
+/// The state of the pinUvAuthToken, as described in the CTAP 2.1 specification.
+///
+/// A token is in use from the moment it is issued until it times out or is stopped. While in use,
+/// it caches whether the user was present and verified when it was issued. These flags are
+/// cleared once a command consumed them, and the user present flag expires on its own.
+pub struct PinUvAuthTokenState {
+    in_use: bool,
+    // Whether the token was used since it was issued. Only unused tokens have an initial limit.
+    used: bool,
+    initial_usage_timer: TimedPermission,
+    max_usage_timer: TimedPermission,
+    user_present_timer: TimedPermission,
+    user_verified: bool,
+}
+
+impl PinUvAuthTokenState {
+    pub fn new() -> PinUvAuthTokenState {
+        PinUvAuthTokenState {
+            in_use: false,
+            used: false,
+            initial_usage_timer: TimedPermission::waiting(),
+            max_usage_timer: TimedPermission::waiting(),
+            user_present_timer: TimedPermission::waiting(),
+            user_verified: false,
+        }
+    }
+
+    /// Starts the timers of a freshly issued token.
+    ///
+    /// Issuing a token always verifies the user, user presence depends on the issuing command.
+    pub fn begin_using_pin_uv_auth_token(&mut self, user_is_present: bool, now: ClockValue) {
+        self.in_use = true;
+        self.used = false;
+        self.initial_usage_timer = TimedPermission::granted(now, INITIAL_USAGE_TIME_LIMIT);
+        self.max_usage_timer = TimedPermission::granted(now, MAX_USAGE_TIME_PERIOD);
+        self.user_present_timer = if user_is_present {
+            TimedPermission::granted(now, USER_PRESENT_TIME_LIMIT)
+        } else {
+            TimedPermission::waiting()
+        };
+        self.user_verified = true;
+    }
+
+    /// Records that the token was used, which lifts the initial usage time limit.
+    pub fn mark_used(&mut self) {
+        self.used = true;
+    }
+
+    /// Expires the flags and stops the token when its timers run out.
+    ///
+    /// Must be called regularly, for the same reasons as `TimedPermission::check_expiration`.
+    pub fn pin_uv_auth_token_usage_timer_observer(&mut self, now: ClockValue) {
+        if !self.in_use {
+            return;
+        }
+        self.user_present_timer = self.user_present_timer.check_expiration(now);
+        self.initial_usage_timer = self.initial_usage_timer.check_expiration(now);
+        self.max_usage_timer = self.max_usage_timer.check_expiration(now);
+        let initial_usage_expired = !self.used && !self.initial_usage_timer.is_granted(now);
+        if initial_usage_expired || !self.max_usage_timer.is_granted(now) {
+            self.stop_using_pin_uv_auth_token();
+        }
+    }
+
+    pub fn is_in_use(&self) -> bool {
+        self.in_use
+    }
+
+    pub fn get_user_present_flag_value(&self) -> bool {
+        match self.user_present_timer {
+            TimedPermission::Granted(_) => self.in_use,
+            TimedPermission::Waiting => false,
+        }
+    }
+
+    pub fn get_user_verified_flag_value(&self) -> bool {
+        self.in_use && self.user_verified
+    }
+
+    pub fn clear_user_present_flag(&mut self) {
+        self.user_present_timer = TimedPermission::waiting();
+    }
+
+    pub fn clear_user_verified_flag(&mut self) {
+        self.user_verified = false;
+    }
+
+    pub fn stop_using_pin_uv_auth_token(&mut self) {
+        *self = PinUvAuthTokenState::new();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLOCK_FREQUENCY_HZ: usize = 32768;
+    const START_CLOCK_VALUE: ClockValue = ClockValue::new(0, CLOCK_FREQUENCY_HZ);
+
+    fn after_ms(ms: isize) -> ClockValue {
+        START_CLOCK_VALUE.wrapping_add(Duration::from_ms(ms))
+    }
+
+    #[test]
+    fn test_begin_and_stop_using() {
+        let mut token_state = PinUvAuthTokenState::new();
+        assert!(!token_state.is_in_use());
+        assert!(!token_state.get_user_present_flag_value());
+        assert!(!token_state.get_user_verified_flag_value());
+
+        token_state.begin_using_pin_uv_auth_token(true, START_CLOCK_VALUE);
+        assert!(token_state.is_in_use());
+        assert!(token_state.get_user_present_flag_value());
+        assert!(token_state.get_user_verified_flag_value());
+
+        token_state.stop_using_pin_uv_auth_token();
+        assert!(!token_state.is_in_use());
+        assert!(!token_state.get_user_present_flag_value());
+        assert!(!token_state.get_user_verified_flag_value());
+    }
+
+    #[test]
+    fn test_clear_flags() {
+        let mut token_state = PinUvAuthTokenState::new();
+        token_state.begin_using_pin_uv_auth_token(true, START_CLOCK_VALUE);
+        token_state.clear_user_present_flag();
+        assert!(!token_state.get_user_present_flag_value());
+        assert!(token_state.get_user_verified_flag_value());
+        token_state.clear_user_verified_flag();
+        assert!(!token_state.get_user_verified_flag_value());
+        assert!(token_state.is_in_use());
+    }
+
+    #[test]
+    fn test_user_present_flag_expires() {
+        let mut token_state = PinUvAuthTokenState::new();
+        token_state.begin_using_pin_uv_auth_token(true, START_CLOCK_VALUE);
+        token_state.mark_used();
+        token_state.pin_uv_auth_token_usage_timer_observer(after_ms(29000));
+        assert!(token_state.get_user_present_flag_value());
+        token_state.pin_uv_auth_token_usage_timer_observer(after_ms(31000));
+        assert!(!token_state.get_user_present_flag_value());
+        assert!(token_state.get_user_verified_flag_value());
+    }
+
+    #[test]
+    fn test_initial_usage_time_limit() {
+        let mut token_state = PinUvAuthTokenState::new();
+        token_state.begin_using_pin_uv_auth_token(false, START_CLOCK_VALUE);
+        token_state.pin_uv_auth_token_usage_timer_observer(after_ms(29000));
+        assert!(token_state.is_in_use());
+        token_state.pin_uv_auth_token_usage_timer_observer(after_ms(31000));
+        assert!(!token_state.is_in_use());
+
+        token_state.begin_using_pin_uv_auth_token(false, START_CLOCK_VALUE);
+        token_state.mark_used();
+        token_state.pin_uv_auth_token_usage_timer_observer(after_ms(31000));
+        assert!(token_state.is_in_use());
+    }
+
+    #[test]
+    fn test_max_usage_time_period() {
+        let mut token_state = PinUvAuthTokenState::new();
+        token_state.begin_using_pin_uv_auth_token(false, START_CLOCK_VALUE);
+        token_state.mark_used();
+        token_state.pin_uv_auth_token_usage_timer_observer(after_ms(599000));
+        assert!(token_state.is_in_use());
+        token_state.pin_uv_auth_token_usage_timer_observer(after_ms(601000));
+        assert!(!token_state.is_in_use());
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::timed_permission::TimedPermission;
+use libtock_drivers::timer::{ClockValue, Duration};
+
+/// A source of user presence, e.g. a capacitive sensor.
+///
+/// Sources are OR'd together: the user is present as soon as one of the sources reports it. The
+/// touch buttons are always a source. Sources are polled while waiting for the user, i.e. when a
+/// button or timer callback wakes the application up.
+pub trait UserPresenceSource {
+    /// Starts listening for user presence.
+    fn enable(&mut self) {}
+
+    /// Returns whether the user was present since the source was enabled.
+    fn is_present(&self) -> bool;
+
+    /// Returns whether the user declined since the source was enabled.
+    ///
+    /// A decline answers immediately instead of waiting for the timeout. Sources that can't tell
+    /// a decline apart, e.g. a simple sensor, never decline.
+    fn is_declined(&self) -> bool {
+        false
+    }
+
+    /// Stops listening for user presence.
+    fn disable(&mut self) {}
+}
+
+/// A decision of the user, made with the buttons or a user presence source.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub enum Gesture {
+    /// The user pressed once to approve.
+    Confirm,
+    /// The user pressed twice in a row or explicitly declined.
+    Deny,
+}
+
+/// Recognizes gestures from button presses.
+///
+/// A single press confirms and a double press denies. To tell them apart, a press only confirms
+/// once the double press window has passed without a second press.
+pub struct GestureDetector {
+    double_press_window: Duration<isize>,
+    // Whether the user pressed at all.
+    pressed: bool,
+    // Open during the double press window after the first press.
+    second_press: TimedPermission,
+    denied: bool,
+}
+
+impl GestureDetector {
+    pub fn new(double_press_window: Duration<isize>) -> GestureDetector {
+        GestureDetector {
+            double_press_window,
+            pressed: false,
+            second_press: TimedPermission::waiting(),
+            denied: false,
+        }
+    }
+
+    /// Records a button press at time `now`.
+    pub fn press(&mut self, now: ClockValue) {
+        if self.second_press.is_granted(now) {
+            self.denied = true;
+        } else if !self.pressed {
+            self.pressed = true;
+            self.second_press = TimedPermission::granted(now, self.double_press_window);
+        }
+    }
+
+    /// Records an explicit decline, e.g. from a dedicated button.
+    pub fn decline(&mut self) {
+        self.denied = true;
+    }
+
+    /// Returns the gesture of the user at time `now`, if it is complete.
+    pub fn gesture(&self, now: ClockValue) -> Option<Gesture> {
+        if self.denied {
+            Some(Gesture::Deny)
+        } else if self.pressed && !self.second_press.is_granted(now) {
+            Some(Gesture::Confirm)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the gesture of the user when waiting stops.
+    ///
+    /// A press still waiting for a second one confirms.
+    pub fn finish(&self) -> Option<Gesture> {
+        if self.denied {
+            Some(Gesture::Deny)
+        } else if self.pressed {
+            Some(Gesture::Confirm)
+        } else {
+            None
+        }
+    }
+}
+
+/// User presence granted in advance by a vendor command.
+///
+/// This source is meant for hardware-in-the-loop tests, where nobody is there to touch the device.
+/// Each grant satisfies exactly one user presence check.
+#[cfg(feature = "debug_ctap")]
+#[derive(Default)]
+pub struct DebugUserPresence {
+    grants: u8,
+}
+
+#[cfg(feature = "debug_ctap")]
+impl DebugUserPresence {
+    /// Grants user presence for the next check.
+    pub fn grant(&mut self) {
+        self.grants = self.grants.saturating_add(1);
+    }
+
+    /// Consumes a grant if there is one.
+    pub fn consume(&mut self) -> bool {
+        if self.grants == 0 {
+            return false;
+        }
+        self.grants -= 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CLOCK_FREQUENCY_HZ: usize = 32768;
+    const DOUBLE_PRESS_WINDOW: Duration<isize> = Duration::from_ms(500);
+
+    fn clock_at(ms: isize) -> ClockValue {
+        ClockValue::new(ms * CLOCK_FREQUENCY_HZ as isize / 1000, CLOCK_FREQUENCY_HZ)
+    }
+
+    #[test]
+    fn test_gesture_confirm() {
+        let mut detector = GestureDetector::new(DOUBLE_PRESS_WINDOW);
+        assert_eq!(detector.gesture(clock_at(100)), None);
+        assert_eq!(detector.finish(), None);
+        detector.press(clock_at(100));
+        assert_eq!(detector.gesture(clock_at(300)), None);
+        assert_eq!(detector.gesture(clock_at(700)), Some(Gesture::Confirm));
+        // Later presses don't change the decision.
+        detector.press(clock_at(800));
+        assert_eq!(detector.gesture(clock_at(800)), Some(Gesture::Confirm));
+    }
+
+    #[test]
+    fn test_gesture_deny() {
+        let mut detector = GestureDetector::new(DOUBLE_PRESS_WINDOW);
+        detector.press(clock_at(100));
+        detector.press(clock_at(400));
+        assert_eq!(detector.gesture(clock_at(400)), Some(Gesture::Deny));
+        assert_eq!(detector.finish(), Some(Gesture::Deny));
+    }
+
+    #[test]
+    fn test_gesture_decline() {
+        let mut detector = GestureDetector::new(DOUBLE_PRESS_WINDOW);
+        detector.decline();
+        assert_eq!(detector.gesture(clock_at(100)), Some(Gesture::Deny));
+
+        // A decline wins over a pending press.
+        let mut detector = GestureDetector::new(DOUBLE_PRESS_WINDOW);
+        detector.press(clock_at(100));
+        detector.decline();
+        assert_eq!(detector.gesture(clock_at(200)), Some(Gesture::Deny));
+        assert_eq!(detector.finish(), Some(Gesture::Deny));
+    }
+
+    #[test]
+    fn test_gesture_finish_pending_press() {
+        let mut detector = GestureDetector::new(DOUBLE_PRESS_WINDOW);
+        detector.press(clock_at(100));
+        assert_eq!(detector.gesture(clock_at(200)), None);
+        assert_eq!(detector.finish(), Some(Gesture::Confirm));
+    }
+
+    #[cfg(feature = "debug_ctap")]
+    #[test]
+    fn test_debug_user_presence() {
+        let mut user_presence = DebugUserPresence::default();
+        assert!(!user_presence.consume());
+        user_presence.grant();
+        user_presence.grant();
+        assert!(user_presence.consume());
+        assert!(user_presence.consume());
+        assert!(!user_presence.consume());
+    }
+}
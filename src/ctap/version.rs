@@ -0,0 +1,98 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// CTAP2 protocol versions, in increasing order.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(any(test, feature = "debug_ctap"), derive(Debug))]
+pub enum CtapVersion {
+    Fido2_0,
+    Fido2_1Pre,
+    Fido2_1,
+}
+
+impl CtapVersion {
+    const ALL: [CtapVersion; 3] = [
+        CtapVersion::Fido2_0,
+        CtapVersion::Fido2_1Pre,
+        CtapVersion::Fido2_1,
+    ];
+
+    /// Returns the version string reported in GetInfo.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CtapVersion::Fido2_0 => "FIDO_2_0",
+            CtapVersion::Fido2_1Pre => "FIDO_2_1_PRE",
+            CtapVersion::Fido2_1 => "FIDO_2_1",
+        }
+    }
+}
+
+/// The latest CTAP2 version implemented by the authenticator.
+///
+/// This is the only place where the version is derived from the features. The advertised
+/// versions, the accepted commands and the reported options all follow from it.
+#[cfg(not(feature = "with_ctap2_1"))]
+pub const CTAP_VERSION: CtapVersion = CtapVersion::Fido2_0;
+// TODO(#106) change to the final version when ready
+#[cfg(feature = "with_ctap2_1")]
+pub const CTAP_VERSION: CtapVersion = CtapVersion::Fido2_1Pre;
+
+/// Returns whether the authenticator implements a version.
+pub fn is_supported(version: CtapVersion) -> bool {
+    version <= CTAP_VERSION
+}
+
+/// Returns the CTAP2 version strings to advertise in GetInfo, in increasing order.
+pub fn ctap_versions() -> Vec<String> {
+    CtapVersion::ALL
+        .iter()
+        .filter(|&&version| is_supported(version))
+        .map(|version| String::from(version.as_str()))
+        .collect()
+}
+
+/// Returns the version introducing an option reported in GetInfo.
+pub fn option_version(option: &str) -> CtapVersion {
+    match option {
+        "alwaysUv" | "authnrCfg" | "largeBlobs" | "makeCredUvNotRqd" => CtapVersion::Fido2_1Pre,
+        _ => CtapVersion::Fido2_0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ctap_versions() {
+        let versions = ctap_versions();
+        assert_eq!(versions[0], "FIDO_2_0");
+        assert_eq!(versions.last().unwrap(), CTAP_VERSION.as_str());
+        #[cfg(feature = "with_ctap2_1")]
+        assert_eq!(versions, vec!["FIDO_2_0", "FIDO_2_1_PRE"]);
+        #[cfg(not(feature = "with_ctap2_1"))]
+        assert_eq!(versions, vec!["FIDO_2_0"]);
+    }
+
+    #[test]
+    fn test_option_version() {
+        assert!(is_supported(option_version("rk")));
+        assert!(is_supported(option_version("clientPin")));
+        assert_eq!(option_version("largeBlobs"), CtapVersion::Fido2_1Pre);
+        assert!(!is_supported(CtapVersion::Fido2_1));
+    }
+}
@@ -0,0 +1,25 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Resets the device when the firmware stops making progress.
+///
+/// A hung call, for example into the storage or the cryptography, would otherwise leave the device
+/// unresponsive until it is unplugged. The watchdog resets the device if it is not petted within
+/// its period. The main loop pets it at each iteration. Long operations pet it right before they
+/// start, so that each of them gets the full period. The period must be longer than the slowest
+/// of them, like a compaction of the store or a key generation.
+pub trait Watchdog {
+    /// Restarts the countdown.
+    fn pet(&mut self);
+}
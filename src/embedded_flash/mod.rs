@@ -12,19 +12,19 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-#[cfg(not(feature = "std"))]
 mod syscall;
 
 #[cfg(not(feature = "std"))]
-pub use self::syscall::SyscallStorage;
+pub use self::syscall::TockSyscalls;
+pub use self::syscall::{StorageSyscalls, SyscallStorage};
 
 /// Storage definition for production.
 #[cfg(not(feature = "std"))]
 mod prod {
-    pub type Storage = super::SyscallStorage;
+    pub type Storage = super::SyscallStorage<super::TockSyscalls>;
 
-    pub fn new_storage(num_pages: usize) -> Storage {
-        Storage::new(num_pages).unwrap()
+    pub fn new_storage(max_pages: usize) -> Storage {
+        Storage::with_max_pages(super::TockSyscalls, max_pages).unwrap()
     }
 }
 #[cfg(not(feature = "std"))]
@@ -35,8 +35,11 @@ pub use self::prod::{new_storage, Storage};
 mod test {
     pub type Storage = persistent_store::BufferStorage;
 
-    pub fn new_storage(num_pages: usize) -> Storage {
+    pub fn new_storage(max_pages: usize) -> Storage {
         const PAGE_SIZE: usize = 0x1000;
+        // The simulated storage locations have as many pages as the smallest supported board.
+        const NUM_PAGES: usize = 20;
+        let num_pages = core::cmp::min(max_pages, NUM_PAGES);
         let store = vec![0xff; num_pages * PAGE_SIZE].into_boxed_slice();
         let options = persistent_store::BufferOptions {
             word_size: 4,
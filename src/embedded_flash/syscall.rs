@@ -13,9 +13,11 @@
 // limitations under the License.
 
 use alloc::vec::Vec;
+#[cfg(not(feature = "std"))]
 use libtock_core::syscalls;
 use persistent_store::{Storage, StorageError, StorageIndex, StorageResult};
 
+#[cfg(not(feature = "std"))]
 const DRIVER_NUMBER: usize = 0x50003;
 
 mod command_nr {
@@ -40,21 +42,123 @@ mod memop_nr {
     pub const STORAGE_LEN: u32 = 14;
 }
 
-fn get_info(nr: usize, arg: usize) -> StorageResult<usize> {
-    let code = syscalls::command(DRIVER_NUMBER, command_nr::GET_INFO, nr, arg);
-    code.map_err(|_| StorageError::CustomError)
+/// Return codes of the kernel for errors that may be transient.
+mod return_code {
+    pub const FAIL: isize = -1;
+    pub const EBUSY: isize = -2;
+    pub const ENOMEM: isize = -9;
 }
 
-fn memop(nr: u32, arg: usize) -> StorageResult<usize> {
-    let code = unsafe { syscalls::raw::memop(nr, arg) };
+/// Maximum number of attempts of a syscall failing with a transient error.
+const MAX_ATTEMPTS: usize = 4;
+
+/// Abstracts the syscalls of the storage driver.
+///
+/// Errors are the negative return codes of the kernel. This permits to test the storage on the
+/// host, in particular how it handles kernel errors.
+pub trait StorageSyscalls {
+    /// Sends a command to the storage driver.
+    fn command(&mut self, nr: usize, arg1: usize, arg2: usize) -> Result<usize, isize>;
+
+    /// Shares a slice with the storage driver.
+    ///
+    /// The driver must not write to the slice.
+    fn allow(&mut self, nr: usize, slice: &[u8]) -> Result<(), isize>;
+
+    /// Executes a memory operation.
+    fn memop(&mut self, nr: u32, arg: usize) -> Result<usize, isize>;
+
+    /// Waits before the next attempt of a failed syscall.
+    ///
+    /// The `attempt` is the number of failed attempts so far. It starts at 1.
+    fn backoff(&mut self, attempt: usize);
+}
+
+/// The syscalls of the Tock kernel.
+#[cfg(not(feature = "std"))]
+pub struct TockSyscalls;
+
+#[cfg(not(feature = "std"))]
+fn to_result(code: isize) -> Result<usize, isize> {
     if code < 0 {
-        Err(StorageError::CustomError)
+        Err(code)
     } else {
         Ok(code as usize)
     }
 }
 
-pub struct SyscallStorage {
+#[cfg(not(feature = "std"))]
+impl StorageSyscalls for TockSyscalls {
+    fn command(&mut self, nr: usize, arg1: usize, arg2: usize) -> Result<usize, isize> {
+        to_result(unsafe { syscalls::raw::command(DRIVER_NUMBER, nr, arg1, arg2) })
+    }
+
+    fn allow(&mut self, nr: usize, slice: &[u8]) -> Result<(), isize> {
+        let code = unsafe {
+            syscalls::raw::allow(
+                DRIVER_NUMBER,
+                nr,
+                // We rely on the driver not writing to the slice. This should use read-only allow
+                // when available. See https://github.com/tock/tock/issues/1274.
+                slice.as_ptr() as *mut u8,
+                slice.len(),
+            )
+        };
+        to_result(code).map(|_| ())
+    }
+
+    fn memop(&mut self, nr: u32, arg: usize) -> Result<usize, isize> {
+        to_result(unsafe { syscalls::raw::memop(nr, arg) })
+    }
+
+    fn backoff(&mut self, attempt: usize) {
+        // There is no timer at this level, so we spin for an exponentially growing time.
+        for _ in 0..1000 << attempt {
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+}
+
+/// Returns whether a syscall failing with this code may succeed when retried.
+fn is_transient(code: isize) -> bool {
+    code == return_code::FAIL || code == return_code::EBUSY || code == return_code::ENOMEM
+}
+
+/// Executes a syscall, retrying it while it fails with a transient error.
+///
+/// This is the only place where kernel errors are retried. The operation must be idempotent. This
+/// holds for the storage driver, since it only writes words that need to be written.
+fn retry<S: StorageSyscalls, T>(
+    syscalls: &mut S,
+    mut operation: impl FnMut(&mut S) -> Result<T, isize>,
+) -> StorageResult<T> {
+    let mut attempt = 0;
+    loop {
+        match operation(syscalls) {
+            Ok(result) => return Ok(result),
+            Err(code) => {
+                attempt += 1;
+                if !is_transient(code) || attempt >= MAX_ATTEMPTS {
+                    return Err(StorageError::CustomError);
+                }
+                syscalls.backoff(attempt);
+            }
+        }
+    }
+}
+
+fn get_info<S: StorageSyscalls>(syscalls: &mut S, nr: usize) -> StorageResult<usize> {
+    retry(syscalls, |syscalls| {
+        syscalls.command(command_nr::GET_INFO, nr, 0)
+    })
+}
+
+fn memop<S: StorageSyscalls>(syscalls: &mut S, nr: u32, arg: usize) -> StorageResult<usize> {
+    retry(syscalls, |syscalls| syscalls.memop(nr, arg))
+}
+
+pub struct SyscallStorage<S: StorageSyscalls> {
+    syscalls: S,
     word_size: usize,
     page_size: usize,
     num_pages: usize,
@@ -63,7 +167,7 @@ pub struct SyscallStorage {
     storage_locations: Vec<&'static [u8]>,
 }
 
-impl SyscallStorage {
+impl<S: StorageSyscalls> SyscallStorage<S> {
     /// Provides access to the embedded flash if available.
     ///
     /// # Errors
@@ -75,13 +179,18 @@ impl SyscallStorage {
     /// - The storage is page-aligned.
     ///
     /// Returns `OutOfBounds` the number of pages does not fit in the storage.
-    pub fn new(mut num_pages: usize) -> StorageResult<SyscallStorage> {
+    pub fn new(mut syscalls: S, mut num_pages: usize) -> StorageResult<SyscallStorage<S>> {
+        let word_size = get_info(&mut syscalls, command_nr::get_info_nr::WORD_SIZE)?;
+        let page_size = get_info(&mut syscalls, command_nr::get_info_nr::PAGE_SIZE)?;
+        let max_word_writes = get_info(&mut syscalls, command_nr::get_info_nr::MAX_WORD_WRITES)?;
+        let max_page_erases = get_info(&mut syscalls, command_nr::get_info_nr::MAX_PAGE_ERASES)?;
         let mut syscall = SyscallStorage {
-            word_size: get_info(command_nr::get_info_nr::WORD_SIZE, 0)?,
-            page_size: get_info(command_nr::get_info_nr::PAGE_SIZE, 0)?,
+            syscalls,
+            word_size,
+            page_size,
             num_pages,
-            max_word_writes: get_info(command_nr::get_info_nr::MAX_WORD_WRITES, 0)?,
-            max_page_erases: get_info(command_nr::get_info_nr::MAX_PAGE_ERASES, 0)?,
+            max_word_writes,
+            max_page_erases,
             storage_locations: Vec::new(),
         };
         if !syscall.word_size.is_power_of_two()
@@ -90,9 +199,9 @@ impl SyscallStorage {
         {
             return Err(StorageError::CustomError);
         }
-        for i in 0..memop(memop_nr::STORAGE_CNT, 0)? {
-            let storage_ptr = memop(memop_nr::STORAGE_PTR, i)?;
-            let max_storage_len = memop(memop_nr::STORAGE_LEN, i)?;
+        for i in 0..memop(&mut syscall.syscalls, memop_nr::STORAGE_CNT, 0)? {
+            let storage_ptr = memop(&mut syscall.syscalls, memop_nr::STORAGE_PTR, i)?;
+            let max_storage_len = memop(&mut syscall.syscalls, memop_nr::STORAGE_LEN, i)?;
             if !syscall.is_page_aligned(storage_ptr) || !syscall.is_page_aligned(max_storage_len) {
                 return Err(StorageError::CustomError);
             }
@@ -109,6 +218,22 @@ impl SyscallStorage {
         Ok(syscall)
     }
 
+    /// Provides access to all the pages of the embedded flash, up to a maximum.
+    ///
+    /// The number of pages is the one of the storage locations provided by the kernel, such that
+    /// boards with different flash layouts get the largest storage. The errors are those of `new`.
+    pub fn with_max_pages(mut syscalls: S, max_pages: usize) -> StorageResult<SyscallStorage<S>> {
+        let page_size = get_info(&mut syscalls, command_nr::get_info_nr::PAGE_SIZE)?;
+        if !page_size.is_power_of_two() {
+            return Err(StorageError::CustomError);
+        }
+        let mut num_pages = 0;
+        for i in 0..memop(&mut syscalls, memop_nr::STORAGE_CNT, 0)? {
+            num_pages += memop(&mut syscalls, memop_nr::STORAGE_LEN, i)? / page_size;
+        }
+        SyscallStorage::new(syscalls, core::cmp::min(num_pages, max_pages))
+    }
+
     fn is_word_aligned(&self, x: usize) -> bool {
         x & (self.word_size - 1) == 0
     }
@@ -118,7 +243,7 @@ impl SyscallStorage {
     }
 }
 
-impl Storage for SyscallStorage {
+impl<S: StorageSyscalls> Storage for SyscallStorage<S> {
     fn word_size(&self) -> usize {
         self.word_size
     }
@@ -149,26 +274,11 @@ impl Storage for SyscallStorage {
             return Err(StorageError::NotAligned);
         }
         let ptr = self.read_slice(index, value.len())?.as_ptr() as usize;
-
-        let code = unsafe {
-            syscalls::raw::allow(
-                DRIVER_NUMBER,
-                allow_nr::WRITE_SLICE,
-                // We rely on the driver not writing to the slice. This should use read-only allow
-                // when available. See https://github.com/tock/tock/issues/1274.
-                value.as_ptr() as *mut u8,
-                value.len(),
-            )
-        };
-        if code < 0 {
-            return Err(StorageError::CustomError);
-        }
-
-        let code = syscalls::command(DRIVER_NUMBER, command_nr::WRITE_SLICE, ptr, value.len());
-        if code.is_err() {
-            return Err(StorageError::CustomError);
-        }
-
+        // The driver consumes the allowed slice, so it is allowed again for each attempt.
+        retry(&mut self.syscalls, |syscalls| {
+            syscalls.allow(allow_nr::WRITE_SLICE, value)?;
+            syscalls.command(command_nr::WRITE_SLICE, ptr, value.len())
+        })?;
         Ok(())
     }
 
@@ -176,10 +286,9 @@ impl Storage for SyscallStorage {
         let index = StorageIndex { page, byte: 0 };
         let length = self.page_size();
         let ptr = self.read_slice(index, length)?.as_ptr() as usize;
-        let code = syscalls::command(DRIVER_NUMBER, command_nr::ERASE_PAGE, ptr, length);
-        if code.is_err() {
-            return Err(StorageError::CustomError);
-        }
+        retry(&mut self.syscalls, |syscalls| {
+            syscalls.command(command_nr::ERASE_PAGE, ptr, length)
+        })?;
         Ok(())
     }
 }
@@ -205,6 +314,205 @@ fn find_slice<'a>(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use alloc::collections::VecDeque;
+
+    const WORD_SIZE: usize = 4;
+    const PAGE_SIZE: usize = 0x100;
+    const NUM_PAGES: usize = 4;
+    const EINVAL: isize = -6;
+
+    /// Simulates the storage driver of the kernel.
+    struct FakeSyscalls {
+        /// The flash, with an extra page to align the storage location.
+        flash: Vec<u8>,
+
+        /// The slice allowed for the next write.
+        slice: Option<Vec<u8>>,
+
+        /// Errors returned by the next commands.
+        errors: VecDeque<isize>,
+
+        /// Whether every other command fails with a transient error.
+        flaky: bool,
+
+        /// Whether the last command failed because of `flaky`.
+        flaked: bool,
+
+        /// The attempts passed to each backoff.
+        backoffs: Vec<usize>,
+    }
+
+    impl FakeSyscalls {
+        fn new() -> FakeSyscalls {
+            FakeSyscalls {
+                flash: vec![0xff; (NUM_PAGES + 1) * PAGE_SIZE],
+                slice: None,
+                errors: VecDeque::new(),
+                flaky: false,
+                flaked: false,
+                backoffs: Vec::new(),
+            }
+        }
+
+        /// Returns the address of the storage location.
+        fn storage_ptr(&self) -> usize {
+            let ptr = self.flash.as_ptr() as usize;
+            (ptr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+        }
+
+        /// Returns the flash of the storage location at a given address.
+        fn location(&mut self, ptr: usize, len: usize) -> &mut [u8] {
+            let start = ptr - self.flash.as_ptr() as usize;
+            &mut self.flash[start..][..len]
+        }
+    }
+
+    impl StorageSyscalls for FakeSyscalls {
+        fn command(&mut self, nr: usize, arg1: usize, arg2: usize) -> Result<usize, isize> {
+            if let Some(code) = self.errors.pop_front() {
+                return Err(code);
+            }
+            self.flaked = self.flaky && !self.flaked;
+            if self.flaked {
+                return Err(return_code::EBUSY);
+            }
+            match (nr, arg1) {
+                (command_nr::GET_INFO, command_nr::get_info_nr::WORD_SIZE) => Ok(WORD_SIZE),
+                (command_nr::GET_INFO, command_nr::get_info_nr::PAGE_SIZE) => Ok(PAGE_SIZE),
+                (command_nr::GET_INFO, command_nr::get_info_nr::MAX_WORD_WRITES) => Ok(2),
+                (command_nr::GET_INFO, command_nr::get_info_nr::MAX_PAGE_ERASES) => Ok(10000),
+                (command_nr::WRITE_SLICE, ptr) => {
+                    let slice = self.slice.take().ok_or(EINVAL)?;
+                    if slice.len() != arg2 {
+                        return Err(EINVAL);
+                    }
+                    for (byte, value) in self.location(ptr, arg2).iter_mut().zip(slice) {
+                        *byte &= value;
+                    }
+                    Ok(0)
+                }
+                (command_nr::ERASE_PAGE, ptr) => {
+                    for byte in self.location(ptr, arg2) {
+                        *byte = 0xff;
+                    }
+                    Ok(0)
+                }
+                _ => Err(EINVAL),
+            }
+        }
+
+        fn allow(&mut self, nr: usize, slice: &[u8]) -> Result<(), isize> {
+            assert_eq!(nr, allow_nr::WRITE_SLICE);
+            self.slice = Some(slice.to_vec());
+            Ok(())
+        }
+
+        fn memop(&mut self, nr: u32, arg: usize) -> Result<usize, isize> {
+            match nr {
+                memop_nr::STORAGE_CNT => Ok(1),
+                memop_nr::STORAGE_PTR if arg == 0 => Ok(self.storage_ptr()),
+                memop_nr::STORAGE_LEN if arg == 0 => Ok(NUM_PAGES * PAGE_SIZE),
+                _ => Err(EINVAL),
+            }
+        }
+
+        fn backoff(&mut self, attempt: usize) {
+            self.backoffs.push(attempt);
+        }
+    }
+
+    fn new_storage() -> SyscallStorage<FakeSyscalls> {
+        SyscallStorage::new(FakeSyscalls::new(), NUM_PAGES).unwrap()
+    }
+
+    #[test]
+    fn storage_ok() {
+        let mut storage = new_storage();
+        assert_eq!(storage.word_size(), WORD_SIZE);
+        assert_eq!(storage.page_size(), PAGE_SIZE);
+        assert_eq!(storage.num_pages(), NUM_PAGES);
+        let index = StorageIndex { page: 1, byte: 4 };
+        storage.write_slice(index, &[0x5c; 8]).unwrap();
+        assert_eq!(storage.read_slice(index, 8).unwrap(), &[0x5c; 8]);
+        storage.erase_page(1).unwrap();
+        assert_eq!(storage.read_slice(index, 8).unwrap(), &[0xff; 8]);
+        assert!(storage.syscalls.backoffs.is_empty());
+    }
+
+    #[test]
+    fn too_many_pages() {
+        assert_eq!(
+            SyscallStorage::new(FakeSyscalls::new(), NUM_PAGES + 1).err(),
+            Some(StorageError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn with_max_pages_ok() {
+        let storage = SyscallStorage::with_max_pages(FakeSyscalls::new(), 64).unwrap();
+        assert_eq!(storage.num_pages(), NUM_PAGES);
+        let storage = SyscallStorage::with_max_pages(FakeSyscalls::new(), NUM_PAGES - 1).unwrap();
+        assert_eq!(storage.num_pages(), NUM_PAGES - 1);
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        let mut storage = new_storage();
+        let index = StorageIndex { page: 0, byte: 0 };
+        storage
+            .syscalls
+            .errors
+            .extend(&[return_code::EBUSY, return_code::FAIL]);
+        storage.write_slice(index, &[0x5c; 4]).unwrap();
+        assert_eq!(storage.read_slice(index, 4).unwrap(), &[0x5c; 4]);
+        assert_eq!(storage.syscalls.backoffs, vec![1, 2]);
+
+        storage.syscalls.backoffs.clear();
+        storage.syscalls.errors.push_back(return_code::ENOMEM);
+        storage.erase_page(0).unwrap();
+        assert_eq!(storage.read_slice(index, 4).unwrap(), &[0xff; 4]);
+        assert_eq!(storage.syscalls.backoffs, vec![1]);
+    }
+
+    #[test]
+    fn persistent_errors_are_not_retried() {
+        let mut storage = new_storage();
+        let index = StorageIndex { page: 0, byte: 0 };
+        storage.syscalls.errors.push_back(EINVAL);
+        assert_eq!(
+            storage.write_slice(index, &[0x5c; 4]),
+            Err(StorageError::CustomError)
+        );
+        assert_eq!(storage.read_slice(index, 4).unwrap(), &[0xff; 4]);
+        assert!(storage.syscalls.backoffs.is_empty());
+    }
+
+    #[test]
+    fn retries_are_bounded() {
+        let mut storage = new_storage();
+        storage
+            .syscalls
+            .errors
+            .extend(&[return_code::EBUSY; MAX_ATTEMPTS + 1]);
+        assert_eq!(storage.erase_page(0), Err(StorageError::CustomError));
+        assert_eq!(storage.syscalls.backoffs.len(), MAX_ATTEMPTS - 1);
+        // Only the attempts of the failed operation consumed errors.
+        assert_eq!(storage.syscalls.errors.len(), 1);
+    }
+
+    #[test]
+    fn store_with_transient_errors() {
+        let mut syscalls = FakeSyscalls::new();
+        syscalls.flaky = true;
+        let storage = SyscallStorage::new(syscalls, NUM_PAGES).unwrap();
+        let mut store = persistent_store::Store::new(storage).ok().unwrap();
+        for key in 0..10 {
+            store.insert(key, &[key as u8; 12]).unwrap();
+        }
+        for key in 0..10 {
+            assert_eq!(store.find(key).unwrap(), Some(vec![key as u8; 12]));
+        }
+    }
 
     #[test]
     fn find_slice_ok() {
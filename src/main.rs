@@ -25,12 +25,21 @@ extern crate byteorder;
 mod ctap;
 pub mod embedded_flash;
 
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 use core::cell::Cell;
 #[cfg(feature = "debug_ctap")]
 use core::fmt::Write;
-use crypto::rng256::TockRng256;
+use crypto::rng256::{HealthCheckedRng256, TockRng256};
+use ctap::display::Display;
+use ctap::hid::send::HidPacketIterator;
 use ctap::hid::{ChannelID, CtapHid, KeepaliveStatus, ProcessedPacket};
+#[cfg(feature = "perf")]
+use ctap::perf::PerfClock;
+use ctap::pin_policy::PinPolicy;
 use ctap::status_code::Ctap2StatusCode;
+use ctap::user_presence::{Gesture, GestureDetector, UserPresenceSource};
+use ctap::watchdog::Watchdog;
 use ctap::CtapState;
 use libtock_core::result::{CommandError, EALREADY};
 use libtock_drivers::buttons;
@@ -40,16 +49,31 @@ use libtock_drivers::console::Console;
 use libtock_drivers::led;
 use libtock_drivers::result::{FlexUnwrap, TockError};
 use libtock_drivers::timer;
-use libtock_drivers::timer::Duration;
-#[cfg(feature = "debug_ctap")]
-use libtock_drivers::timer::Timer;
 #[cfg(feature = "debug_ctap")]
 use libtock_drivers::timer::Timestamp;
+use libtock_drivers::timer::{ClockValue, Duration, Timer};
 use libtock_drivers::usb_ctap_hid;
 
 const KEEPALIVE_DELAY_MS: isize = 100;
 const KEEPALIVE_DELAY: Duration<isize> = Duration::from_ms(KEEPALIVE_DELAY_MS);
 const SEND_TIMEOUT: Duration<isize> = Duration::from_ms(1000);
+// A second press within this delay denies the request instead of confirming it.
+const DOUBLE_PRESS_WINDOW: Duration<isize> = Duration::from_ms(500);
+// On boards with several buttons, you can dedicate one to decline requests, e.g. Some(1). The other
+// buttons keep confirming.
+const DECLINE_BUTTON: Option<usize> = None;
+// The device resets if the firmware doesn't pet the watchdog for this long. It must be longer than
+// the slowest operation, like a reset of the persistent storage that erases all its pages.
+const WATCHDOG_PERIOD: Duration<isize> = Duration::from_ms(5000);
+// How often the bus state is checked while the host is suspended, instead of receiving packets.
+const SUSPENDED_POLL_DELAY: Duration<isize> = Duration::from_ms(200);
+// How long the main loop sleeps without packets, button presses or LED animation. Waking up less
+// often saves power. It must be shorter than the watchdog period, and much shorter than the period
+// of the wrapping clock, such that timeouts are checked before the clock wraps around.
+const IDLE_WAKEUP_DELAY: Duration<isize> = Duration::from_ms(2000);
+// Whether a touch wakes up the host if it suspended the bus while waiting for the user, e.g. when a
+// laptop sleeps mid-operation. The host must also allow remote wakeup.
+const REMOTE_WAKEUP: bool = true;
 
 fn main() {
     // Setup the timer with a dummy callback (we only care about reading the current time, but the
@@ -57,25 +81,97 @@ fn main() {
     let mut with_callback = timer::with_callback(|_, _| {});
     let timer = with_callback.init().flex_unwrap();
 
-    // Setup USB driver.
-    if !usb_ctap_hid::setup() {
-        panic!("Cannot setup USB driver");
+    // The entropy source is continuously tested. If it looks broken, commands fail and the error
+    // LED is lit until reboot.
+    let mut rng = HealthCheckedRng256::new(TockRng256 {});
+    let mut executor = Executor::new(timer, &mut rng, check_user_presence);
+    executor.run()
+}
+
+/// Owner of the firmware state.
+///
+/// The CTAP state, the HID layer, the timer and the LED animation are only accessed through this
+/// structure. Callbacks borrow local cells for the duration of a single step, so no state is
+/// shared between the main loop and the callbacks.
+struct Executor<'a, CheckUserPresence>
+where
+    CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+{
+    timer: Timer<'a>,
+    ctap_state: CtapState<'a, HealthCheckedRng256<TockRng256>, CheckUserPresence>,
+    ctap_hid: CtapHid,
+    led_counter: usize,
+    last_led_increment: ClockValue,
+    // Whether the host suspended the USB bus at the last step.
+    suspended: bool,
+}
+
+impl<'a, CheckUserPresence> Executor<'a, CheckUserPresence>
+where
+    CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+{
+    fn new(
+        timer: Timer<'a>,
+        rng: &'a mut HealthCheckedRng256<TockRng256>,
+        check_user_presence: CheckUserPresence,
+    ) -> Self {
+        // The watchdog is started first, so that it also covers the boot of the persistent store.
+        let watchdog = watchdog();
+        let boot_time = timer.get_current_clock().flex_unwrap();
+        let mut ctap_state = CtapState::new(rng, check_user_presence, boot_time);
+        if let Some(watchdog) = watchdog {
+            ctap_state.set_watchdog(watchdog);
+        }
+        if let Some(display) = display() {
+            ctap_state.set_display(display);
+        }
+        if let Some(pin_policy) = pin_policy() {
+            ctap_state.set_pin_policy(pin_policy);
+        }
+        #[cfg(feature = "perf")]
+        ctap_state.set_perf_clock(Box::new(TockPerfClock));
+
+        // Setup USB driver. The USB identity may be overridden at provisioning, so it is applied
+        // before connecting. If the kernel doesn't support it, we keep the identity of the image.
+        if let Some(identity) = ctap_state.usb_identity() {
+            let mut serial_number = identity.serial_number.unwrap_or_default().into_bytes();
+            usb_ctap_hid::set_identity(identity.vendor_id, identity.product_id, &mut serial_number);
+        }
+        if !usb_ctap_hid::setup() {
+            panic!("Cannot setup USB driver");
+        }
+
+        Executor {
+            timer,
+            ctap_state,
+            ctap_hid: CtapHid::new(),
+            led_counter: 0,
+            last_led_increment: boot_time,
+            suspended: false,
+        }
     }
 
-    let boot_time = timer.get_current_clock().flex_unwrap();
-    let mut rng = TockRng256 {};
-    let mut ctap_state = CtapState::new(&mut rng, check_user_presence, boot_time);
-    let mut ctap_hid = CtapHid::new();
+    fn run(&mut self) -> ! {
+        loop {
+            self.step();
+        }
+    }
 
-    let mut led_counter = 0;
-    let mut last_led_increment = boot_time;
+    // Runs one iteration of the main loop. The app sleeps in a yield syscall until a packet is
+    // received, a button is pressed for U2F if CTAP1 is used, or the next LED animation frame or
+    // idle wakeup is due. Callbacks only execute during yield syscalls, which is why the LEDs and
+    // keepalives are driven by alarms instead.
+    fn step(&mut self) {
+        self.ctap_state.pet_watchdog();
+
+        // Nothing can be exchanged while the host suspends the bus, so receiving and the LED
+        // animation are paused until it resumes.
+        if self.update_bus_state() {
+            timer::sleep(SUSPENDED_POLL_DELAY).flex_unwrap();
+            return;
+        }
 
-    // Main loop. If CTAP1 is used, we register button presses for U2F while receiving and waiting.
-    // The way TockOS and apps currently interact, callbacks need a yield syscall to execute,
-    // making consistent blinking patterns and sending keepalives harder.
-    loop {
-        // Create the button callback, used for CTAP1.
-        #[cfg(feature = "with_ctap1")]
+        // Create the button callback, used for CTAP1. Without CTAP1, buttons are ignored here.
         let button_touched = Cell::new(false);
         #[cfg(feature = "with_ctap1")]
         let mut buttons_callback = buttons::with_callback(|_button_num, state| {
@@ -92,22 +188,32 @@ fn main() {
             button.enable().flex_unwrap();
         }
 
+        let now = self.timer.get_current_clock().flex_unwrap();
+        let wakeup_delay = if self.is_animating_leds(now) {
+            KEEPALIVE_DELAY
+        } else {
+            IDLE_WAKEUP_DELAY
+        };
         let mut pkt_request = [0; 64];
-        let has_packet = match usb_ctap_hid::recv_with_timeout(&mut pkt_request, KEEPALIVE_DELAY) {
+        let status =
+            usb_ctap_hid::recv_with_timeout_or_event(&mut pkt_request, wakeup_delay, || {
+                button_touched.get()
+            });
+        let has_packet = match status {
             Some(usb_ctap_hid::SendOrRecvStatus::Received) => {
                 #[cfg(feature = "debug_ctap")]
-                print_packet_notice("Received packet", &timer);
+                print_packet_notice("Received packet", &self.timer);
                 true
             }
             Some(_) => panic!("Error receiving packet"),
             None => false,
         };
 
-        let now = timer.get_current_clock().flex_unwrap();
+        let now = self.timer.get_current_clock().flex_unwrap();
         #[cfg(feature = "with_ctap1")]
         {
             if button_touched.get() {
-                ctap_state.u2f_up_state.grant_up(now);
+                self.ctap_state.u2f_up_state.grant_up(now);
             }
             // Cleanup button callbacks. We miss button presses while processing though.
             // Heavy computation mostly follows a registered touch luckily. Unregistering
@@ -121,61 +227,132 @@ fn main() {
 
         // These calls are making sure that even for long inactivity, wrapping clock values
         // never randomly wink or grant user presence for U2F.
-        ctap_state.update_command_permission(now);
-        ctap_hid.wink_permission = ctap_hid.wink_permission.check_expiration(now);
+        self.ctap_state.update_command_permission(now);
+        self.ctap_hid.wink_permission = self.ctap_hid.wink_permission.check_expiration(now);
 
         if has_packet {
-            let reply = ctap_hid.process_hid_packet(&pkt_request, now, &mut ctap_state);
-            // This block handles sending packets.
-            for mut pkt_reply in reply {
-                let status = usb_ctap_hid::send_or_recv_with_timeout(&mut pkt_reply, SEND_TIMEOUT);
-                match status {
-                    None => {
-                        #[cfg(feature = "debug_ctap")]
-                        print_packet_notice("Sending packet timed out", &timer);
-                        // TODO: reset the ctap_hid state.
-                        // Since sending the packet timed out, we cancel this reply.
-                        break;
-                    }
-                    Some(usb_ctap_hid::SendOrRecvStatus::Error) => panic!("Error sending packet"),
-                    Some(usb_ctap_hid::SendOrRecvStatus::Sent) => {
-                        #[cfg(feature = "debug_ctap")]
-                        print_packet_notice("Sent packet", &timer);
+            self.process_packet(&pkt_request, now);
+        }
+
+        let now = self.timer.get_current_clock().flex_unwrap();
+        self.update_leds(now);
+    }
+
+    // Handles the host suspending and resuming the bus. Returns whether the bus is suspended.
+    fn update_bus_state(&mut self) -> bool {
+        let suspended = usb_ctap_hid::is_suspended();
+        if suspended != self.suspended {
+            #[cfg(feature = "debug_ctap")]
+            print_packet_notice(
+                if suspended {
+                    "USB bus suspended"
+                } else {
+                    "USB bus resumed"
+                },
+                &self.timer,
+            );
+            self.suspended = suspended;
+            if suspended {
+                self.ctap_hid.suspend();
+                switch_off_leds();
+            } else {
+                let now = self.timer.get_current_clock().flex_unwrap();
+                let reply = self.ctap_hid.resume();
+                self.send_reply(reply, now);
+            }
+        }
+        suspended
+    }
+
+    // Processes a received packet and sends the reply.
+    fn process_packet(&mut self, pkt_request: &[u8; 64], now: ClockValue) {
+        let reply = self
+            .ctap_hid
+            .process_hid_packet(pkt_request, now, &mut self.ctap_state);
+        self.send_reply(reply, now);
+    }
+
+    // Sends the packets of a reply.
+    fn send_reply(&mut self, reply: HidPacketIterator, now: ClockValue) {
+        #[cfg(not(feature = "debug_ctap"))]
+        let _ = now;
+        for mut pkt_reply in reply {
+            // Each packet may wait for the host until the send timeout.
+            self.ctap_state.pet_watchdog();
+            let status = usb_ctap_hid::send_or_recv_with_timeout(&mut pkt_reply, SEND_TIMEOUT);
+            match status {
+                None => {
+                    #[cfg(feature = "debug_ctap")]
+                    {
+                        print_packet_notice("Sending packet timed out", &self.timer);
+                        self.ctap_hid.record_dropped_packet();
                     }
-                    Some(usb_ctap_hid::SendOrRecvStatus::Received) => {
-                        #[cfg(feature = "debug_ctap")]
-                        print_packet_notice("Received an UNEXPECTED packet", &timer);
-                        // TODO: handle this unexpected packet.
+                    // TODO: reset the ctap_hid state.
+                    // Since sending the packet timed out, we cancel this reply.
+                    break;
+                }
+                Some(usb_ctap_hid::SendOrRecvStatus::Error) => panic!("Error sending packet"),
+                Some(usb_ctap_hid::SendOrRecvStatus::Sent) => {
+                    #[cfg(feature = "debug_ctap")]
+                    {
+                        print_packet_notice("Sent packet", &self.timer);
+                        self.ctap_hid.record_sent_packet(now);
                     }
                 }
+                Some(usb_ctap_hid::SendOrRecvStatus::Received) => {
+                    #[cfg(feature = "debug_ctap")]
+                    print_packet_notice("Received an UNEXPECTED packet", &self.timer);
+                    // TODO: handle this unexpected packet.
+                }
             }
         }
+    }
 
-        let now = timer.get_current_clock().flex_unwrap();
-        if let Some(wait_duration) = now.wrapping_sub(last_led_increment) {
+    // Returns whether the LEDs show an animation, which needs the main loop to wake up for each
+    // frame. Otherwise, the LEDs are static and the main loop sleeps longer.
+    fn is_animating_leds(&mut self, now: ClockValue) -> bool {
+        if !self.ctap_state.is_rng_healthy() {
+            return false;
+        }
+        #[cfg(feature = "with_ctap1")]
+        {
+            if self.ctap_state.u2f_up_state.is_up_needed(now) {
+                return true;
+            }
+        }
+        self.ctap_state.is_identifying(now) || self.ctap_hid.wink_permission.is_granted(now)
+    }
+
+    // Advances the LED animation.
+    fn update_leds(&mut self, now: ClockValue) {
+        if let Some(wait_duration) = now.wrapping_sub(self.last_led_increment) {
             if wait_duration > KEEPALIVE_DELAY {
                 // Loops quickly when waiting for U2F user presence, so the next LED blink
                 // state is only set if enough time has elapsed.
-                led_counter += 1;
-                last_led_increment = now;
+                self.led_counter += 1;
+                self.last_led_increment = now;
             }
         } else {
             // This branch means the clock frequency changed. This should never happen.
-            led_counter += 1;
-            last_led_increment = now;
+            self.led_counter += 1;
+            self.last_led_increment = now;
         }
 
-        if ctap_hid.wink_permission.is_granted(now) {
-            wink_leds(led_counter);
+        if !self.ctap_state.is_rng_healthy() {
+            light_error_led();
+        } else if self.ctap_state.is_identifying(now) {
+            identify_leds(self.led_counter);
+        } else if self.ctap_hid.wink_permission.is_granted(now) {
+            wink_leds(self.led_counter);
         } else {
             #[cfg(not(feature = "with_ctap1"))]
             switch_off_leds();
             #[cfg(feature = "with_ctap1")]
             {
-                if ctap_state.u2f_up_state.is_up_needed(now) {
+                if self.ctap_state.u2f_up_state.is_up_needed(now) {
                     // Flash the LEDs with an almost regular pattern. The inaccuracy comes from
                     // delay caused by processing and sending of packets.
-                    blink_leds(led_counter);
+                    blink_leds(self.led_counter);
                 } else {
                     switch_off_leds();
                 }
@@ -272,6 +449,19 @@ fn blink_leds(pattern_seed: usize) {
     }
 }
 
+fn identify_leds(pattern_seed: usize) {
+    // All LEDs flash twice quickly, then pause, so that the authenticator stands out among others
+    // that wink or blink for user presence.
+    let is_on = matches!(pattern_seed % 10, 0 | 2);
+    for l in 0..led::count().flex_unwrap() {
+        if is_on {
+            led::get(l).flex_unwrap().on().flex_unwrap();
+        } else {
+            led::get(l).flex_unwrap().off().flex_unwrap();
+        }
+    }
+}
+
 fn wink_leds(pattern_seed: usize) {
     // This generates a "snake" pattern circling through the LEDs.
     // Fox example with 4 LEDs the sequence of lit LEDs will be the following.
@@ -305,12 +495,69 @@ fn wink_leds(pattern_seed: usize) {
     }
 }
 
+// Lights the first LED only, to signal a hardware failure.
+fn light_error_led() {
+    for l in 0..led::count().flex_unwrap() {
+        if l == 0 {
+            led::get(l).flex_unwrap().on().flex_unwrap();
+        } else {
+            led::get(l).flex_unwrap().off().flex_unwrap();
+        }
+    }
+}
+
 fn switch_off_leds() {
     for l in 0..led::count().flex_unwrap() {
         led::get(l).flex_unwrap().off().flex_unwrap();
     }
 }
 
+// Returns the user presence sources in addition to the buttons. At the moment, there are none. You
+// can register board-specific sources here, e.g. a capacitive sensor.
+fn user_presence_sources() -> Vec<Box<dyn UserPresenceSource>> {
+    Vec::new()
+}
+
+// Returns the screen showing what the user approves. At the moment, there is none. You can
+// register a board-specific display here, e.g. a small OLED screen.
+fn display() -> Option<Box<dyn Display>> {
+    None
+}
+
+// Returns the policy for new PINs, if stricter than the specification. For example, return a
+// `ctap::pin_policy::StrictPinPolicy` that bans common PINs like "123456".
+fn pin_policy() -> Option<Box<dyn PinPolicy>> {
+    None
+}
+
+// Returns the watchdog resetting the device when the firmware hangs. By default, the hardware
+// watchdog of the board is used if the kernel supports it. You can return None to disable it.
+fn watchdog() -> Option<Box<dyn Watchdog>> {
+    libtock_drivers::watchdog::start(WATCHDOG_PERIOD).ok()?;
+    Some(Box::new(TockWatchdog))
+}
+
+// Pets the hardware watchdog of the board through the kernel.
+struct TockWatchdog;
+
+impl Watchdog for TockWatchdog {
+    fn pet(&mut self) {
+        // Tickling only fails if the kernel has no watchdog, in which case there is nothing to pet.
+        libtock_drivers::watchdog::tickle().ok();
+    }
+}
+
+// Reads the clock of the kernel to measure the latency of commands.
+#[cfg(feature = "perf")]
+struct TockPerfClock;
+
+#[cfg(feature = "perf")]
+impl PerfClock for TockPerfClock {
+    fn now(&self) -> ClockValue {
+        timer::get_current_clock().flex_unwrap()
+    }
+}
+
 fn check_user_presence(cid: ChannelID) -> Result<(), Ctap2StatusCode> {
     // The timeout is N times the keepalive delay.
     const TIMEOUT_ITERATIONS: usize = ctap::TOUCH_TIMEOUT_MS as usize / KEEPALIVE_DELAY_MS as usize;
@@ -319,10 +566,12 @@ fn check_user_presence(cid: ChannelID) -> Result<(), Ctap2StatusCode> {
     send_keepalive_up_needed(cid, KEEPALIVE_DELAY)?;
 
     // Listen to the button presses.
-    let button_touched = Cell::new(false);
-    let mut buttons_callback = buttons::with_callback(|_button_num, state| {
+    let button_presses = Cell::new(0usize);
+    let button_declined = Cell::new(false);
+    let mut buttons_callback = buttons::with_callback(|button_num, state| {
         match state {
-            ButtonState::Pressed => button_touched.set(true),
+            ButtonState::Pressed if DECLINE_BUTTON == Some(button_num) => button_declined.set(true),
+            ButtonState::Pressed => button_presses.set(button_presses.get() + 1),
             ButtonState::Released => (),
         };
     });
@@ -331,10 +580,22 @@ fn check_user_presence(cid: ChannelID) -> Result<(), Ctap2StatusCode> {
     for mut button in &mut buttons {
         button.enable().flex_unwrap();
     }
+    let mut sources = user_presence_sources();
+    for source in &mut sources {
+        source.enable();
+    }
+    let source_present = || sources.iter().any(|source| source.is_present());
+    let source_declined = || sources.iter().any(|source| source.is_declined());
+    // Presses are read from the callback counter, since several can happen between wake-ups.
+    let mut gestures = GestureDetector::new(DOUBLE_PRESS_WINDOW);
+    let mut seen_presses = 0;
+    let mut gesture = None;
 
     let mut keepalive_response = Ok(());
     for i in 0..TIMEOUT_ITERATIONS {
         blink_leds(i);
+        // Waiting for the user blocks the main loop, so the watchdog is petted here.
+        TockWatchdog.pet();
 
         // Setup a keep-alive callback.
         let keepalive_expired = Cell::new(false);
@@ -345,7 +606,18 @@ fn check_user_presence(cid: ChannelID) -> Result<(), Ctap2StatusCode> {
         let keepalive_alarm = keepalive.set_alarm(KEEPALIVE_DELAY).flex_unwrap();
 
         // Wait for a button touch or an alarm.
-        libtock_drivers::util::yieldk_for(|| button_touched.get() || keepalive_expired.get());
+        libtock_drivers::util::yieldk_for(|| {
+            button_presses.get() != seen_presses
+                || button_declined.get()
+                || source_present()
+                || source_declined()
+                || keepalive_expired.get()
+        });
+        let now = keepalive.get_current_clock().flex_unwrap();
+        while seen_presses < button_presses.get() {
+            gestures.press(now);
+            seen_presses += 1;
+        }
 
         // Cleanup alarm callback.
         match keepalive.stop_alarm(keepalive_alarm) {
@@ -364,29 +636,50 @@ fn check_user_presence(cid: ChannelID) -> Result<(), Ctap2StatusCode> {
 
         // TODO: this may take arbitrary time. The keepalive_delay should be adjusted accordingly,
         // so that LEDs blink with a consistent pattern.
-        if keepalive_expired.get() {
+        // Keepalives can't be sent while the host suspends the bus. The user can still answer,
+        // and the host is woken up to receive the answer.
+        let suspended = usb_ctap_hid::is_suspended();
+        if keepalive_expired.get() && !suspended {
             // Do not return immediately, because we must clean up still.
             keepalive_response = send_keepalive_up_needed(cid, KEEPALIVE_DELAY);
         }
 
-        if button_touched.get() || keepalive_response.is_err() {
+        // An explicit decline answers immediately and wins over any confirmation.
+        if button_declined.get() || source_declined() {
+            gestures.decline();
+        }
+        gesture = gestures.gesture(now);
+        if gesture != Some(Gesture::Deny) && source_present() {
+            gesture = Some(Gesture::Confirm);
+        }
+        if gesture.is_some() || keepalive_response.is_err() {
+            if gesture.is_some() && suspended && REMOTE_WAKEUP {
+                usb_ctap_hid::remote_wakeup();
+            }
             break;
         }
     }
 
     switch_off_leds();
 
-    // Cleanup button callbacks.
+    // A press waiting for a second one at the timeout still confirms.
+    let gesture = gesture.or_else(|| gestures.finish());
+
+    // Cleanup button callbacks and sources.
     for mut button in &mut buttons {
         button.disable().flex_unwrap();
     }
+    for source in &mut sources {
+        source.disable();
+    }
 
-    // Returns whether the user was present.
     if keepalive_response.is_err() {
         keepalive_response
-    } else if button_touched.get() {
-        Ok(())
     } else {
-        Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT)
+        match gesture {
+            Some(Gesture::Confirm) => Ok(()),
+            Some(Gesture::Deny) => Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED),
+            None => Err(Ctap2StatusCode::CTAP2_ERR_USER_ACTION_TIMEOUT),
+        }
     }
 }
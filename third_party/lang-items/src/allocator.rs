@@ -5,9 +5,7 @@ use core::alloc::Layout;
 use core::fmt::Write;
 use core::ptr;
 use core::ptr::NonNull;
-#[cfg(feature = "debug_allocations")]
 use core::sync::atomic;
-#[cfg(feature = "debug_allocations")]
 use core::sync::atomic::AtomicUsize;
 #[cfg(any(feature = "debug_allocations", feature = "panic_console"))]
 use libtock_drivers::console::Console;
@@ -20,16 +18,16 @@ unsafe fn libtock_alloc_init(app_heap_start: usize, app_heap_size: usize) {
     HEAP.init(app_heap_start, app_heap_size);
 }
 
-// With the "debug_allocations" feature, we use `AtomicUsize` to store the
-// statistics because:
+// We use `AtomicUsize` to store the statistics because:
 // - it is `Sync`, so we can use it in a static object (the allocator),
 // - it implements interior mutability, so we can use it in the allocator
 //   methods (that take an immutable `&self` reference).
 struct TockAllocator {
     #[cfg(feature = "debug_allocations")]
     count: AtomicUsize,
-    #[cfg(feature = "debug_allocations")]
     size: AtomicUsize,
+    // The highest value of `size` since the last reset.
+    peak: AtomicUsize,
 }
 
 impl TockAllocator {
@@ -37,8 +35,8 @@ impl TockAllocator {
         TockAllocator {
             #[cfg(feature = "debug_allocations")]
             count: AtomicUsize::new(0),
-            #[cfg(feature = "debug_allocations")]
             size: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
         }
     }
 }
@@ -50,10 +48,14 @@ unsafe impl GlobalAlloc for TockAllocator {
             .allocate_first_fit(layout)
             .ok()
             .map_or(ptr::null_mut(), NonNull::as_ptr);
+        if !ptr.is_null() {
+            let size = self.size.fetch_add(layout.size(), atomic::Ordering::SeqCst);
+            self.peak
+                .fetch_max(size + layout.size(), atomic::Ordering::SeqCst);
+        }
         #[cfg(feature = "debug_allocations")]
         {
             self.count.fetch_add(1, atomic::Ordering::SeqCst);
-            self.size.fetch_add(layout.size(), atomic::Ordering::SeqCst);
             writeln!(
                 Console::new(),
                 "alloc[{}, {}] = {:?} ({} ptrs, {} bytes)",
@@ -69,10 +71,10 @@ unsafe impl GlobalAlloc for TockAllocator {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.size.fetch_sub(layout.size(), atomic::Ordering::SeqCst);
         #[cfg(feature = "debug_allocations")]
         {
             self.count.fetch_sub(1, atomic::Ordering::SeqCst);
-            self.size.fetch_sub(layout.size(), atomic::Ordering::SeqCst);
             writeln!(
                 Console::new(),
                 "dealloc[{}, {}] = {:?} ({} ptrs, {} bytes)",
@@ -88,10 +90,25 @@ unsafe impl GlobalAlloc for TockAllocator {
     }
 }
 
-#[cfg(any(target_arch = "arm", target_arch = "riscv32"))]
-#[global_allocator]
+#[cfg_attr(any(target_arch = "arm", target_arch = "riscv32"), global_allocator)]
 static ALLOCATOR: TockAllocator = TockAllocator::new();
 
+/// Returns the highest number of allocated bytes since the last reset.
+///
+/// This doesn't account for fragmentation, so the heap consumption may be higher.
+pub fn heap_high_water_mark() -> usize {
+    ALLOCATOR.peak.load(atomic::Ordering::SeqCst)
+}
+
+/// Resets the high-water mark to the number of currently allocated bytes.
+///
+/// Call this before an operation, and `heap_high_water_mark` after it, to
+/// measure its peak heap usage.
+pub fn reset_heap_high_water_mark() {
+    let size = ALLOCATOR.size.load(atomic::Ordering::SeqCst);
+    ALLOCATOR.peak.store(size, atomic::Ordering::SeqCst);
+}
+
 #[alloc_error_handler]
 unsafe fn alloc_error_handler(_layout: Layout) -> ! {
     util::signal_oom();
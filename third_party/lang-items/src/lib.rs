@@ -8,6 +8,9 @@ mod panic_handler;
 #[cfg(not(feature = "std"))]
 mod util;
 
+#[cfg(not(feature = "std"))]
+pub use allocator::{heap_high_water_mark, reset_heap_high_water_mark};
+
 #[cfg(feature = "std")]
 #[no_mangle]
 unsafe fn libtock_alloc_init(_app_heap_start: usize, _app_heap_size: usize) {
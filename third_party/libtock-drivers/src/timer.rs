@@ -41,6 +41,16 @@ pub fn sleep(duration: Duration<isize>) -> TockResult<()> {
     }
 }
 
+/// Reads the current clock without subscribing to alarms.
+pub fn get_current_clock() -> TockResult<ClockValue> {
+    let clock_frequency = syscalls::command(DRIVER_NUMBER, command_nr::GET_CLOCK_FREQUENCY, 0, 0)?;
+    if clock_frequency == 0 {
+        return Err(OtherError::TimerDriverErroneousClockFrequency.into());
+    }
+    let num_ticks = syscalls::command(DRIVER_NUMBER, command_nr::GET_CLOCK_VALUE, 0, 0)?;
+    Ok(ClockValue::new(num_ticks as isize, clock_frequency))
+}
+
 pub fn with_callback<CB>(callback: CB) -> WithCallback<'static, CB> {
     WithCallback {
         callback,
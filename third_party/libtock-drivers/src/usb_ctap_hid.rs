@@ -33,6 +33,13 @@ mod command_nr {
     pub const RECEIVE: usize = 3;
     pub const TRANSMIT_OR_RECEIVE: usize = 4;
     pub const CANCEL: usize = 5;
+    pub const SET_IDENTITY: usize = 6;
+    pub const BUS_STATE: usize = 7;
+    pub const REMOTE_WAKEUP: usize = 8;
+}
+
+mod bus_state {
+    pub const SUSPENDED: usize = 1;
 }
 
 mod subscribe_nr {
@@ -49,6 +56,47 @@ mod allow_nr {
     pub const TRANSMIT: usize = 1;
     pub const RECEIVE: usize = 2;
     pub const TRANSMIT_OR_RECEIVE: usize = 3;
+    pub const SERIAL_NUMBER: usize = 4;
+}
+
+/// Overrides the USB vendor ID, product ID and serial number string.
+///
+/// This must be called before `setup`, because the descriptors are only used when connecting. The
+/// kernel copies the serial number during the call. An empty serial number keeps the one of the
+/// kernel image. Returns false if the kernel doesn't support identity overrides, in which case the
+/// identity of the kernel image is used.
+pub fn set_identity(vendor_id: u16, product_id: u16, serial_number: &mut [u8]) -> bool {
+    let result = syscalls::allow(DRIVER_NUMBER, allow_nr::SERIAL_NUMBER, serial_number);
+    if result.is_err() {
+        return false;
+    }
+
+    let result = syscalls::command(
+        DRIVER_NUMBER,
+        command_nr::SET_IDENTITY,
+        vendor_id as usize,
+        product_id as usize,
+    );
+    result.is_ok()
+}
+
+/// Returns whether the host suspended the USB bus, e.g. because the computer went to sleep.
+///
+/// Nothing can be sent or received while the bus is suspended. Returns false if the kernel doesn't
+/// report the bus state, in which case the bus is assumed to be always active.
+pub fn is_suspended() -> bool {
+    matches!(
+        syscalls::command(DRIVER_NUMBER, command_nr::BUS_STATE, 0, 0),
+        Ok(bus_state::SUSPENDED)
+    )
+}
+
+/// Signals remote wakeup to resume a suspended bus.
+///
+/// The host has to allow remote wakeup when configuring the device. Returns false if the bus isn't
+/// suspended, the host didn't allow remote wakeup or the kernel doesn't support it.
+pub fn remote_wakeup() -> bool {
+    syscalls::command(DRIVER_NUMBER, command_nr::REMOTE_WAKEUP, 0, 0).is_ok()
 }
 
 pub fn setup() -> bool {
@@ -173,10 +221,21 @@ pub fn send_or_recv(buf: &mut [u8; 64]) -> SendOrRecvStatus {
 
 // Same as recv, but with a timeout.
 // If the timeout elapses, return None.
-#[allow(clippy::let_and_return)]
 pub fn recv_with_timeout(
     buf: &mut [u8; 64],
     timeout_delay: Duration<isize>,
+) -> Option<SendOrRecvStatus> {
+    recv_with_timeout_or_event(buf, timeout_delay, || false)
+}
+
+// Same as recv_with_timeout, but also returns None as soon as the event happened.
+// The event is checked each time a callback runs, so it should be set by another callback, e.g. of
+// the buttons. This lets the caller sleep until anything relevant happens, instead of polling.
+#[allow(clippy::let_and_return)]
+pub fn recv_with_timeout_or_event<F: Fn() -> bool>(
+    buf: &mut [u8; 64],
+    timeout_delay: Duration<isize>,
+    event: F,
 ) -> Option<SendOrRecvStatus> {
     #[cfg(feature = "verbose_usb")]
     writeln!(
@@ -186,7 +245,7 @@ pub fn recv_with_timeout(
     )
     .unwrap();
 
-    let result = recv_with_timeout_detail(buf, timeout_delay);
+    let result = recv_with_timeout_detail(buf, timeout_delay, event);
 
     #[cfg(feature = "verbose_usb")]
     {
@@ -226,9 +285,10 @@ pub fn send_or_recv_with_timeout(
     result
 }
 
-fn recv_with_timeout_detail(
+fn recv_with_timeout_detail<F: Fn() -> bool>(
     buf: &mut [u8; 64],
     timeout_delay: Duration<isize>,
+    event: F,
 ) -> Option<SendOrRecvStatus> {
     let result = syscalls::allow(DRIVER_NUMBER, allow_nr::RECEIVE, buf);
     if result.is_err() {
@@ -273,7 +333,7 @@ fn recv_with_timeout_detail(
         return Some(SendOrRecvStatus::Error);
     }
 
-    util::yieldk_for(|| status.get().is_some() || timeout_expired.get());
+    util::yieldk_for(|| status.get().is_some() || timeout_expired.get() || event());
 
     // Cleanup alarm callback.
     match timeout.stop_alarm(timeout_alarm) {
@@ -307,8 +367,10 @@ fn recv_with_timeout_detail(
             unsafe { syscalls::raw::command(DRIVER_NUMBER, command_nr::CANCEL, 0, 0) };
         match result_code {
             // - SUCCESS means that we successfully cancelled the transaction.
-            // - EALREADY means that the transaction was already completed.
-            SUCCESS | EALREADY => (),
+            SUCCESS => (),
+            // - EALREADY means that the transaction was already completed. The packet is in the
+            //   buffer and its callback is pending, so we wait for it instead of dropping it.
+            EALREADY => util::yieldk_for(|| status.get().is_some()),
             // - EBUSY means that the transaction is in progress.
             EBUSY => {
                 // The app should wait for it, but it may never happen if the remote app crashes.
@@ -408,8 +470,10 @@ fn send_or_recv_with_timeout_detail(
             unsafe { syscalls::raw::command(DRIVER_NUMBER, command_nr::CANCEL, 0, 0) };
         match result_code {
             // - SUCCESS means that we successfully cancelled the transaction.
-            // - EALREADY means that the transaction was already completed.
-            SUCCESS | EALREADY => (),
+            SUCCESS => (),
+            // - EALREADY means that the transaction was already completed. Its callback is
+            //   pending, so we wait for it to report whether the packet was sent or received.
+            EALREADY => util::yieldk_for(|| status.get().is_some()),
             // - EBUSY means that the transaction is in progress.
             EBUSY => {
                 // The app should wait for it, but it may never happen if the remote app crashes.
@@ -0,0 +1,30 @@
+use crate::result::TockResult;
+use crate::timer::Duration;
+use libtock_core::syscalls;
+
+const DRIVER_NUMBER: usize = 0x00009;
+
+mod command_nr {
+    pub const AVAILABLE: usize = 0;
+    pub const START: usize = 1;
+    pub const TICKLE: usize = 2;
+}
+
+pub fn is_available() -> TockResult<()> {
+    syscalls::command(DRIVER_NUMBER, command_nr::AVAILABLE, 0, 0)?;
+    Ok(())
+}
+
+/// Starts the watchdog. The board resets if it isn't tickled within the period.
+///
+/// Depending on the hardware, the watchdog can't be stopped and its period can't be changed until
+/// the next reset.
+pub fn start(period: Duration<isize>) -> TockResult<()> {
+    syscalls::command(DRIVER_NUMBER, command_nr::START, period.ms() as usize, 0)?;
+    Ok(())
+}
+
+pub fn tickle() -> TockResult<()> {
+    syscalls::command(DRIVER_NUMBER, command_nr::TICKLE, 0, 0)?;
+    Ok(())
+}
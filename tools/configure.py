@@ -21,6 +21,9 @@ from __future__ import print_function
 import argparse
 import getpass
 import datetime
+import hashlib
+import hmac
+import os
 import sys
 import uuid
 
@@ -28,8 +31,12 @@ import colorama
 from tqdm.auto import tqdm
 
 from cryptography import x509
+from cryptography.exceptions import InvalidSignature
+from cryptography.hazmat.backends import default_backend
+from cryptography.hazmat.primitives import hashes
 from cryptography.hazmat.primitives import serialization
 from cryptography.hazmat.primitives.asymmetric import ec
+from cryptography.hazmat.primitives.ciphers import Cipher, algorithms, modes
 
 from fido2 import ctap
 from fido2 import ctap2
@@ -37,6 +44,7 @@ from fido2 import hid
 
 OPENSK_VID_PID = (0x1915, 0x521F)
 OPENSK_VENDOR_CONFIGURE = 0x40
+OPENSK_ATTESTATION_CHALLENGE_PREFIX = b"OpenSK attestation key possession"
 
 
 def fatal(msg):
@@ -61,11 +69,14 @@ def info(msg):
       message=msg))
 
 
-def get_opensk_devices(batch_mode):
+def get_opensk_devices(batch_mode, vid_pid=None):
+  # Devices already configured with a custom USB identity report it.
+  vid_pids = [OPENSK_VID_PID]
+  if vid_pid is not None:
+    vid_pids.append(vid_pid)
   devices = []
   for dev in hid.CtapHidDevice.list_devices():
-    if (dev.descriptor["vendor_id"],
-        dev.descriptor["product_id"]) == OPENSK_VID_PID:
+    if (dev.descriptor["vendor_id"], dev.descriptor["product_id"]) in vid_pids:
       if dev.capabilities & hid.CAPABILITY.CBOR:
         if batch_mode:
           devices.append(ctap2.CTAP2(dev))
@@ -87,13 +98,38 @@ def get_private_key(data, password=None):
     return get_private_key(data, password=password.encode(sys.stdin.encoding))
 
 
+def encrypt_attestation_material(authenticator, cert, priv_key,
+                                 intermediate_certs):
+  # The private key never leaves the host in plaintext. It is encrypted like a
+  # PIN, under the shared secret of a key agreement with the authenticator.
+  key_agreement, shared_secret = ctap2.PinProtocolV1(
+      authenticator).get_shared_secret()
+  encryptor = Cipher(
+      algorithms.AES(shared_secret),
+      modes.CBC(b"\x00" * 16),
+      backend=default_backend()).encryptor()
+  priv_key_enc = encryptor.update(priv_key) + encryptor.finalize()
+  # The pinAuth covers the whole certificate chain.
+  pin_auth = hmac.new(shared_secret,
+                      cert + b"".join(intermediate_certs) + priv_key_enc,
+                      hashlib.sha256).digest()[:16]
+  material = {1: cert, 2: priv_key_enc, 3: key_agreement, 4: pin_auth}
+  if intermediate_certs:
+    material[5] = intermediate_certs
+  return material
+
+
 def main(args):
   colorama.init()
   # We need either both the certificate and the key or none
   if bool(args.priv_key) ^ bool(args.certificate):
     fatal("Certificate and private key must be set together or both omitted.")
+  if args.intermediate_certificates and not args.certificate:
+    fatal("Intermediate certificates require the certificate.")
 
   cbor_data = {1: args.lock}
+  attestation_material = None
+  attestation_public_key = None
 
   if args.priv_key:
     cbor_data[1] = args.lock
@@ -123,16 +159,52 @@ def main(args):
     if pub_key.public_numbers() != priv_key.public_key().public_numbers():
       fatal("Certificate public doesn't match with the private key.")
     info("Certificate is valid.")
+    attestation_public_key = pub_key
+
+    intermediate_certs = [
+        x509.load_pem_x509_certificate(intermediate_certificate.read())
+        for intermediate_certificate in args.intermediate_certificates
+    ]
+    # Each certificate of the chain must be issued by the next one.
+    chain = [cert] + intermediate_certs
+    for issued, issuer in zip(chain, chain[1:]):
+      if issued.issuer != issuer.subject:
+        fatal("Intermediate certificates are not in the order of the chain.")
+    if intermediate_certs:
+      info("Certificate chain is valid.")
+
+    attestation_material = (
+        cert.public_bytes(serialization.Encoding.DER),
+        priv_key.private_numbers().private_value.to_bytes(
+            length=32, byteorder='big', signed=False),
+        [
+            intermediate_cert.public_bytes(serialization.Encoding.DER)
+            for intermediate_cert in intermediate_certs
+        ],
+    )
+
+  # We need either both the USB vendor and product IDs or none
+  if (args.usb_vid is None) ^ (args.usb_pid is None):
+    fatal("USB vendor and product IDs must be set together or both omitted.")
+  if args.usb_serial is not None and args.usb_vid is None:
+    fatal("USB serial number requires the USB vendor and product IDs.")
 
-    cbor_data[2] = {
-        1:
-            cert.public_bytes(serialization.Encoding.DER),
-        2:
-            priv_key.private_numbers().private_value.to_bytes(
-                length=32, byteorder='big', signed=False)
-    }
+  if args.usb_vid is not None:
+    if not 0 < args.usb_vid <= 0xFFFF or not 0 < args.usb_pid <= 0xFFFF:
+      fatal("USB vendor and product IDs must be between 0x0001 and 0xFFFF.")
+    usb_identity = {1: args.usb_vid, 2: args.usb_pid}
+    if args.usb_serial is not None:
+      if not 0 < len(args.usb_serial) <= 32 or not all(
+          0x20 <= ord(c) < 0x7F for c in args.usb_serial):
+        fatal("USB serial number must be 1 to 32 printable ASCII characters.")
+      usb_identity[3] = args.usb_serial
+    info("USB identity is valid.")
+    cbor_data[3] = usb_identity
 
-  for authenticator in tqdm(get_opensk_devices(args.batch)):
+  vid_pid = None
+  if args.usb_vid is not None:
+    vid_pid = (args.usb_vid, args.usb_pid)
+  for authenticator in tqdm(get_opensk_devices(args.batch, vid_pid)):
     # If the device supports it, wink to show which device
     # we're going to program.
     if authenticator.device.capabilities & hid.CAPABILITY.WINK:
@@ -143,12 +215,31 @@ def main(args):
               authenticator.device.descriptor.get("product_string", "Unknown"),
               aaguid, authenticator.device))
     try:
+      if attestation_material is not None:
+        cbor_data[2] = encrypt_attestation_material(authenticator,
+                                                    *attestation_material)
+        # The device signs a fresh challenge to prove it holds the private key.
+        challenge = os.urandom(32)
+        cbor_data[4] = challenge
       result = authenticator.send_cbor(
           OPENSK_VENDOR_CONFIGURE,
           data=cbor_data,
       )
       info("Certificate: {}".format("Present" if result[1] else "Missing"))
       info("Private Key: {}".format("Present" if result[2] else "Missing"))
+      if attestation_material is not None:
+        try:
+          attestation_public_key.verify(
+              result.get(3, b""),
+              OPENSK_ATTESTATION_CHALLENGE_PREFIX + challenge,
+              ec.ECDSA(hashes.SHA256()),
+          )
+          info("Private key possession: proven")
+        except InvalidSignature:
+          error("Failed to configure OpenSK (the device didn't prove "
+                "possession of the private key).")
+      if args.usb_vid is not None:
+        info("USB identity: applied at next boot")
       if args.lock:
         info("Device is now locked down!")
     except ctap.CtapError as ex:
@@ -161,6 +252,12 @@ def main(args):
         error(
             ("Failed to configure OpenSK (device is partially programmed but "
              "the given cert/key don't match the ones currently programmed)."))
+      elif ex.code.value == ctap.CtapError.ERR.PIN_AUTH_INVALID:
+        error(("Failed to configure OpenSK (the encrypted attestation "
+               "material failed authentication)."))
+      elif ex.code.value == ctap.CtapError.ERR.NOT_ALLOWED:
+        error(("Failed to configure OpenSK (the USB identity can't be changed "
+               "after lockdown)."))
       else:
         error("Failed to configure OpenSK (unknown error: {}".format(ex))
 
@@ -185,6 +282,17 @@ if __name__ == "__main__":
       help=("PEM file containing the certificate to inject into "
             "the OpenSK authenticator."),
   )
+  parser.add_argument(
+      "--intermediate-certificate",
+      type=argparse.FileType("rb"),
+      action="append",
+      default=[],
+      metavar="PEM_FILE",
+      dest="intermediate_certificates",
+      help=("PEM file containing an intermediate certificate of the chain "
+            "from the certificate to the root. Repeat the option for each "
+            "intermediate certificate, in the order of the chain."),
+  )
   parser.add_argument(
       "--private-key",
       type=argparse.FileType("rb"),
@@ -194,6 +302,32 @@ if __name__ == "__main__":
       help=("PEM file containing the private key associated "
             "with the certificate."),
   )
+  parser.add_argument(
+      "--usb-vid",
+      type=lambda x: int(x, 16),
+      default=None,
+      metavar="HEX",
+      dest="usb_vid",
+      help=("USB vendor ID (in hexadecimal) to use instead of the one of the "
+            "firmware image. Must be given with --usb-pid."),
+  )
+  parser.add_argument(
+      "--usb-pid",
+      type=lambda x: int(x, 16),
+      default=None,
+      metavar="HEX",
+      dest="usb_pid",
+      help=("USB product ID (in hexadecimal) to use instead of the one of the "
+            "firmware image. Must be given with --usb-vid."),
+  )
+  parser.add_argument(
+      "--usb-serial",
+      default=None,
+      metavar="SERIAL",
+      dest="usb_serial",
+      help=("USB serial number string to report. The USB identity can't be "
+            "changed anymore once the device is locked down."),
+  )
   parser.add_argument(
       "--lock-device",
       default=False,
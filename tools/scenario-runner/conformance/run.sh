@@ -0,0 +1,41 @@
+#!/usr/bin/env bash
+# Copyright 2020 Google LLC
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#      http://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+# Runs the FIDO conformance tool against the authenticator compiled for the host.
+#
+# The conformance tool is not distributed with OpenSK. CONFORMANCE_COMMAND must run it against the
+# device bridged from CONFORMANCE_ADDRESS, with the metadata statement CONFORMANCE_METADATA, and
+# write the identifiers of the failed tests, one per line, to the file given as first argument.
+
+set -e
+
+cd "$(dirname "$0")/../../.."
+source tools/scenario-runner/conformance/fixture.env
+: "${CONFORMANCE_COMMAND:?must run the conformance tool}"
+
+runner=(cargo run --manifest-path tools/scenario-runner/Cargo.toml
+        --features "$CONFORMANCE_FEATURES" --)
+cargo build --manifest-path tools/scenario-runner/Cargo.toml --features "$CONFORMANCE_FEATURES"
+"${runner[@]}" --serve "$CONFORMANCE_ADDRESS" &
+server=$!
+trap 'kill "$server"' EXIT
+# Reports sent before the socket is bound would be lost.
+sleep 1
+
+failed="$(mktemp)"
+export CONFORMANCE_ADDRESS CONFORMANCE_METADATA
+# The tool fails when tests fail, which the allow-list decides about.
+$CONFORMANCE_COMMAND "$failed" || true
+"${runner[@]}" --check "$failed"
@@ -0,0 +1,414 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Platform side of the CTAP commands, as a browser would send them.
+
+use cbor::{cbor_array, cbor_map, cbor_map_options};
+use core::convert::TryFrom;
+use crypto::cbc::{cbc_decrypt, cbc_encrypt};
+use crypto::hmac::hmac_256;
+use crypto::rng256::{Rng256, ThreadRng256};
+use crypto::sha256::Sha256;
+use crypto::{aes256, ecdh, Hash256};
+use ctap2::ctap::data_formats::CoseKey;
+use ctap2::ctap::hid::ChannelID;
+use ctap2::ctap::status_code::Ctap2StatusCode;
+use ctap2::ctap::CtapState;
+use libtock_drivers::timer::ClockValue;
+
+const CID: ChannelID = [0x12, 0x34, 0x56, 0x78];
+
+const COMMAND_MAKE_CREDENTIAL: u8 = 0x01;
+const COMMAND_GET_ASSERTION: u8 = 0x02;
+const COMMAND_CLIENT_PIN: u8 = 0x06;
+const COMMAND_RESET: u8 = 0x07;
+
+const PIN_PROTOCOL: i64 = 1;
+const SUBCOMMAND_GET_KEY_AGREEMENT: i64 = 0x02;
+const SUBCOMMAND_SET_PIN: i64 = 0x03;
+const SUBCOMMAND_CHANGE_PIN: i64 = 0x04;
+const SUBCOMMAND_GET_PIN_TOKEN: i64 = 0x05;
+
+// The PIN is padded to this length before being encrypted.
+const PADDED_PIN_LENGTH: usize = 64;
+const PIN_TOKEN_LENGTH: usize = 32;
+const ES256_ALGORITHM: i64 = -7;
+
+// Offsets in the authenticator data of a registration.
+const CREDENTIAL_ID_LENGTH_OFFSET: usize = 32 + 1 + 4 + 16;
+const CREDENTIAL_ID_OFFSET: usize = CREDENTIAL_ID_LENGTH_OFFSET + 2;
+
+/// Reason for a command to fail.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The authenticator returned this status code.
+    Status(u8),
+    /// The authenticator returned success with an unexpected response.
+    InvalidResponse(&'static str),
+}
+
+/// Relevant content of an assertion response.
+#[derive(Debug, PartialEq)]
+pub struct Assertion {
+    pub credentials: u64,
+    pub user_id: Vec<u8>,
+}
+
+/// Returns the name of a status code, as in the CTAP specification.
+pub fn status_name(status: u8) -> String {
+    match Ctap2StatusCode::try_from(status) {
+        Ok(status_code) => format!("{:?}", status_code),
+        Err(()) => format!("0x{:02X}", status),
+    }
+}
+
+/// Sends a command and returns the CBOR response, if any.
+fn send<R, CheckUserPresence>(
+    ctap_state: &mut CtapState<R, CheckUserPresence>,
+    now: ClockValue,
+    command: u8,
+    parameters: Option<cbor::Value>,
+) -> Result<Option<cbor::Value>, Error>
+where
+    R: Rng256,
+    CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+{
+    let mut request = vec![command];
+    if let Some(parameters) = parameters {
+        assert!(cbor::write(parameters, &mut request));
+    }
+    let response = ctap_state.process_command(&request, CID, now);
+    match response.split_first() {
+        None => Err(Error::InvalidResponse("empty response")),
+        Some((0x00, [])) => Ok(None),
+        Some((0x00, payload)) => cbor::read(payload)
+            .map(Some)
+            .map_err(|_| Error::InvalidResponse("invalid CBOR")),
+        Some((status, _)) => Err(Error::Status(*status)),
+    }
+}
+
+/// Returns the value of an integer key in a CBOR map.
+fn get(value: &cbor::Value, key: u64) -> Result<&cbor::Value, Error> {
+    match value {
+        cbor::Value::Map(map) => map
+            .get(&cbor::KeyType::Unsigned(key))
+            .ok_or(Error::InvalidResponse("missing response field")),
+        _ => Err(Error::InvalidResponse("response is not a map")),
+    }
+}
+
+fn get_bytes(value: &cbor::Value, key: u64) -> Result<&[u8], Error> {
+    match get(value, key)? {
+        cbor::Value::KeyValue(cbor::KeyType::ByteString(bytes)) => Ok(bytes),
+        _ => Err(Error::InvalidResponse(
+            "response field is not a byte string",
+        )),
+    }
+}
+
+fn aes256_cbc_encrypt(key: &[u8; 32], data: &[u8]) -> Vec<u8> {
+    let mut blocks = to_blocks(data);
+    cbc_encrypt(&aes256::EncryptionKey::new(key), [0; 16], &mut blocks);
+    blocks.concat()
+}
+
+fn aes256_cbc_decrypt(key: &[u8; 32], data: &[u8]) -> Vec<u8> {
+    let mut blocks = to_blocks(data);
+    let encryption_key = aes256::EncryptionKey::new(key);
+    cbc_decrypt(
+        &aes256::DecryptionKey::new(&encryption_key),
+        [0; 16],
+        &mut blocks,
+    );
+    blocks.concat()
+}
+
+fn to_blocks(data: &[u8]) -> Vec<[u8; 16]> {
+    assert_eq!(data.len() % 16, 0);
+    data.chunks(16)
+        .map(|chunk| {
+            let mut block = [0; 16];
+            block.copy_from_slice(chunk);
+            block
+        })
+        .collect()
+}
+
+fn left_hmac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    hmac_256::<Sha256>(key, message)[..16].to_vec()
+}
+
+/// Returns the first 16 bytes of the PIN hash, encrypted with the shared secret.
+fn pin_hash_enc(shared_secret: &[u8; 32], pin: &str) -> Vec<u8> {
+    aes256_cbc_encrypt(shared_secret, &Sha256::hash(pin.as_bytes())[..16])
+}
+
+/// Returns the padded PIN, encrypted with the shared secret.
+fn new_pin_enc(shared_secret: &[u8; 32], pin: &str) -> Vec<u8> {
+    let mut padded_pin = pin.as_bytes().to_vec();
+    padded_pin.resize(PADDED_PIN_LENGTH, 0);
+    aes256_cbc_encrypt(shared_secret, &padded_pin)
+}
+
+/// Client of an authenticator, remembering the credentials it registered.
+pub struct Client {
+    pub now: ClockValue,
+    /// Relying party and ID of the registered credentials.
+    credentials: Vec<(String, Vec<u8>)>,
+}
+
+impl Client {
+    pub fn new(now: ClockValue) -> Client {
+        Client {
+            now,
+            credentials: Vec::new(),
+        }
+    }
+
+    /// Agrees on a shared secret with the authenticator.
+    ///
+    /// Returns the platform key to send along with the shared secret.
+    fn key_agreement<R, CheckUserPresence>(
+        &self,
+        ctap_state: &mut CtapState<R, CheckUserPresence>,
+    ) -> Result<(CoseKey, [u8; 32]), Error>
+    where
+        R: Rng256,
+        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+    {
+        let parameters = cbor_map! {
+            1 => PIN_PROTOCOL,
+            2 => SUBCOMMAND_GET_KEY_AGREEMENT,
+        };
+        let response = send(ctap_state, self.now, COMMAND_CLIENT_PIN, Some(parameters))?
+            .ok_or(Error::InvalidResponse("missing key agreement"))?;
+        let authenticator_key = match get(&response, 1)? {
+            cbor::Value::Map(map) => ecdh::PubKey::try_from(CoseKey(map.clone()))
+                .map_err(|_| Error::InvalidResponse("invalid key agreement"))?,
+            _ => return Err(Error::InvalidResponse("invalid key agreement")),
+        };
+        let platform_secret_key = ecdh::SecKey::gensk(&mut ThreadRng256 {});
+        let shared_secret = platform_secret_key.exchange_x_sha256(&authenticator_key);
+        Ok((CoseKey::from(platform_secret_key.genpk()), shared_secret))
+    }
+
+    pub fn set_pin<R, CheckUserPresence>(
+        &self,
+        ctap_state: &mut CtapState<R, CheckUserPresence>,
+        pin: &str,
+    ) -> Result<(), Error>
+    where
+        R: Rng256,
+        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+    {
+        let (key_agreement, shared_secret) = self.key_agreement(ctap_state)?;
+        let new_pin_enc = new_pin_enc(&shared_secret, pin);
+        let parameters = cbor_map! {
+            1 => PIN_PROTOCOL,
+            2 => SUBCOMMAND_SET_PIN,
+            3 => cbor::Value::Map(key_agreement.0),
+            4 => left_hmac(&shared_secret, &new_pin_enc),
+            5 => new_pin_enc,
+        };
+        send(ctap_state, self.now, COMMAND_CLIENT_PIN, Some(parameters))?;
+        Ok(())
+    }
+
+    pub fn change_pin<R, CheckUserPresence>(
+        &self,
+        ctap_state: &mut CtapState<R, CheckUserPresence>,
+        old_pin: &str,
+        new_pin: &str,
+    ) -> Result<(), Error>
+    where
+        R: Rng256,
+        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+    {
+        let (key_agreement, shared_secret) = self.key_agreement(ctap_state)?;
+        let new_pin_enc = new_pin_enc(&shared_secret, new_pin);
+        let pin_hash_enc = pin_hash_enc(&shared_secret, old_pin);
+        let mut auth_message = new_pin_enc.clone();
+        auth_message.extend(&pin_hash_enc);
+        let parameters = cbor_map! {
+            1 => PIN_PROTOCOL,
+            2 => SUBCOMMAND_CHANGE_PIN,
+            3 => cbor::Value::Map(key_agreement.0),
+            4 => left_hmac(&shared_secret, &auth_message),
+            5 => new_pin_enc,
+            6 => pin_hash_enc,
+        };
+        send(ctap_state, self.now, COMMAND_CLIENT_PIN, Some(parameters))?;
+        Ok(())
+    }
+
+    /// Returns the pinAuth of the client data hash, using a fresh PIN token.
+    fn pin_auth<R, CheckUserPresence>(
+        &self,
+        ctap_state: &mut CtapState<R, CheckUserPresence>,
+        pin: &str,
+        client_data_hash: &[u8],
+    ) -> Result<Vec<u8>, Error>
+    where
+        R: Rng256,
+        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+    {
+        let (key_agreement, shared_secret) = self.key_agreement(ctap_state)?;
+        let parameters = cbor_map! {
+            1 => PIN_PROTOCOL,
+            2 => SUBCOMMAND_GET_PIN_TOKEN,
+            3 => cbor::Value::Map(key_agreement.0),
+            6 => pin_hash_enc(&shared_secret, pin),
+        };
+        let response = send(ctap_state, self.now, COMMAND_CLIENT_PIN, Some(parameters))?
+            .ok_or(Error::InvalidResponse("missing PIN token"))?;
+        let pin_token_enc = get_bytes(&response, 2)?;
+        if pin_token_enc.len() != PIN_TOKEN_LENGTH {
+            return Err(Error::InvalidResponse("invalid PIN token length"));
+        }
+        let pin_token = aes256_cbc_decrypt(&shared_secret, pin_token_enc);
+        Ok(left_hmac(&pin_token, client_data_hash))
+    }
+
+    pub fn make_credential<R, CheckUserPresence>(
+        &mut self,
+        ctap_state: &mut CtapState<R, CheckUserPresence>,
+        rp_id: &str,
+        user_id: &str,
+        resident_key: bool,
+        pin: Option<&str>,
+    ) -> Result<(), Error>
+    where
+        R: Rng256,
+        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+    {
+        let client_data_hash = ThreadRng256 {}.gen_uniform_u8x32().to_vec();
+        let pin_auth = match pin {
+            Some(pin) => Some(self.pin_auth(ctap_state, pin, &client_data_hash)?),
+            None => None,
+        };
+        let parameters = cbor_map_options! {
+            1 => client_data_hash,
+            2 => cbor_map! { "id" => rp_id },
+            3 => cbor_map! { "id" => user_id.as_bytes() },
+            4 => cbor_array![cbor_map! {
+                "alg" => ES256_ALGORITHM,
+                "type" => "public-key",
+            }],
+            7 => cbor_map! { "rk" => resident_key },
+            8 => pin_auth,
+            9 => pin.map(|_| PIN_PROTOCOL),
+        };
+        let response = send(
+            ctap_state,
+            self.now,
+            COMMAND_MAKE_CREDENTIAL,
+            Some(parameters),
+        )?
+        .ok_or(Error::InvalidResponse("missing attestation object"))?;
+        let auth_data = get_bytes(&response, 2)?;
+        if auth_data.len() < CREDENTIAL_ID_OFFSET {
+            return Err(Error::InvalidResponse("missing attested credential data"));
+        }
+        let credential_id_length = u16::from_be_bytes([
+            auth_data[CREDENTIAL_ID_LENGTH_OFFSET],
+            auth_data[CREDENTIAL_ID_LENGTH_OFFSET + 1],
+        ]) as usize;
+        let credential_id = auth_data
+            .get(CREDENTIAL_ID_OFFSET..CREDENTIAL_ID_OFFSET + credential_id_length)
+            .ok_or(Error::InvalidResponse("truncated credential ID"))?;
+        self.credentials
+            .push((String::from(rp_id), credential_id.to_vec()));
+        Ok(())
+    }
+
+    pub fn get_assertion<R, CheckUserPresence>(
+        &self,
+        ctap_state: &mut CtapState<R, CheckUserPresence>,
+        rp_id: &str,
+        allow_list: bool,
+        pin: Option<&str>,
+    ) -> Result<Assertion, Error>
+    where
+        R: Rng256,
+        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+    {
+        let client_data_hash = ThreadRng256 {}.gen_uniform_u8x32().to_vec();
+        let pin_auth = match pin {
+            Some(pin) => Some(self.pin_auth(ctap_state, pin, &client_data_hash)?),
+            None => None,
+        };
+        let allow_list = if allow_list {
+            let descriptors = self
+                .credentials
+                .iter()
+                .filter(|(credential_rp_id, _)| credential_rp_id == rp_id)
+                .map(|(_, credential_id)| {
+                    cbor_map! {
+                        "id" => credential_id.clone(),
+                        "type" => "public-key",
+                    }
+                })
+                .collect();
+            Some(cbor::Value::Array(descriptors))
+        } else {
+            None
+        };
+        let parameters = cbor_map_options! {
+            1 => rp_id,
+            2 => client_data_hash,
+            3 => allow_list,
+            6 => pin_auth,
+            7 => pin.map(|_| PIN_PROTOCOL),
+        };
+        let response = send(
+            ctap_state,
+            self.now,
+            COMMAND_GET_ASSERTION,
+            Some(parameters),
+        )?
+        .ok_or(Error::InvalidResponse("missing assertion"))?;
+        // The number of credentials is omitted when there is only one.
+        let credentials = match get(&response, 5) {
+            Ok(cbor::Value::KeyValue(cbor::KeyType::Unsigned(count))) => *count,
+            Ok(_) => return Err(Error::InvalidResponse("invalid number of credentials")),
+            Err(_) => 1,
+        };
+        // The user is omitted for non-resident credentials.
+        let user_id = match get(&response, 4) {
+            Ok(cbor::Value::Map(user)) => match user.get(&cbor::KeyType::from("id")) {
+                Some(cbor::Value::KeyValue(cbor::KeyType::ByteString(id))) => id.clone(),
+                _ => return Err(Error::InvalidResponse("user without ID")),
+            },
+            Ok(_) => return Err(Error::InvalidResponse("invalid user")),
+            Err(_) => Vec::new(),
+        };
+        Ok(Assertion {
+            credentials,
+            user_id,
+        })
+    }
+
+    pub fn reset<R, CheckUserPresence>(
+        &self,
+        ctap_state: &mut CtapState<R, CheckUserPresence>,
+    ) -> Result<(), Error>
+    where
+        R: Rng256,
+        CheckUserPresence: Fn(ChannelID) -> Result<(), Ctap2StatusCode>,
+    {
+        send(ctap_state, self.now, COMMAND_RESET, None)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeSet;
+
+/// Returns the test identifiers of a list.
+///
+/// There is one identifier per line. Everything after a `#` is a comment, which for the allow-list
+/// is used to explain the deviation. Empty lines are ignored.
+fn parse_list(content: &str) -> BTreeSet<&str> {
+    content
+        .lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// Compares the failed tests of a conformance run with the allow-list of known deviations.
+///
+/// Returns the problems of the run, one per test. Failed tests missing from the allow-list are
+/// regressions. Allow-listed tests that didn't fail are fixed and must be removed from the
+/// allow-list, such that it only shrinks over time.
+pub fn check(failed: &str, allowlist: &str) -> Vec<String> {
+    let failed = parse_list(failed);
+    let allowlist = parse_list(allowlist);
+    let regressions = failed
+        .difference(&allowlist)
+        .map(|test| format!("{}: failed but not in the allow-list", test));
+    let fixed = allowlist
+        .difference(&failed)
+        .map(|test| format!("{}: passed, remove it from the allow-list", test));
+    regressions.chain(fixed).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_ok() {
+        let allowlist = "
+# Known deviations.
+Test-2  # Waiting for the fix.
+
+Test-1
+";
+        let failed = "Test-1\nTest-2\n";
+        assert!(check(failed, allowlist).is_empty());
+    }
+
+    #[test]
+    fn test_check_problems() {
+        let allowlist = "Test-2 # Waiting for the fix.\n";
+        let failed = "Test-1\n";
+        assert_eq!(
+            check(failed, allowlist),
+            vec![
+                "Test-1: failed but not in the allow-list",
+                "Test-2: passed, remove it from the allow-list",
+            ]
+        );
+    }
+}
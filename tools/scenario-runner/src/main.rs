@@ -0,0 +1,107 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The cbor macros refer to the alloc crate.
+extern crate alloc;
+// This explicit "extern crate" is needed to make the linker aware of the
+// `libtock_alloc_init` symbol.
+extern crate lang_items;
+
+mod client;
+mod conformance;
+mod runner;
+mod scenario;
+mod server;
+
+use clap::{App, Arg};
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let matches = App::new("Scenario runner")
+        .version("0.1")
+        .about("Runs scripted CTAP flows against the authenticator compiled for the host")
+        .arg(
+            Arg::with_name("scenario")
+                .value_name("FILE")
+                .help("Scenario to run, in YAML or JSON")
+                .multiple(true)
+                .required_unless_one(&["serve", "check"]),
+        )
+        .arg(
+            Arg::with_name("serve")
+                .long("serve")
+                .value_name("ADDRESS")
+                .help("Serves the authenticator over UDP for the conformance tool")
+                .conflicts_with_all(&["scenario", "check"]),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .value_name("FAILED")
+                .help("Checks the failed conformance tests, one per line, against the allow-list")
+                .conflicts_with("scenario"),
+        )
+        .arg(
+            Arg::with_name("allowlist")
+                .long("allowlist")
+                .value_name("FILE")
+                .help("Known deviations from the conformance tests")
+                .default_value(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/conformance/allowlist.txt"
+                )),
+        )
+        .get_matches();
+    if let Some(address) = matches.value_of("serve") {
+        if let Err(e) = server::serve(address) {
+            eprintln!("Serving on {} failed: {}", address, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(failed) = matches.value_of("check") {
+        let allowlist = matches.value_of("allowlist").unwrap();
+        let read =
+            |path: &str| fs::read_to_string(path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+        let problems = conformance::check(&read(failed), &read(allowlist));
+        for problem in &problems {
+            println!("FAIL {}", problem);
+        }
+        if !problems.is_empty() {
+            std::process::exit(1);
+        }
+        return;
+    }
+    let mut num_failures = 0;
+    for path in matches.values_of("scenario").unwrap() {
+        let path = Path::new(path);
+        let result = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| scenario::parse(path, &content))
+            .and_then(|scenario| {
+                runner::run(&scenario).map_err(|message| format!("{}: {}", scenario.name, message))
+            });
+        match result {
+            Ok(()) => println!("PASS {}", path.display()),
+            Err(message) => {
+                println!("FAIL {}: {}", path.display(), message);
+                num_failures += 1;
+            }
+        }
+    }
+    if num_failures > 0 {
+        std::process::exit(1);
+    }
+}
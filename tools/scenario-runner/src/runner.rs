@@ -0,0 +1,233 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::client::{status_name, Assertion, Client, Error};
+use crate::scenario::{Action, Expect, Scenario};
+use crypto::rng256::ThreadRng256;
+use ctap2::ctap::hid::ChannelID;
+use ctap2::ctap::status_code::Ctap2StatusCode;
+use ctap2::ctap::CtapState;
+use libtock_drivers::timer::{ClockValue, Duration};
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Frequency of the authenticator clock.
+pub const CLOCK_FREQUENCY_HZ: usize = 32768;
+
+/// Runs the scenario on a fresh authenticator.
+///
+/// Stops at the first step with an unexpected outcome, since the following steps usually depend on
+/// it, and describes the failure.
+pub fn run(scenario: &Scenario) -> Result<(), String> {
+    let user_present = Rc::new(Cell::new(true));
+    let check_user_presence = {
+        let user_present = user_present.clone();
+        move |_: ChannelID| {
+            if user_present.get() {
+                Ok(())
+            } else {
+                Err(Ctap2StatusCode::CTAP2_ERR_OPERATION_DENIED)
+            }
+        }
+    };
+    let mut client = Client::new(ClockValue::new(0, CLOCK_FREQUENCY_HZ));
+    let mut rng = ThreadRng256 {};
+    let mut ctap_state = CtapState::new(&mut rng, check_user_presence, client.now);
+    for (index, step) in scenario.steps.iter().enumerate() {
+        let result = match &step.action {
+            Action::SetPin { pin } => client.set_pin(&mut ctap_state, pin).map(|()| None),
+            Action::ChangePin { old_pin, new_pin } => client
+                .change_pin(&mut ctap_state, old_pin, new_pin)
+                .map(|()| None),
+            Action::MakeCredential(parameters) => client
+                .make_credential(
+                    &mut ctap_state,
+                    &parameters.rp_id,
+                    &parameters.user_id,
+                    parameters.resident_key,
+                    parameters.pin.as_deref(),
+                )
+                .map(|()| None),
+            Action::GetAssertion(parameters) => client
+                .get_assertion(
+                    &mut ctap_state,
+                    &parameters.rp_id,
+                    parameters.allow_list,
+                    parameters.pin.as_deref(),
+                )
+                .map(Some),
+            Action::Reset => client.reset(&mut ctap_state).map(|()| None),
+            Action::Reboot => {
+                ctap_state = ctap_state.reboot(client.now);
+                Ok(None)
+            }
+            Action::Wait { ms } => {
+                client.now = client.now.wrapping_add(Duration::from_ms(*ms));
+                Ok(None)
+            }
+            Action::UserPresence { present } => {
+                user_present.set(*present);
+                Ok(None)
+            }
+        };
+        check(&step.expect, result).map_err(|message| {
+            format!("step {} ({}): {}", index + 1, step.action.name(), message)
+        })?;
+    }
+    Ok(())
+}
+
+/// Checks that the outcome of a step is the expected one.
+fn check(expect: &Expect, result: Result<Option<Assertion>, Error>) -> Result<(), String> {
+    let expected_status = expect.status.as_deref().unwrap_or("CTAP2_OK");
+    if !(0..=0xFF).any(|status| status_name(status) == expected_status) {
+        return Err(format!("unknown status {}", expected_status));
+    }
+    let (status, assertion) = match result {
+        Ok(assertion) => (status_name(0x00), assertion),
+        Err(Error::Status(status)) => (status_name(status), None),
+        Err(Error::InvalidResponse(reason)) => {
+            return Err(format!("invalid response: {}", reason));
+        }
+    };
+    if status != expected_status {
+        return Err(format!("expected {}, got {}", expected_status, status));
+    }
+    if expect.credentials.is_none() && expect.user_id.is_none() {
+        return Ok(());
+    }
+    let assertion = match assertion {
+        Some(assertion) => assertion,
+        None => {
+            return Err(String::from(
+                "only assertions can be checked for credentials",
+            ))
+        }
+    };
+    if let Some(credentials) = expect.credentials {
+        if assertion.credentials != credentials {
+            return Err(format!(
+                "expected {} credentials, got {}",
+                credentials, assertion.credentials
+            ));
+        }
+    }
+    if let Some(user_id) = &expect.user_id {
+        if assertion.user_id != user_id.as_bytes() {
+            return Err(format!(
+                "expected user {}, got {}",
+                user_id,
+                String::from_utf8_lossy(&assertion.user_id)
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scenario;
+    use std::fs;
+    use std::path::Path;
+
+    fn run_yaml(content: &str) -> Result<(), String> {
+        run(&scenario::parse(Path::new("test.yaml"), content).unwrap())
+    }
+
+    #[test]
+    fn test_shipped_scenarios() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenarios");
+        for entry in fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            let content = fs::read_to_string(&path).unwrap();
+            let scenario = scenario::parse(&path, &content).unwrap();
+            assert_eq!(run(&scenario), Ok(()), "{}", path.display());
+        }
+    }
+
+    #[test]
+    fn test_unexpected_status() {
+        let content = r#"
+name: test
+steps:
+  - get_assertion:
+      rp_id: example.com
+"#;
+        assert_eq!(
+            run_yaml(content),
+            Err(String::from(
+                "step 1 (get_assertion): expected CTAP2_OK, got CTAP2_ERR_NO_CREDENTIALS"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_unknown_status() {
+        let content = r#"
+name: test
+steps:
+  - reboot:
+    expect:
+      status: CTAP2_ERR_TYPO
+"#;
+        assert_eq!(
+            run_yaml(content),
+            Err(String::from(
+                "step 1 (reboot): unknown status CTAP2_ERR_TYPO"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_reboot_unblocks_pin_auth() {
+        // The consecutive PIN mismatches are not persistent, unlike the PIN retries. Each mismatch
+        // delays the next attempt, with a doubling delay.
+        let content = r#"
+name: test
+steps:
+  - set_pin:
+      pin: "1234"
+  - make_credential:
+      rp_id: example.com
+      pin: "0000"
+    expect:
+      status: CTAP2_ERR_PIN_INVALID
+  - make_credential:
+      rp_id: example.com
+      pin: "1234"
+    expect:
+      status: CTAP2_ERR_NOT_ALLOWED
+  - wait:
+      ms: 1000
+  - make_credential:
+      rp_id: example.com
+      pin: "0000"
+    expect:
+      status: CTAP2_ERR_PIN_INVALID
+  - wait:
+      ms: 2000
+  - make_credential:
+      rp_id: example.com
+      pin: "0000"
+    expect:
+      status: CTAP2_ERR_PIN_AUTH_BLOCKED
+  - reboot:
+  - make_credential:
+      rp_id: example.com
+      pin: "1234"
+"#;
+        assert_eq!(run_yaml(content), Ok(()));
+    }
+}
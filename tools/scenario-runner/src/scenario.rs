@@ -0,0 +1,175 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::Deserialize;
+use std::path::Path;
+
+/// Sequence of steps run against a fresh authenticator.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    pub name: String,
+    pub steps: Vec<Step>,
+}
+
+/// Action sent to the authenticator, with the outcome it must have.
+#[derive(Debug, Deserialize)]
+pub struct Step {
+    #[serde(flatten)]
+    pub action: Action,
+    #[serde(default)]
+    pub expect: Expect,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Sets the PIN of an authenticator without PIN.
+    SetPin { pin: String },
+    /// Changes the PIN, authenticating with the old one.
+    ChangePin { old_pin: String, new_pin: String },
+    /// Registers a credential for the relying party.
+    MakeCredential(MakeCredential),
+    /// Authenticates with a credential of the relying party.
+    GetAssertion(GetAssertion),
+    /// Sends the CTAP reset command.
+    Reset,
+    /// Power cycles the authenticator. Only the persistent storage is kept.
+    Reboot,
+    /// Lets time pass, e.g. for the reset command to time out.
+    Wait { ms: isize },
+    /// Makes the user grant or refuse the following presence checks.
+    UserPresence { present: bool },
+}
+
+impl Action {
+    /// Returns the name of the action, as written in scenarios.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::SetPin { .. } => "set_pin",
+            Action::ChangePin { .. } => "change_pin",
+            Action::MakeCredential(_) => "make_credential",
+            Action::GetAssertion(_) => "get_assertion",
+            Action::Reset => "reset",
+            Action::Reboot => "reboot",
+            Action::Wait { .. } => "wait",
+            Action::UserPresence { .. } => "user_presence",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MakeCredential {
+    pub rp_id: String,
+    #[serde(default = "default_user_id")]
+    pub user_id: String,
+    /// Whether the credential is stored on the authenticator.
+    #[serde(default = "default_true")]
+    pub resident_key: bool,
+    /// PIN used to authorize the command, if any.
+    pub pin: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GetAssertion {
+    pub rp_id: String,
+    /// Whether to list the credentials previously made for this relying party in the scenario.
+    ///
+    /// Non-resident credentials are only found this way.
+    #[serde(default)]
+    pub allow_list: bool,
+    /// PIN used to authorize the command, if any.
+    pub pin: Option<String>,
+}
+
+/// Outcome of a step. All present fields are checked.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expect {
+    /// Name of the status code, e.g. `CTAP2_ERR_PIN_INVALID`. Defaults to `CTAP2_OK`.
+    pub status: Option<String>,
+    /// Number of credentials found by an assertion.
+    pub credentials: Option<u64>,
+    /// User ID of the first credential returned by an assertion.
+    pub user_id: Option<String>,
+}
+
+fn default_user_id() -> String {
+    String::from("user")
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Parses a scenario, written in YAML or JSON depending on the file extension.
+pub fn parse(path: &Path, content: &str) -> Result<Scenario, String> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => serde_json::from_str(content).map_err(|e| e.to_string()),
+        Some("yaml") | Some("yml") => serde_yaml::from_str(content).map_err(|e| e.to_string()),
+        _ => Err(String::from("Scenarios must be .yaml, .yml or .json files")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_yaml() {
+        let content = r#"
+name: test
+steps:
+  - set_pin:
+      pin: "1234"
+  - reboot:
+  - get_assertion:
+      rp_id: example.com
+    expect:
+      status: CTAP2_ERR_NO_CREDENTIALS
+"#;
+        let scenario = parse(Path::new("test.yaml"), content).unwrap();
+        assert_eq!(scenario.steps.len(), 3);
+        match scenario.steps[1].action {
+            Action::Reboot => (),
+            _ => panic!("Expected a reboot step"),
+        }
+        assert_eq!(
+            scenario.steps[2].expect.status.as_deref(),
+            Some("CTAP2_ERR_NO_CREDENTIALS")
+        );
+    }
+
+    #[test]
+    fn test_parse_json() {
+        let content = r#"{
+  "name": "test",
+  "steps": [
+    {"make_credential": {"rp_id": "example.com", "resident_key": false}},
+    {"reboot": null}
+  ]
+}"#;
+        let scenario = parse(Path::new("test.json"), content).unwrap();
+        match &scenario.steps[0].action {
+            Action::MakeCredential(make_credential) => {
+                assert_eq!(make_credential.user_id, "user");
+                assert!(!make_credential.resident_key);
+            }
+            _ => panic!("Expected a make credential step"),
+        }
+        assert!(parse(Path::new("test.txt"), content).is_err());
+    }
+}
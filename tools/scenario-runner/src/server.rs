@@ -0,0 +1,79 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::runner::CLOCK_FREQUENCY_HZ;
+use crypto::rng256::ThreadRng256;
+use ctap2::ctap::hid::{ChannelID, CtapHid, HidPacket};
+use ctap2::ctap::status_code::Ctap2StatusCode;
+use ctap2::ctap::CtapState;
+use libtock_drivers::timer::ClockValue;
+use std::io;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+/// Maximum time without updating the timeouts of the authenticator, when the host is idle.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Serves the authenticator as a CTAPHID device over UDP, until an I/O error occurs.
+///
+/// Each datagram carries exactly one 64-byte HID report, in both directions. Replies are sent to
+/// the address of the request. Datagrams of another size are ignored. The user is always present,
+/// since nobody is there to touch the device during automated runs.
+///
+/// This is the transport shim for the conformance tool. A bridge on the host exposes the reports
+/// as a virtual USB HID device.
+pub fn serve(address: &str) -> io::Result<()> {
+    let socket = UdpSocket::bind(address)?;
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    let boot_time = Instant::now();
+    let now = || clock_value(boot_time.elapsed());
+    let mut rng = ThreadRng256 {};
+    let check_user_presence = |_: ChannelID| -> Result<(), Ctap2StatusCode> { Ok(()) };
+    let mut ctap_state = CtapState::new(&mut rng, check_user_presence, now());
+    let mut ctap_hid = CtapHid::new();
+    // One more byte than a report, to detect longer datagrams.
+    let mut buffer = [0; 65];
+    loop {
+        let request = match socket.recv_from(&mut buffer) {
+            Ok((64, host)) => Some(host),
+            Ok(_) => None,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                None
+            }
+            Err(e) => return Err(e),
+        };
+        // Like the firmware, the timeouts are updated even without packets.
+        let now = now();
+        ctap_state.update_command_permission(now);
+        ctap_hid.wink_permission = ctap_hid.wink_permission.check_expiration(now);
+        if let Some(host) = request {
+            let mut packet: HidPacket = [0; 64];
+            packet.copy_from_slice(&buffer[..64]);
+            for reply in ctap_hid.process_hid_packet(&packet, now, &mut ctap_state) {
+                socket.send_to(&reply, host)?;
+            }
+        }
+    }
+}
+
+/// Converts the time since boot to a value of the authenticator clock.
+fn clock_value(elapsed: Duration) -> ClockValue {
+    let ticks = elapsed.as_millis() * CLOCK_FREQUENCY_HZ as u128 / 1000;
+    ClockValue::new(ticks as isize, CLOCK_FREQUENCY_HZ)
+}
@@ -0,0 +1,218 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The cbor macros used in tests refer to the alloc crate.
+#[cfg(test)]
+extern crate alloc;
+
+mod storage;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use persistent_store::{BufferOptions, BufferStorage, Storage, StorageIndex, Store};
+use std::fs;
+
+/// Configuration, built from CLI parameters.
+struct Config {
+    /// Path to the raw flash dump of the store.
+    image: String,
+    /// Size of a flash page in bytes.
+    page_size: usize,
+    /// Number of pages of the store.
+    num_pages: usize,
+    /// Path where the edited image is written, the image itself by default.
+    output: String,
+}
+
+fn parse_number(matches: &ArgMatches, name: &str) -> usize {
+    let value = matches.value_of(name).unwrap();
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => value.parse::<usize>(),
+    };
+    parsed.unwrap_or_else(|_| panic!("The {} parameter must be an integer", name))
+}
+
+fn parse_cli() -> (Config, ArgMatches<'static>) {
+    let key_arg = Arg::with_name("key")
+        .help("Key of the entry")
+        .required(true);
+    let matches = App::new("Store tool")
+        .version("0.1")
+        .about("Tool to inspect and edit a dumped store image")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("image")
+                .short("i")
+                .long("image")
+                .value_name("FILE")
+                .help("Raw flash dump of the store pages")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("page-size")
+                .long("page-size")
+                .value_name("BYTES")
+                .help("Size of a flash page")
+                .takes_value(true)
+                .default_value("0x1000"),
+        )
+        .arg(
+            Arg::with_name("num-pages")
+                .long("num-pages")
+                .value_name("PAGES")
+                .help("Number of pages of the store")
+                .takes_value(true)
+                .default_value("20"),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .help("Where to write the edited image (defaults to the image itself)")
+                .takes_value(true),
+        )
+        .subcommand(SubCommand::with_name("list").about("Lists the entries with their meaning"))
+        .subcommand(SubCommand::with_name("check").about("Scans the store for inconsistencies"))
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("Deletes an entry")
+                .arg(key_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("insert")
+                .about("Inserts or replaces an entry")
+                .arg(key_arg)
+                .arg(
+                    Arg::with_name("value")
+                        .help("Value of the entry in hexadecimal")
+                        .required(true),
+                ),
+        )
+        .get_matches();
+
+    let image = matches.value_of("image").unwrap().to_string();
+    let output = matches.value_of("output").unwrap_or(&image).to_string();
+    let config = Config {
+        page_size: parse_number(&matches, "page-size"),
+        num_pages: parse_number(&matches, "num-pages"),
+        image,
+        output,
+    };
+    (config, matches)
+}
+
+/// Mounts a store on the content of a flash dump.
+fn mount(config: &Config) -> Store<BufferStorage> {
+    let content = fs::read(&config.image).expect("Couldn't read --image");
+    assert_eq!(
+        content.len(),
+        config.page_size * config.num_pages,
+        "The image size doesn't match --page-size and --num-pages"
+    );
+    // Those options match the nRF52840 flash.
+    let options = BufferOptions {
+        word_size: 4,
+        page_size: config.page_size,
+        max_word_writes: 2,
+        max_page_erases: 10000,
+        strict_mode: true,
+    };
+    let storage = BufferStorage::new(content.into_boxed_slice(), options);
+    match Store::new(storage) {
+        Ok(store) => store,
+        Err((error, _)) => panic!("Couldn't mount the store: {:?}", error),
+    }
+}
+
+/// Writes the storage of a store back to a flash dump.
+fn unmount(config: &Config, store: Store<BufferStorage>) {
+    let storage = store.extract_storage();
+    let mut content = Vec::with_capacity(config.page_size * config.num_pages);
+    for page in 0..storage.num_pages() {
+        let index = StorageIndex { page, byte: 0 };
+        content.extend_from_slice(storage.read_slice(index, config.page_size).unwrap());
+    }
+    fs::write(&config.output, content).expect("Couldn't write --output");
+}
+
+fn list(store: &Store<BufferStorage>) {
+    let mut handles = store
+        .iter()
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    handles.sort_by_key(|handle| handle.get_key());
+    for handle in handles {
+        let key = handle.get_key();
+        let value = handle.get_value(store).unwrap();
+        println!(
+            "{:4} {}{}: {}",
+            key,
+            storage::key_name(key),
+            if storage::is_persistent(key) {
+                " (persistent)"
+            } else {
+                ""
+            },
+            storage::describe_value(key, &value)
+        );
+    }
+    let capacity = store.capacity().unwrap();
+    println!(
+        "Capacity: {} of {} words used",
+        capacity.used(),
+        capacity.total()
+    );
+}
+
+/// Prints the consistency report of the store and returns whether it is consistent.
+fn check(store: &Store<BufferStorage>) -> bool {
+    let check = store.check().expect("Couldn't scan the store");
+    println!("{:#?}", check);
+    check.is_consistent()
+}
+
+fn main() {
+    let (config, matches) = parse_cli();
+    let mut store = mount(&config);
+    match matches.subcommand() {
+        ("list", Some(_)) => {
+            list(&store);
+            return;
+        }
+        ("check", Some(_)) => {
+            if !check(&store) {
+                eprintln!("The store is inconsistent.");
+                std::process::exit(1);
+            }
+            return;
+        }
+        ("delete", Some(matches)) => {
+            let key = parse_number(matches, "key");
+            store.remove(key).expect("Couldn't delete the entry");
+        }
+        ("insert", Some(matches)) => {
+            let key = parse_number(matches, "key");
+            let value = storage::from_hex(matches.value_of("value").unwrap())
+                .expect("The value must be in hexadecimal");
+            store
+                .insert(key, &value)
+                .expect("Couldn't insert the entry");
+        }
+        _ => unreachable!(),
+    }
+    unmount(&config, store);
+}
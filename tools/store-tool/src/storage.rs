@@ -0,0 +1,297 @@
+// Copyright 2020 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// The keys are shared with the firmware, such that entries are decoded with the same meaning.
+// The tool doesn't use all of them.
+#[allow(dead_code)]
+#[path = "../../../src/ctap/storage/key.rs"]
+mod key;
+
+/// Tags of the credential fields, as serialized by the firmware.
+const CREDENTIAL_RP_ID: u64 = 2;
+const CREDENTIAL_USER_HANDLE: u64 = 3;
+const CREDENTIAL_USER_DISPLAY_NAME: u64 = 4;
+const CREDENTIAL_CRED_PROTECT_POLICY: u64 = 6;
+const CREDENTIAL_CREATION_ORDER: u64 = 7;
+const CREDENTIAL_USER_NAME: u64 = 8;
+
+/// Tags of the USB identity fields, as serialized by the firmware.
+const USB_IDENTITY_VENDOR_ID: u64 = 1;
+const USB_IDENTITY_PRODUCT_ID: u64 = 2;
+const USB_IDENTITY_SERIAL_NUMBER: u64 = 3;
+
+/// Returns the name of a key.
+pub fn key_name(key: usize) -> &'static str {
+    match key {
+        key::_ATTESTATION_PRIVATE_KEY => "legacy attestation private key",
+        key::ATTESTATION_CERTIFICATE => "attestation certificate",
+        key::AAGUID => "AAGUID",
+        key::METRICS => "metrics",
+        _ if key::ATTESTATION_PRIVATE_KEY.contains(&key) => "attestation private key",
+        key::USB_IDENTITY => "USB identity",
+        key::VENDOR_LOCKED => "vendor lock",
+        key::FIRMWARE_VERSION => "firmware version",
+        _ if key::ATTESTATION_INTERMEDIATE_CERTIFICATES.contains(&key) => {
+            "attestation intermediate certificates"
+        }
+        _ if key::AUDIT_LOG.contains(&key) => "audit log",
+        _ if key::CREDENTIALS.contains(&key) => "credential",
+        key::SELF_TEST => "self test scratch",
+        key::CRED_RANDOM_SECRET => "CredRandom secret",
+        #[cfg(feature = "with_ctap2_1")]
+        key::MIN_PIN_LENGTH => "minimum PIN length",
+        #[cfg(feature = "with_ctap2_1")]
+        key::MIN_PIN_LENGTH_RP_IDS => "minimum PIN length RP IDs",
+        key::PIN_RETRIES => "PIN retries",
+        key::PIN_HASH => "PIN hash",
+        _ if key::MASTER_KEYS.contains(&key) => "master keys",
+        key::_MASTER_KEYS => "legacy master keys",
+        key::GLOBAL_SIGNATURE_COUNTER => "global signature counter",
+        _ => "unknown",
+    }
+}
+
+/// Returns whether an entry persists the CTAP reset command.
+pub fn is_persistent(key: usize) -> bool {
+    key < key::NUM_PERSISTENT_KEYS
+}
+
+/// Describes the value of an entry.
+///
+/// Secrets are never printed, only their length. Values that cannot be decoded are printed in
+/// hexadecimal.
+pub fn describe_value(key: usize, value: &[u8]) -> String {
+    let description = match key {
+        _ if is_critical_pointer(key) && value.len() == 1 => Some(format!(
+            "current value in shadow entry {}",
+            key + 1 + value[0] as usize
+        )),
+        _ if key::ATTESTATION_PRIVATE_KEY.contains(&key) || key::MASTER_KEYS.contains(&key) => {
+            Some(format!("secret of {} bytes", value.len()))
+        }
+        key::_ATTESTATION_PRIVATE_KEY | key::CRED_RANDOM_SECRET | key::_MASTER_KEYS => {
+            Some(format!("secret of {} bytes", value.len()))
+        }
+        key::ATTESTATION_CERTIFICATE => Some(format!("{} bytes", value.len())),
+        key::PIN_HASH => Some("present, a PIN is set".to_string()),
+        key::VENDOR_LOCKED => Some("present, the vendor configuration is locked".to_string()),
+        key::USB_IDENTITY => describe_usb_identity(value),
+        key::METRICS if value.len() == 8 => Some(format!(
+            "{} registrations, {} assertions",
+            read_u32(&value[..4]),
+            read_u32(&value[4..])
+        )),
+        key::GLOBAL_SIGNATURE_COUNTER if value.len() == 4 => Some(read_u32(value).to_string()),
+        key::FIRMWARE_VERSION if value.len() == 8 => Some(read_u64(value).to_string()),
+        key::PIN_RETRIES if value.len() == 1 => Some(value[0].to_string()),
+        #[cfg(feature = "with_ctap2_1")]
+        key::MIN_PIN_LENGTH if value.len() == 1 => Some(value[0].to_string()),
+        #[cfg(feature = "with_ctap2_1")]
+        key::MIN_PIN_LENGTH_RP_IDS => Some(format!("{} RP ID hashes", value.len() / 32)),
+        _ if key::CREDENTIALS.contains(&key) => describe_credential(value),
+        _ => None,
+    };
+    description.unwrap_or_else(|| to_hex(value))
+}
+
+/// Returns whether a key is the pointer entry of a critical entry.
+///
+/// The pointer entry holds the index of the shadow entry with the current value.
+fn is_critical_pointer(key: usize) -> bool {
+    key == key::ATTESTATION_PRIVATE_KEY.start || key == key::MASTER_KEYS.start
+}
+
+/// Describes a serialized credential.
+///
+/// Returns `None` if the credential does not decode.
+fn describe_credential(value: &[u8]) -> Option<String> {
+    let map = match cbor::read(value).ok()? {
+        cbor::Value::Map(map) => map,
+        _ => return None,
+    };
+    let field = |tag: u64| map.get(&cbor::KeyType::Unsigned(tag));
+    let text = |tag: u64| match field(tag) {
+        Some(cbor::Value::KeyValue(cbor::KeyType::TextString(text))) => Some(text.clone()),
+        _ => None,
+    };
+    let unsigned = |tag: u64| match field(tag) {
+        Some(cbor::Value::KeyValue(cbor::KeyType::Unsigned(unsigned))) => Some(*unsigned),
+        _ => None,
+    };
+    let mut description = format!("rp_id={}", text(CREDENTIAL_RP_ID)?);
+    if let Some(cbor::Value::KeyValue(cbor::KeyType::ByteString(user_handle))) =
+        field(CREDENTIAL_USER_HANDLE)
+    {
+        description.push_str(&format!(" user_handle={}", to_hex(user_handle)));
+    }
+    if let Some(user_name) = text(CREDENTIAL_USER_NAME) {
+        description.push_str(&format!(" user_name={:?}", user_name));
+    }
+    if let Some(user_display_name) = text(CREDENTIAL_USER_DISPLAY_NAME) {
+        description.push_str(&format!(" user_display_name={:?}", user_display_name));
+    }
+    if let Some(policy) = unsigned(CREDENTIAL_CRED_PROTECT_POLICY) {
+        description.push_str(&format!(" cred_protect={}", policy));
+    }
+    if let Some(creation_order) = unsigned(CREDENTIAL_CREATION_ORDER) {
+        description.push_str(&format!(" creation_order={}", creation_order));
+    }
+    Some(description)
+}
+
+/// Describes a serialized USB identity.
+///
+/// Returns `None` if the identity does not decode.
+fn describe_usb_identity(value: &[u8]) -> Option<String> {
+    let map = match cbor::read(value).ok()? {
+        cbor::Value::Map(map) => map,
+        _ => return None,
+    };
+    let field = |tag: u64| map.get(&cbor::KeyType::Unsigned(tag));
+    let unsigned = |tag: u64| match field(tag) {
+        Some(cbor::Value::KeyValue(cbor::KeyType::Unsigned(unsigned))) => Some(*unsigned),
+        _ => None,
+    };
+    let mut description = format!(
+        "vendor_id={:04x} product_id={:04x}",
+        unsigned(USB_IDENTITY_VENDOR_ID)?,
+        unsigned(USB_IDENTITY_PRODUCT_ID)?
+    );
+    if let Some(cbor::Value::KeyValue(cbor::KeyType::TextString(serial_number))) =
+        field(USB_IDENTITY_SERIAL_NUMBER)
+    {
+        description.push_str(&format!(" serial_number={:?}", serial_number));
+    }
+    Some(description)
+}
+
+/// Reads a little-endian integer, as written by the firmware on the supported boards.
+fn read_u32(bytes: &[u8]) -> u32 {
+    let mut array = [0; 4];
+    array.copy_from_slice(bytes);
+    u32::from_le_bytes(array)
+}
+
+/// Reads a little-endian integer, as written by the firmware on the supported boards.
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut array = [0; 8];
+    array.copy_from_slice(bytes);
+    u64::from_le_bytes(array)
+}
+
+/// Formats bytes in hexadecimal.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parses bytes from hexadecimal.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cbor::cbor_map;
+
+    #[test]
+    fn test_hex_round_trip() {
+        let bytes = vec![0x00, 0x2d, 0xff];
+        assert_eq!(to_hex(&bytes), "002dff");
+        assert_eq!(from_hex("002dff"), Some(bytes));
+        assert_eq!(from_hex("002"), None);
+        assert_eq!(from_hex("0g"), None);
+    }
+
+    #[test]
+    fn test_describe_counters() {
+        assert_eq!(
+            describe_value(key::GLOBAL_SIGNATURE_COUNTER, &[0x2a, 0, 0, 0]),
+            "42"
+        );
+        assert_eq!(
+            describe_value(key::METRICS, &[1, 0, 0, 0, 2, 0, 0, 0]),
+            "1 registrations, 2 assertions"
+        );
+        assert_eq!(describe_value(key::PIN_RETRIES, &[8]), "8");
+        assert_eq!(
+            describe_value(key::FIRMWARE_VERSION, &[3, 0, 0, 0, 0, 0, 0, 0]),
+            "3"
+        );
+        // Malformed values are printed in hexadecimal.
+        assert_eq!(describe_value(key::PIN_RETRIES, &[8, 8]), "0808");
+    }
+
+    #[test]
+    fn test_describe_secrets() {
+        assert_eq!(
+            describe_value(key::MASTER_KEYS.start + 1, &[0x5c; 64]),
+            "secret of 64 bytes"
+        );
+        assert_eq!(
+            describe_value(key::MASTER_KEYS.start, &[1]),
+            format!(
+                "current value in shadow entry {}",
+                key::MASTER_KEYS.start + 2
+            )
+        );
+        assert_eq!(
+            describe_value(key::PIN_HASH, &[0x5c; 16]),
+            "present, a PIN is set"
+        );
+    }
+
+    #[test]
+    fn test_describe_credential() {
+        let credential = cbor_map! {
+            0 => vec![0x2d; 4],
+            1 => vec![0x5c; 32],
+            2 => "example.com",
+            3 => vec![0x1d; 2],
+            7 => 3,
+            8 => "foo",
+        };
+        let mut value = Vec::new();
+        assert!(cbor::write(credential, &mut value));
+        assert_eq!(
+            describe_value(key::CREDENTIALS.start, &value),
+            "rp_id=example.com user_handle=1d1d user_name=\"foo\" creation_order=3"
+        );
+        assert_eq!(key_name(key::CREDENTIALS.start), "credential");
+        assert!(!is_persistent(key::CREDENTIALS.start));
+    }
+
+    #[test]
+    fn test_describe_usb_identity() {
+        let identity = cbor_map! {
+            1 => 0x1915,
+            2 => 0x521f,
+            3 => "WL-0001",
+        };
+        let mut value = Vec::new();
+        assert!(cbor::write(identity, &mut value));
+        assert_eq!(
+            describe_value(key::USB_IDENTITY, &value),
+            "vendor_id=1915 product_id=521f serial_number=\"WL-0001\""
+        );
+        assert_eq!(key_name(key::USB_IDENTITY), "USB identity");
+        assert!(is_persistent(key::USB_IDENTITY));
+    }
+}